@@ -0,0 +1,105 @@
+//! Reading the bookmarks of a repository, like the `bmstore` class of the
+//! `bookmarks` module of Python.
+//!
+//! Active bookmarks and writing bookmarks are out of scope.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::Node;
+use crate::errors::HgError;
+use crate::errors::IoResultExt;
+use crate::repo::Repo;
+use crate::requirements::BOOKMARKS_IN_STORE_REQUIREMENT;
+use crate::revlog::RevlogError;
+
+/// The file listing the bookmarks of `repo`, which is in its store, in the
+/// repository it shares them with, or in its `.hg` directory, like the
+/// `bookmarksvfs` of Python
+pub fn bookmarks_path(repo: &Repo) -> Result<PathBuf, HgError> {
+    if repo.requirements().contains(BOOKMARKS_IN_STORE_REQUIREMENT) {
+        return Ok(repo.store_path().join("bookmarks"));
+    }
+    let shared = repo.hg_vfs().try_read("shared")?.unwrap_or_default();
+    if shared.split(|&byte| byte == b'\n').any(|line| line == b"bookmarks") {
+        return Ok(repo.shared_path().join("bookmarks"));
+    }
+    Ok(repo.hg_vfs().join("bookmarks"))
+}
+
+/// The bookmarks of `repo`, by name. Bookmarks pointing to changesets
+/// unknown to the changelog are left out, like in Python, and so are
+/// malformed lines.
+pub fn read_bookmarks(repo: &Repo) -> Result<BTreeMap<Vec<u8>, Node>, HgError> {
+    let path = bookmarks_path(repo)?;
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(BTreeMap::new());
+        }
+        Err(error) => return Err(error).when_reading_file(&path)?,
+    };
+    let changelog = repo.changelog()?;
+    let mut bookmarks = BTreeMap::new();
+    for (name, node) in parse_bookmarks(&data) {
+        match changelog.rev_from_node(node.into()) {
+            Ok(_) => {
+                bookmarks.insert(name.to_vec(), node);
+            }
+            Err(RevlogError::InvalidRevision { .. }) => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(bookmarks)
+}
+
+/// Whether `name` is a divergent bookmark like `book@remote`, which is not
+/// exchanged, like `isdivergent` in Python
+pub fn is_divergent(name: &[u8]) -> bool {
+    name.contains(&b'@') && !name.ends_with(b"@")
+}
+
+/// The name and node of each valid line of a `bookmarks` file, in order
+fn parse_bookmarks(data: &[u8]) -> impl Iterator<Item = (&[u8], Node)> {
+    data.split(|&byte| byte == b'\n').filter_map(|line| {
+        let line = line.trim_ascii();
+        let (node, name) = line.split_at(line.iter().position(|&b| b == b' ')?);
+        let node = Node::from_hex(node).ok()?;
+        Some((&name[1..], node))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bookmarks() {
+        let data = format!(
+            "{} book\n\
+             \n\
+             garbage\n\
+             {} with space \n\
+             abc bad\n",
+            "11".repeat(20),
+            "22".repeat(20),
+        );
+        let parsed: Vec<_> = parse_bookmarks(data.as_bytes()).collect();
+        assert_eq!(
+            parsed,
+            vec![
+                (&b"book"[..], Node::from([0x11; 20])),
+                (b"with space", Node::from([0x22; 20])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_divergent() {
+        assert!(!is_divergent(b"book"));
+        assert!(!is_divergent(b"@"));
+        assert!(!is_divergent(b"book@"));
+        assert!(is_divergent(b"book@remote"));
+        assert!(is_divergent(b"@remote"));
+    }
+}
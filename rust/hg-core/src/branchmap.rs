@@ -0,0 +1,160 @@
+//! The heads of the named branches of a repository, like the `branchmap`
+//! module of Python, computed from the changelog instead of cached.
+
+use std::collections::BTreeMap;
+
+use crate::Graph;
+use crate::Revision;
+use crate::errors::HgError;
+use crate::revlog::RevlogError;
+use crate::revlog::changelog::Changelog;
+
+/// A head of a named branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchHead {
+    pub rev: Revision,
+    /// Whether the head closes its branch
+    pub closed: bool,
+}
+
+/// What the heads of branches depend on in a changeset
+struct Changeset {
+    branch: Vec<u8>,
+    parents: [Revision; 2],
+    closes: bool,
+}
+
+/// The heads of every named branch, by name
+pub struct BranchMap {
+    /// By increasing revision number
+    heads: BTreeMap<Vec<u8>, Vec<BranchHead>>,
+}
+
+impl BranchMap {
+    /// Reads the branch of every changeset of `changelog`
+    pub fn new(changelog: &Changelog) -> Result<Self, HgError> {
+        let len = changelog.get_index().len();
+        let mut changesets = Vec::with_capacity(len);
+        for rev in 0..len {
+            let revision = Revision(rev as i32);
+            let extra = changelog.entry(revision)?.data()?.extra()?;
+            let parents =
+                changelog.parents(revision).map_err(RevlogError::from)?;
+            changesets.push(Changeset {
+                branch: extra
+                    .get("branch")
+                    .map_or_else(|| b"default".to_vec(), Vec::clone),
+                parents,
+                closes: extra.contains_key("close"),
+            });
+        }
+        Ok(Self::from_changesets(changesets))
+    }
+
+    /// The heads of a branch are its changesets with no child in the same
+    /// branch. `changesets` are by revision number.
+    fn from_changesets(changesets: Vec<Changeset>) -> Self {
+        let mut heads: BTreeMap<Vec<u8>, Vec<BranchHead>> = BTreeMap::new();
+        let mut is_head = vec![true; changesets.len()];
+        for changeset in &changesets {
+            for parent in changeset.parents {
+                if parent.0 >= 0
+                    && changesets[parent.0 as usize].branch == changeset.branch
+                {
+                    is_head[parent.0 as usize] = false;
+                }
+            }
+        }
+        for (rev, changeset) in changesets.into_iter().enumerate() {
+            if is_head[rev] {
+                heads.entry(changeset.branch).or_default().push(BranchHead {
+                    rev: Revision(rev as i32),
+                    closed: changeset.closes,
+                });
+            }
+        }
+        Self { heads }
+    }
+
+    /// The branches with their heads, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[BranchHead])> {
+        self.heads
+            .iter()
+            .map(|(name, heads)| (name.as_slice(), heads.as_slice()))
+    }
+
+    /// The heads of `branch`, by increasing revision number
+    pub fn heads(&self, branch: &[u8]) -> Option<&[BranchHead]> {
+        self.heads.get(branch).map(Vec::as_slice)
+    }
+
+    /// The tip-most head of `branch` that does not close it, or its tip-most
+    /// head if they all do, like `branchtip` in Python
+    pub fn tip(&self, branch: &[u8]) -> Option<Revision> {
+        let heads = self.heads(branch)?;
+        heads
+            .iter()
+            .rev()
+            .find(|head| !head.closed)
+            .or_else(|| heads.last())
+            .map(|head| head.rev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NULL_REVISION;
+
+    fn changeset(branch: &str, parents: [i32; 2], closes: bool) -> Changeset {
+        Changeset {
+            branch: branch.as_bytes().to_vec(),
+            parents: parents.map(Revision),
+            closes,
+        }
+    }
+
+    fn head(rev: i32, closed: bool) -> BranchHead {
+        BranchHead { rev: Revision(rev), closed }
+    }
+
+    #[test]
+    fn test_branch_heads() {
+        let null = NULL_REVISION.0;
+        let branchmap = BranchMap::from_changesets(vec![
+            changeset("default", [null, null], false),
+            changeset("default", [0, null], false),
+            changeset("stable", [1, null], false),
+            changeset("default", [0, null], false),
+            changeset("stable", [2, null], true),
+            changeset("default", [3, null], false),
+        ]);
+        let branches: Vec<_> = branchmap.iter().map(|(name, _)| name).collect();
+        assert_eq!(branches, vec![&b"default"[..], b"stable"]);
+        // Revision 1 only has a child in another branch
+        assert_eq!(
+            branchmap.heads(b"default"),
+            Some(&[head(1, false), head(5, false)][..])
+        );
+        assert_eq!(branchmap.heads(b"stable"), Some(&[head(4, true)][..]));
+        assert_eq!(branchmap.heads(b"other"), None);
+        assert_eq!(branchmap.tip(b"default"), Some(Revision(5)));
+        // A closed head is the tip when all the heads are closed
+        assert_eq!(branchmap.tip(b"stable"), Some(Revision(4)));
+    }
+
+    #[test]
+    fn test_branch_tip() {
+        let null = NULL_REVISION.0;
+        let branchmap = BranchMap::from_changesets(vec![
+            changeset("default", [null, null], false),
+            changeset("default", [0, null], false),
+            changeset("default", [0, null], true),
+        ]);
+        assert_eq!(
+            branchmap.heads(b"default"),
+            Some(&[head(1, false), head(2, true)][..])
+        );
+        assert_eq!(branchmap.tip(b"default"), Some(Revision(1)));
+    }
+}
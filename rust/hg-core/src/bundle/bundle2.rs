@@ -0,0 +1,802 @@
+//! The `HG20` bundle container format
+//!
+//! This is a Rust counterpart to the framing part of `mercurial.bundle2`.
+//!
+//! After the magic string, a bundle2 is made of:
+//! - its stream parameters, as a big-endian 32 bits size followed by
+//!   space-separated, url-quoted `name=value` pairs. A parameter whose name
+//!   starts with an uppercase letter is mandatory: a reader that does not
+//!   know it must abort.
+//! - a sequence of parts, each made of a header and a payload. The header is
+//!   prefixed by its size, and holds the type of the part (uppercase if the
+//!   part is mandatory), its id and its mandatory and advisory parameters.
+//!   The payload is a sequence of chunks prefixed by their size, closed by
+//!   an empty chunk. A chunk size of -1 interrupts the payload: an
+//!   out-of-band part follows, typically to report an error on the sending
+//!   side, then the payload resumes.
+//! - an empty part header closing the bundle.
+//!
+//! Everything after the stream parameters is compressed as described by the
+//! `Compression` stream parameter.
+
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+
+use super::BundleCompression;
+use super::CompressedWriter;
+use crate::changegroup::read_exactly;
+use crate::changegroup::write_all;
+use crate::errors::HgError;
+use crate::errors::HgIoError;
+use crate::errors::IoErrorContext;
+use crate::exit_codes;
+use crate::utils::strings::url_quote;
+use crate::utils::strings::url_unquote;
+
+pub const MAGIC: &[u8] = b"HG20";
+
+/// Size of the chunks of streamed payloads
+const PREFERRED_CHUNK_SIZE: usize = 32768;
+
+/// Chunk size announcing an out-of-band part
+const FLAG_INTERRUPT: i32 = -1;
+
+/// A stream parameter, whose value is optional
+pub type StreamParam = (String, Option<Vec<u8>>);
+
+/// Returns the error for a mandatory feature the reader does not know,
+/// `part_type` being `None` for stream parameters.
+///
+/// Equivalent to `BundleUnknownFeatureError` in Python.
+pub fn unknown_feature(
+    part_type: Option<&str>,
+    params: &[(&str, Option<&[u8]>)],
+) -> HgError {
+    let mut feature = part_type.unwrap_or("Stream Parameter").to_string();
+    if !params.is_empty() {
+        let params: Vec<_> = params
+            .iter()
+            .map(|(name, value)| match value {
+                Some(value) => {
+                    format!("{}='{}'", name, String::from_utf8_lossy(value))
+                }
+                None => name.to_string(),
+            })
+            .collect();
+        feature = format!("{} - {}", feature, params.join(", "));
+    }
+    HgError::abort(
+        format!("unknown bundle feature, {}", feature),
+        exit_codes::ABORT,
+        Some(
+            "see https://mercurial-scm.org/wiki/BundleFeature for more \
+            information"
+                .into(),
+        ),
+    )
+}
+
+fn read_i32(reader: &mut impl Read) -> Result<i32, HgError> {
+    Ok(BigEndian::read_i32(&read_exactly(reader, 4)?))
+}
+
+/// The header of a part, which describes its payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartHeader {
+    /// The type of the part in lowercase, like `changegroup`
+    pub part_type: String,
+    /// Identifies the part in the bundle, assigned by [`Bundle2Writer`] when
+    /// writing
+    pub id: u32,
+    /// Whether a reader that does not know this part must abort
+    pub mandatory: bool,
+    /// The parameters a reader must know, if it processes the part
+    pub mandatory_params: Vec<(String, Vec<u8>)>,
+    pub advisory_params: Vec<(String, Vec<u8>)>,
+}
+
+impl PartHeader {
+    pub fn new(part_type: &str, mandatory: bool) -> Self {
+        Self {
+            part_type: part_type.to_ascii_lowercase(),
+            id: 0,
+            mandatory,
+            mandatory_params: vec![],
+            advisory_params: vec![],
+        }
+    }
+
+    /// Adds a parameter, which must not be already present
+    pub fn add_param(
+        &mut self,
+        name: &str,
+        value: impl Into<Vec<u8>>,
+        mandatory: bool,
+    ) {
+        assert!(self.param(name).is_none(), "duplicated params: {}", name);
+        let params = if mandatory {
+            &mut self.mandatory_params
+        } else {
+            &mut self.advisory_params
+        };
+        params.push((name.to_string(), value.into()));
+    }
+
+    /// Returns the value of a mandatory or advisory parameter
+    pub fn param(&self, name: &str) -> Option<&[u8]> {
+        self.params().find(|(key, _)| *key == name).map(|(_, value)| value)
+    }
+
+    /// Iterates over the mandatory then advisory parameters
+    pub fn params(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.mandatory_params
+            .iter()
+            .chain(&self.advisory_params)
+            .map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+
+    /// Returns an error if some mandatory parameters are not in `supported`,
+    /// as would a reader processing this part.
+    pub fn check_params(&self, supported: &[&str]) -> Result<(), HgError> {
+        let mut unknown: Vec<_> = self
+            .mandatory_params
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| !supported.contains(name))
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort_unstable();
+        let unknown: Vec<_> = unknown.into_iter().map(|n| (n, None)).collect();
+        Err(unknown_feature(Some(&self.part_type), &unknown))
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, HgError> {
+        let part_type = if self.mandatory {
+            self.part_type.to_ascii_uppercase()
+        } else {
+            self.part_type.to_ascii_lowercase()
+        };
+        let too_long = |what: &str, value: &[u8]| {
+            HgError::abort_simple(format!(
+                "bundle2 {} too long: {}",
+                what,
+                String::from_utf8_lossy(value)
+            ))
+        };
+        let type_length = u8::try_from(part_type.len())
+            .map_err(|_| too_long("part type", part_type.as_bytes()))?;
+        let count = |params: &[(String, Vec<u8>)]| {
+            u8::try_from(params.len()).map_err(|_| {
+                too_long("parameter list", self.part_type.as_bytes())
+            })
+        };
+        let mut header = vec![type_length];
+        header.extend_from_slice(part_type.as_bytes());
+        header.extend_from_slice(&self.id.to_be_bytes());
+        header.push(count(&self.mandatory_params)?);
+        header.push(count(&self.advisory_params)?);
+        for (name, value) in self.params() {
+            header
+                .push(u8::try_from(name.len()).map_err(|_| {
+                    too_long("parameter name", name.as_bytes())
+                })?);
+            header.push(
+                u8::try_from(value.len())
+                    .map_err(|_| too_long("parameter value", value))?,
+            );
+        }
+        for (name, value) in self.params() {
+            header.extend_from_slice(name.as_bytes());
+            header.extend_from_slice(value);
+        }
+        Ok(header)
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, HgError> {
+        let truncated = || HgError::abort_simple("truncated part header");
+        let mut rest = data;
+        let mut take = |length: usize| -> Result<&[u8], HgError> {
+            if rest.len() < length {
+                return Err(truncated());
+            }
+            let (taken, remaining) = rest.split_at(length);
+            rest = remaining;
+            Ok(taken)
+        };
+        let as_string = |bytes: &[u8]| {
+            String::from_utf8(bytes.to_vec())
+                .map_err(|_| HgError::abort_simple("non-ASCII part header"))
+        };
+        let type_length = take(1)?[0];
+        let part_type = as_string(take(type_length.into())?)?;
+        let id = BigEndian::read_u32(take(4)?);
+        let counts = take(2)?;
+        let (mandatory_count, advisory_count) =
+            (usize::from(counts[0]), usize::from(counts[1]));
+        let sizes = take(2 * (mandatory_count + advisory_count))?.to_vec();
+        let mut params = vec![];
+        for size in sizes.chunks(2) {
+            let name = as_string(take(size[0].into())?)?;
+            let value = take(size[1].into())?.to_vec();
+            params.push((name, value));
+        }
+        let advisory_params = params.split_off(mandatory_count);
+        Ok(Self {
+            mandatory: part_type != part_type.to_ascii_lowercase(),
+            part_type: part_type.to_ascii_lowercase(),
+            id,
+            mandatory_params: params,
+            advisory_params,
+        })
+    }
+}
+
+/// Reads a bundle2 stream, part after part.
+///
+/// Equivalent to `unbundle20` in Python.
+pub struct Bundle2Reader<'r> {
+    params: Vec<StreamParam>,
+    compression: BundleCompression,
+    stream: Box<dyn Read + 'r>,
+    /// The number of bytes left in the current chunk of the current part
+    /// payload, `None` when no payload is being read
+    payload_left: Option<usize>,
+    /// What `output` parts interrupting a payload had to say
+    output: Vec<u8>,
+}
+
+impl<'r> Bundle2Reader<'r> {
+    /// Reads the stream parameters of a bundle2, whose magic string has
+    /// already been read out of `reader`
+    pub fn new(mut reader: impl Read + 'r) -> Result<Self, HgError> {
+        let size = read_i32(&mut reader)?;
+        if size < 0 {
+            return Err(HgError::abort_simple(format!(
+                "negative bundle param size: {}",
+                size
+            )));
+        }
+        let mut params = vec![];
+        let mut compression = BundleCompression::None;
+        if size > 0 {
+            let block = read_exactly(&mut reader, size as usize)?;
+            for param in block.split(|&byte| byte == b' ') {
+                let mut split = param.splitn(2, |&byte| byte == b'=');
+                let name = url_unquote(split.next().unwrap_or_default());
+                let value = split.next().map(url_unquote);
+                let name = String::from_utf8(name)
+                    .ok()
+                    .filter(|name| {
+                        name.starts_with(|c: char| c.is_ascii_alphabetic())
+                    })
+                    .ok_or_else(|| {
+                        HgError::abort_simple(format!(
+                            "invalid stream parameter name: {}",
+                            String::from_utf8_lossy(param)
+                        ))
+                    })?;
+                if name.eq_ignore_ascii_case("compression") {
+                    compression = value
+                        .as_deref()
+                        .and_then(BundleCompression::from_bundle_type)
+                        .ok_or_else(|| {
+                            unknown_feature(
+                                None,
+                                &[(
+                                    &name,
+                                    Some(value.as_deref().unwrap_or(b"None")),
+                                )],
+                            )
+                        })?;
+                } else if name.starts_with(|c: char| c.is_ascii_uppercase()) {
+                    return Err(unknown_feature(None, &[(&name, None)]));
+                }
+                params.push((name, value));
+            }
+        }
+        Ok(Self {
+            params,
+            compression,
+            stream: compression.decompress(reader)?,
+            payload_left: None,
+            output: vec![],
+        })
+    }
+
+    /// The stream parameters, in bundle order
+    pub fn params(&self) -> &[StreamParam] {
+        &self.params
+    }
+
+    pub fn compression(&self) -> BundleCompression {
+        self.compression
+    }
+
+    /// Returns and forgets what the `output` parts interrupting payloads had
+    /// to say so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Returns the next part, or `None` once the bundle is over. Whatever
+    /// was not read of the payload of the previous part is skipped.
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, 'r>>, HgError> {
+        self.skip_payload()?;
+        let Some(header) = self.part_header()? else {
+            return Ok(None);
+        };
+        self.payload_left = Some(0);
+        Ok(Some(Part { header, bundle: self }))
+    }
+
+    /// Skips what is left of the current payload, if any
+    fn skip_payload(&mut self) -> Result<(), HgError> {
+        while let Some(left) = self.payload_left {
+            if left == 0 {
+                let size = self.next_chunk()?;
+                self.payload_left = (size > 0).then_some(size);
+                continue;
+            }
+            let skipped = std::io::copy(
+                &mut (&mut self.stream).take(left as u64),
+                &mut std::io::sink(),
+            )
+            .map_err(|error| {
+                HgIoError::from_os_error(error, IoErrorContext::ReadingStream)
+            })?;
+            if skipped < left as u64 {
+                return Err(HgError::abort_simple(format!(
+                    "stream ended unexpectedly (got {} bytes, expected {})",
+                    skipped, left
+                )));
+            }
+            self.payload_left = Some(0);
+        }
+        Ok(())
+    }
+
+    fn part_header(&mut self) -> Result<Option<PartHeader>, HgError> {
+        let size = read_i32(&mut self.stream)?;
+        if size < 0 {
+            return Err(HgError::abort_simple(format!(
+                "negative part header size: {}",
+                size
+            )));
+        }
+        if size == 0 {
+            return Ok(None);
+        }
+        let header = read_exactly(&mut self.stream, size as usize)?;
+        PartHeader::decode(&header).map(Some)
+    }
+
+    /// Reads the size of the next chunk of the current payload, handling
+    /// interruptions. Returns 0 at the end of the payload.
+    fn next_chunk(&mut self) -> Result<usize, HgError> {
+        loop {
+            let size = read_i32(&mut self.stream)?;
+            if size == FLAG_INTERRUPT {
+                self.interruption()?;
+                continue;
+            }
+            return usize::try_from(size).map_err(|_| {
+                HgError::abort_simple(format!(
+                    "negative payload chunk size: {}",
+                    size
+                ))
+            });
+        }
+    }
+
+    /// Reads and processes the out-of-band part following an interruption.
+    ///
+    /// Equivalent to `interrupthandler` in Python.
+    fn interruption(&mut self) -> Result<(), HgError> {
+        let Some(header) = self.part_header()? else {
+            return Ok(());
+        };
+        let mut payload = vec![];
+        loop {
+            let size = read_i32(&mut self.stream)?;
+            let size = usize::try_from(size).map_err(|_| {
+                HgError::abort_simple(format!(
+                    "negative payload chunk size: {}",
+                    size
+                ))
+            })?;
+            if size == 0 {
+                break;
+            }
+            payload.extend(read_exactly(&mut self.stream, size)?);
+        }
+        match header.part_type.as_str() {
+            "error:abort" => {
+                header.check_params(&["message", "hint"])?;
+                let param = |name| {
+                    header.param(name).map(|value| {
+                        String::from_utf8_lossy(value).into_owned()
+                    })
+                };
+                Err(HgError::abort(
+                    param("message").unwrap_or_default(),
+                    exit_codes::ABORT,
+                    param("hint"),
+                ))
+            }
+            "output" => {
+                header.check_params(&[])?;
+                self.output.extend(payload);
+                Ok(())
+            }
+            _ if header.mandatory => {
+                Err(unknown_feature(Some(&header.part_type), &[]))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A part being read, whose payload is read through [`Read`]
+pub struct Part<'a, 'r> {
+    pub header: PartHeader,
+    bundle: &'a mut Bundle2Reader<'r>,
+}
+
+impl Part<'_, '_> {
+    /// Reads the whole remaining payload
+    pub fn read_payload(&mut self) -> Result<Vec<u8>, HgError> {
+        let mut payload = vec![];
+        while let Some(left) = self.bundle.payload_left {
+            if left == 0 {
+                let size = self.bundle.next_chunk()?;
+                self.bundle.payload_left = (size > 0).then_some(size);
+                continue;
+            }
+            payload.extend(read_exactly(&mut self.bundle.stream, left)?);
+            self.bundle.payload_left = Some(0);
+        }
+        Ok(payload)
+    }
+}
+
+impl Read for Part<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.bundle.payload_left {
+                None => return Ok(0),
+                Some(0) => {
+                    let size = self
+                        .bundle
+                        .next_chunk()
+                        .map_err(std::io::Error::other)?;
+                    self.bundle.payload_left = (size > 0).then_some(size);
+                }
+                Some(left) => {
+                    let wanted = left.min(buf.len());
+                    let read = self.bundle.stream.read(&mut buf[..wanted])?;
+                    if read == 0 && wanted > 0 {
+                        return Err(std::io::Error::other(
+                            HgError::abort_simple(format!(
+                                "stream ended unexpectedly \
+                                (got 0 bytes, expected {})",
+                                left
+                            )),
+                        ));
+                    }
+                    self.bundle.payload_left = Some(left - read);
+                    return Ok(read);
+                }
+            }
+        }
+    }
+}
+
+/// Writes a bundle2 stream.
+///
+/// Equivalent to `bundle20` in Python, except that parts are written as they
+/// are added.
+pub struct Bundle2Writer<W: Write> {
+    out: CompressedWriter<W>,
+    next_part_id: u32,
+}
+
+impl<W: Write> Bundle2Writer<W> {
+    /// Writes the magic string and the stream parameters, the rest of the
+    /// bundle being compressed with `compression`, which adds its own
+    /// parameter.
+    pub fn new(
+        out: W,
+        params: &[StreamParam],
+        compression: BundleCompression,
+    ) -> Result<Self, HgError> {
+        Self::with_level(out, params, compression, None)
+    }
+
+    /// Like [`Bundle2Writer::new`], compressing at `level` as in
+    /// [`CompressedWriter::with_level`]
+    pub fn with_level(
+        mut out: W,
+        params: &[StreamParam],
+        compression: BundleCompression,
+        level: Option<i32>,
+    ) -> Result<Self, HgError> {
+        let mut blocks = vec![];
+        if compression != BundleCompression::None {
+            blocks.push(
+                format!("Compression={}", compression.bundle_type())
+                    .into_bytes(),
+            );
+        }
+        for (name, value) in params {
+            assert!(
+                name.starts_with(|c: char| c.is_ascii_alphabetic()),
+                "invalid stream parameter name: {}",
+                name
+            );
+            let mut block = url_quote(name.as_bytes());
+            if let Some(value) = value {
+                block.push(b'=');
+                block.extend(url_quote(value));
+            }
+            blocks.push(block);
+        }
+        let params = blocks.join(&b' ');
+        write_all(&mut out, MAGIC)?;
+        write_all(&mut out, &(params.len() as i32).to_be_bytes())?;
+        write_all(&mut out, &params)?;
+        Ok(Self {
+            out: CompressedWriter::with_level(out, compression, level)?,
+            next_part_id: 0,
+        })
+    }
+
+    fn write_header(&mut self, mut header: PartHeader) -> Result<(), HgError> {
+        header.id = self.next_part_id;
+        self.next_part_id += 1;
+        let header = header.encode()?;
+        write_all(&mut self.out, &(header.len() as i32).to_be_bytes())?;
+        write_all(&mut self.out, &header)
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), HgError> {
+        let size = i32::try_from(chunk.len())
+            .map_err(|_| HgError::abort_simple("bundle2 chunk too large"))?;
+        write_all(&mut self.out, &size.to_be_bytes())?;
+        write_all(&mut self.out, chunk)
+    }
+
+    /// Adds a part whose whole payload is known, written as a single chunk
+    pub fn add_part(
+        &mut self,
+        header: PartHeader,
+        payload: &[u8],
+    ) -> Result<(), HgError> {
+        self.write_header(header)?;
+        if !payload.is_empty() {
+            self.write_chunk(payload)?;
+        }
+        self.write_chunk(&[])
+    }
+
+    /// Starts a part whose payload is then streamed through the returned
+    /// writer, in chunks of 32KiB. The part is closed by
+    /// [`PartWriter::finish`] or [`PartWriter::abort`].
+    pub fn start_part(
+        &mut self,
+        header: PartHeader,
+    ) -> Result<PartWriter<'_, W>, HgError> {
+        self.write_header(header)?;
+        Ok(PartWriter {
+            bundle: self,
+            buffer: Vec::with_capacity(PREFERRED_CHUNK_SIZE),
+        })
+    }
+
+    /// Closes the bundle, returning the underlying writer
+    pub fn finish(mut self) -> Result<W, HgError> {
+        write_all(&mut self.out, &0i32.to_be_bytes())?;
+        self.out.finish()
+    }
+}
+
+/// Writes the payload of a part
+pub struct PartWriter<'a, W: Write> {
+    bundle: &'a mut Bundle2Writer<W>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> PartWriter<'_, W> {
+    fn flush_buffer(&mut self) -> Result<(), HgError> {
+        if !self.buffer.is_empty() {
+            let buffer = std::mem::take(&mut self.buffer);
+            self.bundle.write_chunk(&buffer)?;
+            self.buffer = buffer;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Closes the payload
+    pub fn finish(mut self) -> Result<(), HgError> {
+        self.flush_buffer()?;
+        self.bundle.write_chunk(&[])
+    }
+
+    /// Interrupts the payload with an `error:abort` part, so that the reader
+    /// aborts with `message`, then closes it.
+    pub fn abort(mut self, message: &str) -> Result<(), HgError> {
+        self.flush_buffer()?;
+        write_all(&mut self.bundle.out, &FLAG_INTERRUPT.to_be_bytes())?;
+        let mut header = PartHeader::new("error:abort", false);
+        header.add_param(
+            "message",
+            format!("unexpected error: {}", message),
+            true,
+        );
+        let header = header.encode()?;
+        write_all(&mut self.bundle.out, &(header.len() as i32).to_be_bytes())?;
+        write_all(&mut self.bundle.out, &header)?;
+        self.bundle.write_chunk(&[])?;
+        self.bundle.write_chunk(&[])
+    }
+}
+
+impl<W: Write> Write for PartWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let taken = buf.len().min(PREFERRED_CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..taken]);
+        if self.buffer.len() == PREFERRED_CHUNK_SIZE {
+            self.flush_buffer().map_err(std::io::Error::other)?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abort_message(error: HgError) -> String {
+        match error {
+            HgError::Abort { message, .. } => message,
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    fn write_test_bundle(compression: BundleCompression) -> Vec<u8> {
+        let mut writer = Bundle2Writer::new(
+            vec![],
+            &[("some param".into(), Some(b"a value".to_vec()))],
+            compression,
+        )
+        .unwrap();
+        let mut header = PartHeader::new("small", true);
+        header.add_param("key", "value", true);
+        header.add_param("hint", "", false);
+        writer.add_part(header, b"payload").unwrap();
+        let mut part =
+            writer.start_part(PartHeader::new("streamed", false)).unwrap();
+        for index in 0..10000u32 {
+            part.write_all(&index.to_be_bytes()).unwrap();
+        }
+        part.finish().unwrap();
+        writer.add_part(PartHeader::new("empty", false), b"").unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for compression in [
+            BundleCompression::None,
+            BundleCompression::Bzip2,
+            BundleCompression::Gzip,
+            BundleCompression::Zstd,
+        ] {
+            let bundle = write_test_bundle(compression);
+            assert_eq!(&bundle[..4], MAGIC);
+            let mut reader = Bundle2Reader::new(&bundle[4..]).unwrap();
+            assert_eq!(reader.compression(), compression);
+            let params = reader.params().to_vec();
+            assert_eq!(
+                params.last().unwrap(),
+                &("some param".to_string(), Some(b"a value".to_vec()))
+            );
+
+            let mut part = reader.next_part().unwrap().unwrap();
+            assert_eq!(part.header.part_type, "small");
+            assert_eq!(part.header.id, 0);
+            assert!(part.header.mandatory);
+            assert_eq!(part.header.param("key"), Some(&b"value"[..]));
+            assert_eq!(part.header.param("hint"), Some(&b""[..]));
+            assert!(part.header.check_params(&["key"]).is_ok());
+            assert_eq!(
+                abort_message(part.header.check_params(&[]).unwrap_err()),
+                "unknown bundle feature, small - key"
+            );
+            assert_eq!(part.read_payload().unwrap(), b"payload");
+
+            let mut part = reader.next_part().unwrap().unwrap();
+            assert_eq!(part.header.part_type, "streamed");
+            assert_eq!(part.header.id, 1);
+            assert!(!part.header.mandatory);
+            let mut start = [0; 8];
+            part.read_exact(&mut start).unwrap();
+            assert_eq!(start, [0, 0, 0, 0, 0, 0, 0, 1]);
+
+            // The rest of the payload is skipped
+            let mut part = reader.next_part().unwrap().unwrap();
+            assert_eq!(part.header.part_type, "empty");
+            assert_eq!(part.read_payload().unwrap(), b"");
+            assert!(reader.next_part().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_chunking() {
+        let mut writer =
+            Bundle2Writer::new(vec![], &[], BundleCompression::None).unwrap();
+        let mut part =
+            writer.start_part(PartHeader::new("test", true)).unwrap();
+        part.write_all(&[1; PREFERRED_CHUNK_SIZE + 1]).unwrap();
+        part.finish().unwrap();
+        let bundle = writer.finish().unwrap();
+        // magic, params, header size, header, then the chunks
+        let chunks = &bundle[4 + 4 + 4 + 11..];
+        assert_eq!(BigEndian::read_i32(chunks), PREFERRED_CHUNK_SIZE as i32);
+        let chunks = &chunks[4 + PREFERRED_CHUNK_SIZE..];
+        assert_eq!(chunks, [0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_interruption() {
+        let mut writer =
+            Bundle2Writer::new(vec![], &[], BundleCompression::None).unwrap();
+        let mut part =
+            writer.start_part(PartHeader::new("test", true)).unwrap();
+        part.write_all(b"partial").unwrap();
+        part.abort("oops").unwrap();
+        let bundle = writer.finish().unwrap();
+
+        let mut reader = Bundle2Reader::new(&bundle[4..]).unwrap();
+        let mut part = reader.next_part().unwrap().unwrap();
+        let mut partial = [0; 7];
+        part.read_exact(&mut partial).unwrap();
+        assert_eq!(&partial, b"partial");
+        let error = part.read_payload().unwrap_err();
+        assert_eq!(abort_message(error), "unexpected error: oops");
+    }
+
+    #[test]
+    fn test_stream_params() {
+        let bundle = |params: &[u8]| {
+            let mut bundle = (params.len() as i32).to_be_bytes().to_vec();
+            bundle.extend_from_slice(params);
+            bundle.extend_from_slice(&[0; 4]);
+            std::io::Cursor::new(bundle)
+        };
+        let reader = Bundle2Reader::new(bundle(b"e%20f=g%3Dh ijk"));
+        assert_eq!(
+            reader.unwrap().params(),
+            [("e f".into(), Some(b"g=h".to_vec())), ("ijk".into(), None)]
+        );
+        let reader = Bundle2Reader::new(bundle(b"Unknown=1"));
+        assert_eq!(
+            abort_message(reader.err().unwrap()),
+            "unknown bundle feature, Stream Parameter - Unknown"
+        );
+        let reader = Bundle2Reader::new(bundle(b"Compression=XX"));
+        assert_eq!(
+            abort_message(reader.err().unwrap()),
+            "unknown bundle feature, Stream Parameter - Compression='XX'"
+        );
+    }
+}
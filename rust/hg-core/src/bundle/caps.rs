@@ -0,0 +1,208 @@
+//! The bundle2 capabilities that peers exchange to agree on what a bundle
+//! may contain
+//!
+//! This is a Rust counterpart to `mercurial.exchanges.bundle_caps` and to
+//! the capabilities encoding of `mercurial.bundle2`.
+
+use std::collections::BTreeMap;
+
+use crate::errors::HgError;
+use crate::repo::Repo;
+use crate::requirements::CHANGELOGV2_REQUIREMENT;
+use crate::requirements::DELTA_INFO_REQUIREMENT;
+use crate::requirements::REVLOGV2_REQUIREMENT;
+use crate::utils::strings::url_quote;
+use crate::utils::strings::url_unquote;
+
+/// The values of each capability, by name
+pub type Capabilities = BTreeMap<Vec<u8>, Vec<Vec<u8>>>;
+
+/// Which end of an exchange capabilities are for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Encodes `caps` as sorted lines of url-quoted `name=value,value`, like
+/// `encodecaps` in Python
+pub fn encode_caps(caps: &Capabilities) -> Vec<u8> {
+    let lines: Vec<Vec<u8>> = caps
+        .iter()
+        .map(|(name, values)| {
+            let mut line = url_quote(name);
+            if !values.is_empty() {
+                let values: Vec<_> =
+                    values.iter().map(|value| url_quote(value)).collect();
+                line.push(b'=');
+                line.extend(values.join(&b','));
+            }
+            line
+        })
+        .collect();
+    lines.join(&b'\n')
+}
+
+/// Decodes capabilities encoded by [`encode_caps`], like `decodecaps` in
+/// Python
+pub fn decode_caps(blob: &[u8]) -> Capabilities {
+    let mut caps = Capabilities::new();
+    for line in blob.split(|&byte| byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let (name, values) = match line.iter().position(|&b| b == b'=') {
+            Some(equal) => {
+                let values = line[equal + 1..]
+                    .split(|&byte| byte == b',')
+                    .map(url_unquote)
+                    .collect();
+                (&line[..equal], values)
+            }
+            None => (line, vec![]),
+        };
+        caps.insert(url_unquote(name), values);
+    }
+    caps
+}
+
+/// The bundle2 capabilities in the `bundlecaps` argument of a `getbundle`
+/// request, like `b2_caps_from_bundle_caps` in Python
+pub fn caps_from_bundle_caps<'a>(
+    bundle_caps: impl IntoIterator<Item = &'a [u8]>,
+) -> Capabilities {
+    let mut caps = Capabilities::new();
+    for bundle_cap in bundle_caps {
+        if let Some(blob) = bundle_cap.strip_prefix(b"bundle2=") {
+            caps.extend(decode_caps(&url_unquote(blob)));
+        }
+    }
+    caps
+}
+
+/// The changegroup versions that `repo` can apply, like
+/// `supportedincomingversions` in Python
+pub fn incoming_changegroup_versions(
+    repo: &Repo,
+) -> Result<Vec<&'static str>, HgError> {
+    let config = repo.config();
+    let requirements = repo.requirements();
+    let mut versions = vec!["01", "02", "03"];
+    let want_v4 =
+        match config.get_option_no_default(b"experimental", b"changegroup4")? {
+            Some(want_v4) => want_v4,
+            None => requirements.contains(DELTA_INFO_REQUIREMENT),
+        };
+    if want_v4 {
+        versions.push("04");
+    }
+    if config.get_bool(b"experimental", b"changegroup5")?
+        || requirements.contains(REVLOGV2_REQUIREMENT)
+        || requirements.contains(CHANGELOGV2_REQUIREMENT)
+    {
+        versions.push("05");
+    }
+    Ok(versions)
+}
+
+/// Whether obsolescence markers are configured, which rhg does not exchange
+pub fn obsolescence_configured(repo: &Repo) -> bool {
+    let config = repo.config();
+    config
+        .get_list(b"experimental", b"evolution")
+        .is_some_and(|values| !values.is_empty())
+        || config.get(b"experimental", b"evolution.exchange").is_some()
+        || config.get(b"experimental", b"evolution.createmarkers").is_some()
+}
+
+/// The bundle2 capabilities of `repo`, like `get_repo_caps` in Python
+/// without push-back support. Obsolescence markers are not supported, see
+/// [`obsolescence_configured`].
+pub fn repo_caps(repo: &Repo, role: Role) -> Result<Capabilities, HgError> {
+    let config = repo.config();
+    let values = |values: &[&str]| -> Vec<Vec<u8>> {
+        values.iter().map(|value| value.as_bytes().to_vec()).collect()
+    };
+    let mut caps = Capabilities::new();
+    for (name, value) in [
+        ("HG20", &[][..]),
+        ("bookmarks", &[]),
+        ("error", &["abort", "unsupportedcontent", "pushraced", "pushkey"]),
+        ("listkeys", &[]),
+        ("pushkey", &[]),
+        ("digests", &["md5", "sha1", "sha512"]),
+        ("remote-changegroup", &["http", "https"]),
+        ("hgtagsfnodes", &[]),
+        ("phases", &["heads"]),
+        ("stream", &["v2"]),
+    ] {
+        caps.insert(name.as_bytes().to_vec(), values(value));
+    }
+    caps.insert(
+        b"changegroup".to_vec(),
+        values(&incoming_changegroup_versions(repo)?),
+    );
+    if config.get_bool(b"storage", b"revlog.exchange-compressed-delta")? {
+        caps.insert(
+            b"delta-compression".to_vec(),
+            values(&["none", "zlib", "zstd"]),
+        );
+    }
+    if config.get_str(b"server", b"concurrent-push-mode")?
+        == Some("check-related")
+    {
+        caps.insert(b"checkheads".to_vec(), values(&["related"]));
+    }
+    let legacy =
+        config.get_list(b"devel", b"legacy.exchange").unwrap_or_default();
+    if legacy.iter().any(|value| value == b"phases") {
+        caps.remove(&b"phases"[..]);
+    }
+    if role == Role::Server
+        && !(config.get_bool(b"server", b"uncompressed")?
+            && config.get_bool(b"server", b"bundle2.stream")?)
+    {
+        caps.remove(&b"stream"[..]);
+    }
+    if config.get_bool(b"experimental", b"stream-v3")?
+        && let Some(versions) = caps.get_mut(&b"stream"[..])
+    {
+        versions.push(b"v3-exp".to_vec());
+    }
+    Ok(caps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caps_encoding() {
+        let mut caps = Capabilities::new();
+        caps.insert(b"HG20".to_vec(), vec![]);
+        caps.insert(
+            b"changegroup".to_vec(),
+            vec![b"01".to_vec(), b"02".to_vec()],
+        );
+        caps.insert(b"odd name".to_vec(), vec![b"a=b,c".to_vec()]);
+        let encoded = encode_caps(&caps);
+        assert_eq!(
+            encoded,
+            b"HG20\nchangegroup=01,02\nodd%20name=a%3Db%2Cc".to_vec()
+        );
+        assert_eq!(decode_caps(&encoded), caps);
+    }
+
+    #[test]
+    fn test_caps_from_bundle_caps() {
+        let bundle_caps: [&[u8]; 3] =
+            [b"HG20", b"bundle2=HG20%0Achangegroup%3D01%2C02", b"unrelated"];
+        let caps = caps_from_bundle_caps(bundle_caps);
+        assert_eq!(caps.len(), 2);
+        assert_eq!(caps[&b"HG20"[..]], Vec::<Vec<u8>>::new());
+        assert_eq!(
+            caps[&b"changegroup"[..]],
+            vec![b"01".to_vec(), b"02".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,964 @@
+//! Bundles, the files holding revisions to transfer between repositories
+//!
+//! This is a Rust counterpart to `mercurial.exchange.readbundle` and to the
+//! bundle side of the compression engines of `mercurial.utils.compression`.
+//!
+//! A bundle starts with a 4 bytes magic string:
+//! - `HG10` is followed by a 2 bytes compression type (`UN`, `BZ` or `GZ`)
+//!   and by a version `01` changegroup,
+//! - `HG20` introduces the [`bundle2`] container format,
+//! - `HGS1` is a stream clone bundle, of which only the header is read here.
+
+pub mod bundle2;
+pub mod caps;
+pub mod parts;
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+
+use crate::FastHashSet;
+use crate::Revision;
+use crate::changegroup::ChangegroupUnpacker;
+use crate::changegroup::ChangegroupVersion;
+use crate::changegroup::read_exactly;
+use crate::changegroup::write_all;
+use crate::changegroup::write_changegroup;
+use crate::config::parse_bool;
+use crate::discovery::Outgoing;
+use crate::errors::HgError;
+use crate::errors::HgIoError;
+use crate::errors::IoErrorContext;
+use crate::exit_codes;
+use crate::repo::Repo;
+use crate::requirements::GENERALDELTA_REQUIREMENT;
+use crate::requirements::REVLOGV2_REQUIREMENT;
+use crate::revlog::RevlogError;
+use crate::revlog::index::Phase;
+use crate::stream_clone::StreamGenerator;
+use crate::stream_clone::StreamVersion;
+use crate::stream_clone::streamed_requirements;
+use crate::tags::cached_tags_fnodes;
+use crate::utils::strings::url_quote;
+use crate::utils::strings::url_unquote;
+
+use self::bundle2::Bundle2Reader;
+use self::bundle2::Bundle2Writer;
+use self::bundle2::PartHeader;
+
+/// How the content of a bundle is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleCompression {
+    None,
+    Bzip2,
+    /// Despite its name, this is a zlib stream
+    Gzip,
+    Zstd,
+}
+
+impl BundleCompression {
+    /// Parses the 2 letters code used in `HG10` headers and in the
+    /// `Compression` stream parameter of bundle2
+    pub fn from_bundle_type(bundle_type: &[u8]) -> Option<Self> {
+        match bundle_type {
+            b"UN" => Some(Self::None),
+            b"BZ" => Some(Self::Bzip2),
+            b"GZ" => Some(Self::Gzip),
+            b"ZS" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn bundle_type(self) -> &'static str {
+        match self {
+            Self::None => "UN",
+            Self::Bzip2 => "BZ",
+            Self::Gzip => "GZ",
+            Self::Zstd => "ZS",
+        }
+    }
+
+    /// Parses the name of the compression in a bundle specification, like
+    /// `gzip` in `gzip-v2`
+    pub fn from_spec_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "bzip2" => Some(Self::Bzip2),
+            "gzip" => Some(Self::Gzip),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn spec_name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Bzip2 => "bzip2",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Wraps `reader` to decompress the data read from it
+    pub fn decompress<'r>(
+        self,
+        reader: impl Read + 'r,
+    ) -> Result<Box<dyn Read + 'r>, HgError> {
+        Ok(match self {
+            Self::None => Box::new(reader),
+            Self::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+            Self::Gzip => Box::new(flate2::read::ZlibDecoder::new(reader)),
+            Self::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(reader).map_err(|error| {
+                    HgIoError::from_os_error(
+                        error,
+                        IoErrorContext::ReadingStream,
+                    )
+                })?,
+            ),
+        })
+    }
+}
+
+/// A writer compressing the data written to it.
+///
+/// The engines of [`crate::revlog::compression`] are not used: they work on
+/// a whole revision in memory and may leave it uncompressed, while a bundle
+/// is a single stream of any size.
+pub enum CompressedWriter<W: Write> {
+    None(W),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Gzip(flate2::write::ZlibEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    /// Compresses with the same levels as Python's bundle compression
+    /// engines by default
+    pub fn new(
+        writer: W,
+        compression: BundleCompression,
+    ) -> Result<Self, HgError> {
+        Self::with_level(writer, compression, None)
+    }
+
+    /// Compresses at `level` if given, like the `level` compression option
+    /// in Python, `-1` being the default level of zlib
+    pub fn with_level(
+        writer: W,
+        compression: BundleCompression,
+        level: Option<i32>,
+    ) -> Result<Self, HgError> {
+        let invalid_level = |level: i32| {
+            HgError::abort_simple(format!(
+                "invalid {} compression level: {}",
+                compression.spec_name(),
+                level
+            ))
+        };
+        Ok(match compression {
+            BundleCompression::None => Self::None(writer),
+            BundleCompression::Bzip2 => {
+                let level = match level {
+                    None => bzip2::Compression::best(),
+                    Some(level @ 1..=9) => {
+                        bzip2::Compression::new(level as u32)
+                    }
+                    Some(level) => return Err(invalid_level(level)),
+                };
+                Self::Bzip2(bzip2::write::BzEncoder::new(writer, level))
+            }
+            BundleCompression::Gzip => {
+                let level = match level {
+                    None | Some(-1) => flate2::Compression::default(),
+                    Some(level @ 0..=9) => {
+                        flate2::Compression::new(level as u32)
+                    }
+                    Some(level) => return Err(invalid_level(level)),
+                };
+                Self::Gzip(flate2::write::ZlibEncoder::new(writer, level))
+            }
+            BundleCompression::Zstd => {
+                let level = level.unwrap_or(3);
+                if !zstd::compression_level_range().contains(&level) {
+                    return Err(invalid_level(level));
+                }
+                Self::Zstd(
+                    zstd::stream::write::Encoder::new(writer, level)
+                        .map_err(writing_error)?,
+                )
+            }
+        })
+    }
+
+    /// Writes whatever the compressor still holds, returning the underlying
+    /// writer
+    pub fn finish(self) -> Result<W, HgError> {
+        match self {
+            Self::None(writer) => Ok(writer),
+            Self::Bzip2(encoder) => encoder.finish(),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+        .map_err(writing_error)
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Bzip2(encoder) => encoder.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Bzip2(encoder) => encoder.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+fn writing_error(error: std::io::Error) -> HgError {
+    HgIoError::from_os_error(error, IoErrorContext::WritingStream).into()
+}
+
+/// The header of a `HGS1` stream clone bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamCloneHeader {
+    pub file_count: u64,
+    pub byte_count: u64,
+    pub requirements: Vec<String>,
+}
+
+/// A bundle whose header has been read
+pub enum Bundle<'r> {
+    /// `HG10`: a version `01` changegroup
+    Changegroup {
+        compression: BundleCompression,
+        unpacker: ChangegroupUnpacker<Box<dyn Read + 'r>>,
+    },
+    /// `HG20`: a bundle2 container
+    Bundle2(Bundle2Reader<'r>),
+    /// `HGS1`: a stream clone bundle, positioned right after its header
+    StreamClone { header: StreamCloneHeader, reader: Box<dyn Read + 'r> },
+}
+
+/// Reads the header of the bundle in `reader`. `name` identifies the bundle
+/// in error messages.
+pub fn read_bundle<'r>(
+    mut reader: impl Read + 'r,
+    name: &str,
+) -> Result<Bundle<'r>, HgError> {
+    let header = read_exactly(&mut reader, 4)?;
+    let (magic, version) = header.split_at(2);
+    if magic != b"HG" {
+        return Err(HgError::abort_simple(format!(
+            "{}: not a Mercurial bundle",
+            name
+        )));
+    }
+    match version {
+        b"10" => {
+            let bundle_type = read_exactly(&mut reader, 2)?;
+            let (compression, reader): (_, Box<dyn Read + 'r>) =
+                match &bundle_type[..] {
+                    // The "BZ" magic of the bzip2 stream doubles as the
+                    // compression type
+                    b"BZ" => (
+                        BundleCompression::Bzip2,
+                        BundleCompression::Bzip2
+                            .decompress(Cursor::new(b"BZ").chain(reader))?,
+                    ),
+                    b"UN" | b"GZ" => {
+                        let compression =
+                            BundleCompression::from_bundle_type(&bundle_type)
+                                .expect("known bundle type");
+                        (compression, compression.decompress(reader)?)
+                    }
+                    _ => {
+                        return Err(HgError::abort_simple(format!(
+                            "unknown stream compression type: {}",
+                            String::from_utf8_lossy(&bundle_type)
+                        )));
+                    }
+                };
+            Ok(Bundle::Changegroup {
+                compression,
+                unpacker: ChangegroupUnpacker::new(
+                    reader,
+                    ChangegroupVersion::V1,
+                ),
+            })
+        }
+        b"20" => Ok(Bundle::Bundle2(Bundle2Reader::new(reader)?)),
+        b"S1" => {
+            let header = read_stream_clone_header(&mut reader)?;
+            Ok(Bundle::StreamClone { header, reader: Box::new(reader) })
+        }
+        _ => Err(HgError::abort_simple(format!(
+            "{}: unknown bundle version {}",
+            name,
+            String::from_utf8_lossy(version)
+        ))),
+    }
+}
+
+/// Reads what follows the `HGS1` magic string of a stream clone bundle
+fn read_stream_clone_header(
+    reader: &mut impl Read,
+) -> Result<StreamCloneHeader, HgError> {
+    let compression = read_exactly(reader, 2)?;
+    if compression != b"UN" {
+        return Err(HgError::abort_simple(format!(
+            "only uncompressed stream clone bundles are supported; got {}",
+            String::from_utf8_lossy(&compression)
+        )));
+    }
+    let counts = read_exactly(reader, 18)?;
+    let requirements_length = BigEndian::read_u16(&counts[16..]);
+    let requirements = read_exactly(reader, requirements_length.into())?;
+    let Some(requirements) = requirements.strip_suffix(b"\0") else {
+        return Err(HgError::abort_simple(
+            "malformed stream clone bundle: \
+            requirements not properly encoded",
+        ));
+    };
+    Ok(StreamCloneHeader {
+        file_count: BigEndian::read_u64(&counts[..8]),
+        byte_count: BigEndian::read_u64(&counts[8..16]),
+        requirements: requirements
+            .split(|&byte| byte == b',')
+            .map(|requirement| String::from_utf8_lossy(requirement).into())
+            .collect(),
+    })
+}
+
+/// Formats requirements as the `requirements` parameter of a bundle
+/// specification
+fn requirements_spec_param<'a>(
+    requirements: impl IntoIterator<Item = &'a str>,
+) -> String {
+    let mut requirements: Vec<_> = requirements
+        .into_iter()
+        .filter(|requirement| *requirement != "shared")
+        .collect();
+    requirements.sort_unstable();
+    let quoted = url_quote(requirements.join(",").as_bytes());
+    format!("requirements%3D{}", String::from_utf8_lossy(&quoted))
+}
+
+/// Infers the specification of a bundle, like `bzip2-v2` or
+/// `none-v2;stream=v2;requirements%3Drevlogv1`, reading it entirely if
+/// needed.
+///
+/// Equivalent to `mercurial.exchange.getbundlespec`.
+pub fn bundle_spec(bundle: Bundle) -> Result<String, HgError> {
+    let mut bundle2 = match bundle {
+        Bundle::Changegroup { compression, .. } => {
+            return Ok(format!("{}-v1", compression.spec_name()));
+        }
+        Bundle::StreamClone { header, .. } => {
+            return Ok(format!(
+                "none-packed1;{}",
+                requirements_spec_param(
+                    header.requirements.iter().map(String::as_str)
+                )
+            ));
+        }
+        Bundle::Bundle2(bundle2) => bundle2,
+    };
+    let compression = bundle2.compression().spec_name();
+    let mut has_changegroup = false;
+    // Sorted by name
+    let mut params = std::collections::BTreeMap::new();
+    while let Some(part) = bundle2.next_part()? {
+        let header = &part.header;
+        match header.part_type.as_str() {
+            "changegroup" => {
+                let version = header.param("version").unwrap_or(b"01");
+                match version {
+                    b"01" | b"02" => {}
+                    b"03" | b"04" => {
+                        params.insert("cg.version", version.to_vec());
+                    }
+                    _ => {
+                        return Err(HgError::abort(
+                            format!(
+                                "changegroup version {} does not have a \
+                                known bundlespec",
+                                String::from_utf8_lossy(version)
+                            ),
+                            crate::exit_codes::ABORT,
+                            Some("try upgrading your Mercurial client".into()),
+                        ));
+                    }
+                }
+                if let Some(compression) = header.param("delta-compression") {
+                    params.insert("cg.delta-compression", compression.into());
+                }
+                has_changegroup = true;
+            }
+            "stream2" if !has_changegroup => {
+                let requirements = url_unquote(
+                    header.param("requirements").unwrap_or_default(),
+                );
+                let requirements = String::from_utf8_lossy(&requirements);
+                let mut spec = format!(
+                    "none-v2;stream=v2;{}",
+                    requirements_spec_param(requirements.split_whitespace())
+                );
+                if let Some(fingerprint) = header.param("store-fingerprint") {
+                    spec.push_str(";store-fingerprint=");
+                    spec.push_str(&String::from_utf8_lossy(fingerprint));
+                }
+                return Ok(spec);
+            }
+            "obsmarkers" => {
+                params.insert("obsolescence", b"yes".to_vec());
+                if !header.mandatory {
+                    params.insert("obsolescence-mandatory", b"no".to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+    if !has_changegroup {
+        params.insert("changegroup", b"no".to_vec());
+    }
+    let mut spec = format!("{}-v2", compression);
+    for (name, value) in params {
+        let value = if name == "cg.delta-compression" {
+            value
+                .split(|&byte| byte == b',')
+                .map(url_quote)
+                .collect::<Vec<_>>()
+                .join(&b',')
+        } else {
+            url_quote(&value)
+        };
+        spec.push_str(&format!(
+            ";{}={}",
+            name,
+            String::from_utf8_lossy(&value)
+        ));
+    }
+    Ok(spec)
+}
+
+/// The default values of the boolean parameters of a bundle specification
+type SpecDefaults = &'static [(&'static str, bool)];
+
+/// The versions of bundle specifications, with the version of their
+/// changegroup and the default value of their boolean parameters
+const SPEC_VERSIONS: &[(&str, &str, SpecDefaults)] = &[
+    (
+        "v1",
+        "01",
+        &[
+            ("changegroup", true),
+            ("obsolescence", false),
+            ("phases", false),
+            ("tagsfnodescache", false),
+            ("revbranchcache", false),
+        ],
+    ),
+    (
+        "v2",
+        "02",
+        &[
+            ("changegroup", true),
+            ("obsolescence", false),
+            ("phases", false),
+            ("tagsfnodescache", true),
+            ("revbranchcache", true),
+        ],
+    ),
+    // Legacy name of `v2`
+    (
+        "bundle2",
+        "02",
+        &[
+            ("changegroup", true),
+            ("obsolescence", false),
+            ("phases", false),
+            ("tagsfnodescache", true),
+            ("revbranchcache", true),
+        ],
+    ),
+    (
+        "v3",
+        "03",
+        &[
+            ("changegroup", true),
+            ("obsolescence", false),
+            ("phases", true),
+            ("tagsfnodescache", true),
+            ("revbranchcache", true),
+        ],
+    ),
+    (
+        "streamv2",
+        "02",
+        &[
+            ("changegroup", false),
+            ("obsolescence", false),
+            ("phases", false),
+            ("tagsfnodescache", false),
+            ("revbranchcache", false),
+        ],
+    ),
+    (
+        "streamv3-exp",
+        "03",
+        &[
+            ("changegroup", false),
+            ("obsolescence", false),
+            ("phases", false),
+            ("tagsfnodescache", false),
+            ("revbranchcache", false),
+        ],
+    ),
+    ("packed1", "s1", &[]),
+];
+
+/// The parameters of bundle specifications whose value is a boolean
+const BOOLEAN_SPEC_PARAMS: &[&str] = &[
+    "obsolescence",
+    "obsolescence-mandatory",
+    "phases",
+    "changegroup",
+    "tagsfnodescache",
+    "revbranchcache",
+];
+
+/// A bundle specification like `gzip-v2;phases=yes`, telling how a bundle is
+/// compressed and what it contains.
+///
+/// Equivalent to `bundlespec` in Python.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleSpec {
+    pub compression: BundleCompression,
+    /// `v1`, `v2`, `v3` or `packed1`
+    pub version: String,
+    /// The parameters given explicitly, by name
+    pub params: BTreeMap<String, String>,
+}
+
+impl BundleSpec {
+    /// Parses a specification in which the compression or the version may
+    /// be left out, defaulting to what suits a repository with
+    /// `requirements`, like `parsebundlespec` with `strict=False` in Python.
+    pub fn parse(
+        spec: &str,
+        requirements: &FastHashSet<String>,
+    ) -> Result<Self, HgError> {
+        let unsupported = |message: String| {
+            HgError::abort(message, exit_codes::INPUT_ERROR, None)
+        };
+        let pre_params = spec.split(';').next().unwrap_or_default();
+        let (compression, version, params) = if pre_params.contains('-') {
+            let (compression, version) =
+                spec.split_once('-').expect("checked above");
+            let compression = BundleCompression::from_spec_name(compression)
+                .ok_or_else(|| {
+                    unsupported(format!(
+                        "{} compression is not supported",
+                        compression
+                    ))
+                })?;
+            let (version, params) = parse_spec_params(version)?;
+            if spec_version(version).is_none() {
+                return Err(unsupported(format!(
+                    "{} is not a recognized bundle version",
+                    version
+                )));
+            }
+            (compression, version, params)
+        } else {
+            let (spec, params) = parse_spec_params(spec)?;
+            if let Some(compression) = BundleCompression::from_spec_name(spec) {
+                let version = if requirements.contains(GENERALDELTA_REQUIREMENT)
+                    || requirements.contains(REVLOGV2_REQUIREMENT)
+                    || !V1_COMPRESSIONS.contains(&compression)
+                {
+                    "v2"
+                } else {
+                    "v1"
+                };
+                (compression, version, params)
+            } else if spec_version(spec).is_some() {
+                let compression = if spec == "packed1" {
+                    BundleCompression::None
+                } else {
+                    BundleCompression::Bzip2
+                };
+                (compression, spec, params)
+            } else {
+                return Err(unsupported(format!(
+                    "{} is not a recognized bundle specification",
+                    spec
+                )));
+            }
+        };
+        if version == "v1" && !V1_COMPRESSIONS.contains(&compression) {
+            return Err(unsupported(format!(
+                "compression engine {} is not supported on v1 bundles",
+                compression.spec_name()
+            )));
+        }
+        Ok(Self { compression, version: version.to_owned(), params })
+    }
+
+    /// The version whose content this specification follows by default,
+    /// which `stream` overrides
+    fn content_version(&self) -> &str {
+        match self.params.get("stream").map(String::as_str) {
+            Some("v2") => "streamv2",
+            Some("v3-exp") => "streamv3-exp",
+            _ => &self.version,
+        }
+    }
+
+    /// The version of the changegroup, `None` for `packed1` bundles
+    pub fn changegroup_version(&self) -> Option<ChangegroupVersion> {
+        let (cg_version, _) =
+            spec_version(&self.version).expect("checked when parsing");
+        ChangegroupVersion::from_bytes(cg_version.as_bytes())
+    }
+
+    /// The value of a boolean parameter, explicit or by default
+    pub fn flag(&self, name: &str) -> bool {
+        match self.params.get(name) {
+            Some(value) => parse_bool(value.as_bytes()).unwrap_or(false),
+            None => spec_version(self.content_version())
+                .and_then(|(_, defaults)| {
+                    defaults.iter().find(|(param, _)| *param == name)
+                })
+                .is_some_and(|&(_, value)| value),
+        }
+    }
+
+    /// Sets a boolean parameter, unless given explicitly
+    pub fn set_default_flag(&mut self, name: &str, value: bool) {
+        self.params
+            .entry(name.to_owned())
+            .or_insert_with(|| if value { "yes" } else { "no" }.to_owned());
+    }
+}
+
+/// The compressions that version 1 bundles can use
+const V1_COMPRESSIONS: &[BundleCompression] = &[
+    BundleCompression::None,
+    BundleCompression::Bzip2,
+    BundleCompression::Gzip,
+];
+
+fn spec_version(version: &str) -> Option<(&'static str, SpecDefaults)> {
+    SPEC_VERSIONS
+        .iter()
+        .find(|(name, _, _)| *name == version)
+        .map(|&(_, cg_version, defaults)| (cg_version, defaults))
+}
+
+/// Splits the `;` separated parameters, URL-encoded, from what precedes
+/// them in a bundle specification
+fn parse_spec_params(
+    spec: &str,
+) -> Result<(&str, BTreeMap<String, String>), HgError> {
+    let mut params = BTreeMap::new();
+    let Some((before, params_str)) = spec.split_once(';') else {
+        return Ok((spec, params));
+    };
+    for param in params_str.split(';') {
+        let Some((key, value)) = param.split_once('=') else {
+            return Err(HgError::abort_simple(format!(
+                "invalid bundle specification: missing \"=\" in parameter: {}",
+                param
+            )));
+        };
+        let key =
+            String::from_utf8_lossy(&url_unquote(key.as_bytes())).into_owned();
+        let value = String::from_utf8_lossy(&url_unquote(value.as_bytes()))
+            .into_owned();
+        if BOOLEAN_SPEC_PARAMS.contains(&key.as_str())
+            && parse_bool(value.as_bytes()).is_none()
+        {
+            return Err(HgError::abort_simple(format!(
+                "parameter {} should be a boolean ('{}')",
+                key, value
+            )));
+        }
+        params.insert(key, value);
+    }
+    Ok((before, params))
+}
+
+/// Writes a bundle of the changesets of `outgoing` to `out`, with the
+/// content and the compression of `spec`. `level` is the compression level,
+/// the default one of the compression if `None`.
+///
+/// Obsolescence markers are not supported, and stream clone bundles are
+/// written by [`write_stream_bundle`] instead.
+///
+/// Equivalent to `bundle2.writenewbundle` in Python.
+pub fn write_bundle<W: Write>(
+    repo: &Repo,
+    outgoing: &Outgoing,
+    spec: &BundleSpec,
+    level: Option<i32>,
+    mut out: W,
+) -> Result<W, HgError> {
+    if spec.params.contains_key("stream") {
+        return Err(HgError::unsupported("writing stream clone bundles"));
+    }
+    let Some(version) = spec.changegroup_version() else {
+        return Err(HgError::unsupported("writing packed bundles"));
+    };
+    if spec.flag("obsolescence") {
+        let obsstore = repo.store_vfs().try_read("obsstore")?;
+        if obsstore.is_some_and(|markers| !markers.is_empty()) {
+            return Err(HgError::unsupported("bundling obsolescence markers"));
+        }
+    }
+
+    if version == ChangegroupVersion::V1 {
+        write_all(&mut out, b"HG10")?;
+        // The "BZ" magic of the bzip2 stream doubles as the compression type
+        if spec.compression != BundleCompression::Bzip2 {
+            write_all(&mut out, spec.compression.bundle_type().as_bytes())?;
+        }
+        let mut out =
+            CompressedWriter::with_level(out, spec.compression, level)?;
+        write_changegroup(repo, outgoing, version, false, &mut out)?;
+        return out.finish();
+    }
+
+    let changelog = repo.changelog()?;
+    let index = changelog.get_index();
+    let mut bundle =
+        Bundle2Writer::with_level(out, &[], spec.compression, level)?;
+
+    if spec.flag("changegroup") {
+        let mut header =
+            parts::changegroup_header(version, outgoing.missing.len());
+        if spec.flag("phases") {
+            let phases = repo
+                .store()
+                .phase_cache()?
+                .phases(index)
+                .map_err(RevlogError::from)?;
+            let target_phase = outgoing
+                .ancestors_of
+                .iter()
+                .map(|rev| phases[rev.0 as usize])
+                .fold(Phase::Draft, Phase::max);
+            if target_phase > Phase::Draft {
+                header.add_param(
+                    "targetphase",
+                    target_phase.number().to_string(),
+                    false,
+                );
+            }
+        }
+        let mut part = bundle.start_part(header)?;
+        write_changegroup(repo, outgoing, version, false, &mut part)?;
+        part.finish()?;
+    }
+
+    if spec.flag("tagsfnodescache") {
+        let data = tags_fnodes_payload(repo, &outgoing.ancestors_of)?;
+        if !data.is_empty() {
+            bundle.add_part(PartHeader::new("hgtagsfnodes", false), &data)?;
+        }
+    }
+
+    if spec.flag("revbranchcache") {
+        bundle.add_part(
+            PartHeader::new("cache:rev-branch-cache", false),
+            &rev_branch_cache_payload(repo, &outgoing.missing)?,
+        )?;
+    }
+
+    if spec.flag("phases") {
+        let phase_cache = repo.store().phase_cache()?;
+        let mut heads = vec![];
+        for (phase, revs) in phase_cache
+            .subset_phase_heads(index, &outgoing.missing)
+            .map_err(RevlogError::from)?
+        {
+            for rev in revs {
+                heads.push((phase, *changelog.node_from_rev(rev)));
+            }
+        }
+        bundle.add_part(
+            PartHeader::new("phase-heads", true),
+            &parts::encode_phase_heads(&heads),
+        )?;
+    }
+
+    bundle.finish()
+}
+
+/// Writes a bundle2 holding the stream clone of `generator`, listed from
+/// `repo`, in `version` to `out`. Stream clone bundles are never compressed.
+///
+/// Equivalent to `bundle2.writenewbundle` with a `stream` specification in
+/// Python.
+pub fn write_stream_bundle<W: Write>(
+    repo: &Repo,
+    generator: &StreamGenerator,
+    version: StreamVersion,
+    out: W,
+) -> Result<W, HgError> {
+    let mut bundle = Bundle2Writer::new(out, &[], BundleCompression::None)?;
+    add_stream_part(&mut bundle, repo, generator, version)?;
+    bundle.finish()
+}
+
+/// Adds a `stream2` or `stream3-exp` part holding the stream clone of
+/// `generator`, listed from `repo`, in `version` to `bundle`
+pub fn add_stream_part<W: Write>(
+    bundle: &mut Bundle2Writer<W>,
+    repo: &Repo,
+    generator: &StreamGenerator,
+    version: StreamVersion,
+) -> Result<(), HgError> {
+    let requirements = streamed_requirements(repo.requirements());
+    match version {
+        StreamVersion::V2 => {
+            let header = parts::Stream2Params {
+                requirements,
+                file_count: generator.file_count(),
+                byte_count: generator.byte_count(),
+            }
+            .to_header();
+            let mut part = bundle.start_part(header)?;
+            generator.write_v2(&mut part)?;
+            part.finish()
+        }
+        StreamVersion::V3Exp => {
+            let header = parts::Stream3Params { requirements }.to_header();
+            let mut part = bundle.start_part(header)?;
+            generator.write_v3(&mut part)?;
+            part.finish()
+        }
+    }
+}
+
+/// The payload of an `hgtagsfnodes` part with the cached `.hgtags`
+/// filenodes of `heads`, empty if none is cached, like
+/// `addparttagsfnodescache` in Python
+pub fn tags_fnodes_payload(
+    repo: &Repo,
+    heads: &[Revision],
+) -> Result<Vec<u8>, HgError> {
+    let fnodes = cached_tags_fnodes(repo, heads)?;
+    let mut data = Vec::with_capacity(fnodes.len() * 40);
+    for (node, fnode) in fnodes {
+        data.extend_from_slice(node.as_bytes());
+        data.extend_from_slice(fnode.as_bytes());
+    }
+    Ok(data)
+}
+
+/// The payload of a `cache:rev-branch-cache` part with the branches of
+/// `revs`, like `addpartrevbranchcache` in Python
+pub fn rev_branch_cache_payload(
+    repo: &Repo,
+    revs: &[Revision],
+) -> Result<Vec<u8>, HgError> {
+    let changelog = repo.changelog()?;
+    let mut branches: BTreeMap<Vec<u8>, parts::BranchNodes> = BTreeMap::new();
+    for &rev in revs {
+        let node = *changelog.node_from_rev(rev);
+        let data = changelog.entry(rev)?.data()?;
+        let extra = data.extra()?;
+        let branch = extra
+            .get("branch")
+            .map_or_else(|| b"default".to_vec(), |branch| branch.to_vec());
+        let nodes = branches.entry(branch.clone()).or_insert_with(|| {
+            parts::BranchNodes { branch, open: vec![], closed: vec![] }
+        });
+        if extra.contains_key("close") {
+            nodes.closed.push(node);
+        } else {
+            nodes.open.push(node);
+        }
+    }
+    let mut branches: Vec<_> = branches.into_values().collect();
+    for branch in &mut branches {
+        branch.open.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        branch.closed.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    }
+    Ok(parts::encode_rev_branch_cache(&branches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirements(names: &[&str]) -> FastHashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_bundle_spec() {
+        let gd = requirements(&[GENERALDELTA_REQUIREMENT]);
+        let spec = BundleSpec::parse("bzip2", &gd).unwrap();
+        assert_eq!(spec.compression, BundleCompression::Bzip2);
+        assert_eq!(spec.version, "v2");
+        assert_eq!(spec.changegroup_version(), Some(ChangegroupVersion::V2));
+        assert!(spec.flag("revbranchcache"));
+        assert!(!spec.flag("phases"));
+
+        let spec = BundleSpec::parse("gzip", &requirements(&[])).unwrap();
+        assert_eq!(spec.version, "v1");
+        let spec = BundleSpec::parse("zstd", &requirements(&[])).unwrap();
+        assert_eq!(spec.version, "v2");
+
+        let spec = BundleSpec::parse("v3", &gd).unwrap();
+        assert_eq!(spec.compression, BundleCompression::Bzip2);
+        assert_eq!(spec.changegroup_version(), Some(ChangegroupVersion::V3));
+        assert!(spec.flag("phases"));
+
+        let mut spec =
+            BundleSpec::parse("none-v2;phases=yes;revbranchcache=0", &gd)
+                .unwrap();
+        assert_eq!(spec.compression, BundleCompression::None);
+        assert!(spec.flag("phases"));
+        assert!(!spec.flag("revbranchcache"));
+        spec.set_default_flag("phases", false);
+        spec.set_default_flag("obsolescence", true);
+        assert!(spec.flag("phases"));
+        assert!(spec.flag("obsolescence"));
+
+        let spec = BundleSpec::parse("packed1", &gd).unwrap();
+        assert_eq!(spec.compression, BundleCompression::None);
+        assert_eq!(spec.changegroup_version(), None);
+    }
+
+    #[test]
+    fn test_parse_invalid_bundle_spec() {
+        let gd = requirements(&[GENERALDELTA_REQUIREMENT]);
+        for spec in [
+            "foo",
+            "lzma-v2",
+            "gzip-v9",
+            "zstd-v1",
+            "gzip-v2;phases",
+            "gzip-v2;phases=maybe",
+        ] {
+            assert!(BundleSpec::parse(spec, &gd).is_err(), "{}", spec);
+        }
+    }
+}
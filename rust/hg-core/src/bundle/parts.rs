@@ -0,0 +1,550 @@
+//! The common bundle2 parts and the binary formats of their payloads
+//!
+//! This is a Rust counterpart to the part generators of `mercurial.bundle2`
+//! and to the binary encodings of `mercurial.phases`, `mercurial.bookmarks`
+//! and `mercurial.obsolete` they rely on.
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+
+use super::bundle2::PartHeader;
+use super::bundle2::unknown_feature;
+use crate::Node;
+use crate::changegroup::ChangegroupVersion;
+use crate::errors::HgError;
+use crate::revlog::index::Phase;
+use crate::revlog::node::NODE_BYTES_LENGTH;
+use crate::utils::strings::url_quote;
+use crate::utils::strings::url_unquote;
+
+/// The node standing for a missing bookmark in a `bookmarks` part
+const MISSING_BOOKMARK_NODE: [u8; NODE_BYTES_LENGTH] =
+    [0xff; NODE_BYTES_LENGTH];
+
+/// The obsolescence markers format used in `obsmarkers` parts
+pub const OBSMARKERS_VERSION: u8 = 1;
+
+/// Obsolescence marker flag for markers using SHA-256 nodes
+const OBSMARKER_USING_SHA256: u16 = 1 << 1;
+
+/// Marker of "no parents information" in the parents count of obsolescence
+/// markers
+const OBSMARKER_NO_PARENTS: u8 = 3;
+
+/// Splits `length` bytes off the start of `data`, calling `error` if it is
+/// too short
+fn take<'a>(
+    data: &mut &'a [u8],
+    length: usize,
+    error: impl FnOnce() -> HgError,
+) -> Result<&'a [u8], HgError> {
+    if data.len() < length {
+        return Err(error());
+    }
+    let (taken, rest) = data.split_at(length);
+    *data = rest;
+    Ok(taken)
+}
+
+fn node(bytes: &[u8]) -> Node {
+    Node::try_from(bytes).expect("node-sized slice")
+}
+
+/// Returns the header of a `changegroup` part
+pub fn changegroup_header(
+    version: ChangegroupVersion,
+    changesets: usize,
+) -> PartHeader {
+    let mut header = PartHeader::new("changegroup", true);
+    header.add_param("version", version.as_bytes(), true);
+    header.add_param("nbchanges", changesets.to_string(), false);
+    header
+}
+
+/// Returns the version of the changegroup in a `changegroup` part
+pub fn changegroup_version(
+    header: &PartHeader,
+) -> Result<ChangegroupVersion, HgError> {
+    let version = header.param("version").unwrap_or(b"01");
+    ChangegroupVersion::from_bytes(version).ok_or_else(|| {
+        unknown_feature(Some(&header.part_type), &[("version", Some(version))])
+    })
+}
+
+/// Encodes the payload of a `phase-heads` part
+pub fn encode_phase_heads(heads: &[(Phase, Node)]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(heads.len() * (4 + NODE_BYTES_LENGTH));
+    for (phase, head) in heads {
+        data.extend_from_slice(&phase.number().to_be_bytes());
+        data.extend_from_slice(head.as_bytes());
+    }
+    data
+}
+
+/// Decodes the payload of a `phase-heads` part
+pub fn decode_phase_heads(
+    mut data: &[u8],
+) -> Result<Vec<(Phase, Node)>, HgError> {
+    let error = || HgError::abort_simple("bad phase-heads stream");
+    let mut heads = vec![];
+    while !data.is_empty() {
+        let entry = take(&mut data, 4 + NODE_BYTES_LENGTH, error)?;
+        let phase = BigEndian::read_u32(entry) as usize;
+        let phase = Phase::try_from(phase).map_err(|_| error())?;
+        heads.push((phase, node(&entry[4..])));
+    }
+    Ok(heads)
+}
+
+/// A bookmark name and its target, `None` standing for a bookmark to delete
+pub type BookmarkChange = (Vec<u8>, Option<Node>);
+
+/// Encodes the payload of a `bookmarks` part, `None` standing for a
+/// bookmark to delete
+pub fn encode_bookmarks(bookmarks: &[(&[u8], Option<Node>)]) -> Vec<u8> {
+    let mut data = vec![];
+    for (name, node) in bookmarks {
+        match node {
+            Some(node) => data.extend_from_slice(node.as_bytes()),
+            None => data.extend_from_slice(&MISSING_BOOKMARK_NODE),
+        }
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(name);
+    }
+    data
+}
+
+/// Decodes the payload of a `bookmarks` part
+pub fn decode_bookmarks(
+    mut data: &[u8],
+) -> Result<Vec<BookmarkChange>, HgError> {
+    let error = || HgError::abort_simple("bad bookmark stream");
+    let mut bookmarks = vec![];
+    while !data.is_empty() {
+        let entry = take(&mut data, NODE_BYTES_LENGTH + 2, error)?;
+        let length = BigEndian::read_u16(&entry[NODE_BYTES_LENGTH..]);
+        let name = take(&mut data, length.into(), error)?;
+        let node = &entry[..NODE_BYTES_LENGTH];
+        let node = (node != MISSING_BOOKMARK_NODE).then(|| self::node(node));
+        bookmarks.push((name.to_vec(), node));
+    }
+    Ok(bookmarks)
+}
+
+/// An obsolescence marker, recording that `predecessor` was rewritten into
+/// `successors`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObsMarker {
+    pub predecessor: Node,
+    /// Empty if the predecessor was pruned
+    pub successors: Vec<Node>,
+    pub flags: u16,
+    pub metadata: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Seconds since the epoch, and timezone offset in seconds west of UTC
+    pub date: (f64, i32),
+    /// The parents of the predecessor, if recorded
+    pub parents: Option<Vec<Node>>,
+}
+
+impl ObsMarker {
+    /// Compares markers like Python compares their tuples
+    pub fn python_cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let nodes = |nodes: &[Node]| -> Vec<Vec<u8>> {
+            nodes.iter().map(|node| node.as_bytes().to_vec()).collect()
+        };
+        let key = |marker: &Self| {
+            (
+                marker.predecessor.as_bytes().to_vec(),
+                nodes(&marker.successors),
+                marker.flags,
+                marker.metadata.clone(),
+            )
+        };
+        key(self)
+            .cmp(&key(other))
+            .then(self.date.0.total_cmp(&other.date.0))
+            .then(self.date.1.cmp(&other.date.1))
+            .then_with(|| {
+                let parents =
+                    |marker: &Self| marker.parents.as_deref().map(nodes);
+                parents(self).cmp(&parents(other))
+            })
+    }
+}
+
+/// Decodes the payload of an `obsmarkers` part, which starts with the
+/// version of its format. Only [`OBSMARKERS_VERSION`] is supported.
+pub fn decode_obsmarkers(data: &[u8]) -> Result<Vec<ObsMarker>, HgError> {
+    let error = || {
+        HgError::abort_simple("parsing obsolete marker: truncated marker data")
+    };
+    let Some((&version, mut data)) = data.split_first() else {
+        return Ok(vec![]);
+    };
+    if version != OBSMARKERS_VERSION {
+        return Err(HgError::unsupported(format!(
+            "parsing obsolete marker: unknown version {}",
+            version
+        )));
+    }
+    let mut markers = vec![];
+    while !data.is_empty() {
+        let fixed = take(&mut data, 19, error)?;
+        let size = BigEndian::read_u32(fixed) as usize;
+        let seconds = BigEndian::read_f64(&fixed[4..]);
+        let timezone = BigEndian::read_i16(&fixed[12..]);
+        let flags = BigEndian::read_u16(&fixed[14..]);
+        let (successors_count, parents_count, metadata_count) =
+            (fixed[16], fixed[17], fixed[18]);
+        if flags & OBSMARKER_USING_SHA256 != 0 {
+            return Err(HgError::unsupported(
+                "obsolescence markers with SHA-256 nodes",
+            ));
+        }
+        let mut marker_data = take(&mut data, size.saturating_sub(19), error)?;
+        let mut nodes = |count: u8| -> Result<Vec<Node>, HgError> {
+            let bytes = take(
+                &mut marker_data,
+                NODE_BYTES_LENGTH * usize::from(count),
+                error,
+            )?;
+            Ok(bytes.chunks(NODE_BYTES_LENGTH).map(node).collect())
+        };
+        let predecessor = nodes(1)?[0];
+        let successors = nodes(successors_count)?;
+        let parents = if parents_count == OBSMARKER_NO_PARENTS {
+            None
+        } else {
+            Some(nodes(parents_count)?)
+        };
+        let sizes =
+            take(&mut marker_data, 2 * usize::from(metadata_count), error)?;
+        let mut metadata = vec![];
+        for size in sizes.chunks(2) {
+            let key = take(&mut marker_data, size[0].into(), error)?;
+            let value = take(&mut marker_data, size[1].into(), error)?;
+            metadata.push((key.to_vec(), value.to_vec()));
+        }
+        markers.push(ObsMarker {
+            predecessor,
+            successors,
+            flags,
+            metadata,
+            date: (seconds, i32::from(timezone) * 60),
+            parents,
+        });
+    }
+    Ok(markers)
+}
+
+/// Encodes the payload of an `obsmarkers` part
+pub fn encode_obsmarkers(markers: &[ObsMarker]) -> Result<Vec<u8>, HgError> {
+    let mut data = vec![OBSMARKERS_VERSION];
+    for marker in markers {
+        let too_long = |what: &str| {
+            HgError::abort_simple(format!(
+                "too many {} in obsolescence marker for {:x}",
+                what, marker.predecessor
+            ))
+        };
+        let count = |length: usize, what| {
+            u8::try_from(length).map_err(|_| too_long(what))
+        };
+        let parents_count = match &marker.parents {
+            Some(parents) => count(parents.len(), "parents")?,
+            None => OBSMARKER_NO_PARENTS,
+        };
+        let mut fixed = [0; 19];
+        BigEndian::write_f64(&mut fixed[4..], marker.date.0);
+        BigEndian::write_i16(&mut fixed[12..], (marker.date.1 / 60) as i16);
+        BigEndian::write_u16(&mut fixed[14..], marker.flags);
+        fixed[16] = count(marker.successors.len(), "successors")?;
+        fixed[17] = parents_count;
+        fixed[18] = count(marker.metadata.len(), "metadata entries")?;
+        let mut encoded = fixed.to_vec();
+        let parents = marker.parents.iter().flatten();
+        for node in std::iter::once(&marker.predecessor)
+            .chain(&marker.successors)
+            .chain(parents)
+        {
+            encoded.extend_from_slice(node.as_bytes());
+        }
+        for (key, value) in &marker.metadata {
+            encoded.push(count(key.len(), "metadata key bytes")?);
+            encoded.push(count(value.len(), "metadata value bytes")?);
+        }
+        for (key, value) in &marker.metadata {
+            encoded.extend_from_slice(key);
+            encoded.extend_from_slice(value);
+        }
+        let size = encoded.len() as u32;
+        BigEndian::write_u32(&mut encoded, size);
+        data.extend(encoded);
+    }
+    Ok(data)
+}
+
+/// The changesets of a branch in a `cache:rev-branch-cache` part
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchNodes {
+    pub branch: Vec<u8>,
+    pub open: Vec<Node>,
+    /// The changesets closing the branch
+    pub closed: Vec<Node>,
+}
+
+/// Encodes the payload of a `cache:rev-branch-cache` part
+pub fn encode_rev_branch_cache(branches: &[BranchNodes]) -> Vec<u8> {
+    let mut data = vec![];
+    for branch in branches {
+        for length in
+            [branch.branch.len(), branch.open.len(), branch.closed.len()]
+        {
+            data.extend_from_slice(&(length as u32).to_be_bytes());
+        }
+        data.extend_from_slice(&branch.branch);
+        for node in branch.open.iter().chain(&branch.closed) {
+            data.extend_from_slice(node.as_bytes());
+        }
+    }
+    data
+}
+
+/// Decodes the payload of a `cache:rev-branch-cache` part
+pub fn decode_rev_branch_cache(
+    mut data: &[u8],
+) -> Result<Vec<BranchNodes>, HgError> {
+    let error = || HgError::abort_simple("bad rev-branch-cache stream");
+    let mut branches = vec![];
+    while !data.is_empty() {
+        let lengths = take(&mut data, 12, error)?;
+        let length =
+            |index: usize| BigEndian::read_u32(&lengths[index * 4..]) as usize;
+        let branch = take(&mut data, length(0), error)?.to_vec();
+        let mut nodes = |count: usize| -> Result<Vec<Node>, HgError> {
+            let bytes = take(&mut data, count * NODE_BYTES_LENGTH, error)?;
+            Ok(bytes.chunks(NODE_BYTES_LENGTH).map(node).collect())
+        };
+        let open = nodes(length(1))?;
+        let closed = nodes(length(2))?;
+        branches.push(BranchNodes { branch, open, closed });
+    }
+    Ok(branches)
+}
+
+/// The parameters of a `stream2` part, whose payload is the store files of
+/// a stream clone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stream2Params {
+    pub requirements: Vec<String>,
+    pub file_count: u64,
+    pub byte_count: u64,
+}
+
+impl Stream2Params {
+    pub fn from_header(header: &PartHeader) -> Result<Self, HgError> {
+        header.check_params(&["requirements", "filecount", "bytecount"])?;
+        let param = |name| {
+            header.param(name).ok_or_else(|| {
+                HgError::abort_simple(format!(
+                    "missing '{}' parameter in stream2 part",
+                    name
+                ))
+            })
+        };
+        let count = |name| {
+            let value = param(name)?;
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| {
+                    HgError::abort_simple(format!(
+                        "invalid '{}' parameter in stream2 part: {}",
+                        name,
+                        String::from_utf8_lossy(value)
+                    ))
+                })
+        };
+        Ok(Self {
+            requirements: decode_requirements(param("requirements")?),
+            file_count: count("filecount")?,
+            byte_count: count("bytecount")?,
+        })
+    }
+
+    /// Returns the header of a `stream2` part with these parameters
+    pub fn to_header(&self) -> PartHeader {
+        let mut header = PartHeader::new("stream2", true);
+        header.add_param("bytecount", self.byte_count.to_string(), true);
+        header.add_param("filecount", self.file_count.to_string(), true);
+        header.add_param(
+            "requirements",
+            encode_requirements(&self.requirements),
+            true,
+        );
+        header
+    }
+}
+
+/// The parameters of a `stream3-exp` part, whose payload is the store files
+/// of a stream clone, counted in the payload itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stream3Params {
+    pub requirements: Vec<String>,
+}
+
+impl Stream3Params {
+    pub fn from_header(header: &PartHeader) -> Result<Self, HgError> {
+        header.check_params(&["requirements"])?;
+        let requirements = header.param("requirements").ok_or_else(|| {
+            HgError::abort_simple(
+                "missing 'requirements' parameter in stream3-exp part",
+            )
+        })?;
+        Ok(Self { requirements: decode_requirements(requirements) })
+    }
+
+    /// Returns the header of a `stream3-exp` part with these parameters
+    pub fn to_header(&self) -> PartHeader {
+        let mut header = PartHeader::new("stream3-exp", true);
+        header.add_param(
+            "requirements",
+            encode_requirements(&self.requirements),
+            true,
+        );
+        header
+    }
+}
+
+/// Decodes the `requirements` parameter of the stream clone parts
+fn decode_requirements(value: &[u8]) -> Vec<String> {
+    url_unquote(value)
+        .split(|&byte| byte == b',')
+        .filter(|requirement| !requirement.is_empty())
+        .map(|requirement| String::from_utf8_lossy(requirement).into())
+        .collect()
+}
+
+/// Encodes the `requirements` parameter of the stream clone parts, like
+/// `_formatrequirementsspec` in Python
+fn encode_requirements(requirements: &[String]) -> Vec<u8> {
+    let mut requirements: Vec<_> = requirements
+        .iter()
+        .map(String::as_str)
+        .filter(|requirement| *requirement != "shared")
+        .collect();
+    requirements.sort_unstable();
+    url_quote(requirements.join(",").as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(byte: u8) -> Node {
+        Node::from(&[byte; NODE_BYTES_LENGTH])
+    }
+
+    #[test]
+    fn test_phase_heads() {
+        let heads = [(Phase::Public, node(1)), (Phase::Archived, node(2))];
+        let data = encode_phase_heads(&heads);
+        assert_eq!(data.len(), 48);
+        assert_eq!(&data[24..28], [0, 0, 0, 32]);
+        assert_eq!(decode_phase_heads(&data).unwrap(), heads);
+        assert!(decode_phase_heads(&data[..47]).is_err());
+    }
+
+    #[test]
+    fn test_bookmarks() {
+        let bookmarks: [(&[u8], _); 2] =
+            [(b"book", Some(node(1))), (b"deleted", None)];
+        let data = encode_bookmarks(&bookmarks);
+        let decoded = decode_bookmarks(&data).unwrap();
+        assert_eq!(
+            decoded,
+            [(b"book".to_vec(), Some(node(1))), (b"deleted".to_vec(), None)]
+        );
+        assert!(decode_bookmarks(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_obsmarkers() {
+        let markers = [
+            ObsMarker {
+                predecessor: node(1),
+                successors: vec![node(2)],
+                flags: 0,
+                metadata: vec![(b"user".to_vec(), b"test".to_vec())],
+                date: (1234.5, -3600),
+                parents: None,
+            },
+            ObsMarker {
+                predecessor: node(3),
+                successors: vec![],
+                flags: 0,
+                metadata: vec![],
+                date: (0., 0),
+                parents: Some(vec![node(4)]),
+            },
+        ];
+        let data = encode_obsmarkers(&markers).unwrap();
+        assert_eq!(data[0], OBSMARKERS_VERSION);
+        // Fixed part, 2 nodes, 1 metadata entry of 4 + 4 bytes
+        assert_eq!(BigEndian::read_u32(&data[1..]), 19 + 40 + 2 + 8);
+        assert_eq!(decode_obsmarkers(&data).unwrap(), markers);
+        assert!(decode_obsmarkers(&data[..data.len() - 1]).is_err());
+        assert!(decode_obsmarkers(&[0]).is_err());
+    }
+
+    #[test]
+    fn test_rev_branch_cache() {
+        let branches = [
+            BranchNodes {
+                branch: b"default".to_vec(),
+                open: vec![node(1), node(2)],
+                closed: vec![node(3)],
+            },
+            BranchNodes {
+                branch: b"stable".to_vec(),
+                open: vec![],
+                closed: vec![node(4)],
+            },
+        ];
+        let data = encode_rev_branch_cache(&branches);
+        assert_eq!(decode_rev_branch_cache(&data).unwrap(), branches);
+        assert!(decode_rev_branch_cache(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_stream2_params() {
+        let params = Stream2Params {
+            requirements: vec!["revlogv1".into(), "generaldelta".into()],
+            file_count: 11,
+            byte_count: 1284,
+        };
+        let header = params.to_header();
+        assert_eq!(
+            header.param("requirements"),
+            Some(&b"generaldelta%2Crevlogv1"[..])
+        );
+        let decoded = Stream2Params::from_header(&header).unwrap();
+        assert_eq!(decoded.requirements, ["generaldelta", "revlogv1"]);
+        assert_eq!((decoded.file_count, decoded.byte_count), (11, 1284));
+    }
+
+    #[test]
+    fn test_stream3_params() {
+        let params = Stream3Params {
+            requirements: vec!["sparserevlog".into(), "revlogv1".into()],
+        };
+        let header = params.to_header();
+        assert_eq!(header.part_type, "stream3-exp");
+        assert_eq!(
+            header.param("requirements"),
+            Some(&b"revlogv1%2Csparserevlog"[..])
+        );
+        let decoded = Stream3Params::from_header(&header).unwrap();
+        assert_eq!(decoded.requirements, ["revlogv1", "sparserevlog"]);
+    }
+}
@@ -0,0 +1,986 @@
+//! Changegroups, the format used to exchange revisions between repositories
+//!
+//! This is a Rust counterpart to `mercurial.changegroup`.
+//!
+//! A changegroup is a stream of chunks, each prefixed by its length as a
+//! big-endian 32 bits integer which includes the 4 bytes of the length
+//! itself. An empty chunk closes a group of revisions. The groups come in
+//! order: the changelog, the root manifest, (for version `03`) a list of
+//! tree manifest directories, then a list of files. Each directory or file
+//! group is introduced by a chunk holding its path, and each list is closed
+//! by an empty chunk.
+//!
+//! Every revision is sent as a delta against a revision that the receiving
+//! side either already has or received earlier in the same group.
+
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+
+use crate::AncestorsIterator;
+use crate::FastHashMap;
+use crate::FastHashSet;
+use crate::Graph;
+use crate::NULL_REVISION;
+use crate::Node;
+use crate::Revision;
+use crate::discovery::Outgoing;
+use crate::errors::HgError;
+use crate::errors::HgIoError;
+use crate::errors::IoErrorContext;
+use crate::repo::Repo;
+use crate::revlog::REVIDX_DELTA_INFO_FLAGS;
+use crate::revlog::REVISION_FLAG_HASCOPIESINFO;
+use crate::revlog::Revlog;
+use crate::revlog::RevlogError;
+use crate::revlog::add_group::AddedRevision;
+use crate::revlog::add_group::RevlogGroupWriter;
+use crate::revlog::add_group::SplitIndex;
+use crate::revlog::inner_revlog::hash;
+use crate::revlog::patch;
+use crate::revlog::path_encode::PathEncoding;
+use crate::revlog::path_encode::path_encode;
+use crate::store::fncache::Fncache;
+use crate::transaction::StoreTransaction;
+use crate::utils::files::get_path_from_bytes;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+
+/// Flags describing how a revision is stored locally, which are meaningless
+/// to the receiving side
+const LOCAL_FLAGS: u16 = REVIDX_DELTA_INFO_FLAGS | REVISION_FLAG_HASCOPIESINFO;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangegroupVersion {
+    /// `01`: deltas are always against the previous revision of the group
+    V1,
+    /// `02`: every revision names its delta base (generaldelta)
+    V2,
+    /// `03`: adds the revlog flags and the tree manifests
+    V3,
+}
+
+impl ChangegroupVersion {
+    pub fn from_bytes(version: &[u8]) -> Option<Self> {
+        match version {
+            b"01" => Some(Self::V1),
+            b"02" => Some(Self::V2),
+            b"03" => Some(Self::V3),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Self::V1 => b"01",
+            Self::V2 => b"02",
+            Self::V3 => b"03",
+        }
+    }
+
+    /// Size of the fixed header preceding the delta of each revision
+    fn delta_header_size(self) -> usize {
+        match self {
+            Self::V1 => 80,
+            Self::V2 => 100,
+            Self::V3 => 102,
+        }
+    }
+
+    /// Whether the root manifest is followed by a list of directories
+    fn has_tree_manifests(self) -> bool {
+        self == Self::V3
+    }
+}
+
+/// The revlog a group of revisions belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RevlogTarget {
+    Changelog,
+    /// The manifest of a directory, which is empty for the root manifest and
+    /// ends with a slash otherwise
+    Manifest(HgPathBuf),
+    Filelog(HgPathBuf),
+}
+
+/// A revision as found in a changegroup
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevisionDelta {
+    pub node: Node,
+    pub p1: Node,
+    pub p2: Node,
+    /// The revision the delta applies to, [`crate::NULL_NODE`] for a full
+    /// text
+    pub delta_base: Node,
+    /// The changeset that introduced this revision
+    pub link_node: Node,
+    /// The revlog flags of the revision, always 0 before version `03`
+    pub flags: u16,
+    /// The delta in the binary format of [`patch`]
+    pub delta: Vec<u8>,
+}
+
+impl RevisionDelta {
+    /// Returns the data of this revision given the data of its delta base
+    pub fn apply(&self, base: &[u8]) -> Result<Vec<u8>, RevlogError> {
+        patch::apply_delta(base, &self.delta)
+    }
+
+    /// Returns a revision sent as its full text, for versions `02` and
+    /// later which tell the delta base
+    #[cfg(test)]
+    pub(crate) fn full_text(
+        node: Node,
+        p1: Node,
+        p2: Node,
+        link_node: Node,
+        text: &[u8],
+    ) -> Self {
+        Self {
+            node,
+            p1,
+            p2,
+            delta_base: crate::NULL_NODE,
+            link_node,
+            flags: 0,
+            delta: [&trivial_diff_header(text.len())[..], text].concat(),
+        }
+    }
+
+    /// Returns whether `data` hashes to the node of this revision
+    pub fn check_hash(&self, data: &[u8]) -> bool {
+        hash(data, self.p1.as_bytes(), self.p2.as_bytes())
+            == *self.node.as_bytes()
+    }
+
+    fn write_to(
+        &self,
+        version: ChangegroupVersion,
+        out: &mut impl Write,
+    ) -> Result<usize, HgError> {
+        let mut header = Vec::with_capacity(version.delta_header_size());
+        header.extend_from_slice(self.node.as_bytes());
+        header.extend_from_slice(self.p1.as_bytes());
+        header.extend_from_slice(self.p2.as_bytes());
+        if version != ChangegroupVersion::V1 {
+            header.extend_from_slice(self.delta_base.as_bytes());
+        }
+        header.extend_from_slice(self.link_node.as_bytes());
+        if version == ChangegroupVersion::V3 {
+            header.extend_from_slice(&self.flags.to_be_bytes());
+        }
+        write_chunk(out, &[&header, &self.delta])
+    }
+}
+
+/// Reads a changegroup out of a stream.
+///
+/// Equivalent to the `cg1unpacker` class and its subclasses in Python.
+pub struct ChangegroupUnpacker<R> {
+    reader: R,
+    version: ChangegroupVersion,
+    /// The last revision read in the current group, which is the delta base
+    /// of the next one in version `01`
+    previous_node: Option<Node>,
+}
+
+impl<R: Read> ChangegroupUnpacker<R> {
+    pub fn new(reader: R, version: ChangegroupVersion) -> Self {
+        Self { reader, version, previous_node: None }
+    }
+
+    pub fn version(&self) -> ChangegroupVersion {
+        self.version
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_exactly(&mut self, length: usize) -> Result<Vec<u8>, HgError> {
+        read_exactly(&mut self.reader, length)
+    }
+
+    /// Returns the length of the data of the next chunk, 0 for an empty
+    /// chunk
+    fn chunk_length(&mut self) -> Result<usize, HgError> {
+        let length = BigEndian::read_i32(&self.read_exactly(4)?);
+        if length <= 4 {
+            if length != 0 {
+                return Err(HgError::abort_simple(format!(
+                    "invalid chunk length {}",
+                    length
+                )));
+            }
+            return Ok(0);
+        }
+        Ok(length as usize - 4)
+    }
+
+    /// Returns the next revision of the current group, or `None` once the
+    /// group is over.
+    pub fn delta_chunk(&mut self) -> Result<Option<RevisionDelta>, HgError> {
+        let length = self.chunk_length()?;
+        if length == 0 {
+            self.previous_node = None;
+            return Ok(None);
+        }
+        let header_size = self.version.delta_header_size();
+        if length < header_size {
+            return Err(HgError::abort_simple(format!(
+                "invalid chunk length {}",
+                length + 4
+            )));
+        }
+        let header = self.read_exactly(header_size)?;
+        let delta = self.read_exactly(length - header_size)?;
+        let node_at = |index: usize| {
+            Node::try_from(&header[index * 20..(index + 1) * 20])
+                .expect("20 bytes slice")
+        };
+        let (node, p1, p2) = (node_at(0), node_at(1), node_at(2));
+        let (delta_base, link_node) = match self.version {
+            ChangegroupVersion::V1 => {
+                (self.previous_node.unwrap_or(p1), node_at(3))
+            }
+            ChangegroupVersion::V2 | ChangegroupVersion::V3 => {
+                (node_at(3), node_at(4))
+            }
+        };
+        let flags = match self.version {
+            ChangegroupVersion::V3 => BigEndian::read_u16(&header[100..]),
+            _ => 0,
+        };
+        self.previous_node = Some(node);
+        Ok(Some(RevisionDelta {
+            node,
+            p1,
+            p2,
+            delta_base,
+            link_node,
+            flags,
+            delta,
+        }))
+    }
+
+    /// Returns the path introducing the next group in a list of directories
+    /// or files, or `None` once the list is over.
+    pub fn group_header(&mut self) -> Result<Option<HgPathBuf>, HgError> {
+        let length = self.chunk_length()?;
+        if length == 0 {
+            return Ok(None);
+        }
+        Ok(Some(HgPathBuf::from_bytes(&self.read_exactly(length)?)))
+    }
+
+    /// Reads the rest of the changegroup, calling `visit` for every revision
+    /// in stream order.
+    pub fn unpack<E: From<HgError>>(
+        &mut self,
+        mut visit: impl FnMut(&RevlogTarget, RevisionDelta) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut visit_group =
+            |unpacker: &mut Self, target: RevlogTarget| -> Result<(), E> {
+                while let Some(delta) = unpacker.delta_chunk()? {
+                    visit(&target, delta)?;
+                }
+                Ok(())
+            };
+        visit_group(self, RevlogTarget::Changelog)?;
+        visit_group(self, RevlogTarget::Manifest(HgPathBuf::new()))?;
+        if self.version.has_tree_manifests() {
+            while let Some(directory) = self.group_header()? {
+                visit_group(self, RevlogTarget::Manifest(directory))?;
+            }
+        }
+        while let Some(path) = self.group_header()? {
+            visit_group(self, RevlogTarget::Filelog(path))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads exactly `length` bytes out of `reader`, aborting if the stream ends
+/// before that.
+///
+/// An [`HgError`] wrapped in an IO error by a reader, such as a bundle2 part
+/// interrupted by an error, is passed through as-is.
+pub(crate) fn read_exactly(
+    reader: &mut impl Read,
+    length: usize,
+) -> Result<Vec<u8>, HgError> {
+    let mut buffer = vec![0; length];
+    let mut read = 0;
+    while read < length {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => {
+                return Err(HgError::abort_simple(format!(
+                    "stream ended unexpectedly (got {} bytes, expected {})",
+                    read, length
+                )));
+            }
+            Ok(n) => read += n,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => {
+                return Err(match error.downcast::<HgError>() {
+                    Ok(error) => error,
+                    Err(error) => HgIoError::from_os_error(
+                        error,
+                        IoErrorContext::ReadingStream,
+                    )
+                    .into(),
+                });
+            }
+        }
+    }
+    Ok(buffer)
+}
+
+/// Writes a chunk made of the concatenation of `parts`, returning the number
+/// of bytes written
+fn write_chunk(
+    out: &mut impl Write,
+    parts: &[&[u8]],
+) -> Result<usize, HgError> {
+    let length: usize = parts.iter().map(|part| part.len()).sum();
+    let header = i32::try_from(length + 4)
+        .map_err(|_| HgError::abort_simple("changegroup chunk too large"))?;
+    write_all(out, &header.to_be_bytes())?;
+    for part in parts {
+        write_all(out, part)?;
+    }
+    Ok(length + 4)
+}
+
+/// Writes the empty chunk closing a group, returning the number of bytes
+/// written
+fn close_chunk(out: &mut impl Write) -> Result<usize, HgError> {
+    write_all(out, &0i32.to_be_bytes())?;
+    Ok(4)
+}
+
+/// Writes all of `data` to `out`
+pub(crate) fn write_all(
+    out: &mut impl Write,
+    data: &[u8],
+) -> Result<(), HgError> {
+    out.write_all(data).map_err(|error| {
+        HgIoError::from_os_error(error, IoErrorContext::WritingStream).into()
+    })
+}
+
+/// How to pick the delta base of the revisions to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeltaMode {
+    /// Reuse the stored deltas whenever possible
+    Standard,
+    /// Always send a delta against the previous revision of the group
+    Previous,
+}
+
+/// Writes one group of revisions of `revlog`, given as `(revision, link
+/// node)` pairs sorted by revision. Returns the number of bytes written.
+///
+/// Equivalent to `revlog.emitrevisions` followed by
+/// `_revisiondeltatochunks` in Python, assuming the receiving side has the
+/// parents of the revisions to send.
+fn write_revisions(
+    revlog: &Revlog,
+    revisions: &[(Revision, Node)],
+    version: ChangegroupVersion,
+    out: &mut impl Write,
+) -> Result<usize, HgError> {
+    let mut size = 0;
+    let Some(&(first, _)) = revisions.first() else {
+        return Ok(size);
+    };
+    let mode = match version {
+        ChangegroupVersion::V1 => DeltaMode::Previous,
+        _ => DeltaMode::Standard,
+    };
+    let sent: FastHashSet<Revision> =
+        revisions.iter().map(|&(rev, _)| rev).collect();
+    let mut common_heads = FastHashSet::default();
+    for &(rev, _) in revisions {
+        common_heads.extend(revlog.parents(rev).map_err(RevlogError::from)?);
+    }
+    common_heads.retain(|rev| *rev != NULL_REVISION && !sent.contains(rev));
+    let mut available =
+        AncestorsIterator::new(revlog, common_heads, Revision(0), true)
+            .map_err(RevlogError::from)?;
+    let mut emitted = FastHashSet::default();
+    let mut previous = revlog.parents(first).map_err(RevlogError::from)?[0];
+
+    for &(rev, link_node) in revisions {
+        let [p1, p2] = revlog.parents(rev).map_err(RevlogError::from)?;
+        let delta_parent = revlog.delta_parent(rev);
+        let mut is_usable_base = |base: Revision| -> Result<bool, HgError> {
+            Ok(base != NULL_REVISION
+                && (emitted.contains(&base)
+                    || available.contains(base).map_err(RevlogError::from)?))
+        };
+        let base = match mode {
+            DeltaMode::Previous => previous,
+            DeltaMode::Standard => {
+                if is_usable_base(delta_parent)?
+                    || delta_parent == NULL_REVISION
+                {
+                    delta_parent
+                } else if is_usable_base(p1)? {
+                    p1
+                } else if is_usable_base(p2)? {
+                    p2
+                } else {
+                    previous
+                }
+            }
+        };
+        let delta = if base == NULL_REVISION && mode != DeltaMode::Previous {
+            let data = revlog.get_data(rev)?;
+            [&trivial_diff_header(data.len())[..], &data].concat()
+        } else {
+            revlog.rev_diff(base, rev)?
+        };
+        let entry = revlog.get_entry(rev)?;
+        let revision = RevisionDelta {
+            node: *entry.node(),
+            p1: *revlog.node_from_rev(p1),
+            p2: *revlog.node_from_rev(p2),
+            delta_base: *revlog.node_from_rev(base),
+            link_node,
+            flags: entry.flags() & !LOCAL_FLAGS,
+            delta,
+        };
+        size += revision.write_to(version, out)?;
+        emitted.insert(rev);
+        previous = rev;
+    }
+    Ok(size)
+}
+
+/// The header of a delta replacing nothing with `length` bytes, turning a
+/// full text into a delta against the empty revision
+fn trivial_diff_header(length: usize) -> [u8; 12] {
+    let mut header = [0; 12];
+    BigEndian::write_u32(&mut header[8..], length as u32);
+    header
+}
+
+/// Writes a changegroup of revisions given as is rather than read from
+/// revlogs: the changesets, the root manifests, then the revisions of each
+/// file. Returns the number of bytes written.
+#[cfg(test)]
+pub(crate) fn write_revision_groups(
+    version: ChangegroupVersion,
+    changelog: &[RevisionDelta],
+    manifests: &[RevisionDelta],
+    files: &[(&HgPath, &[RevisionDelta])],
+    out: &mut impl Write,
+) -> Result<usize, HgError> {
+    let mut size = 0;
+    for group in [changelog, manifests] {
+        for revision in group {
+            size += revision.write_to(version, out)?;
+        }
+        size += close_chunk(out)?;
+    }
+    if version.has_tree_manifests() {
+        size += close_chunk(out)?;
+    }
+    for (path, revisions) in files {
+        size += write_chunk(out, &[path.as_bytes()])?;
+        for revision in *revisions {
+            size += revision.write_to(version, out)?;
+        }
+        size += close_chunk(out)?;
+    }
+    Ok(size + close_chunk(out)?)
+}
+
+/// Writes a changegroup with the changesets of `outgoing.missing` and the
+/// manifest and file revisions they introduce to `out`. Returns the number
+/// of bytes written for each group.
+///
+/// `fast_path` tells that the link revision of every file revision to send
+/// is in `outgoing.missing`, so the filelogs can be walked without reading
+/// the manifests. This is always the case when sending all the heads of the
+/// repository.
+///
+/// Equivalent to `changegroup.makestream` in Python.
+pub fn write_changegroup(
+    repo: &Repo,
+    outgoing: &Outgoing,
+    version: ChangegroupVersion,
+    fast_path: bool,
+    out: &mut impl Write,
+) -> Result<Vec<(RevlogTarget, usize)>, HgError> {
+    let changelog = repo.changelog()?;
+    let manifestlog = repo.manifestlog()?;
+    let mut sizes = vec![];
+
+    let fast_path = fast_path || {
+        let mut heads = outgoing.ancestors_of.clone();
+        heads.sort_unstable();
+        let mut repo_heads =
+            changelog.get_index().head_revs().map_err(RevlogError::from)?;
+        repo_heads.sort_unstable();
+        heads == repo_heads
+    };
+
+    // The changelog, collecting the manifests and files to send along with
+    // the first changeset introducing each manifest
+    let mut changesets = Vec::with_capacity(outgoing.missing.len());
+    let mut manifests: FastHashMap<Node, Node> = FastHashMap::default();
+    let mut changed_files: FastHashSet<HgPathBuf> = FastHashSet::default();
+    for &rev in &outgoing.missing {
+        let node = *changelog.node_from_rev(rev);
+        let data = changelog.entry(rev)?.data()?;
+        manifests.entry(data.manifest_node()?).or_insert(node);
+        changed_files.extend(data.files().map(HgPath::to_owned));
+        changesets.push((rev, node));
+    }
+    let size = write_revisions(&changelog.revlog, &changesets, version, out)?
+        + close_chunk(out)?;
+    sizes.push((RevlogTarget::Changelog, size));
+
+    // The manifests, collecting the file revisions to send along with the
+    // first changeset introducing each of them
+    let order: FastHashMap<Node, usize> = changesets
+        .iter()
+        .enumerate()
+        .map(|(index, &(_, node))| (node, index))
+        .collect();
+    let mut manifest_revisions = vec![];
+    for (manifest_node, link_node) in manifests {
+        let rev = manifestlog.rev_for_node(manifest_node.into())?;
+        manifest_revisions.push((rev, link_node));
+    }
+    manifest_revisions.sort_unstable_by_key(|&(rev, _)| rev);
+    let mut file_link_nodes: FastHashMap<HgPathBuf, FastHashMap<Node, Node>> =
+        FastHashMap::default();
+    if !fast_path {
+        for &(rev, link_node) in &manifest_revisions {
+            let manifest = manifestlog.inexact_data_delta_parents(rev)?;
+            for entry in manifest.iter() {
+                let entry = entry?;
+                let link_nodes =
+                    file_link_nodes.entry(entry.path.to_owned()).or_default();
+                let current =
+                    link_nodes.entry(entry.node_id()?).or_insert(link_node);
+                if order[&link_node] < order[current] {
+                    *current = link_node;
+                }
+            }
+        }
+    }
+    let size = write_revisions(
+        &manifestlog.revlog,
+        &manifest_revisions,
+        version,
+        out,
+    )? + close_chunk(out)?;
+    sizes.push((RevlogTarget::Manifest(HgPathBuf::new()), size));
+    if version.has_tree_manifests() {
+        close_chunk(out)?;
+    }
+
+    // The files
+    let mut changed_files: Vec<_> = changed_files.into_iter().collect();
+    changed_files.sort_unstable();
+    let missing: FastHashSet<Revision> =
+        outgoing.missing.iter().copied().collect();
+    for path in changed_files {
+        let filelog = repo.filelog(&path)?;
+        if filelog.is_empty() {
+            return Err(HgError::abort_simple(format!(
+                "empty or missing file data for {}",
+                String::from_utf8_lossy(path.as_bytes())
+            )));
+        }
+        let revlog = &filelog.revlog;
+        let mut revisions = vec![];
+        if fast_path {
+            for rev in 0..revlog.len() as i32 {
+                let rev = Revision(rev);
+                let link_rev = revlog.link_revision(rev, &changelog.revlog)?;
+                if missing.contains(&link_rev) {
+                    revisions.push((rev, *changelog.node_from_rev(link_rev)));
+                }
+            }
+        } else if let Some(link_nodes) = file_link_nodes.remove(&path) {
+            for (file_node, link_node) in link_nodes {
+                revisions
+                    .push((revlog.rev_from_node(file_node.into())?, link_node));
+            }
+        }
+        // Skip the revisions the other side is known to have
+        let mut to_send = vec![];
+        for (rev, link_node) in revisions {
+            let link_rev = revlog.link_revision(rev, &changelog.revlog)?;
+            if !outgoing.common.contains(&link_rev) {
+                to_send.push((rev, link_node));
+            }
+        }
+        if to_send.is_empty() {
+            continue;
+        }
+        to_send.sort_unstable_by_key(|&(rev, _)| rev);
+        let size = write_chunk(out, &[path.as_bytes()])?
+            + write_revisions(revlog, &to_send, version, out)?
+            + close_chunk(out)?;
+        sizes.push((RevlogTarget::Filelog(path), size));
+    }
+    close_chunk(out)?;
+    Ok(sizes)
+}
+
+/// The steps of [`apply_changegroup`], reported as they start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyStage {
+    Changesets,
+    Manifests,
+    Files,
+}
+
+/// What [`apply_changegroup`] added to a repository
+#[derive(Debug, Clone, Default)]
+pub struct AppliedChangegroup {
+    /// The new changesets, by increasing revision number
+    pub changesets: Vec<Revision>,
+    /// The changesets of the changegroup that the repository already had
+    pub duplicate_changesets: Vec<Revision>,
+    /// The number of new file revisions
+    pub file_revisions: usize,
+    /// The number of files in the changegroup
+    pub files: usize,
+    /// How many heads were added, or removed if negative, not counting the
+    /// new heads closing their branch
+    pub head_delta: isize,
+}
+
+/// Adds the revisions read by `unpacker` to `repo` within `tr`, calling
+/// `on_stage` as each group of revlogs starts. The new filelogs are added to
+/// `fncache` if the store has one.
+///
+/// The changelog is left delayed: the caller must call
+/// [`Changelog::finalize_pending`](crate::revlog::changelog::Changelog::finalize_pending)
+/// before closing `tr`.
+///
+/// Equivalent to `cg1unpacker.apply` in Python, without the hooks and the
+/// phases.
+pub fn apply_changegroup<R: Read>(
+    repo: &Repo,
+    unpacker: &mut ChangegroupUnpacker<R>,
+    tr: &mut StoreTransaction,
+    mut fncache: Option<&mut Fncache>,
+    mut on_stage: impl FnMut(ApplyStage),
+) -> Result<AppliedChangegroup, HgError> {
+    let mut applied = AppliedChangegroup::default();
+    let encoding = repo.store_vfs().encoding;
+    let encode = |name: &[u8]| match encoding {
+        PathEncoding::None => get_path_from_bytes(name).to_owned(),
+        encoding => {
+            get_path_from_bytes(&path_encode(name, encoding)).to_owned()
+        }
+    };
+
+    on_stage(ApplyStage::Changesets);
+    let old_len = {
+        let mut changelog = repo.changelog_mut()?;
+        changelog.delay_update(tr)?;
+        let old_len = changelog.revlog.len();
+        let revlog = &mut changelog.revlog;
+        let mut writer =
+            RevlogGroupWriter::new(revlog, "00changelog", None, tr)?;
+        while let Some(delta) = unpacker.delta_chunk()? {
+            let link_rev = writer.next_rev();
+            match writer.add(&delta, link_rev, tr)? {
+                AddedRevision::New(rev) => applied.changesets.push(rev),
+                AddedRevision::Duplicate(rev) => {
+                    applied.duplicate_changesets.push(rev)
+                }
+            }
+        }
+        old_len
+    };
+    let changelog = repo.changelog()?;
+    let link_rev = |node: &Node| -> Result<Revision, HgError> {
+        Ok(changelog.rev_from_node(node.into())?)
+    };
+
+    on_stage(ApplyStage::Manifests);
+    {
+        let mut manifestlog = repo.manifestlog_mut()?;
+        let split_index = SplitIndex {
+            path: "00manifest.i.s".into(),
+            name: b"00manifest.i.s".to_vec(),
+        };
+        let mut writer = RevlogGroupWriter::new(
+            &mut manifestlog.revlog,
+            "00manifest",
+            Some(split_index),
+            tr,
+        )?;
+        while let Some(delta) = unpacker.delta_chunk()? {
+            writer.add(&delta, link_rev(&delta.link_node)?, tr)?;
+        }
+    }
+    if unpacker.version().has_tree_manifests()
+        && let Some(directory) = unpacker.group_header()?
+    {
+        return Err(HgError::unsupported(format!(
+            "tree manifest for {}",
+            String::from_utf8_lossy(directory.as_bytes())
+        )));
+    }
+
+    on_stage(ApplyStage::Files);
+    while let Some(path) = unpacker.group_header()? {
+        applied.files += 1;
+        let mut filelog = repo.filelog(&path)?;
+        let was_empty = filelog.is_empty();
+        let radix = [b"data/", path.as_bytes()].concat();
+        let split_name = [b"data-s/", path.as_bytes(), b".i"].concat();
+        let split_index =
+            SplitIndex { path: encode(&split_name), name: split_name };
+        let revlog = &mut filelog.revlog;
+        for suffix in [&b".i"[..], b".d"] {
+            let name = [&radix[..], suffix].concat();
+            tr.add_name(encode(&name), &name);
+        }
+        let mut writer = RevlogGroupWriter::new(
+            revlog,
+            &String::from_utf8_lossy(&radix),
+            Some(split_index),
+            tr,
+        )?;
+        let mut received = 0;
+        while let Some(delta) = unpacker.delta_chunk()? {
+            received += 1;
+            let added = writer.add(&delta, link_rev(&delta.link_node)?, tr)?;
+            if let AddedRevision::New(_) = added {
+                applied.file_revisions += 1;
+            }
+        }
+        drop(writer);
+        if received == 0 {
+            return Err(HgError::abort_simple(
+                "received file revlog group is empty",
+            ));
+        }
+        if let Some(fncache) = fncache.as_deref_mut() {
+            if was_empty && !revlog.is_empty() {
+                let index = [&radix[..], b".i"].concat();
+                fncache.add(HgPath::new(&index));
+            }
+            if !revlog.is_inline() {
+                let data = [&radix[..], b".d"].concat();
+                fncache.add(HgPath::new(&data));
+            }
+        }
+    }
+
+    let last_rev = |len: usize| Revision(len as i32 - 1);
+    let (heads_removed, heads_added) = changelog
+        .get_index()
+        .head_revs_diff(last_rev(old_len), last_rev(changelog.revlog.len()))
+        .map_err(RevlogError::from)?;
+    applied.head_delta =
+        heads_added.len() as isize - heads_removed.len() as isize;
+    for head in heads_added {
+        if changelog.entry(head)?.data()?.extra()?.contains_key("close") {
+            applied.head_delta -= 1;
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NULL_NODE;
+
+    fn node(byte: u8) -> Node {
+        Node::from(&[byte; 20])
+    }
+
+    fn full_text(data: &[u8]) -> Vec<u8> {
+        [&trivial_diff_header(data.len())[..], data].concat()
+    }
+
+    fn revision(byte: u8, delta_base: Node, delta: Vec<u8>) -> RevisionDelta {
+        RevisionDelta {
+            node: node(byte),
+            p1: node(byte - 1),
+            p2: NULL_NODE,
+            delta_base,
+            link_node: node(byte + 100),
+            flags: 0,
+            delta,
+        }
+    }
+
+    /// Writes a changegroup with the given changelog revisions and files
+    fn write_test_changegroup(
+        version: ChangegroupVersion,
+        changelog: &[RevisionDelta],
+        files: &[(&[u8], &[RevisionDelta])],
+    ) -> Vec<u8> {
+        let files: Vec<_> = files
+            .iter()
+            .map(|&(path, revisions)| (HgPath::new(path), revisions))
+            .collect();
+        let mut out = vec![];
+        let size =
+            write_revision_groups(version, changelog, &[], &files, &mut out)
+                .unwrap();
+        assert_eq!(size, out.len());
+        out
+    }
+
+    fn abort_message(error: HgError) -> String {
+        match error {
+            HgError::Abort { message, .. } => message,
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    fn read_test_changegroup(
+        version: ChangegroupVersion,
+        data: &[u8],
+    ) -> Result<Vec<(RevlogTarget, RevisionDelta)>, HgError> {
+        let mut unpacker = ChangegroupUnpacker::new(data, version);
+        let mut revisions = vec![];
+        unpacker.unpack(|target, revision| -> Result<(), HgError> {
+            revisions.push((target.clone(), revision));
+            Ok(())
+        })?;
+        assert!(unpacker.into_inner().is_empty());
+        Ok(revisions)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut first = revision(1, NULL_NODE, full_text(b"a\n"));
+        first.flags = 1 << 15;
+        let second = revision(2, node(1), full_text(b"b\n"));
+        let file = revision(11, NULL_NODE, full_text(b"content\n"));
+        for version in [
+            ChangegroupVersion::V1,
+            ChangegroupVersion::V2,
+            ChangegroupVersion::V3,
+        ] {
+            let data = write_test_changegroup(
+                version,
+                &[first.clone(), second.clone()],
+                &[(b"dir/file", std::slice::from_ref(&file))],
+            );
+            let revisions = read_test_changegroup(version, &data).unwrap();
+            let targets: Vec<_> =
+                revisions.iter().map(|(target, _)| target.clone()).collect();
+            assert_eq!(
+                targets,
+                vec![
+                    RevlogTarget::Changelog,
+                    RevlogTarget::Changelog,
+                    RevlogTarget::Filelog(HgPathBuf::from_bytes(b"dir/file")),
+                ]
+            );
+            let (_, read) = &revisions[0];
+            assert_eq!(read.node, first.node);
+            assert_eq!(read.p1, first.p1);
+            assert_eq!(read.link_node, first.link_node);
+            assert_eq!(read.delta, first.delta);
+            let expected_flags = match version {
+                ChangegroupVersion::V3 => first.flags,
+                _ => 0,
+            };
+            assert_eq!(read.flags, expected_flags);
+            // Version 01 has no delta base in the stream, the first revision
+            // of a group is a delta against its first parent.
+            let expected_base = match version {
+                ChangegroupVersion::V1 => first.p1,
+                _ => first.delta_base,
+            };
+            assert_eq!(read.delta_base, expected_base);
+            assert_eq!(revisions[1].1.delta_base, node(1));
+            assert_eq!(revisions[2].1.apply(b"").unwrap(), b"content\n");
+        }
+    }
+
+    #[test]
+    fn test_apply_deltas() {
+        let text = b"line 1\nline 2\n";
+        let mut first = revision(1, NULL_NODE, full_text(text));
+        first.p1 = NULL_NODE;
+        first.node =
+            Node::from(&hash(text, NULL_NODE.as_bytes(), NULL_NODE.as_bytes()));
+        let mut delta = vec![];
+        delta.extend_from_slice(&7u32.to_be_bytes());
+        delta.extend_from_slice(&14u32.to_be_bytes());
+        delta.extend_from_slice(&7u32.to_be_bytes());
+        delta.extend_from_slice(b"line 3\n");
+        let second = revision(2, first.node, delta);
+        let data = write_test_changegroup(
+            ChangegroupVersion::V2,
+            &[first, second],
+            &[],
+        );
+        let revisions =
+            read_test_changegroup(ChangegroupVersion::V2, &data).unwrap();
+        let first_text = revisions[0].1.apply(b"").unwrap();
+        assert_eq!(first_text, text);
+        assert!(revisions[0].1.check_hash(&first_text));
+        assert_eq!(
+            revisions[1].1.apply(&first_text).unwrap(),
+            b"line 1\nline 3\n"
+        );
+        assert!(!revisions[1].1.check_hash(&first_text));
+    }
+
+    #[test]
+    fn test_invalid_streams() {
+        let data = write_test_changegroup(
+            ChangegroupVersion::V2,
+            &[revision(1, NULL_NODE, full_text(b"a\n"))],
+            &[],
+        );
+        // truncated stream
+        let error = read_test_changegroup(ChangegroupVersion::V2, &data[..50])
+            .unwrap_err();
+        assert_eq!(
+            abort_message(error),
+            "stream ended unexpectedly (got 46 bytes, expected 100)"
+        );
+        // chunks shorter than their own header
+        let error =
+            read_test_changegroup(ChangegroupVersion::V2, &[0, 0, 0, 2])
+                .unwrap_err();
+        assert_eq!(abort_message(error), "invalid chunk length 2");
+        // chunks shorter than a revision header
+        let error = read_test_changegroup(
+            ChangegroupVersion::V2,
+            &[0, 0, 0, 8, 1, 2, 3, 4],
+        )
+        .unwrap_err();
+        assert_eq!(abort_message(error), "invalid chunk length 8");
+    }
+
+    #[test]
+    fn test_version() {
+        for version in [
+            ChangegroupVersion::V1,
+            ChangegroupVersion::V2,
+            ChangegroupVersion::V3,
+        ] {
+            assert_eq!(
+                ChangegroupVersion::from_bytes(version.as_bytes()),
+                Some(version)
+            );
+        }
+        assert_eq!(ChangegroupVersion::from_bytes(b"04"), None);
+    }
+}
@@ -26,6 +26,7 @@ pub use layer::ConfigOrigin;
 pub use layer::ConfigParseError;
 use lazy_static::lazy_static;
 pub use plain_info::PlainInfo;
+pub(crate) use values::parse_bool;
 
 use self::config_items::DefaultConfig;
 use self::config_items::DefaultConfigItem;
@@ -10,7 +10,7 @@
 
 use crate::utils::strings::SliceExt;
 
-pub(super) fn parse_bool(v: &[u8]) -> Option<bool> {
+pub(crate) fn parse_bool(v: &[u8]) -> Option<bool> {
     match v.to_ascii_lowercase().as_slice() {
         b"1" | b"yes" | b"true" | b"on" | b"always" => Some(true),
         b"0" | b"no" | b"false" | b"off" | b"never" => Some(false),
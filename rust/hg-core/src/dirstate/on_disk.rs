@@ -4,7 +4,6 @@
 
 use std::borrow::Cow;
 use std::fmt::Write;
-use std::path::Path;
 
 use bitflags::bitflags;
 use bytes_cast::BytesCast;
@@ -30,7 +29,6 @@ use crate::dirstate::on_disk;
 use crate::dirstate::path_with_basename::WithBasename;
 use crate::errors::HgBacktrace;
 use crate::errors::HgError;
-use crate::errors::IoResultExt;
 use crate::repo::Repo;
 use crate::requirements::DIRSTATE_TRACKED_HINT_V1;
 use crate::revlog::manifest::ManifestFlags;
@@ -38,7 +36,6 @@ use crate::utils::hg_path::HgPath;
 use crate::utils::u_u32;
 use crate::utils::u_u64;
 use crate::utils::u32_u;
-use crate::vfs::Vfs;
 
 /// Added at the start of `.hg/dirstate` when the "v2" format is used.
 /// This a redundant sanity check more than an actual "magic number" since
@@ -1065,10 +1062,10 @@ pub fn write_tracked_key(repo: &Repo) -> Result<(), HgError> {
     if !repo.requirements().contains(DIRSTATE_TRACKED_HINT_V1) {
         return Ok(());
     }
-    let hg_vfs = repo.hg_vfs();
-    let path = Path::new("dirstate-tracked-hint");
-    let mut file = hg_vfs.open_write(path)?;
-    Ok(write_tracked_key_to(&mut file).when_writing_file(path)?)
+    // Atomically and creating the file if needed, like Python
+    let mut key = vec![];
+    write_tracked_key_to(&mut key).expect("writing to memory");
+    Ok(repo.hg_vfs().atomic_write("dirstate-tracked-hint", &key)?)
 }
 
 /// Write a new dirstate tracked key to this writer. See [`write_tracked_key`].
@@ -24,8 +24,16 @@ use super::NULL_REVISION;
 use super::Revision;
 use crate::FastHashMap;
 use crate::FastHashSet;
+use crate::NULL_NODE;
+use crate::Node;
+use crate::ancestors::AncestorsIterator;
 use crate::ancestors::MissingAncestors;
 use crate::dagops;
+use crate::errors::HgError;
+use crate::peer::Peer;
+use crate::repo::Repo;
+use crate::revlog::RevlogError;
+use crate::revlog::changelog::Changelog;
 
 type Rng = rand_pcg::Pcg32;
 type Seed = [u8; 16];
@@ -483,6 +491,271 @@ impl<G: Graph + Clone> PartialDiscovery<G> {
     }
 }
 
+/// The changesets that are missing on one side, given the heads both sides
+/// have in common.
+///
+/// This is a Rust counterpart to the `outgoing` class of
+/// `mercurial.discovery`.
+pub struct Outgoing {
+    /// The heads of what both sides have in common
+    pub common_heads: Vec<Revision>,
+    /// The heads of what is to be sent
+    pub ancestors_of: Vec<Revision>,
+    /// `::common_heads`
+    pub common: FastHashSet<Revision>,
+    /// `(::ancestors_of) - (::common_heads)`, sorted by revision number
+    pub missing: Vec<Revision>,
+}
+
+impl Outgoing {
+    pub fn new<G: Graph>(
+        graph: &G,
+        common_heads: Vec<Revision>,
+        ancestors_of: Vec<Revision>,
+    ) -> Result<Self, GraphError> {
+        let common = AncestorsIterator::new(
+            graph,
+            common_heads.iter().copied(),
+            Revision(0),
+            true,
+        )?
+        .collect::<Result<_, _>>()?;
+        let missing =
+            MissingAncestors::new(graph, common_heads.iter().copied())
+                .missing_ancestors(ancestors_of.iter().copied())?;
+        Ok(Self { common_heads, ancestors_of, common, missing })
+    }
+}
+
+/// What [`find_common_heads`] learned about a remote repository
+pub struct CommonHeads {
+    /// The heads of what both repositories have, null if nothing
+    pub common: Vec<Node>,
+    /// Whether the remote has changesets missing locally
+    pub any_incoming: bool,
+    /// The heads of the remote, null if it is empty
+    pub remote_heads: Vec<Node>,
+    /// Whether the repositories have no changeset in common although
+    /// neither is empty, only possible when forced
+    pub unrelated: bool,
+}
+
+/// Finds the heads that `repo` has in common with `peer`, sampling the
+/// changesets of `repo` until all are known to be common or missing.
+///
+/// `ancestors_of` restricts the discovery to the ancestors of these local
+/// changesets. `searching` is called when the heads are not enough to
+/// conclude, to tell the user. The repositories being unrelated aborts
+/// unless `force` is set.
+///
+/// This is a Rust counterpart to `setdiscovery.findcommonheads`.
+pub fn find_common_heads<E: From<HgError>>(
+    repo: &Repo,
+    peer: &mut dyn Peer,
+    ancestors_of: Option<&[Node]>,
+    force: bool,
+    mut searching: impl FnMut() -> Result<(), E>,
+) -> Result<CommonHeads, E> {
+    let config = repo.config();
+    let exchange_heads =
+        config.get_bool(b"devel", b"discovery.exchange-heads")?;
+    let initial_size = config
+        .get_u64(b"devel", b"discovery.sample-size.initial")?
+        .unwrap_or(100) as usize;
+    let mut full_size = config
+        .get_u64(b"devel", b"discovery.sample-size")?
+        .unwrap_or(200) as usize;
+    let grow_sample = config.get_bool(b"devel", b"discovery.grow-sample")?;
+    let grow_rate = config
+        .get_f64(b"devel", b"discovery.grow-sample.rate")?
+        .unwrap_or(1.05);
+    let dynamic_sample =
+        config.get_bool(b"devel", b"discovery.grow-sample.dynamic")?;
+    let randomize = config.get_bool(b"devel", b"discovery.randomize")?;
+
+    let changelog = repo.changelog()?;
+    let index = changelog.get_index();
+    let own_heads = match ancestors_of {
+        Some(nodes) => nodes
+            .iter()
+            .map(|node| changelog.rev_from_node((*node).into()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(HgError::from)?,
+        None => {
+            let mut heads = index
+                .head_revs()
+                .map_err(|error| HgError::from(RevlogError::from(error)))?;
+            heads.retain(|&rev| rev != NULL_REVISION);
+            heads
+        }
+    };
+    let sample = if exchange_heads {
+        own_heads.clone()
+    } else {
+        vec![]
+    };
+    let remote_heads = peer.heads()?;
+    let known = if sample.is_empty() {
+        vec![]
+    } else {
+        peer.known(&nodes(&changelog, &sample))?
+    };
+    if index.is_empty() {
+        let any_incoming = remote_heads != [NULL_NODE];
+        return Ok(CommonHeads {
+            common: vec![NULL_NODE],
+            any_incoming,
+            remote_heads: if any_incoming { remote_heads } else { vec![] },
+            unrelated: false,
+        });
+    }
+    searching()?;
+    let mut known_remote_heads = vec![];
+    for node in &remote_heads {
+        if let Some(rev) = local_rev(&changelog, *node)?
+            && rev != NULL_REVISION
+        {
+            known_remote_heads.push(rev);
+        }
+    }
+    if known_remote_heads.len() == remote_heads.len() {
+        return Ok(CommonHeads {
+            common: remote_heads.clone(),
+            any_incoming: false,
+            remote_heads,
+            unrelated: false,
+        });
+    }
+    if exchange_heads && known.iter().all(|&known| known) {
+        return Ok(CommonHeads {
+            common: nodes(&changelog, &own_heads),
+            any_incoming: true,
+            remote_heads,
+            unrelated: false,
+        });
+    }
+
+    let graph_error = |error| HgError::from(RevlogError::from(error));
+    let mut discovery = PartialDiscovery::new(
+        index,
+        own_heads.clone(),
+        !dynamic_sample,
+        randomize,
+    );
+    discovery.add_common_revisions(known_remote_heads).map_err(graph_error)?;
+    add_info(&mut discovery, &sample, &known).map_err(graph_error)?;
+    let mut full = !exchange_heads;
+    while !discovery.is_complete() {
+        let sample = if full || discovery.has_info() {
+            let size = full_size;
+            if grow_sample {
+                full_size = (full_size as f64 * grow_rate) as usize;
+            }
+            discovery.take_full_sample(size)
+        } else {
+            discovery.take_quick_sample(own_heads.iter().copied(), initial_size)
+        }
+        .map_err(graph_error)?;
+        let known = peer.known(&nodes(&changelog, &sample))?;
+        full = true;
+        add_info(&mut discovery, &sample, &known).map_err(graph_error)?;
+    }
+    let mut common: Vec<Revision> =
+        discovery.common_heads().map_err(graph_error)?.into_iter().collect();
+    common.sort_unstable();
+    if common.is_empty() && remote_heads != [NULL_NODE] {
+        if !force {
+            return Err(HgError::abort_simple("repository is unrelated").into());
+        }
+        return Ok(CommonHeads {
+            common: vec![NULL_NODE],
+            any_incoming: true,
+            remote_heads,
+            unrelated: true,
+        });
+    }
+    Ok(CommonHeads {
+        common: nodes(&changelog, &common),
+        any_incoming: remote_heads != [NULL_NODE],
+        remote_heads,
+        unrelated: false,
+    })
+}
+
+/// Finds what `repo` has in common with `peer` before pulling `heads` of
+/// `peer`, or all of its heads if empty. The remote heads of the result are
+/// then `heads` if given.
+///
+/// This is a Rust counterpart to `discovery.findcommonincoming`.
+pub fn find_common_incoming<E: From<HgError>>(
+    repo: &Repo,
+    peer: &mut dyn Peer,
+    heads: &[Node],
+    force: bool,
+    searching: impl FnMut() -> Result<(), E>,
+) -> Result<CommonHeads, E> {
+    if repo.config().get_bool(b"experimental", b"skip-changeset-discovery")? {
+        return Err(HgError::unsupported("skipping changeset discovery").into());
+    }
+    if !heads.is_empty() && !any_unknown(repo, heads)? {
+        return Ok(CommonHeads {
+            common: heads.to_vec(),
+            any_incoming: false,
+            remote_heads: heads.to_vec(),
+            unrelated: false,
+        });
+    }
+    let mut result = find_common_heads(repo, peer, None, force, searching)?;
+    if !heads.is_empty() {
+        // The remote could be lying about its heads
+        if !result.any_incoming {
+            result.any_incoming = any_unknown(repo, heads)?;
+        }
+        result.remote_heads = heads.to_vec();
+    }
+    Ok(result)
+}
+
+/// Records whether each revision of `sample` is `known` by the remote, like
+/// `addinfo` in Python
+fn add_info<G: Graph + Clone>(
+    discovery: &mut PartialDiscovery<G>,
+    sample: &[Revision],
+    known: &[bool],
+) -> Result<(), GraphError> {
+    let (common, missing): (Vec<_>, Vec<_>) =
+        sample.iter().zip(known).partition(|(_, known)| **known);
+    discovery.add_common_revisions(common.into_iter().map(|(&rev, _)| rev))?;
+    discovery.add_missing_revisions(missing.into_iter().map(|(&rev, _)| rev))
+}
+
+/// Whether `repo` lacks any of `nodes`
+fn any_unknown(repo: &Repo, nodes: &[Node]) -> Result<bool, HgError> {
+    let changelog = repo.changelog()?;
+    for node in nodes {
+        if local_rev(&changelog, *node)?.is_none() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn nodes(changelog: &Changelog, revs: &[Revision]) -> Vec<Node> {
+    revs.iter().map(|&rev| *changelog.node_from_rev(rev)).collect()
+}
+
+/// The revision of `node` if `changelog` has it
+fn local_rev(
+    changelog: &Changelog,
+    node: Node,
+) -> Result<Option<Revision>, HgError> {
+    match changelog.rev_from_node(node.into()) {
+        Ok(rev) => Ok(Some(rev)),
+        Err(RevlogError::InvalidRevision { .. }) => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -714,4 +987,23 @@ mod tests {
         assert_eq!(sample, vec![0, 1, 2, 3, 4, 5, 7, 8, 9, 10, 11, 12, 13]);
         Ok(())
     }
+
+    #[test]
+    fn test_outgoing() -> Result<(), GraphError> {
+        let outgoing =
+            Outgoing::new(&SampleGraph, vec![R!(4)], vec![R!(10), R!(12)])?;
+        let mut common: Vec<Revision> =
+            outgoing.common.iter().cloned().collect();
+        common.sort_unstable();
+        assert_eq!(common, vec![R!(0), R!(1), R!(2), R!(4)]);
+        assert_eq!(
+            outgoing.missing,
+            vec![R!(5), R!(6), R!(7), R!(9), R!(10), R!(12)]
+        );
+
+        let outgoing = Outgoing::new(&SampleGraph, vec![], vec![R!(3)])?;
+        assert!(outgoing.common.is_empty());
+        assert_eq!(outgoing.missing, vec![R!(0), R!(1), R!(3)]);
+        Ok(())
+    }
 }
@@ -95,6 +95,8 @@ impl From<StatusError> for HgError {
     }
 }
 
+impl std::error::Error for HgError {}
+
 /// Details about where an I/O error happened
 #[derive(Debug, PartialEq)]
 pub enum IoErrorContext {
@@ -117,6 +119,10 @@ pub enum IoErrorContext {
     CurrentDir,
     /// `std::env::current_exe`
     CurrentExe,
+    /// Reading from a stream that is not a file, like a pipe or a socket
+    ReadingStream,
+    /// Writing to a stream that is not a file, like a pipe or a socket
+    WritingStream,
 }
 
 impl HgError {
@@ -757,6 +763,8 @@ impl fmt::Display for IoErrorContext {
             IoErrorContext::CurrentExe => {
                 write!(f, "error getting current executable")
             }
+            IoErrorContext::ReadingStream => write!(f, "when reading stream"),
+            IoErrorContext::WritingStream => write!(f, "when writing stream"),
         }
     }
 }
@@ -27,3 +27,9 @@ pub const UNIMPLEMENTED: ExitCode = 252;
 
 /// The fallback path is not valid
 pub const INVALID_FALLBACK: ExitCode = 253;
+
+/// A hook failed, aborting the command
+pub const HOOK_FAILURE: ExitCode = 40;
+
+/// The remote repository failed to complete a command
+pub const REMOTE_ERROR: ExitCode = 100;
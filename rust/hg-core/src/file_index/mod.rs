@@ -106,6 +106,32 @@ pub struct Config {
     pub garbage_timestamp: Option<u32>,
 }
 
+impl Config {
+    /// Reads the file index configuration from `config`.
+    pub fn from_config(
+        config: &crate::config::Config,
+    ) -> Result<Self, HgError> {
+        let vacuum_mode = config
+            .get(b"devel", b"fileindex.vacuum-mode")
+            .and_then(VacuumMode::parse)
+            .unwrap_or_default();
+        let max_unused_percentage = config
+            .get_u32(b"storage", b"fileindex.max-unused-percentage")?
+            .unwrap_or(50);
+        let gc_retention_s = config
+            .get_u32(b"storage", b"fileindex.gc-retention-seconds")?
+            .unwrap_or(86400);
+        let garbage_timestamp =
+            config.get_u32(b"devel", b"fileindex.garbage-timestamp")?;
+        Ok(Self {
+            vacuum_mode,
+            max_unused_ratio: max_unused_percentage as f64 / 100.0,
+            gc_retention_s,
+            garbage_timestamp,
+        })
+    }
+}
+
 /// The main file index object. It provides a high level interface that
 /// abstracts over data on disk and pending changes in memory.
 pub struct FileIndex {
@@ -52,6 +52,45 @@ impl Transaction for FakeTransaction {
         // No need to do anything. We only add new files to the transaction
         // to ensure that rolling back will delete them.
     }
+
+    fn add_backup(&mut self, _file: impl AsRef<Path>) {
+        // No need to do anything. The file index never rewrites files.
+    }
+}
+
+#[test]
+fn test_config_from_config() {
+    let mut config = crate::config::Config::empty();
+    let parse = |config: &crate::config::Config| {
+        let parsed = Config::from_config(config).unwrap();
+        (
+            format!("{:?}", parsed.vacuum_mode),
+            parsed.max_unused_ratio,
+            parsed.gc_retention_s,
+            parsed.garbage_timestamp,
+        )
+    };
+    assert_eq!(parse(&config), ("Auto".to_owned(), 0.5, 86400, None));
+    config
+        .load_cli_args(
+            [
+                "devel.fileindex.vacuum-mode=always",
+                "storage.fileindex.max-unused-percentage=20",
+                "storage.fileindex.gc-retention-seconds=60",
+                "devel.fileindex.garbage-timestamp=42",
+            ],
+            None,
+        )
+        .unwrap();
+    assert_eq!(parse(&config), ("Always".to_owned(), 0.2, 60, Some(42)));
+    config
+        .load_cli_args(["devel.fileindex.vacuum-mode=sometimes"], None)
+        .unwrap();
+    assert_eq!(parse(&config).0, "Auto");
+    config
+        .load_cli_args(["storage.fileindex.gc-retention-seconds=soon"], None)
+        .unwrap();
+    assert!(Config::from_config(&config).is_err());
 }
 
 #[test]
@@ -661,17 +661,30 @@ pub fn parse_pattern_args(
     patterns: Vec<Vec<u8>>,
     cwd: &Path,
     root: &Path,
+) -> Result<Vec<FilePattern>, HgPathError> {
+    parse_pattern_args_with_default(patterns, cwd, root, PatternSyntax::RelPath)
+}
+
+/// Like [`parse_pattern_args`], for patterns defaulting to another syntax,
+/// like `glob` for `-I/--include` and `-X/--exclude`.
+pub fn parse_pattern_args_with_default(
+    patterns: Vec<Vec<u8>>,
+    cwd: &Path,
+    root: &Path,
+    default: PatternSyntax,
 ) -> Result<Vec<FilePattern>, HgPathError> {
     let mut file_patterns: Vec<FilePattern> = Vec::new();
     for pattern in patterns {
         let pattern = parse_one_pattern(
             &pattern,
             Path::new("<args>"),
-            PatternSyntax::RelPath,
+            default.clone(),
             true,
         );
         match pattern.syntax {
-            PatternSyntax::RelGlob | PatternSyntax::RelPath => {
+            PatternSyntax::Glob
+            | PatternSyntax::RelGlob
+            | PatternSyntax::RelPath => {
                 let name = get_path_from_bytes(&pattern.raw);
                 let canon = canonical_path(root, cwd, name)?;
                 file_patterns.push(FilePattern {
@@ -1001,6 +1014,38 @@ mod tests {
         .map(|re_opt| re_opt.map(|re| re.to_bytes()))
     }
 
+    #[test]
+    fn test_parse_pattern_args() {
+        let root = Path::new("/repo");
+        let cwd = Path::new("/repo/dir");
+        let patterns = [&b"a"[..], b"glob:*.rs", b"rootglob:*.rs", b"path:a"];
+        let parsed = parse_pattern_args(
+            patterns.iter().map(|p| p.to_vec()).collect(),
+            cwd,
+            root,
+        )
+        .unwrap();
+        let parsed: Vec<_> = parsed
+            .iter()
+            .map(|p| (p.syntax.clone(), p.raw.as_slice()))
+            .collect();
+        // Like in Python, `glob:` patterns are relative to the current
+        // directory, and `rootglob:` ones to the root
+        assert_eq!(
+            parsed,
+            vec![
+                (PatternSyntax::RelPath, &b"dir/a"[..]),
+                (PatternSyntax::Glob, b"dir/*.rs"),
+                (PatternSyntax::RootGlob, b"*.rs"),
+                (PatternSyntax::Path, b"a"),
+            ]
+        );
+        assert!(
+            parse_pattern_args(vec![b"glob:../../*".to_vec()], cwd, root)
+                .is_err()
+        );
+    }
+
     #[test]
     fn test_build_single_regex() {
         assert_eq!(
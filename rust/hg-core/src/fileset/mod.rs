@@ -0,0 +1,940 @@
+//! The fileset language, selecting files by their properties in `set:`
+//! patterns, like Python's `fileset.py`. See `hg help filesets`.
+//!
+//! Parse errors, `resolved()`, `unresolved()`, `subrepo()` and the
+//! predicates defined by extensions are reported as unsupported, so that
+//! the Python implementation has the last word.
+
+mod parser;
+
+use std::cell::OnceCell;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rayon::prelude::*;
+use regex::bytes::Regex;
+use regex::bytes::RegexBuilder;
+
+use crate::FastHashSet;
+use crate::Graph;
+use crate::Revision;
+use crate::checkexec::check_exec;
+use crate::dirstate::DirstateError;
+use crate::dirstate::status::StatusOptions;
+use crate::errors::HgError;
+use crate::file_patterns::PatternSyntax;
+use crate::file_patterns::parse_pattern_args_with_default;
+use crate::matchers::AlwaysMatcher;
+use crate::matchers::DifferenceMatcher;
+use crate::matchers::IntersectionMatcher;
+use crate::matchers::Matcher;
+use crate::matchers::PatternMatcher;
+use crate::matchers::PredicateMatcher;
+use crate::matchers::UnionMatcher;
+use crate::matchers::get_ignore_files;
+use crate::matchers::get_ignore_matcher;
+use crate::operations::DiffStatus;
+use crate::operations::has_rust_only_syntax;
+use crate::operations::settle_unsure_files;
+use crate::operations::status_rev_rev_no_copies;
+use crate::repo::Repo;
+use crate::revlog::RevisionOrWdir;
+use crate::revlog::RevlogError;
+use crate::revlog::RevlogType;
+use crate::revlog::filelog::FileCompOutcome;
+use crate::revlog::filelog::Filelog;
+use crate::revlog::manifest::Manifest;
+use crate::revlog::options::RevlogOpenOptions;
+use crate::revlog::options::default_revlog_options;
+use crate::utils::files::check_windows_filename;
+use crate::utils::files::get_bytes_from_path;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::utils::hg_path::hg_path_to_path_buf;
+use crate::utils::path_auditor::PathAuditor;
+use crate::vfs::VfsImpl;
+use crate::warnings::HgWarningSender;
+use parser::Expr;
+
+/// A matcher built from a fileset
+pub type FilesetMatcher = Box<dyn Matcher + Send>;
+
+/// Returns a matcher for the fileset `expression` evaluated in `rev`, like
+/// Python's `fileset.match`.
+///
+/// Patterns in the expression are globs relative to `cwd` by default.
+pub fn fileset_matcher(
+    repo: &Repo,
+    rev: RevisionOrWdir,
+    cwd: &Path,
+    expression: &[u8],
+    warnings: &HgWarningSender,
+) -> Result<FilesetMatcher, HgError> {
+    let expr = parser::parse_expression(expression)?;
+    let mut lists = StatusLists::default();
+    expr.visit_funcs(&mut |name| match name {
+        b"clean" => lists.clean = true,
+        b"unknown" => lists.unknown = true,
+        b"ignored" => lists.ignored = true,
+        _ => {}
+    });
+    let evaluator =
+        Evaluator { repo, cwd, warnings, lists, working_copy: OnceCell::new() };
+    let context = evaluator.context(rev)?;
+    evaluator.matcher(&context, &expr)
+}
+
+/// The optional lists of files that the status predicates need
+#[derive(Debug, Default, Clone, Copy)]
+struct StatusLists {
+    clean: bool,
+    unknown: bool,
+    ignored: bool,
+}
+
+/// The files of each state, in the status between two revisions
+#[derive(Debug, Default)]
+struct Status {
+    modified: FastHashSet<HgPathBuf>,
+    added: FastHashSet<HgPathBuf>,
+    removed: FastHashSet<HgPathBuf>,
+    deleted: FastHashSet<HgPathBuf>,
+    unknown: FastHashSet<HgPathBuf>,
+    ignored: FastHashSet<HgPathBuf>,
+    clean: FastHashSet<HgPathBuf>,
+}
+
+/// What predicates need to know about the files of the working copy
+struct WorkingCopy {
+    root: PathBuf,
+    check_exec: bool,
+    auditor: PathAuditor,
+    /// The revision of the first parent
+    p1: Revision,
+    tracked: FastHashSet<HgPathBuf>,
+    /// Files recorded as copies of a file of the first parent
+    copied: FastHashSet<HgPathBuf>,
+}
+
+impl WorkingCopy {
+    /// The metadata of `path`, if it is a valid path to an existing file
+    fn metadata(&self, path: &HgPath) -> Option<std::fs::Metadata> {
+        if !self.auditor.check(path) {
+            return None;
+        }
+        let fs_path = self.root.join(hg_path_to_path_buf(path).ok()?);
+        fs_path.symlink_metadata().ok()
+    }
+
+    /// The contents of `path` (or the target of a symlink), like
+    /// `workingfilectx.data()` without the decode filters
+    fn data(&self, path: &HgPath) -> Option<Vec<u8>> {
+        let metadata = self.metadata(path)?;
+        let fs_path = self.root.join(hg_path_to_path_buf(path).ok()?);
+        if metadata.is_symlink() {
+            let target = std::fs::read_link(fs_path).ok()?;
+            Some(get_bytes_from_path(target))
+        } else {
+            std::fs::read(fs_path).ok()
+        }
+    }
+
+    /// The flag of `path` on the filesystem, `b'l'`, `b'x'` or `None`
+    fn flag(&self, path: &HgPath) -> Option<u8> {
+        let metadata = self.metadata(path)?;
+        if metadata.is_symlink() {
+            return Some(b'l');
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if self.check_exec
+                && metadata.is_file()
+                && metadata.permissions().mode() & 0o111 != 0
+            {
+                return Some(b'x');
+            }
+        }
+        None
+    }
+}
+
+/// What predicates need to read the files of a revision
+struct RevisionFiles {
+    rev: Revision,
+    manifest: Manifest,
+    store_vfs: VfsImpl,
+    filelog_options: RevlogOpenOptions,
+}
+
+/// The files of the revision a fileset is evaluated in
+#[derive(Clone)]
+enum Files {
+    WorkingCopy(Arc<WorkingCopy>),
+    Revision(Arc<RevisionFiles>),
+}
+
+/// Like Python's `matchctx`: the revision whose files are matched, with
+/// the base revision of the status predicates
+struct Context {
+    base: Revision,
+    files: Files,
+    status: OnceCell<Status>,
+}
+
+struct Evaluator<'a> {
+    repo: &'a Repo,
+    cwd: &'a Path,
+    warnings: &'a HgWarningSender,
+    lists: StatusLists,
+    working_copy: OnceCell<Arc<WorkingCopy>>,
+}
+
+impl Evaluator<'_> {
+    /// The context to evaluate a fileset in `rev`, compared to its first
+    /// parent
+    fn context(&self, rev: RevisionOrWdir) -> Result<Context, HgError> {
+        let base = match rev.exclude_wdir() {
+            Some(rev) => {
+                let changelog = self.repo.changelog()?;
+                changelog
+                    .parents(rev)
+                    .map_err(|e| HgError::corrupted(e.to_string()))?[0]
+            }
+            None => self.working_copy()?.p1,
+        };
+        self.context_with_base(base, rev)
+    }
+
+    fn context_with_base(
+        &self,
+        base: Revision,
+        rev: RevisionOrWdir,
+    ) -> Result<Context, HgError> {
+        let files = match rev.exclude_wdir() {
+            Some(rev) => Files::Revision(Arc::new(RevisionFiles {
+                rev,
+                manifest: self.repo.manifest_for_rev(rev.into())?,
+                store_vfs: self.repo.store_vfs(),
+                filelog_options: default_revlog_options(
+                    self.repo.config(),
+                    self.repo.requirements(),
+                    RevlogType::Filelog,
+                )?,
+            })),
+            None => Files::WorkingCopy(self.working_copy()?),
+        };
+        Ok(Context { base, files, status: OnceCell::new() })
+    }
+
+    fn working_copy(&self) -> Result<Arc<WorkingCopy>, HgError> {
+        if let Some(working_copy) = self.working_copy.get() {
+            return Ok(working_copy.clone());
+        }
+        let repo = self.repo;
+        let parents = repo.dirstate_parents()?;
+        let p1 = repo.changelog()?.rev_from_node(parents.p1.into())?;
+        let p1_manifest = repo.manifest_for_node(parents.p1)?;
+        let mut tracked = FastHashSet::default();
+        let mut copied = FastHashSet::default();
+        {
+            let dirstate = repo.dirstate_map()?;
+            for result in dirstate.iter() {
+                let (path, entry) = result.map_err(DirstateError::from)?;
+                if entry.tracked() {
+                    tracked.insert(path.to_owned());
+                }
+            }
+            for result in dirstate.copy_map_iter() {
+                let (path, source) = result.map_err(DirstateError::from)?;
+                if p1_manifest.find_by_path(source)?.is_some() {
+                    copied.insert(path.to_owned());
+                }
+            }
+        }
+        let root = repo.working_directory_path().to_owned();
+        let working_copy = Arc::new(WorkingCopy {
+            check_exec: check_exec(&root),
+            auditor: PathAuditor::new(&root),
+            root,
+            p1,
+            tracked,
+            copied,
+        });
+        Ok(self.working_copy.get_or_init(|| working_copy).clone())
+    }
+
+    fn matcher(
+        &self,
+        context: &Context,
+        expr: &Expr,
+    ) -> Result<FilesetMatcher, HgError> {
+        Ok(match expr {
+            Expr::String(pattern) => self.pattern_matcher(pattern.clone())?,
+            Expr::KindPat(kind, pattern) => {
+                let pattern = Expr::string(
+                    pattern.as_deref(),
+                    "pattern must be a string",
+                )?;
+                match &kind[..] {
+                    b"re" | b"glob" | b"path" | b"filepath" | b"relglob"
+                    | b"relpath" | b"relre" | b"rootglob" | b"rootfilesin" => {}
+                    b"listfile" | b"listfile0" | b"set" | b"include"
+                    | b"subinclude" => {
+                        return Err(HgError::unsupported(format!(
+                            "{}: pattern in a fileset",
+                            String::from_utf8_lossy(kind)
+                        )));
+                    }
+                    _ => {
+                        return Err(HgError::unsupported(format!(
+                            "fileset parse error: invalid pattern kind: {}",
+                            String::from_utf8_lossy(kind)
+                        )));
+                    }
+                }
+                self.pattern_matcher([&kind[..], b":", &pattern].concat())?
+            }
+            Expr::Not(expr) => {
+                let matcher = self.matcher(context, expr)?;
+                Box::new(PredicateMatcher::new(move |path: &HgPath| {
+                    !matcher.matches(path)
+                }))
+            }
+            Expr::And(left, right) => {
+                let left = self.matcher(context, left)?;
+                match &**right {
+                    Expr::Not(right) => Box::new(DifferenceMatcher::new(
+                        left,
+                        self.matcher(context, right)?,
+                    )),
+                    right => Box::new(IntersectionMatcher::new(
+                        left,
+                        self.matcher(context, right)?,
+                    )),
+                }
+            }
+            Expr::Or(exprs) => Box::new(UnionMatcher::new(
+                exprs
+                    .iter()
+                    .map(|expr| self.matcher(context, expr))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            Expr::List(_) => {
+                return Err(HgError::unsupported(
+                    "fileset parse error: can't use a list in this context",
+                ));
+            }
+            Expr::Func(name, args) => {
+                self.predicate(context, name, args.as_deref())?
+            }
+        })
+    }
+
+    /// A matcher for a pattern, which is a glob relative to the current
+    /// directory by default
+    fn pattern_matcher(
+        &self,
+        pattern: Vec<u8>,
+    ) -> Result<FilesetMatcher, HgError> {
+        let root = self.repo.working_directory_path();
+        let patterns = parse_pattern_args_with_default(
+            vec![pattern],
+            self.cwd,
+            root,
+            PatternSyntax::Glob,
+        )?;
+        Ok(Box::new(PatternMatcher::new(patterns)?))
+    }
+
+    fn predicate(
+        &self,
+        context: &Context,
+        name: &[u8],
+        args: Option<&Expr>,
+    ) -> Result<FilesetMatcher, HgError> {
+        let args = Expr::args(args);
+        let no_args = || {
+            if args.is_empty() {
+                Ok(())
+            } else {
+                Err(HgError::unsupported(format!(
+                    "fileset parse error: {} takes no arguments",
+                    String::from_utf8_lossy(name)
+                )))
+            }
+        };
+        let string_arg = |error: &str| {
+            if args.len() > 1 {
+                return Err(HgError::unsupported(format!(
+                    "fileset parse error: {error}"
+                )));
+            }
+            Expr::string(args.first().copied(), error)
+        };
+        Ok(match name {
+            b"modified" | b"added" | b"removed" | b"deleted" | b"missing"
+            | b"unknown" | b"ignored" | b"clean" => {
+                no_args()?;
+                let status = self.status(context)?;
+                let files = match name {
+                    b"modified" => &status.modified,
+                    b"added" => &status.added,
+                    b"removed" => &status.removed,
+                    b"deleted" | b"missing" => &status.deleted,
+                    b"unknown" => &status.unknown,
+                    b"ignored" => &status.ignored,
+                    _ => &status.clean,
+                };
+                set_matcher(files.clone())
+            }
+            b"tracked" => {
+                no_args()?;
+                match &context.files {
+                    Files::WorkingCopy(working_copy) => {
+                        let working_copy = working_copy.clone();
+                        Box::new(PredicateMatcher::new(move |path: &HgPath| {
+                            working_copy.tracked.contains(path)
+                        }))
+                    }
+                    Files::Revision(files) => {
+                        let files = files.clone();
+                        Box::new(PredicateMatcher::new(move |path: &HgPath| {
+                            files
+                                .manifest
+                                .find_by_path(path)
+                                .is_ok_and(|entry| entry.is_some())
+                        }))
+                    }
+                }
+            }
+            b"exec" | b"symlink" => {
+                no_args()?;
+                let flag = if name == b"exec" { b'x' } else { b'l' };
+                match &context.files {
+                    Files::WorkingCopy(working_copy) => {
+                        if flag == b'x' && !working_copy.check_exec {
+                            return Err(HgError::unsupported(
+                                "exec() without exec bit support",
+                            ));
+                        }
+                        let working_copy = working_copy.clone();
+                        Box::new(PredicateMatcher::new(move |path: &HgPath| {
+                            working_copy.flag(path) == Some(flag)
+                        }))
+                    }
+                    Files::Revision(files) => {
+                        let files = files.clone();
+                        Box::new(PredicateMatcher::new(move |path: &HgPath| {
+                            files.manifest.find_by_path(path).is_ok_and(
+                                |entry| {
+                                    entry.is_some_and(|entry| {
+                                        entry.flags.as_byte() == Some(flag)
+                                    })
+                                },
+                            )
+                        }))
+                    }
+                }
+            }
+            b"portable" => {
+                no_args()?;
+                Box::new(PredicateMatcher::new(|path: &HgPath| {
+                    check_windows_filename(path.as_bytes()).is_none()
+                }))
+            }
+            b"hgignore" => {
+                no_args()?;
+                Box::new(get_ignore_matcher(
+                    get_ignore_files(self.repo),
+                    self.repo.working_directory_path(),
+                    &mut |_, _| {},
+                    self.warnings,
+                )?)
+            }
+            b"binary" => {
+                no_args()?;
+                data_matcher(context, |data| data.contains(&0))?
+            }
+            b"grep" => {
+                let pattern = string_arg("grep requires a pattern")?;
+                let regex = compile_search_pattern(&pattern)?;
+                data_matcher(context, move |data| regex.is_match(data))?
+            }
+            b"encoding" => {
+                let encoding =
+                    string_arg("encoding requires an encoding name")?;
+                let decodes = decoder(&encoding)?;
+                data_matcher(context, decodes)?
+            }
+            b"eol" => {
+                let style = string_arg("eol requires a style name")?;
+                data_matcher(context, move |data| has_eol(data, &style))?
+            }
+            b"size" => {
+                let expression = string_arg("size requires an expression")?;
+                let range = SizeRange::parse(&expression)?;
+                size_matcher(context, move |size| range.contains(size))?
+            }
+            b"copied" => {
+                no_args()?;
+                copied_matcher(context)?
+            }
+            b"revs" => {
+                let [revs, expr] = args[..] else {
+                    return Err(HgError::unsupported(
+                        "fileset parse error: revs takes two arguments",
+                    ));
+                };
+                let revs = Expr::string(
+                    Some(revs),
+                    "first argument to revs must be a revision",
+                )?;
+                let rev = self.resolve(&revs)?;
+                self.matcher(&self.context(rev)?, expr)?
+            }
+            b"status" => {
+                let [base, rev, expr] = args[..] else {
+                    return Err(HgError::unsupported(
+                        "fileset parse error: status takes three arguments",
+                    ));
+                };
+                let base_error = "first argument to status must be a revision";
+                let base = Expr::string(Some(base), base_error)?;
+                let rev_error = "second argument to status must be a revision";
+                let rev = Expr::string(Some(rev), rev_error)?;
+                if base.is_empty() || rev.is_empty() {
+                    return Err(HgError::unsupported(
+                        "fileset parse error: empty revision",
+                    ));
+                }
+                let Some(base) = self.resolve(&base)?.exclude_wdir() else {
+                    return Err(HgError::unsupported(
+                        "status() from the working copy",
+                    ));
+                };
+                let rev = self.resolve(&rev)?;
+                self.matcher(&self.context_with_base(base, rev)?, expr)?
+            }
+            _ => {
+                return Err(HgError::unsupported(format!(
+                    "fileset predicate {}()",
+                    String::from_utf8_lossy(name)
+                )));
+            }
+        })
+    }
+
+    fn resolve(&self, revision: &[u8]) -> Result<RevisionOrWdir, HgError> {
+        let revision = std::str::from_utf8(revision)
+            .map_err(|_| HgError::unsupported("non-UTF-8 revision"))?;
+        crate::revset::resolve_single(revision, self.repo)
+    }
+
+    /// The status of the files of `context` compared to its base, computed
+    /// on first use
+    fn status<'c>(&self, context: &'c Context) -> Result<&'c Status, HgError> {
+        if let Some(status) = context.status.get() {
+            return Ok(status);
+        }
+        let status = match &context.files {
+            Files::WorkingCopy(working_copy) => {
+                if context.base != working_copy.p1 {
+                    return Err(HgError::unsupported(
+                        "fileset status between a revision and the working \
+                         copy",
+                    ));
+                }
+                self.working_copy_status(working_copy)?
+            }
+            Files::Revision(files) => {
+                self.revision_status(context.base, files.rev)?
+            }
+        };
+        Ok(context.status.get_or_init(|| status))
+    }
+
+    fn revision_status(
+        &self,
+        base: Revision,
+        rev: Revision,
+    ) -> Result<Status, HgError> {
+        let mut status = Status::default();
+        let diff =
+            status_rev_rev_no_copies(self.repo, base, rev, AlwaysMatcher)?;
+        for result in diff.iter() {
+            let (path, diff_status) = result?;
+            let path = path.path.into_owned();
+            match diff_status {
+                DiffStatus::Removed => status.removed.insert(path),
+                DiffStatus::Added => status.added.insert(path),
+                DiffStatus::Modified => status.modified.insert(path),
+                DiffStatus::Matching if self.lists.clean => {
+                    status.clean.insert(path)
+                }
+                DiffStatus::Matching => false,
+            };
+        }
+        Ok(status)
+    }
+
+    fn working_copy_status(
+        &self,
+        working_copy: &WorkingCopy,
+    ) -> Result<Status, HgError> {
+        let repo = self.repo;
+        let options = StatusOptions {
+            check_exec: working_copy.check_exec,
+            list_clean: self.lists.clean,
+            list_unknown: self.lists.unknown,
+            list_ignored: self.lists.ignored,
+            list_copies: false,
+            collect_traversed_dirs: false,
+            empty_dirs_keep_files: false,
+        };
+        let (mut status, unsure) = repo.dirstate_map_mut()?.with_status(
+            &AlwaysMatcher,
+            working_copy.root.clone(),
+            get_ignore_files(repo),
+            options,
+            |result, _warnings| -> Result<_, HgError> {
+                let result = result?;
+                let set = |paths: Vec<crate::dirstate::status::StatusPath>| {
+                    paths.into_iter().map(|p| p.path.into_owned()).collect()
+                };
+                let status = Status {
+                    modified: set(result.modified),
+                    added: set(result.added),
+                    removed: set(result.removed),
+                    deleted: set(result.deleted),
+                    unknown: set(result.unknown),
+                    ignored: set(result.ignored),
+                    clean: set(result.clean),
+                };
+                let unsure: Vec<HgPathBuf> = result
+                    .unsure
+                    .into_iter()
+                    .map(|p| p.path.into_owned())
+                    .collect();
+                Ok((status, unsure))
+            },
+        )?;
+
+        // Settle the files whose metadata was not enough to tell
+        let p1_manifest = repo.manifest_for_rev(working_copy.p1.into())?;
+        let settled = settle_unsure_files(
+            repo,
+            working_copy.check_exec,
+            &p1_manifest,
+            unsure,
+        )?;
+        for (path, outcome) in settled {
+            match outcome {
+                FileCompOutcome::Modified => status.modified.insert(path),
+                FileCompOutcome::Deleted => status.deleted.insert(path),
+                FileCompOutcome::Clean if self.lists.clean => {
+                    status.clean.insert(path)
+                }
+                FileCompOutcome::Clean => false,
+            };
+        }
+        Ok(status)
+    }
+}
+
+/// A matcher for a precomputed set of files
+fn set_matcher(files: FastHashSet<HgPathBuf>) -> FilesetMatcher {
+    Box::new(PredicateMatcher::new(move |path: &HgPath| files.contains(path)))
+}
+
+/// A matcher for the files whose contents satisfy `predicate`.
+///
+/// Missing files never match. The working copy is read on demand, while
+/// all the files of a revision are read upfront.
+fn data_matcher(
+    context: &Context,
+    predicate: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+) -> Result<FilesetMatcher, HgError> {
+    Ok(match &context.files {
+        Files::WorkingCopy(working_copy) => {
+            let working_copy = working_copy.clone();
+            Box::new(PredicateMatcher::new(move |path: &HgPath| {
+                working_copy.data(path).is_some_and(|data| predicate(&data))
+            }))
+        }
+        Files::Revision(files) => {
+            set_matcher(files.select(|filelog, node| {
+                let data = filelog.data_for_node(node)?.into_file_data()?;
+                Ok(predicate(&data))
+            })?)
+        }
+    })
+}
+
+/// A matcher for the files whose size satisfies `predicate`
+fn size_matcher(
+    context: &Context,
+    predicate: impl Fn(u64) -> bool + Send + Sync + 'static,
+) -> Result<FilesetMatcher, HgError> {
+    Ok(match &context.files {
+        Files::WorkingCopy(working_copy) => {
+            let working_copy = working_copy.clone();
+            Box::new(PredicateMatcher::new(move |path: &HgPath| {
+                working_copy
+                    .metadata(path)
+                    .is_some_and(|metadata| predicate(metadata.len()))
+            }))
+        }
+        Files::Revision(files) => {
+            set_matcher(files.select(|filelog, node| {
+                let size = filelog.contents_size_for_node(node)?;
+                Ok(predicate(size as u64))
+            })?)
+        }
+    })
+}
+
+/// A matcher for the files recorded as copied
+fn copied_matcher(context: &Context) -> Result<FilesetMatcher, HgError> {
+    Ok(match &context.files {
+        Files::WorkingCopy(working_copy) => {
+            set_matcher(working_copy.copied.clone())
+        }
+        Files::Revision(files) => {
+            set_matcher(files.select(|filelog, node| {
+                let entry = filelog.entry_for_node(node)?;
+                if !entry.maybe_has_metadata() {
+                    return Ok(false);
+                }
+                let data = entry.data()?;
+                let metadata = data.metadata()?.parse()?;
+                Ok(metadata.copy.is_some() && metadata.copyrev.is_some())
+            })?)
+        }
+    })
+}
+
+impl RevisionFiles {
+    /// Returns the files of the revision for which `predicate` is true
+    fn select(
+        &self,
+        predicate: impl Fn(&Filelog, crate::Node) -> Result<bool, RevlogError>
+        + Sync,
+    ) -> Result<FastHashSet<HgPathBuf>, HgError> {
+        let entries: Vec<_> = self.manifest.iter().collect::<Result<_, _>>()?;
+        let selected = entries
+            .par_iter()
+            .map(|entry| {
+                let filelog = Filelog::open_vfs(
+                    &self.store_vfs,
+                    entry.path,
+                    &self.filelog_options,
+                )?;
+                let selected = predicate(&filelog, entry.node_id()?)?;
+                Ok(selected.then(|| entry.path.to_owned()))
+            })
+            .collect::<Result<Vec<_>, RevlogError>>()?;
+        Ok(selected.into_iter().flatten().collect())
+    }
+}
+
+/// Compile the pattern of `grep()`, searched with Python's `re.search`
+fn compile_search_pattern(pattern: &[u8]) -> Result<Regex, HgError> {
+    let pattern = std::str::from_utf8(pattern)
+        .map_err(|_| HgError::unsupported("non-UTF-8 grep() pattern"))?;
+    // Without `re.MULTILINE`, Python's `$` also matches before a final
+    // newline, which the `regex` crate cannot express.
+    if pattern.contains('$') || has_rust_only_syntax(pattern) {
+        return Err(HgError::unsupported(
+            "grep() pattern with a different meaning in Python",
+        ));
+    }
+    RegexBuilder::new(pattern).unicode(false).build().map_err(|error| {
+        HgError::unsupported(format!("grep() pattern: {error}"))
+    })
+}
+
+/// Returns whether data can be decoded with `encoding`, for the few
+/// encodings we know of
+fn decoder(
+    encoding: &[u8],
+) -> Result<impl Fn(&[u8]) -> bool + Send + Sync + 'static, HgError> {
+    let normalized: Vec<u8> = encoding
+        .iter()
+        .map(|&c| if c == b'-' || c == b' ' { b'_' } else { c })
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    let decodes: fn(&[u8]) -> bool = match &normalized[..] {
+        b"ascii" | b"us_ascii" => |data| data.is_ascii(),
+        b"utf_8" | b"utf8" | b"u8" => |data| std::str::from_utf8(data).is_ok(),
+        b"latin_1" | b"latin1" | b"iso_8859_1" | b"iso8859_1" | b"l1" => {
+            |_data| true
+        }
+        _ => {
+            return Err(HgError::unsupported(format!(
+                "encoding '{}' in a fileset",
+                String::from_utf8_lossy(encoding)
+            )));
+        }
+    };
+    Ok(decodes)
+}
+
+/// Whether non-binary `data` has newlines of the given `style`
+fn has_eol(data: &[u8], style: &[u8]) -> bool {
+    if data.contains(&0) {
+        return false;
+    }
+    match style {
+        b"dos" | b"win" => data.windows(2).any(|w| w == b"\r\n"),
+        b"unix" => data
+            .iter()
+            .enumerate()
+            .any(|(i, &c)| c == b'\n' && (i == 0 || data[i - 1] != b'\r')),
+        b"mac" => data
+            .iter()
+            .enumerate()
+            .any(|(i, &c)| c == b'\r' && data.get(i + 1) != Some(&b'\n')),
+        _ => false,
+    }
+}
+
+/// The sizes accepted by a `size()` expression, inclusive
+#[derive(Debug, PartialEq)]
+struct SizeRange {
+    min: u64,
+    max: u64,
+}
+
+impl SizeRange {
+    fn contains(&self, size: u64) -> bool {
+        self.min <= size && size <= self.max
+    }
+
+    /// Parse a size expression like Python's `fileset.sizematcher`
+    fn parse(expression: &[u8]) -> Result<Self, HgError> {
+        let expression = expression.trim_ascii();
+        let range = if let Some(dash) =
+            expression.iter().position(|&c| c == b'-')
+        {
+            Self {
+                min: size_to_int(&expression[..dash])?,
+                max: size_to_int(&expression[dash + 1..])?,
+            }
+        } else if let Some(rest) = expression.strip_prefix(b"<=") {
+            Self { min: 0, max: size_to_int(rest)? }
+        } else if let Some(rest) = expression.strip_prefix(b"<") {
+            Self { min: 0, max: size_to_int(rest)?.wrapping_sub(1) }
+        } else if let Some(rest) = expression.strip_prefix(b">=") {
+            Self { min: size_to_int(rest)?, max: u64::MAX }
+        } else if let Some(rest) = expression.strip_prefix(b">") {
+            Self { min: size_to_int(rest)?.saturating_add(1), max: u64::MAX }
+        } else {
+            Self {
+                min: size_to_int(expression)?,
+                max: size_to_max(expression)?,
+            }
+        };
+        Ok(range)
+    }
+}
+
+/// Size units, in the order Python's `util._sizeunits` tries them
+const SIZE_UNITS: &[(&[u8], f64)] = &[
+    (b"m", (1 << 20) as f64),
+    (b"k", (1 << 10) as f64),
+    (b"g", (1 << 30) as f64),
+    (b"kb", (1 << 10) as f64),
+    (b"mb", (1 << 20) as f64),
+    (b"gb", (1 << 30) as f64),
+    (b"b", 1.0),
+];
+
+fn size_error(size: &[u8]) -> HgError {
+    HgError::unsupported(format!(
+        "fileset parse error: couldn't parse size: {}",
+        String::from_utf8_lossy(size)
+    ))
+}
+
+fn parse_float(number: &[u8], size: &[u8]) -> Result<f64, HgError> {
+    std::str::from_utf8(number)
+        .ok()
+        .and_then(|number| number.trim().parse::<f64>().ok())
+        .filter(|number| number.is_finite())
+        .ok_or_else(|| size_error(size))
+}
+
+/// Parse a size with an optional unit, like Python's `util.sizetoint`
+fn size_to_int(size: &[u8]) -> Result<u64, HgError> {
+    let lowered = size.trim_ascii().to_ascii_lowercase();
+    for (unit, multiplier) in SIZE_UNITS {
+        if let Some(number) = lowered.strip_suffix(*unit) {
+            let value = parse_float(number, size)? * multiplier;
+            return Ok(value.max(0.0) as u64);
+        }
+    }
+    std::str::from_utf8(&lowered)
+        .ok()
+        .and_then(|number| number.parse::<u64>().ok())
+        .ok_or_else(|| size_error(size))
+}
+
+/// The largest size that `size` stands for, given its precision, like
+/// Python's `fileset._sizetomax`: `4k` is up to `5k - 1`, `4.5k` up to
+/// `4.6k - 1`.
+fn size_to_max(size: &[u8]) -> Result<u64, HgError> {
+    let lowered = size.trim_ascii().to_ascii_lowercase();
+    for (unit, multiplier) in SIZE_UNITS {
+        if let Some(number) = lowered.strip_suffix(*unit) {
+            let mut increment = 1.0;
+            if let Some(dot) = number.iter().position(|&c| c == b'.') {
+                let decimals = number.len() - dot - 1;
+                increment /= 10f64.powi(decimals as i32);
+            }
+            let value = (parse_float(number, size)? + increment) * multiplier;
+            return Ok((value.max(0.0) as u64).saturating_sub(1));
+        }
+    }
+    size_to_int(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_range() {
+        let range = |expression: &[u8]| {
+            let range = SizeRange::parse(expression).unwrap();
+            (range.min, range.max)
+        };
+        assert_eq!(range(b"1k"), (1024, 2047));
+        assert_eq!(range(b"4.5k"), (4608, 4709));
+        assert_eq!(range(b"< 20k"), (0, 20479));
+        assert_eq!(range(b">= .5MB"), (524288, u64::MAX));
+        assert_eq!(range(b"4k - 1MB"), (4096, 1048576));
+        assert_eq!(range(b"10"), (10, 10));
+        assert!(SizeRange::parse(b"big").is_err());
+    }
+
+    #[test]
+    fn test_has_eol() {
+        assert!(has_eol(b"a\r\nb", b"dos"));
+        assert!(!has_eol(b"a\r\nb", b"unix"));
+        assert!(has_eol(b"a\r\nb\n", b"unix"));
+        assert!(has_eol(b"a\rb", b"mac"));
+        assert!(!has_eol(b"a\r\nb", b"mac"));
+        assert!(!has_eol(b"a\0\r\nb", b"dos"));
+    }
+
+    #[test]
+    fn test_decoder() {
+        let utf8 = decoder(b"UTF-8").unwrap();
+        assert!(utf8("é".as_bytes()));
+        assert!(!utf8(b"\xe9"));
+        assert!(!decoder(b"ascii").unwrap()(b"\xe9"));
+        assert!(decoder(b"klingon").is_err());
+    }
+}
@@ -0,0 +1,504 @@
+//! Tokenizer and parser of the fileset language, ported from Python's
+//! `filesetlang.py` and `parser.py`.
+
+use crate::errors::HgError;
+
+/// A token of a fileset expression
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// One of `(),-:|&+!`
+    Operator(u8),
+    /// One of the `and`, `or` and `not` keywords
+    Keyword(&'static str),
+    Symbol(Vec<u8>),
+    String(Vec<u8>),
+    End,
+}
+
+impl Token {
+    /// The binding strength of the token when used as an infix operator
+    fn binding(&self) -> u8 {
+        match self {
+            Token::Operator(b'(') => 20,
+            Token::Operator(b':') => 15,
+            Token::Operator(b'!') | Token::Keyword("not") => 10,
+            Token::Operator(b'-' | b'&') | Token::Keyword("and") => 5,
+            Token::Operator(b'|' | b'+') | Token::Keyword("or") => 4,
+            Token::Operator(b',') => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Characters allowed in symbols besides alphanumeric and non-ASCII ones
+const GLOB_CHARS: &[u8] = b".*{}[]?/\\_";
+
+fn is_symbol_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || GLOB_CHARS.contains(&c) || c > 127
+}
+
+fn parse_error(message: impl std::fmt::Display) -> HgError {
+    HgError::unsupported(format!("fileset parse error: {}", message))
+}
+
+/// Split `program` into tokens with their position, ending with
+/// [`Token::End`].
+fn tokenize(program: &[u8]) -> Result<Vec<(Token, usize)>, HgError> {
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while pos < program.len() {
+        let c = program[pos];
+        if c.is_ascii_whitespace() || c == b'\x0b' {
+            // skip inter-token whitespace
+        } else if b"(),-:|&+!".contains(&c) {
+            tokens.push((Token::Operator(c), pos));
+        } else if c == b'"'
+            || c == b'\''
+            || (c == b'r' && matches!(program.get(pos + 1), Some(b'"' | b'\'')))
+        {
+            let raw = c == b'r';
+            if raw {
+                pos += 1;
+            }
+            let quote = program[pos];
+            pos += 1;
+            let start = pos;
+            loop {
+                match program.get(pos) {
+                    None => return Err(parse_error("unterminated string")),
+                    // skip over escaped characters
+                    Some(b'\\') => pos += 2,
+                    Some(&d) if d == quote => break,
+                    Some(_) => pos += 1,
+                }
+            }
+            let value = &program[start..pos];
+            let value = if raw {
+                value.to_vec()
+            } else {
+                unescape(value)?
+            };
+            tokens.push((Token::String(value), start));
+        } else if is_symbol_char(c) {
+            let start = pos;
+            while pos + 1 < program.len() && is_symbol_char(program[pos + 1]) {
+                pos += 1;
+            }
+            let symbol = &program[start..=pos];
+            let token = match symbol {
+                b"and" => Token::Keyword("and"),
+                b"or" => Token::Keyword("or"),
+                b"not" => Token::Keyword("not"),
+                _ => Token::Symbol(symbol.to_vec()),
+            };
+            tokens.push((token, start));
+        } else {
+            return Err(parse_error(format!("syntax error at {}", pos)));
+        }
+        pos += 1;
+    }
+    tokens.push((Token::End, program.len()));
+    Ok(tokens)
+}
+
+/// Decode backslash escapes like Python's `codecs.escape_decode`
+fn unescape(s: &[u8]) -> Result<Vec<u8>, HgError> {
+    let mut result = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] != b'\\' {
+            result.push(s[i]);
+            i += 1;
+            continue;
+        }
+        let Some(&c) = s.get(i + 1) else {
+            return Err(parse_error("trailing \\ in string"));
+        };
+        i += 2;
+        match c {
+            b'\n' => {}
+            b'\\' | b'\'' | b'"' => result.push(c),
+            b'a' => result.push(b'\x07'),
+            b'b' => result.push(b'\x08'),
+            b'f' => result.push(b'\x0c'),
+            b'n' => result.push(b'\n'),
+            b'r' => result.push(b'\r'),
+            b't' => result.push(b'\t'),
+            b'v' => result.push(b'\x0b'),
+            b'x' => {
+                let byte = s
+                    .get(i..i + 2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| parse_error("invalid \\x escape"))?;
+                result.push(byte);
+                i += 2;
+            }
+            b'0'..=b'7' => {
+                let mut value = u32::from(c - b'0');
+                let mut digits = 1;
+                while digits < 3
+                    && let Some(&d @ b'0'..=b'7') = s.get(i)
+                {
+                    value = value * 8 + u32::from(d - b'0');
+                    digits += 1;
+                    i += 1;
+                }
+                result.push(value as u8);
+            }
+            _ => result.extend_from_slice(&[b'\\', c]),
+        }
+    }
+    Ok(result)
+}
+
+/// A raw parse tree, before [`analyze`]
+#[derive(Debug, PartialEq)]
+enum Tree {
+    Symbol(Vec<u8>),
+    String(Vec<u8>),
+    KindPat(Box<Tree>, Box<Tree>),
+    Group(Option<Box<Tree>>),
+    Negate(Box<Tree>),
+    Not(Box<Tree>),
+    And(Box<Tree>, Box<Tree>),
+    Minus(Box<Tree>, Box<Tree>),
+    Or(Vec<Tree>),
+    List(Vec<Tree>),
+    Func(Box<Tree>, Option<Box<Tree>>),
+}
+
+/// A top-down operator precedence parser over the tokens of an expression
+struct Parser {
+    tokens: std::vec::IntoIter<(Token, usize)>,
+    current: (Token, usize),
+}
+
+impl Parser {
+    fn advance(&mut self) -> (Token, usize) {
+        let next = self.tokens.next().unwrap_or((Token::End, 0));
+        std::mem::replace(&mut self.current, next)
+    }
+
+    fn expect_close(&mut self) -> Result<(), HgError> {
+        if self.current.0 != Token::Operator(b')') {
+            return Err(parse_error(format!(
+                "unexpected token at {}",
+                self.current.1
+            )));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Parse the operand of a parenthesized construct, which may be empty
+    fn parse_enclosed(&mut self) -> Result<Option<Box<Tree>>, HgError> {
+        let operand = if self.current.0 == Token::Operator(b')') {
+            None
+        } else {
+            Some(Box::new(self.parse(1)?))
+        };
+        self.expect_close()?;
+        Ok(operand)
+    }
+
+    fn parse(&mut self, binding: u8) -> Result<Tree, HgError> {
+        let (token, pos) = self.advance();
+        let mut tree = match token {
+            Token::Symbol(symbol) => Tree::Symbol(symbol),
+            Token::String(string) => Tree::String(string),
+            Token::Operator(b'(') => Tree::Group(self.parse_enclosed()?),
+            Token::Operator(b'-') => Tree::Negate(Box::new(self.parse(19)?)),
+            Token::Operator(b'!') | Token::Keyword("not") => {
+                Tree::Not(Box::new(self.parse(10)?))
+            }
+            _ => return Err(parse_error(format!("not a prefix at {}", pos))),
+        };
+        while binding < self.current.0.binding() {
+            let (token, pos) = self.advance();
+            tree = match token {
+                Token::Operator(b'(') => {
+                    Tree::Func(Box::new(tree), self.parse_enclosed()?)
+                }
+                Token::Operator(b':') => {
+                    Tree::KindPat(Box::new(tree), Box::new(self.parse(15)?))
+                }
+                Token::Operator(b'-') => {
+                    Tree::Minus(Box::new(tree), Box::new(self.parse(5)?))
+                }
+                Token::Operator(b'&') | Token::Keyword("and") => {
+                    Tree::And(Box::new(tree), Box::new(self.parse(5)?))
+                }
+                Token::Operator(b'|' | b'+') | Token::Keyword("or") => {
+                    let right = self.parse(4)?;
+                    match tree {
+                        Tree::Or(mut operands) => {
+                            operands.push(right);
+                            Tree::Or(operands)
+                        }
+                        left => Tree::Or(vec![left, right]),
+                    }
+                }
+                Token::Operator(b',') => {
+                    let right = self.parse(2)?;
+                    match tree {
+                        Tree::List(mut operands) => {
+                            operands.push(right);
+                            Tree::List(operands)
+                        }
+                        left => Tree::List(vec![left, right]),
+                    }
+                }
+                _ => {
+                    return Err(parse_error(format!(
+                        "not an infix at {}",
+                        pos
+                    )));
+                }
+            };
+        }
+        Ok(tree)
+    }
+}
+
+fn parse(program: &[u8]) -> Result<Tree, HgError> {
+    let mut tokens = tokenize(program)?.into_iter();
+    let current = tokens.next().expect("at least the end token");
+    let mut parser = Parser { tokens, current };
+    let tree = parser.parse(0)?;
+    if parser.current.0 != Token::End {
+        return Err(parse_error(format!(
+            "invalid token at {}",
+            parser.current.1
+        )));
+    }
+    Ok(tree)
+}
+
+/// An analyzed fileset expression, ready to be evaluated
+#[derive(Debug, PartialEq)]
+pub(super) enum Expr {
+    /// A string or a symbol, to be used as a pattern or as an argument
+    String(Vec<u8>),
+    /// A `kind:pattern` pattern
+    KindPat(Vec<u8>, Option<Box<Expr>>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Vec<Expr>),
+    /// Arguments of a function, invalid anywhere else
+    List(Vec<Expr>),
+    /// A predicate, with its arguments
+    Func(Vec<u8>, Option<Box<Expr>>),
+}
+
+impl Expr {
+    /// Returns the string value of this argument, or the `error` message
+    pub(super) fn string(
+        expr: Option<&Expr>,
+        error: &str,
+    ) -> Result<Vec<u8>, HgError> {
+        match expr {
+            Some(Expr::String(value)) => Ok(value.clone()),
+            _ => Err(parse_error(error)),
+        }
+    }
+
+    /// Returns the arguments of a function
+    pub(super) fn args(expr: Option<&Expr>) -> Vec<&Expr> {
+        match expr {
+            None => vec![],
+            Some(Expr::List(args)) => args.iter().collect(),
+            Some(expr) => vec![expr],
+        }
+    }
+
+    /// Calls `f` with every function name in this expression
+    pub(super) fn visit_funcs(&self, f: &mut impl FnMut(&[u8])) {
+        match self {
+            Expr::String(_) => {}
+            Expr::KindPat(_, pattern) => {
+                if let Some(pattern) = pattern {
+                    pattern.visit_funcs(f)
+                }
+            }
+            Expr::Not(expr) => expr.visit_funcs(f),
+            Expr::And(left, right) => {
+                left.visit_funcs(f);
+                right.visit_funcs(f);
+            }
+            Expr::Or(exprs) | Expr::List(exprs) => {
+                exprs.iter().for_each(|expr| expr.visit_funcs(f))
+            }
+            Expr::Func(name, args) => {
+                f(name);
+                if let Some(args) = args {
+                    args.visit_funcs(f)
+                }
+            }
+        }
+    }
+}
+
+fn symbol(tree: &Tree) -> Result<Vec<u8>, HgError> {
+    match tree {
+        Tree::Symbol(symbol) => Ok(symbol.clone()),
+        _ => Err(parse_error("not a symbol")),
+    }
+}
+
+fn required(expr: Option<Expr>) -> Result<Box<Expr>, HgError> {
+    expr.map(Box::new).ok_or_else(|| parse_error("missing argument"))
+}
+
+/// Transform the raw tree into an evaluatable expression, like Python's
+/// `filesetlang.analyze` without the status hints.
+///
+/// Returns `None` for empty groups, which are only valid as empty argument
+/// lists.
+fn analyze(tree: Tree) -> Result<Option<Expr>, HgError> {
+    Ok(Some(match tree {
+        Tree::Symbol(value) | Tree::String(value) => Expr::String(value),
+        Tree::KindPat(kind, pattern) => {
+            Expr::KindPat(symbol(&kind)?, analyze(*pattern)?.map(Box::new))
+        }
+        Tree::Group(None) => return Ok(None),
+        Tree::Group(Some(tree)) => return analyze(*tree),
+        Tree::Negate(_) => {
+            return Err(parse_error(
+                "can't use negate operator in this context",
+            ));
+        }
+        Tree::Not(tree) => Expr::Not(required(analyze(*tree)?)?),
+        Tree::And(left, right) => {
+            Expr::And(required(analyze(*left)?)?, required(analyze(*right)?)?)
+        }
+        Tree::Minus(left, right) => Expr::And(
+            required(analyze(*left)?)?,
+            Box::new(Expr::Not(required(analyze(*right)?)?)),
+        ),
+        Tree::Or(trees) => Expr::Or(
+            trees
+                .into_iter()
+                .map(|tree| required(analyze(tree)?).map(|expr| *expr))
+                .collect::<Result<_, _>>()?,
+        ),
+        Tree::List(trees) => Expr::List(
+            trees
+                .into_iter()
+                .map(|tree| required(analyze(tree)?).map(|expr| *expr))
+                .collect::<Result<_, _>>()?,
+        ),
+        Tree::Func(name, args) => {
+            let args = match args {
+                Some(args) => analyze(*args)?.map(Box::new),
+                None => None,
+            };
+            Expr::Func(symbol(&name)?, args)
+        }
+    }))
+}
+
+/// Parse and analyze a fileset expression
+pub(super) fn parse_expression(program: &[u8]) -> Result<Expr, HgError> {
+    analyze(parse(program)?)?.ok_or_else(|| parse_error("missing argument"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &[u8]) -> Expr {
+        Expr::String(value.to_vec())
+    }
+
+    fn func(name: &[u8], args: Option<Expr>) -> Expr {
+        Expr::Func(name.to_vec(), args.map(Box::new))
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let tokens: Vec<Token> = tokenize(b"a.c and not r'x\\'y' | \"\\x41\"")
+            .unwrap()
+            .into_iter()
+            .map(|(token, _pos)| token)
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Symbol(b"a.c".to_vec()),
+                Token::Keyword("and"),
+                Token::Keyword("not"),
+                Token::String(b"x\\'y".to_vec()),
+                Token::Operator(b'|'),
+                Token::String(b"A".to_vec()),
+                Token::End,
+            ]
+        );
+        assert!(tokenize(b"'unterminated").is_err());
+        assert!(tokenize(b"a = b").is_err());
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(b"a\\nb\\\\c").unwrap(), b"a\nb\\c");
+        assert_eq!(unescape(b"\\101\\x42\\q").unwrap(), b"AB\\q");
+        assert!(unescape(b"\\x4").is_err());
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        assert_eq!(parse_expression(b"**.c").unwrap(), string(b"**.c"));
+        assert_eq!(
+            parse_expression(b"glob:'*.c' or added()").unwrap(),
+            Expr::Or(vec![
+                Expr::KindPat(b"glob".to_vec(), Some(Box::new(string(b"*.c")))),
+                func(b"added", None),
+            ])
+        );
+        // `and` binds tighter than `or`, `not` tighter than both
+        assert_eq!(
+            parse_expression(b"a | not b & c").unwrap(),
+            Expr::Or(vec![
+                string(b"a"),
+                Expr::And(
+                    Box::new(Expr::Not(Box::new(string(b"b")))),
+                    Box::new(string(b"c"))
+                ),
+            ])
+        );
+        assert_eq!(
+            parse_expression(b"a - (b)").unwrap(),
+            Expr::And(
+                Box::new(string(b"a")),
+                Box::new(Expr::Not(Box::new(string(b"b"))))
+            )
+        );
+        assert_eq!(
+            parse_expression(b"revs('.', size('>1k'))").unwrap(),
+            func(
+                b"revs",
+                Some(Expr::List(vec![
+                    string(b"."),
+                    func(b"size", Some(string(b">1k"))),
+                ]))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for invalid in [
+            &b""[..],
+            b"()",
+            b"-a",
+            b"a)",
+            b"not",
+            b"(a",
+            b"'a'(b)",
+            b"'a':b",
+            b"a b",
+        ] {
+            assert!(parse_expression(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
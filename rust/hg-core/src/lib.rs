@@ -5,13 +5,21 @@
 // GNU General Public License version 2 or any later version.
 
 pub mod ancestors;
+pub mod bookmarks;
+pub mod branchmap;
+pub mod bundle;
 mod bdiff;
+pub mod changegroup;
 pub mod dagops;
 pub mod encoding;
 pub mod errors;
 pub mod linkrev;
 pub mod narrow;
+pub mod similar;
 pub mod sparse;
+pub mod store;
+pub mod stream_clone;
+pub mod tags;
 pub use ancestors::AncestorsIterator;
 pub use ancestors::MissingAncestors;
 pub mod dirstate;
@@ -25,6 +33,7 @@ pub mod testing; // unconditionally built, for use from integration tests
 pub use dirstate::DirstateParents;
 pub mod copy_tracing;
 pub mod file_patterns;
+pub mod fileset;
 pub mod matchers;
 pub mod repo;
 pub mod revlog;
@@ -48,6 +57,8 @@ pub mod dyn_bytes;
 pub mod lock;
 pub mod logging;
 pub mod operations;
+pub mod peer;
+pub mod phases;
 mod pre_regex;
 pub mod progress;
 pub mod revset;
@@ -57,6 +68,7 @@ pub mod update;
 pub mod utils;
 pub mod vfs;
 pub mod warnings;
+pub mod wireprotocol;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::atomic::AtomicBool;
@@ -9,9 +9,13 @@ use crate::NULL_REVISION;
 use crate::Node;
 use crate::Revision;
 use crate::dirstate::owning::OwningDirstateMap;
+use crate::errors::HgBacktrace;
 use crate::errors::HgError;
 use crate::repo::Repo;
 use crate::revlog::RevisionOrWdir;
+use crate::revlog::Revlog;
+use crate::revlog::RevlogError;
+use crate::revlog::UncheckedRevision;
 use crate::revlog::changelog::Changelog;
 use crate::revlog::filelog::Filelog;
 use crate::revlog::manifest::Manifestlog;
@@ -273,8 +277,39 @@ pub fn ancestor_iter<'a>(
     .expect("base_revision should not be null")
 }
 
+/// Where the linkrev of a revision points, see [`check_link_revision`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinkRevision {
+    /// A changeset that the caller expects
+    Expected(Revision),
+    /// A changeset that the caller does not expect
+    Unexpected(Revision),
+    /// Not a revision of the changelog
+    Nonexistent(UncheckedRevision),
+}
+
+/// Checks that the linkrev of `rev` in `revlog` is a revision of
+/// `changelog`, and whether it is one that `is_expected` accepts.
+pub fn check_link_revision<E>(
+    revlog: &Revlog,
+    rev: Revision,
+    changelog: &Revlog,
+    is_expected: impl FnOnce(Revision) -> Result<bool, E>,
+) -> Result<LinkRevision, E> {
+    let Ok(linkrev) = revlog.link_revision(rev, changelog) else {
+        return Ok(LinkRevision::Nonexistent(
+            revlog.unchecked_link_revision(rev),
+        ));
+    };
+    Ok(if is_expected(linkrev)? {
+        LinkRevision::Expected(linkrev)
+    } else {
+        LinkRevision::Unexpected(linkrev)
+    })
+}
+
 /// If the linkrev of `id` is in `ancestors`, returns it.
-pub fn check_link_revision(
+pub fn check_file_link_revision(
     state: &RepoState<'_>,
     fls: &FilelogSet,
     id: FileId,
@@ -285,12 +320,21 @@ pub fn check_link_revision(
         FileId::Wdir => return Ok(Some(RevisionOrWdir::wdir())),
     };
     let FilelogSetItem { filelog, .. } = fls.get(id.index);
-    let linkrev =
-        filelog.revlog.link_revision(id.revision, &state.changelog.revlog)?;
-    if ancestors.contains(linkrev).map_err(from_graph_error)? {
-        return Ok(Some(linkrev.into()));
+    let checked = check_link_revision(
+        &filelog.revlog,
+        id.revision,
+        &state.changelog.revlog,
+        |linkrev| ancestors.contains(linkrev).map_err(from_graph_error),
+    )?;
+    match checked {
+        LinkRevision::Expected(linkrev) => Ok(Some(linkrev.into())),
+        LinkRevision::Unexpected(_) => Ok(None),
+        LinkRevision::Nonexistent(_) => Err(RevlogError::InvalidLinkRev {
+            backtrace: HgBacktrace::capture(),
+            rev: id.revision,
+        }
+        .into()),
     }
-    Ok(None)
 }
 
 /// Finds and returns the first ancestor of `descendant` that introduced `id`
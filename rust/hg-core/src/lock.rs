@@ -3,6 +3,8 @@
 use std::io;
 use std::io::ErrorKind;
 use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
 
 use crate::errors::HgBacktrace;
 use crate::errors::HgIoError;
@@ -59,6 +61,30 @@ pub fn try_with_lock_no_wait<R>(
     Err(LockError::AlreadyHeld)
 }
 
+/// Like [`try_with_lock_no_wait`], but waits up to `timeout` for the lock
+/// to be released, checking it every second like Python.
+pub fn try_with_lock<R>(
+    hg_vfs: &VfsImpl,
+    lock_filename: &str,
+    timeout: Duration,
+    f: impl FnOnce() -> R,
+) -> Result<R, LockError> {
+    let deadline = Instant::now() + timeout;
+    let mut f = Some(f);
+    loop {
+        let result = try_with_lock_no_wait(hg_vfs, lock_filename, || {
+            f.take().expect("called at most once")()
+        });
+        let now = Instant::now();
+        match result {
+            Err(LockError::AlreadyHeld) if now < deadline => {
+                std::thread::sleep((deadline - now).min(Duration::from_secs(1)))
+            }
+            result => return result,
+        }
+    }
+}
+
 fn break_lock(hg_vfs: &VfsImpl, lock_filename: &str) -> Result<(), LockError> {
     try_with_lock_no_wait(hg_vfs, &format!("{}.break", lock_filename), || {
         // Check again in case some other process broke and
@@ -623,6 +623,60 @@ impl Matcher for IncludeMatcher<'_> {
     }
 }
 
+/// Matches the files for which a function returns `true`, like Python's
+/// `predicatematcher`. Every directory may contain matching files.
+///
+///```
+/// use hg::{ matchers::{Matcher, PredicateMatcher}, utils::hg_path::HgPath };
+///
+/// let matcher = PredicateMatcher::new(|path: &HgPath| path.as_bytes().ends_with(b".c"));
+///
+/// assert_eq!(matcher.matches(HgPath::new(b"main.c")), true);
+/// assert_eq!(matcher.matches(HgPath::new(b"b.txt")), false);
+/// ```
+pub struct PredicateMatcher<F> {
+    predicate: F,
+}
+
+impl<F> PredicateMatcher<F>
+where
+    F: Fn(&HgPath) -> bool + Sync,
+{
+    pub fn new(predicate: F) -> Self {
+        Self { predicate }
+    }
+}
+
+impl<F> core::fmt::Debug for PredicateMatcher<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PredicateMatcher").finish_non_exhaustive()
+    }
+}
+
+impl<F> Matcher for PredicateMatcher<F>
+where
+    F: Fn(&HgPath) -> bool + Sync,
+{
+    fn file_set(&self) -> Option<&FastHashSet<HgPathBuf>> {
+        None
+    }
+    fn exact_match(&self, _filename: &HgPath) -> bool {
+        false
+    }
+    fn matches(&self, filename: &HgPath) -> bool {
+        (self.predicate)(filename)
+    }
+    fn visit_children_set(&self, _directory: &HgPath) -> VisitChildrenSet {
+        VisitChildrenSet::This
+    }
+    fn matches_everything(&self) -> bool {
+        false
+    }
+    fn is_exact(&self) -> bool {
+        false
+    }
+}
+
 /// The union of multiple matchers. Will match if any of the matchers match.
 #[derive(Debug)]
 pub struct UnionMatcher<M> {
@@ -10,7 +10,7 @@ use crate::linkrev::FilelogSet;
 use crate::linkrev::RepoState;
 use crate::linkrev::adjust_link_revision;
 use crate::linkrev::ancestor_iter;
-use crate::linkrev::check_link_revision;
+use crate::linkrev::check_file_link_revision;
 use crate::repo::Repo;
 use crate::revlog::RevisionOrWdir;
 use crate::utils;
@@ -307,7 +307,7 @@ pub fn annotate(
     // newer) so that we populate the ancestor bitset in a tight loop early on.
     for &id in &topological_order {
         if let Some(revision) =
-            check_link_revision(&state, &fls, id, &mut ancestors)?
+            check_file_link_revision(&state, &fls, id, &mut ancestors)?
         {
             graph[id].revision = ChangelogRevisionState::Done(revision);
         }
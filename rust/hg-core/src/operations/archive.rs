@@ -0,0 +1,699 @@
+//! Create an unversioned archive of a revision, as a directory of files or
+//! as a tar or zip archive. This is `hg archive`, the Rust counterpart of
+//! `mercurial/archival.py`.
+//!
+//! File contents are read from the filelogs in parallel, then added to the
+//! archive in manifest order so that archives are reproducible. Members get
+//! the date of the changeset as their modification time.
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use bzip2::write::BzEncoder;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Timelike;
+use flate2::Compression;
+use flate2::GzBuilder;
+use flate2::write::GzEncoder;
+use rayon::prelude::*;
+
+use crate::NULL_NODE;
+use crate::Revision;
+use crate::errors::HgError;
+use crate::errors::IoResultExt;
+use crate::exit_codes;
+use crate::matchers::Matcher;
+use crate::repo::Repo;
+use crate::revlog::RevlogType;
+use crate::revlog::filelog::Filelog;
+use crate::revlog::manifest::ManifestFlags;
+use crate::revlog::options::default_revlog_options;
+use crate::tags::global_tags;
+use crate::tags::latest_tags;
+use crate::utils::files::get_bytes_from_path;
+use crate::utils::files::get_path_from_bytes;
+use crate::utils::files::normalize_path;
+use crate::utils::hg_path::HgPath;
+
+/// Name of the archive member describing the archived revision
+const METADATA_FILE: &str = ".hg_archival.txt";
+
+/// How many files to read in parallel before adding them to the archive,
+/// bounding memory usage
+const CHUNK_SIZE: usize = 256;
+
+/// The oldest date that can be stored in a zip archive, 1980-01-01
+const ZIP_EPOCH: i64 = 315532800;
+
+/// The types of archives, as given to `hg archive --type`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArchiveKind {
+    /// A directory full of files
+    Files,
+    /// An uncompressed tar archive
+    Tar,
+    /// A tar archive compressed using bzip2
+    Tbz2,
+    /// A tar archive compressed using gzip
+    Tgz,
+    /// A tar archive compressed using lzma, which is not supported here
+    Txz,
+    /// A tar archive compressed using zstd, which Python doesn't know about
+    Tzst,
+    /// An uncompressed zip archive
+    Uzip,
+    /// A zip archive compressed using deflate
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Kinds in the order Python tries their extensions. `tzst` is not one
+    /// of them, so that `hg` and `rhg` archive to the same paths alike.
+    const GUESSABLE: [Self; 5] =
+        [Self::Tar, Self::Tbz2, Self::Tgz, Self::Zip, Self::Txz];
+
+    /// Parse the name of an archive type, returning `None` if unknown
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        Some(match name {
+            b"files" => Self::Files,
+            b"tar" => Self::Tar,
+            b"tbz2" => Self::Tbz2,
+            b"tgz" => Self::Tgz,
+            b"txz" => Self::Txz,
+            b"tzst" => Self::Tzst,
+            b"uzip" => Self::Uzip,
+            b"zip" => Self::Zip,
+            _ => return None,
+        })
+    }
+
+    /// Guess the kind of archive from the extension of its destination
+    pub fn guess(dest: &[u8]) -> Option<Self> {
+        Self::GUESSABLE.into_iter().find(|kind| {
+            kind.extensions().iter().any(|ext| dest.ends_with(ext))
+        })
+    }
+
+    fn extensions(self) -> &'static [&'static [u8]] {
+        match self {
+            Self::Tar => &[b".tar"],
+            Self::Tbz2 => &[b".tbz2", b".tar.bz2"],
+            Self::Tgz => &[b".tgz", b".tar.gz"],
+            Self::Zip => &[b".zip"],
+            Self::Txz => &[b".txz", b".tar.xz"],
+            Self::Tzst => &[b".tzst", b".tar.zst"],
+            Self::Files | Self::Uzip => &[],
+        }
+    }
+}
+
+/// Returns the directory to put before the name of every archive member,
+/// like Python's `archival.tidyprefix`.
+///
+/// It defaults to the name of the destination without its extension, and is
+/// always empty when archiving to files.
+pub fn archive_prefix(
+    dest: &[u8],
+    kind: ArchiveKind,
+    prefix: Option<&[u8]>,
+) -> Result<Vec<u8>, HgError> {
+    let prefix = match prefix.filter(|prefix| !prefix.is_empty()) {
+        Some(_) if kind == ArchiveKind::Files => {
+            return Err(HgError::abort_simple(
+                "cannot give prefix when archiving to files",
+            ));
+        }
+        None if kind == ArchiveKind::Files => return Ok(vec![]),
+        Some(prefix) => prefix.to_owned(),
+        None => {
+            let name = match dest.iter().rposition(|&b| b == b'/') {
+                Some(slash) => &dest[slash + 1..],
+                None => dest,
+            };
+            let lower = name.to_ascii_lowercase();
+            match kind.extensions().iter().find(|ext| lower.ends_with(ext)) {
+                Some(ext) => name[..name.len() - ext.len()].to_owned(),
+                None => name.to_owned(),
+            }
+        }
+    };
+    let normalized = normalize_path(get_path_from_bytes(&prefix));
+    let mut prefix = get_bytes_from_path(&normalized);
+    if prefix.first() == Some(&b'/')
+        || prefix == b".."
+        || prefix.starts_with(b"../")
+    {
+        return Err(HgError::abort_simple(
+            "archive prefix contains illegal components",
+        ));
+    }
+    if !prefix.is_empty() {
+        prefix.push(b'/');
+    }
+    Ok(prefix)
+}
+
+/// Returns the contents of `.hg_archival.txt` for `rev`, with the default
+/// `experimental.archivemetatemplate`
+pub fn archive_metadata(
+    repo: &Repo,
+    rev: Revision,
+) -> Result<Vec<u8>, HgError> {
+    let changelog = repo.changelog()?;
+    let root = if changelog.get_index().is_empty() {
+        NULL_NODE
+    } else {
+        *changelog.node_from_rev(Revision(0))
+    };
+    let node = *changelog.node_from_rev(rev);
+    let extra = changelog.entry(rev)?.data()?.extra()?;
+    let branch = extra.get("branch").map_or(&b"default"[..], |b| b);
+    drop(changelog);
+
+    let mut metadata = format_bytes::format_bytes!(
+        b"repo: {}\nnode: {}\nbranch: {}\n",
+        format!("{:x}", root).as_bytes(),
+        format!("{:x}", node).as_bytes(),
+        branch
+    );
+    let latest = latest_tags(repo, rev, &global_tags(repo)?)?;
+    if latest.distance == 0 {
+        for tag in &latest.tags {
+            metadata.extend_from_slice(b"tag: ");
+            metadata.extend_from_slice(tag);
+            metadata.push(b'\n');
+        }
+    } else {
+        for tag in &latest.tags {
+            metadata.extend_from_slice(b"latesttag: ");
+            metadata.extend_from_slice(tag);
+            metadata.push(b'\n');
+        }
+        metadata.extend_from_slice(
+            format!(
+                "latesttagdistance: {}\nchangessincelatesttag: {}\n",
+                latest.distance, latest.changes
+            )
+            .as_bytes(),
+        );
+    }
+    Ok(metadata)
+}
+
+/// Options of [`archive`]
+pub struct ArchiveOptions {
+    /// The type of archive to create
+    pub kind: ArchiveKind,
+    /// The directory to put before the name of every member, from
+    /// [`archive_prefix`]
+    pub prefix: Vec<u8>,
+    /// Whether to add a `.hg_archival.txt` member (`ui.archivemeta`)
+    pub metadata: bool,
+}
+
+/// Archive the files of `rev` matched by `matcher` to `dest`.
+///
+/// Returns the number of files archived, not counting the metadata. Aborts
+/// without creating anything if no file matches.
+pub fn archive(
+    repo: &Repo,
+    rev: Revision,
+    matcher: &dyn Matcher,
+    dest: &Path,
+    options: &ArchiveOptions,
+) -> Result<usize, HgError> {
+    let manifest = repo.manifest_for_rev(rev.into())?;
+    let mut files = vec![];
+    for entry in manifest.iter() {
+        let entry = entry?;
+        if matcher.matches(entry.path) {
+            files.push(entry.decode()?);
+        }
+    }
+    if files.is_empty() {
+        return Err(HgError::abort(
+            "no files match the archive pattern",
+            exit_codes::ABORT,
+            None,
+        ));
+    }
+    let mtime = repo.changelog()?.entry(rev)?.data()?.timestamp()?.timestamp();
+
+    let mut archiver: Box<dyn Archiver> = match options.kind {
+        ArchiveKind::Files => Box::new(FilesArchiver {
+            base: dest.to_owned(),
+            mtime: filetime::FileTime::from_unix_time(mtime, 0),
+        }),
+        ArchiveKind::Tar => {
+            let file = File::create(dest).when_writing_file(dest)?;
+            Box::new(TarArchiver::new(TarOutput::Plain(file), dest, mtime))
+        }
+        ArchiveKind::Tbz2 => {
+            let file = File::create(dest).when_writing_file(dest)?;
+            let encoder = BzEncoder::new(file, bzip2::Compression::best());
+            Box::new(TarArchiver::new(TarOutput::Bzip2(encoder), dest, mtime))
+        }
+        ArchiveKind::Tgz => {
+            let file = File::create(dest).when_writing_file(dest)?;
+            // Like Python's `gzip` module, record the name of the archive
+            // without `.gz` and the modification time in the header
+            let name = dest.file_name().map_or(vec![], |name| {
+                let name = get_bytes_from_path(name);
+                name.strip_suffix(b".gz").unwrap_or(&name).to_owned()
+            });
+            let encoder = GzBuilder::new()
+                .filename(name)
+                .mtime(mtime.try_into().unwrap_or(0))
+                .write(file, Compression::best());
+            Box::new(TarArchiver::new(TarOutput::Gzip(encoder), dest, mtime))
+        }
+        ArchiveKind::Txz => {
+            return Err(HgError::unsupported("txz archives"));
+        }
+        ArchiveKind::Tzst => {
+            let file = File::create(dest).when_writing_file(dest)?;
+            let encoder =
+                zstd::Encoder::new(file, 0).when_writing_file(dest)?;
+            Box::new(TarArchiver::new(TarOutput::Zstd(encoder), dest, mtime))
+        }
+        ArchiveKind::Uzip | ArchiveKind::Zip => {
+            let file = File::create(dest).when_writing_file(dest)?;
+            Box::new(ZipArchiver::new(
+                file,
+                dest,
+                mtime,
+                options.kind == ArchiveKind::Zip,
+            )?)
+        }
+    };
+
+    let member = |path: &[u8]| [&options.prefix[..], path].concat();
+    if options.metadata && matcher.matches(HgPath::new(METADATA_FILE)) {
+        let metadata = archive_metadata(repo, rev)?;
+        archiver.add_file(
+            &member(METADATA_FILE.as_bytes()),
+            0o644,
+            false,
+            &metadata,
+        )?;
+    }
+    let filelog_options = default_revlog_options(
+        repo.config(),
+        repo.requirements(),
+        RevlogType::Filelog,
+    )?;
+    let store_vfs = repo.store_vfs();
+    for chunk in files.chunks(CHUNK_SIZE) {
+        let contents = chunk
+            .par_iter()
+            .map(|entry| {
+                let filelog = Filelog::open_vfs(
+                    &store_vfs,
+                    entry.path,
+                    &filelog_options,
+                )?;
+                let data =
+                    filelog.data_for_node(entry.node)?.into_file_data()?;
+                Ok((entry.path, entry.flags, data))
+            })
+            .collect::<Result<Vec<_>, HgError>>()?;
+        for (path, flags, data) in contents {
+            archiver.add_file(
+                &member(path.as_bytes()),
+                file_mode(flags),
+                flags.is_link(),
+                &data,
+            )?;
+        }
+    }
+    archiver.finish()?;
+    Ok(files.len())
+}
+
+fn file_mode(flags: ManifestFlags) -> u32 {
+    if flags.is_exec() { 0o755 } else { 0o644 }
+}
+
+/// A type of archive being written
+trait Archiver {
+    /// Add a member. The contents of a symlink are its target.
+    fn add_file(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        is_link: bool,
+        data: &[u8],
+    ) -> Result<(), HgError>;
+
+    /// Write what remains of the archive
+    fn finish(self: Box<Self>) -> Result<(), HgError>;
+}
+
+/// Writes members as files in a directory
+struct FilesArchiver {
+    base: PathBuf,
+    mtime: filetime::FileTime,
+}
+
+impl Archiver for FilesArchiver {
+    fn add_file(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        is_link: bool,
+        data: &[u8],
+    ) -> Result<(), HgError> {
+        let path = self.base.join(get_path_from_bytes(name));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).when_writing_file(parent)?;
+        }
+        if is_link {
+            std::os::unix::fs::symlink(get_path_from_bytes(data), &path)
+                .when_writing_file(&path)?;
+            return Ok(());
+        }
+        std::fs::write(&path, data).when_writing_file(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+            .when_writing_file(&path)?;
+        filetime::set_file_times(&path, self.mtime, self.mtime)
+            .when_writing_file(&path)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), HgError> {
+        Ok(())
+    }
+}
+
+/// The file a tar archive is written to, possibly compressed
+enum TarOutput {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+    Bzip2(BzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl TarOutput {
+    /// Write the end of the compressed stream, if any
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(mut file) => file.flush(),
+            Self::Gzip(encoder) => encoder.finish()?.flush(),
+            Self::Bzip2(encoder) => encoder.finish()?.flush(),
+            Self::Zstd(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for TarOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(file) => file.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Bzip2(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Bzip2(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+struct TarArchiver {
+    builder: tar::Builder<TarOutput>,
+    dest: PathBuf,
+    mtime: u64,
+}
+
+impl TarArchiver {
+    fn new(output: TarOutput, dest: &Path, mtime: i64) -> Self {
+        Self {
+            builder: tar::Builder::new(output),
+            dest: dest.to_owned(),
+            mtime: mtime.try_into().unwrap_or(0),
+        }
+    }
+}
+
+impl Archiver for TarArchiver {
+    fn add_file(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        is_link: bool,
+        data: &[u8],
+    ) -> Result<(), HgError> {
+        let path = get_path_from_bytes(name);
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(self.mtime);
+        if is_link {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_size(0);
+            self.builder.append_link(
+                &mut header,
+                path,
+                get_path_from_bytes(data),
+            )
+        } else {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(mode);
+            header.set_size(data.len() as u64);
+            self.builder.append_data(&mut header, path, data)
+        }
+        .when_writing_file(&self.dest)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), HgError> {
+        let output = self.builder.into_inner().when_writing_file(&self.dest)?;
+        output.finish().when_writing_file(&self.dest)?;
+        Ok(())
+    }
+}
+
+struct ZipArchiver {
+    writer: zip::ZipWriter<File>,
+    dest: PathBuf,
+    mtime: i64,
+    date_time: zip::DateTime,
+    compression: zip::CompressionMethod,
+}
+
+impl ZipArchiver {
+    fn new(
+        file: File,
+        dest: &Path,
+        mtime: i64,
+        compress: bool,
+    ) -> Result<Self, HgError> {
+        let mtime = mtime.max(ZIP_EPOCH);
+        let date = DateTime::from_timestamp(mtime, 0)
+            .ok_or_else(|| HgError::unsupported("zip archive date"))?;
+        let date_time = zip::DateTime::from_date_and_time(
+            date.year()
+                .try_into()
+                .map_err(|_| HgError::unsupported("zip archive date"))?,
+            date.month() as u8,
+            date.day() as u8,
+            date.hour() as u8,
+            date.minute() as u8,
+            date.second() as u8,
+        )
+        .map_err(|_| HgError::unsupported("zip archive date"))?;
+        Ok(Self {
+            writer: zip::ZipWriter::new(file),
+            dest: dest.to_owned(),
+            mtime,
+            date_time,
+            compression: if compress {
+                zip::CompressionMethod::Deflated
+            } else {
+                zip::CompressionMethod::Stored
+            },
+        })
+    }
+}
+
+impl Archiver for ZipArchiver {
+    fn add_file(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        is_link: bool,
+        data: &[u8],
+    ) -> Result<(), HgError> {
+        let utf8 = |bytes| {
+            std::str::from_utf8(bytes).map_err(|_| {
+                HgError::unsupported("non-UTF-8 file names in zip archives")
+            })
+        };
+        let name = utf8(name)?;
+        let mut options = zip::write::FullFileOptions::default()
+            .compression_method(self.compression)
+            .last_modified_time(self.date_time)
+            .large_file(data.len() as u64 >= u32::MAX as u64);
+        // Record the modification time in UTC, otherwise unzip uses the
+        // local timezone
+        let mut timestamp = vec![1];
+        timestamp.extend_from_slice(&(self.mtime as i32).to_le_bytes());
+        options
+            .add_extra_data(0x5455, timestamp, false)
+            .map_err(std::io::Error::from)
+            .when_writing_file(&self.dest)?;
+        if is_link {
+            self.writer.add_symlink(name, utf8(data)?, options)
+        } else {
+            self.writer
+                .start_file(name, options.unix_permissions(mode))
+                .and_then(|()| Ok(self.writer.write_all(data)?))
+        }
+        .map_err(std::io::Error::from)
+        .when_writing_file(&self.dest)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), HgError> {
+        self.writer
+            .finish()
+            .map_err(std::io::Error::from)
+            .when_writing_file(&self.dest)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::matchers::AlwaysMatcher;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn test_guess_kind() {
+        assert_eq!(ArchiveKind::guess(b"a.tar"), Some(ArchiveKind::Tar));
+        assert_eq!(ArchiveKind::guess(b"a.tar.gz"), Some(ArchiveKind::Tgz));
+        assert_eq!(ArchiveKind::guess(b"a.tbz2"), Some(ArchiveKind::Tbz2));
+        assert_eq!(ArchiveKind::guess(b"a.tar.xz"), Some(ArchiveKind::Txz));
+        assert_eq!(ArchiveKind::guess(b"a.zip"), Some(ArchiveKind::Zip));
+        assert_eq!(ArchiveKind::guess(b"a.tar.zst"), None);
+        assert_eq!(ArchiveKind::guess(b"a.ZIP"), None);
+        assert_eq!(ArchiveKind::guess(b"a"), None);
+    }
+
+    #[test]
+    fn test_archive_prefix() {
+        let prefix = |dest: &[u8], kind, prefix: Option<&[u8]>| {
+            archive_prefix(dest, kind, prefix).map_err(|e| e.to_string())
+        };
+        let tgz = ArchiveKind::Tgz;
+        assert_eq!(prefix(b"out/a.TAR.GZ", tgz, None), Ok(b"a/".to_vec()));
+        assert_eq!(
+            prefix(b"a.tgz", tgz, Some(b"b/./c/")),
+            Ok(b"b/c/".to_vec())
+        );
+        assert_eq!(prefix(b"a.tgz", tgz, Some(b"./")), Ok(vec![]));
+        assert_eq!(prefix(b"a.zip", tgz, None), Ok(b"a.zip/".to_vec()));
+        assert!(prefix(b"a.tgz", tgz, Some(b"../b")).is_err());
+        assert!(prefix(b"a.tgz", tgz, Some(b"/b")).is_err());
+        assert_eq!(prefix(b"a", ArchiveKind::Files, None), Ok(vec![]));
+        assert!(prefix(b"a", ArchiveKind::Files, Some(b"b")).is_err());
+    }
+
+    /// Archives the first revision of a repository with two files, returning
+    /// the archive
+    fn archive_test_repo(kind: ArchiveKind, name: &str) -> Vec<u8> {
+        let mut repo = TestRepo::new(&[]);
+        repo.commit(NULL_NODE, &[("a", Some("a\n")), ("dir/b", Some("b\n"))]);
+        let dest = repo.path().join(name);
+        let options =
+            ArchiveOptions { kind, prefix: b"out/".to_vec(), metadata: false };
+        let count =
+            archive(&repo.repo, Revision(0), &AlwaysMatcher, &dest, &options)
+                .unwrap();
+        assert_eq!(count, 2);
+        std::fs::read(dest).unwrap()
+    }
+
+    fn read_tar(archive: impl std::io::Read) -> Vec<(String, u32, Vec<u8>)> {
+        let mut archive = tar::Archive::new(archive);
+        let mut members = vec![];
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_owned();
+            let mode = entry.header().mode().unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), 0);
+            let mut data = vec![];
+            entry.read_to_end(&mut data).unwrap();
+            members.push((name, mode, data));
+        }
+        members
+    }
+
+    fn expected_members() -> Vec<(String, u32, Vec<u8>)> {
+        vec![
+            ("out/a".to_owned(), 0o644, b"a\n".to_vec()),
+            ("out/dir/b".to_owned(), 0o644, b"b\n".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn test_tar_round_trip() {
+        let archive = archive_test_repo(ArchiveKind::Tar, "out.tar");
+        assert_eq!(read_tar(&archive[..]), expected_members());
+    }
+
+    #[test]
+    fn test_tgz_round_trip() {
+        let archive = archive_test_repo(ArchiveKind::Tgz, "out.tar.gz");
+        let decoder = flate2::read::GzDecoder::new(&archive[..]);
+        assert_eq!(decoder.header().unwrap().filename(), Some(&b"out.tar"[..]));
+        assert_eq!(read_tar(decoder), expected_members());
+    }
+
+    #[test]
+    fn test_tzst_round_trip() {
+        let archive = archive_test_repo(ArchiveKind::Tzst, "out.tar.zst");
+        let decoder = zstd::Decoder::new(&archive[..]).unwrap();
+        assert_eq!(read_tar(decoder), expected_members());
+    }
+
+    #[test]
+    fn test_zip_round_trip() {
+        for (kind, method) in [
+            (ArchiveKind::Zip, zip::CompressionMethod::Deflated),
+            (ArchiveKind::Uzip, zip::CompressionMethod::Stored),
+        ] {
+            let archive = archive_test_repo(kind, "out.zip");
+            let mut archive =
+                zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+            let mut members = vec![];
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i).unwrap();
+                assert_eq!(file.compression(), method);
+                // Before 1980, so clamped to the zip epoch
+                assert_eq!(
+                    file.last_modified(),
+                    Some(zip::DateTime::default())
+                );
+                let name = file.name().to_owned();
+                let mode = file.unix_mode().unwrap() & 0o777;
+                let mut data = vec![];
+                file.read_to_end(&mut data).unwrap();
+                members.push((name, mode, data));
+            }
+            assert_eq!(members, expected_members());
+        }
+    }
+}
@@ -0,0 +1,786 @@
+//! Copy and rename files of the working copy, recording their sources in the
+//! dirstate. This is `hg copy` and `hg rename`.
+//!
+//! A [`Copier`] first finds the files to copy for each pattern given on the
+//! command line and where to copy them, then copies them one at a time. The
+//! caller is responsible for taking the working copy lock and writing the
+//! dirstate.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::FastHashMap;
+use crate::FastHashSet;
+use crate::NULL_NODE;
+use crate::dirstate::on_disk::write_tracked_key;
+use crate::dirstate::owning::OwningDirstateMap;
+use crate::dirstate::status::BadMatch;
+use crate::errors::HgError;
+use crate::exit_codes;
+use crate::matchers::Matcher;
+use crate::repo::Repo;
+use crate::revlog::manifest::Manifest;
+use crate::update::working_copy_remove;
+use crate::utils::files::canonical_path;
+use crate::utils::files::check_windows_filename;
+use crate::utils::files::get_bytes_from_path;
+use crate::utils::files::get_path_from_bytes;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::utils::hg_path::HgPathError;
+use crate::utils::hg_path::HgPathErrorKind;
+use crate::utils::hg_path::hg_path_to_path_buf;
+use crate::utils::hg_path::path_to_hg_path_buf;
+use crate::utils::path_auditor::PathAuditor;
+use crate::utils::strings::shell_quote;
+use crate::warnings::HgWarning;
+use crate::warnings::HgWarningContext;
+
+use super::PortabilityCheck;
+use super::TrackingWarning;
+use super::tracking::add_files;
+use super::tracking::check_file_names;
+use super::tracking::explicit_files;
+use super::tracking::forget_files;
+use super::tracking::is_file_on_disk;
+use super::tracking::walk;
+
+/// How to copy files
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyOptions {
+    /// Remove the sources after copying them
+    pub rename: bool,
+    /// Only record copies that were already done in the working directory
+    pub after: bool,
+    /// Overwrite existing targets
+    pub force: bool,
+    /// Do not change anything, only report what would be done
+    pub dry_run: bool,
+}
+
+/// A file to copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopySource {
+    pub path: HgPathBuf,
+    /// Whether the file was given explicitly rather than through a directory
+    pub exact: bool,
+}
+
+/// The files to copy for one of the given patterns
+#[derive(Debug, Default)]
+pub struct CopySources {
+    pub sources: Vec<CopySource>,
+    /// Explicitly given paths that could not be looked at
+    pub bad: Vec<(HgPathBuf, BadMatch)>,
+    /// Explicitly given files that will not be copied
+    pub skipped: Vec<CopyWarning>,
+}
+
+/// Reasons for not copying a file, and other issues met while copying
+#[derive(Debug)]
+pub enum CopyWarning {
+    /// The source is marked as removed
+    MarkedForRemove(HgPathBuf),
+    /// The source is not tracked
+    NotManaged(HgPathBuf),
+    /// Another source was already copied to the same target
+    Collides { target: HgPathBuf, source: HgPathBuf, previous: HgPathBuf },
+    /// The target exists in the working directory
+    TargetExists(HgPathBuf),
+    /// The target is tracked in the parent revision. `copied_in_parent` is
+    /// whether it looks like the copy was done in the parent revision.
+    TargetCommitted { target: HgPathBuf, copied_in_parent: bool },
+    /// When recording a copy after the fact, the target does not exist
+    TargetMissing { source: HgPathBuf, target: HgPathBuf },
+    /// The source is missing from the working directory, so only the copy
+    /// is recorded
+    SourceDeleted(HgPathBuf),
+    /// Copying the file failed
+    CannotCopy(HgPathBuf, std::io::Error),
+    /// The source was added in the working copy, so there is no copy to
+    /// record
+    NotCommitted { source: HgPathBuf, target: HgPathBuf },
+    /// The target is neither a regular file nor a symlink
+    NotAFile(HgPathBuf),
+    /// Issue met when adding the target or forgetting the source
+    Tracking(TrackingWarning),
+}
+
+/// What happened when copying a file, in order
+#[derive(Debug)]
+pub enum CopyEvent {
+    Warning(CopyWarning),
+    /// The file was copied, or would have been in a dry run. This is
+    /// absent if the copy failed.
+    Copied,
+}
+
+/// Copies files of the working copy, remembering where previous ones went
+pub struct Copier<'a> {
+    repo: &'a Repo,
+    options: CopyOptions,
+    portability: PortabilityCheck,
+    remove_empty_dirs: bool,
+    parent_manifest: Manifest,
+    /// Checks the paths read, written or removed in the working directory
+    auditor: PathAuditor,
+    /// Files copied so far, by target
+    targets: FastHashMap<HgPathBuf, HgPathBuf>,
+}
+
+impl<'a> Copier<'a> {
+    pub fn new(repo: &'a Repo, options: CopyOptions) -> Result<Self, HgError> {
+        let config = repo.config();
+        let p1 = repo.dirstate_parents()?.p1;
+        let parent_manifest = if p1 == NULL_NODE {
+            Manifest::empty()
+        } else {
+            repo.manifest_for_node(p1)?
+        };
+        Ok(Self {
+            repo,
+            options,
+            portability: PortabilityCheck::from_config(config)?,
+            remove_empty_dirs: config
+                .get_bool(b"experimental", b"removeemptydirs")?,
+            parent_manifest,
+            auditor: PathAuditor::new(repo.working_directory_path()),
+            targets: FastHashMap::default(),
+        })
+    }
+
+    /// Find the files matched by `matcher` that can be copied. Those that
+    /// are removed can only be copied after the fact.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn sources(
+        &self,
+        dirstate: &mut OwningDirstateMap,
+        matcher: &impl Matcher,
+    ) -> Result<CopySources, HgError> {
+        let walk = walk(self.repo, dirstate, matcher, true, true)?;
+        let tracked: FastHashSet<_> =
+            [&walk.modified, &walk.added, &walk.deleted, &walk.clean]
+                .into_iter()
+                .flatten()
+                .collect();
+        let mut paths: Vec<&HgPathBuf> = tracked
+            .iter()
+            .copied()
+            .chain(&walk.removed)
+            .chain(&walk.unknown)
+            .collect();
+        // Explicitly given ignored files are not listed as unknown
+        for path in explicit_files(matcher) {
+            if !path.is_empty()
+                && !paths.contains(&path)
+                && !walk.bad.iter().any(|(bad, _)| bad == path)
+                && is_file_on_disk(self.repo, path)?
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort_unstable();
+
+        let mut sources = CopySources::default();
+        for path in paths {
+            let exact = matcher.exact_match(path);
+            if !tracked.contains(path) {
+                if self.parent_manifest.find_by_path(path)?.is_some() {
+                    if !self.options.after {
+                        if exact {
+                            sources.skipped.push(CopyWarning::MarkedForRemove(
+                                path.to_owned(),
+                            ));
+                        }
+                        continue;
+                    }
+                } else {
+                    if exact {
+                        sources
+                            .skipped
+                            .push(CopyWarning::NotManaged(path.to_owned()));
+                    }
+                    continue;
+                }
+            }
+            sources.sources.push(CopySource { path: path.to_owned(), exact });
+        }
+        sources.bad = walk.bad;
+        Ok(sources)
+    }
+
+    /// Returns where to copy each of `sources`, which were found for the
+    /// command line `pattern`. Like `pattern` and `dest`, `cwd` is a path of
+    /// the filesystem.
+    ///
+    /// When copying a directory, the targets keep the paths of the sources
+    /// relative to it, or to its parent if `dest` exists. After the fact,
+    /// the layout that matches the most existing files is picked.
+    pub fn targets(
+        &self,
+        cwd: &Path,
+        pattern: &Path,
+        dest: &Path,
+        dest_dir_exists: bool,
+        sources: &[CopySource],
+    ) -> Result<Vec<HgPathBuf>, HgError> {
+        let root = self.repo.working_directory_path();
+        let dest = cwd.join(dest);
+        let pattern_prefix = || -> Result<Vec<u8>, HgError> {
+            let path =
+                canonical_path(root, cwd, pattern).map_err(audit_error)?;
+            Ok(get_bytes_from_path(path))
+        };
+        // Length of the prefix of the sources to replace with `dest`
+        let strip_len = |prefix: &[u8]| {
+            if prefix.is_empty() {
+                0
+            } else {
+                prefix.len() + 1
+            }
+        };
+        let parent_len = |prefix: &[u8]| {
+            strip_len(match prefix.iter().rposition(|&b| b == b'/') {
+                Some(index) => &prefix[..index],
+                None => b"",
+            })
+        };
+        let joined = |source: &HgPath, strip: usize| {
+            dest.join(get_path_from_bytes(&source.as_bytes()[strip..]))
+        };
+        let base_name = |source: &HgPath| {
+            dest.join(
+                get_path_from_bytes(source.as_bytes())
+                    .file_name()
+                    .expect("a source file name is never empty"),
+            )
+        };
+
+        let targets: Vec<PathBuf> = if !self.options.after {
+            if cwd.join(pattern).is_dir() {
+                let prefix = pattern_prefix()?;
+                let strip = if dest_dir_exists {
+                    parent_len(&prefix)
+                } else {
+                    strip_len(&prefix)
+                };
+                sources.iter().map(|s| joined(&s.path, strip)).collect()
+            } else if dest_dir_exists {
+                sources.iter().map(|s| base_name(&s.path)).collect()
+            } else {
+                sources.iter().map(|_| dest.to_owned()).collect()
+            }
+        } else {
+            let prefix = pattern_prefix()?;
+            if sources.first().is_some_and(|s| prefix.len() < s.path.len()) {
+                // A directory. The target paths may or may not contain its
+                // last component.
+                let existing = |strip: usize| {
+                    sources
+                        .iter()
+                        .filter(|s| {
+                            std::fs::symlink_metadata(joined(&s.path, strip))
+                                .is_ok()
+                        })
+                        .count()
+                };
+                let mut strip = strip_len(&prefix);
+                let last_component = get_path_from_bytes(&prefix).file_name();
+                if last_component.is_some_and(|name| dest.join(name).is_dir()) {
+                    let parent_strip = parent_len(&prefix);
+                    if existing(parent_strip) > existing(strip) {
+                        strip = parent_strip;
+                    }
+                }
+                sources.iter().map(|s| joined(&s.path, strip)).collect()
+            } else if dest_dir_exists {
+                sources.iter().map(|s| base_name(&s.path)).collect()
+            } else {
+                sources.iter().map(|_| dest.to_owned()).collect()
+            }
+        };
+        targets
+            .into_iter()
+            .map(|target| {
+                let path =
+                    canonical_path(root, cwd, target).map_err(audit_error)?;
+                Ok(path_to_hg_path_buf(path)?)
+            })
+            .collect()
+    }
+
+    /// Copy `source` to `target` in the working directory, unless copying
+    /// after the fact, and record the copy in the dirstate. When renaming,
+    /// also remove the source.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn copy(
+        &mut self,
+        dirstate: &mut OwningDirstateMap,
+        source: &HgPath,
+        target: &HgPath,
+    ) -> Result<Vec<CopyEvent>, HgError> {
+        let CopyOptions { rename, after, force, dry_run } = self.options;
+        let mut events = vec![];
+        let mut warn = |warning| events.push(CopyEvent::Warning(warning));
+
+        self.audit(source)?;
+        self.audit(target)?;
+        check_file_names([target])?;
+        if self.portability != PortabilityCheck::Ignore
+            && let Some(reason) = check_windows_filename(target.as_bytes())
+        {
+            if self.portability == PortabilityCheck::Abort {
+                return Err(HgError::abort(
+                    format!(
+                        "{}: {}",
+                        reason,
+                        String::from_utf8_lossy(&shell_quote(
+                            target.as_bytes()
+                        ))
+                    ),
+                    exit_codes::INPUT_ERROR,
+                    None,
+                ));
+            }
+            warn(CopyWarning::Tracking(TrackingWarning::NotPortable(
+                target.to_owned(),
+                reason,
+            )));
+        }
+
+        if let Some(previous) = self.targets.get(target) {
+            warn(CopyWarning::Collides {
+                target: target.to_owned(),
+                source: source.to_owned(),
+                previous: previous.to_owned(),
+            });
+            return Ok(events);
+        }
+
+        let working_directory = self.repo.working_directory_path();
+        let fs_source = working_directory.join(hg_path_to_path_buf(source)?);
+        let fs_target = working_directory.join(hg_path_to_path_buf(target)?);
+        let exists = std::fs::symlink_metadata(&fs_target).is_ok();
+        let already_committed =
+            dirstate.get(target)?.is_some_and(|e| e.tracked() && !e.added());
+        if ((!after && exists) || (after && already_committed)) && !force {
+            if already_committed {
+                let copied_in_parent =
+                    self.looks_like_copy_in_parent(source, target)?;
+                warn(CopyWarning::TargetCommitted {
+                    target: target.to_owned(),
+                    copied_in_parent,
+                });
+            } else {
+                warn(CopyWarning::TargetExists(target.to_owned()));
+            }
+            return Ok(events);
+        }
+
+        let mut source_exists = true;
+        if after {
+            if !exists {
+                warn(CopyWarning::TargetMissing {
+                    source: source.to_owned(),
+                    target: target.to_owned(),
+                });
+                return Ok(events);
+            }
+        } else if !dry_run {
+            match copy_working_file(&fs_source, &fs_target, exists, rename) {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                    warn(CopyWarning::SourceDeleted(source.to_owned()));
+                    source_exists = false;
+                }
+                Err(error) => {
+                    warn(CopyWarning::CannotCopy(source.to_owned(), error));
+                    return Ok(events);
+                }
+            }
+        }
+        events.push(CopyEvent::Copied);
+        self.targets.insert(target.to_owned(), source.to_owned());
+
+        let tracking_warnings = HgWarningContext::new();
+        self.record_copy(
+            dirstate,
+            source,
+            target,
+            &mut events,
+            &tracking_warnings,
+        )?;
+        if rename && !dry_run {
+            if !after && source_exists {
+                self.audit(source)?;
+                working_copy_remove(
+                    hg_path_to_path_buf(source)?,
+                    &self.repo.working_directory_vfs(),
+                    self.remove_empty_dirs,
+                )?;
+            }
+            forget_files(
+                self.repo,
+                dirstate,
+                &[source],
+                tracking_warnings.sender(),
+            )?;
+        }
+        tracking_warnings.finish(|warning| {
+            if let HgWarning::Tracking(warning) = warning {
+                events.push(CopyEvent::Warning(CopyWarning::Tracking(warning)))
+            }
+            Ok::<_, HgError>(())
+        })?;
+        Ok(events)
+    }
+
+    /// Check that `path` can be used in the working directory
+    fn audit(&self, path: &HgPath) -> Result<(), HgError> {
+        self.auditor.audit_path(path).map_err(audit_error)
+    }
+
+    /// Record in the dirstate that `source` was copied to `target`. The copy
+    /// is recorded from the original source if `source` is itself a copy,
+    /// and only if `source` is not newly added.
+    fn record_copy(
+        &self,
+        dirstate: &mut OwningDirstateMap,
+        source: &HgPath,
+        target: &HgPath,
+        events: &mut Vec<CopyEvent>,
+        tracking_warnings: &HgWarningContext,
+    ) -> Result<(), HgError> {
+        let dry_run = self.options.dry_run;
+        let original = match dirstate.copy_map_get(source)? {
+            Some(original) => original.to_owned(),
+            None => source.to_owned(),
+        };
+        if *original == *target {
+            // Copying back a copy
+            let entry = dirstate.get(target)?;
+            if entry.is_none_or(|e| e.added() || !e.tracked())
+                && !dry_run
+                && dirstate.set_tracked(target)?
+            {
+                write_tracked_key(self.repo)?;
+            }
+        } else if *original == *source
+            && dirstate.get(source)?.is_some_and(|e| e.added())
+        {
+            events.push(CopyEvent::Warning(CopyWarning::NotCommitted {
+                source: source.to_owned(),
+                target: target.to_owned(),
+            }));
+            if !dirstate.get(target)?.is_some_and(|e| e.tracked()) && !dry_run {
+                add_files(
+                    self.repo,
+                    dirstate,
+                    &[target],
+                    tracking_warnings.sender(),
+                )?;
+            }
+        } else if !dry_run {
+            let fs_target = self
+                .repo
+                .working_directory_path()
+                .join(hg_path_to_path_buf(target)?);
+            match std::fs::symlink_metadata(fs_target) {
+                Err(_) => {
+                    events.push(CopyEvent::Warning(CopyWarning::Tracking(
+                        TrackingWarning::DoesNotExist(target.to_owned()),
+                    )))
+                }
+                Ok(metadata)
+                    if !(metadata.is_file() || metadata.is_symlink()) =>
+                {
+                    events.push(CopyEvent::Warning(CopyWarning::NotAFile(
+                        target.to_owned(),
+                    )))
+                }
+                Ok(_) => {
+                    if dirstate.set_tracked(target)? {
+                        write_tracked_key(self.repo)?;
+                    }
+                    dirstate.copy_map_insert(target, &original)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `target` was added in the parent revision while `source`
+    /// already existed, meaning the copy was probably done there
+    fn looks_like_copy_in_parent(
+        &self,
+        source: &HgPath,
+        target: &HgPath,
+    ) -> Result<bool, HgError> {
+        let p1 = self.repo.dirstate_parents()?.p1;
+        if p1 == NULL_NODE
+            || self.parent_manifest.find_by_path(target)?.is_none()
+        {
+            return Ok(false);
+        }
+        let changelog = self.repo.changelog()?;
+        let entry = changelog.entry(changelog.rev_from_node(p1.into())?)?;
+        for grandparent in
+            [entry.p1_entry()?, entry.p2_entry()?].into_iter().flatten()
+        {
+            let manifest = self
+                .repo
+                .manifest_for_node(*grandparent.as_revlog_entry().node())?;
+            if manifest.find_by_path(source)?.is_some()
+                && manifest.find_by_path(target)?.is_none()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Abort with the message of Python's path auditor for the paths it refuses.
+/// Like there, the paths refused because of what is on disk are not input
+/// errors.
+fn audit_error(error: HgPathError) -> HgError {
+    let (message, exit_code) = match error.kind {
+        HgPathErrorKind::EndsWithSlash(path) => (
+            format!("path ends in directory separator: {}", path),
+            exit_codes::INPUT_ERROR,
+        ),
+        HgPathErrorKind::ContainsIllegalComponent(path)
+        | HgPathErrorKind::InsideDotHg(path) => (
+            format!("path contains illegal component: {}", path),
+            exit_codes::INPUT_ERROR,
+        ),
+        HgPathErrorKind::IsInsideNestedRepo { path, nested_repo } => {
+            let lexical = path
+                .as_bytes()
+                .split(|&b| b == b'/')
+                .any(|component| component.eq_ignore_ascii_case(b".hg"));
+            (
+                format!(
+                    "path '{}' is inside nested repo '{}'",
+                    path, nested_repo
+                ),
+                if lexical {
+                    exit_codes::INPUT_ERROR
+                } else {
+                    exit_codes::ABORT
+                },
+            )
+        }
+        HgPathErrorKind::TraversesSymbolicLink { path, symlink } => (
+            format!("path '{}' traverses symbolic link '{}'", path, symlink),
+            exit_codes::ABORT,
+        ),
+        _ => return error.into(),
+    };
+    HgError::abort(message, exit_code, None)
+}
+
+/// Copy a file or symlink of the working directory, replacing the target if
+/// it `exists`. With `keep_times`, the target gets the access and
+/// modification times of the source, as when moving files.
+fn copy_working_file(
+    source: &Path,
+    target: &Path,
+    exists: bool,
+    keep_times: bool,
+) -> Result<(), std::io::Error> {
+    if exists {
+        std::fs::remove_file(target)?;
+    }
+    if let Some(parent) = target.parent()
+        && !parent.is_dir()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let metadata = std::fs::symlink_metadata(source)?;
+    if metadata.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(source)?, target)?;
+    } else {
+        // This also copies the permissions
+        std::fs::copy(source, target)?;
+        if keep_times {
+            filetime::set_file_times(
+                target,
+                filetime::FileTime::from_last_access_time(&metadata),
+                filetime::FileTime::from_last_modification_time(&metadata),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    fn path(path: &str) -> &HgPath {
+        HgPath::new(path.as_bytes())
+    }
+
+    fn copied(events: &[CopyEvent]) -> bool {
+        events.iter().any(|event| matches!(event, CopyEvent::Copied))
+    }
+
+    fn abort_message(error: HgError) -> String {
+        match error {
+            HgError::Abort { message, .. } => message,
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    fn test_repo() -> TestRepo {
+        let mut repo = TestRepo::new(&[]);
+        let node = repo
+            .commit(NULL_NODE, &[("a", Some("a\n")), ("dir/b", Some("b\n"))]);
+        repo.update_from_null(node);
+        repo
+    }
+
+    #[test]
+    fn test_copy() {
+        let repo = test_repo();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let mut copier =
+            Copier::new(&repo.repo, CopyOptions::default()).unwrap();
+        let events = copier.copy(&mut dirstate, path("a"), path("c")).unwrap();
+        assert!(copied(&events));
+        assert_eq!(std::fs::read(repo.path().join("c")).unwrap(), b"a\n");
+        assert!(repo.path().join("a").exists());
+        assert!(dirstate.get(path("c")).unwrap().unwrap().added());
+        assert_eq!(dirstate.copy_map_get(path("c")).unwrap(), Some(path("a")));
+
+        // The target is taken
+        let events =
+            copier.copy(&mut dirstate, path("dir/b"), path("c")).unwrap();
+        assert!(!copied(&events));
+        assert!(matches!(
+            &events[..],
+            [CopyEvent::Warning(CopyWarning::Collides { .. })]
+        ));
+        let mut copier =
+            Copier::new(&repo.repo, CopyOptions::default()).unwrap();
+        let events =
+            copier.copy(&mut dirstate, path("dir/b"), path("c")).unwrap();
+        assert!(matches!(
+            &events[..],
+            [CopyEvent::Warning(CopyWarning::TargetExists(_))]
+        ));
+
+        // Copies of copies are recorded from the original source
+        let events = copier.copy(&mut dirstate, path("c"), path("d")).unwrap();
+        assert!(copied(&events));
+        assert_eq!(dirstate.copy_map_get(path("d")).unwrap(), Some(path("a")));
+    }
+
+    #[test]
+    fn test_rename() {
+        let repo = test_repo();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let options = CopyOptions { rename: true, ..Default::default() };
+        let mut copier = Copier::new(&repo.repo, options).unwrap();
+        let events =
+            copier.copy(&mut dirstate, path("dir/b"), path("moved")).unwrap();
+        assert!(copied(&events));
+        assert!(!repo.path().join("dir/b").exists());
+        assert!(dirstate.get(path("dir/b")).unwrap().unwrap().removed());
+        assert!(dirstate.get(path("moved")).unwrap().unwrap().added());
+        assert_eq!(
+            dirstate.copy_map_get(path("moved")).unwrap(),
+            Some(path("dir/b"))
+        );
+    }
+
+    #[test]
+    fn test_copy_after() {
+        let repo = test_repo();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let options = CopyOptions { after: true, ..Default::default() };
+        let mut copier = Copier::new(&repo.repo, options).unwrap();
+        let events = copier.copy(&mut dirstate, path("a"), path("c")).unwrap();
+        assert!(matches!(
+            &events[..],
+            [CopyEvent::Warning(CopyWarning::TargetMissing { .. })]
+        ));
+        assert!(dirstate.get(path("c")).unwrap().is_none());
+
+        // Only the copy is recorded, the file is left alone
+        repo.write("c", "changed\n");
+        let events = copier.copy(&mut dirstate, path("a"), path("c")).unwrap();
+        assert!(copied(&events));
+        assert_eq!(std::fs::read(repo.path().join("c")).unwrap(), b"changed\n");
+        assert_eq!(dirstate.copy_map_get(path("c")).unwrap(), Some(path("a")));
+
+        // The target is already committed
+        let events =
+            copier.copy(&mut dirstate, path("a"), path("dir/b")).unwrap();
+        assert!(matches!(
+            &events[..],
+            [CopyEvent::Warning(CopyWarning::TargetCommitted {
+                copied_in_parent: false,
+                ..
+            })]
+        ));
+
+        // Renaming after the fact keeps the missing source missing
+        std::fs::rename(repo.path().join("a"), repo.path().join("moved"))
+            .unwrap();
+        let options = CopyOptions { rename: true, after: true, ..options };
+        let mut copier = Copier::new(&repo.repo, options).unwrap();
+        let events =
+            copier.copy(&mut dirstate, path("a"), path("moved")).unwrap();
+        assert!(copied(&events));
+        assert!(dirstate.get(path("a")).unwrap().unwrap().removed());
+        assert_eq!(
+            dirstate.copy_map_get(path("moved")).unwrap(),
+            Some(path("a"))
+        );
+    }
+
+    #[test]
+    fn test_copy_audits_paths() {
+        let repo = test_repo();
+        std::fs::create_dir(repo.path().join("target")).unwrap();
+        std::os::unix::fs::symlink("target", repo.path().join("link")).unwrap();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let options = CopyOptions { rename: true, ..Default::default() };
+        let mut copier = Copier::new(&repo.repo, options).unwrap();
+        let source =
+            CopySource { path: HgPathBuf::from_bytes(b"a"), exact: true };
+
+        let error = copier
+            .targets(
+                repo.path(),
+                Path::new("a"),
+                Path::new("link/a"),
+                false,
+                std::slice::from_ref(&source),
+            )
+            .unwrap_err();
+        assert_eq!(
+            abort_message(error),
+            "path 'link/a' traverses symbolic link 'link'"
+        );
+        let error =
+            copier.copy(&mut dirstate, path("a"), path("link/a")).unwrap_err();
+        assert_eq!(
+            abort_message(error),
+            "path 'link/a' traverses symbolic link 'link'"
+        );
+        assert!(!repo.path().join("target/a").exists());
+        let error =
+            copier.copy(&mut dirstate, path("a"), path(".hg/a")).unwrap_err();
+        assert_eq!(
+            abort_message(error),
+            "path contains illegal component: .hg/a"
+        );
+        // Nothing was removed
+        assert!(repo.path().join("a").exists());
+        assert!(dirstate.get(path("a")).unwrap().unwrap().tracked());
+    }
+}
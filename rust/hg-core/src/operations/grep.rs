@@ -0,0 +1,676 @@
+//! Search the contents of files for lines matching a regular expression, in
+//! the working copy or in revisions. This is `hg grep`, the Rust counterpart
+//! of `mercurial/grep.py`.
+//!
+//! Files are read and searched in parallel, and results are returned in the
+//! order Python displays them.
+
+use rayon::prelude::*;
+use regex_automata::Input;
+use regex_automata::meta::Regex;
+use regex_automata::util::syntax;
+
+use crate::FastHashMap;
+use crate::Node;
+use crate::Revision;
+use crate::dirstate::DirstateError;
+use crate::errors::HgError;
+use crate::errors::HgResultExt;
+use crate::matchers::Matcher;
+use crate::repo::Repo;
+use crate::revlog::RevlogType;
+use crate::revlog::filelog::Filelog;
+use crate::revlog::manifest::Manifest;
+use crate::revlog::options::RevlogOpenOptions;
+use crate::revlog::options::default_revlog_options;
+use crate::utils::RawData;
+use crate::utils::files::get_bytes_from_path;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::utils::hg_path::hg_path_to_path_buf;
+use crate::vfs::VfsImpl;
+
+/// How many revisions to search in parallel when looking for changes,
+/// bounding memory usage
+const CHUNK_SIZE: usize = 64;
+
+/// A compiled `hg grep` pattern, searched from many threads at once.
+///
+/// Like [`crate::matchers`] does for its regexes, every thread gets its own
+/// clone of the compiled regex to avoid contending on its lazy DFA cache.
+pub struct GrepPattern {
+    base: Regex,
+    local: thread_local::ThreadLocal<Regex>,
+}
+
+impl GrepPattern {
+    /// Returns the start and end of the first match in `haystack` starting
+    /// at or after `start`, if any
+    pub fn find_at(
+        &self,
+        haystack: &[u8],
+        start: usize,
+    ) -> Option<(usize, usize)> {
+        let regex = self.local.get_or(|| self.base.clone());
+        let found = regex.find(Input::new(haystack).range(start..))?;
+        Some((found.start(), found.end()))
+    }
+
+    pub fn is_match(&self, haystack: &[u8]) -> bool {
+        self.local.get_or(|| self.base.clone()).is_match(haystack)
+    }
+}
+
+/// Compile a `hg grep` pattern, which is matched in multi-line mode against
+/// whole files.
+///
+/// Like Python's `re` module with byte strings, classes and case folding
+/// only know about ASCII. Patterns that the regex engine does not support
+/// (back-references, look-around…) are reported as unsupported, since they
+/// may be valid for Python.
+pub fn compile_pattern(
+    pattern: &[u8],
+    ignore_case: bool,
+    word: bool,
+) -> Result<GrepPattern, HgError> {
+    let pattern = std::str::from_utf8(pattern)
+        .map_err(|_| HgError::unsupported("non-UTF-8 grep pattern"))?;
+    if has_rust_only_syntax(pattern) {
+        return Err(HgError::unsupported(
+            "grep pattern with a different meaning in Python",
+        ));
+    }
+    let pattern = if word {
+        format!(r"\b(?:{pattern})\b")
+    } else {
+        pattern.to_owned()
+    };
+    let base = Regex::builder()
+        .configure(Regex::config().utf8_empty(false))
+        .syntax(
+            syntax::Config::new()
+                .unicode(false)
+                .utf8(false)
+                .multi_line(true)
+                .case_insensitive(ignore_case),
+        )
+        .build(&pattern)
+        .map_err(|error| {
+            let message = match error.syntax_error() {
+                Some(syntax_error) => syntax_error.to_string(),
+                None => error.to_string(),
+            };
+            HgError::unsupported(format!("grep pattern: {message}"))
+        })?;
+    Ok(GrepPattern { base, local: Default::default() })
+}
+
+/// Whether `pattern` uses syntax that the regex engine accepts but that
+/// means something else to Python's `re` module: word boundaries like `\<`,
+/// and nested classes or set operations in character classes, including
+/// POSIX classes like `[[:alpha:]]`.
+pub(crate) fn has_rust_only_syntax(pattern: &str) -> bool {
+    let bytes = pattern.as_bytes();
+    let mut in_class = false;
+    let mut index = 0;
+    while index < bytes.len() {
+        let rest = &bytes[index..];
+        if rest[0] == b'\\' {
+            if !in_class
+                && (rest[1..].starts_with(b"<")
+                    || rest[1..].starts_with(b">")
+                    || rest[1..].starts_with(b"b{")
+                    || rest[1..].starts_with(b"B{"))
+            {
+                return true;
+            }
+            index += 2;
+            continue;
+        }
+        if in_class {
+            if rest[0] == b'['
+                || rest.starts_with(b"&&")
+                || rest.starts_with(b"--")
+                || rest.starts_with(b"~~")
+            {
+                return true;
+            }
+            if rest[0] == b']' {
+                in_class = false;
+            }
+        } else if rest[0] == b'[' {
+            in_class = true;
+            // A `]` right after the opening bracket is a literal
+            index += 1;
+            if bytes.get(index) == Some(&b'^') {
+                index += 1;
+            }
+            if bytes.get(index) == Some(&b']') {
+                index += 1;
+            }
+            continue;
+        } else if rest.starts_with(b"(?<") {
+            // Named groups, which Python only knows as `(?P<name>…)`
+            return true;
+        }
+        index += 1;
+    }
+    false
+}
+
+/// A line matching the pattern, like Python's `grep.linestate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedLine {
+    /// The line number, starting at 1
+    pub number: usize,
+    /// The line, without its end of line
+    pub line: Vec<u8>,
+    /// The start of the first match in the line
+    pub start: usize,
+    /// The end of the first match in the line
+    pub end: usize,
+}
+
+impl MatchedLine {
+    /// Returns the positions of all the matches in the line, the first one
+    /// being the match that selected it.
+    pub fn match_positions(&self, regex: &GrepPattern) -> Vec<(usize, usize)> {
+        let mut positions = vec![(self.start, self.end)];
+        let mut position = self.end;
+        while position < self.line.len() {
+            let Some((start, end)) = regex.find_at(&self.line, position) else {
+                break;
+            };
+            if end == position {
+                position += 1;
+            } else {
+                positions.push((start, end));
+                position = end;
+            }
+        }
+        positions
+    }
+}
+
+/// Returns the lines of `body` matching `regex`, like Python's
+/// `grep.matchlines`.
+///
+/// A match spanning multiple lines selects all of them as a single line.
+pub fn match_lines(body: &[u8], regex: &GrepPattern) -> Vec<MatchedLine> {
+    let mut lines = vec![];
+    let mut begin = 0;
+    let mut number = 0;
+    while begin < body.len() {
+        let Some((match_start, match_end)) = regex.find_at(body, begin) else {
+            break;
+        };
+        let before = &body[begin..match_start];
+        number += before.iter().filter(|&&b| b == b'\n').count() + 1;
+        let line_start = match before.iter().rposition(|&b| b == b'\n') {
+            Some(index) => begin + index + 1,
+            None => begin,
+        };
+        begin = match body[match_end..].iter().position(|&b| b == b'\n') {
+            Some(index) => match_end + index + 1,
+            None => body.len() + 1,
+        };
+        lines.push(MatchedLine {
+            number,
+            line: body[line_start..begin - 1].to_owned(),
+            start: match_start - line_start,
+            end: match_end - line_start,
+        });
+    }
+    lines
+}
+
+/// Whether a line started or stopped matching in a revision
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LineChange {
+    /// A matching line was added, shown as `+`
+    Added,
+    /// A matching line was removed, shown as `-`
+    Removed,
+}
+
+/// The lines of a file matching the pattern
+#[derive(Debug, Clone)]
+pub struct FileMatches {
+    pub path: HgPathBuf,
+    /// Whether the file contains a NUL byte, in which case Python only says
+    /// that it matches
+    pub binary: bool,
+    /// The matching lines in file order, along with how they changed when
+    /// searching differences
+    pub lines: Vec<(Option<LineChange>, MatchedLine)>,
+}
+
+impl FileMatches {
+    fn new(path: HgPathBuf, data: &[u8], regex: &GrepPattern) -> Option<Self> {
+        let lines: Vec<_> = match_lines(data, regex)
+            .into_iter()
+            .map(|line| (None, line))
+            .collect();
+        if lines.is_empty() {
+            return None;
+        }
+        Some(Self { path, binary: data.contains(&0), lines })
+    }
+}
+
+/// Search the tracked files of the working copy matched by `matcher`.
+///
+/// Files that are missing from the working copy are skipped.
+pub fn grep_working_copy(
+    repo: &Repo,
+    matcher: &dyn Matcher,
+    regex: &GrepPattern,
+) -> Result<Vec<FileMatches>, HgError> {
+    let mut files = vec![];
+    for entry in repo.dirstate_map()?.iter() {
+        let (path, entry) = entry.map_err(DirstateError::from)?;
+        if entry.tracked() && matcher.matches(path) {
+            files.push(path.to_owned());
+        }
+    }
+    files.sort_unstable();
+    let vfs = repo.working_directory_vfs();
+    let matches = files
+        .into_par_iter()
+        .map(|path| {
+            Ok(match read_working_file(&vfs, &path)? {
+                Some(data) => FileMatches::new(path, &data, regex),
+                None => None,
+            })
+        })
+        .collect::<Result<Vec<_>, HgError>>()?;
+    Ok(matches.into_iter().flatten().collect())
+}
+
+/// Returns the contents of a working copy file or the target of a symlink,
+/// or `None` if it is missing.
+fn read_working_file(
+    vfs: &VfsImpl,
+    path: &HgPath,
+) -> Result<Option<Vec<u8>>, HgError> {
+    let fs_path = hg_path_to_path_buf(path)?;
+    let Some(metadata) =
+        vfs.symlink_metadata(&fs_path).io_not_found_as_none()?
+    else {
+        return Ok(None);
+    };
+    if metadata.is_symlink() {
+        Ok(Some(get_bytes_from_path(vfs.read_link(&fs_path)?)))
+    } else {
+        Ok(vfs.read(&fs_path).io_not_found_as_none()?)
+    }
+}
+
+/// Search all the files of `rev` matched by `matcher`, in manifest order.
+pub fn grep_revision(
+    repo: &Repo,
+    rev: Revision,
+    matcher: &dyn Matcher,
+    regex: &GrepPattern,
+) -> Result<Vec<FileMatches>, HgError> {
+    let manifest = repo.manifest_for_rev(rev.into())?;
+    let mut files = vec![];
+    for entry in manifest.iter() {
+        let entry = entry?;
+        if matcher.matches(entry.path) {
+            files.push((entry.path.to_owned(), entry.node_id()?));
+        }
+    }
+    let reader = FileReader::new(repo)?;
+    let matches = files
+        .into_par_iter()
+        .map(|(path, node)| {
+            let data = reader.read(&path, node)?;
+            Ok(FileMatches::new(path, data.as_ref(), regex))
+        })
+        .collect::<Result<Vec<_>, HgError>>()?;
+    Ok(matches.into_iter().flatten().collect())
+}
+
+/// Search each of `revs` for matching lines that were added or removed
+/// compared to its first parent, in the files it changed that are matched
+/// by `matcher`. This is `hg grep --diff`.
+///
+/// `emit` is called with the changed files of each revision, in the order
+/// of `revs`. Revisions without changes are skipped, and files removed by a
+/// revision are ignored.
+pub fn grep_changes<E: From<HgError>>(
+    repo: &Repo,
+    revs: &[Revision],
+    matcher: &dyn Matcher,
+    regex: &GrepPattern,
+    mut emit: impl FnMut(Revision, Vec<FileMatches>) -> Result<(), E>,
+) -> Result<(), E> {
+    let reader = FileReader::new(repo)?;
+    for chunk in revs.chunks(CHUNK_SIZE) {
+        let mut searches = vec![];
+        for (index, &rev) in chunk.iter().enumerate() {
+            searches.extend(
+                changed_files(repo, rev, matcher)?
+                    .into_iter()
+                    .map(|search| (index, search)),
+            );
+        }
+        let results = searches
+            .into_par_iter()
+            .map(|(index, (path, node, parent_node))| {
+                let data = reader.read(&path, node)?;
+                let lines = match_lines(data.as_ref(), regex);
+                let parent_lines = match parent_node {
+                    Some(node) => {
+                        match_lines(reader.read(&path, node)?.as_ref(), regex)
+                    }
+                    None => vec![],
+                };
+                let lines = diff_lines(parent_lines, lines);
+                let binary = data.as_ref().contains(&0);
+                Ok((index, FileMatches { path, binary, lines }))
+            })
+            .collect::<Result<Vec<_>, HgError>>()?;
+        let mut by_rev: FastHashMap<usize, Vec<FileMatches>> =
+            FastHashMap::default();
+        for (index, matches) in results {
+            if !matches.lines.is_empty() {
+                by_rev.entry(index).or_default().push(matches);
+            }
+        }
+        for (index, &rev) in chunk.iter().enumerate() {
+            if let Some(matches) = by_rev.remove(&index) {
+                emit(rev, matches)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A file to search for changes: its path, its node in the revision and
+/// its node in the first parent if any
+type ChangedFile = (HgPathBuf, Node, Option<Node>);
+
+/// Returns the files changed by `rev` matched by `matcher` that still exist
+/// in it, in sorted order.
+fn changed_files(
+    repo: &Repo,
+    rev: Revision,
+    matcher: &dyn Matcher,
+) -> Result<Vec<ChangedFile>, HgError> {
+    let changelog = repo.changelog()?;
+    let entry = changelog.entry(rev)?;
+    let data = entry.data()?;
+    let mut files: Vec<&HgPath> =
+        data.files().filter(|&path| matcher.matches(path)).collect();
+    if files.is_empty() {
+        return Ok(vec![]);
+    }
+    files.sort_unstable();
+    files.dedup();
+    let manifest = repo.manifest_for_rev(rev.into())?;
+    let parent_manifest = match entry.as_revlog_entry().p1() {
+        Some(p1) => repo.manifest_for_rev(p1.into())?,
+        None => Manifest::empty(),
+    };
+    let mut changed = vec![];
+    for path in files {
+        let Some(entry) = manifest.find_by_path(path)? else {
+            continue;
+        };
+        let parent_node = match parent_manifest.find_by_path(path)? {
+            Some(parent_entry) => Some(parent_entry.node_id()?),
+            None => None,
+        };
+        changed.push((path.to_owned(), entry.node_id()?, parent_node));
+    }
+    Ok(changed)
+}
+
+/// Reads file revisions from any thread
+struct FileReader {
+    store_vfs: VfsImpl,
+    options: RevlogOpenOptions,
+}
+
+impl FileReader {
+    fn new(repo: &Repo) -> Result<Self, HgError> {
+        let options = default_revlog_options(
+            repo.config(),
+            repo.requirements(),
+            RevlogType::Filelog,
+        )?;
+        Ok(Self { store_vfs: repo.store_vfs(), options })
+    }
+
+    fn read(&self, path: &HgPath, node: Node) -> Result<RawData, HgError> {
+        let filelog = Filelog::open_vfs(&self.store_vfs, path, &self.options)?;
+        Ok(filelog.data_for_node(node)?.into_file_data()?)
+    }
+}
+
+/// Returns the matching lines added or removed between `old` and `new`,
+/// comparing lines by content, like Python's `grep.difflinestates`.
+fn diff_lines(
+    old: Vec<MatchedLine>,
+    new: Vec<MatchedLine>,
+) -> Vec<(Option<LineChange>, MatchedLine)> {
+    let old_lines: Vec<&[u8]> = old.iter().map(|l| &l.line[..]).collect();
+    let new_lines: Vec<&[u8]> = new.iter().map(|l| &l.line[..]).collect();
+    let blocks = matching_blocks(&old_lines, &new_lines);
+    let mut old = old.into_iter();
+    let mut new = new.into_iter();
+    let mut changes = vec![];
+    let (mut i, mut j) = (0, 0);
+    for (old_start, new_start, size) in blocks {
+        let removed = old.by_ref().take(old_start - i);
+        changes.extend(removed.map(|line| (Some(LineChange::Removed), line)));
+        let added = new.by_ref().take(new_start - j);
+        changes.extend(added.map(|line| (Some(LineChange::Added), line)));
+        old.by_ref().take(size).for_each(drop);
+        new.by_ref().take(size).for_each(drop);
+        i = old_start + size;
+        j = new_start + size;
+    }
+    changes
+}
+
+/// Returns the matching blocks of `a` and `b` as `(a_start, b_start, size)`,
+/// ending with an empty block at their ends. This is a port of
+/// `get_matching_blocks` from Python's `difflib.SequenceMatcher`, without
+/// junk but with its "popular elements" heuristic, so that the same
+/// differences are found.
+fn matching_blocks(a: &[&[u8]], b: &[&[u8]]) -> Vec<(usize, usize, usize)> {
+    let mut b2j: FastHashMap<&[u8], Vec<usize>> = FastHashMap::default();
+    for (j, &line) in b.iter().enumerate() {
+        b2j.entry(line).or_default().push(j);
+    }
+    if b.len() >= 200 {
+        let popular = b.len() / 100 + 1;
+        b2j.retain(|_, indices| indices.len() <= popular);
+    }
+
+    let longest_match = |a_lo, a_hi, b_lo, b_hi| {
+        let (mut best_i, mut best_j, mut best_size) = (a_lo, b_lo, 0);
+        let mut j2len: FastHashMap<usize, usize> = FastHashMap::default();
+        for (i, line) in a.iter().enumerate().take(a_hi).skip(a_lo) {
+            let mut new_j2len = FastHashMap::default();
+            for &j in b2j.get(line).map_or(&[][..], |v| v) {
+                if j < b_lo {
+                    continue;
+                }
+                if j >= b_hi {
+                    break;
+                }
+                let k = j
+                    .checked_sub(1)
+                    .and_then(|prev| j2len.get(&prev))
+                    .unwrap_or(&0)
+                    + 1;
+                new_j2len.insert(j, k);
+                if k > best_size {
+                    (best_i, best_j, best_size) = (i + 1 - k, j + 1 - k, k);
+                }
+            }
+            j2len = new_j2len;
+        }
+        // Extend the match with equal popular elements on both sides
+        while best_i > a_lo && best_j > b_lo && a[best_i - 1] == b[best_j - 1] {
+            (best_i, best_j, best_size) =
+                (best_i - 1, best_j - 1, best_size + 1);
+        }
+        while best_i + best_size < a_hi
+            && best_j + best_size < b_hi
+            && a[best_i + best_size] == b[best_j + best_size]
+        {
+            best_size += 1;
+        }
+        (best_i, best_j, best_size)
+    };
+
+    let mut blocks = vec![];
+    let mut queue = vec![(0, a.len(), 0, b.len())];
+    while let Some((a_lo, a_hi, b_lo, b_hi)) = queue.pop() {
+        let (i, j, k) = longest_match(a_lo, a_hi, b_lo, b_hi);
+        if k > 0 {
+            blocks.push((i, j, k));
+            if a_lo < i && b_lo < j {
+                queue.push((a_lo, i, b_lo, j));
+            }
+            if i + k < a_hi && j + k < b_hi {
+                queue.push((i + k, a_hi, j + k, b_hi));
+            }
+        }
+    }
+    blocks.sort_unstable();
+
+    // Collapse adjacent blocks
+    let mut collapsed = vec![];
+    let (mut i1, mut j1, mut k1) = (0, 0, 0);
+    for (i2, j2, k2) in blocks {
+        if i1 + k1 == i2 && j1 + k1 == j2 {
+            k1 += k2;
+        } else {
+            if k1 > 0 {
+                collapsed.push((i1, j1, k1));
+            }
+            (i1, j1, k1) = (i2, j2, k2);
+        }
+    }
+    if k1 > 0 {
+        collapsed.push((i1, j1, k1));
+    }
+    collapsed.push((a.len(), b.len(), 0));
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(regex: &str, body: &[u8]) -> Vec<(usize, String, usize, usize)> {
+        let regex = compile_pattern(regex.as_bytes(), false, false).unwrap();
+        match_lines(body, &regex)
+            .into_iter()
+            .map(|l| {
+                let line = String::from_utf8(l.line).unwrap();
+                (l.number, line, l.start, l.end)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_match_lines() {
+        assert_eq!(
+            lines("b+", b"abc\nxyz\nbb\n"),
+            vec![(1, "abc".into(), 1, 2), (3, "bb".into(), 0, 2)]
+        );
+        // Only the first match of a line selects it
+        assert_eq!(lines("a", b"aaa"), vec![(1, "aaa".into(), 0, 1)]);
+        // Multi-line mode
+        assert_eq!(lines("^x", b"ax\nxa"), vec![(2, "xa".into(), 0, 1)]);
+        // A match spanning lines
+        assert_eq!(
+            lines("b\nc", b"a\nb\nc\nd\n"),
+            vec![(2, "b\nc".into(), 0, 3)]
+        );
+    }
+
+    #[test]
+    fn test_match_positions() {
+        let regex = compile_pattern(b"a*", false, false).unwrap();
+        let matched = &match_lines(b"xaxaa", &regex)[0];
+        assert_eq!(
+            matched.match_positions(&regex),
+            vec![(0, 0), (1, 2), (3, 5)]
+        );
+    }
+
+    #[test]
+    fn test_compile_pattern() {
+        let regex = compile_pattern(b"foo", true, true).unwrap();
+        assert!(regex.is_match(b"a FOO b"));
+        assert!(!regex.is_match(b"afoo"));
+        // ASCII classes, like Python's `re` with bytes
+        let regex = compile_pattern(br"^\w+$", false, false).unwrap();
+        assert!(!regex.is_match("é".as_bytes()));
+        assert!(compile_pattern(br"(a)\1", false, false).is_err());
+        // Valid for both, but with different meanings
+        assert!(compile_pattern(br"\<foo", false, false).is_err());
+        assert!(compile_pattern(b"[[:alpha:]]", false, false).is_err());
+        assert!(compile_pattern(b"[a-z&&[^x]]", false, false).is_err());
+        assert!(compile_pattern(b"[]a[]", false, false).is_err());
+        assert!(compile_pattern(br"[\[a]\<", false, false).is_err());
+        assert!(compile_pattern(br"[]a]\[x", false, false).is_ok());
+        // Files are not necessarily UTF-8
+        let regex = compile_pattern(b"a.b", false, false).unwrap();
+        assert_eq!(regex.find_at(b"xa\xffb", 0), Some((1, 4)));
+        assert_eq!(regex.find_at(b"a\xffb", 1), None);
+    }
+
+    #[test]
+    fn test_pattern_from_threads() {
+        let regex = compile_pattern(b"o+", false, false).unwrap();
+        let found: Vec<_> = (0..64)
+            .into_par_iter()
+            .map(|i| regex.find_at(format!("{i}foo").as_bytes(), 0))
+            .collect();
+        assert!(found.iter().enumerate().all(|(i, found)| {
+            let start = i.to_string().len() + 1;
+            *found == Some((start, start + 2))
+        }));
+    }
+
+    #[test]
+    fn test_matching_blocks() {
+        let a: Vec<&[u8]> = vec![b"a", b"b", b"c", b"d"];
+        let b: Vec<&[u8]> = vec![b"a", b"x", b"c", b"d", b"e"];
+        assert_eq!(
+            matching_blocks(&a, &b),
+            vec![(0, 0, 1), (2, 2, 2), (4, 5, 0)]
+        );
+        assert_eq!(matching_blocks(&[], &b), vec![(0, 5, 0)]);
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let line = |number, line: &str| MatchedLine {
+            number,
+            line: line.into(),
+            start: 0,
+            end: 1,
+        };
+        let changes = diff_lines(
+            vec![line(1, "a"), line(2, "b")],
+            vec![line(1, "a"), line(3, "c")],
+        );
+        assert_eq!(
+            changes,
+            vec![
+                (Some(LineChange::Removed), line(2, "b")),
+                (Some(LineChange::Added), line(3, "c")),
+            ]
+        );
+    }
+}
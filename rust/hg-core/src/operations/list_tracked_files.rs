@@ -13,10 +13,12 @@ use crate::matchers::Matcher;
 use crate::matchers::VisitChildrenSet;
 use crate::repo::Repo;
 use crate::revlog::RevlogError;
+use crate::revlog::RevlogType;
 use crate::revlog::manifest::DecodedManifestEntry;
 use crate::revlog::manifest::Manifest;
 use crate::utils::filter_map_results;
 use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
 use crate::utils::par_filter_map_results;
 
 /// List files under Mercurial control at a given revset.
@@ -62,6 +64,22 @@ pub fn list_rev_tracked_files<M: Matcher>(
     Ok(FilesForRev { manifest, narrow_matcher })
 }
 
+/// List every file that has been tracked in any revision, in sorted order.
+///
+/// This reads the list of filelogs in the store (from the file index or the
+/// fncache) instead of going through the whole changelog.
+pub fn list_all_tracked_files(repo: &Repo) -> Result<Vec<HgPathBuf>, HgError> {
+    let mut files: Vec<_> = repo
+        .store()
+        .walk()?
+        .into_iter()
+        .filter(|revlog| revlog.revlog_type == RevlogType::Filelog)
+        .map(|revlog| revlog.target)
+        .collect();
+    files.sort_unstable();
+    Ok(files)
+}
+
 pub struct FilesForRev<M> {
     manifest: Manifest,
     narrow_matcher: M,
@@ -3,25 +3,84 @@
 //! the cli. A single command can use several operations to achieve its goal.
 
 mod annotate;
+mod archive;
 mod cat;
+mod copy;
 mod debugdata;
+mod grep;
 mod list_tracked_files;
+mod revert;
 mod status_rev_rev;
+mod tracking;
+mod verify;
 pub use annotate::AnnotateOptions;
 pub use annotate::AnnotateOutput;
 pub use annotate::ChangesetAnnotatedFile;
 pub use annotate::ChangesetAnnotation;
 pub use annotate::annotate;
+pub use archive::ArchiveKind;
+pub use archive::ArchiveOptions;
+pub use archive::archive;
+pub use archive::archive_metadata;
+pub use archive::archive_prefix;
 pub use cat::CatOutput;
 pub use cat::cat;
+pub use copy::Copier;
+pub use copy::CopyEvent;
+pub use copy::CopyOptions;
+pub use copy::CopySource;
+pub use copy::CopySources;
+pub use copy::CopyWarning;
 pub use debugdata::debug_data;
+pub use grep::FileMatches;
+pub use grep::GrepPattern;
+pub use grep::LineChange;
+pub use grep::MatchedLine;
+pub use grep::compile_pattern;
+pub use grep::grep_changes;
+pub use grep::grep_revision;
+pub use grep::grep_working_copy;
+pub(crate) use grep::has_rust_only_syntax;
+pub use grep::match_lines;
 pub use list_tracked_files::FilesForDirstateBorrowed;
 pub use list_tracked_files::FilesForRev;
 pub use list_tracked_files::FilesForRevBorrowed;
+pub use list_tracked_files::list_all_tracked_files;
 pub use list_tracked_files::list_rev_tracked_files;
 pub use list_tracked_files::list_revset_tracked_files;
+pub use revert::RevertAction;
+pub use revert::RevertEntry;
+pub use revert::RevertPlan;
+pub use revert::revert_apply;
+pub use revert::revert_plan;
 pub use status_rev_rev::DiffStatus;
 pub use status_rev_rev::ListCopies;
 pub use status_rev_rev::StatusRevRev;
 pub use status_rev_rev::status_change;
 pub use status_rev_rev::status_rev_rev_no_copies;
+pub use tracking::AddEntry;
+pub use tracking::AddPlan;
+pub use tracking::AddRemoveAction;
+pub use tracking::AddRemoveEntry;
+pub use tracking::AddRemovePlan;
+pub use tracking::ForgetPlan;
+pub use tracking::NotRemovingReason;
+pub use tracking::PortabilityCheck;
+pub use tracking::RemovePlan;
+pub use tracking::TrackingEntry;
+pub use tracking::TrackingWarning;
+pub use tracking::add_apply;
+pub use tracking::add_plan;
+pub use tracking::addremove_apply;
+pub use tracking::addremove_plan;
+pub use tracking::forget_apply;
+pub use tracking::forget_plan;
+pub use tracking::remove_apply;
+pub use tracking::remove_plan;
+pub(crate) use tracking::settle_unsure_files;
+pub use verify::VerifyError;
+pub use verify::VerifyMessage;
+pub use verify::VerifyOptions;
+pub use verify::VerifyStage;
+pub use verify::VerifySummary;
+pub use verify::verify;
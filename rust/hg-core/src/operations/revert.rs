@@ -0,0 +1,789 @@
+//! Restore files to their state in a given revision, without moving the
+//! working copy parent. This is `hg revert`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::FastHashMap;
+use crate::FastHashSet;
+use crate::Graph;
+use crate::NULL_NODE;
+use crate::Revision;
+use crate::checkexec::check_exec;
+use crate::dirstate::on_disk::write_tracked_key;
+use crate::dirstate::owning::OwningDirstateMap;
+use crate::dirstate::status::StatusOptions;
+use crate::errors::HgError;
+use crate::matchers::Matcher;
+use crate::matchers::get_ignore_files;
+use crate::operations::tracking::settle_unsure_files;
+use crate::progress::Progress;
+use crate::repo::Repo;
+use crate::revlog::RevlogType;
+use crate::revlog::filelog::FileCompOutcome;
+use crate::revlog::filelog::is_file_modified;
+use crate::revlog::manifest::Manifest;
+use crate::revlog::manifest::ManifestEntry;
+use crate::revlog::options::RevlogOpenOptions;
+use crate::revlog::options::default_revlog_options;
+use crate::update::UpdateConfig;
+use crate::update::backup_path;
+use crate::update::record_restored_files;
+use crate::update::restore_working_copy_files;
+use crate::update::working_copy_remove;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::utils::hg_path::hg_path_to_path_buf;
+use crate::utils::path_auditor::PathAuditor;
+use crate::vfs::Vfs;
+use crate::vfs::VfsImpl;
+
+/// What `revert` does to a file. Variants are declared in the order in which
+/// the actions are applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RevertAction {
+    /// Stop tracking a file added in the working copy, keeping it on disk
+    Forget,
+    /// Delete and stop tracking a file added since the target revision
+    Remove,
+    /// Stop tracking a file added since the target revision whose working
+    /// copy file is already gone (missing or backed up)
+    Drop,
+    /// Restore the contents of a file from the target revision
+    Revert,
+    /// Restore and track a file removed since the target revision
+    Add,
+    /// Restore a file marked as removed in the working copy
+    Undelete,
+    /// The file already matches the target revision
+    Noop,
+    /// The file is tracked neither in the working copy nor in the target
+    Unknown,
+}
+
+impl RevertAction {
+    /// Whether this action changes the working copy or the dirstate
+    pub fn is_change(self) -> bool {
+        !matches!(self, Self::Noop | Self::Unknown)
+    }
+
+    /// Whether this action writes the file from the target revision
+    fn restores_file(self) -> bool {
+        matches!(self, Self::Revert | Self::Add | Self::Undelete)
+    }
+}
+
+/// A file concerned by a `revert` and what will happen to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevertEntry {
+    pub path: HgPathBuf,
+    pub action: RevertAction,
+    /// Whether the path was given explicitly, as opposed to matched through
+    /// a directory or a pattern
+    pub exact: bool,
+    /// Where the current file will be saved before being acted upon,
+    /// relative to the working directory
+    pub backup: Option<PathBuf>,
+}
+
+/// The outcome of [`revert_plan`], to be given to [`revert_apply`]
+pub struct RevertPlan {
+    /// Every file concerned by the revert, sorted by path
+    pub entries: Vec<RevertEntry>,
+    /// Explicitly given paths found neither in the working copy nor in the
+    /// target revision
+    pub bad: Vec<HgPathBuf>,
+    /// Copy information to record in the dirstate, as `(dest, source)`
+    copies: Vec<(HgPathBuf, HgPathBuf)>,
+    target_manifest: Manifest,
+    /// Whether the target is the parent of the working copy
+    at_parent: bool,
+}
+
+/// State of a file in the working copy, relative to its parent or to the
+/// target revision depending on context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileState {
+    Clean,
+    Modified,
+    Added,
+    Removed,
+    Deleted,
+    Unknown,
+}
+
+/// Whether to save the current file before acting on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backup {
+    Discard,
+    /// Only if the file differs from the target revision
+    Check,
+    Always,
+}
+
+/// Compute what needs to be done to bring the files matched by `matcher`
+/// back to their state in `target`.
+///
+/// This has no side-effect besides the creation of the directories for the
+/// backup files if `ui.origbackuppath` is set, so it can be used for dry
+/// runs. The caller is responsible for taking the working copy lock.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn revert_plan(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    target: Revision,
+    matcher: &impl Matcher,
+    no_backup: bool,
+    orig_backup_path: Option<&[u8]>,
+) -> Result<RevertPlan, HgError> {
+    let parents = repo.dirstate_parents()?;
+    if parents.is_merge() {
+        return Err(HgError::unsupported("revert during a merge"));
+    }
+    let target_node =
+        repo.node(target.into()).expect("target revision should exist");
+    let at_parent = target_node == parents.p1;
+    let all = matcher.matches_everything();
+
+    let working_directory_vfs = repo.working_directory_vfs();
+    let store_vfs = repo.store_vfs();
+    let filelog_options = default_revlog_options(
+        repo.config(),
+        repo.requirements(),
+        RevlogType::Filelog,
+    )?;
+    let check_exec = check_exec(repo.working_directory_path());
+    let p1_manifest = repo.manifest_for_node(parents.p1)?;
+    let target_manifest = repo.manifest_for_rev(target.into())?;
+
+    let options = StatusOptions {
+        check_exec,
+        // Unchanged files in the parent may differ from the target
+        list_clean: !all || !at_parent,
+        list_unknown: !all,
+        list_ignored: false,
+        list_copies: false,
+        collect_traversed_dirs: false,
+        empty_dirs_keep_files: false,
+    };
+    let (mut wc_status, unsure) = dirstate.with_status(
+        matcher,
+        repo.working_directory_path().to_owned(),
+        get_ignore_files(repo),
+        options,
+        |res, _warnings| -> Result<_, HgError> {
+            let status = res?;
+            let mut states = FastHashMap::default();
+            for (paths, state) in [
+                (status.clean, FileState::Clean),
+                (status.modified, FileState::Modified),
+                (status.added, FileState::Added),
+                (status.removed, FileState::Removed),
+                (status.deleted, FileState::Deleted),
+                (status.unknown, FileState::Unknown),
+            ] {
+                for path in paths {
+                    states.insert(path.path.into_owned(), state);
+                }
+            }
+            let unsure: Vec<HgPathBuf> = status
+                .unsure
+                .into_iter()
+                .map(|p| p.path.into_owned())
+                .collect();
+            Ok((states, unsure))
+        },
+    )?;
+
+    // Settle the files whose metadata was not enough to tell
+    for (path, outcome) in
+        settle_unsure_files(repo, check_exec, &p1_manifest, unsure)?
+    {
+        let state = match outcome {
+            FileCompOutcome::Clean => FileState::Clean,
+            FileCompOutcome::Modified => FileState::Modified,
+            FileCompOutcome::Deleted => FileState::Deleted,
+        };
+        if state != FileState::Clean || options.list_clean {
+            wc_status.insert(path, state);
+        }
+    }
+
+    let on_disk = |path: &HgPath| -> Result<bool, HgError> {
+        let fs_path = hg_path_to_path_buf(path)?;
+        Ok(working_directory_vfs.symlink_metadata(&fs_path).is_ok())
+    };
+
+    // Status of the working copy compared to the target revision
+    let mut target_status: FastHashMap<HgPathBuf, FileState> = if at_parent {
+        wc_status.clone()
+    } else {
+        let mut target_status = FastHashMap::default();
+        for (path, &state) in wc_status.iter() {
+            let target_entry = target_manifest.find_by_path(path)?;
+            let target_state = match (state, target_entry) {
+                (FileState::Deleted, _) => Some(FileState::Deleted),
+                (FileState::Unknown | FileState::Removed, Some(_)) => {
+                    Some(FileState::Removed)
+                }
+                (FileState::Unknown, None) => Some(FileState::Unknown),
+                (FileState::Removed, None) => None,
+                (_, None) => Some(FileState::Added),
+                (FileState::Clean, Some(target_entry)) => {
+                    let p1_entry = p1_manifest
+                        .find_by_path(path)?
+                        .expect("clean file should be in p1");
+                    if same_revision(&p1_entry, &target_entry) {
+                        Some(FileState::Clean)
+                    } else {
+                        Some(FileState::Modified)
+                    }
+                }
+                (_, Some(_)) => match is_file_modified(
+                    &working_directory_vfs,
+                    &store_vfs,
+                    check_exec,
+                    &target_manifest,
+                    path,
+                    &filelog_options,
+                )? {
+                    FileCompOutcome::Clean => Some(FileState::Clean),
+                    FileCompOutcome::Modified => Some(FileState::Modified),
+                    FileCompOutcome::Deleted => Some(FileState::Deleted),
+                },
+            };
+            if let Some(target_state) = target_state {
+                target_status.insert(path.to_owned(), target_state);
+            }
+        }
+        // Files of the target that the working copy knows nothing about.
+        // Clean files are listed, so these are not in the parent either.
+        for entry in target_manifest.iter() {
+            let entry = entry?;
+            if !wc_status.contains_key(entry.path)
+                && matcher.matches(entry.path)
+            {
+                target_status.insert(entry.path.to_owned(), FileState::Removed);
+            }
+        }
+        target_status
+    };
+
+    // Gather every file concerned, and whether it was given explicitly
+    let mut names: BTreeMap<HgPathBuf, bool> = BTreeMap::new();
+    let mut bad = vec![];
+    if all {
+        for (path, state) in target_status.iter() {
+            if !matches!(state, FileState::Clean | FileState::Unknown) {
+                names.insert(path.to_owned(), false);
+            }
+        }
+    } else {
+        for path in target_status.keys().chain(wc_status.keys()) {
+            names.insert(path.to_owned(), matcher.exact_match(path));
+        }
+        if at_parent {
+            for entry in target_manifest.iter() {
+                let entry = entry?;
+                if matcher.matches(entry.path) {
+                    names
+                        .entry(entry.path.to_owned())
+                        .or_insert_with(|| matcher.exact_match(entry.path));
+                }
+            }
+        }
+        let mut exact_files: Vec<_> = matcher
+            .file_set()
+            .map(|files| files.iter().collect())
+            .unwrap_or_default();
+        exact_files.sort_unstable();
+        for path in exact_files {
+            if path.is_empty() || names.contains_key(path) {
+                continue;
+            }
+            let dir_prefix = path.join(HgPath::new(b""));
+            if names
+                .range(dir_prefix.clone()..)
+                .next()
+                .is_some_and(|(name, _)| name.starts_with(&dir_prefix))
+            {
+                continue;
+            }
+            if on_disk(path)? {
+                // Ignored files are not part of the status, but should still
+                // be reported as not managed
+                names.insert(path.to_owned(), true);
+                target_status.insert(path.to_owned(), FileState::Unknown);
+            } else {
+                bad.push(path.to_owned());
+            }
+        }
+    }
+
+    // Also restore the source of locally renamed files
+    let local_changes: Vec<_> = names
+        .keys()
+        .filter(|path| {
+            matches!(
+                wc_status.get(*path),
+                Some(FileState::Modified | FileState::Added)
+            )
+        })
+        .cloned()
+        .collect();
+    for path in local_changes {
+        if let Some(source) = dirstate.copy_map_get(&path)?
+            && !names.contains_key(source)
+            && dirstate.get(source)?.is_some_and(|e| e.removed())
+        {
+            let source = source.to_owned();
+            if target_manifest.find_by_path(&source)?.is_some() {
+                target_status.insert(source.to_owned(), FileState::Removed);
+            }
+            wc_status.insert(source.to_owned(), FileState::Removed);
+            names.insert(source, true);
+        }
+    }
+
+    let mut entries = Vec::with_capacity(names.len());
+    for (path, exact) in names {
+        let wc_state = wc_status.get(&path).copied();
+        let target_state = target_status.get(&path).copied();
+        let in_target = target_manifest.find_by_path(&path)?.is_some();
+        let ds_added = dirstate.get(&path)?.is_some_and(|e| e.added());
+        let Some((action, mut backup_strategy)) = classify(
+            wc_state,
+            target_state,
+            at_parent,
+            in_target,
+            ds_added,
+            no_backup,
+            on_disk(&path)?,
+        ) else {
+            continue;
+        };
+        if no_backup {
+            backup_strategy = Backup::Discard;
+        }
+        let needs_backup = match backup_strategy {
+            Backup::Discard => false,
+            Backup::Always => true,
+            Backup::Check => {
+                is_file_modified(
+                    &working_directory_vfs,
+                    &store_vfs,
+                    check_exec,
+                    &target_manifest,
+                    &path,
+                    &filelog_options,
+                )? != FileCompOutcome::Clean
+            }
+        };
+        let backup = if needs_backup {
+            Some(backup_path(
+                orig_backup_path,
+                &working_directory_vfs,
+                &hg_path_to_path_buf(&path)?,
+            )?)
+        } else {
+            None
+        };
+        entries.push(RevertEntry { path, action, exact, backup });
+    }
+
+    let copies = if at_parent {
+        vec![]
+    } else {
+        restored_copies(
+            repo,
+            target,
+            &p1_manifest,
+            &target_manifest,
+            &entries,
+            &store_vfs,
+            &filelog_options,
+        )?
+    };
+
+    Ok(RevertPlan { entries, bad, copies, target_manifest, at_parent })
+}
+
+/// Whether two manifest entries point to the same file revision
+fn same_revision(a: &ManifestEntry, b: &ManifestEntry) -> bool {
+    a.hex_node_id == b.hex_node_id && a.flags == b.flags
+}
+
+/// Decide what to do with a file given its state compared to the working
+/// copy parent (`wc_state`) and to the target revision (`target_state`).
+/// This is the dispatch table from `cmdutil.revert` in Python.
+fn classify(
+    wc_state: Option<FileState>,
+    target_state: Option<FileState>,
+    at_parent: bool,
+    in_target: bool,
+    ds_added: bool,
+    no_backup: bool,
+    on_disk: bool,
+) -> Option<(RevertAction, Backup)> {
+    use FileState::*;
+    use RevertAction as A;
+
+    let check_if_on_disk = if on_disk {
+        Backup::Check
+    } else {
+        Backup::Discard
+    };
+    let forget_or_remove = if ds_added { A::Forget } else { A::Remove };
+    let locally_changed = matches!(wc_state, Some(Modified | Added));
+
+    let result = match target_state {
+        Some(Deleted) if in_target => (A::Revert, Backup::Discard),
+        Some(Deleted) if ds_added => (A::Forget, Backup::Discard),
+        Some(Deleted) => (A::Drop, Backup::Discard),
+        Some(Modified) if at_parent || locally_changed => {
+            (A::Revert, Backup::Always)
+        }
+        Some(Modified) => (A::Revert, Backup::Discard),
+        Some(Added) if !at_parent && wc_state == Some(Modified) => {
+            // Added since the target with local modifications: save them
+            let action = if no_backup { A::Remove } else { A::Drop };
+            (action, Backup::Always)
+        }
+        Some(Added) => (forget_or_remove, Backup::Discard),
+        Some(Removed) if wc_state == Some(Removed) => {
+            (A::Undelete, check_if_on_disk)
+        }
+        Some(Removed) => (A::Add, check_if_on_disk),
+        Some(Clean) => (A::Noop, Backup::Discard),
+        Some(Unknown) => (A::Unknown, Backup::Discard),
+        // Removed in the working copy and absent from the target
+        None if wc_state == Some(Removed) => (A::Noop, Backup::Discard),
+        None => return None,
+    };
+    Some(result)
+}
+
+/// Returns the copy information that should be recorded for the restored
+/// files, as `(dest, source)`.
+///
+/// Only copies across a single changeset between the parent and the target
+/// are handled, anything further needs full copy tracing.
+fn restored_copies(
+    repo: &Repo,
+    target: Revision,
+    p1_manifest: &Manifest,
+    target_manifest: &Manifest,
+    entries: &[RevertEntry],
+    store_vfs: &VfsImpl,
+    filelog_options: &RevlogOpenOptions,
+) -> Result<Vec<(HgPathBuf, HgPathBuf)>, HgError> {
+    let mut candidates = vec![];
+    for entry in entries {
+        if entry.action.restores_file()
+            && p1_manifest.find_by_path(&entry.path)?.is_none()
+        {
+            candidates.push(entry.path.as_ref());
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(vec![]);
+    }
+    let copy_source = |path: &HgPath, entry: &ManifestEntry| {
+        let filelog = crate::revlog::filelog::Filelog::open_vfs(
+            store_vfs,
+            path,
+            filelog_options,
+        )?;
+        let data = filelog.data_for_node(entry.node_id()?)?;
+        Ok::<_, HgError>(data.metadata()?.parse()?.copy.map(HgPath::to_owned))
+    };
+
+    let changelog = repo.changelog()?;
+    let p1 = repo.dirstate_parents()?.p1;
+    let p1_rev = changelog.rev_from_node(p1.into())?;
+    let parents = |rev| {
+        changelog.parents(rev).map_err(|e| HgError::corrupted(e.to_string()))
+    };
+    let mut copies = vec![];
+    if parents(target)?.contains(&p1_rev) {
+        // The target is a child of the parent: follow copies forward
+        for path in candidates {
+            let entry = target_manifest
+                .find_by_path(path)?
+                .expect("restored file should be in the target");
+            if let Some(source) = copy_source(path, &entry)?
+                && p1_manifest.find_by_path(&source)?.is_some()
+            {
+                copies.push((path.to_owned(), source));
+            }
+        }
+    } else if p1 != NULL_NODE && parents(p1_rev)?.contains(&target) {
+        // The target is the parent's parent: reverse the renames done in
+        // the parent, the source of which gets restored.
+        let mut renames = FastHashMap::default();
+        for (target_entry, p1_entry) in target_manifest.diff(p1_manifest)? {
+            let (None, Some(p1_entry)) = (target_entry, p1_entry) else {
+                continue;
+            };
+            if let Some(source) = copy_source(p1_entry.path, &p1_entry)?
+                && target_manifest.find_by_path(&source)?.is_some()
+                && p1_manifest.find_by_path(&source)?.is_none()
+            {
+                renames.insert(source, p1_entry.path.to_owned());
+            }
+        }
+        for path in candidates {
+            if let Some(dest) = renames.get(path) {
+                copies.push((path.to_owned(), dest.to_owned()));
+            }
+        }
+    } else {
+        return Err(HgError::unsupported(
+            "revert of files needing copy tracing across several changesets",
+        ));
+    }
+    Ok(copies)
+}
+
+/// Apply the changes computed by [`revert_plan`] to the working copy and to
+/// `dirstate`, without writing the latter to disk.
+///
+/// The caller is responsible for holding the working copy lock.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn revert_apply(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    plan: &RevertPlan,
+    progress: &dyn Progress,
+    update_config: &UpdateConfig,
+) -> Result<(), HgError> {
+    let working_directory_vfs = repo.working_directory_vfs();
+    let auditor = PathAuditor::new(working_directory_vfs.base());
+
+    for entry in &plan.entries {
+        if let Some(backup) = &entry.backup {
+            let path = hg_path_to_path_buf(&entry.path)?;
+            working_directory_vfs.rename(&path, backup, false)?;
+        }
+    }
+
+    let mut entries: Vec<&RevertEntry> =
+        plan.entries.iter().filter(|e| e.action.is_change()).collect();
+    entries.sort_by_key(|e| e.action);
+    let tracked_set_changed =
+        entries.iter().any(|e| !matches!(e.action, RevertAction::Revert));
+
+    let mut to_restore = vec![];
+    for entry in &entries {
+        match entry.action {
+            RevertAction::Forget => {
+                dirstate.set_untracked(&entry.path)?;
+            }
+            RevertAction::Remove => {
+                auditor.audit_path(&entry.path)?;
+                // Like Python, ignore errors: the file may already be gone
+                let _ = working_copy_remove(
+                    hg_path_to_path_buf(&entry.path)?,
+                    &working_directory_vfs,
+                    update_config.remove_empty_dirs,
+                );
+                dirstate.set_untracked(&entry.path)?;
+            }
+            RevertAction::Drop => {
+                auditor.audit_path(&entry.path)?;
+                dirstate.set_untracked(&entry.path)?;
+            }
+            RevertAction::Revert
+            | RevertAction::Add
+            | RevertAction::Undelete => {
+                let manifest_entry = plan
+                    .target_manifest
+                    .find_by_path(&entry.path)?
+                    .expect("restored file should be in the target");
+                to_restore.push(manifest_entry.decode()?);
+            }
+            RevertAction::Noop | RevertAction::Unknown => unreachable!(),
+        }
+    }
+
+    progress.update(0, Some(to_restore.len() as u64));
+    to_restore.sort_unstable_by_key(|e| e.path);
+    let restored =
+        restore_working_copy_files(repo, to_restore, progress, update_config)?;
+
+    for entry in &entries {
+        match entry.action {
+            RevertAction::Revert | RevertAction::Undelete => {
+                dirstate.copy_map_remove(&entry.path)?;
+                if !plan.at_parent {
+                    // The file now differs from the parent, which `status`
+                    // will find out from its contents
+                    dirstate.set_tracked(&entry.path)?;
+                }
+            }
+            RevertAction::Add => {
+                dirstate.set_tracked(&entry.path)?;
+            }
+            _ => {}
+        }
+    }
+    if plan.at_parent {
+        // There is no merge, so the restored files are clean and `status`
+        // does not need to read them again
+        // Added files are not in the parent, and stay added
+        let added: FastHashSet<&HgPath> = entries
+            .iter()
+            .filter(|e| e.action == RevertAction::Add)
+            .map(|e| e.path.as_ref())
+            .collect();
+        let restored = restored
+            .into_iter()
+            .filter(|update| !added.contains(update.path))
+            .collect();
+        record_restored_files(repo, dirstate, restored)?;
+    }
+    for (dest, source) in &plan.copies {
+        dirstate.copy_map_insert(dest, source)?;
+    }
+
+    if tracked_set_changed {
+        write_tracked_key(repo)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        use FileState::*;
+        use RevertAction as A;
+
+        let at_parent = |state| {
+            classify(
+                Some(state),
+                Some(state),
+                true,
+                true,
+                state == Added,
+                false,
+                true,
+            )
+            .map(|(action, _)| action)
+        };
+        assert_eq!(at_parent(Clean), Some(A::Noop));
+        assert_eq!(at_parent(Modified), Some(A::Revert));
+        assert_eq!(at_parent(Added), Some(A::Forget));
+        assert_eq!(at_parent(Removed), Some(A::Undelete));
+        assert_eq!(at_parent(Unknown), Some(A::Unknown));
+
+        // Deleted files are restored if they exist in the target
+        let deleted = |in_target, ds_added| {
+            classify(
+                Some(Deleted),
+                Some(Deleted),
+                false,
+                in_target,
+                ds_added,
+                false,
+                false,
+            )
+        };
+        assert_eq!(deleted(true, false), Some((A::Revert, Backup::Discard)));
+        assert_eq!(deleted(false, true), Some((A::Forget, Backup::Discard)));
+        assert_eq!(deleted(false, false), Some((A::Drop, Backup::Discard)));
+
+        // Locally modified files are backed up, unless modified only
+        // compared to the target
+        assert_eq!(
+            classify(
+                Some(Clean),
+                Some(Modified),
+                false,
+                true,
+                false,
+                false,
+                true
+            ),
+            Some((A::Revert, Backup::Discard))
+        );
+        assert_eq!(
+            classify(
+                Some(Modified),
+                Some(Modified),
+                false,
+                true,
+                false,
+                false,
+                true
+            ),
+            Some((A::Revert, Backup::Always))
+        );
+
+        // Added since the target, with and without local changes
+        assert_eq!(
+            classify(
+                Some(Clean),
+                Some(Added),
+                false,
+                false,
+                false,
+                false,
+                true
+            ),
+            Some((A::Remove, Backup::Discard))
+        );
+        assert_eq!(
+            classify(
+                Some(Modified),
+                Some(Added),
+                false,
+                false,
+                false,
+                false,
+                true
+            ),
+            Some((A::Drop, Backup::Always))
+        );
+        assert_eq!(
+            classify(
+                Some(Modified),
+                Some(Added),
+                false,
+                false,
+                false,
+                true,
+                true
+            ),
+            Some((A::Remove, Backup::Always))
+        );
+
+        // Removed since the target, possibly with an unknown file in the way
+        assert_eq!(
+            classify(None, Some(Removed), false, true, false, false, false),
+            Some((A::Add, Backup::Discard))
+        );
+        assert_eq!(
+            classify(
+                Some(Unknown),
+                Some(Removed),
+                false,
+                true,
+                false,
+                false,
+                true
+            ),
+            Some((A::Add, Backup::Check))
+        );
+        assert_eq!(
+            classify(Some(Removed), None, false, false, false, false, false),
+            Some((A::Noop, Backup::Discard))
+        );
+        assert_eq!(
+            classify(None, None, false, false, false, false, false),
+            None
+        );
+    }
+}
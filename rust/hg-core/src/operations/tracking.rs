@@ -0,0 +1,1130 @@
+//! Change which files of the working copy are tracked. This is `hg add`,
+//! `hg forget`, `hg remove` and `hg addremove`.
+//!
+//! Each operation is split into a plan, which has no side-effect and can be
+//! used for dry runs, and its application to the dirstate. The caller is
+//! responsible for taking the working copy lock and writing the dirstate.
+
+use rayon::prelude::*;
+
+use crate::FastHashMap;
+use crate::FastHashSet;
+use crate::checkexec::check_exec;
+use crate::config::Config;
+use crate::dirstate::DirstateError;
+use crate::dirstate::on_disk::write_tracked_key;
+use crate::dirstate::owning::OwningDirstateMap;
+use crate::dirstate::status::BadMatch;
+use crate::dirstate::status::StatusOptions;
+use crate::errors::HgError;
+use crate::exit_codes;
+use crate::matchers::Matcher;
+use crate::matchers::get_ignore_files;
+use crate::repo::Repo;
+use crate::revlog::RevlogError;
+use crate::revlog::RevlogType;
+use crate::revlog::filelog::FileCompOutcome;
+use crate::revlog::filelog::is_file_modified;
+use crate::revlog::manifest::Manifest;
+use crate::revlog::options::default_revlog_options;
+use crate::similar::Rename;
+use crate::similar::find_renames;
+use crate::update::working_copy_remove;
+use crate::utils::files::check_windows_filename;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::utils::hg_path::hg_path_to_path_buf;
+use crate::utils::strings::shell_quote;
+use crate::warnings::HgWarningSender;
+
+/// Warnings emitted while changing which files are tracked
+#[derive(Debug)]
+pub enum TrackingWarning {
+    /// The file name is not valid on Windows, for the given reason
+    NotPortable(HgPathBuf, String),
+    /// The file to add is not in the working directory
+    DoesNotExist(HgPathBuf),
+    /// The file to add is neither a regular file nor a symlink
+    NotAFile(HgPathBuf),
+    /// The file to add is already tracked
+    AlreadyTracked(HgPathBuf),
+    /// The file to add is large enough to need a lot of memory, with its
+    /// size in bytes
+    LargeFile(HgPathBuf, u64),
+    /// The file to forget is not tracked
+    NotTracked(HgPathBuf),
+    /// An explicitly given file cannot be removed or forgotten
+    NotRemoving(HgPathBuf, NotRemovingReason),
+}
+
+/// Why `remove` or `forget` leave a file alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotRemovingReason {
+    /// `forget` of a file that is not tracked
+    AlreadyUntracked,
+    /// `remove` of a file that is not tracked
+    Untracked,
+    /// `remove` of a directory without any tracked file
+    NoTrackedFiles,
+    /// `remove --after` of a file that is still there
+    StillExists,
+    /// `remove` of a modified file without `--force`
+    Modified,
+    /// `remove` of an added file without `--force`
+    MarkedForAdd,
+}
+
+/// What to do about file names that would be a problem on other platforms,
+/// as configured by `ui.portablefilenames`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortabilityCheck {
+    Ignore,
+    Warn,
+    Abort,
+}
+
+impl PortabilityCheck {
+    pub fn from_config(config: &Config) -> Result<Self, HgError> {
+        let value = config
+            .get(b"ui", b"portablefilenames")
+            .unwrap_or(b"warn")
+            .to_ascii_lowercase();
+        Ok(match value.as_slice() {
+            b"abort" => Self::Abort,
+            b"warn" => Self::Warn,
+            b"ignore" => Self::Ignore,
+            _ => {
+                if config.get_bool(b"ui", b"portablefilenames")? {
+                    Self::Warn
+                } else {
+                    Self::Ignore
+                }
+            }
+        })
+    }
+}
+
+/// A file concerned by a tracking operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackingEntry {
+    pub path: HgPathBuf,
+    /// Whether the path was given explicitly, as opposed to matched through
+    /// a directory or a pattern
+    pub exact: bool,
+}
+
+/// The outcome of [`add_plan`], to be given to [`add_apply`]
+pub struct AddPlan {
+    /// Files to add, sorted by path
+    pub entries: Vec<AddEntry>,
+    /// Explicitly given paths that could not be looked at
+    pub bad: Vec<(HgPathBuf, BadMatch)>,
+}
+
+/// A file to add
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddEntry {
+    pub path: HgPathBuf,
+    pub exact: bool,
+    /// Whether the path only differs by case from a known one, which is only
+    /// checked when portability checks are enabled
+    pub case_collision: bool,
+}
+
+/// The outcome of [`forget_plan`], to be given to [`forget_apply`]
+pub struct ForgetPlan {
+    /// Files to forget, sorted by path
+    pub entries: Vec<TrackingEntry>,
+    /// Explicitly given paths that could not be looked at
+    pub bad: Vec<(HgPathBuf, BadMatch)>,
+    /// Whether some explicitly given paths will not be forgotten
+    pub rejected: bool,
+}
+
+/// The outcome of [`remove_plan`], to be given to [`remove_apply`]
+pub struct RemovePlan {
+    /// Files to remove, sorted by path
+    pub entries: Vec<TrackingEntry>,
+    /// Explicitly given paths that could not be looked at
+    pub bad: Vec<(HgPathBuf, BadMatch)>,
+    /// Whether some files will not be removed
+    pub rejected: bool,
+    /// Only record the removal of files, leaving the working copy alone
+    after: bool,
+    /// Files to remove that were added, which are never deleted
+    added: FastHashSet<HgPathBuf>,
+}
+
+/// What `addremove` does to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddRemoveAction {
+    Add,
+    Remove,
+}
+
+/// A file concerned by `addremove`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddRemoveEntry {
+    pub path: HgPathBuf,
+    pub action: AddRemoveAction,
+    pub exact: bool,
+}
+
+/// The outcome of [`addremove_plan`], to be given to [`addremove_apply`]
+pub struct AddRemovePlan {
+    /// Files to add or remove, sorted by path
+    pub entries: Vec<AddRemoveEntry>,
+    /// Removed files detected as renamed to added ones
+    pub renames: Vec<Rename>,
+    /// Explicitly given paths that could not be looked at
+    pub bad: Vec<(HgPathBuf, BadMatch)>,
+}
+
+/// Files of the working copy matched by an operation, as given by status
+#[derive(Default)]
+pub(super) struct WalkStatus {
+    pub(super) modified: Vec<HgPathBuf>,
+    pub(super) added: Vec<HgPathBuf>,
+    pub(super) removed: Vec<HgPathBuf>,
+    pub(super) deleted: Vec<HgPathBuf>,
+    pub(super) clean: Vec<HgPathBuf>,
+    pub(super) unknown: Vec<HgPathBuf>,
+    pub(super) bad: Vec<(HgPathBuf, BadMatch)>,
+}
+
+/// Run the status of the working copy for `matcher`. When listing clean
+/// files, those that cannot be told from modified ones by their metadata are
+/// compared with their contents in the parent.
+pub(super) fn walk(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    matcher: &impl Matcher,
+    list_clean: bool,
+    list_unknown: bool,
+) -> Result<WalkStatus, HgError> {
+    let check_exec = check_exec(repo.working_directory_path());
+    let options = StatusOptions {
+        check_exec,
+        list_clean,
+        list_unknown,
+        list_ignored: false,
+        list_copies: false,
+        collect_traversed_dirs: false,
+        empty_dirs_keep_files: false,
+    };
+    let (mut walk, unsure) = dirstate.with_status(
+        matcher,
+        repo.working_directory_path().to_owned(),
+        get_ignore_files(repo),
+        options,
+        |res, _warnings| -> Result<_, HgError> {
+            let status = res?;
+            let owned = |paths: Vec<crate::dirstate::status::StatusPath>| {
+                let mut paths: Vec<HgPathBuf> =
+                    paths.into_iter().map(|p| p.path.into_owned()).collect();
+                paths.sort_unstable();
+                paths
+            };
+            let walk = WalkStatus {
+                modified: owned(status.modified),
+                added: owned(status.added),
+                removed: owned(status.removed),
+                deleted: owned(status.deleted),
+                clean: owned(status.clean),
+                unknown: owned(status.unknown),
+                bad: status
+                    .bad
+                    .into_iter()
+                    .map(|(path, error)| (path.into_owned(), error))
+                    .collect(),
+            };
+            Ok((walk, owned(status.unsure)))
+        },
+    )?;
+    if unsure.is_empty() || !list_clean {
+        // Files we are unsure about are tracked and present on disk, which
+        // is all that matters when clean files are not needed.
+        walk.modified.extend(unsure);
+        walk.modified.sort_unstable();
+        return Ok(walk);
+    }
+    let manifest = repo.manifest_for_node(repo.dirstate_parents()?.p1)?;
+    let settled = settle_unsure_files(repo, check_exec, &manifest, unsure)?;
+    for (path, outcome) in settled {
+        match outcome {
+            FileCompOutcome::Clean => walk.clean.push(path),
+            FileCompOutcome::Modified => walk.modified.push(path),
+            FileCompOutcome::Deleted => walk.deleted.push(path),
+        }
+    }
+    walk.clean.sort_unstable();
+    walk.modified.sort_unstable();
+    walk.deleted.sort_unstable();
+    Ok(walk)
+}
+
+/// Compare the contents of the files that `status` was unsure about with
+/// their revision in `p1_manifest`, since their metadata was not enough to
+/// tell whether they changed.
+pub(crate) fn settle_unsure_files(
+    repo: &Repo,
+    check_exec: bool,
+    p1_manifest: &Manifest,
+    unsure: Vec<HgPathBuf>,
+) -> Result<Vec<(HgPathBuf, FileCompOutcome)>, HgError> {
+    let working_directory_vfs = repo.working_directory_vfs();
+    let store_vfs = repo.store_vfs();
+    let filelog_options = default_revlog_options(
+        repo.config(),
+        repo.requirements(),
+        RevlogType::Filelog,
+    )?;
+    let settled = unsure
+        .into_par_iter()
+        .map(|path| {
+            let outcome = match is_file_modified(
+                &working_directory_vfs,
+                &store_vfs,
+                check_exec,
+                p1_manifest,
+                &path,
+                &filelog_options,
+            ) {
+                Ok(outcome) => outcome,
+                // The file was most likely deleted in the meantime
+                Err(RevlogError::IO(_)) => FileCompOutcome::Deleted,
+                Err(e) => return Err(e),
+            };
+            Ok((path, outcome))
+        })
+        .collect::<Result<_, RevlogError>>()?;
+    Ok(settled)
+}
+
+/// Returns the explicitly given paths of `matcher`, sorted
+pub(super) fn explicit_files(matcher: &impl Matcher) -> Vec<&HgPathBuf> {
+    let mut files: Vec<_> = matcher
+        .file_set()
+        .map(|files| files.iter().collect())
+        .unwrap_or_default();
+    files.sort_unstable();
+    files
+}
+
+/// Whether `path` is a regular file or a symlink in the working directory
+pub(super) fn is_file_on_disk(
+    repo: &Repo,
+    path: &HgPath,
+) -> Result<bool, HgError> {
+    let fs_path =
+        repo.working_directory_path().join(hg_path_to_path_buf(path)?);
+    Ok(std::fs::symlink_metadata(fs_path)
+        .is_ok_and(|m| m.is_file() || m.is_symlink()))
+}
+
+/// Fall back for file names that Python refuses with an error of its own
+pub(super) fn check_file_names<'a>(
+    paths: impl IntoIterator<Item = &'a HgPath>,
+) -> Result<(), HgError> {
+    for path in paths {
+        if path.as_bytes().iter().any(|&b| b == b'\n' || b == b'\r') {
+            return Err(HgError::unsupported(
+                "file names containing '\\n' or '\\r'",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Find the untracked files matched by `matcher` to start tracking. Unknown
+/// files are matched through directories and patterns, while explicitly
+/// given files are added even if ignored.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn add_plan(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    matcher: &impl Matcher,
+) -> Result<AddPlan, HgError> {
+    let walk = walk(repo, dirstate, matcher, false, true)?;
+    let mut names: FastHashMap<HgPathBuf, bool> = walk
+        .unknown
+        .into_iter()
+        .map(|path| {
+            let exact = matcher.exact_match(&path);
+            (path, exact)
+        })
+        .collect();
+    for path in explicit_files(matcher) {
+        if path.is_empty()
+            || names.contains_key(path)
+            || walk.bad.iter().any(|(bad, _)| bad == path)
+        {
+            continue;
+        }
+        // Tracked files get a warning, and removed ones are added back
+        if dirstate.get(path)?.is_some() || is_file_on_disk(repo, path)? {
+            names.insert(path.to_owned(), true);
+        }
+    }
+    let mut names: Vec<_> = names.into_iter().collect();
+    names.sort_unstable();
+    check_file_names(names.iter().map(|(path, _)| path.as_ref()))?;
+
+    let mut case_collisions = FastHashSet::default();
+    if !names.is_empty()
+        && PortabilityCheck::from_config(repo.config())?
+            != PortabilityCheck::Ignore
+    {
+        let mut lowered = FastHashSet::default();
+        for entry in dirstate.iter() {
+            let (path, _) = entry.map_err(DirstateError::from)?;
+            lowered.insert(path.as_bytes().to_ascii_lowercase());
+        }
+        for (path, _) in &names {
+            let lower = path.as_bytes().to_ascii_lowercase();
+            if lowered.contains(&lower) && dirstate.get(path)?.is_none() {
+                case_collisions.insert(path.to_owned());
+            }
+            lowered.insert(lower);
+        }
+    }
+
+    let entries = names
+        .into_iter()
+        .map(|(path, exact)| {
+            let case_collision = case_collisions.contains(&path);
+            AddEntry { path, exact, case_collision }
+        })
+        .collect();
+    Ok(AddPlan { entries, bad: walk.bad })
+}
+
+/// Start tracking the files of `plan`. Returns the files that could not be
+/// added.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn add_apply(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    plan: &AddPlan,
+    warnings: &HgWarningSender,
+) -> Result<Vec<HgPathBuf>, HgError> {
+    let files: Vec<_> = plan.entries.iter().map(|e| e.path.as_ref()).collect();
+    add_files(repo, dirstate, &files, warnings)
+}
+
+/// Start tracking `files`, which must exist in the working directory.
+/// Returns the files that could not be added.
+pub(super) fn add_files(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    files: &[&HgPath],
+    warnings: &HgWarningSender,
+) -> Result<Vec<HgPathBuf>, HgError> {
+    let config = repo.config();
+    let portability = PortabilityCheck::from_config(config)?;
+    let large_file_limit =
+        config.get_byte_size(b"ui", b"large-file-limit")?.unwrap_or(0);
+    let mut rejected = vec![];
+    let mut changed = false;
+    for &path in files {
+        if portability != PortabilityCheck::Ignore
+            && let Some(reason) = check_windows_filename(path.as_bytes())
+        {
+            if portability == PortabilityCheck::Abort {
+                return Err(HgError::abort(
+                    format!(
+                        "{}: {}",
+                        reason,
+                        String::from_utf8_lossy(&shell_quote(path.as_bytes()))
+                    ),
+                    exit_codes::INPUT_ERROR,
+                    None,
+                ));
+            }
+            warnings
+                .send(TrackingWarning::NotPortable(path.to_owned(), reason));
+        }
+        let fs_path =
+            repo.working_directory_path().join(hg_path_to_path_buf(path)?);
+        let Ok(metadata) = std::fs::symlink_metadata(fs_path) else {
+            warnings.send(TrackingWarning::DoesNotExist(path.to_owned()));
+            rejected.push(path.to_owned());
+            continue;
+        };
+        if large_file_limit != 0 && metadata.len() > large_file_limit {
+            warnings.send(TrackingWarning::LargeFile(
+                path.to_owned(),
+                metadata.len(),
+            ));
+        }
+        if !(metadata.is_file() || metadata.is_symlink()) {
+            warnings.send(TrackingWarning::NotAFile(path.to_owned()));
+            rejected.push(path.to_owned());
+        } else if dirstate.set_tracked(path)? {
+            changed = true;
+        } else {
+            warnings.send(TrackingWarning::AlreadyTracked(path.to_owned()));
+        }
+    }
+    if changed {
+        write_tracked_key(repo)?;
+    }
+    Ok(rejected)
+}
+
+/// Stop tracking `files`. Returns the files that were not tracked.
+pub(super) fn forget_files(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    files: &[&HgPath],
+    warnings: &HgWarningSender,
+) -> Result<Vec<HgPathBuf>, HgError> {
+    let mut rejected = vec![];
+    for &path in files {
+        if !dirstate.set_untracked(path)? {
+            warnings.send(TrackingWarning::NotTracked(path.to_owned()));
+            rejected.push(path.to_owned());
+        }
+    }
+    if rejected.len() < files.len() {
+        write_tracked_key(repo)?;
+    }
+    Ok(rejected)
+}
+
+/// Find the tracked files matched by `matcher` to stop tracking, and warn
+/// about explicitly given files that are not tracked.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn forget_plan(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    matcher: &impl Matcher,
+    warnings: &HgWarningSender,
+) -> Result<ForgetPlan, HgError> {
+    let walk = walk(repo, dirstate, matcher, true, false)?;
+    let mut rejected = !walk.bad.is_empty();
+    let mut files: Vec<_> =
+        [walk.modified, walk.added, walk.deleted, walk.clean]
+            .into_iter()
+            .flatten()
+            .collect();
+    files.sort_unstable();
+    for path in explicit_files(matcher) {
+        let fs_path =
+            repo.working_directory_path().join(hg_path_to_path_buf(path)?);
+        if dirstate.get(path)?.is_some() || path.is_empty() || fs_path.is_dir()
+        {
+            continue;
+        }
+        if std::fs::symlink_metadata(&fs_path).is_ok() {
+            warnings.send(TrackingWarning::NotRemoving(
+                path.to_owned(),
+                NotRemovingReason::AlreadyUntracked,
+            ));
+        }
+        rejected = true;
+    }
+    let entries = files
+        .into_iter()
+        .map(|path| {
+            let exact = matcher.exact_match(&path);
+            TrackingEntry { path, exact }
+        })
+        .collect();
+    Ok(ForgetPlan { entries, bad: walk.bad, rejected })
+}
+
+/// Stop tracking the files of `plan`. Returns the files that could not be
+/// forgotten.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn forget_apply(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    plan: &ForgetPlan,
+    warnings: &HgWarningSender,
+) -> Result<Vec<HgPathBuf>, HgError> {
+    let files: Vec<_> = plan.entries.iter().map(|e| e.path.as_ref()).collect();
+    forget_files(repo, dirstate, &files, warnings)
+}
+
+/// Find the tracked files matched by `matcher` to remove, and warn about
+/// those that will be left alone.
+///
+/// By default, only clean and missing files are removed. With `force`,
+/// modified and added files are also removed. With `after`, only missing
+/// files are, and the others are only warned about if `verbose` or given
+/// explicitly.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn remove_plan(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    matcher: &impl Matcher,
+    after: bool,
+    force: bool,
+    verbose: bool,
+    warnings: &HgWarningSender,
+) -> Result<RemovePlan, HgError> {
+    let walk = walk(repo, dirstate, matcher, true, false)?;
+    let mut rejected = false;
+
+    // Warn about explicitly given paths that cannot be removed
+    let explicit = explicit_files(matcher);
+    for &path in &explicit {
+        if path.is_empty()
+            || dirstate.get(path)?.is_some()
+            || dirstate.has_tracked_dir(path)?
+        {
+            continue;
+        }
+        let fs_path =
+            repo.working_directory_path().join(hg_path_to_path_buf(path)?);
+        if std::fs::symlink_metadata(&fs_path).is_ok() {
+            let reason = if fs_path.is_dir() {
+                NotRemovingReason::NoTrackedFiles
+            } else {
+                NotRemovingReason::Untracked
+            };
+            warnings
+                .send(TrackingWarning::NotRemoving(path.to_owned(), reason));
+        }
+        // Missing files were reported by the status
+        rejected = true;
+    }
+
+    let mut left_alone = vec![];
+    let mut files: Vec<HgPathBuf> = if force {
+        [&walk.modified, &walk.deleted, &walk.clean, &walk.added]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    } else if after {
+        let explicit: FastHashSet<_> = explicit.into_iter().collect();
+        for path in
+            [&walk.modified, &walk.added, &walk.clean].into_iter().flatten()
+        {
+            if verbose || explicit.contains(path) {
+                left_alone.push((path, NotRemovingReason::StillExists));
+            }
+            rejected = true;
+        }
+        walk.deleted.clone()
+    } else {
+        left_alone.extend(
+            walk.modified.iter().map(|p| (p, NotRemovingReason::Modified)),
+        );
+        left_alone.extend(
+            walk.added.iter().map(|p| (p, NotRemovingReason::MarkedForAdd)),
+        );
+        rejected |= !left_alone.is_empty();
+        [&walk.deleted, &walk.clean].into_iter().flatten().cloned().collect()
+    };
+    for (path, reason) in left_alone {
+        warnings.send(TrackingWarning::NotRemoving(path.to_owned(), reason));
+    }
+    files.sort_unstable();
+
+    let entries = files
+        .into_iter()
+        .map(|path| {
+            let exact = matcher.exact_match(&path);
+            TrackingEntry { path, exact }
+        })
+        .collect();
+    Ok(RemovePlan {
+        entries,
+        bad: walk.bad,
+        rejected,
+        after,
+        added: walk.added.into_iter().collect(),
+    })
+}
+
+/// Delete the files of `plan` from the working directory, unless they were
+/// added or `after` was given, and stop tracking them.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn remove_apply(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    plan: &RemovePlan,
+    remove_empty_dirs: bool,
+    warnings: &HgWarningSender,
+) -> Result<(), HgError> {
+    if !plan.after {
+        let working_directory_vfs = repo.working_directory_vfs();
+        for entry in &plan.entries {
+            if plan.added.contains(&entry.path) {
+                continue;
+            }
+            let path = hg_path_to_path_buf(&entry.path)?;
+            if working_directory_vfs.symlink_metadata(&path).is_ok() {
+                working_copy_remove(
+                    &path,
+                    &working_directory_vfs,
+                    remove_empty_dirs,
+                )?;
+            }
+        }
+    }
+    let files: Vec<_> = plan.entries.iter().map(|e| e.path.as_ref()).collect();
+    forget_files(repo, dirstate, &files, warnings)?;
+    Ok(())
+}
+
+/// Find the unknown files matched by `matcher` to add and the missing ones
+/// to remove. If `similarity` is above 0, also find which of the added
+/// files are renames of the removed ones, see [`find_renames`].
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn addremove_plan(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    matcher: &impl Matcher,
+    similarity: f64,
+) -> Result<AddRemovePlan, HgError> {
+    let walk = walk(repo, dirstate, matcher, false, true)?;
+    let mut to_add: FastHashSet<HgPathBuf> = walk.unknown.into_iter().collect();
+    // Removed files that are back on disk are added again
+    let mut removed = vec![];
+    for path in walk.removed {
+        if is_file_on_disk(repo, &path)? {
+            to_add.insert(path);
+        } else {
+            removed.push(path);
+        }
+    }
+    // Explicitly given files are added even if ignored
+    for path in explicit_files(matcher) {
+        if !path.is_empty()
+            && !to_add.contains(path)
+            && dirstate.get(path)?.is_none()
+            && !walk.bad.iter().any(|(bad, _)| bad == path)
+            && is_file_on_disk(repo, path)?
+        {
+            to_add.insert(path.to_owned());
+        }
+    }
+    check_file_names(to_add.iter().map(|path| path.as_ref()))?;
+
+    let mut entries: Vec<_> = to_add
+        .iter()
+        .map(|path| (path, AddRemoveAction::Add))
+        .chain(walk.deleted.iter().map(|path| (path, AddRemoveAction::Remove)))
+        .map(|(path, action)| AddRemoveEntry {
+            path: path.to_owned(),
+            action,
+            exact: matcher.exact_match(path),
+        })
+        .collect();
+    entries.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+    let renames = if similarity > 0.0 {
+        let parents = repo.dirstate_parents()?;
+        let added: Vec<_> = walk.added.into_iter().chain(to_add).collect();
+        let removed: Vec<_> = removed.into_iter().chain(walk.deleted).collect();
+        find_renames(
+            repo,
+            &repo.manifest_for_node(parents.p1)?,
+            &added,
+            &removed,
+            similarity,
+        )?
+    } else {
+        vec![]
+    };
+    Ok(AddRemovePlan { entries, renames, bad: walk.bad })
+}
+
+/// Add and remove the files of `plan`, and record its renames as copies.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn addremove_apply(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    plan: &AddRemovePlan,
+    warnings: &HgWarningSender,
+) -> Result<(), HgError> {
+    let with_action = |action| -> Vec<&HgPath> {
+        plan.entries
+            .iter()
+            .filter(|e| e.action == action)
+            .map(|e| e.path.as_ref())
+            .collect()
+    };
+    forget_files(
+        repo,
+        dirstate,
+        &with_action(AddRemoveAction::Remove),
+        warnings,
+    )?;
+    add_files(repo, dirstate, &with_action(AddRemoveAction::Add), warnings)?;
+    for rename in &plan.renames {
+        if !is_file_on_disk(repo, &rename.dest)? {
+            warnings
+                .send(TrackingWarning::DoesNotExist(rename.dest.to_owned()));
+            continue;
+        }
+        dirstate.set_tracked(&rename.dest)?;
+        dirstate.copy_map_insert(&rename.dest, &rename.source)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::NULL_NODE;
+    use crate::matchers::AlwaysMatcher;
+    use crate::matchers::FileMatcher;
+    use crate::testing::TestRepo;
+    use crate::warnings::HgWarning;
+    use crate::warnings::HgWarningContext;
+
+    fn paths(paths: &[HgPathBuf]) -> Vec<&str> {
+        paths
+            .iter()
+            .map(|path| std::str::from_utf8(path.as_bytes()).unwrap())
+            .collect()
+    }
+
+    fn entry_paths(entries: &[TrackingEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| std::str::from_utf8(entry.path.as_bytes()).unwrap())
+            .collect()
+    }
+
+    fn file_matcher(files: &[&str]) -> FileMatcher {
+        FileMatcher::new(
+            files.iter().map(|f| HgPathBuf::from_bytes(f.as_bytes())).collect(),
+        )
+        .unwrap()
+    }
+
+    /// Sets a modification time that the dirstate cannot have recorded, so
+    /// that status needs to compare the contents of `path`
+    fn touch(repo: &TestRepo, path: &str) {
+        let file = std::fs::File::options()
+            .write(true)
+            .open(repo.path().join(path))
+            .unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1000))
+            .unwrap();
+    }
+
+    /// The warnings of `warnings` about files left alone
+    fn not_removing(
+        warnings: HgWarningContext,
+    ) -> Vec<(String, NotRemovingReason)> {
+        let mut not_removing = vec![];
+        warnings
+            .finish(|warning| -> Result<(), ()> {
+                if let HgWarning::Tracking(TrackingWarning::NotRemoving(
+                    path,
+                    reason,
+                )) = warning
+                {
+                    let path = String::from_utf8(path.into_vec()).unwrap();
+                    not_removing.push((path, reason));
+                }
+                Ok(())
+            })
+            .unwrap();
+        not_removing
+    }
+
+    /// A repository with the files `clean`, `modified` and `deleted` at the
+    /// working copy parent, the last two changed accordingly, and the new
+    /// file `added` tracked
+    fn changed_repo() -> TestRepo {
+        let mut repo = TestRepo::new(&[]);
+        let node = repo.commit(
+            NULL_NODE,
+            &[
+                ("clean", Some("clean\n")),
+                ("deleted", Some("deleted\n")),
+                ("modified", Some("modified\n")),
+            ],
+        );
+        repo.update_from_null(node);
+        repo.write("modified", "modified twice\n");
+        repo.write("added", "added\n");
+        std::fs::remove_file(repo.path().join("deleted")).unwrap();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        dirstate.set_tracked(HgPath::new(b"added")).unwrap();
+        drop(dirstate);
+        repo
+    }
+
+    #[test]
+    fn test_walk_settles_unsure_files() {
+        let mut repo = TestRepo::new(&[]);
+        let node = repo.commit(
+            NULL_NODE,
+            &[
+                ("a", Some("a1\n")),
+                ("b", Some("b\n")),
+                ("c", Some("c\n")),
+                ("dir/d", Some("d\n")),
+            ],
+        );
+        repo.update_from_null(node);
+        // Same size, so only the contents tell `a` from `b`
+        repo.write("a", "a2\n");
+        touch(&repo, "a");
+        touch(&repo, "b");
+        touch(&repo, "dir/d");
+        std::fs::remove_file(repo.path().join("c")).unwrap();
+        repo.write("unknown", "unknown\n");
+
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let walked =
+            walk(&repo.repo, &mut dirstate, &AlwaysMatcher, true, true)
+                .unwrap();
+        assert_eq!(paths(&walked.modified), ["a"]);
+        assert_eq!(paths(&walked.clean), ["b", "dir/d"]);
+        assert_eq!(paths(&walked.deleted), ["c"]);
+        assert_eq!(paths(&walked.unknown), ["unknown"]);
+
+        // Without clean files, the unsure ones are not compared
+        let walked =
+            walk(&repo.repo, &mut dirstate, &AlwaysMatcher, false, false)
+                .unwrap();
+        assert_eq!(paths(&walked.modified), ["a", "b", "dir/d"]);
+        assert!(walked.clean.is_empty());
+        assert!(walked.unknown.is_empty());
+    }
+
+    #[test]
+    fn test_addremove_similarity() {
+        let mut repo = TestRepo::new(&[]);
+        let content: String =
+            (0..10).map(|line| format!("line {}\n", line)).collect();
+        let node = repo.commit(
+            NULL_NODE,
+            &[("old", Some(&content)), ("kept", Some("kept\n"))],
+        );
+        repo.update_from_null(node);
+        std::fs::remove_file(repo.path().join("old")).unwrap();
+        // 8 out of 10 lines are the same
+        repo.write(
+            "new",
+            &content
+                .replace("line 1\n", "changed 1\n")
+                .replace("line 2\n", "changed 2\n"),
+        );
+        repo.write("other", "other\n");
+
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let plan =
+            addremove_plan(&repo.repo, &mut dirstate, &AlwaysMatcher, 0.0)
+                .unwrap();
+        let actions: Vec<_> = plan
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_bytes(), entry.action))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (&b"new"[..], AddRemoveAction::Add),
+                (b"old", AddRemoveAction::Remove),
+                (b"other", AddRemoveAction::Add),
+            ]
+        );
+        assert!(plan.renames.is_empty());
+
+        let plan =
+            addremove_plan(&repo.repo, &mut dirstate, &AlwaysMatcher, 0.9)
+                .unwrap();
+        assert!(plan.renames.is_empty());
+        let plan =
+            addremove_plan(&repo.repo, &mut dirstate, &AlwaysMatcher, 0.5)
+                .unwrap();
+        assert_eq!(plan.renames.len(), 1);
+        let rename = &plan.renames[0];
+        assert_eq!(rename.source.as_bytes(), b"old");
+        assert_eq!(rename.dest.as_bytes(), b"new");
+        assert!(rename.score > 0.5 && rename.score < 0.9);
+
+        let warnings = HgWarningContext::new();
+        addremove_apply(&repo.repo, &mut dirstate, &plan, warnings.sender())
+            .unwrap();
+        assert!(not_removing(warnings).is_empty());
+        let new = HgPath::new(b"new");
+        assert!(dirstate.get(new).unwrap().unwrap().added());
+        assert!(dirstate.get(HgPath::new(b"old")).unwrap().unwrap().removed());
+        assert!(dirstate.get(HgPath::new(b"other")).unwrap().unwrap().added());
+        assert_eq!(
+            dirstate.copy_map_get(new).unwrap().map(HgPath::as_bytes),
+            Some(&b"old"[..])
+        );
+    }
+
+    #[test]
+    fn test_remove_plan() {
+        let repo = changed_repo();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let remove = |dirstate: &mut OwningDirstateMap,
+                      matcher: &dyn Fn() -> Box<dyn Matcher + Sync>,
+                      after,
+                      force,
+                      verbose| {
+            let warnings = HgWarningContext::new();
+            let plan = remove_plan(
+                &repo.repo,
+                dirstate,
+                &matcher(),
+                after,
+                force,
+                verbose,
+                warnings.sender(),
+            )
+            .unwrap();
+            (plan, not_removing(warnings))
+        };
+        let all = || -> Box<dyn Matcher + Sync> { Box::new(AlwaysMatcher) };
+
+        // Modified and added files are left alone without `--force`
+        let (plan, warnings) = remove(&mut dirstate, &all, false, false, false);
+        assert_eq!(entry_paths(&plan.entries), ["clean", "deleted"]);
+        assert!(plan.rejected);
+        assert_eq!(
+            warnings,
+            [
+                ("modified".to_owned(), NotRemovingReason::Modified),
+                ("added".to_owned(), NotRemovingReason::MarkedForAdd),
+            ]
+        );
+
+        let (plan, warnings) = remove(&mut dirstate, &all, false, true, false);
+        assert_eq!(
+            entry_paths(&plan.entries),
+            ["added", "clean", "deleted", "modified"]
+        );
+        assert!(!plan.rejected);
+        assert!(warnings.is_empty());
+
+        // `--after` only removes missing files, and only warns about the
+        // others if verbose or given explicitly
+        let (plan, warnings) = remove(&mut dirstate, &all, true, false, false);
+        assert_eq!(entry_paths(&plan.entries), ["deleted"]);
+        assert!(plan.rejected);
+        assert!(warnings.is_empty());
+        let (_, warnings) = remove(&mut dirstate, &all, true, false, true);
+        assert_eq!(warnings.len(), 3);
+        let explicit = || -> Box<dyn Matcher + Sync> {
+            Box::new(file_matcher(&["clean", "deleted", "unknown"]))
+        };
+        repo.write("unknown", "unknown\n");
+        let (plan, warnings) =
+            remove(&mut dirstate, &explicit, true, false, false);
+        assert_eq!(entry_paths(&plan.entries), ["deleted"]);
+        assert_eq!(
+            warnings,
+            [
+                ("unknown".to_owned(), NotRemovingReason::Untracked),
+                ("clean".to_owned(), NotRemovingReason::StillExists),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_apply() {
+        let repo = changed_repo();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let warnings = HgWarningContext::new();
+        let plan = remove_plan(
+            &repo.repo,
+            &mut dirstate,
+            &AlwaysMatcher,
+            false,
+            true,
+            false,
+            warnings.sender(),
+        )
+        .unwrap();
+        remove_apply(
+            &repo.repo,
+            &mut dirstate,
+            &plan,
+            false,
+            warnings.sender(),
+        )
+        .unwrap();
+        assert!(not_removing(warnings).is_empty());
+        // Added files are only forgotten
+        assert!(dirstate.get(HgPath::new(b"added")).unwrap().is_none());
+        assert!(repo.path().join("added").exists());
+        for path in ["clean", "deleted", "modified"] {
+            let entry = dirstate.get(HgPath::new(path.as_bytes())).unwrap();
+            assert!(entry.unwrap().removed());
+            assert!(!repo.path().join(path).exists());
+        }
+    }
+
+    #[test]
+    fn test_remove_after_keeps_files() {
+        let repo = changed_repo();
+        std::fs::remove_file(repo.path().join("clean")).unwrap();
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let warnings = HgWarningContext::new();
+        let plan = remove_plan(
+            &repo.repo,
+            &mut dirstate,
+            &AlwaysMatcher,
+            true,
+            false,
+            false,
+            warnings.sender(),
+        )
+        .unwrap();
+        assert_eq!(entry_paths(&plan.entries), ["clean", "deleted"]);
+        remove_apply(
+            &repo.repo,
+            &mut dirstate,
+            &plan,
+            false,
+            warnings.sender(),
+        )
+        .unwrap();
+        assert!(not_removing(warnings).is_empty());
+        assert!(
+            dirstate.get(HgPath::new(b"clean")).unwrap().unwrap().removed()
+        );
+        assert!(repo.path().join("modified").exists());
+        assert!(
+            dirstate.get(HgPath::new(b"modified")).unwrap().unwrap().tracked()
+        );
+    }
+
+    #[test]
+    fn test_forget() {
+        let repo = changed_repo();
+        repo.write("unknown", "unknown\n");
+        let mut dirstate = repo.repo.dirstate_map_mut().unwrap();
+        let warnings = HgWarningContext::new();
+        let matcher = file_matcher(&["added", "modified", "unknown"]);
+        let plan =
+            forget_plan(&repo.repo, &mut dirstate, &matcher, warnings.sender())
+                .unwrap();
+        // Modified and added files are forgotten as well
+        assert_eq!(entry_paths(&plan.entries), ["added", "modified"]);
+        assert!(plan.entries.iter().all(|entry| entry.exact));
+        assert!(plan.rejected);
+        forget_apply(&repo.repo, &mut dirstate, &plan, warnings.sender())
+            .unwrap();
+        assert_eq!(
+            not_removing(warnings),
+            [("unknown".to_owned(), NotRemovingReason::AlreadyUntracked)]
+        );
+        assert!(dirstate.get(HgPath::new(b"added")).unwrap().is_none());
+        assert!(
+            dirstate.get(HgPath::new(b"modified")).unwrap().unwrap().removed()
+        );
+        // The files stay in the working directory
+        assert!(repo.path().join("added").exists());
+        assert!(repo.path().join("modified").exists());
+    }
+}
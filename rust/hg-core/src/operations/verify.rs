@@ -0,0 +1,1207 @@
+//! Checking the integrity of the store of a repository, like Python's
+//! `verify.py`.
+//!
+//! Problems are reported in the same order and with the same messages as
+//! Python, but filelogs are checked in parallel.
+
+use std::convert::Infallible;
+
+use format_bytes::format_bytes;
+use rayon::prelude::*;
+
+use crate::FastHashMap;
+use crate::FastHashSet;
+use crate::Graph;
+use crate::NULL_NODE;
+use crate::NULL_REVISION;
+use crate::Node;
+use crate::Revision;
+use crate::dirstate::DirstateError;
+use crate::errors::HgError;
+use crate::linkrev::LinkRevision;
+use crate::linkrev::check_link_revision;
+use crate::repo::Repo;
+use crate::revlog::BaseRevision;
+use crate::revlog::Revlog;
+use crate::revlog::RevlogError;
+use crate::revlog::RevlogType;
+use crate::revlog::changelog::Changelog;
+use crate::revlog::filelog::Filelog;
+use crate::revlog::manifest::Manifest;
+use crate::revlog::manifest::Manifestlog;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+
+/// How thoroughly to check a repository
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Also read the full text of every manifest, like `verify --full`
+    pub full: bool,
+    /// Also check that copy sources are in the parents of the changesets
+    /// introducing copies, and report copies from the null revision
+    pub verbose: bool,
+    /// Whether censored file revisions are errors, from `censor.policy`
+    pub error_on_censored: bool,
+    /// The revision flags of file revisions whose hash is not checked, from
+    /// `verify.skipflags`
+    pub skip_flags: u32,
+}
+
+/// A step of the verification, announced before its problems
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyStage {
+    Changesets,
+    Manifests,
+    CrosscheckFiles,
+    Files,
+    Dirstate,
+}
+
+impl VerifyStage {
+    /// The status line announcing the step
+    pub fn message(self) -> &'static [u8] {
+        match self {
+            VerifyStage::Changesets => b"checking changesets",
+            VerifyStage::Manifests => b"checking manifests",
+            VerifyStage::CrosscheckFiles => {
+                b"crosschecking files in changesets and manifests"
+            }
+            VerifyStage::Files => b"checking files",
+            VerifyStage::Dirstate => b"checking dirstate",
+        }
+    }
+}
+
+/// An integrity error, tied to a changeset when possible
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The changeset that seems damaged, if known
+    pub linkrev: Option<BaseRevision>,
+    /// The file, or `changelog` or `manifest`, if the error is about one
+    pub label: Option<HgPathBuf>,
+    pub message: Vec<u8>,
+}
+
+impl VerifyError {
+    /// Formats the error like Python, as ` file@linkrev: message`
+    pub fn format(&self) -> Vec<u8> {
+        let linkrev = match self.linkrev {
+            Some(linkrev) => linkrev.to_string().into_bytes(),
+            None => b"?".to_vec(),
+        };
+        let mut line = b" ".to_vec();
+        if let Some(label) = &self.label {
+            line.extend_from_slice(label.as_bytes());
+            line.push(b'@');
+        }
+        line.extend(format_bytes!(b"{}: {}", linkrev, self.message));
+        line
+    }
+}
+
+/// Something reported while verifying, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyMessage {
+    Stage(VerifyStage),
+    /// The dirstate is not checked because the store has errors
+    SkippingDirstate,
+    Warning(Vec<u8>),
+    Error(VerifyError),
+    /// The dirstate does not match the manifests of its parents
+    DirstateError(Vec<u8>),
+    /// Information only shown in verbose mode
+    Note(Vec<u8>),
+}
+
+/// The counts reported at the end of the verification
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifySummary {
+    pub changesets: usize,
+    /// The number of file revisions
+    pub file_revisions: usize,
+    pub files: usize,
+    pub warnings: usize,
+    /// All errors, including dirstate errors
+    pub errors: usize,
+    pub dirstate_errors: usize,
+    /// Whether revlogs are missing from the fncache, which
+    /// `hg debugrebuildfncache` fixes
+    pub fncache_warned: bool,
+    /// The lowest changeset referred to by an error
+    pub first_damaged_changeset: Option<BaseRevision>,
+}
+
+/// The changesets referring to each manifest node
+type ManifestLinkrevs = FastHashMap<Node, Vec<Revision>>;
+/// The changesets touching each file, in increasing order
+type FileLinkrevs = FastHashMap<HgPathBuf, Vec<Revision>>;
+/// The file nodes referred to by manifests, with the changeset of the first
+/// manifest introducing them
+type FileNodes =
+    FastHashMap<HgPathBuf, FastHashMap<Node, Option<BaseRevision>>>;
+
+/// Checks the integrity of the store of `repo`, calling `emit` with the
+/// problems found as they would be printed, and returns the final counts.
+///
+/// The caller should hold the store lock.
+pub fn verify<E: From<HgError>>(
+    repo: &Repo,
+    options: &VerifyOptions,
+    emit: impl FnMut(VerifyMessage) -> Result<(), E>,
+) -> Result<VerifySummary, E> {
+    let changelog = repo.changelog()?;
+    let manifestlog = repo.manifestlog()?;
+    let context = Context {
+        repo,
+        options,
+        changelog: &changelog,
+        manifestlog: &manifestlog,
+        has_changelog: !changelog.revlog.is_empty(),
+        has_manifest: !manifestlog.revlog.is_empty(),
+    };
+    let mut verifier = Verifier {
+        context,
+        refers_manifest: false,
+        summary: VerifySummary::default(),
+        emit,
+    };
+
+    let (manifest_linkrevs, file_linkrevs) = verifier.verify_changelog()?;
+    let file_nodes = verifier.verify_manifest(manifest_linkrevs)?;
+    verifier.crosscheck_files(&file_linkrevs, &file_nodes)?;
+    verifier.verify_files(file_linkrevs, file_nodes)?;
+    if verifier.summary.errors > 0 {
+        verifier.report(VerifyMessage::SkippingDirstate)?;
+    } else {
+        verifier.report(VerifyMessage::Stage(VerifyStage::Dirstate))?;
+        for message in verify_dirstate(repo)? {
+            verifier.report(VerifyMessage::DirstateError(message))?;
+        }
+    }
+    verifier.summary.changesets = changelog.revlog.len();
+    Ok(verifier.summary)
+}
+
+/// What checking any revlog needs, shared by the threads checking filelogs
+#[derive(Clone, Copy)]
+struct Context<'a> {
+    repo: &'a Repo,
+    options: &'a VerifyOptions,
+    changelog: &'a Changelog,
+    manifestlog: &'a Manifestlog,
+    has_changelog: bool,
+    has_manifest: bool,
+}
+
+/// The problems found by a check, before they are reported
+#[derive(Default)]
+struct Problems(Vec<VerifyMessage>);
+
+impl Problems {
+    fn error(
+        &mut self,
+        linkrev: Option<BaseRevision>,
+        label: Option<&HgPath>,
+        message: impl Into<Vec<u8>>,
+    ) {
+        self.0.push(VerifyMessage::Error(VerifyError {
+            linkrev,
+            label: label.map(ToOwned::to_owned),
+            message: message.into(),
+        }));
+    }
+
+    fn warning(&mut self, message: impl Into<Vec<u8>>) {
+        self.0.push(VerifyMessage::Warning(message.into()))
+    }
+}
+
+struct Verifier<'a, F> {
+    context: Context<'a>,
+    /// Whether a changeset refers to a manifest, in which case the
+    /// manifestlog must not be empty
+    refers_manifest: bool,
+    summary: VerifySummary,
+    emit: F,
+}
+
+impl<E: From<HgError>, F: FnMut(VerifyMessage) -> Result<(), E>>
+    Verifier<'_, F>
+{
+    fn report(&mut self, message: VerifyMessage) -> Result<(), E> {
+        match &message {
+            VerifyMessage::Warning(_) => self.summary.warnings += 1,
+            VerifyMessage::Error(error) => {
+                self.summary.errors += 1;
+                if let Some(linkrev) = error.linkrev {
+                    let first = self.summary.first_damaged_changeset;
+                    self.summary.first_damaged_changeset =
+                        Some(first.map_or(linkrev, |first| first.min(linkrev)));
+                }
+            }
+            VerifyMessage::DirstateError(_) => {
+                self.summary.errors += 1;
+                self.summary.dirstate_errors += 1;
+            }
+            _ => {}
+        }
+        (self.emit)(message)
+    }
+
+    fn report_all(&mut self, problems: &mut Problems) -> Result<(), E> {
+        for message in problems.0.drain(..) {
+            self.report(message)?;
+        }
+        Ok(())
+    }
+
+    /// Checks every changeset, returning the manifests and files they
+    /// refer to
+    fn verify_changelog(
+        &mut self,
+    ) -> Result<(ManifestLinkrevs, FileLinkrevs), E> {
+        self.report(VerifyMessage::Stage(VerifyStage::Changesets))?;
+        let context = self.context;
+        let changelog = context.changelog;
+        let label = HgPath::new(b"changelog");
+        let mut manifest_linkrevs = ManifestLinkrevs::default();
+        let mut file_linkrevs = FileLinkrevs::default();
+        let mut seen = FastHashMap::default();
+        let mut problems = Problems::default();
+        context.check_revlog(&mut problems, &changelog.revlog, label);
+        for rev in revisions(&changelog.revlog) {
+            context.check_entry(
+                &mut problems,
+                &changelog.revlog,
+                rev,
+                &mut seen,
+                &[rev],
+                label,
+            );
+            let read = || -> Result<_, HgError> {
+                let data = changelog.entry(rev)?.data()?;
+                let files: Vec<_> = data.files().map(normalize_path).collect();
+                Ok((data.manifest_node()?, files))
+            };
+            match read() {
+                Ok((manifest_node, files)) => {
+                    if manifest_node != NULL_NODE {
+                        manifest_linkrevs
+                            .entry(manifest_node)
+                            .or_default()
+                            .push(rev);
+                        self.refers_manifest = true;
+                    }
+                    for file in files {
+                        file_linkrevs.entry(file).or_default().push(rev);
+                    }
+                }
+                Err(error) => {
+                    self.refers_manifest = true;
+                    let node = changelog.revlog.node_from_rev(rev);
+                    let message = format!(
+                        "unpacking changeset {:x}: {}",
+                        node.short(),
+                        describe(error, b"00changelog")
+                    );
+                    problems.error(Some(rev.0), None, message);
+                }
+            }
+            self.report_all(&mut problems)?;
+        }
+        Ok((manifest_linkrevs, file_linkrevs))
+    }
+
+    /// Checks every manifest, returning the file nodes they refer to
+    fn verify_manifest(
+        &mut self,
+        mut manifest_linkrevs: ManifestLinkrevs,
+    ) -> Result<FileNodes, E> {
+        self.report(VerifyMessage::Stage(VerifyStage::Manifests))?;
+        let context = self.context;
+        let manifestlog = context.manifestlog;
+        let revlog = &manifestlog.revlog;
+        let label = HgPath::new(b"manifest");
+        let mut file_nodes = FileNodes::default();
+        let mut seen = FastHashMap::default();
+        let mut problems = Problems::default();
+        if self.refers_manifest {
+            // Only changesets with null manifests do not need a manifestlog
+            context.check_revlog(&mut problems, revlog, label);
+        }
+        for rev in revisions(revlog) {
+            let node = *revlog.node_from_rev(rev);
+            let linkrevs = manifest_linkrevs.remove(&node);
+            let linkrev = context.check_entry(
+                &mut problems,
+                revlog,
+                rev,
+                &mut seen,
+                linkrevs.as_deref().unwrap_or_default(),
+                label,
+            );
+            if linkrevs.is_none() {
+                let message = format!("{:x} not in changesets", node.short());
+                problems.error(linkrev, Some(label), message);
+            }
+            let read_delta = |file_nodes: &mut FileNodes,
+                              problems: &mut Problems|
+             -> Result<(), HgError> {
+                let delta = manifestlog.data_delta_new_entries(rev)?;
+                for entry in delta.iter() {
+                    let entry = entry?;
+                    if entry.path.is_empty() {
+                        let message = "entry without name in manifest";
+                        problems.error(linkrev, None, message);
+                        continue;
+                    }
+                    if entry.path.as_bytes() == b"/dev/null" {
+                        // Ignored in very old repositories
+                        continue;
+                    }
+                    file_nodes
+                        .entry(normalize_path(entry.path))
+                        .or_default()
+                        .entry(entry.node_id()?)
+                        .or_insert(linkrev);
+                }
+                Ok(())
+            };
+            if let Err(error) = read_delta(&mut file_nodes, &mut problems) {
+                let message = format!(
+                    "reading delta {:x}: {}",
+                    node.short(),
+                    describe(error, b"00manifest")
+                );
+                problems.error(linkrev, Some(label), message);
+            }
+            if context.options.full
+                && let Err(error) = manifestlog.data(rev)
+            {
+                let message = format!(
+                    "reading full manifest {:x}: {}",
+                    node.short(),
+                    describe(error.into(), b"00manifest")
+                );
+                problems.error(linkrev, Some(label), message);
+            }
+            self.report_all(&mut problems)?;
+        }
+        if context.has_manifest {
+            let mut missing: Vec<_> = manifest_linkrevs
+                .iter()
+                .flat_map(|(node, revs)| revs.iter().map(move |r| (*r, node)))
+                .collect();
+            missing.sort_unstable_by_key(|(rev, node)| (*rev, node.as_bytes()));
+            for (rev, node) in missing {
+                let message = format!(
+                    "changeset refers to unknown revision {:x}",
+                    node.short()
+                );
+                problems.error(Some(rev.0), Some(label), message);
+            }
+        }
+        self.report_all(&mut problems)?;
+        Ok(file_nodes)
+    }
+
+    /// Checks that changesets and manifests agree on the files touched
+    fn crosscheck_files(
+        &mut self,
+        file_linkrevs: &FileLinkrevs,
+        file_nodes: &FileNodes,
+    ) -> Result<(), E> {
+        self.report(VerifyMessage::Stage(VerifyStage::CrosscheckFiles))?;
+        let context = self.context;
+        let mut problems = Problems::default();
+        if context.has_manifest {
+            for path in sorted_keys(file_linkrevs) {
+                if !file_nodes.contains_key(path) {
+                    let linkrev = file_linkrevs[path][0].0;
+                    let message = "in changeset but not in manifest";
+                    problems.error(Some(linkrev), Some(path), message);
+                }
+            }
+        }
+        if context.has_changelog {
+            for path in sorted_keys(file_nodes) {
+                if !file_linkrevs.contains_key(path) {
+                    let linkrev =
+                        context.first_linkrev(path, &file_nodes[path]);
+                    let message = "in manifest but not in changeset";
+                    problems.error(linkrev, Some(path), message);
+                }
+            }
+        }
+        self.report_all(&mut problems)
+    }
+
+    /// Checks every filelog in parallel, and that the store lists them
+    fn verify_files(
+        &mut self,
+        file_linkrevs: FileLinkrevs,
+        mut file_nodes: FileNodes,
+    ) -> Result<(), E> {
+        self.report(VerifyMessage::Stage(VerifyStage::Files))?;
+        let context = self.context;
+        let mut store_files = StoreFiles::read(context.repo)?;
+
+        let mut paths: Vec<_> = file_linkrevs
+            .keys()
+            .chain(
+                file_nodes.keys().filter(|p| !file_linkrevs.contains_key(*p)),
+            )
+            .cloned()
+            .collect();
+        paths.sort_unstable();
+        let files = paths.len();
+        let reports: Vec<_> = paths
+            .into_iter()
+            .map(|path| {
+                let nodes = file_nodes.remove(&path);
+                (path, nodes)
+            })
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(path, nodes)| {
+                let linkrevs =
+                    file_linkrevs.get(&path).map(Vec::as_slice).unwrap_or(&[]);
+                context.check_file(&path, linkrevs, nodes, &store_files)
+            })
+            .collect();
+
+        for mut report in reports {
+            self.summary.file_revisions += report.revisions;
+            self.summary.fncache_warned |= report.fncache_warned;
+            for file in &report.store_files {
+                store_files.remove(file);
+            }
+            self.report_all(&mut report.problems)?;
+        }
+        for warning in store_files.orphan_warnings() {
+            self.report(VerifyMessage::Warning(warning))?;
+        }
+        self.summary.files = files;
+        Ok(())
+    }
+}
+
+/// The result of checking a filelog
+#[derive(Default)]
+struct FileReport {
+    problems: Problems,
+    /// The number of revisions of the filelog
+    revisions: usize,
+    /// The entries of the store used by this filelog
+    store_files: Vec<HgPathBuf>,
+    fncache_warned: bool,
+}
+
+impl Context<'_> {
+    /// Checks that a changelog or manifestlog exists and has the expected
+    /// size
+    fn check_revlog(
+        &self,
+        problems: &mut Problems,
+        revlog: &Revlog,
+        label: &HgPath,
+    ) {
+        if revlog.is_empty() && (self.has_changelog || self.has_manifest) {
+            let message =
+                format_bytes!(b"empty or missing {}", label.as_bytes());
+            problems.error(Some(0), None, message);
+            return;
+        }
+        let (data_extra, index_extra) = revlog.check_size();
+        if data_extra != 0 {
+            let message = format!("data length off by {} bytes", data_extra);
+            problems.error(None, Some(label), message);
+        }
+        if index_extra != 0 {
+            let message = format!("index contains {} extra bytes", index_extra);
+            problems.error(None, Some(label), message);
+        }
+    }
+
+    /// Checks the linkrev and parents of a revision, and that it is not a
+    /// duplicate. `linkrevs` are the changesets that introduce the revision,
+    /// in increasing order.
+    ///
+    /// Returns the linkrev, unless it cannot be trusted.
+    fn check_entry(
+        &self,
+        problems: &mut Problems,
+        revlog: &Revlog,
+        rev: Revision,
+        seen: &mut FastHashMap<Node, Revision>,
+        linkrevs: &[Revision],
+        label: &HgPath,
+    ) -> Option<BaseRevision> {
+        let node = *revlog.node_from_rev(rev);
+        let Ok(checked) = check_link_revision(
+            revlog,
+            rev,
+            &self.changelog.revlog,
+            |linkrev| {
+                Ok::<_, Infallible>(linkrevs.binary_search(&linkrev).is_ok())
+            },
+        );
+        let (linkrev, kind) = match checked {
+            LinkRevision::Expected(linkrev) => (linkrev.0, None),
+            // Not a changeset, even if a valid revision
+            LinkRevision::Unexpected(NULL_REVISION) => {
+                (NULL_REVISION.0, Some("nonexistent"))
+            }
+            LinkRevision::Unexpected(linkrev) => {
+                (linkrev.0, Some("unexpected"))
+            }
+            LinkRevision::Nonexistent(linkrev) => {
+                (linkrev.0, Some("nonexistent"))
+            }
+        };
+        let mut trusted_linkrev = Some(linkrev);
+        // Without a changelog, only negative linkrevs are known to be wrong
+        if let Some(kind) = kind.filter(|_| linkrev < 0 || self.has_changelog) {
+            let message =
+                format!("rev {} points to {} changeset {}", rev, kind, linkrev);
+            problems.error(None, Some(label), message);
+            if !linkrevs.is_empty() {
+                let linkrevs = if linkrevs.len() > 1 {
+                    self.introducing_changesets(linkrevs, label, &node)
+                } else {
+                    linkrevs.to_vec()
+                };
+                let linkrevs: Vec<_> =
+                    linkrevs.iter().map(ToString::to_string).collect();
+                problems.warning(format!(" (expected {})", linkrevs.join(" ")));
+            }
+            trusted_linkrev = None;
+        }
+
+        match revlog.parents(rev) {
+            Ok(parents) => {
+                for (index, parent) in parents.into_iter().enumerate() {
+                    if parent != NULL_REVISION && parent >= rev {
+                        let message = format!(
+                            "unknown parent {} {:x} of {:x}",
+                            index + 1,
+                            revlog.node_from_rev(parent).short(),
+                            node.short()
+                        );
+                        problems.error(trusted_linkrev, Some(label), message);
+                    }
+                }
+            }
+            Err(error) => {
+                let message = format!(
+                    "checking parents of {:x}: {}",
+                    node.short(),
+                    error
+                );
+                problems.error(trusted_linkrev, Some(label), message);
+            }
+        }
+
+        if let Some(previous) = seen.insert(node, rev) {
+            let message = format!("duplicate revision {} ({})", rev, previous);
+            problems.error(trusted_linkrev, Some(label), message);
+        }
+        trusted_linkrev
+    }
+
+    /// The changesets of `linkrevs` where `path` has the given node, up to
+    /// the first one that does not have the file
+    fn introducing_changesets(
+        &self,
+        linkrevs: &[Revision],
+        path: &HgPath,
+        node: &Node,
+    ) -> Vec<Revision> {
+        let mut introducing = vec![];
+        for &linkrev in linkrevs {
+            match self.file_node(linkrev, path) {
+                Ok(Some(file_node)) if file_node == *node => {
+                    introducing.push(linkrev)
+                }
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+        introducing
+    }
+
+    /// The node of `path` in the manifest of a changeset
+    fn file_node(
+        &self,
+        rev: Revision,
+        path: &HgPath,
+    ) -> Result<Option<Node>, HgError> {
+        let manifest = self.manifest(rev)?;
+        match manifest.find_by_path(path)? {
+            Some(entry) => Ok(Some(entry.node_id()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn manifest(&self, rev: Revision) -> Result<Manifest, HgError> {
+        if rev == NULL_REVISION {
+            return Ok(Manifest::empty());
+        }
+        let manifest_node =
+            self.changelog.entry(rev)?.data()?.manifest_node()?;
+        Ok(self.manifestlog.data_for_node(manifest_node.into())?)
+    }
+
+    /// The lowest linkrev of the given file nodes, if they all exist
+    fn first_linkrev(
+        &self,
+        path: &HgPath,
+        nodes: &FastHashMap<Node, Option<BaseRevision>>,
+    ) -> Option<BaseRevision> {
+        let filelog = self.repo.filelog(path).ok()?;
+        let mut linkrevs = vec![];
+        for node in nodes.keys() {
+            let rev = filelog.revlog.rev_from_node((*node).into()).ok()?;
+            linkrevs.push(filelog.revlog.unchecked_link_revision(rev).0);
+        }
+        linkrevs.into_iter().min()
+    }
+
+    /// Checks a filelog: its integrity, the linkrev and parents of its
+    /// revisions, copy sources, and that it matches the manifests and the
+    /// store listing
+    fn check_file(
+        &self,
+        path: &HgPath,
+        linkrevs: &[Revision],
+        mut nodes: Option<FastHashMap<Node, Option<BaseRevision>>>,
+        store_files: &StoreFiles,
+    ) -> FileReport {
+        let mut report = FileReport::default();
+        let problems = &mut report.problems;
+        let linkrev = linkrevs.first().map(|rev| rev.0);
+        let filelog = match self.repo.filelog(path) {
+            Ok(filelog) => filelog,
+            Err(error) => {
+                let explanation = match HgError::from(error) {
+                    HgError::CorruptedRepository(..)
+                    | HgError::Revlog(
+                        RevlogError::InvalidInlineRevlogLength { .. },
+                    ) => format!(
+                        "index {} is corrupted",
+                        String::from_utf8_lossy(path.as_bytes())
+                    ),
+                    error => error.to_string(),
+                };
+                let message = format!("broken revlog! ({})", explanation);
+                problems.error(linkrev, Some(path), message);
+                return report;
+            }
+        };
+        let revlog = &filelog.revlog;
+
+        match store_files {
+            StoreFiles::FileIndex(paths) => {
+                if !paths.contains(path) {
+                    problems.warning(format_bytes!(
+                        b" warning: revlog '{}' not in file index!",
+                        path.as_bytes()
+                    ));
+                }
+                report.store_files.push(path.to_owned());
+            }
+            StoreFiles::Fncache(files) => {
+                for file in revlog_files(path, revlog.is_inline()) {
+                    if !files.contains(&file) {
+                        problems.warning(format_bytes!(
+                            b" warning: revlog '{}' not in fncache!",
+                            file.as_bytes()
+                        ));
+                        report.fncache_warned = true;
+                    }
+                    report.store_files.push(file);
+                }
+            }
+        }
+
+        let mut skip_read = FastHashSet::default();
+        if revlog.is_empty() && (self.has_changelog || self.has_manifest) {
+            let message =
+                format_bytes!(b"empty or missing {}", path.as_bytes());
+            problems.error(linkrev, None, message);
+        } else {
+            self.check_integrity(
+                problems,
+                revlog,
+                path,
+                linkrev,
+                &mut skip_read,
+            );
+        }
+
+        let mut seen = FastHashMap::default();
+        for rev in revisions(revlog) {
+            report.revisions += 1;
+            let node = *revlog.node_from_rev(rev);
+            let linkrev = self
+                .check_entry(problems, revlog, rev, &mut seen, linkrevs, path);
+            if let Some(nodes) = &mut nodes
+                && nodes.remove(&node).is_none()
+                && self.has_manifest
+            {
+                let message = format!("{:x} not in manifests", node.short());
+                problems.error(linkrev, Some(path), message);
+            }
+            if skip_read.contains(&rev) {
+                continue;
+            }
+            if let Err(error) =
+                self.check_rename(problems, &filelog, rev, path, linkrev)
+            {
+                let message =
+                    format!("checking rename of {:x}: {}", node.short(), error);
+                problems.error(linkrev, Some(path), message);
+            }
+        }
+
+        if let Some(nodes) = nodes {
+            let mut unknown: Vec<_> = nodes
+                .into_iter()
+                .map(|(node, linkrev)| (linkrev, node))
+                .collect();
+            unknown.sort_unstable_by(|(rev1, node1), (rev2, node2)| {
+                (rev1, node1.as_bytes()).cmp(&(rev2, node2.as_bytes()))
+            });
+            for (linkrev, node) in unknown {
+                let message = format!(
+                    "manifest refers to unknown revision {:x}",
+                    node.short()
+                );
+                problems.error(linkrev, Some(path), message);
+            }
+        }
+        report
+    }
+
+    /// Checks the size of the files of a filelog, and the hash and size of
+    /// every revision, like Python's `revlog.verifyintegrity`.
+    ///
+    /// Revisions that cannot be read are added to `skip_read`.
+    fn check_integrity(
+        &self,
+        problems: &mut Problems,
+        revlog: &Revlog,
+        path: &HgPath,
+        linkrev: Option<BaseRevision>,
+        skip_read: &mut FastHashSet<Revision>,
+    ) {
+        let (data_extra, index_extra) = revlog.check_size();
+        if data_extra != 0 {
+            let message = format!("data length off by {} bytes", data_extra);
+            problems.error(linkrev, Some(path), message);
+        }
+        if index_extra != 0 {
+            let message = format!("index contains {} extra bytes", index_extra);
+            problems.error(linkrev, Some(path), message);
+        }
+
+        for rev in revisions(revlog) {
+            let node = revlog.node_from_rev(rev);
+            let linkrev = Some(revlog.unchecked_link_revision(rev).0);
+            let unpacking_error = |problems: &mut Problems, error: String| {
+                let message =
+                    format!("unpacking {:x}: {}", node.short(), error);
+                problems.error(linkrev, Some(path), message);
+            };
+            let entry = match revlog.get_entry(rev) {
+                Ok(entry) => entry,
+                Err(error) => {
+                    unpacking_error(problems, describe(error.into(), b""));
+                    skip_read.insert(rev);
+                    continue;
+                }
+            };
+            if entry.is_censored() {
+                if self.options.error_on_censored {
+                    problems.error(linkrev, Some(path), "censored file data");
+                }
+                skip_read.insert(rev);
+                continue;
+            }
+            let skip_hash =
+                self.options.skip_flags & u32::from(entry.flags()) != 0;
+            if skip_hash {
+                skip_read.insert(rev);
+            }
+            let data = match entry.data_unchecked() {
+                Ok(data) => data,
+                Err(error) => {
+                    unpacking_error(problems, describe(error.into(), b""));
+                    skip_read.insert(rev);
+                    continue;
+                }
+            };
+            let p1 = entry.p1().unwrap_or(NULL_REVISION);
+            let p2 = entry.p2().unwrap_or(NULL_REVISION);
+            if !skip_hash && !revlog.check_hash(p1, p2, node.as_bytes(), &data)
+            {
+                let error = format_bytes!(
+                    b"integrity check failed on {}:{}",
+                    path.as_bytes(),
+                    rev.0
+                );
+                unpacking_error(
+                    problems,
+                    String::from_utf8_lossy(&error).into(),
+                );
+                skip_read.insert(rev);
+                continue;
+            }
+            if let Some(expected) = entry.uncompressed_len()
+                && expected as usize != data.len()
+            {
+                let message = format!(
+                    "unpacked size is {}, {} expected",
+                    data.len(),
+                    expected
+                );
+                problems.error(linkrev, Some(path), message);
+            }
+        }
+    }
+
+    /// Checks that the copy source of a revision exists
+    fn check_rename(
+        &self,
+        problems: &mut Problems,
+        filelog: &Filelog,
+        rev: Revision,
+        path: &HgPath,
+        linkrev: Option<BaseRevision>,
+    ) -> Result<(), HgError> {
+        let entry = filelog.entry(rev)?;
+        if !entry.maybe_has_metadata() {
+            return Ok(());
+        }
+        let data = entry.data()?;
+        let metadata = data.metadata()?.parse()?;
+        let (Some(source), Some(source_node)) =
+            (metadata.copy, metadata.copyrev)
+        else {
+            return Ok(());
+        };
+        if self.options.verbose
+            && let Some(linkrev) = linkrev
+        {
+            let changeset = Revision(linkrev);
+            let mut parents = self
+                .changelog
+                .parents(changeset)
+                .map_err(RevlogError::from)?
+                .to_vec();
+            // The null second parent is not a parent
+            if parents[1] == NULL_REVISION {
+                parents.pop();
+            }
+            let mut in_parents = false;
+            for parent in parents {
+                in_parents |=
+                    self.manifest(parent)?.find_by_path(source)?.is_some();
+            }
+            if !in_parents {
+                let changeset_node = self.changelog.node_from_rev(changeset);
+                problems.warning(format_bytes!(
+                    b"warning: copy source of '{}' not in parents of {}",
+                    path.as_bytes(),
+                    format!("{:x}", changeset_node.short()).into_bytes()
+                ));
+            }
+        }
+        let source_filelog = self.repo.filelog(source)?;
+        if source_filelog.revlog.is_empty() {
+            let message = format_bytes!(
+                b"empty or missing copy source revlog {}:{}",
+                source.as_bytes(),
+                format!("{:x}", source_node.short()).into_bytes()
+            );
+            problems.error(linkrev, Some(path), message);
+        } else if source_node == NULL_NODE {
+            let linkrev = match linkrev {
+                Some(linkrev) => linkrev.to_string(),
+                None => "None".to_string(),
+            };
+            problems.0.push(VerifyMessage::Note(format_bytes!(
+                b"warning: {}@{}: copy source revision is nullid {}:{}",
+                path.as_bytes(),
+                linkrev.into_bytes(),
+                source.as_bytes(),
+                format!("{:x}", source_node.short()).into_bytes()
+            )));
+        } else if source_filelog
+            .revlog
+            .rev_from_node(source_node.into())
+            .is_err()
+        {
+            return Err(HgError::corrupted(format!(
+                "{}@{:x}: no node",
+                source, source_node
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The filelogs listed by the store, to find unlisted and orphan filelogs
+enum StoreFiles {
+    /// The tracked files of the file index
+    FileIndex(FastHashSet<HgPathBuf>),
+    /// The files of filelogs listed in the fncache
+    Fncache(FastHashSet<HgPathBuf>),
+}
+
+impl StoreFiles {
+    fn read(repo: &Repo) -> Result<Self, HgError> {
+        let store_vfs = repo.store_vfs();
+        let filelogs = repo
+            .store()
+            .walk()?
+            .into_iter()
+            .filter(|revlog| revlog.revlog_type == RevlogType::Filelog);
+        if repo.has_fileindex() {
+            Ok(Self::FileIndex(filelogs.map(|revlog| revlog.target).collect()))
+        } else {
+            let files = filelogs
+                .flat_map(|revlog| revlog.files(&store_vfs))
+                .map(|file| normalize_path(&file))
+                .collect();
+            Ok(Self::Fncache(files))
+        }
+    }
+
+    fn remove(&mut self, file: &HgPath) {
+        match self {
+            StoreFiles::FileIndex(files) | StoreFiles::Fncache(files) => {
+                files.remove(file)
+            }
+        };
+    }
+
+    /// The warnings for the files that no filelog uses
+    fn orphan_warnings(&self) -> Vec<Vec<u8>> {
+        let (files, template): (_, &[u8]) = match self {
+            StoreFiles::FileIndex(files) => {
+                (files, b"warning: orphan file index entry '")
+            }
+            StoreFiles::Fncache(files) => {
+                (files, b"warning: orphan data file '")
+            }
+        };
+        let mut files: Vec<_> = files.iter().collect();
+        files.sort_unstable();
+        files
+            .into_iter()
+            .map(|file| [template, file.as_bytes(), b"'"].concat())
+            .collect()
+    }
+}
+
+/// Checks that the dirstate matches the manifests of its parents, like
+/// Python's `dirstate.verify`. Returns the problems found.
+fn verify_dirstate(repo: &Repo) -> Result<Vec<Vec<u8>>, HgError> {
+    let parents = repo.dirstate_parents()?;
+    let manifest = |node: Node| -> Result<Manifest, HgError> {
+        if node == NULL_NODE {
+            Ok(Manifest::empty())
+        } else {
+            repo.manifest_for_node(node)
+        }
+    };
+    let manifest1 = manifest(parents.p1)?;
+    let manifest2 = manifest(parents.p2)?;
+    let p1 = format!("{:x}", parents.p1.short()).into_bytes();
+    let map = repo.dirstate_map()?;
+    let mut problems = vec![];
+    for item in map.iter() {
+        let (path, entry) = item.map_err(DirstateError::from)?;
+        let path = path.as_bytes();
+        let in_manifest1 = manifest1.find_by_path(HgPath::new(path))?.is_some();
+        if entry.p1_tracked() {
+            if entry.modified()
+                && !in_manifest1
+                && manifest2.find_by_path(HgPath::new(path))?.is_none()
+            {
+                problems.push(format_bytes!(
+                    b"{} marked as modified, but not in either manifest",
+                    path
+                ));
+            } else if !in_manifest1 {
+                problems.push(format_bytes!(
+                    b"{} marked as tracked in p1 ({}) but not in manifest1",
+                    path,
+                    p1
+                ));
+            }
+        }
+        if entry.added() && in_manifest1 {
+            problems.push(format_bytes!(
+                b"{} marked as added, but also in manifest1",
+                path
+            ));
+        }
+    }
+    for entry in manifest1.iter() {
+        let path = entry?.path;
+        let p1_tracked = map.get(path)?.is_some_and(|entry| entry.p1_tracked());
+        if !p1_tracked {
+            problems.push(format_bytes!(
+                b"{} in manifest1, but not marked as tracked in p1 ({})",
+                path.as_bytes(),
+                p1
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// The revisions of a revlog, in order
+fn revisions(revlog: &Revlog) -> impl Iterator<Item = Revision> + use<> {
+    (0..revlog.len() as BaseRevision).map(Revision)
+}
+
+fn sorted_keys<V>(map: &FastHashMap<HgPathBuf, V>) -> Vec<&HgPathBuf> {
+    let mut keys: Vec<_> = map.keys().collect();
+    keys.sort_unstable();
+    keys
+}
+
+/// The unencoded paths of the files of a filelog, like Python's
+/// `revlog.files`
+fn revlog_files(path: &HgPath, inline: bool) -> Vec<HgPathBuf> {
+    let radix = [b"data/", path.as_bytes()].concat();
+    let mut files = vec![HgPathBuf::from_bytes(&[&radix, &b".i"[..]].concat())];
+    if !inline {
+        files.push(HgPathBuf::from_bytes(&[&radix, &b".d"[..]].concat()));
+    }
+    files
+}
+
+/// Collapses repeated slashes, which converted repositories from
+/// Mercurial < 2.4 may have
+fn normalize_path(path: &HgPath) -> HgPathBuf {
+    let mut normalized = path.as_bytes().to_vec();
+    normalized.dedup_by(|a, b| *a == b'/' && *b == b'/');
+    HgPathBuf::from_bytes(&normalized)
+}
+
+/// Describes an error like Python, which names the revlog when a hash
+/// does not match
+fn describe(error: HgError, display_id: &[u8]) -> String {
+    match error {
+        HgError::Revlog(RevlogError::HashCheckFailed { rev, .. }) => {
+            format!(
+                "integrity check failed on {}:{}",
+                String::from_utf8_lossy(display_id),
+                rev
+            )
+        }
+        HgError::CorruptedRepository(explanation, _) => explanation,
+        error => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NULL_NODE;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path(HgPath::new(b"a//b///c/d")),
+            HgPathBuf::from_bytes(b"a/b/c/d")
+        );
+        assert_eq!(
+            normalize_path(HgPath::new(b"a/b")),
+            HgPathBuf::from_bytes(b"a/b")
+        );
+    }
+
+    #[test]
+    fn test_format_error() {
+        let error = VerifyError {
+            linkrev: Some(3),
+            label: Some(HgPathBuf::from_bytes(b"dir/a")),
+            message: b"broken".to_vec(),
+        };
+        assert_eq!(error.format(), b" dir/a@3: broken");
+        let error = VerifyError {
+            linkrev: None,
+            label: None,
+            message: b"empty or missing a".to_vec(),
+        };
+        assert_eq!(error.format(), b" ?: empty or missing a");
+    }
+
+    /// The errors reported by `verify` on the repository of `test_repo`
+    fn verify_errors(test_repo: &TestRepo) -> Vec<VerifyError> {
+        let mut errors = vec![];
+        verify(&test_repo.repo, &VerifyOptions::default(), |message| {
+            if let VerifyMessage::Error(error) = message {
+                errors.push(error);
+            }
+            Ok::<_, HgError>(())
+        })
+        .unwrap();
+        errors
+    }
+
+    #[test]
+    fn test_verify_linkrevs() {
+        let mut test_repo = TestRepo::new(&[]);
+        let first = test_repo.commit(NULL_NODE, &[("a", Some("a"))]);
+        test_repo.commit(first, &[("b", Some("b"))]);
+        assert_eq!(verify_errors(&test_repo), vec![]);
+
+        let set_linkrev = |linkrev: u32| {
+            // The filelog is inline, and its first entry starts the file
+            let path = test_repo.repo.store_path().join("data/a.i");
+            let mut data = std::fs::read(&path).unwrap();
+            data[20..24].copy_from_slice(&linkrev.to_be_bytes());
+            std::fs::write(&path, data).unwrap();
+        };
+        // Like Python, the bad linkrev is not blamed on any changeset
+        let error = |message: &str| VerifyError {
+            linkrev: None,
+            label: Some(HgPathBuf::from_bytes(b"a")),
+            message: message.as_bytes().to_vec(),
+        };
+
+        set_linkrev(1);
+        assert_eq!(
+            verify_errors(&test_repo),
+            vec![error("rev 0 points to unexpected changeset 1")]
+        );
+        set_linkrev(7);
+        assert_eq!(
+            verify_errors(&test_repo),
+            vec![error("rev 0 points to nonexistent changeset 7")]
+        );
+    }
+
+    #[test]
+    fn test_revlog_files() {
+        let path = HgPath::new(b"a.txt");
+        assert_eq!(
+            revlog_files(path, true),
+            vec![HgPathBuf::from_bytes(b"data/a.txt.i")]
+        );
+        assert_eq!(
+            revlog_files(path, false),
+            vec![
+                HgPathBuf::from_bytes(b"data/a.txt.i"),
+                HgPathBuf::from_bytes(b"data/a.txt.d")
+            ]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::io::Read;
+
+use super::GetBundleArgs;
+use super::Peer;
+use crate::Node;
+use crate::errors::HgError;
+use crate::errors::HgIoError;
+use crate::errors::IoErrorContext;
+use crate::errors::IoResultExt;
+use crate::exit_codes;
+use crate::repo::Repo;
+use crate::wireprotocol::Keys;
+use crate::wireprotocol::Server;
+use crate::wireprotocol::Transport;
+use crate::wireprotocol::check_supported;
+
+/// A repository on the local filesystem, answering requests without a
+/// transport like `localpeer` in Python
+pub struct LocalPeer<'a> {
+    repo: &'a Repo,
+    server: Server<'a>,
+    url: String,
+    capabilities: Vec<Vec<u8>>,
+}
+
+impl<'a> LocalPeer<'a> {
+    pub fn new(repo: &'a Repo) -> Result<Self, HgError> {
+        check_supported(repo)?;
+        let root = repo.working_directory_path();
+        let url = format!(
+            "file:{}",
+            std::fs::canonicalize(root).when_reading_file(root)?.display()
+        );
+        let server = Server::new(repo, Transport::Local);
+        let capabilities = server.capability_list()?;
+        Ok(Self { repo, server, url, capabilities })
+    }
+}
+
+impl Peer for LocalPeer<'_> {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn capabilities(&self) -> &[Vec<u8>] {
+        &self.capabilities
+    }
+
+    fn heads(&mut self) -> Result<Vec<Node>, HgError> {
+        self.server.head_nodes()
+    }
+
+    fn known(&mut self, nodes: &[Node]) -> Result<Vec<bool>, HgError> {
+        self.server.known_nodes(nodes)
+    }
+
+    fn list_keys(&mut self, namespace: &[u8]) -> Result<Keys, HgError> {
+        self.server.list_keys(namespace)
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Result<Node, HgError> {
+        self.server.lookup_symbol(key)?.map_err(|message| {
+            let message = String::from_utf8_lossy(&message);
+            HgError::abort(message, exit_codes::INPUT_ERROR, None)
+        })
+    }
+
+    fn get_bundle(
+        &mut self,
+        args: &GetBundleArgs,
+        read: &mut dyn FnMut(&mut dyn Read) -> Result<(), HgError>,
+    ) -> Result<(), HgError> {
+        let bundle = self.server.get_bundle(args.to_args())?;
+        let repo = self.repo;
+        std::thread::scope(|scope| {
+            let (mut reader, mut writer) =
+                std::io::pipe().map_err(|error| {
+                    HgError::from(HgIoError::from_os_error(
+                        error,
+                        IoErrorContext::WritingStream,
+                    ))
+                })?;
+            let writing = scope.spawn(move || bundle.write(repo, &mut writer));
+            let read = read(&mut reader);
+            // Unblocks the writer if reading stopped early
+            drop(reader);
+            let written = writing.join().expect("bundle writer panicked");
+            read?;
+            written
+        })
+    }
+
+    fn take_messages(&mut self) -> Vec<Vec<u8>> {
+        let messages = self.server.take_messages().concat();
+        messages
+            .split_inclusive(|&byte| byte == b'\n')
+            .map(|line| line.strip_suffix(b"\n").unwrap_or(line).to_vec())
+            .collect()
+    }
+
+    fn local(&self) -> Option<&Repo> {
+        Some(self.repo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NULL_NODE;
+    use crate::bundle::Bundle;
+    use crate::bundle::caps::Role;
+    use crate::bundle::caps::encode_caps;
+    use crate::bundle::caps::repo_caps;
+    use crate::bundle::parts::changegroup_version;
+    use crate::bundle::read_bundle;
+    use crate::discovery::find_common_incoming;
+    use crate::testing::TestRepo;
+    use crate::utils::strings::url_quote;
+
+    /// Pulls from `peer` into `repo` like `rhg pull`, returning whether
+    /// there was anything to pull
+    fn pull(repo: &TestRepo, peer: &mut LocalPeer) -> bool {
+        let incoming =
+            find_common_incoming(&repo.repo, peer, &[], false, || {
+                Ok::<_, HgError>(())
+            })
+            .unwrap();
+        if !incoming.any_incoming {
+            return false;
+        }
+        let caps = encode_caps(&repo_caps(&repo.repo, Role::Client).unwrap());
+        let args = GetBundleArgs {
+            heads: incoming.remote_heads,
+            common: incoming.common,
+            bundle_caps: vec![
+                b"HG20".to_vec(),
+                [&b"bundle2="[..], &url_quote(&caps)].concat(),
+            ],
+            changegroup: true,
+            phases: false,
+        };
+        peer.get_bundle(&args, &mut |reader| {
+            let Bundle::Bundle2(mut bundle) = read_bundle(reader, "test")?
+            else {
+                panic!("not a bundle2");
+            };
+            while let Some(mut part) = bundle.next_part()? {
+                if part.header.part_type == "changegroup" {
+                    let version = changegroup_version(&part.header)?;
+                    repo.apply(&part.read_payload()?, version);
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        true
+    }
+
+    fn heads(repo: &Repo) -> Vec<Node> {
+        let mut heads = LocalPeer::new(repo).unwrap().heads().unwrap();
+        heads.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        heads
+    }
+
+    #[test]
+    fn test_pull() {
+        let mut source = TestRepo::new(&[]);
+        let mut destination = TestRepo::new(&[]);
+        let first = source.commit(NULL_NODE, &[("a", Some("0"))]);
+        let second = source.commit(first, &[("b", Some("1"))]);
+        // Same changesets on both sides, the nodes being deterministic
+        assert_eq!(destination.commit(NULL_NODE, &[("a", Some("0"))]), first);
+        assert_eq!(destination.commit(first, &[("b", Some("1"))]), second);
+        let third = source.commit(second, &[("a", Some("2"))]);
+        let fourth = source.commit(third, &[("c", Some("3"))]);
+        let fifth = source.commit(first, &[("d", Some("4"))]);
+        // Unknown to the source, so discovery has to sample
+        let local = destination.commit(second, &[("e", Some("5"))]);
+
+        let mut peer = LocalPeer::new(&source.repo).unwrap();
+        let incoming = find_common_incoming(
+            &destination.repo,
+            &mut peer,
+            &[],
+            false,
+            || Ok::<_, HgError>(()),
+        )
+        .unwrap();
+        assert_eq!(incoming.common, vec![second]);
+        assert!(incoming.any_incoming);
+        assert!(pull(&destination, &mut peer));
+        let changelog = destination.repo.changelog().unwrap();
+        for node in [third, fourth, fifth] {
+            assert!(changelog.rev_from_node(node.into()).is_ok());
+        }
+        drop(changelog);
+        let mut expected = vec![fourth, fifth, local];
+        expected.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(heads(&destination.repo), expected);
+
+        // Nothing left to pull
+        assert!(!pull(&destination, &mut peer));
+    }
+}
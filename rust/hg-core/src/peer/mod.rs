@@ -0,0 +1,146 @@
+//! The repositories that changesets are pulled from, whether they are local
+//! or reached through version 1 of the wire protocol
+//!
+//! This is a Rust counterpart to the `peer` interface of Python, covering
+//! the commands needed to pull.
+
+mod local;
+mod ssh;
+
+use std::io::Read;
+
+pub use local::LocalPeer;
+pub use ssh::SshPeer;
+
+use crate::Node;
+use crate::bundle::caps::Capabilities;
+use crate::bundle::caps::decode_caps;
+use crate::errors::HgError;
+use crate::repo::Repo;
+use crate::utils::strings::url_unquote;
+use crate::wireprotocol::Args;
+use crate::wireprotocol::Keys;
+use crate::wireprotocol::encode_list;
+
+/// A repository to pull from
+pub trait Peer {
+    /// The location of the repository, like `peer.url()` in Python
+    fn url(&self) -> &str;
+
+    /// The capabilities of the repository, like `capabilities` in Python
+    fn capabilities(&self) -> &[Vec<u8>];
+
+    /// The heads of the repository, null if it is empty
+    fn heads(&mut self) -> Result<Vec<Node>, HgError>;
+
+    /// Whether the repository has each of `nodes`
+    fn known(&mut self, nodes: &[Node]) -> Result<Vec<bool>, HgError>;
+
+    /// The keys of the pushkey `namespace`
+    fn list_keys(&mut self, namespace: &[u8]) -> Result<Keys, HgError>;
+
+    /// Resolves `key` to a changeset of the repository
+    fn lookup(&mut self, key: &[u8]) -> Result<Node, HgError>;
+
+    /// Requests a bundle, which is passed to `read` as it arrives
+    fn get_bundle(
+        &mut self,
+        args: &GetBundleArgs,
+        read: &mut dyn FnMut(&mut dyn Read) -> Result<(), HgError>,
+    ) -> Result<(), HgError>;
+
+    /// Takes the lines that the repository wrote for the user since last
+    /// called, like the `remote: ` output of Python
+    fn take_messages(&mut self) -> Vec<Vec<u8>>;
+
+    /// The repository, if it is local
+    fn local(&self) -> Option<&Repo> {
+        None
+    }
+
+    /// Ends the exchange, after which only [`Peer::take_messages`] may be
+    /// called
+    fn close(&mut self) -> Result<(), HgError> {
+        Ok(())
+    }
+
+    /// The value of the capability `name`, empty if it has none, like
+    /// `capable` in Python
+    fn capable(&self, name: &str) -> Option<&[u8]> {
+        self.capabilities().iter().find_map(|cap| {
+            let rest = cap.strip_prefix(name.as_bytes())?;
+            match rest {
+                [] => Some(rest),
+                [b'=', value @ ..] => Some(value),
+                _ => None,
+            }
+        })
+    }
+
+    /// The bundle2 capabilities of the repository, like
+    /// `bundle2.bundle2caps` in Python
+    fn bundle2_caps(&self) -> Capabilities {
+        match self.capable("bundle2") {
+            Some(blob) => decode_caps(&url_unquote(blob)),
+            None => Capabilities::new(),
+        }
+    }
+}
+
+/// The arguments of a `getbundle` request, encoded like
+/// `GETBUNDLE_ARGUMENTS` in Python
+#[derive(Debug, Default)]
+pub struct GetBundleArgs {
+    pub heads: Vec<Node>,
+    pub common: Vec<Node>,
+    pub bundle_caps: Vec<Vec<u8>>,
+    /// Whether to send a changegroup
+    pub changegroup: bool,
+    /// Whether to send the phases of the heads
+    pub phases: bool,
+}
+
+impl GetBundleArgs {
+    /// The arguments as sent to the `getbundle` command
+    pub fn to_args(&self) -> Args {
+        let boolean = |value: bool| if value { b"1" } else { b"0" }.to_vec();
+        let mut bundle_caps = self.bundle_caps.clone();
+        bundle_caps.sort_unstable();
+        let mut args = Args::default();
+        args.insert_other(b"bundlecaps".to_vec(), bundle_caps.join(&b','));
+        args.insert_other(b"cg".to_vec(), boolean(self.changegroup));
+        args.insert_other(b"common".to_vec(), encode_list(&self.common));
+        args.insert_other(b"heads".to_vec(), encode_list(&self.heads));
+        if self.phases {
+            args.insert_other(b"phases".to_vec(), boolean(self.phases));
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_bundle_args() {
+        let args = GetBundleArgs {
+            heads: vec![Node::from([0x12; 20])],
+            common: vec![],
+            bundle_caps: vec![b"bundle2=HG20".to_vec(), b"HG20".to_vec()],
+            changegroup: true,
+            phases: false,
+        }
+        .to_args();
+        let args: Vec<_> = args.others().collect();
+        assert_eq!(
+            args,
+            vec![
+                (&b"bundlecaps"[..], &b"HG20,bundle2=HG20"[..]),
+                (b"cg", b"1"),
+                (b"common", b""),
+                (b"heads", "12".repeat(20).as_bytes()),
+            ]
+        );
+    }
+}
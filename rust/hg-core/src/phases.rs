@@ -0,0 +1,403 @@
+//! The phases of changesets, stored as the roots of each non-public phase in
+//! the `phaseroots` file of the store.
+//!
+//! This is a Rust counterpart to the `phasecache` class of
+//! `mercurial.phases`, covering what is needed to add changesets.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use crate::FastHashSet;
+use crate::Graph;
+use crate::GraphError;
+use crate::Node;
+use crate::Revision;
+use crate::errors::HgError;
+use crate::revlog::RevlogError;
+use crate::revlog::RevlogIndex;
+use crate::revlog::changelog::Changelog;
+use crate::revlog::index::Index;
+use crate::revlog::index::Phase;
+use crate::transaction::StoreTransaction;
+use crate::transaction::Transaction;
+use crate::vfs::VfsImpl;
+
+const PHASEROOTS: &str = "phaseroots";
+/// Written for the hooks run before the end of a transaction
+const PHASEROOTS_PENDING: &str = "phaseroots.pending";
+
+/// The roots of the non-public phases of a repository
+pub struct PhaseCache {
+    /// The store, without path encoding
+    vfs: VfsImpl,
+    roots: BTreeMap<Phase, BTreeSet<Revision>>,
+    /// Whether the roots differ from the file
+    dirty: bool,
+}
+
+impl PhaseCache {
+    /// Reads the `phaseroots` file of the store of `vfs`, which does not
+    /// encode paths. Roots unknown to `changelog` are dropped, like in
+    /// Python.
+    pub fn read(vfs: VfsImpl, changelog: &Changelog) -> Result<Self, HgError> {
+        let mut roots: BTreeMap<Phase, BTreeSet<Revision>> = BTreeMap::new();
+        let mut dirty = false;
+        let data = vfs.try_read(PHASEROOTS)?.unwrap_or_default();
+        for line in data.split(|&byte| byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let corrupted = || {
+                HgError::corrupted(format!(
+                    "invalid phaseroots line: {}",
+                    String::from_utf8_lossy(line)
+                ))
+            };
+            let mut fields = line.splitn(2, |&byte| byte == b' ');
+            let phase = fields
+                .next()
+                .and_then(|phase| std::str::from_utf8(phase).ok())
+                .and_then(|phase| phase.parse::<usize>().ok())
+                .and_then(|phase| Phase::try_from(phase).ok())
+                .ok_or_else(corrupted)?;
+            let node = fields
+                .next()
+                .and_then(|node| Node::from_hex(node).ok())
+                .ok_or_else(corrupted)?;
+            match changelog.rev_from_node(node.into()) {
+                Ok(rev) => {
+                    roots.entry(phase).or_default().insert(rev);
+                }
+                Err(RevlogError::InvalidRevision { .. }) => dirty = true,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(Self { vfs, roots, dirty })
+    }
+
+    /// Whether any changeset is secret, like `hassecret` in Python
+    pub fn has_secret(&self) -> bool {
+        self.roots.get(&Phase::Secret).is_some_and(|roots| !roots.is_empty())
+    }
+
+    /// Whether any changeset is not public, like `hasnonpublicphases` in
+    /// Python
+    pub fn has_non_public(&self) -> bool {
+        self.roots.values().any(|roots| !roots.is_empty())
+    }
+
+    /// The roots of `phase`, by revision number
+    pub fn roots(&self, phase: Phase) -> impl Iterator<Item = Revision> + '_ {
+        self.roots.get(&phase).into_iter().flatten().copied()
+    }
+
+    /// The phase of every revision of `index`, by revision number
+    pub fn phases(&self, index: &Index) -> Result<Vec<Phase>, GraphError> {
+        let mut phases = vec![Phase::Public; index.len()];
+        for (phase, roots) in &self.roots {
+            for root in roots {
+                if let Some(current) = phases.get_mut(root.0 as usize) {
+                    *current = (*current).max(*phase);
+                }
+            }
+        }
+        for rev in 0..index.len() {
+            for parent in index.parents(Revision(rev as i32))? {
+                if parent.0 >= 0 {
+                    let inherited = phases[parent.0 as usize];
+                    phases[rev] = phases[rev].max(inherited);
+                }
+            }
+        }
+        Ok(phases)
+    }
+
+    /// Gives the phase `target` to the revisions of `index` from `first` on,
+    /// which were added since the cache was read, unless their parents are
+    /// in a higher phase. Like `registernew` in Python. Returns whether any
+    /// root was added.
+    pub fn register_new(
+        &mut self,
+        index: &Index,
+        target: Phase,
+        first: Revision,
+    ) -> Result<bool, GraphError> {
+        let mut changed = false;
+        if target == Phase::Public {
+            return Ok(changed);
+        }
+        let mut phases = self.phases(index)?;
+        for rev in first.0 as usize..index.len() {
+            for parent in index.parents(Revision(rev as i32))? {
+                if parent.0 >= 0 {
+                    let inherited = phases[parent.0 as usize];
+                    phases[rev] = phases[rev].max(inherited);
+                }
+            }
+            if phases[rev] < target {
+                phases[rev] = target;
+                self.roots
+                    .entry(target)
+                    .or_default()
+                    .insert(Revision(rev as i32));
+                changed = true;
+            }
+        }
+        self.dirty |= changed;
+        Ok(changed)
+    }
+
+    /// Moves `revs` and their ancestors to the phase `target` or a lower
+    /// one, like `advanceboundary` in Python. Returns whether any phase
+    /// changed.
+    pub fn advance_boundary(
+        &mut self,
+        index: &Index,
+        target: Phase,
+        revs: &[Revision],
+    ) -> Result<bool, GraphError> {
+        let phases = self.phases(index)?;
+        let mut lowered = FastHashSet::default();
+        let mut to_visit: Vec<Revision> = revs
+            .iter()
+            .copied()
+            .filter(|rev| phases[rev.0 as usize] > target)
+            .collect();
+        while let Some(rev) = to_visit.pop() {
+            if !lowered.insert(rev) {
+                continue;
+            }
+            for parent in index.parents(rev)? {
+                if parent.0 >= 0 && phases[parent.0 as usize] > target {
+                    to_visit.push(parent);
+                }
+            }
+        }
+        if lowered.is_empty() {
+            return Ok(false);
+        }
+        let mut changed = false;
+        for (phase, roots) in self.roots.iter_mut() {
+            if *phase <= target || roots.is_empty() {
+                continue;
+            }
+            // roots((roots::) - lowered)
+            let first = roots.first().expect("not empty").0 as usize;
+            let mut descendants = FastHashSet::default();
+            let mut new_roots = BTreeSet::new();
+            for rev in first..index.len() {
+                let rev = Revision(rev as i32);
+                let parents = index.parents(rev)?;
+                let is_descendant = roots.contains(&rev)
+                    || parents.iter().any(|p| descendants.contains(p));
+                if !is_descendant {
+                    continue;
+                }
+                descendants.insert(rev);
+                if lowered.contains(&rev) {
+                    continue;
+                }
+                let is_root = parents
+                    .iter()
+                    .all(|p| !descendants.contains(p) || lowered.contains(p));
+                if is_root {
+                    new_roots.insert(rev);
+                }
+            }
+            if new_roots != *roots {
+                *roots = new_roots;
+                changed = true;
+            }
+        }
+        self.dirty |= changed;
+        Ok(changed)
+    }
+
+    /// The heads of the revisions of `subset` in each phase, like
+    /// `subsetphaseheads` in Python
+    pub fn subset_phase_heads(
+        &self,
+        index: &Index,
+        subset: &[Revision],
+    ) -> Result<Vec<(Phase, Vec<Revision>)>, GraphError> {
+        let phases = self.phases(index)?;
+        let mut heads = vec![];
+        for &phase in Phase::all_phases() {
+            let revs: BTreeSet<Revision> = subset
+                .iter()
+                .copied()
+                .filter(|rev| phases[rev.0 as usize] == phase)
+                .collect();
+            let mut parents = FastHashSet::default();
+            for &rev in &revs {
+                parents.extend(index.parents(rev)?);
+            }
+            heads.push((
+                phase,
+                revs.into_iter().filter(|rev| !parents.contains(rev)).collect(),
+            ));
+        }
+        Ok(heads)
+    }
+
+    fn serialize(&self, index: &Index) -> Vec<u8> {
+        let mut contents = vec![];
+        for (phase, roots) in &self.roots {
+            for root in roots {
+                let node = index.node(*root);
+                contents.extend_from_slice(
+                    format!("{} {:x}\n", phase.number(), node).as_bytes(),
+                );
+            }
+        }
+        contents
+    }
+
+    /// Writes the roots if they changed, backing up the file in `tr`
+    pub fn write(
+        &mut self,
+        index: &Index,
+        tr: &mut StoreTransaction,
+    ) -> Result<(), HgError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        tr.add_backup(PHASEROOTS);
+        self.vfs.atomic_write(PHASEROOTS, &self.serialize(index))?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Writes the roots for the hooks run before `tr` closes, which see them
+    /// through `HG_PENDING`. Returns whether anything was written.
+    pub fn write_pending(
+        &self,
+        index: &Index,
+        tr: &mut StoreTransaction,
+    ) -> Result<bool, HgError> {
+        if !self.dirty {
+            return Ok(false);
+        }
+        tr.register_tmp(PHASEROOTS_PENDING);
+        self.vfs.atomic_write(PHASEROOTS_PENDING, &self.serialize(index))?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NULL_NODE;
+    use crate::store::Store;
+    use crate::testing::TestRepo;
+
+    use Phase::*;
+
+    /// A repository with the changesets `0 - 1 - 2` and `0 - 3`
+    fn test_repo() -> TestRepo {
+        let mut test_repo = TestRepo::new(&[]);
+        let first = test_repo.commit(NULL_NODE, &[("a", Some("0"))]);
+        let second = test_repo.commit(first, &[("a", Some("1"))]);
+        test_repo.commit(second, &[("a", Some("2"))]);
+        test_repo.commit(first, &[("a", Some("3"))]);
+        test_repo
+    }
+
+    fn revs(revs: &[i32]) -> Vec<Revision> {
+        revs.iter().copied().map(Revision).collect()
+    }
+
+    #[test]
+    fn test_register_and_advance() {
+        let test_repo = test_repo();
+        let repo = &test_repo.repo;
+        let changelog = repo.changelog().unwrap();
+        let index = changelog.get_index();
+        let mut cache = Store::new(repo).phase_cache().unwrap();
+        assert_eq!(cache.phases(index).unwrap(), [Public; 4]);
+
+        assert!(!cache.register_new(index, Public, Revision(0)).unwrap());
+        assert!(cache.register_new(index, Draft, Revision(0)).unwrap());
+        assert_eq!(cache.phases(index).unwrap(), [Draft; 4]);
+        assert_eq!(cache.roots(Draft).collect::<Vec<_>>(), revs(&[0]));
+        // Descendants of a draft are already draft
+        assert!(!cache.register_new(index, Draft, Revision(2)).unwrap());
+        assert!(cache.register_new(index, Secret, Revision(3)).unwrap());
+        assert_eq!(cache.phases(index).unwrap(), [Draft, Draft, Draft, Secret]);
+
+        assert!(cache.advance_boundary(index, Public, &revs(&[1])).unwrap());
+        assert_eq!(
+            cache.phases(index).unwrap(),
+            [Public, Public, Draft, Secret]
+        );
+        // Like in Python, the secret root stays a draft root
+        assert_eq!(cache.roots(Draft).collect::<Vec<_>>(), revs(&[2, 3]));
+        assert!(!cache.advance_boundary(index, Public, &revs(&[0])).unwrap());
+        assert!(cache.advance_boundary(index, Draft, &revs(&[3])).unwrap());
+        assert_eq!(
+            cache.phases(index).unwrap(),
+            [Public, Public, Draft, Draft]
+        );
+        assert_eq!(cache.roots(Draft).collect::<Vec<_>>(), revs(&[2, 3]));
+        assert_eq!(cache.roots(Secret).count(), 0);
+
+        let heads = cache.subset_phase_heads(index, &revs(&[0, 1, 2, 3]));
+        assert_eq!(
+            heads.unwrap()[..3],
+            [(Public, revs(&[1])), (Draft, revs(&[2, 3])), (Secret, vec![])]
+        );
+        let heads = cache.subset_phase_heads(index, &revs(&[0, 3]));
+        assert_eq!(
+            heads.unwrap()[..2],
+            [(Public, revs(&[0])), (Draft, revs(&[3]))]
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let test_repo = test_repo();
+        let repo = &test_repo.repo;
+        let store = Store::new(repo);
+        let changelog = repo.changelog().unwrap();
+        let index = changelog.get_index();
+        let mut cache = store.phase_cache().unwrap();
+        cache.register_new(index, Draft, Revision(1)).unwrap();
+        cache.register_new(index, Secret, Revision(3)).unwrap();
+
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        assert!(cache.write_pending(index, &mut tr).unwrap());
+        let phaseroots = repo.store_path().join(PHASEROOTS);
+        let pending = repo.store_path().join(PHASEROOTS_PENDING);
+        // Every new revision is given the draft phase first
+        let expected = format!(
+            "1 {:x}\n1 {:x}\n2 {:x}\n",
+            index.node(Revision(1)),
+            index.node(Revision(3)),
+            index.node(Revision(3))
+        );
+        assert_eq!(std::fs::read_to_string(&pending).unwrap(), expected);
+        assert!(!phaseroots.exists());
+        cache.write(index, &mut tr).unwrap();
+        // Nothing left to write
+        assert!(!cache.write_pending(index, &mut tr).unwrap());
+        tr.close().unwrap();
+        assert!(!pending.exists());
+        assert_eq!(std::fs::read_to_string(&phaseroots).unwrap(), expected);
+
+        let cache = store.phase_cache().unwrap();
+        assert_eq!(
+            cache.phases(index).unwrap(),
+            [Public, Draft, Draft, Secret]
+        );
+
+        // Roots unknown to the changelog are dropped
+        let unknown = format!("{}1 {:x}\n", expected, Node::from(&[1; 20]));
+        std::fs::write(&phaseroots, unknown).unwrap();
+        let mut cache = store.phase_cache().unwrap();
+        assert_eq!(cache.roots(Draft).collect::<Vec<_>>(), revs(&[1, 3]));
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        cache.write(index, &mut tr).unwrap();
+        tr.close().unwrap();
+        assert_eq!(std::fs::read_to_string(&phaseroots).unwrap(), expected);
+    }
+}
@@ -3,6 +3,7 @@ use std::io::SeekFrom;
 use std::io::Write as IoWrite;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use parking_lot::MappedRwLockReadGuard;
 use parking_lot::MappedRwLockWriteGuard;
@@ -26,7 +27,10 @@ use crate::errors::HgBacktrace;
 use crate::errors::HgError;
 use crate::errors::HgResultExt;
 use crate::errors::IoResultExt;
+use crate::file_index::Config as FileIndexConfig;
+use crate::file_index::FileIndex;
 use crate::lock::LockError;
+use crate::lock::try_with_lock;
 use crate::lock::try_with_lock_no_wait;
 use crate::matchers::get_ignore_files;
 use crate::matchers::get_ignore_function;
@@ -44,8 +48,11 @@ use crate::revlog::manifest::Manifestlog;
 use crate::revlog::options::RevlogOpenOptions;
 use crate::revlog::options::default_revlog_options;
 use crate::revlog::path_encode::PathEncoding;
+use crate::store::Store;
 use crate::utils::debug::debug_wait_for_file_or_print;
+use crate::utils::files::get_bytes_from_path;
 use crate::utils::files::get_path_from_bytes;
+use crate::utils::files::relative_path;
 use crate::utils::hg_path::HgPath;
 use crate::utils::strings::SliceExt;
 use crate::vfs::Vfs;
@@ -59,6 +66,23 @@ const V2_MAX_READ_ATTEMPTS: usize = 5;
 /// Docket file identity, data file uuid and the data size
 type DirstateV2Identity = (Option<DirstateIdentity>, Option<Vec<u8>>, usize);
 
+/// The content of the `00changelog.i` file outside of the store of a new
+/// repository
+const DUMMY_CHANGELOG: &[u8] =
+    b"\0\0\xFF\xFF dummy changelog to prevent using the old repo layout";
+
+/// How a new repository shares the store of another one, see
+/// [`Repo::create`]
+pub struct ShareOptions {
+    /// The `.hg` directory holding the shared store, see
+    /// [`Repo::shared_path`]
+    pub source: PathBuf,
+    /// Whether `.hg/sharedpath` is relative to the new `.hg`
+    pub relative: bool,
+    /// What else is shared, like `bookmarks`, listed in `.hg/shared`
+    pub items: Vec<&'static str>,
+}
+
 /// A repository on disk
 pub struct Repo {
     working_directory: PathBuf,
@@ -127,6 +151,80 @@ impl Repo {
         }
     }
 
+    /// Creates a repository with `requirements` in `working_directory`,
+    /// which is created if needed, and opens it, like `createrepository` in
+    /// Python. The repository uses the store of `share` if given, instead of
+    /// having one of its own.
+    pub fn create(
+        config: &Config,
+        working_directory: &Path,
+        requirements: &FastHashSet<String>,
+        share: Option<&ShareOptions>,
+    ) -> Result<Self, HgError> {
+        let dot_hg = working_directory.join(".hg");
+        if std::fs::symlink_metadata(&dot_hg).is_ok() {
+            return Err(HgError::abort_simple(format!(
+                "repository {} already exists",
+                working_directory.display()
+            )));
+        }
+        std::fs::create_dir_all(working_directory)
+            .when_writing_file(working_directory)?;
+        std::fs::create_dir(&dot_hg).when_writing_file(&dot_hg)?;
+        let has_store = requirements.contains(requirements::STORE_REQUIREMENT);
+        let mut directories = vec!["wcache"];
+        if share.is_none() {
+            directories.push("cache");
+            if has_store {
+                directories.push("store");
+            }
+        }
+        for directory in directories {
+            let path = dot_hg.join(directory);
+            std::fs::create_dir(&path).when_writing_file(&path)?;
+        }
+        if has_store && share.is_none() {
+            // Locks out the versions of Mercurial from before the
+            // requirements, which would use it as the changelog
+            let path = dot_hg.join("00changelog.i");
+            std::fs::write(&path, DUMMY_CHANGELOG).when_writing_file(&path)?;
+        }
+        let hg_vfs = VfsImpl::new(dot_hg.to_owned(), false, PathEncoding::None);
+        let (working_directory_requirements, store_requirements) =
+            requirements::split_working_directory(requirements);
+        requirements::write(&hg_vfs, &working_directory_requirements)?;
+        if let Some(store_requirements) = store_requirements
+            && share.is_none()
+            && !store_requirements.is_empty()
+        {
+            let store_vfs =
+                VfsImpl::new(dot_hg.join("store"), false, PathEncoding::None);
+            requirements::write(&store_vfs, &store_requirements)?;
+        }
+        if let Some(share) = share {
+            let source = std::fs::canonicalize(&share.source)
+                .when_reading_file(&share.source)?;
+            let shared_path = if share.relative {
+                let dot_hg = std::fs::canonicalize(&dot_hg)
+                    .when_reading_file(&dot_hg)?;
+                relative_path(&source, &dot_hg)
+            } else {
+                source
+            };
+            let path = dot_hg.join("sharedpath");
+            std::fs::write(&path, get_bytes_from_path(shared_path))
+                .when_writing_file(&path)?;
+            if !share.items.is_empty() {
+                let mut items = share.items.to_owned();
+                items.sort_unstable();
+                let path = dot_hg.join("shared");
+                std::fs::write(&path, format!("{}\n", items.join("\n")))
+                    .when_writing_file(&path)?;
+            }
+        }
+        Self::new_at_path(working_directory.to_owned(), config)
+    }
+
     /// To be called after checking that `.hg` is a sub-directory
     fn new_at_path(
         working_directory: PathBuf,
@@ -181,6 +279,12 @@ impl Repo {
         &self.store
     }
 
+    /// The `.hg` directory holding the store, which shares of this
+    /// repository point to, like `repo.sharedpath` in Python
+    pub fn shared_path(&self) -> &Path {
+        self.store.parent().unwrap_or(&self.dot_hg)
+    }
+
     pub fn requirements(&self) -> &FastHashSet<String> {
         &self.requirements
     }
@@ -195,6 +299,16 @@ impl Repo {
         VfsImpl::new(self.dot_hg.to_owned(), false, PathEncoding::None)
     }
 
+    /// For accessing the caches shared with the store, in the `.hg/cache`
+    /// next to it, like `repo.cachevfs` in Python
+    pub fn cache_vfs(&self) -> VfsImpl {
+        VfsImpl::new(
+            self.shared_path().join("cache"),
+            false,
+            PathEncoding::None,
+        )
+    }
+
     /// For accessing repository store files (in `.hg/store`)
     pub fn store_vfs(&self) -> VfsImpl {
         let repo_reqs = self.requirements();
@@ -229,6 +343,24 @@ impl Repo {
         try_with_lock_no_wait(&self.hg_vfs(), "wlock", f)
     }
 
+    /// Like [`Self::try_with_wlock_no_wait`], for the store lock
+    pub fn try_with_lock_no_wait<R>(
+        &self,
+        f: impl FnOnce() -> R,
+    ) -> Result<R, LockError> {
+        try_with_lock_no_wait(&self.store_vfs(), "lock", f)
+    }
+
+    /// Like [`Self::try_with_lock_no_wait`], waiting up to `timeout` for
+    /// the store lock
+    pub fn try_with_lock<R>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce() -> R,
+    ) -> Result<R, LockError> {
+        try_with_lock(&self.store_vfs(), "lock", timeout, f)
+    }
+
     /// Whether this repo should use dirstate-v2.
     /// The presence of `dirstate-v2` in the requirements does not mean that
     /// the on-disk dirstate is necessarily in version 2. In most cases,
@@ -251,6 +383,32 @@ impl Repo {
         self.requirements.contains(requirements::NARROW_REQUIREMENT)
     }
 
+    pub fn has_fileindex(&self) -> bool {
+        self.requirements.contains(FILEINDEX_V1_REQUIREMENT)
+    }
+
+    pub fn store(&self) -> Store<'_> {
+        Store::new(self)
+    }
+
+    /// Opens the file index of a repository using `fileindex-v1`, which
+    /// replaces the fncache as the list of files in the store.
+    pub fn file_index(&self) -> Result<FileIndex, HgError> {
+        let raw_store_vfs =
+            VfsImpl::new(self.store.to_owned(), false, PathEncoding::None);
+        FileIndex::open(
+            raw_store_vfs,
+            false,
+            FileIndexConfig::from_config(self.config())?,
+            || {
+                debug_wait_for_file_or_print(
+                    self.config(),
+                    "fileindex.pre-read-data-files",
+                )
+            },
+        )
+    }
+
     pub fn has_nodemap(&self) -> bool {
         self.requirements.contains(requirements::NODEMAP_REQUIREMENT)
     }
@@ -1,4 +1,5 @@
 use crate::FastHashSet;
+use crate::config::Config;
 use crate::errors::HgError;
 use crate::errors::HgResultExt;
 use crate::utils::strings::join_display;
@@ -45,6 +46,229 @@ pub(crate) fn load_if_exists(
     }
 }
 
+/// Writes `requirements` to the `requires` file of `vfs`, sorted, like
+/// `scmutil.writerequires` in Python
+pub fn write(
+    vfs: &VfsImpl,
+    requirements: &FastHashSet<String>,
+) -> Result<(), HgError> {
+    let mut sorted: Vec<&str> =
+        requirements.iter().map(String::as_str).collect();
+    sorted.sort_unstable();
+    let content: String =
+        sorted.iter().map(|requirement| format!("{}\n", requirement)).collect();
+    Ok(vfs.atomic_write("requires", content.as_bytes())?)
+}
+
+/// Splits `requirements` between those of `.hg/requires` and those of
+/// `.hg/store/requires`, the latter being `None` if the repository does not
+/// use share-safe, like `scmutil.filterrequirements` in Python
+pub fn split_working_directory(
+    requirements: &FastHashSet<String>,
+) -> (FastHashSet<String>, Option<FastHashSet<String>>) {
+    if !requirements.contains(SHARESAFE_REQUIREMENT) {
+        return (requirements.clone(), None);
+    }
+    let (working_directory, store) = requirements
+        .iter()
+        .cloned()
+        .partition(|requirement| is_working_directory(requirement));
+    (working_directory, Some(store))
+}
+
+/// Whether `requirement` is about the working directory rather than the
+/// store
+pub fn is_working_directory(requirement: &str) -> bool {
+    WORKING_DIR_REQUIREMENTS.contains(&requirement)
+}
+
+/// The requirements of a new repository given the `format` configuration,
+/// like `new_repo_requirements` in Python.
+///
+/// Falls back for the formats rhg does not support.
+pub fn new_repository_requirements(
+    config: &Config,
+) -> Result<FastHashSet<String>, HgError> {
+    let mut requirements = FastHashSet::default();
+    requirements.insert(REVLOGV1_REQUIREMENT.to_owned());
+    if !config.get_bool(b"format", b"usestore")? {
+        return Err(HgError::unsupported(
+            "creating a repository without store",
+        ));
+    }
+    requirements.insert(STORE_REQUIREMENT.to_owned());
+    let fncache = if config.get_bool(b"format", b"use-fileindex-v1")? {
+        requirements.insert(FILEINDEX_V1_REQUIREMENT.to_owned());
+        true
+    } else if config.get_bool(b"format", b"usefncache")? {
+        requirements.insert(FNCACHE_REQUIREMENT.to_owned());
+        if config.get_bool(b"format", b"dotencode")? {
+            requirements.insert(DOTENCODE_REQUIREMENT.to_owned());
+        }
+        true
+    } else {
+        false
+    };
+    if config.get_bool(
+        b"format",
+        b"exp-use-very-fragile-and-unsafe-plain-store-encoding",
+    )? {
+        if !fncache || requirements.contains(DOTENCODE_REQUIREMENT) {
+            return Err(HgError::unsupported(
+                "plain store encoding with incompatible settings",
+            ));
+        }
+        requirements.insert(PLAIN_ENCODE_REQUIREMENT.to_owned());
+    }
+    let engines = config
+        .get_list(b"format", b"revlog-compression")
+        .unwrap_or_else(|| vec![b"zstd".to_vec(), b"zlib".to_vec()]);
+    match engines
+        .iter()
+        .find(|engine| matches!(engine.as_slice(), b"zstd" | b"zlib"))
+        .map(Vec::as_slice)
+    {
+        Some(b"zstd") => {
+            requirements.insert(REVLOG_COMPRESSION_ZSTD.to_owned());
+        }
+        Some(_) => {}
+        None => {
+            return Err(HgError::unsupported(
+                "compression engines of format.revlog-compression",
+            ));
+        }
+    }
+    let explicit_gd = config.get(b"format", b"generaldelta").is_some()
+        || config.get(b"format", b"usegeneraldelta").is_some();
+    let enabled_gd = config.get_bool(b"format", b"generaldelta")?
+        || config.get_bool(b"format", b"usegeneraldelta")?;
+    if enabled_gd {
+        requirements.insert(GENERALDELTA_REQUIREMENT.to_owned());
+    }
+    if config.get_bool(b"format", b"sparse-revlog")? {
+        let explicit_sr = config.get(b"format", b"sparse-revlog").is_some();
+        // Sparse revlogs need general delta, explicitly disabling the
+        // latter disables the former unless it is explicitly enabled
+        if explicit_sr || !explicit_gd || enabled_gd {
+            requirements.insert(SPARSEREVLOG_REQUIREMENT.to_owned());
+        }
+    }
+    if config.get_bool(b"format", b"use-dirstate-v2")? {
+        requirements.insert(DIRSTATE_V2_REQUIREMENT.to_owned());
+    }
+    if config.get_bool(b"format", b"use-delta-info-flags")? {
+        requirements.insert(DELTA_INFO_REQUIREMENT.to_owned());
+        requirements.insert(SPARSEREVLOG_REQUIREMENT.to_owned());
+    }
+    if requirements.contains(SPARSEREVLOG_REQUIREMENT) {
+        requirements.insert(GENERALDELTA_REQUIREMENT.to_owned());
+    }
+    for (section, item) in [
+        (&b"format"[..], &b"exp-use-copies-in-changeset"[..]),
+        (b"experimental", b"treemanifest"),
+        (b"format", b"use-internal-phase"),
+        (b"format", b"exp-archived-phase"),
+    ] {
+        if config.get_bool(section, item)? {
+            return Err(HgError::unsupported(format!(
+                "creating a repository with {}.{}",
+                String::from_utf8_lossy(section),
+                String::from_utf8_lossy(item)
+            )));
+        }
+    }
+    for (section, item) in [
+        (&b"format"[..], &b"exp-use-changelog-v2"[..]),
+        (b"experimental", b"revlogv2"),
+    ] {
+        if config.get(section, item)
+            == Some(b"enable-unstable-format-and-corrupt-my-data")
+        {
+            return Err(HgError::unsupported(format!(
+                "creating a repository with {}.{}",
+                String::from_utf8_lossy(section),
+                String::from_utf8_lossy(item)
+            )));
+        }
+    }
+    if config.get_bool(b"format", b"bookmarks-in-store")? {
+        requirements.insert(BOOKMARKS_IN_STORE_REQUIREMENT.to_owned());
+    }
+    // The default depends on a fast implementation being available, which
+    // is always the case in Rust
+    if config
+        .get_option_no_default(b"format", b"use-persistent-nodemap")?
+        .unwrap_or(true)
+    {
+        requirements.insert(NODEMAP_REQUIREMENT.to_owned());
+    }
+    if config.get_bool(b"format", b"use-share-safe")? {
+        requirements.insert(SHARESAFE_REQUIREMENT.to_owned());
+    }
+    if config.get_bool(b"format", b"use-dirstate-tracked-hint")? {
+        let version =
+            config.get_u32(b"format", b"use-dirstate-tracked-hint.version")?;
+        if version != Some(1) {
+            // Python warns about it
+            return Err(HgError::unsupported("unknown tracked key version"));
+        }
+        requirements.insert(DIRSTATE_TRACKED_HINT_V1.to_owned());
+    }
+    Ok(requirements)
+}
+
+/// The requirements of a local clone of a repository with the `source`
+/// requirements: those of its store, and those of a new working directory,
+/// like `clone_requirements` in Python
+pub fn clone_requirements(
+    config: &Config,
+    source: &FastHashSet<String>,
+) -> Result<FastHashSet<String>, HgError> {
+    let mut requirements: FastHashSet<String> =
+        new_repository_requirements(config)?
+            .into_iter()
+            .filter(|requirement| is_working_directory(requirement))
+            .collect();
+    requirements.extend(
+        source
+            .iter()
+            .filter(|requirement| !is_working_directory(requirement))
+            .cloned(),
+    );
+    Ok(requirements)
+}
+
+/// The requirements of a share of a repository with the `source`
+/// requirements, like the `sharedrepo` case of `new_repo_requirements` in
+/// Python. The path to the source is `relative` to the share or not.
+pub fn share_requirements(
+    config: &Config,
+    source: &FastHashSet<String>,
+    relative: bool,
+) -> Result<FastHashSet<String>, HgError> {
+    let new = new_repository_requirements(config)?;
+    let mut requirements = source.clone();
+    if source.contains(SHARESAFE_REQUIREMENT) {
+        // Only the working directory is up to the share
+        requirements.extend(
+            new.into_iter()
+                .filter(|requirement| is_working_directory(requirement)),
+        );
+    } else if new.contains(DIRSTATE_TRACKED_HINT_V1) {
+        // Python adds it regardless of the source
+        requirements.insert(DIRSTATE_TRACKED_HINT_V1.to_owned());
+    }
+    requirements.insert(
+        if relative {
+            RELATIVE_SHARED_REQUIREMENT
+        } else {
+            SHARED_REQUIREMENT
+        }
+        .to_owned(),
+    );
+    Ok(requirements)
+}
+
 pub(crate) fn check(reqs: &FastHashSet<String>) -> Result<(), HgError> {
     let unknown: Vec<_> = reqs
         .iter()
@@ -204,6 +428,37 @@ pub const REVLOG_COMPRESSION_ZSTD: &str = "revlog-compression-zstd";
 /// remote machine
 pub const THIN_REQUIREMENT: &str = "exp-v0-thin";
 
+/// The requirements about the working directory, which are not shared with
+/// the store
+pub const WORKING_DIR_REQUIREMENTS: &[&str] = &[
+    SPARSE_REQUIREMENT,
+    SHARED_REQUIREMENT,
+    RELATIVE_SHARED_REQUIREMENT,
+    SHARESAFE_REQUIREMENT,
+    DIRSTATE_TRACKED_HINT_V1,
+    DIRSTATE_V2_REQUIREMENT,
+];
+
+/// The requirements that a stream clone does not carry over, since they do
+/// not change the content of the files it copies
+pub const STREAM_IGNORABLE_REQUIREMENTS: &[&str] = &[
+    // About the working copy
+    SPARSE_REQUIREMENT,
+    SHARED_REQUIREMENT,
+    RELATIVE_SHARED_REQUIREMENT,
+    SHARESAFE_REQUIREMENT,
+    DIRSTATE_TRACKED_HINT_V1,
+    DIRSTATE_V2_REQUIREMENT,
+    // Abstracted by the vfs layer
+    DOTENCODE_REQUIREMENT,
+    PLAIN_ENCODE_REQUIREMENT,
+    FNCACHE_REQUIREMENT,
+    FILEINDEX_V1_REQUIREMENT,
+    STORE_REQUIREMENT,
+    // The nodemap files are removed after the clone if not wanted
+    NODEMAP_REQUIREMENT,
+];
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,6 +479,174 @@ mod tests {
         set
     }
 
+    fn new_requirements(config_args: &[&str]) -> Result<Vec<String>, HgError> {
+        let mut config = Config::empty();
+        config.load_cli_args(config_args, None).unwrap();
+        let mut requirements: Vec<_> =
+            new_repository_requirements(&config)?.into_iter().collect();
+        requirements.sort_unstable();
+        Ok(requirements)
+    }
+
+    #[test]
+    fn test_new_repository_requirements() {
+        assert_eq!(
+            new_requirements(&[]).unwrap(),
+            [
+                DOTENCODE_REQUIREMENT,
+                FNCACHE_REQUIREMENT,
+                GENERALDELTA_REQUIREMENT,
+                NODEMAP_REQUIREMENT,
+                REVLOG_COMPRESSION_ZSTD,
+                REVLOGV1_REQUIREMENT,
+                SHARESAFE_REQUIREMENT,
+                SPARSEREVLOG_REQUIREMENT,
+                STORE_REQUIREMENT,
+            ]
+        );
+        assert_eq!(
+            new_requirements(&[
+                "format.revlog-compression=zlib",
+                "format.use-persistent-nodemap=no",
+                "format.use-share-safe=no",
+                "format.usegeneraldelta=no",
+                "format.use-dirstate-v2=yes",
+            ])
+            .unwrap(),
+            [
+                DIRSTATE_V2_REQUIREMENT,
+                DOTENCODE_REQUIREMENT,
+                FNCACHE_REQUIREMENT,
+                REVLOGV1_REQUIREMENT,
+                STORE_REQUIREMENT,
+            ]
+        );
+        // Explicitly enabling sparse revlogs enables general delta
+        assert!(
+            new_requirements(&[
+                "format.usegeneraldelta=no",
+                "format.sparse-revlog=yes"
+            ])
+            .unwrap()
+            .contains(&GENERALDELTA_REQUIREMENT.to_owned())
+        );
+        assert!(new_requirements(&["experimental.treemanifest=yes"]).is_err());
+        assert!(new_requirements(&["format.usestore=no"]).is_err());
+    }
+
+    fn sorted(requirements: FastHashSet<String>) -> Vec<String> {
+        let mut requirements: Vec<_> = requirements.into_iter().collect();
+        requirements.sort_unstable();
+        requirements
+    }
+
+    fn config(config_args: &[&str]) -> Config {
+        let mut config = Config::empty();
+        config.load_cli_args(config_args, None).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_clone_requirements() {
+        // The store keeps the format of the source, the working directory
+        // follows the configuration
+        let source = create_reqs(&[
+            FNCACHE_REQUIREMENT,
+            DOTENCODE_REQUIREMENT,
+            DIRSTATE_V2_REQUIREMENT,
+        ]);
+        assert_eq!(
+            sorted(clone_requirements(&config(&[]), &source).unwrap()),
+            [
+                DOTENCODE_REQUIREMENT,
+                FNCACHE_REQUIREMENT,
+                REVLOGV1_REQUIREMENT,
+                SHARESAFE_REQUIREMENT,
+                STORE_REQUIREMENT,
+            ]
+        );
+        let config = config(&[
+            "format.use-share-safe=no",
+            "format.use-dirstate-v2=yes",
+            "format.use-dirstate-tracked-hint=yes",
+        ]);
+        let source = create_reqs(&[FNCACHE_REQUIREMENT, SHARESAFE_REQUIREMENT]);
+        assert_eq!(
+            sorted(clone_requirements(&config, &source).unwrap()),
+            [
+                DIRSTATE_TRACKED_HINT_V1,
+                DIRSTATE_V2_REQUIREMENT,
+                FNCACHE_REQUIREMENT,
+                REVLOGV1_REQUIREMENT,
+                STORE_REQUIREMENT,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_share_requirements() {
+        let tracked_hint = config(&["format.use-dirstate-tracked-hint=yes"]);
+        // With share-safe, the working directory follows the configuration
+        let source =
+            create_reqs(&[SHARESAFE_REQUIREMENT, DIRSTATE_V2_REQUIREMENT]);
+        assert_eq!(
+            sorted(share_requirements(&tracked_hint, &source, false).unwrap()),
+            [
+                DIRSTATE_TRACKED_HINT_V1,
+                DIRSTATE_V2_REQUIREMENT,
+                REVLOGV1_REQUIREMENT,
+                SHARESAFE_REQUIREMENT,
+                SHARED_REQUIREMENT,
+                STORE_REQUIREMENT,
+            ]
+        );
+        // Without it, everything comes from the source but the tracked hint
+        let source = create_reqs(&[DIRSTATE_V2_REQUIREMENT]);
+        let config_v2 = config(&["format.use-dirstate-v2=no"]);
+        assert_eq!(
+            sorted(share_requirements(&config_v2, &source, true).unwrap()),
+            [
+                DIRSTATE_V2_REQUIREMENT,
+                RELATIVE_SHARED_REQUIREMENT,
+                REVLOGV1_REQUIREMENT,
+                STORE_REQUIREMENT,
+            ]
+        );
+        assert_eq!(
+            sorted(share_requirements(&tracked_hint, &source, true).unwrap()),
+            [
+                DIRSTATE_TRACKED_HINT_V1,
+                DIRSTATE_V2_REQUIREMENT,
+                RELATIVE_SHARED_REQUIREMENT,
+                REVLOGV1_REQUIREMENT,
+                STORE_REQUIREMENT,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_working_directory() {
+        let requirements = create_reqs(&[DIRSTATE_V2_REQUIREMENT]);
+        let (working_directory, store) = split_working_directory(&requirements);
+        assert_eq!(working_directory, requirements);
+        assert_eq!(store, None);
+
+        let requirements = create_reqs(&[
+            DIRSTATE_V2_REQUIREMENT,
+            SHARESAFE_REQUIREMENT,
+            FNCACHE_REQUIREMENT,
+        ]);
+        let (working_directory, store) = split_working_directory(&requirements);
+        assert_eq!(
+            sorted(working_directory),
+            [DIRSTATE_V2_REQUIREMENT, SHARESAFE_REQUIREMENT]
+        );
+        assert_eq!(
+            sorted(store.unwrap()),
+            [FNCACHE_REQUIREMENT, REVLOGV1_REQUIREMENT, STORE_REQUIREMENT]
+        );
+    }
+
     #[test]
     fn test_check() {
         // minimum reqs
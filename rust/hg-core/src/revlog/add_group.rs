@@ -0,0 +1,419 @@
+//! Adding the revisions received from another repository to a revlog, like
+//! `revlog.addgroup` in Python.
+
+use std::path::PathBuf;
+
+use super::NULL_REVISION;
+use super::Node;
+use super::Revision;
+use super::Revlog;
+use super::RevlogError;
+use super::diff;
+use super::index::FLAG_INLINE_DATA;
+use super::index::IndexHeader;
+use super::index::RevisionDataParams;
+use crate::changegroup::RevisionDelta;
+use crate::errors::HgError;
+use crate::transaction::StoreTransaction;
+use crate::transaction::Transaction;
+use crate::utils::RawData;
+
+/// Inline revlogs are split once their data reaches this size, like
+/// `_maxinline` in Python
+const MAX_INLINE_SIZE: usize = 131072;
+
+/// The full text of a delta chain is at most this many times larger than the
+/// revision it stores, like `LIMIT_DELTA2TEXT` in Python
+const LIMIT_DELTA_TO_TEXT: usize = 2;
+
+/// Where an inline revlog writes its index when it gets split during a
+/// transaction, which then renames it to the actual index
+#[derive(Debug, Clone)]
+pub struct SplitIndex {
+    /// The path of the temporary index, relative to the store
+    pub path: PathBuf,
+    /// Its unencoded name, as written in the journal
+    pub name: Vec<u8>,
+}
+
+/// What happened to a revision given to [`RevlogGroupWriter::add`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddedRevision {
+    New(Revision),
+    /// The revlog already had it
+    Duplicate(Revision),
+}
+
+/// Adds revisions given as deltas to a revlog, keeping its files open for
+/// writing until it is dropped.
+pub struct RevlogGroupWriter<'a> {
+    revlog: &'a mut Revlog,
+    /// The name of the revlog in error messages, like `data/foo`
+    name: String,
+    split_index: Option<SplitIndex>,
+    /// The full text of the last revision added, which is usually the delta
+    /// base of the next one
+    last: Option<(Revision, Vec<u8>)>,
+}
+
+impl<'a> RevlogGroupWriter<'a> {
+    /// Opens the files of `revlog` for writing in `tr`. An inline revlog is
+    /// split into `split_index` if it grows too much, or is rewritten in
+    /// place if it is `None`.
+    pub fn new(
+        revlog: &'a mut Revlog,
+        name: &str,
+        split_index: Option<SplitIndex>,
+        tr: &mut StoreTransaction,
+    ) -> Result<Self, HgError> {
+        revlog.inner.enter_writing_context(tr).inspect_err(|_| {
+            revlog.inner.exit_writing_context();
+        })?;
+        Ok(Self { revlog, name: name.to_owned(), split_index, last: None })
+    }
+
+    /// The revision number the next revision added gets
+    pub fn next_rev(&self) -> Revision {
+        Revision(self.revlog.len() as i32)
+    }
+
+    fn lookup_error(&self, node: &Node, message: &str) -> HgError {
+        HgError::abort_simple(format!(
+            "{}@{:x}: {}",
+            self.name,
+            node.short(),
+            message
+        ))
+    }
+
+    /// The revision of `node`, failing with `message` if it is unknown
+    fn rev(&self, node: &Node, message: &str) -> Result<Revision, HgError> {
+        match self.revlog.rev_from_node(node.into()) {
+            Ok(rev) => Ok(rev),
+            Err(RevlogError::InvalidRevision { .. }) => {
+                Err(self.lookup_error(node, message))
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn full_text(&self, rev: Revision) -> Result<RawData, HgError> {
+        match &self.last {
+            Some((last, text)) if *last == rev => {
+                Ok(RawData::from(text.to_owned()))
+            }
+            _ => Ok(self.revlog.get_data(rev)?),
+        }
+    }
+
+    /// Adds the revision `delta` introduced by the changeset `link_rev`,
+    /// unless the revlog already has it
+    pub fn add(
+        &mut self,
+        delta: &RevisionDelta,
+        link_rev: Revision,
+        tr: &mut StoreTransaction,
+    ) -> Result<AddedRevision, HgError> {
+        if let Ok(rev) = self.revlog.rev_from_node(delta.node.into()) {
+            return Ok(AddedRevision::Duplicate(rev));
+        }
+        let p1 = self.rev(&delta.p1, "unknown parent")?;
+        let p2 = self.rev(&delta.p2, "unknown parent")?;
+        let base = self.rev(&delta.delta_base, "unknown delta base")?;
+        if delta.flags != 0 {
+            return Err(HgError::unsupported(format!(
+                "revision flags {:#x} in {}",
+                delta.flags, self.name
+            )));
+        }
+        let text = delta
+            .apply(&self.full_text(base)?)
+            .map_err(|_| self.lookup_error(&delta.node, "invalid delta"))?;
+        if !delta.check_hash(&text) {
+            return Err(HgError::abort_simple(format!(
+                "integrity check failed on {}:{:x}",
+                self.name,
+                delta.node.short()
+            )));
+        }
+
+        let inner = &self.revlog.inner;
+        let rev = Revision(inner.len() as i32);
+        // The format of the revlog rather than the configuration, since
+        // changelogs do not use generaldelta
+        let general_delta = inner.index.uses_generaldelta();
+        let sparse_revlog = general_delta && inner.delta_config().sparse_revlog;
+        // The delta against the base the revision is stored against
+        let candidate = if general_delta {
+            let reusable = base != NULL_REVISION
+                && (base == p1
+                    || base == p2
+                    || (sparse_revlog && inner.is_snapshot(base)?));
+            if reusable {
+                Some((base, delta.delta.to_owned()))
+            } else if p1 != NULL_REVISION {
+                Some((p1, diff::text_delta(&self.full_text(p1)?, &text)))
+            } else {
+                None
+            }
+        } else if rev.0 > 0 {
+            let previous = Revision(rev.0 - 1);
+            if base == previous {
+                Some((previous, delta.delta.to_owned()))
+            } else {
+                Some((
+                    previous,
+                    diff::text_delta(&self.full_text(previous)?, &text),
+                ))
+            }
+        } else {
+            None
+        };
+        let mut stored = None;
+        if let Some((delta_base, delta)) = candidate {
+            let (header, data) = self.compress(&delta)?;
+            let size = header.len() + data.len();
+            if self.is_good_delta(delta_base, size, &text, sparse_revlog)? {
+                let chain_base = if general_delta {
+                    delta_base
+                } else {
+                    Revision(
+                        inner
+                            .index
+                            .get_entry(delta_base)
+                            .base_revision_or_base_of_delta_chain()
+                            .0,
+                    )
+                };
+                stored = Some((chain_base, header, data));
+            }
+        }
+        let (chain_base, header, data) = match stored {
+            Some(stored) => stored,
+            None => {
+                let (header, data) = self.compress(&text)?;
+                (rev, header, data)
+            }
+        };
+
+        let inner = &mut self.revlog.inner;
+        let params = RevisionDataParams {
+            data_offset: inner.next_data_offset() as u64,
+            data_compressed_length: (header.len() + data.len()) as i32,
+            data_uncompressed_length: text.len() as i32,
+            data_delta_base: chain_base.0,
+            link_rev: link_rev.0,
+            parent_rev_1: p1.0,
+            parent_rev_2: p2.0,
+            node_id: delta.node.as_bytes().try_into().expect("20 bytes node"),
+            ..Default::default()
+        };
+        inner.add_entry(&mut *tr, params, (header, &data))?;
+        self.last = Some((rev, text));
+        self.enforce_inline_size(tr)?;
+        Ok(AddedRevision::New(rev))
+    }
+
+    /// Compresses `data` as stored in the revlog, returning the header to
+    /// write before it
+    fn compress(
+        &self,
+        data: &[u8],
+    ) -> Result<(&'static [u8], Vec<u8>), HgError> {
+        Ok(match self.revlog.inner.compress(data)? {
+            Some(compressed) => (b"", compressed.into_owned()),
+            None => (b"u", data.to_owned()),
+        })
+    }
+
+    /// Whether storing a delta of `size` bytes against `base` keeps reading
+    /// `text` cheap, like `_isgooddeltainfo` in Python
+    fn is_good_delta(
+        &self,
+        base: Revision,
+        size: usize,
+        text: &[u8],
+        sparse_revlog: bool,
+    ) -> Result<bool, HgError> {
+        let inner = &self.revlog.inner;
+        if size > text.len() {
+            return Ok(false);
+        }
+        let (chain, _) = inner.delta_chain(base, None)?;
+        if let Some(max_chain_len) = inner.delta_config().max_chain_len
+            && chain.len() as u64 + 1 > max_chain_len
+        {
+            return Ok(false);
+        }
+        let chain_size: usize = chain
+            .iter()
+            .map(|&rev| inner.data_compressed_length(rev))
+            .sum::<usize>()
+            + size;
+        if chain_size > text.len() * LIMIT_DELTA_TO_TEXT {
+            return Ok(false);
+        }
+        if !sparse_revlog {
+            let start = inner.data_start(chain[0]);
+            let distance = inner.next_data_offset() + size - start;
+            let max_distance = (text.len() * 4)
+                .max(inner.delta_config().max_deltachain_span.unwrap_or(0)
+                    as usize);
+            if distance > max_distance {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Splits an inline revlog whose data grew too much, like
+    /// `_enforceinlinesize` in Python
+    fn enforce_inline_size(
+        &mut self,
+        tr: &mut StoreTransaction,
+    ) -> Result<(), HgError> {
+        let inner = &self.revlog.inner;
+        if !inner.is_inline() || inner.next_data_offset() < MAX_INLINE_SIZE {
+            return Ok(());
+        }
+        split_inline(self.revlog, self.split_index.take(), tr)
+    }
+}
+
+impl Drop for RevlogGroupWriter<'_> {
+    fn drop(&mut self) {
+        self.revlog.inner.exit_writing_context();
+    }
+}
+
+/// Moves the data of the inline `revlog` to a separate data file within
+/// `tr`, writing the new index to `split_index` if given, or in place
+/// otherwise.
+pub fn split_inline(
+    revlog: &mut Revlog,
+    split_index: Option<SplitIndex>,
+    tr: &mut StoreTransaction,
+) -> Result<(), HgError> {
+    let inner = &mut revlog.inner;
+    let index_file = inner.index_file.to_owned();
+    tr.add_backup(&index_file);
+    tr.add(&inner.data_file, 0);
+    let mut header_bytes = inner.index.header.header_bytes;
+    header_bytes[1] &= !(FLAG_INLINE_DATA as u8);
+    let header = IndexHeader { header_bytes };
+    let new_index = split_index.map(|split_index| {
+        tr.add_name(&split_index.path, &split_index.name);
+        tr.register_rename(&split_index.path, &index_file);
+        split_index.path
+    });
+    inner.split_inline(header, new_index)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NULL_NODE;
+    use crate::changegroup::ChangegroupVersion;
+    use crate::changegroup::write_changegroup;
+    use crate::discovery::Outgoing;
+    use crate::repo::Repo;
+    use crate::testing::TestRepo;
+    use crate::utils::hg_path::HgPath;
+
+    /// Bundles every changeset of `repo`, like `hg bundle --all`
+    fn bundle_all(repo: &Repo, version: ChangegroupVersion) -> Vec<u8> {
+        let changelog = repo.changelog().unwrap();
+        let index = changelog.get_index();
+        let heads = index.head_revs().unwrap();
+        let outgoing = Outgoing::new(index, vec![], heads).unwrap();
+        drop(changelog);
+        let mut out = vec![];
+        write_changegroup(repo, &outgoing, version, true, &mut out).unwrap();
+        out
+    }
+
+    /// The node and the full text of every revision of `revlog`
+    fn revisions(revlog: &Revlog) -> Vec<(Node, Vec<u8>)> {
+        (0..revlog.len() as i32)
+            .map(|rev| {
+                let rev = Revision(rev);
+                let data = revlog.get_data(rev).unwrap();
+                (*revlog.node_from_rev(rev), data.to_vec())
+            })
+            .collect()
+    }
+
+    /// Checks that `to` has the same revisions as `from`
+    fn assert_same_revlogs(from: &Repo, to: &Repo, files: &[&str]) {
+        assert_eq!(
+            revisions(&to.changelog().unwrap().revlog),
+            revisions(&from.changelog().unwrap().revlog)
+        );
+        assert_eq!(
+            revisions(&to.manifestlog().unwrap().revlog),
+            revisions(&from.manifestlog().unwrap().revlog)
+        );
+        for file in files {
+            let path = HgPath::new(file.as_bytes());
+            assert_eq!(
+                revisions(&to.filelog(path).unwrap().revlog),
+                revisions(&from.filelog(path).unwrap().revlog)
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut source = TestRepo::new(&[]);
+        let first = source.commit(NULL_NODE, &[("a", Some("a\n"))]);
+        let second = source.commit(first, &[("a", Some("a\nb\n"))]);
+        source.commit(second, &[("b", Some("b\n")), ("a", None)]);
+        source.commit(first, &[("a", Some("a\nc\n"))]);
+
+        for config in [&[][..], &["format.usegeneraldelta=no"]] {
+            for version in [ChangegroupVersion::V1, ChangegroupVersion::V2] {
+                let target = TestRepo::new(config);
+                target.apply(&bundle_all(&source.repo, version), version);
+                assert_same_revlogs(&source.repo, &target.repo, &["a", "b"]);
+                // Applying it again adds nothing
+                target.apply(&bundle_all(&source.repo, version), version);
+                assert_eq!(target.repo.changelog().unwrap().revlog.len(), 4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_inline() {
+        // Barely compressible, so that the revlog outgrows its index
+        let mut state = 1u64;
+        let big: String = (0..MAX_INLINE_SIZE * 2)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                char::from(b'a' + (state >> 59) as u8)
+            })
+            .collect();
+        let mut source = TestRepo::new(&[]);
+        let first = source.commit(NULL_NODE, &[("big", Some("small\n"))]);
+        let second = source.commit(first, &[("big", Some(&big))]);
+        source.commit(second, &[("big", Some(&big[1..]))]);
+
+        let target = TestRepo::new(&[]);
+        let version = ChangegroupVersion::V2;
+        target.apply(&bundle_all(&source.repo, version), version);
+        for test_repo in [&source, &target] {
+            let repo = &test_repo.repo;
+            let filelog = repo.filelog(HgPath::new(b"big")).unwrap();
+            assert!(!filelog.revlog.is_inline());
+            assert_eq!(filelog.revlog.len(), 3);
+            let store = repo.store_path();
+            assert!(store.join("data/big.d").exists());
+            assert!(!store.join("data-s/big.i").exists());
+            let fncache = std::fs::read(store.join("fncache")).unwrap();
+            let mut entries: Vec<_> = fncache.split(|&b| b == b'\n').collect();
+            entries.sort_unstable();
+            assert_eq!(entries, [&b""[..], b"data/big.d", b"data/big.i"]);
+        }
+        assert_same_revlogs(&source.repo, &target.repo, &["big"]);
+    }
+}
@@ -27,6 +27,8 @@ use crate::revlog::Revlog;
 use crate::revlog::RevlogEntry;
 use crate::revlog::RevlogError;
 use crate::revlog::RevlogType;
+use crate::revlog::add_group::split_inline;
+use crate::transaction::StoreTransaction;
 use crate::utils::RawData;
 use crate::utils::hg_path::HgPath;
 use crate::vfs::VfsImpl;
@@ -53,6 +55,48 @@ impl Changelog {
         Ok(Self { revlog })
     }
 
+    /// Keeps the new revisions out of the index until
+    /// [`Self::finalize_pending`] is called, so that readers do not see them
+    /// before the transaction closes. An inline changelog is split first.
+    ///
+    /// Equivalent to `changelog.delayupdate` in Python.
+    pub fn delay_update(
+        &mut self,
+        tr: &mut StoreTransaction,
+    ) -> Result<(), HgError> {
+        if self.revlog.is_inline() {
+            split_inline(&mut self.revlog, None, tr)?;
+        }
+        if let Some(diverted) = self.revlog.inner.delay()? {
+            tr.register_tmp(diverted);
+        }
+        Ok(())
+    }
+
+    /// Writes the delayed revisions to a temporary index, which the hooks
+    /// run before the transaction closes can read through `HG_PENDING`.
+    /// Returns whether there are any.
+    pub fn write_pending(
+        &mut self,
+        tr: &mut StoreTransaction,
+    ) -> Result<bool, HgError> {
+        let (pending, any_pending) = self.revlog.inner.write_pending()?;
+        if let Some(pending) = pending {
+            tr.register_tmp(pending);
+        }
+        Ok(any_pending)
+    }
+
+    /// Writes the revisions delayed by [`Self::delay_update`] to the index,
+    /// if it was called, like the finalize callback it registers in Python
+    pub fn finalize_pending(&mut self) -> Result<(), HgError> {
+        if !self.revlog.inner.is_delaying() {
+            return Ok(());
+        }
+        self.revlog.inner.finalize_pending()?;
+        Ok(())
+    }
+
     /// Return the `ChangelogRevisionData` for the given node ID.
     pub fn data_for_node(
         &self,
@@ -212,7 +256,9 @@ lazy_static! {
 }
 
 impl ChangelogRevisionData {
-    fn new(rev: Revision, bytes: RawData) -> Result<Self, RevlogError> {
+    /// Parses the data `bytes` of the changeset `rev`, which only serves to
+    /// report errors
+    pub fn new(rev: Revision, bytes: RawData) -> Result<Self, RevlogError> {
         let mut line_iter = bytes.as_ref().split(|b| b == &b'\n');
         let manifest_end =
             line_iter.next().expect("Empty iterator from split()?").len();
@@ -67,6 +67,12 @@ impl Filelog {
         Self::open_vfs(&repo.store_vfs(), file_path, options)
     }
 
+    /// Returns whether the filelog has no revisions, which is also the case
+    /// when it does not exist
+    pub fn is_empty(&self) -> bool {
+        self.revlog.is_empty()
+    }
+
     /// The given node ID is that of the file as found in a filelog, not of a
     /// changeset.
     pub fn data_for_node(
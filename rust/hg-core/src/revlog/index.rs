@@ -1823,6 +1823,28 @@ impl Phase {
     pub const fn non_public_phases() -> &'static [Self] {
         &[Self::Draft, Self::Secret, Self::Archived, Self::Internal]
     }
+
+    /// The name of the phase, as shown to users
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Draft => "draft",
+            Self::Secret => "secret",
+            Self::Archived => "archived",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// The number of the phase in the on-disk and exchange formats
+    pub const fn number(self) -> u32 {
+        match self {
+            Self::Public => 0,
+            Self::Draft => 1,
+            Self::Secret => 2,
+            Self::Archived => 32,
+            Self::Internal => 96,
+        }
+    }
 }
 
 fn inline_scan(bytes: &[u8]) -> (usize, Vec<usize>) {
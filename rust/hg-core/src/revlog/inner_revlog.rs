@@ -951,7 +951,7 @@ impl InnerRevlog {
                 }
             }
             // Use stable sort here since it's *mostly* sorted
-            chunks.sort_by(|a, b| a.0.cmp(&b.0));
+            chunks.sort_by_key(|a| a.0);
         }
         Ok(chunks.into_iter().map(|(_r, chunk)| chunk).collect())
     }
@@ -1012,9 +1012,7 @@ impl InnerRevlog {
         let mut sliced = vec![];
 
         for chunk in to_density {
-            sliced.extend(
-                self.slice_chunk_to_size(chunk, target_size)?.into_iter(),
-            );
+            sliced.extend(self.slice_chunk_to_size(chunk, target_size)?);
         }
 
         Ok(sliced)
@@ -1510,6 +1508,10 @@ impl InnerRevlog {
                 0
             },
         );
+        if self.inline {
+            // Like Python, a data file next to an inline revlog is ignored
+            return (0, index_size - expected_index - expected_data);
+        }
         let data_size = u64_i64(
             if let Ok(mut fp) = self.vfs.open(self.data_file.as_ref()) {
                 fp.seek(SeekFrom::End(0)).unwrap_or(0)
@@ -1517,11 +1519,7 @@ impl InnerRevlog {
                 0
             },
         );
-        if self.inline {
-            (index_size - expected_index - expected_data, data_size)
-        } else {
-            (index_size - expected_index, data_size - expected_data)
-        }
+        (data_size - expected_data, index_size - expected_index)
     }
 
     /// Return the path to the diverted index
@@ -1534,6 +1532,11 @@ impl InnerRevlog {
         self.segment_file.is_open()
     }
 
+    /// The configuration of the deltas of this revlog
+    pub fn delta_config(&self) -> &RevlogDeltaConfig {
+        &self.delta_config
+    }
+
     /// True if this revlog supports the metadata flag for its revisions
     pub fn supports_hasmeta_flag(&self) -> bool {
         self.feature_config.hasmeta_flag
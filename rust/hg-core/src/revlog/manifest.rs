@@ -124,6 +124,33 @@ impl Manifestlog {
         }
         Ok(Manifest::from_bytes(rev, Box::new(bytes)))
     }
+
+    /// Returns a manifest containing the entries for `rev` that are not in
+    /// its delta parent, without resolving the full manifest.
+    /// Equivalent to `manifestctx.read_delta_new_entries()` in Python.
+    pub fn data_delta_new_entries(
+        &self,
+        rev: Revision,
+    ) -> Result<Manifest, RevlogError> {
+        let delta_parent = self.revlog.delta_parent(rev);
+        if delta_parent == NULL_REVISION {
+            return self.data(rev);
+        }
+        // A file node introduced by `rev` that still exists in a delta
+        // parent with a later linkrev is not in the delta, so fall back to
+        // comparing with the parents.
+        let index = self.revlog.index();
+        if index.get_entry(delta_parent).link_revision()
+            > index.get_entry(rev).link_revision()
+        {
+            return self.inexact_data_delta_parents(rev);
+        }
+        let mut bytes = vec![];
+        for chunk in self.revlog.get_data_incr(rev)?.as_delta()?.chunks {
+            bytes.extend_from_slice(chunk.data);
+        }
+        Ok(Manifest::from_bytes(rev, Box::new(bytes)))
+    }
 }
 
 /// [`Manifestlog`] entry which knows how to interpret the data bytes.
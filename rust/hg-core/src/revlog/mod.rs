@@ -5,6 +5,7 @@
 // GNU General Public License version 2 or any later version.
 //! Mercurial concepts for handling revision history
 
+pub mod add_group;
 pub mod deltas;
 pub mod diff;
 pub mod node;
@@ -339,7 +340,7 @@ impl<T> RevlogIndexNodeLookup for T where T: RevlogIndex {}
 const REVISION_FLAG_CENSORED: u16 = 1 << 15;
 const REVISION_FLAG_ELLIPSIS: u16 = 1 << 14;
 const REVISION_FLAG_EXTSTORED: u16 = 1 << 13;
-const REVISION_FLAG_HASCOPIESINFO: u16 = 1 << 12;
+pub(crate) const REVISION_FLAG_HASCOPIESINFO: u16 = 1 << 12;
 const REVISION_FLAG_HASMETA: u16 = 1 << 11;
 const REVISION_FLAG_DELTA_IS_SNAPSHOT: u16 = 1 << 10;
 const REVISION_FLAG_DELTA_HAS_QUALITY: u16 = 1 << 9;
@@ -362,7 +363,7 @@ const REVIDX_KNOWN_FLAGS: u16 = REVISION_FLAG_CENSORED
 
 const NULL_REVLOG_ENTRY_FLAGS: u16 = 0;
 
-const REVIDX_DELTA_INFO_FLAGS: u16 = REVISION_FLAG_DELTA_IS_SNAPSHOT
+pub(crate) const REVIDX_DELTA_INFO_FLAGS: u16 = REVISION_FLAG_DELTA_IS_SNAPSHOT
     | REVISION_FLAG_DELTA_HAS_QUALITY
     | REVISION_FLAG_DELTA_IS_GOOD
     | REVISION_FLAG_DELTA_P1_IS_SMALL
@@ -675,6 +676,24 @@ impl Revlog {
         )
     }
 
+    /// Returns the link revision of the given revision, without checking
+    /// that it exists in the linked revlog, for `verify`.
+    pub fn unchecked_link_revision(&self, rev: Revision) -> UncheckedRevision {
+        self.index().get_entry(rev).link_revision()
+    }
+
+    /// Whether the index and data of this revlog are interleaved in the
+    /// index file
+    pub fn is_inline(&self) -> bool {
+        self.inner.is_inline()
+    }
+
+    /// Returns the number of unexpected bytes at the end of the data file
+    /// and of the index file. A healthy revlog has `(0, 0)`.
+    pub fn check_size(&self) -> (i64, i64) {
+        self.inner.check_size()
+    }
+
     /// Return the full data associated to a revision.
     ///
     /// All entries required to build the final data out of deltas will be
@@ -718,6 +737,20 @@ impl Revlog {
         Ok(RawdataBuf { base, data })
     }
 
+    /// Returns a binary delta turning the data of `base` into that of `rev`,
+    /// reusing the stored delta when `base` is the delta parent of `rev`.
+    /// Equivalent to `revlog.revdiff` in Python.
+    pub fn rev_diff(
+        &self,
+        base: Revision,
+        rev: Revision,
+    ) -> Result<Vec<u8>, RevlogError> {
+        if base != NULL_REVISION && self.delta_parent(rev) == base {
+            return Ok(self.inner.chunk_for_rev(rev)?.into());
+        }
+        Ok(diff::text_delta(&self.get_data(base)?, &self.get_data(rev)?))
+    }
+
     /// Check the hash of some given data against the recorded hash.
     pub fn check_hash(
         &self,
@@ -919,6 +952,10 @@ impl<'revlog> RevlogEntry<'revlog> {
         }
     }
 
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
     pub fn is_censored(&self) -> bool {
         (self.flags & REVISION_FLAG_CENSORED) != 0
     }
@@ -946,7 +983,7 @@ impl<'revlog> RevlogEntry<'revlog> {
     }
 
     /// Get the revision data, without checking it integrity
-    fn data_unchecked(&self) -> Result<RawData, RevlogError> {
+    pub fn data_unchecked(&self) -> Result<RawData, RevlogError> {
         if self.rev == NULL_REVISION {
             return Ok(RawData::empty());
         }
@@ -1138,6 +1175,51 @@ mod tests {
         assert_eq!(p2_entry.unwrap().revision(), Revision(1));
     }
 
+    #[test]
+    fn test_check_size() {
+        let temp = tempfile::tempdir().unwrap();
+        let vfs = VfsImpl::new(
+            temp.path().to_owned(),
+            false,
+            PathEncoding::DotEncode,
+        );
+        let open = |name: &str| {
+            Revlog::open(
+                &vfs,
+                name,
+                None,
+                &RevlogOpenOptions::default(),
+                RevlogType::Changelog,
+            )
+            .unwrap()
+        };
+
+        let entry = IndexEntryBuilder::new()
+            .is_first(true)
+            .with_version(1)
+            .with_compressed_len(4)
+            .build();
+        std::fs::write(temp.path().join("foo.i"), &entry).unwrap();
+        std::fs::write(temp.path().join("foo.d"), b"datamore").unwrap();
+        // The extra bytes of the data file come first, like in Python
+        assert_eq!(open("foo.i").inner.check_size(), (4, 0));
+        std::fs::write(temp.path().join("foo.d"), b"data").unwrap();
+        assert_eq!(open("foo.i").inner.check_size(), (0, 0));
+
+        let inline = IndexEntryBuilder::new()
+            .is_first(true)
+            .with_version(1)
+            .with_inline(true)
+            .with_compressed_len(4)
+            .build();
+        let contents = [&inline[..], b"data"].concat();
+        std::fs::write(temp.path().join("bar.i"), contents).unwrap();
+        assert_eq!(open("bar.i").inner.check_size(), (0, 0));
+        // A stray data file next to an inline revlog is not reported
+        std::fs::write(temp.path().join("bar.d"), b"stale").unwrap();
+        assert_eq!(open("bar.i").inner.check_size(), (0, 0));
+    }
+
     #[test]
     fn test_nodemap() {
         let temp = tempfile::tempdir().unwrap();
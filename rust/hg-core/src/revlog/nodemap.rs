@@ -654,7 +654,7 @@ impl NodeTree {
         index: &impl RevlogIndex,
         from: Revision,
     ) -> Result<(), RevlogError> {
-        let to = Revision(0.max(index.len() - 1) as BaseRevision);
+        let to = Revision(index.len().saturating_sub(1) as BaseRevision);
         self.insert_many(index, from, to)
     }
 
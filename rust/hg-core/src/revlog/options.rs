@@ -482,7 +482,11 @@ pub fn default_revlog_options(
             RevlogVersionOptions::V2
         } else if requirements.contains(REVLOGV1_REQUIREMENT) {
             RevlogVersionOptions::V1 {
-                general_delta: requirements.contains(GENERALDELTA_REQUIREMENT),
+                // Changelogs don't benefit from generaldelta, so new ones
+                // don't use it, like in Python
+                general_delta: requirements
+                    .contains(GENERALDELTA_REQUIREMENT)
+                    && !is_changelog,
                 hasmeta_flag: requirements.contains(DELTA_INFO_REQUIREMENT),
                 delta_info: requirements.contains(DELTA_INFO_REQUIREMENT),
                 inline: !is_changelog,
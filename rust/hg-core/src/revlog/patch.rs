@@ -647,6 +647,46 @@ where
     deltas.iter().map(|d| Delta::new(d.as_ref())).collect()
 }
 
+/// Apply a single Delta in binary form to a Full-Text.
+///
+/// Unlike the deltas stored in a revlog, this one usually comes from outside
+/// the repository (a changegroup for example), so it is validated against the
+/// Full-Text before being applied.
+pub fn apply_delta(
+    full_text: &[u8],
+    delta: &[u8],
+) -> Result<Vec<u8>, RevlogError> {
+    let corrupted =
+        || RevlogError::CorruptedDelta { backtrace: HgBacktrace::capture() };
+    let mut remaining = delta;
+    let mut last_end = 0;
+    while !remaining.is_empty() {
+        if remaining.len() < 12 {
+            return Err(corrupted());
+        }
+        let start = BigEndian::read_u32(&remaining[0..]);
+        let end = BigEndian::read_u32(&remaining[4..]);
+        let len = BigEndian::read_u32(&remaining[8..]);
+        if start < last_end || start > end || u32_u(end) > full_text.len() {
+            return Err(corrupted());
+        }
+        let available = remaining.len() - 12;
+        if u32_u(len) > available {
+            return Err(RevlogError::DeltaInsertsTooMuch {
+                backtrace: HgBacktrace::capture(),
+                len,
+                available,
+            });
+        }
+        last_end = end;
+        remaining = &remaining[12 + u32_u(len)..];
+    }
+    let delta = Delta::new(delta)?;
+    let target_size = full_text.len() as i64 + i64::from(delta.len_diff());
+    let target_size = u32::try_from(target_size).map_err(|_| corrupted())?;
+    Ok(delta.as_applied(full_text, 0, target_size))
+}
+
 /// apply a chain of Delta in binary form to a Full-Text
 #[allow(dead_code)]
 pub(super) fn apply_chain<D>(
@@ -845,7 +885,7 @@ mod tests {
                 }
 
                 assert!(!tmp_patches.is_empty());
-                tmp_patches.sort_by(|a, b| a.pos.cmp(&b.pos));
+                tmp_patches.sort_by_key(|a| a.pos);
                 for p in tmp_patches.into_iter() {
                     d.pieces.push(p);
                 }
@@ -1432,4 +1472,38 @@ mod tests {
             }
         }
     }
+
+    fn raw_piece(start: u32, end: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&start.to_be_bytes());
+        bytes.extend_from_slice(&end.to_be_bytes());
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let delta = [raw_piece(0, 1, b"A"), raw_piece(4, 6, b"xyz")].concat();
+        assert_eq!(apply_delta(b"abcdefg", &delta).unwrap(), b"Abcdxyzg");
+        assert_eq!(apply_delta(b"abc", b"").unwrap(), b"abc");
+        assert_eq!(
+            apply_delta(b"", &raw_piece(0, 0, b"full")).unwrap(),
+            b"full"
+        );
+    }
+
+    #[test]
+    fn test_apply_invalid_delta() {
+        // beyond the end of the full text
+        assert!(apply_delta(b"abc", &raw_piece(2, 4, b"")).is_err());
+        // overlapping pieces
+        let delta = [raw_piece(0, 2, b""), raw_piece(1, 3, b"")].concat();
+        assert!(apply_delta(b"abc", &delta).is_err());
+        // truncated header and data
+        assert!(apply_delta(b"abc", &[0, 0, 0, 0, 0]).is_err());
+        let mut delta = raw_piece(0, 1, b"xyz");
+        delta.pop();
+        assert!(apply_delta(b"abc", &delta).is_err());
+    }
 }
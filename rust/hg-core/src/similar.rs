@@ -0,0 +1,193 @@
+//! Finding renamed files by comparing the contents of added and removed
+//! files. This is the Rust counterpart of `mercurial/similar.py`.
+
+use std::path::Path;
+
+use rayon::prelude::*;
+use sha1::Digest;
+use sha1::Sha1;
+
+use crate::FastHashMap;
+use crate::bdiff;
+use crate::errors::HgError;
+use crate::errors::IoResultExt;
+use crate::repo::Repo;
+use crate::revlog::manifest::Manifest;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::utils::hg_path::hg_path_to_path_buf;
+
+/// A removed file considered to have been renamed to an added one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rename {
+    pub source: HgPathBuf,
+    pub dest: HgPathBuf,
+    /// How similar the contents are, between 0 and 1
+    pub score: f64,
+}
+
+/// Returns how similar `a` and `b` are, as the proportion of their bytes
+/// that belong to matching lines.
+pub fn score(a: &[u8], b: &[u8]) -> Result<f64, HgError> {
+    let lengths = a.len() + b.len();
+    if lengths == 0 {
+        return Ok(1.0);
+    }
+    let a_lines = bdiff::split_lines(a)?;
+    let b_lines = bdiff::split_lines(b)?;
+    let b_line_lengths: Vec<usize> = b_lines.iter().map(<[u8]>::len).collect();
+    let mut equal = 0;
+    for hunk in bdiff::diff(&a_lines, &b_lines)?.iter() {
+        equal += b_line_lengths[hunk.b1 as usize..hunk.b2 as usize]
+            .iter()
+            .sum::<usize>();
+    }
+    Ok(equal as f64 * 2.0 / lengths as f64)
+}
+
+/// Find which of the `removed` files were renamed to which of the `added`
+/// ones. Added files are read from the working directory, and removed ones
+/// from `parent_manifest`, where they are ignored if absent.
+///
+/// Files with identical contents are matched first. If `threshold` is below
+/// 1, the remaining added files are then matched to the removed file they
+/// are most similar to, if their [`score`] is above `threshold`. Empty files
+/// are never matched, since they are most likely unrelated.
+///
+/// Results are in the same order as in Python.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn find_renames(
+    repo: &Repo,
+    parent_manifest: &Manifest,
+    added: &[HgPathBuf],
+    removed: &[HgPathBuf],
+    threshold: f64,
+) -> Result<Vec<Rename>, HgError> {
+    let working_directory = repo.working_directory_path();
+    let mut added: Vec<&HgPathBuf> = added.iter().collect();
+    added.sort_unstable();
+    let mut added_files = vec![];
+    for path in added {
+        let relative_path = hg_path_to_path_buf(path)?;
+        let metadata =
+            std::fs::symlink_metadata(working_directory.join(&relative_path))
+                .when_reading_file(&relative_path)?;
+        if metadata.len() > 0 {
+            added_files.push(path.as_ref());
+        }
+    }
+    let mut removed: Vec<&HgPathBuf> = removed.iter().collect();
+    removed.sort_unstable();
+    let mut removed_files = vec![];
+    for path in removed {
+        let Some(entry) = parent_manifest.find_by_path(path)? else {
+            continue;
+        };
+        let data = repo
+            .filelog(path)?
+            .data_for_node(entry.node_id()?)?
+            .into_file_data()?;
+        if !data.is_empty() {
+            removed_files.push((path.as_ref(), data));
+        }
+    }
+
+    let mut renames = vec![];
+
+    // Exact matches
+    let mut hashes: FastHashMap<_, Vec<usize>> = FastHashMap::default();
+    for (index, (_, data)) in removed_files.iter().enumerate() {
+        hashes.entry(Sha1::digest(data)).or_default().push(index);
+    }
+    let mut unmatched = vec![];
+    for dest in added_files {
+        let data = read_working_file(working_directory, dest)?;
+        let source = hashes.get(&Sha1::digest(&data)).and_then(|indices| {
+            indices
+                .iter()
+                .map(|&index| &removed_files[index])
+                .find(|(_, removed_data)| **removed_data == *data)
+        });
+        match source {
+            Some(&(source, _)) => renames.push(Rename {
+                source: source.to_owned(),
+                dest: dest.to_owned(),
+                score: 1.0,
+            }),
+            None => unmatched.push(dest),
+        }
+    }
+
+    // Similar matches
+    if threshold < 1.0 {
+        let mut best: Vec<Option<(usize, f64)>> = vec![None; unmatched.len()];
+        // Order in which added files were first matched
+        let mut order = vec![];
+        for (source_index, (_, source_data)) in removed_files.iter().enumerate()
+        {
+            let scores = unmatched
+                .par_iter()
+                .map(|dest| {
+                    let data = read_working_file(working_directory, dest)?;
+                    score(&data, source_data)
+                })
+                .collect::<Result<Vec<_>, HgError>>()?;
+            for (dest_index, score) in scores.into_iter().enumerate() {
+                let best = &mut best[dest_index];
+                let best_score = best.map_or(threshold, |(_, score)| score);
+                if score > best_score {
+                    if best.is_none() {
+                        order.push(dest_index);
+                    }
+                    *best = Some((source_index, score));
+                }
+            }
+        }
+        for dest_index in order {
+            let (source_index, score) =
+                best[dest_index].expect("matched files have a best score");
+            renames.push(Rename {
+                source: removed_files[source_index].0.to_owned(),
+                dest: unmatched[dest_index].to_owned(),
+                score,
+            });
+        }
+    }
+
+    Ok(renames)
+}
+
+/// Returns the contents of a working directory file, or the target of a
+/// symlink.
+fn read_working_file(
+    working_directory: &Path,
+    path: &HgPath,
+) -> Result<Vec<u8>, HgError> {
+    let relative_path = hg_path_to_path_buf(path)?;
+    let fs_path = working_directory.join(&relative_path);
+    let metadata = std::fs::symlink_metadata(&fs_path)
+        .when_reading_file(&relative_path)?;
+    if metadata.is_symlink() {
+        let target =
+            std::fs::read_link(&fs_path).when_reading_file(&relative_path)?;
+        Ok(crate::utils::files::get_bytes_from_path(target))
+    } else {
+        Ok(std::fs::read(&fs_path).when_reading_file(&relative_path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score(b"a\nb\nc\n", b"a\nb\nc\n").unwrap(), 1.0);
+        assert_eq!(score(b"a\nb\n", b"c\nd\n").unwrap(), 0.0);
+        // Two bytes out of four match on each side
+        assert_eq!(score(b"a\nb\n", b"a\nc\n").unwrap(), 0.5);
+        // Only the lengths of the matching lines count
+        assert_eq!(score(b"same\nx\n", b"same\nyyyyyyy\n").unwrap(), 0.5);
+        assert_eq!(score(b"no newline", b"no newline").unwrap(), 1.0);
+    }
+}
@@ -316,7 +316,7 @@ fn patterns_for_rev(
                 config.includes.push(b'\n');
                 config.excludes.extend(&subconfig.excludes);
             }
-            profiles.extend(subconfig.profiles.into_iter());
+            profiles.extend(subconfig.profiles);
         }
 
         config.profiles = visited;
@@ -424,7 +424,7 @@ pub fn active_profiles(
     let mut profiles = FastHashSet::default();
     for rev in revs {
         if let Some(config) = patterns_for_rev(repo, rev, warnings)? {
-            profiles.extend(config.profiles.into_iter());
+            profiles.extend(config.profiles);
         }
     }
     Ok(profiles)
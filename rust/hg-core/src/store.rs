@@ -0,0 +1,349 @@
+//! The revlogs of the store of a repository, in `.hg/store`, like Python's
+//! `store.py`.
+
+pub mod fncache;
+
+use crate::errors::HgError;
+use crate::errors::IoResultExt;
+use crate::phases::PhaseCache;
+use crate::repo::Repo;
+use crate::revlog::RevlogType;
+use crate::revlog::path_encode::PathEncoding;
+use crate::revlog::path_encode::path_encode;
+use crate::utils::files::get_bytes_from_path;
+use crate::utils::files::get_path_from_bytes;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::vfs::VfsImpl;
+use crate::vfs::is_revlog_file;
+use fncache::Fncache;
+
+/// Extensions of revlog files that may have a `-ID` form, like
+/// `00changelog-1234abcd.nd`
+const LONG_EXTENSIONS: &[&[u8]] = &[b".nd", b".dat", b".idx", b".sda"];
+
+/// A revlog found by [`Store::walk`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreRevlog {
+    pub revlog_type: RevlogType,
+    /// The unencoded path of the files of the revlog without their
+    /// extension, like `data/foo.txt` or `00changelog`
+    pub radix: HgPathBuf,
+    /// The tracked file of a filelog, the directory of a tree manifest
+    /// with a trailing `/`, or empty for the changelog and root manifest
+    pub target: HgPathBuf,
+    /// The extensions of the files of the revlog, with `.i` last, unless
+    /// the store only lists the revlog itself
+    extensions: Option<Vec<Vec<u8>>>,
+}
+
+impl StoreRevlog {
+    /// The unencoded path of the index file of the revlog
+    pub fn index_path(&self) -> HgPathBuf {
+        HgPathBuf::from_bytes(&[self.radix.as_bytes(), b".i"].concat())
+    }
+
+    /// The unencoded paths of the files of the revlog, with the index last.
+    ///
+    /// When the store does not list them, these are the index and the data
+    /// file if it exists.
+    pub fn files(&self, store_vfs: &VfsImpl) -> Vec<HgPathBuf> {
+        let with_extension = |extension: &[u8]| {
+            HgPathBuf::from_bytes(&[self.radix.as_bytes(), extension].concat())
+        };
+        match &self.extensions {
+            Some(extensions) => {
+                extensions.iter().map(|ext| with_extension(ext)).collect()
+            }
+            None => {
+                let data_path = with_extension(b".d");
+                let encoded =
+                    path_encode(data_path.as_bytes(), store_vfs.encoding);
+                let mut files = vec![];
+                if store_vfs.join(get_path_from_bytes(&encoded)).exists() {
+                    files.push(data_path);
+                }
+                files.push(self.index_path());
+                files
+            }
+        }
+    }
+}
+
+/// The store of a repository
+pub struct Store<'repo> {
+    repo: &'repo Repo,
+}
+
+impl<'repo> Store<'repo> {
+    pub fn new(repo: &'repo Repo) -> Self {
+        Self { repo }
+    }
+
+    /// The store, without path encoding
+    fn raw_vfs(&self) -> VfsImpl {
+        VfsImpl::new(
+            self.repo.store_path().to_owned(),
+            false,
+            PathEncoding::None,
+        )
+    }
+
+    /// Reads the fncache of a store using the `fncache` requirement
+    pub fn fncache(&self) -> Result<Fncache, HgError> {
+        Fncache::read(self.raw_vfs())
+    }
+
+    /// Reads the roots of the phases of the store
+    pub fn phase_cache(&self) -> Result<PhaseCache, HgError> {
+        PhaseCache::read(self.raw_vfs(), &*self.repo.changelog()?)
+    }
+
+    /// Returns every revlog of the store, like Python's `store.walk`:
+    /// filelogs and tree manifests sorted by radix, from the fncache or the
+    /// file index, then the root manifest and the changelog.
+    pub fn walk(&self) -> Result<Vec<StoreRevlog>, HgError> {
+        let mut revlogs = self.data_revlogs()?;
+        revlogs.extend(self.top_revlogs()?);
+        Ok(revlogs)
+    }
+
+    /// The filelogs and tree manifests, sorted by radix, like Python's
+    /// `store.data_entries`
+    pub fn data_revlogs(&self) -> Result<Vec<StoreRevlog>, HgError> {
+        let revlogs = if self.repo.has_fileindex() {
+            let file_index = self.repo.file_index()?;
+            let mut revlogs = vec![];
+            for result in file_index.iter() {
+                let (path, _token) = result?;
+                revlogs.push(StoreRevlog {
+                    revlog_type: RevlogType::Filelog,
+                    radix: HgPathBuf::from_bytes(
+                        &[b"data/", path.as_bytes()].concat(),
+                    ),
+                    target: path.to_owned(),
+                    extensions: None,
+                });
+            }
+            revlogs.sort_unstable_by(|a, b| a.radix.cmp(&b.radix));
+            revlogs
+        } else {
+            let fncache = self.fncache()?;
+            let files = fncache.iter().filter(|path| {
+                is_revlog_file(get_path_from_bytes(path.as_bytes()))
+            });
+            group_by_revlog(files)
+                .into_iter()
+                .filter_map(|(radix, extensions)| {
+                    let (revlog_type, target) = data_revlog_target(&radix)?;
+                    Some(StoreRevlog {
+                        revlog_type,
+                        radix,
+                        target,
+                        extensions: Some(extensions),
+                    })
+                })
+                .collect()
+        };
+        Ok(revlogs)
+    }
+
+    /// The root manifest and the changelog, in that order
+    pub fn top_revlogs(&self) -> Result<Vec<StoreRevlog>, HgError> {
+        let store_path = self.repo.store_path();
+        let mut files = vec![];
+        for entry in
+            std::fs::read_dir(store_path).when_reading_file(store_path)?
+        {
+            let entry = entry.when_reading_file(store_path)?;
+            let is_file =
+                entry.file_type().when_reading_file(entry.path())?.is_file();
+            let name = get_bytes_from_path(entry.file_name());
+            let is_top_revlog = name.starts_with(b"00changelog")
+                || name.starts_with(b"00manifest");
+            if is_file && is_top_revlog && is_revlog_file(entry.file_name()) {
+                files.push(HgPathBuf::from_bytes(&name));
+            }
+        }
+        let revlogs = group_by_revlog(files.iter().map(|f| f.as_ref()));
+        let mut top_revlogs = vec![];
+        for (prefix, revlog_type) in [
+            (&b"00manifest"[..], RevlogType::Manifestlog),
+            (b"00changelog", RevlogType::Changelog),
+        ] {
+            for (radix, extensions) in &revlogs {
+                if radix.as_bytes().starts_with(prefix) {
+                    top_revlogs.push(StoreRevlog {
+                        revlog_type,
+                        radix: radix.to_owned(),
+                        target: HgPathBuf::new(),
+                        extensions: Some(extensions.to_owned()),
+                    });
+                }
+            }
+        }
+        Ok(top_revlogs)
+    }
+}
+
+/// The type and target of a revlog listed in the fncache
+fn data_revlog_target(radix: &HgPath) -> Option<(RevlogType, HgPathBuf)> {
+    let radix = radix.as_bytes();
+    if let Some(path) = radix.strip_prefix(b"data/") {
+        Some((RevlogType::Filelog, HgPathBuf::from_bytes(path)))
+    } else if let Some(path) = radix.strip_prefix(b"meta/") {
+        // Drop the `00manifest` file name, keeping the trailing slash
+        let directory_end = path.iter().rposition(|&byte| byte == b'/')?;
+        let directory = &path[..directory_end + 1];
+        Some((RevlogType::Manifestlog, HgPathBuf::from_bytes(directory)))
+    } else {
+        None
+    }
+}
+
+/// Groups the files of revlogs by radix, in sorted order, with their
+/// extensions sorted to have the index last, like Python's
+/// `store._gather_revlog`
+fn group_by_revlog<'a>(
+    files: impl Iterator<Item = &'a HgPath>,
+) -> Vec<(HgPathBuf, Vec<Vec<u8>>)> {
+    let mut revlogs = std::collections::BTreeMap::<_, Vec<_>>::new();
+    for file in files {
+        let (radix, extension) = split_extension(file.as_bytes());
+        revlogs
+            .entry(HgPathBuf::from_bytes(radix))
+            .or_default()
+            .push(extension.to_vec());
+    }
+    revlogs
+        .into_iter()
+        .map(|(radix, mut extensions)| {
+            extensions
+                .sort_unstable_by_key(|ext| (extension_rank(ext), ext.clone()));
+            extensions.dedup();
+            (radix, extensions)
+        })
+        .collect()
+}
+
+/// Splits the radix of a revlog file from its extension
+fn split_extension(file: &[u8]) -> (&[u8], &[u8]) {
+    let separator = if LONG_EXTENSIONS.iter().any(|ext| file.ends_with(ext)) {
+        b'-'
+    } else {
+        b'.'
+    };
+    match file.iter().rposition(|&byte| byte == separator) {
+        Some(index) => file.split_at(index),
+        None => (file, b""),
+    }
+}
+
+/// The order of the files of a revlog, which only matters for the index
+/// to be last, like Python's `store._ext_key`
+fn extension_rank(extension: &[u8]) -> u8 {
+    if extension.ends_with(b".n") {
+        0
+    } else if extension.ends_with(b".nd") {
+        10
+    } else if extension.ends_with(b".d") {
+        20
+    } else if extension.ends_with(b".i") {
+        50
+    } else {
+        40
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::list_all_tracked_files;
+    use crate::testing::TestRepo;
+    use crate::transaction::StoreTransaction;
+
+    #[test]
+    fn test_data_revlogs_from_file_index() {
+        let test_repo = TestRepo::new(&[
+            "format.use-fileindex-v1=yes",
+            "devel.fileindex.vacuum-mode=always",
+        ]);
+        let repo = &test_repo.repo;
+        assert!(repo.has_fileindex());
+        let mut file_index = repo.file_index().unwrap();
+        for path in ["dir/a", "b", "a"] {
+            file_index.add(HgPath::new(path)).unwrap();
+        }
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        file_index.write(&mut tr).unwrap();
+        tr.close().unwrap();
+        // Like in Python, writing the docket is up to the caller
+        let docket = file_index.docket().serialize();
+        std::fs::write(repo.store_path().join("fileindex"), docket).unwrap();
+
+        let revlogs = Store::new(repo).data_revlogs().unwrap();
+        let revlogs: Vec<_> = revlogs
+            .iter()
+            .map(|revlog| {
+                assert_eq!(revlog.revlog_type, RevlogType::Filelog);
+                (revlog.radix.as_bytes(), revlog.target.as_bytes())
+            })
+            .collect();
+        assert_eq!(
+            revlogs,
+            vec![
+                (&b"data/a"[..], &b"a"[..]),
+                (b"data/b", b"b"),
+                (b"data/dir/a", b"dir/a"),
+            ]
+        );
+        let files = list_all_tracked_files(repo).unwrap();
+        let files: Vec<_> = files.iter().map(|f| f.as_bytes()).collect();
+        assert_eq!(files, [&b"a"[..], b"b", b"dir/a"]);
+    }
+
+    #[test]
+    fn test_group_by_revlog() {
+        let files = [
+            "data/b.i",
+            "data/a.txt.i",
+            "data/a.txt.d",
+            "meta/dir/00manifest.i",
+            "00changelog-1234.nd",
+            "00changelog.n",
+            "00changelog.i",
+        ];
+        let grouped =
+            group_by_revlog(files.iter().map(|f| HgPath::new(f.as_bytes())));
+        let grouped: Vec<_> = grouped
+            .iter()
+            .map(|(radix, extensions)| {
+                let extensions: Vec<_> =
+                    extensions.iter().map(|e| e.as_slice()).collect();
+                (radix.as_bytes(), extensions)
+            })
+            .collect();
+        assert_eq!(
+            grouped,
+            vec![
+                (&b"00changelog"[..], vec![&b".n"[..], b"-1234.nd", b".i"]),
+                (b"data/a.txt", vec![b".d", b".i"]),
+                (b"data/b", vec![b".i"]),
+                (b"meta/dir/00manifest", vec![b".i"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_revlog_target() {
+        assert_eq!(
+            data_revlog_target(HgPath::new(b"data/dir/a.txt")),
+            Some((RevlogType::Filelog, HgPathBuf::from_bytes(b"dir/a.txt")))
+        );
+        assert_eq!(
+            data_revlog_target(HgPath::new(b"meta/dir/sub/00manifest")),
+            Some((RevlogType::Manifestlog, HgPathBuf::from_bytes(b"dir/sub/")))
+        );
+        assert_eq!(data_revlog_target(HgPath::new(b"undo")), None);
+    }
+}
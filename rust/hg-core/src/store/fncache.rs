@@ -0,0 +1,338 @@
+//! The `fncache` file, listing the filelogs and tree manifests of a store
+//! using the `fncache` requirement, like Python's `store.fncache`.
+//!
+//! Each line is the unencoded path of a file of a revlog, like
+//! `data/foo.txt.i`, with directories that look like revlog files escaped
+//! by [`encode_dir`].
+
+use std::fmt;
+
+use crate::FastHashSet;
+use crate::errors::HgError;
+use crate::exit_codes;
+use crate::transaction::Transaction;
+use crate::utils::hg_path::HgPath;
+use crate::utils::hg_path::HgPathBuf;
+use crate::utils::strings::replace_slice;
+use crate::vfs::VfsImpl;
+
+const FNCACHE_PATH: &str = "fncache";
+
+/// A problem found while reading the fncache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FncacheProblem {
+    /// The last entry is truncated, and ignored
+    MissingFinalNewline,
+    /// An empty line, counting from 1
+    EmptyEntry { line: usize },
+}
+
+impl fmt::Display for FncacheProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FncacheProblem::MissingFinalNewline => {
+                write!(f, "fncache does not ends with a newline")
+            }
+            FncacheProblem::EmptyEntry { line } => {
+                write!(f, "invalid entry in fncache, line {}", line)
+            }
+        }
+    }
+}
+
+/// The entries of the fncache of a store, with the changes to write
+pub struct Fncache {
+    /// The store, without path encoding
+    vfs: VfsImpl,
+    entries: FastHashSet<HgPathBuf>,
+    /// Entries added since the last write, not in `entries`
+    added: FastHashSet<HgPathBuf>,
+    /// Whether entries were removed since the last write, which requires
+    /// rewriting the whole file instead of appending to it
+    dirty: bool,
+}
+
+impl Fncache {
+    /// Reads the fncache of the store of `vfs`, which does not encode
+    /// paths. A missing file has no entries.
+    ///
+    /// Aborts if the file is corrupted, like Python.
+    pub fn read(vfs: VfsImpl) -> Result<Self, HgError> {
+        let (fncache, problems) = Self::read_with_problems(vfs)?;
+        match problems.first() {
+            None => Ok(fncache),
+            Some(problem @ FncacheProblem::MissingFinalNewline) => {
+                Err(HgError::abort(
+                    problem.to_string(),
+                    exit_codes::ABORT,
+                    Some(
+                        "use 'hg debugrebuildfncache' to rebuild the fncache"
+                            .to_string(),
+                    ),
+                ))
+            }
+            Some(problem) => Err(HgError::abort_simple(problem.to_string())),
+        }
+    }
+
+    /// Like [`Self::read`], but returns the problems of a corrupted file
+    /// instead of failing, for `verify`
+    pub fn read_with_problems(
+        vfs: VfsImpl,
+    ) -> Result<(Self, Vec<FncacheProblem>), HgError> {
+        let data = vfs.try_read(FNCACHE_PATH)?.unwrap_or_default();
+        let (entries, problems) = parse(&data);
+        let fncache =
+            Self { vfs, entries, added: FastHashSet::default(), dirty: false };
+        Ok((fncache, problems))
+    }
+
+    pub fn contains(&self, path: &HgPath) -> bool {
+        self.entries.contains(path) || self.added.contains(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.added.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the entries, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &HgPath> {
+        self.entries.iter().chain(&self.added).map(|path| path.as_ref())
+    }
+
+    /// Records a new file of a revlog, unless it is already listed
+    pub fn add(&mut self, path: &HgPath) {
+        if !self.entries.contains(path) {
+            self.added.insert(path.to_owned());
+        }
+    }
+
+    /// Removes the entry of a file that no longer exists. Returns whether
+    /// it was listed.
+    pub fn remove(&mut self, path: &HgPath) -> bool {
+        if self.added.remove(path) {
+            return true;
+        }
+        let removed = self.entries.remove(path);
+        self.dirty |= removed;
+        removed
+    }
+
+    /// Writes the changes since the last write, backing up the file in `tr`.
+    ///
+    /// New entries are appended, but removing entries rewrites the whole
+    /// file. Both are atomic: readers never see a partial write.
+    ///
+    /// The caller must hold the store lock.
+    pub fn write(&mut self, tr: &mut impl Transaction) -> Result<(), HgError> {
+        if !self.dirty && self.added.is_empty() {
+            return Ok(());
+        }
+        tr.add_backup(FNCACHE_PATH);
+        let contents = if self.dirty {
+            self.entries.extend(self.added.drain());
+            serialize(&self.entries)
+        } else {
+            let mut contents =
+                self.vfs.try_read(FNCACHE_PATH)?.unwrap_or_default();
+            contents.extend(serialize(&self.added));
+            self.entries.extend(self.added.drain());
+            contents
+        };
+        self.vfs.atomic_write(FNCACHE_PATH, &contents)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Parses the contents of the fncache, skipping invalid entries
+fn parse(data: &[u8]) -> (FastHashSet<HgPathBuf>, Vec<FncacheProblem>) {
+    let mut problems = vec![];
+    let complete = match data.iter().rposition(|&byte| byte == b'\n') {
+        Some(last_newline) => &data[..last_newline + 1],
+        None => &[][..],
+    };
+    if complete.len() != data.len() {
+        problems.push(FncacheProblem::MissingFinalNewline);
+    }
+    let mut entries = FastHashSet::default();
+    let lines = complete.split(|&byte| byte == b'\n');
+    let line_count = complete.iter().filter(|&&byte| byte == b'\n').count();
+    for (index, line) in lines.take(line_count).enumerate() {
+        if line.is_empty() {
+            problems.push(FncacheProblem::EmptyEntry { line: index + 1 });
+        } else {
+            entries.insert(HgPathBuf::from_bytes(&decode_dir(line)));
+        }
+    }
+    (entries, problems)
+}
+
+/// Serializes entries in sorted order, one per line
+fn serialize(entries: &FastHashSet<HgPathBuf>) -> Vec<u8> {
+    let mut sorted: Vec<_> = entries.iter().collect();
+    sorted.sort_unstable();
+    let mut contents = vec![];
+    for entry in sorted {
+        contents.extend(encode_dir(entry.as_bytes()));
+        contents.push(b'\n');
+    }
+    contents
+}
+
+/// Escapes the directories that look like store files, like Python's
+/// `store.encodedir`
+pub fn encode_dir(path: &[u8]) -> Vec<u8> {
+    let mut encoded = path.to_vec();
+    for (from, to) in [
+        (&b".hg/"[..], &b".hg.hg/"[..]),
+        (b".i/", b".i.hg/"),
+        (b".d/", b".d.hg/"),
+    ] {
+        encoded = replace_slice(&encoded, from, to);
+    }
+    encoded
+}
+
+/// Undoes [`encode_dir`], like Python's `store.decodedir`
+pub fn decode_dir(path: &[u8]) -> Vec<u8> {
+    let mut decoded = path.to_vec();
+    if !path.windows(4).any(|window| window == b".hg/") {
+        return decoded;
+    }
+    for (from, to) in [
+        (&b".d.hg/"[..], &b".d/"[..]),
+        (b".i.hg/", b".i/"),
+        (b".hg.hg/", b".hg/"),
+    ] {
+        decoded = replace_slice(&decoded, from, to);
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::revlog::path_encode::PathEncoding;
+
+    struct BackupTransaction {
+        backups: Vec<std::path::PathBuf>,
+    }
+
+    impl Transaction for BackupTransaction {
+        fn add(&mut self, _file: impl AsRef<Path>, _offset: usize) {}
+
+        fn add_backup(&mut self, file: impl AsRef<Path>) {
+            self.backups.push(file.as_ref().to_owned());
+        }
+    }
+
+    fn sorted(fncache: &Fncache) -> Vec<&[u8]> {
+        let mut entries: Vec<_> =
+            fncache.iter().map(|p| p.as_bytes()).collect();
+        entries.sort_unstable();
+        entries
+    }
+
+    #[test]
+    fn test_encode_decode_dir() {
+        assert_eq!(encode_dir(b"data/foo.i"), b"data/foo.i");
+        assert_eq!(encode_dir(b"data/foo.i/bla.i"), b"data/foo.i.hg/bla.i");
+        assert_eq!(
+            encode_dir(b"data/foo.i.hg/bla.i"),
+            b"data/foo.i.hg.hg/bla.i"
+        );
+        assert_eq!(decode_dir(b"data/foo.i"), b"data/foo.i");
+        assert_eq!(decode_dir(b"data/foo.i.hg/bla.i"), b"data/foo.i/bla.i");
+        assert_eq!(
+            decode_dir(b"data/foo.i.hg.hg/bla.i"),
+            b"data/foo.i.hg/bla.i"
+        );
+        assert_eq!(decode_dir(b"a.i.hg/b.d.hg/c"), b"a.i/b.d/c");
+        assert_eq!(decode_dir(b"x.hg.hg/y"), b"x.hg/y");
+    }
+
+    #[test]
+    fn test_parse() {
+        let (entries, problems) = parse(b"data/a.i\ndata/b.d.hg/c.i\n");
+        assert!(problems.is_empty());
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains(HgPath::new(b"data/b.d/c.i")));
+
+        let (entries, problems) = parse(b"data/a.i\n\ndata/b.i");
+        assert_eq!(
+            problems,
+            vec![
+                FncacheProblem::MissingFinalNewline,
+                FncacheProblem::EmptyEntry { line: 2 }
+            ]
+        );
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn test_read_corrupted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let vfs =
+            VfsImpl::new(temp_dir.path().to_owned(), false, PathEncoding::None);
+        std::fs::write(temp_dir.path().join("fncache"), b"data/a.i").unwrap();
+        let error = Fncache::read(vfs).err().unwrap();
+        assert!(error.to_string().contains("does not ends with a newline"));
+    }
+
+    #[test]
+    fn test_write() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("fncache");
+        let vfs =
+            VfsImpl::new(temp_dir.path().to_owned(), false, PathEncoding::None);
+        let mut tr = BackupTransaction { backups: vec![] };
+
+        let mut fncache = Fncache::read(vfs.clone()).unwrap();
+        assert!(fncache.is_empty());
+        fncache.write(&mut tr).unwrap();
+        assert!(tr.backups.is_empty());
+        assert!(!path.exists());
+
+        fncache.add(HgPath::new(b"data/b.i"));
+        fncache.add(HgPath::new(b"data/a.i/c.i"));
+        fncache.write(&mut tr).unwrap();
+        assert_eq!(tr.backups.len(), 1);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"data/a.i.hg/c.i\ndata/b.i\n"
+        );
+
+        // Appending keeps the existing lines as they are
+        fncache.add(HgPath::new(b"data/a.i"));
+        fncache.add(HgPath::new(b"data/b.i"));
+        fncache.write(&mut tr).unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"data/a.i.hg/c.i\ndata/b.i\ndata/a.i\n"
+        );
+
+        // Removing rewrites the file
+        assert!(fncache.remove(HgPath::new(b"data/b.i")));
+        assert!(!fncache.remove(HgPath::new(b"data/z.i")));
+        fncache.add(HgPath::new(b"data/z.i"));
+        fncache.write(&mut tr).unwrap();
+        assert_eq!(tr.backups.len(), 3);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"data/a.i\ndata/a.i.hg/c.i\ndata/z.i\n"
+        );
+
+        let fncache = Fncache::read(vfs).unwrap();
+        assert_eq!(
+            sorted(&fncache),
+            [&b"data/a.i"[..], b"data/a.i/c.i", b"data/z.i"]
+        );
+    }
+}
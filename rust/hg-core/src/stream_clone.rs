@@ -0,0 +1,810 @@
+//! Stream clones, which copy the files of a store as they are instead of
+//! exchanging changegroups, like Python's `streamclone.py`.
+//!
+//! The `v2` format is a sequence of files, whose count and total size are
+//! given by the parameters of the `stream2` bundle2 part. Each file is:
+//!
+//! - a byte for its destination, `s` for the store and `c` for the caches,
+//! - the size of its name, then of its content, as unsigned varints,
+//! - its unencoded name, then its content.
+//!
+//! The experimental `v3` format starts with the number of entries as a
+//! varint. Each entry is the number of its files as a varint, followed by
+//! the files in the `v2` format.
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::FastHashSet;
+use crate::changegroup::read_exactly;
+use crate::changegroup::write_all;
+use crate::errors::HgError;
+use crate::errors::HgIoError;
+use crate::errors::IoErrorContext;
+use crate::errors::IoResultExt;
+use crate::exit_codes;
+use crate::repo::Repo;
+use crate::requirements::NARROW_REQUIREMENT;
+use crate::requirements::NODEMAP_REQUIREMENT;
+use crate::requirements::REVLOGV1_REQUIREMENT;
+use crate::requirements::STREAM_IGNORABLE_REQUIREMENTS;
+use crate::revlog::path_encode::PathEncoding;
+use crate::revlog::path_encode::path_encode;
+use crate::store::StoreRevlog;
+use crate::store::fncache::Fncache;
+use crate::transaction::StoreTransaction;
+use crate::transaction::Transaction;
+use crate::utils::files::get_bytes_from_path;
+use crate::utils::files::get_path_from_bytes;
+use crate::utils::hg_path::HgPath;
+use crate::vfs::Vfs;
+use crate::vfs::VfsImpl;
+
+/// Size of the buffer used to copy the content of files
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The repository views whose branch and tags caches are copied, like
+/// `repoviewutil.get_ordered_subset` in Python
+const CACHE_SUBSETS: &[&str] = &["base", "immutable", "served", "visible"];
+
+/// The version of the format of a stream clone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StreamVersion {
+    V2,
+    V3Exp,
+}
+
+impl StreamVersion {
+    /// Parses the `stream` parameter of a bundle specification
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "v2" => Some(Self::V2),
+            "v3-exp" => Some(Self::V3Exp),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V2 => "v2",
+            Self::V3Exp => "v3-exp",
+        }
+    }
+}
+
+/// Where a streamed file goes in the destination repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Destination {
+    Store,
+    Cache,
+}
+
+impl Destination {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b's' => Some(Self::Store),
+            b'c' => Some(Self::Cache),
+            _ => None,
+        }
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            Self::Store => b's',
+            Self::Cache => b'c',
+        }
+    }
+}
+
+/// Whether `repo` lets stream clones of itself be generated, like
+/// `allowservergeneration` in Python
+pub fn allows_generation(repo: &Repo) -> Result<bool, HgError> {
+    let config = repo.config();
+    if !config.get_bool(b"server", b"uncompressed")? {
+        return Ok(false);
+    }
+    // Stream clones cannot hide secret changesets
+    if repo.store().phase_cache()?.has_secret() {
+        return config.get_bool(b"server", b"uncompressedallowsecret");
+    }
+    Ok(true)
+}
+
+/// Checks that a stream clone of `repo` in `version` can be generated,
+/// with the errors of `addpartbundlestream2` in Python
+pub fn check_generation(
+    repo: &Repo,
+    version: StreamVersion,
+) -> Result<(), HgError> {
+    if !allows_generation(repo)? {
+        return Err(HgError::abort(
+            "stream data requested but server does not allow this feature",
+            exit_codes::ABORT,
+            Some("the client seems buggy".to_owned()),
+        ));
+    }
+    let supported = supported_versions(repo)?;
+    if !supported.contains(&version) {
+        let supported: Vec<_> =
+            supported.iter().map(|version| version.name()).collect();
+        return Err(HgError::abort_simple(format!(
+            "no common supported version with the client: {}; {}",
+            supported.join(","),
+            version.name()
+        )));
+    }
+    Ok(())
+}
+
+/// The versions of stream clones that `repo` can generate, in increasing
+/// order
+pub fn supported_versions(repo: &Repo) -> Result<Vec<StreamVersion>, HgError> {
+    let mut versions = vec![StreamVersion::V2];
+    if repo.config().get_bool(b"experimental", b"stream-v3")? {
+        versions.push(StreamVersion::V3Exp);
+    }
+    Ok(versions)
+}
+
+/// The requirements that a stream clone of a repository with `requirements`
+/// imposes on its destination, sorted, like `streamed_requirements` in
+/// Python
+pub fn streamed_requirements(
+    requirements: &FastHashSet<String>,
+) -> Vec<String> {
+    let mut streamed: Vec<_> = requirements
+        .iter()
+        .filter(|requirement| {
+            !STREAM_IGNORABLE_REQUIREMENTS.contains(&requirement.as_str())
+        })
+        .cloned()
+        .collect();
+    streamed.sort_unstable();
+    streamed
+}
+
+/// The requirements of a repository receiving a stream clone, keeping the
+/// local choices of `default` that the stream does not constrain, like
+/// `new_stream_clone_requirements` in Python
+pub fn new_requirements(
+    default: &FastHashSet<String>,
+    streamed: &[String],
+) -> FastHashSet<String> {
+    default
+        .iter()
+        .filter(|requirement| {
+            STREAM_IGNORABLE_REQUIREMENTS.contains(&requirement.as_str())
+                || *requirement == NARROW_REQUIREMENT
+        })
+        .chain(streamed)
+        .cloned()
+        .collect()
+}
+
+/// A file to stream
+struct StreamFile {
+    destination: Destination,
+    /// The name sent in the stream, not encoded
+    name: Vec<u8>,
+    path: PathBuf,
+    /// The size of the file when it was listed
+    size: u64,
+    /// A handle opened when the file was listed, for files that may be
+    /// replaced afterwards. It keeps their content consistent with `size`.
+    handle: Option<File>,
+}
+
+impl StreamFile {
+    /// A file that is only appended to, whose first `size` bytes do not
+    /// change
+    fn append_only(
+        destination: Destination,
+        name: &[u8],
+        path: PathBuf,
+    ) -> Result<Self, HgError> {
+        let size = std::fs::metadata(&path).when_reading_file(&path)?.len();
+        Ok(Self {
+            destination,
+            name: name.to_owned(),
+            path,
+            size,
+            handle: None,
+        })
+    }
+
+    /// A file that may be replaced, like `VolatileManager` preserves in
+    /// Python. Returns `None` if it does not exist.
+    fn volatile(
+        destination: Destination,
+        name: &[u8],
+        path: PathBuf,
+    ) -> Result<Option<Self>, HgError> {
+        let handle = match File::open(&path) {
+            Ok(handle) => handle,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    ErrorKind::NotFound | ErrorKind::PermissionDenied
+                ) =>
+            {
+                return Ok(None);
+            }
+            Err(error) => Err(error).when_reading_file(&path)?,
+        };
+        let size = handle.metadata().when_reading_file(&path)?.len();
+        Ok(Some(Self {
+            destination,
+            name: name.to_owned(),
+            path,
+            size,
+            handle: Some(handle),
+        }))
+    }
+
+    /// Writes the header and the content of the file to `out`
+    fn write(&self, out: &mut impl Write) -> Result<(), HgError> {
+        write_all(out, &[self.destination.byte()])?;
+        write_uvarint(out, self.name.len() as u64)?;
+        write_uvarint(out, self.size)?;
+        write_all(out, &self.name)?;
+        let mut file = match &self.handle {
+            Some(handle) => {
+                let mut handle =
+                    handle.try_clone().when_reading_file(&self.path)?;
+                handle
+                    .seek(SeekFrom::Start(0))
+                    .when_reading_file(&self.path)?;
+                handle
+            }
+            None => File::open(&self.path).when_reading_file(&self.path)?,
+        }
+        .take(self.size);
+        let mut buffer = vec![0; COPY_BUFFER_SIZE];
+        let mut copied = 0;
+        loop {
+            let read = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(error) => Err(error).when_reading_file(&self.path)?,
+            };
+            write_all(out, &buffer[..read])?;
+            copied += read as u64;
+        }
+        if copied != self.size {
+            // Most likely a race with a strip or the split of an inline
+            // revlog
+            return Err(HgError::abort_simple(format!(
+                "clone could only read {} bytes from {}, but expected {} \
+                 bytes",
+                copied,
+                String::from_utf8_lossy(&self.name),
+                self.size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The files of a repository to stream, listed under its store lock.
+///
+/// The sizes of the files are recorded when listing them, and only that
+/// much is sent. Revlogs being append-only, this gives a consistent view of
+/// the store once the lock is released, except for the files that may be
+/// replaced, which are kept open.
+pub struct StreamGenerator {
+    /// The files of each entry, a revlog or a single file, none of them
+    /// empty
+    entries: Vec<Vec<StreamFile>>,
+}
+
+impl StreamGenerator {
+    /// Lists the files of the store and the caches of `repo`, in the order
+    /// of Python's `_entries_walk`. The obsolescence markers are only
+    /// included if `include_obsmarkers` is set.
+    ///
+    /// The caller must hold the store lock.
+    pub fn new(repo: &Repo, include_obsmarkers: bool) -> Result<Self, HgError> {
+        let store = repo.store();
+        let store_vfs = repo.store_vfs();
+        let mut entries = vec![];
+        for revlog in store.data_revlogs()? {
+            entries.push(revlog_files(&store_vfs, &revlog)?);
+        }
+        let publishing = repo.config().get_bool(b"phases", b"publish")?;
+        for (name, wanted) in
+            [("phaseroots", !publishing), ("obsstore", include_obsmarkers)]
+        {
+            if !wanted {
+                continue;
+            }
+            let path = store_vfs.join(name);
+            let file = StreamFile::volatile(
+                Destination::Store,
+                name.as_bytes(),
+                path,
+            )?;
+            entries.extend(file.map(|file| vec![file]));
+        }
+        for revlog in store.top_revlogs()? {
+            entries.push(revlog_files(&store_vfs, &revlog)?);
+        }
+        let cache_vfs = repo.cache_vfs();
+        for name in cache_files() {
+            let path = cache_vfs.join(&name);
+            let file = StreamFile::volatile(
+                Destination::Cache,
+                name.as_bytes(),
+                path,
+            )?;
+            entries.extend(file.map(|file| vec![file]));
+        }
+        Ok(Self { entries })
+    }
+
+    /// The number of files to stream
+    pub fn file_count(&self) -> u64 {
+        self.entries.iter().map(|files| files.len() as u64).sum()
+    }
+
+    /// The total size of the files to stream
+    pub fn byte_count(&self) -> u64 {
+        self.entries.iter().flatten().map(|file| file.size).sum()
+    }
+
+    /// The number of entries of a `v3` stream
+    pub fn entry_count(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    /// Writes the files in the `v2` format, like `generatev2` in Python
+    pub fn write_v2(&self, out: &mut impl Write) -> Result<(), HgError> {
+        for file in self.entries.iter().flatten() {
+            file.write(out)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the files in the `v3` format, like `generatev3` in Python
+    pub fn write_v3(&self, out: &mut impl Write) -> Result<(), HgError> {
+        write_uvarint(out, self.entry_count())?;
+        for files in &self.entries {
+            write_uvarint(out, files.len() as u64)?;
+            for file in files {
+                file.write(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the files into the empty repository `dest`, hardlinking them
+    /// while possible, like `_copy_files` in Python for local clones. The
+    /// revlogs copied are added to `fncache`, the one of `dest` if it uses
+    /// one.
+    ///
+    /// Unlike when streaming, the files are copied whole: the caller must
+    /// still hold the store lock of the source.
+    pub fn copy_to(
+        &self,
+        dest: &Repo,
+        mut fncache: Option<&mut Fncache>,
+    ) -> Result<(), HgError> {
+        let store_vfs = dest.store_vfs();
+        let cache_vfs = dest.cache_vfs();
+        let mut hardlink = true;
+        for file in self.entries.iter().flatten() {
+            let to = match file.destination {
+                Destination::Store => {
+                    if let Some(fncache) = &mut fncache
+                        && is_fncache_file(&file.name)
+                    {
+                        fncache.add(HgPath::new(&file.name));
+                    }
+                    store_vfs.join(encode_store_path(&store_vfs, &file.name))
+                }
+                Destination::Cache => {
+                    cache_vfs.join(get_path_from_bytes(&file.name))
+                }
+            };
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent).when_writing_file(parent)?;
+            }
+            if hardlink && std::fs::hard_link(&file.path, &to).is_ok() {
+                continue;
+            }
+            hardlink = false;
+            match std::fs::copy(&file.path, &to) {
+                Ok(_) => {}
+                // The files that may be replaced may also be removed
+                Err(error)
+                    if error.kind() == ErrorKind::NotFound
+                        && file.handle.is_some() => {}
+                Err(error) => {
+                    return Err(error).with_context(|| {
+                        IoErrorContext::CopyingFile {
+                            from: file.path.to_owned(),
+                            to,
+                        }
+                    })?;
+                }
+            }
+        }
+        tracing::debug!(
+            "{} {} files",
+            if hardlink { "linked" } else { "copied" },
+            self.file_count()
+        );
+        Ok(())
+    }
+}
+
+/// The files of `revlog`, whose nodemap files may be replaced
+fn revlog_files(
+    store_vfs: &VfsImpl,
+    revlog: &StoreRevlog,
+) -> Result<Vec<StreamFile>, HgError> {
+    let mut files = vec![];
+    for name in revlog.files(store_vfs) {
+        let name = name.as_bytes();
+        let path = store_vfs.join(encode_store_path(store_vfs, name));
+        if name.ends_with(b".n") || name.ends_with(b".nd") {
+            let file =
+                StreamFile::volatile(Destination::Store, name, path.clone())?;
+            let file = file.ok_or_else(|| {
+                HgError::abort_simple(format!(
+                    "nodemap file disappeared during stream clone: {}",
+                    path.display()
+                ))
+            })?;
+            files.push(file);
+        } else {
+            files.push(StreamFile::append_only(
+                Destination::Store,
+                name,
+                path,
+            )?);
+        }
+    }
+    Ok(files)
+}
+
+/// The caches worth copying in a clone, like `cacheutil.cachetocopy` in
+/// Python
+fn cache_files() -> Vec<String> {
+    let mut files = vec![];
+    for base in ["branch2", "branch3"] {
+        files.push(base.to_owned());
+        files.extend(
+            CACHE_SUBSETS.iter().map(|subset| format!("{}-{}", base, subset)),
+        );
+    }
+    files.push("rbc-names-v2".to_owned());
+    files.push("rbc-revs-v2".to_owned());
+    files.push("tags2".to_owned());
+    files
+        .extend(CACHE_SUBSETS.iter().map(|subset| format!("tags2-{}", subset)));
+    files.push("hgtagsfnodes1".to_owned());
+    files
+}
+
+/// The path in the store of the file named `name`
+fn encode_store_path(store_vfs: &VfsImpl, name: &[u8]) -> PathBuf {
+    match store_vfs.encoding {
+        PathEncoding::None => get_path_from_bytes(name).to_owned(),
+        encoding => {
+            get_path_from_bytes(&path_encode(name, encoding)).to_owned()
+        }
+    }
+}
+
+/// Writes the files of a stream clone into an empty repository, like
+/// `consumev2` and `consumev3` in Python.
+///
+/// The new files of the store are journaled in the transaction, so that
+/// rolling it back removes them. The caches are not.
+pub struct StreamApplier<'a> {
+    store_vfs: VfsImpl,
+    cache_vfs: VfsImpl,
+    tr: &'a mut StoreTransaction,
+    fncache: Option<&'a mut Fncache>,
+    file_count: u64,
+    byte_count: u64,
+}
+
+impl<'a> StreamApplier<'a> {
+    /// Applies stream clones to `repo`, whose store lists its revlogs in
+    /// `fncache`, if it uses one
+    pub fn new(
+        repo: &Repo,
+        tr: &'a mut StoreTransaction,
+        fncache: Option<&'a mut Fncache>,
+    ) -> Result<Self, HgError> {
+        if repo.has_fileindex() {
+            return Err(HgError::unsupported(
+                "stream clone into a store with a file index",
+            ));
+        }
+        Ok(Self {
+            store_vfs: repo.store_vfs(),
+            cache_vfs: repo.cache_vfs(),
+            tr,
+            fncache,
+            file_count: 0,
+            byte_count: 0,
+        })
+    }
+
+    /// The number of files written so far
+    pub fn file_count(&self) -> u64 {
+        self.file_count
+    }
+
+    /// The total size of the files written so far
+    pub fn byte_count(&self) -> u64 {
+        self.byte_count
+    }
+
+    /// Writes the `file_count` files of a `v2` stream
+    pub fn apply_v2(
+        &mut self,
+        reader: &mut impl Read,
+        file_count: u64,
+    ) -> Result<(), HgError> {
+        for _ in 0..file_count {
+            self.apply_file(reader)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the `entry_count` entries of a `v3` stream, read beforehand
+    /// with [`read_entry_count`]
+    pub fn apply_v3(
+        &mut self,
+        reader: &mut impl Read,
+        entry_count: u64,
+    ) -> Result<(), HgError> {
+        for _ in 0..entry_count {
+            let file_count = read_uvarint(reader)?;
+            self.apply_v2(reader, file_count)?;
+        }
+        Ok(())
+    }
+
+    fn apply_file(&mut self, reader: &mut impl Read) -> Result<(), HgError> {
+        let destination = read_exactly(reader, 1)?[0];
+        let destination =
+            Destination::from_byte(destination).ok_or_else(|| {
+                HgError::abort_simple(format!(
+                    "invalid stream clone destination: {}",
+                    String::from_utf8_lossy(&[destination])
+                ))
+            })?;
+        let name_length = read_uvarint(reader)?;
+        let size = read_uvarint(reader)?;
+        let name = read_exactly(reader, name_length as usize)?;
+        check_streamed_name(&name)?;
+        let mut file = match destination {
+            Destination::Store => {
+                if let Some(fncache) = &mut self.fncache
+                    && is_fncache_file(&name)
+                {
+                    fncache.add(HgPath::new(&name));
+                }
+                let path = encode_store_path(&self.store_vfs, &name);
+                self.tr.add_name(&path, &name);
+                self.tr.add(&path, 0);
+                self.store_vfs.create(&path, false)?
+            }
+            Destination::Cache => {
+                self.cache_vfs.create(get_path_from_bytes(&name), false)?
+            }
+        };
+        let path = file.path().into_owned();
+        let mut left = size;
+        let mut buffer = vec![0; COPY_BUFFER_SIZE.min(size as usize)];
+        while left > 0 {
+            let wanted = buffer.len().min(left as usize);
+            let read = match reader.read(&mut buffer[..wanted]) {
+                Ok(0) => {
+                    return Err(HgError::abort_simple(format!(
+                        "stream ended unexpectedly (got {} bytes, expected \
+                         {})",
+                        size - left,
+                        size
+                    )));
+                }
+                Ok(read) => read,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(error) => {
+                    return Err(match error.downcast::<HgError>() {
+                        Ok(error) => error,
+                        Err(error) => HgIoError::from_os_error(
+                            error,
+                            IoErrorContext::ReadingStream,
+                        )
+                        .into(),
+                    });
+                }
+            };
+            file.write_all(&buffer[..read]).when_writing_file(&path)?;
+            left -= read as u64;
+        }
+        self.file_count += 1;
+        self.byte_count += size;
+        Ok(())
+    }
+}
+
+/// Reads the number of entries that starts a `v3` stream
+pub fn read_entry_count(reader: &mut impl Read) -> Result<u64, HgError> {
+    read_uvarint(reader)
+}
+
+/// Removes the persistent nodemap files of the changelog and the manifest
+/// streamed into a store whose `requirements` do not use them, like
+/// `nodemap.post_stream_cleanup` in Python
+pub fn post_stream_cleanup(
+    repo: &Repo,
+    requirements: &FastHashSet<String>,
+) -> Result<(), HgError> {
+    if !requirements.contains(REVLOGV1_REQUIREMENT)
+        || requirements.contains(NODEMAP_REQUIREMENT)
+    {
+        return Ok(());
+    }
+    let store_path = repo.store_path();
+    for entry in std::fs::read_dir(store_path).when_reading_file(store_path)? {
+        let entry = entry.when_reading_file(store_path)?;
+        let name = get_bytes_from_path(entry.file_name());
+        if is_nodemap_file(&name, b"00changelog")
+            || is_nodemap_file(&name, b"00manifest")
+        {
+            let path = entry.path();
+            std::fs::remove_file(&path).when_writing_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` is a file of the persistent nodemap of the revlog
+/// `radix`, like the pattern of `nodemap.delete_nodemap` in Python
+fn is_nodemap_file(name: &[u8], radix: &[u8]) -> bool {
+    let Some(suffix) = name.strip_prefix(radix) else {
+        return false;
+    };
+    if suffix == b".n" || suffix == b".n.a" {
+        return true;
+    }
+    suffix
+        .strip_prefix(b"-")
+        .and_then(|suffix| suffix.strip_suffix(b".nd"))
+        .is_some_and(|id| {
+            !id.is_empty()
+                && id
+                    .iter()
+                    .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+        })
+}
+
+/// Whether the fncache lists the store file `name`, like `RE_FNCACHE_FILE`
+/// in Python
+fn is_fncache_file(name: &[u8]) -> bool {
+    (name.starts_with(b"data/") || name.starts_with(b"meta/"))
+        && (name.ends_with(b".i") || name.ends_with(b".d"))
+}
+
+/// Refuses the names that would write outside of the store or the caches,
+/// like the path auditor of the vfs does in Python
+fn check_streamed_name(name: &[u8]) -> Result<(), HgError> {
+    let valid = !name.contains(&0)
+        && name.split(|&byte| byte == b'/').all(|component| {
+            !matches!(component, b"" | b"." | b"..")
+                && !component.eq_ignore_ascii_case(b".hg")
+        });
+    if !valid {
+        return Err(HgError::abort_simple(format!(
+            "path contains illegal component: {}",
+            String::from_utf8_lossy(name)
+        )));
+    }
+    Ok(())
+}
+
+/// Writes `value` as an unsigned varint, like `uvarintencode` in Python
+fn write_uvarint(out: &mut impl Write, mut value: u64) -> Result<(), HgError> {
+    let mut bytes = vec![];
+    while value >= 0x80 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+    write_all(out, &bytes)
+}
+
+/// Reads an unsigned varint, like `uvarintdecodestream` in Python
+fn read_uvarint(reader: &mut impl Read) -> Result<u64, HgError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_exactly(reader, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(HgError::abort_simple("invalid varint in stream clone"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uvarint() {
+        for (value, encoded) in [
+            (0, &b"\x00"[..]),
+            (1, b"\x01"),
+            (127, b"\x7f"),
+            (128, b"\x80\x01"),
+            (1337, b"\xb9\x0a"),
+            (65536, b"\x80\x80\x04"),
+        ] {
+            let mut out = vec![];
+            write_uvarint(&mut out, value).unwrap();
+            assert_eq!(out, encoded);
+            assert_eq!(read_uvarint(&mut &encoded[..]).unwrap(), value);
+        }
+        assert!(read_uvarint(&mut &b"\x80"[..]).is_err());
+    }
+
+    #[test]
+    fn test_check_streamed_name() {
+        for name in [&b"data/a.i"[..], b"00changelog.i", b"branch2-served"] {
+            assert!(check_streamed_name(name).is_ok());
+        }
+        for name in [
+            &b""[..],
+            b"/etc/passwd",
+            b"data/../../hgrc",
+            b"data//a.i",
+            b"data/.HG/hgrc",
+            b"data/a.i\0",
+        ] {
+            assert!(check_streamed_name(name).is_err());
+        }
+    }
+
+    #[test]
+    fn test_is_nodemap_file() {
+        assert!(is_nodemap_file(b"00changelog.n", b"00changelog"));
+        assert!(is_nodemap_file(b"00changelog-1a2b.nd", b"00changelog"));
+        assert!(is_nodemap_file(b"00manifest.n.a", b"00manifest"));
+        assert!(!is_nodemap_file(b"00changelog.i", b"00changelog"));
+        assert!(!is_nodemap_file(b"00changelog-.nd", b"00changelog"));
+        assert!(!is_nodemap_file(b"00manifest.n", b"00changelog"));
+    }
+
+    #[test]
+    fn test_new_requirements() {
+        let set = |requirements: &[&str]| -> FastHashSet<String> {
+            requirements.iter().map(|r| r.to_string()).collect()
+        };
+        let source =
+            set(&["revlogv1", "store", "fncache", "share-safe", "zstd"]);
+        let streamed = streamed_requirements(&source);
+        assert_eq!(streamed, ["revlogv1", "zstd"]);
+        let default = set(&["revlogv1", "generaldelta", "store", "dotencode"]);
+        assert_eq!(
+            new_requirements(&default, &streamed),
+            set(&["revlogv1", "zstd", "store", "dotencode"])
+        );
+    }
+}
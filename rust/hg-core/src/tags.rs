@@ -0,0 +1,332 @@
+//! Reading the global tags defined in `.hgtags` files, and finding the latest
+//! tags of revisions like the `{latesttag}` template keyword.
+//!
+//! Local tags and the `tip` pseudo-tag are out of scope.
+
+use std::collections::BTreeMap;
+
+use crate::FastHashMap;
+use crate::Graph;
+use crate::GraphError;
+use crate::MissingAncestors;
+use crate::NULL_NODE;
+use crate::NULL_REVISION;
+use crate::Node;
+use crate::Revision;
+use crate::errors::HgError;
+use crate::repo::Repo;
+use crate::revlog::NodePrefix;
+use crate::revlog::RevlogError;
+use crate::revlog::changelog::Changelog;
+use crate::utils::hg_path::HgPath;
+
+/// Cache of the `.hgtags` filenodes of each changeset, maintained by Python
+const FNODES_CACHE: &str = "cache/hgtagsfnodes1";
+/// Size of a record of [`FNODES_CACHE`]: a changeset node prefix and a
+/// filenode
+const FNODES_RECORD_SIZE: usize = 4 + 20;
+
+/// A tag name, with the node it points to and the nodes it previously
+/// pointed to
+type TagHistory = (Node, Vec<Node>);
+
+/// The global tags of a repository, by name, like Python's `repo.tags()`
+/// without local tags and `tip`.
+///
+/// Deleted tags (pointing to the null node) and tags pointing to unknown
+/// changesets are left out.
+pub fn global_tags(repo: &Repo) -> Result<BTreeMap<Vec<u8>, Node>, HgError> {
+    let changelog = repo.changelog()?;
+    let mut heads =
+        changelog.get_index().head_revs().map_err(from_graph_error)?;
+    heads.sort_unstable();
+    let fnodes_cache =
+        repo.hg_vfs().try_read(FNODES_CACHE)?.unwrap_or_default();
+
+    // Read the `.hgtags` of the heads from oldest to newest, skipping
+    // duplicates: the order matters when merging histories.
+    let mut fnodes = vec![];
+    for head in heads {
+        let fnode = match cached_fnode(&fnodes_cache, &changelog, head) {
+            Some(fnode) => Some(fnode),
+            None => {
+                let manifest = repo.manifest_for_rev(head.into())?;
+                match manifest.find_by_path(HgPath::new(b".hgtags"))? {
+                    Some(entry) => Some(entry.node_id()?),
+                    None => None,
+                }
+            }
+        };
+        if let Some(fnode) = fnode
+            && fnode != NULL_NODE
+            && !fnodes.contains(&fnode)
+        {
+            fnodes.push(fnode);
+        }
+    }
+    let mut all_tags: FastHashMap<Vec<u8>, TagHistory> = FastHashMap::default();
+    if !fnodes.is_empty() {
+        let filelog = repo.filelog(HgPath::new(b".hgtags"))?;
+        for fnode in fnodes {
+            let data = filelog.data_for_node(fnode)?.into_file_data()?;
+            update_tags(&mut all_tags, parse_tags(&data));
+        }
+    }
+
+    let mut tags = BTreeMap::new();
+    for (name, (node, _history)) in all_tags {
+        let known = match changelog.rev_from_node(node.into()) {
+            Ok(_) => true,
+            Err(RevlogError::InvalidRevision { .. }) => false,
+            Err(e) => return Err(e.into()),
+        };
+        if node != NULL_NODE && known {
+            tags.insert(name, node);
+        }
+    }
+    Ok(tags)
+}
+
+/// The `.hgtags` filenodes of those of `revs` that are in the cache, without
+/// computing the missing ones, like `getfnode(node, computemissing=False)`
+/// in Python.
+///
+/// Returns pairs of changeset node and filenode, the null filenode meaning
+/// that there is no `.hgtags` in that changeset.
+pub fn cached_tags_fnodes(
+    repo: &Repo,
+    revs: &[Revision],
+) -> Result<Vec<(Node, Node)>, HgError> {
+    let changelog = repo.changelog()?;
+    let cache = repo.hg_vfs().try_read(FNODES_CACHE)?.unwrap_or_default();
+    Ok(revs
+        .iter()
+        .filter_map(|&rev| {
+            let fnode = cached_fnode(&cache, &changelog, rev)?;
+            Some((*changelog.node_from_rev(rev), fnode))
+        })
+        .collect())
+}
+
+/// Returns the `.hgtags` filenode of `rev` from the cache, if valid.
+///
+/// The null node means that there is no `.hgtags` in that revision.
+fn cached_fnode(
+    cache: &[u8],
+    changelog: &Changelog,
+    rev: Revision,
+) -> Option<Node> {
+    let offset = rev.0 as usize * FNODES_RECORD_SIZE;
+    let record = cache.get(offset..offset + FNODES_RECORD_SIZE)?;
+    let node = changelog.node_from_rev(rev);
+    if record[..4] != node.as_bytes()[..4] {
+        return None;
+    }
+    Node::try_from(&record[4..]).ok()
+}
+
+/// Parse the contents of a `.hgtags` file, skipping invalid lines.
+///
+/// Returns the tags in file order.
+fn parse_tags(data: &[u8]) -> Vec<(Vec<u8>, TagHistory)> {
+    let mut tags: Vec<(Vec<u8>, Vec<Node>)> = vec![];
+    for line in data.split(|&b| b == b'\n' || b == b'\r') {
+        let Some(space) = line.iter().position(|&b| b == b' ') else {
+            continue;
+        };
+        let name = line[space + 1..].trim_ascii();
+        let Ok(node) = Node::from_hex(&line[..space]) else {
+            continue;
+        };
+        match tags.iter_mut().find(|(n, _)| n == name) {
+            Some((_, nodes)) => nodes.push(node),
+            None => tags.push((name.to_owned(), vec![node])),
+        }
+    }
+    tags.into_iter()
+        .map(|(name, mut nodes)| {
+            let node = nodes.pop().expect("at least one node per tag");
+            (name, (node, nodes))
+        })
+        .collect()
+}
+
+/// Merge the tags of a newer `.hgtags` file into `all_tags`, like Python's
+/// `tags._updatetags`.
+fn update_tags(
+    all_tags: &mut FastHashMap<Vec<u8>, TagHistory>,
+    file_tags: Vec<(Vec<u8>, TagHistory)>,
+) {
+    for (name, (mut a_node, mut a_history)) in file_tags {
+        let Some((b_node, b_history)) = all_tags.get(&name) else {
+            all_tags.insert(name, (a_node, a_history));
+            continue;
+        };
+        // Prefer the previous node if it supersedes the new one, or if they
+        // supersede each other and it has a higher rank. Otherwise the new
+        // one wins, being tip-most.
+        if *b_node != a_node
+            && b_history.contains(&a_node)
+            && (!a_history.contains(b_node)
+                || b_history.len() > a_history.len())
+        {
+            a_node = *b_node;
+        }
+        for node in b_history {
+            if !a_history.contains(node) {
+                a_history.push(*node);
+            }
+        }
+        all_tags.insert(name, (a_node, a_history));
+    }
+}
+
+/// The latest tags of a revision, like the `{latesttag}` template keyword
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatestTags {
+    /// The global tags on the most recent globally tagged ancestor, in
+    /// sorted order, or `["null"]` if there is none
+    pub tags: Vec<Vec<u8>>,
+    /// The longest path to that ancestor
+    pub distance: usize,
+    /// The number of ancestors that are not ancestors of the first tag,
+    /// like `{changessincelatesttag}`
+    pub changes: usize,
+}
+
+/// Find the latest tags of `rev` given the `global_tags` of the repository,
+/// like Python's `templateutil.get_latest_tags`
+pub fn latest_tags(
+    repo: &Repo,
+    rev: Revision,
+    global_tags: &BTreeMap<Vec<u8>, Node>,
+) -> Result<LatestTags, HgError> {
+    let changelog = repo.changelog()?;
+    let mut tags_by_rev: FastHashMap<Revision, Vec<Vec<u8>>> =
+        FastHashMap::default();
+    let mut tag_revs: FastHashMap<&[u8], Revision> = FastHashMap::default();
+    for (name, node) in global_tags {
+        let tag_rev = changelog.rev_from_node(NodePrefix::from(*node))?;
+        tags_by_rev.entry(tag_rev).or_default().push(name.clone());
+        tag_revs.insert(name, tag_rev);
+    }
+    let tag_rev =
+        |name: &[u8]| tag_revs.get(name).copied().unwrap_or(NULL_REVISION);
+    let changes_since = |rev: Revision, tag: &[u8]| {
+        MissingAncestors::new(&*changelog, [tag_rev(tag)])
+            .missing_ancestors([rev])
+            .map(|revs| revs.len())
+            .map_err(from_graph_error)
+    };
+
+    // Each revision maps to the date of the tagged changeset (to sort
+    // branches in a stable manner), the distance and the tags
+    let mut latest: FastHashMap<Revision, (i64, usize, Vec<Vec<u8>>)> =
+        FastHashMap::default();
+    latest.insert(NULL_REVISION, (0, 0, vec![b"null".to_vec()]));
+    let mut todo = vec![rev];
+    while let Some(current) = todo.pop() {
+        if latest.contains_key(&current) {
+            continue;
+        }
+        if let Some(tags) = tags_by_rev.get(&current) {
+            let date = changelog.entry(current)?.data()?.timestamp()?;
+            latest.insert(current, (date.timestamp(), 0, tags.clone()));
+            continue;
+        }
+        let parents = changelog.parents(current).map_err(from_graph_error)?;
+        let parents: Vec<Revision> = match parents {
+            [p1, NULL_REVISION] => vec![p1],
+            [p1, p2] => vec![p1, p2],
+        };
+        let missing: Vec<Revision> = parents
+            .iter()
+            .copied()
+            .filter(|p| !latest.contains_key(p))
+            .collect();
+        if !missing.is_empty() {
+            todo.push(current);
+            todo.extend(missing);
+            continue;
+        }
+        let candidates: Vec<&(i64, usize, Vec<Vec<u8>>)> =
+            parents.iter().map(|p| &latest[p]).collect();
+        let best = match candidates[..] {
+            [only] => only,
+            [a, b] if a.2 == b.2 => a.max(b),
+            [a, b] => {
+                // The fewest changes since the tag wins, the date of the
+                // tagged changeset breaking ties
+                let key_a =
+                    (std::cmp::Reverse(changes_since(current, &a.2[0])?), a.0);
+                let key_b =
+                    (std::cmp::Reverse(changes_since(current, &b.2[0])?), b.0);
+                if key_b > key_a { b } else { a }
+            }
+            _ => unreachable!("a revision has one or two parents"),
+        };
+        let (date, distance, tags) = best.clone();
+        latest.insert(current, (date, distance + 1, tags));
+    }
+    let (_date, distance, tags) = latest.remove(&rev).expect("computed above");
+    let changes = changes_since(rev, &tags[0])?;
+    Ok(LatestTags { tags, distance, changes })
+}
+
+/// Converts a [`GraphError`] to an [`HgError`].
+fn from_graph_error(err: GraphError) -> HgError {
+    HgError::corrupted(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(byte: u8) -> Node {
+        Node::from([byte; 20])
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let data = format!(
+            "{} v1\n\
+             garbage\n\
+             {} v2 \r\n\
+             abc v3\n\
+             {} v1\n",
+            "11".repeat(20),
+            "22".repeat(20),
+            "33".repeat(20),
+        );
+        assert_eq!(
+            parse_tags(data.as_bytes()),
+            vec![
+                (b"v1".to_vec(), (node(0x33), vec![node(0x11)])),
+                (b"v2".to_vec(), (node(0x22), vec![])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_update_tags() {
+        let mut all_tags = FastHashMap::default();
+        update_tags(
+            &mut all_tags,
+            vec![
+                (b"a".to_vec(), (node(2), vec![node(1)])),
+                (b"b".to_vec(), (node(1), vec![])),
+            ],
+        );
+        // A newer file moving `a` back to a node it superseded loses, but
+        // one moving `b` forward wins.
+        update_tags(
+            &mut all_tags,
+            vec![
+                (b"a".to_vec(), (node(1), vec![])),
+                (b"b".to_vec(), (node(3), vec![node(1)])),
+            ],
+        );
+        assert_eq!(all_tags[&b"a".to_vec()], (node(2), vec![node(1)]));
+        assert_eq!(all_tags[&b"b".to_vec()], (node(3), vec![node(1)]));
+    }
+}
@@ -79,3 +79,8 @@ impl Graph for VecGraph {
         Ok(self[rev.0 as usize])
     }
 }
+
+#[cfg(test)]
+mod repo;
+#[cfg(test)]
+pub(crate) use repo::TestRepo;
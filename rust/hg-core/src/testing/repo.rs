@@ -0,0 +1,193 @@
+//! A repository with history in a temporary directory, for the tests that
+//! need actual revlogs and a working copy.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use crate::FastHashMap;
+use crate::NULL_NODE;
+use crate::Node;
+use crate::changegroup::ChangegroupUnpacker;
+use crate::changegroup::ChangegroupVersion;
+use crate::changegroup::RevisionDelta;
+use crate::changegroup::apply_changegroup;
+use crate::changegroup::write_revision_groups;
+use crate::config::Config;
+use crate::progress::HgProgressBar;
+use crate::repo::Repo;
+use crate::requirements::new_repository_requirements;
+use crate::revlog::inner_revlog::hash;
+use crate::store::Store;
+use crate::transaction::StoreTransaction;
+use crate::update::FileConflictConfig;
+use crate::update::UpdateConfig;
+use crate::update::update_from_null;
+use crate::utils::hg_path::HgPathBuf;
+use crate::warnings::HgWarningContext;
+
+/// The files of a changeset, with the node of their file revision
+type Files = BTreeMap<HgPathBuf, Node>;
+
+/// A repository in a temporary directory, whose changesets are added as
+/// changegroups built from the files they change
+pub(crate) struct TestRepo {
+    pub(crate) repo: Repo,
+    /// The manifest node and the files of every changeset committed
+    changesets: FastHashMap<Node, (Node, Files)>,
+    /// Deleted on drop, so it must outlive `repo`
+    dir: TempDir,
+}
+
+impl TestRepo {
+    /// Creates an empty repository with the default requirements, given the
+    /// configuration `config_args` in the format of `--config`
+    pub(crate) fn new(config_args: &[&str]) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::empty();
+        config.load_cli_args(config_args, None).unwrap();
+        let requirements = new_repository_requirements(&config).unwrap();
+        let repo =
+            Repo::create(&config, dir.path(), &requirements, None).unwrap();
+        Self { repo, changesets: FastHashMap::default(), dir }
+    }
+
+    /// The working directory
+    pub(crate) fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Writes `content` to `path` in the working directory, creating its
+    /// parent directories
+    pub(crate) fn write(&self, path: &str, content: &str) {
+        let path = self.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    /// Adds a changeset on top of `parent` changing its files to the given
+    /// contents, or removing them for `None`, and returns its node
+    pub(crate) fn commit(
+        &mut self,
+        parent: Node,
+        changes: &[(&str, Option<&str>)],
+    ) -> Node {
+        let (manifest_parent, mut files) = match parent {
+            NULL_NODE => (NULL_NODE, Files::new()),
+            parent => self.changesets[&parent].clone(),
+        };
+        let mut file_revisions = vec![];
+        for &(path, content) in changes {
+            let path = HgPathBuf::from_bytes(path.as_bytes());
+            let Some(content) = content else {
+                files.remove(&path);
+                continue;
+            };
+            let p1 = files.get(&path).copied().unwrap_or(NULL_NODE);
+            let node = node_of(content.as_bytes(), p1);
+            files.insert(path.to_owned(), node);
+            file_revisions.push((path, node, p1, content.as_bytes()));
+        }
+
+        let mut manifest = vec![];
+        for (path, node) in &files {
+            manifest.extend_from_slice(path.as_bytes());
+            manifest.extend_from_slice(format!("\0{:x}\n", node).as_bytes());
+        }
+        let manifest_node = node_of(&manifest, manifest_parent);
+        let mut changed: Vec<_> =
+            changes.iter().map(|(path, _)| path.to_owned()).collect();
+        changed.sort_unstable();
+        let changeset = format!(
+            "{:x}\ntest\n0 0\n{}\n\ncommit {}",
+            manifest_node,
+            changed.join("\n"),
+            self.changesets.len()
+        );
+        let node = node_of(changeset.as_bytes(), parent);
+
+        let revision = |revision_node, p1, text: &[u8]| {
+            RevisionDelta::full_text(revision_node, p1, NULL_NODE, node, text)
+        };
+        let changelog = [revision(node, parent, changeset.as_bytes())];
+        let manifests = [revision(manifest_node, manifest_parent, &manifest)];
+        let file_groups: Vec<_> = file_revisions
+            .into_iter()
+            .map(|(path, file_node, p1, content)| {
+                (path, [revision(file_node, p1, content)])
+            })
+            .collect();
+        let file_groups: Vec<_> = file_groups
+            .iter()
+            .map(|(path, revisions)| (path.as_ref(), &revisions[..]))
+            .collect();
+        let version = ChangegroupVersion::V2;
+        let mut stream = vec![];
+        write_revision_groups(
+            version,
+            &changelog,
+            &manifests,
+            &file_groups,
+            &mut stream,
+        )
+        .unwrap();
+
+        self.apply(&stream, version);
+        self.changesets.insert(node, (manifest_node, files));
+        node
+    }
+
+    /// Adds the revisions of the changegroup `stream` in a transaction
+    pub(crate) fn apply(&self, stream: &[u8], version: ChangegroupVersion) {
+        let repo = &self.repo;
+        let mut unpacker = ChangegroupUnpacker::new(stream, version);
+        let mut tr = StoreTransaction::open(repo, "commit").unwrap();
+        let mut fncache = Store::new(repo).fncache().unwrap();
+        apply_changegroup(
+            repo,
+            &mut unpacker,
+            &mut tr,
+            Some(&mut fncache),
+            |_| {},
+        )
+        .unwrap();
+        repo.changelog_mut().unwrap().finalize_pending().unwrap();
+        fncache.write(&mut tr).unwrap();
+        tr.close().unwrap();
+    }
+
+    /// Checks out `node` in the working directory, which must be at the
+    /// null revision
+    pub(crate) fn update_from_null(&self, node: Node) {
+        let repo = &self.repo;
+        let rev = repo.changelog().unwrap().rev_from_node(node.into()).unwrap();
+        let mut dirstate = repo.dirstate_map_mut().unwrap();
+        let update_config = UpdateConfig {
+            workers: Some(1),
+            remove_empty_dirs: false,
+            devel_abort_dirstate: false,
+            orig_backup_path: None,
+            atomic_file: false,
+            ignored_conflict: FileConflictConfig::Abort,
+            unknown_conflict: FileConflictConfig::Abort,
+        };
+        let warnings = HgWarningContext::new();
+        update_from_null(
+            repo,
+            rev.into(),
+            &mut dirstate,
+            &HgProgressBar::new("updating"),
+            &update_config,
+            warnings.sender(),
+        )
+        .unwrap();
+        drop(dirstate);
+        repo.write_dirstate().unwrap();
+    }
+}
+
+/// The node of a revision with `data` and a single parent
+fn node_of(data: &[u8], p1: Node) -> Node {
+    Node::from(&hash(data, p1.as_bytes(), NULL_NODE.as_bytes()))
+}
@@ -1,4 +1,25 @@
+use std::fs::File;
+use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use rand::RngExt;
+use sha1::Digest;
+use sha1::Sha1;
+
+use crate::FastHashMap;
+use crate::FastHashSet;
+use crate::errors::HgError;
+use crate::errors::IoErrorContext;
+use crate::errors::IoResultExt;
+use crate::exit_codes;
+use crate::repo::Repo;
+use crate::revlog::path_encode::PathEncoding;
+use crate::revlog::path_encode::path_encode;
+use crate::utils::files::get_bytes_from_path;
+use crate::utils::files::get_path_from_bytes;
+use crate::vfs::VfsImpl;
 
 /// The Mercurial transaction system is based on the append-only nature
 /// of its core files. This exposes the necessary methods to safely write to
@@ -7,5 +28,687 @@ pub trait Transaction {
     /// Record the state of an append-only file before update
     fn add(&mut self, file: impl AsRef<Path>, offset: usize);
 
+    /// Back up a file of the store before it is rewritten, so that rolling
+    /// back restores it
+    fn add_backup(&mut self, file: impl AsRef<Path>);
+
     // TODO the rest of the methods once we do more in Rust.
 }
+
+impl<T: Transaction> Transaction for &mut T {
+    fn add(&mut self, file: impl AsRef<Path>, offset: usize) {
+        (**self).add(file, offset)
+    }
+
+    fn add_backup(&mut self, file: impl AsRef<Path>) {
+        (**self).add_backup(file)
+    }
+}
+
+/// The journal of the transaction in progress, in the store
+const JOURNAL: &str = "journal";
+/// The list of the backups and temporary files of the transaction, in the
+/// store
+const BACKUP_JOURNAL: &str = "journal.backupfiles";
+/// The description of the transaction, in `.hg`
+const JOURNAL_DESC: &str = "journal.desc";
+/// The version of the format of [`BACKUP_JOURNAL`]
+const BACKUP_JOURNAL_VERSION: &[u8] = b"2";
+
+/// Files left by a previous transaction for `hg rollback`, removed when
+/// writing new ones: `(in the store, name)`. `undo` must come last, so that
+/// an interrupted cleanup is detected.
+const UNDO_FILES: &[(bool, &str)] = &[
+    (true, "undo.narrowspec"),
+    (false, "undo.narrowspec.dirstate"),
+    (false, "undo.branch"),
+    (false, "undo.bookmarks"),
+    (true, "undo.phaseroots"),
+    (false, "undo.dirstate"),
+    (false, "undo.desc"),
+    (true, "undo"),
+];
+
+/// A file of the store touched by a [`StoreTransaction`]
+#[derive(Debug, Clone)]
+struct JournalFile {
+    /// The path of the file, relative to the store
+    path: PathBuf,
+    /// The unencoded name of the file, as written in the journal
+    name: Vec<u8>,
+}
+
+/// A backup or a temporary file of a [`StoreTransaction`]
+#[derive(Debug, Clone)]
+struct BackupEntry {
+    /// The backed up file, `None` for a temporary file
+    file: Option<JournalFile>,
+    /// The backup or the temporary file, `None` if the backed up file did
+    /// not exist
+    backup: Option<JournalFile>,
+}
+
+/// A transaction on the store of a repository, journaled on disk like
+/// Python's `transaction.transaction`: `hg recover` rolls back an
+/// interrupted one, and `hg rollback` undoes a closed one.
+///
+/// The store lock must be held for the whole life of the transaction.
+/// Dropping it without calling [`Self::close`] rolls it back.
+pub struct StoreTransaction {
+    /// The identifier given to hooks, like `TXN:<hex>`
+    id: String,
+    store_vfs: VfsImpl,
+    hg_vfs: VfsImpl,
+    encoding: PathEncoding,
+    journal: Option<File>,
+    backup_journal: Option<File>,
+    /// The unencoded names of the store files whose path is encoded
+    names: FastHashMap<PathBuf, Vec<u8>>,
+    /// The files appended to, with their size before the transaction
+    entries: Vec<(JournalFile, usize)>,
+    backups: Vec<BackupEntry>,
+    /// Every file in `entries` or `backups`
+    touched: FastHashSet<PathBuf>,
+    /// Temporary files replacing their target when closing
+    renames: Vec<(PathBuf, PathBuf)>,
+    /// The first error that happened while journaling, which can only be
+    /// reported when closing through the [`Transaction`] trait
+    error: Option<HgError>,
+}
+
+impl StoreTransaction {
+    /// Starts a transaction named `name` in `repo`, like
+    /// `localrepo.transaction` in Python.
+    ///
+    /// Aborts if the journal of an interrupted transaction is found.
+    pub fn open(repo: &Repo, name: &str) -> Result<Self, HgError> {
+        let store_vfs = repo.store_vfs();
+        let raw_store_vfs = VfsImpl::new(
+            repo.store_path().to_owned(),
+            false,
+            PathEncoding::None,
+        );
+        if raw_store_vfs.join(JOURNAL).exists() {
+            return Err(HgError::abort(
+                "abandoned transaction found",
+                exit_codes::ABORT,
+                Some("run 'hg recover' to clean up transaction".to_string()),
+            ));
+        }
+        let changesets = repo.changelog()?.revlog.len();
+        let hg_vfs = repo.hg_vfs();
+        std::fs::write(
+            hg_vfs.join(JOURNAL_DESC),
+            format!("{}\n{}\n", changesets, name),
+        )
+        .when_writing_file(hg_vfs.join(JOURNAL_DESC))?;
+        let create = |name: &str| {
+            let path = raw_store_vfs.join(name);
+            File::create(&path).when_writing_file(&path)
+        };
+        let journal = create(JOURNAL)?;
+        let mut backup_journal = create(BACKUP_JOURNAL)?;
+        backup_journal
+            .write_all(&[BACKUP_JOURNAL_VERSION, b"\n"].concat())
+            .when_writing_file(raw_store_vfs.join(BACKUP_JOURNAL))?;
+        let seed = format!(
+            "{:.40}#{}",
+            rand::rng().random::<f64>(),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        );
+        Ok(Self {
+            id: format!("TXN:{:x}", Sha1::digest(seed.as_bytes())),
+            encoding: store_vfs.encoding,
+            store_vfs: raw_store_vfs,
+            hg_vfs,
+            journal: Some(journal),
+            backup_journal: Some(backup_journal),
+            names: FastHashMap::default(),
+            entries: vec![],
+            backups: vec![],
+            touched: FastHashSet::default(),
+            renames: vec![],
+            error: None,
+        })
+    }
+
+    /// The random identifier of the transaction, given to hooks as `txnid`
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Records the unencoded `name` of the store file at `path`, which the
+    /// journal uses so that Python can replay it. Paths without a name are
+    /// assumed not to be encoded.
+    pub fn add_name(&mut self, path: impl AsRef<Path>, name: &[u8]) {
+        self.names.insert(path.as_ref().to_owned(), name.to_owned());
+    }
+
+    fn journal_file(&self, path: &Path) -> JournalFile {
+        let name = match self.names.get(path) {
+            Some(name) => name.to_owned(),
+            None => get_bytes_from_path(path),
+        };
+        JournalFile { path: path.to_owned(), name }
+    }
+
+    /// The backup of `file` in this transaction, like `addbackup` in Python
+    fn backup_file(&self, file: &JournalFile, prefix: &str) -> JournalFile {
+        let (dir, base) = match file.name.iter().rposition(|&b| b == b'/') {
+            Some(slash) => file.name.split_at(slash + 1),
+            None => (&[][..], &file.name[..]),
+        };
+        let name =
+            [dir, prefix.as_bytes(), b".backup.", base, b".bck"].concat();
+        let path = match self.encoding {
+            PathEncoding::None => name.to_owned(),
+            encoding => path_encode(&name, encoding),
+        };
+        JournalFile { path: get_path_from_bytes(&path).to_owned(), name }
+    }
+
+    /// Runs `f`, keeping its error for [`Self::close`] if it fails
+    fn deferred(&mut self, f: impl FnOnce(&mut Self) -> Result<(), HgError>) {
+        if self.error.is_none()
+            && let Err(error) = f(self)
+        {
+            self.error = Some(error);
+        }
+    }
+
+    fn write_journal(
+        file: &mut Option<File>,
+        vfs: &VfsImpl,
+        name: &str,
+        line: &[u8],
+    ) -> Result<(), HgError> {
+        let file = file.as_mut().expect("journal written after closing");
+        file.write_all(line)
+            .and_then(|()| file.flush())
+            .when_writing_file(vfs.join(name))?;
+        Ok(())
+    }
+
+    fn add_backup_entry(&mut self, entry: BackupEntry) -> Result<(), HgError> {
+        let name = |file: &Option<JournalFile>| match file {
+            Some(file) => file.name.to_owned(),
+            None => vec![],
+        };
+        let line =
+            [&name(&entry.file)[..], b"\0", &name(&entry.backup), b"\x000\n"]
+                .concat();
+        // The location of the store in Python's `vfsmap` is empty
+        let line = [&b"\0"[..], &line].concat();
+        match (&entry.file, &entry.backup) {
+            (Some(file), _) => self.touched.insert(file.path.to_owned()),
+            // Temporary files are not journaled either, like in Python
+            (None, Some(tmp)) => self.touched.insert(tmp.path.to_owned()),
+            (None, None) => false,
+        };
+        self.backups.push(entry);
+        Self::write_journal(
+            &mut self.backup_journal,
+            &self.store_vfs,
+            BACKUP_JOURNAL,
+            &line,
+        )
+    }
+
+    /// Registers a temporary file of the store, which is deleted when the
+    /// transaction ends
+    pub fn register_tmp(&mut self, file: impl AsRef<Path>) {
+        let backup = self.journal_file(file.as_ref());
+        self.deferred(|tr| {
+            tr.add_backup_entry(BackupEntry {
+                file: None,
+                backup: Some(backup),
+            })
+        });
+    }
+
+    /// Registers a temporary file of the store that replaces `target` when
+    /// the transaction closes, and is deleted if it aborts
+    pub fn register_rename(
+        &mut self,
+        tmp: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+    ) {
+        self.register_tmp(tmp.as_ref());
+        self.renames
+            .push((tmp.as_ref().to_owned(), target.as_ref().to_owned()));
+    }
+
+    /// Whether the transaction wrote anything else than temporary files, in
+    /// which case aborting it is reported like in Python
+    pub fn needs_rollback(&self) -> bool {
+        !self.entries.is_empty()
+            || self.backups.iter().any(|entry| entry.file.is_some())
+    }
+
+    /// Commits the transaction, keeping what is needed for `hg rollback`
+    pub fn close(mut self) -> Result<(), HgError> {
+        if let Some(error) = self.error.take() {
+            // Report the journaling error rather than a rollback error
+            let _ = self.rollback();
+            return Err(error);
+        }
+        for (tmp, target) in std::mem::take(&mut self.renames) {
+            let (from, to) =
+                (self.store_vfs.join(tmp), self.store_vfs.join(target));
+            std::fs::rename(&from, &to)
+                .with_context(|| IoErrorContext::RenamingFile { from, to })?;
+        }
+        self.journal = None;
+        self.backup_journal = None;
+        for entry in &self.backups {
+            if let (None, Some(tmp)) = (&entry.file, &entry.backup) {
+                self.remove_store_file(&tmp.path)?;
+            }
+        }
+        self.write_undo()?;
+        self.remove_store_file(Path::new(BACKUP_JOURNAL))?;
+        self.remove_store_file(Path::new(JOURNAL))?;
+        for entry in std::mem::take(&mut self.backups) {
+            if let Some(backup) = &entry.backup {
+                self.remove_store_file(&backup.path)?;
+            }
+        }
+        self.entries.clear();
+        Ok(())
+    }
+
+    /// Rolls back the transaction, like `_playback` in Python
+    pub fn abort(mut self) -> Result<(), HgError> {
+        self.rollback()
+    }
+
+    fn rollback(&mut self) -> Result<(), HgError> {
+        self.journal = None;
+        self.backup_journal = None;
+        let backups = std::mem::take(&mut self.backups);
+        let mut restored = FastHashSet::default();
+        let mut entries = std::mem::take(&mut self.entries);
+        entries.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        for (file, offset) in &entries {
+            let backup = backups.iter().position(|entry| {
+                entry.file.as_ref().is_some_and(|f| f.path == file.path)
+            });
+            if let Some(index) = backup {
+                self.restore_backup(&backups[index])?;
+                restored.insert(index);
+            }
+            let path = self.store_vfs.join(&file.path);
+            if *offset == 0 {
+                self.remove_store_file(&file.path)?;
+                continue;
+            }
+            let handle = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .when_writing_file(&path)?;
+            let size = handle.metadata().when_reading_file(&path)?.len();
+            if size < *offset as u64 {
+                return Err(HgError::abort_simple(format!(
+                    "attempted to truncate {} to {} bytes, but it was \
+                     already {} bytes\n",
+                    String::from_utf8_lossy(&file.name),
+                    offset,
+                    size
+                )));
+            }
+            handle.set_len(*offset as u64).when_writing_file(&path)?;
+        }
+        for (index, entry) in backups.iter().enumerate() {
+            if restored.contains(&index) {
+                continue;
+            }
+            match (&entry.file, &entry.backup) {
+                (Some(_), Some(_)) => self.restore_backup(entry)?,
+                (Some(file), None) | (None, Some(file)) => {
+                    self.remove_store_file(&file.path)?
+                }
+                (None, None) => {}
+            }
+        }
+        self.remove_store_file(Path::new(BACKUP_JOURNAL))?;
+        self.remove_store_file(Path::new(JOURNAL))?;
+        for entry in &backups {
+            if let (Some(_), Some(backup)) = (&entry.file, &entry.backup) {
+                self.remove_store_file(&backup.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_backup(&self, entry: &BackupEntry) -> Result<(), HgError> {
+        if let (Some(file), Some(backup)) = (&entry.file, &entry.backup) {
+            let from = self.store_vfs.join(&backup.path);
+            let to = self.store_vfs.join(&file.path);
+            std::fs::copy(&from, &to)
+                .with_context(|| IoErrorContext::CopyingFile { from, to })?;
+        }
+        Ok(())
+    }
+
+    /// Removes a file of the store if it exists
+    fn remove_store_file(&self, path: &Path) -> Result<(), HgError> {
+        remove_file(&self.store_vfs.join(path))
+    }
+
+    /// Replaces the files of the previous transaction used by
+    /// `hg rollback` with those of this one, like `_writeundo` in Python
+    fn write_undo(&self) -> Result<(), HgError> {
+        remove_undo_files(&self.store_vfs, &self.hg_vfs, self.encoding)?;
+
+        let mut listing = [BACKUP_JOURNAL_VERSION, b"\n"].concat();
+        for entry in &self.backups {
+            let Some(file) = &entry.file else {
+                continue;
+            };
+            let undo_name = match &entry.backup {
+                None => vec![],
+                Some(backup) => {
+                    let undo = self.backup_file(file, "undo");
+                    let from = self.store_vfs.join(&backup.path);
+                    let to = self.store_vfs.join(&undo.path);
+                    std::fs::hard_link(&from, &to)
+                        .or_else(|_| std::fs::copy(&from, &to).map(|_| ()))
+                        .with_context(|| IoErrorContext::CopyingFile {
+                            from,
+                            to,
+                        })?;
+                    undo.name
+                }
+            };
+            listing.extend_from_slice(
+                &[b"\0", &file.name[..], b"\0", &undo_name, b"\x000\n"]
+                    .concat(),
+            );
+        }
+        let undo_backups = self.store_vfs.join("undo.backupfiles");
+        std::fs::write(&undo_backups, listing)
+            .when_writing_file(&undo_backups)?;
+        for (vfs, from, to) in [
+            (&self.store_vfs, JOURNAL, "undo"),
+            (&self.hg_vfs, JOURNAL_DESC, "undo.desc"),
+        ] {
+            let (from, to) = (vfs.join(from), vfs.join(to));
+            if from.exists() {
+                std::fs::rename(&from, &to).with_context(|| {
+                    IoErrorContext::RenamingFile { from, to }
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes the files of the last transaction used by `hg rollback`, like
+/// `cleanup_undo_files` in Python. This is for operations after which undoing
+/// that transaction makes no sense, like a local clone.
+pub fn cleanup_undo_files(repo: &Repo) -> Result<(), HgError> {
+    let raw_store_vfs =
+        VfsImpl::new(repo.store_path().to_owned(), false, PathEncoding::None);
+    remove_undo_files(&raw_store_vfs, &repo.hg_vfs(), repo.store_vfs().encoding)
+}
+
+/// Removes the files used by `hg rollback`, including the backups listed in
+/// `undo.backupfiles` whose names are encoded with `encoding` in the store
+fn remove_undo_files(
+    store_vfs: &VfsImpl,
+    hg_vfs: &VfsImpl,
+    encoding: PathEncoding,
+) -> Result<(), HgError> {
+    let undo_backups = store_vfs.join("undo.backupfiles");
+    if let Ok(listing) = std::fs::read(&undo_backups) {
+        let mut lines = listing.split(|&b| b == b'\n');
+        if lines.next() == Some(BACKUP_JOURNAL_VERSION) {
+            for line in lines {
+                let fields: Vec<&[u8]> = line.split(|&b| b == 0).collect();
+                if let [b"", _, backup, _] = fields[..]
+                    && !backup.is_empty()
+                {
+                    let path = match encoding {
+                        PathEncoding::None => backup.to_owned(),
+                        encoding => path_encode(backup, encoding),
+                    };
+                    remove_file(&store_vfs.join(get_path_from_bytes(&path)))?;
+                }
+            }
+        }
+    }
+    for entry in
+        std::fs::read_dir(hg_vfs.join("")).when_reading_file(hg_vfs.join(""))?
+    {
+        let name = entry.when_reading_file(hg_vfs.join(""))?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("undo.backup.dirstate.") && name.ends_with(".bck") {
+            remove_file(&hg_vfs.join(name.as_ref()))?;
+        }
+    }
+    remove_file(&undo_backups)?;
+    for (in_store, name) in UNDO_FILES {
+        let vfs = if *in_store { store_vfs } else { hg_vfs };
+        remove_file(&vfs.join(name))?;
+    }
+    Ok(())
+}
+
+/// Removes the file at `path` if it exists
+fn remove_file(path: &Path) -> Result<(), HgError> {
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result.when_writing_file(path)?),
+    }
+}
+
+impl Transaction for StoreTransaction {
+    fn add(&mut self, file: impl AsRef<Path>, offset: usize) {
+        let file = file.as_ref();
+        if self.touched.contains(file) {
+            return;
+        }
+        let file = self.journal_file(file);
+        self.deferred(|tr| {
+            let line =
+                [&file.name[..], b"\0", offset.to_string().as_bytes(), b"\n"]
+                    .concat();
+            tr.touched.insert(file.path.to_owned());
+            tr.entries.push((file, offset));
+            Self::write_journal(&mut tr.journal, &tr.store_vfs, JOURNAL, &line)
+        });
+    }
+
+    fn add_backup(&mut self, file: impl AsRef<Path>) {
+        let file = file.as_ref();
+        let is_new = self
+            .entries
+            .iter()
+            .any(|(f, offset)| f.path == file && *offset == 0);
+        let is_backed_up = self
+            .backups
+            .iter()
+            .any(|entry| entry.file.as_ref().is_some_and(|f| f.path == file));
+        // A new file is removed when rolling back, there is nothing to
+        // restore. Files appended to are backed up when they are about to be
+        // rewritten, like for the split of an inline revlog.
+        if is_new || is_backed_up {
+            return;
+        }
+        let file = self.journal_file(file);
+        self.deferred(|tr| {
+            let path = tr.store_vfs.join(&file.path);
+            let backup = if path.exists() {
+                let backup = tr.backup_file(&file, "journal");
+                let to = tr.store_vfs.join(&backup.path);
+                std::fs::copy(&path, &to).with_context(|| {
+                    IoErrorContext::CopyingFile { from: path, to }
+                })?;
+                Some(backup)
+            } else {
+                None
+            };
+            tr.add_backup_entry(BackupEntry { file: Some(file), backup })
+        });
+    }
+}
+
+impl Drop for StoreTransaction {
+    fn drop(&mut self) {
+        if self.journal.is_some() {
+            let _ = self.rollback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    /// A repository with the store files `appended` and `rewritten`
+    fn repo_with_files() -> TestRepo {
+        let test_repo = TestRepo::new(&[]);
+        let store = test_repo.repo.store_path();
+        std::fs::write(store.join("appended"), "abc").unwrap();
+        std::fs::write(store.join("rewritten"), "old").unwrap();
+        test_repo
+    }
+
+    /// Appends to, rewrites and creates files of the store within `tr`
+    fn write_files(repo: &Repo, tr: &mut StoreTransaction) {
+        let store = repo.store_path();
+        tr.add("appended", 3);
+        let mut appended = std::fs::OpenOptions::new()
+            .append(true)
+            .open(store.join("appended"))
+            .unwrap();
+        appended.write_all(b"def").unwrap();
+        tr.add_backup("rewritten");
+        std::fs::write(store.join("rewritten"), "new").unwrap();
+        tr.add("new", 0);
+        std::fs::write(store.join("new"), "new").unwrap();
+        tr.add_backup("created");
+        std::fs::write(store.join("created"), "created").unwrap();
+    }
+
+    fn read(path: &Path) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    fn assert_rolled_back(repo: &Repo) {
+        let store = repo.store_path();
+        assert_eq!(read(&store.join("appended")).as_deref(), Some("abc"));
+        assert_eq!(read(&store.join("rewritten")).as_deref(), Some("old"));
+        assert_eq!(read(&store.join("new")), None);
+        assert_eq!(read(&store.join("created")), None);
+        assert_eq!(read(&store.join("journal.backup.rewritten.bck")), None);
+        assert!(!store.join(JOURNAL).exists());
+        assert!(!store.join(BACKUP_JOURNAL).exists());
+        assert!(!store.join("undo").exists());
+    }
+
+    #[test]
+    fn test_abort() {
+        let test_repo = repo_with_files();
+        let repo = &test_repo.repo;
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        assert!(!tr.needs_rollback());
+        write_files(repo, &mut tr);
+        assert!(tr.needs_rollback());
+        assert!(repo.store_path().join(JOURNAL).exists());
+        tr.abort().unwrap();
+        assert_rolled_back(repo);
+    }
+
+    #[test]
+    fn test_drop() {
+        let test_repo = repo_with_files();
+        let repo = &test_repo.repo;
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        write_files(repo, &mut tr);
+        drop(tr);
+        assert_rolled_back(repo);
+        // The journal is gone, so a new transaction can start
+        StoreTransaction::open(repo, "test").unwrap().close().unwrap();
+    }
+
+    #[test]
+    fn test_close_writes_undo() {
+        let test_repo = repo_with_files();
+        let repo = &test_repo.repo;
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        write_files(repo, &mut tr);
+        tr.close().unwrap();
+
+        let store = repo.store_path();
+        assert_eq!(read(&store.join("appended")).as_deref(), Some("abcdef"));
+        assert_eq!(read(&store.join("rewritten")).as_deref(), Some("new"));
+        assert!(!store.join(JOURNAL).exists());
+        assert!(!store.join(BACKUP_JOURNAL).exists());
+        assert_eq!(read(&store.join("journal.backup.rewritten.bck")), None);
+        // In the formats `transaction.rollback` reads in Python
+        assert_eq!(
+            read(&store.join("undo")).as_deref(),
+            Some("appended\x003\nnew\x000\n")
+        );
+        assert_eq!(
+            read(&store.join("undo.backupfiles")).as_deref(),
+            Some(
+                "2\n\
+                 \0rewritten\0undo.backup.rewritten.bck\x000\n\
+                 \0created\0\x000\n"
+            )
+        );
+        assert_eq!(
+            read(&store.join("undo.backup.rewritten.bck")).as_deref(),
+            Some("old")
+        );
+        assert_eq!(
+            read(&repo.hg_vfs().join("undo.desc")).as_deref(),
+            Some("0\ntest\n")
+        );
+
+        // The next transaction replaces the undo files
+        let mut tr = StoreTransaction::open(repo, "next").unwrap();
+        tr.add("appended", 6);
+        tr.close().unwrap();
+        assert_eq!(read(&store.join("undo.backup.rewritten.bck")), None);
+        assert_eq!(
+            read(&store.join("undo")).as_deref(),
+            Some("appended\x006\n")
+        );
+        assert_eq!(
+            read(&store.join("undo.backupfiles")).as_deref(),
+            Some("2\n")
+        );
+    }
+
+    #[test]
+    fn test_register_rename() {
+        let test_repo = TestRepo::new(&[]);
+        let repo = &test_repo.repo;
+        let store = repo.store_path();
+        std::fs::write(store.join("target"), "old").unwrap();
+
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        std::fs::write(store.join("target.tmp"), "new").unwrap();
+        tr.register_rename("target.tmp", "target");
+        assert!(!tr.needs_rollback());
+        tr.abort().unwrap();
+        assert_eq!(read(&store.join("target")).as_deref(), Some("old"));
+        assert_eq!(read(&store.join("target.tmp")), None);
+
+        let mut tr = StoreTransaction::open(repo, "test").unwrap();
+        std::fs::write(store.join("target.tmp"), "new").unwrap();
+        tr.register_rename("target.tmp", "target");
+        tr.close().unwrap();
+        assert_eq!(read(&store.join("target")).as_deref(), Some("new"));
+        assert_eq!(read(&store.join("target.tmp")), None);
+        // Temporary files are not part of what `hg rollback` restores
+        assert_eq!(read(&store.join("undo")).as_deref(), Some(""));
+    }
+}
@@ -480,7 +480,7 @@ pub fn apply_flags_to_file<'a>(
     }
 }
 
-fn working_copy_remove(
+pub(crate) fn working_copy_remove(
     path: impl AsRef<Path>,
     vfs: &impl Vfs,
     remove_empty_dirs: bool,
@@ -1182,6 +1182,69 @@ fn create_working_copy<'a: 'b, 'b>(
     res
 }
 
+/// Write the contents of `entries` to the working copy, possibly in parallel,
+/// overwriting any file already present.
+///
+/// This is for commands that restore files from a revision without moving
+/// the working copy, like `revert`. `entries` must be sorted by path.
+/// Returns the stat information of the newly written files.
+#[tracing::instrument(level = "debug", skip_all)]
+pub(crate) fn restore_working_copy_files<'a>(
+    repo: &Repo,
+    entries: Vec<DecodedManifestEntry<'a>>,
+    progress: &dyn Progress,
+    update_config: &UpdateConfig,
+) -> Result<Vec<DirstateUpdate<'a>>, HgError> {
+    let options = default_revlog_options(
+        repo.config(),
+        repo.requirements(),
+        RevlogType::Filelog,
+    )?;
+    let updates: Vec<_> = entries
+        .into_iter()
+        .map(|entry| WorkingCopyFileUpdate {
+            entry,
+            backup: false,
+            flags_differ: false,
+        })
+        .collect();
+    let (symlinks, chunks) = chunk_tracked_files(updates, true);
+    let (files_sender, files_receiver) = crossbeam_channel::unbounded();
+    create_working_copy(
+        chunks,
+        symlinks,
+        &repo.working_directory_vfs(),
+        &repo.store_vfs(),
+        &options,
+        &files_sender,
+        progress,
+        update_config,
+    )?;
+    drop(files_sender);
+    Ok(files_receiver.into_iter().collect())
+}
+
+/// Record the files written by [`restore_working_copy_files`] as clean in
+/// `dirstate`, with their stat information if it is reliable enough.
+///
+/// Only valid if the files were restored from the first parent, outside of
+/// a merge.
+pub(crate) fn record_restored_files<'a>(
+    repo: &Repo,
+    dirstate: &mut OwningDirstateMap,
+    updates: Vec<DirstateUpdate<'a>>,
+) -> Result<(), HgError> {
+    update_dirstate(
+        repo,
+        dirstate,
+        updates,
+        None,
+        false,
+        UpdateKind::FromClean,
+    )?;
+    Ok(())
+}
+
 /// Returns the backup path for `path`, relative to the `working_copy_vfs`'s
 /// base. The backup root can be overridden by the config.
 pub fn backup_path(
@@ -1194,17 +1257,18 @@ pub fn backup_path(
     //  - is_dir, is_symlink
     //  - join from hg_path
     //  - etc.
-    let vfs = if let Some(orig_path) = orig_backup_path {
-        if orig_path.is_empty() {
-            return Ok(working_copy_vfs.join(path.with_extension("orig")));
-        }
-        VfsImpl::new(
+    let vfs = match orig_backup_path {
+        Some(orig_path) if !orig_path.is_empty() => VfsImpl::new(
             working_copy_vfs.join(get_path_from_bytes(orig_path)),
             false,
             PathEncoding::None,
-        )
-    } else {
-        return Ok(working_copy_vfs.join(path.with_extension("orig")));
+        ),
+        _ => {
+            // Append the extension instead of replacing any existing one
+            let mut orig = path.as_os_str().to_owned();
+            orig.push(".orig");
+            return Ok(PathBuf::from(orig));
+        }
     };
 
     let full_path = vfs.join(path);
@@ -1404,10 +1468,10 @@ fn working_copy_worker<'a: 'b, 'b>(
 
 /// Update the dirstate to reflect the changes we've made to the working copy
 #[tracing::instrument(level = "debug", skip_all)]
-fn update_dirstate(
+fn update_dirstate<'a>(
     repo: &Repo,
     dirstate: &mut OwningDirstateMap,
-    files_receiver: Receiver<DirstateUpdate>,
+    files_receiver: impl IntoIterator<Item = DirstateUpdate<'a>>,
     removals_receiver: Option<Receiver<&HgPath>>,
     devel_abort: bool,
     update_kind: UpdateKind,
@@ -1709,4 +1773,29 @@ mod test {
         let expected = vec![(p(""), chunk(large_dir))];
         assert_eq!(chunk_tracked_files(files, false).1, expected);
     }
+
+    #[test]
+    fn test_backup_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let vfs =
+            VfsImpl::new(dir.path().to_owned(), false, PathEncoding::None);
+
+        // The `.orig` suffix is appended, not substituted
+        let path = Path::new("dir/file.txt");
+        assert_eq!(
+            backup_path(None, &vfs, path).unwrap(),
+            Path::new("dir/file.txt.orig")
+        );
+        assert_eq!(
+            backup_path(Some(b""), &vfs, path).unwrap(),
+            Path::new("dir/file.txt.orig")
+        );
+
+        // `ui.origbackuppath` mirrors the tree and creates the directories
+        assert_eq!(
+            backup_path(Some(b".hg/origbackups"), &vfs, path).unwrap(),
+            Path::new(".hg/origbackups/dir/file.txt")
+        );
+        assert!(dir.path().join(".hg/origbackups/dir").is_dir());
+    }
 }
@@ -17,6 +17,7 @@ use std::io;
 use std::iter::FusedIterator;
 use std::ops::Deref;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
 use std::time::SystemTime;
@@ -225,9 +226,9 @@ pub fn canonical_path(
     let name = name.as_ref();
 
     let name = if !name.is_absolute() {
-        root.join(cwd).join(name)
+        normalize_path(&root.join(cwd).join(name))
     } else {
-        name.to_owned()
+        normalize_path(name)
     };
     let auditor = PathAuditor::new(root);
     if name != root && name.starts_with(root) {
@@ -270,6 +271,50 @@ pub fn canonical_path(
     }
 }
 
+/// Lexically resolve the `.` and `..` components of `path`, like Python's
+/// `os.path.normpath`.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(component),
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The path of `path` relative to the directory `base`, like Python's
+/// `os.path.relpath`. Both paths are normalized first, and should be
+/// absolute.
+pub fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let path = normalize_path(path);
+    let base = normalize_path(base);
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    while let (Some(a), Some(b)) =
+        (path_components.peek(), base_components.peek())
+        && a == b
+    {
+        path_components.next();
+        base_components.next();
+    }
+    let mut relative: PathBuf =
+        base_components.map(|_| Component::ParentDir).collect();
+    relative.extend(path_components);
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    relative
+}
+
 /// Returns the representation of the path relative to the current working
 /// directory for display purposes.
 ///
@@ -367,6 +412,74 @@ pub fn is_binary(content: &[u8]) -> bool {
     !content.is_empty() && memchr::memchr(b'\0', content).is_some()
 }
 
+const WINDOWS_RESERVED_NAMES: &[&[u8]] = &[
+    b"con", b"prn", b"aux", b"nul", b"com1", b"com2", b"com3", b"com4",
+    b"com5", b"com6", b"com7", b"com8", b"com9", b"lpt1", b"lpt2", b"lpt3",
+    b"lpt4", b"lpt5", b"lpt6", b"lpt7", b"lpt8", b"lpt9",
+];
+
+/// Checks that `path` is a valid file name on Windows. Returns `None` if it
+/// is, or a message describing the problem.
+///
+/// Matches `checkwinfilename` in `util.py`.
+pub fn check_windows_filename(path: &[u8]) -> Option<String> {
+    if path.ends_with(b"\\") {
+        return Some(
+            "filename ends with '\\', which is invalid on Windows".into(),
+        );
+    }
+    if path.windows(2).any(|w| w == b"\\/") {
+        return Some(
+            "directory name ends with '\\', which is invalid on Windows".into(),
+        );
+    }
+    for component in path.split(|&b| b == b'/' || b == b'\\') {
+        if component.is_empty() {
+            continue;
+        }
+        for &byte in component {
+            if b":*?\"<>|".contains(&byte) {
+                return Some(format!(
+                    "filename contains '{}', which is reserved on Windows",
+                    byte as char
+                ));
+            }
+            if byte <= 31 {
+                let escaped = match byte {
+                    b'\t' => "\\t".to_owned(),
+                    b'\n' => "\\n".to_owned(),
+                    b'\r' => "\\r".to_owned(),
+                    _ => format!("\\x{:02x}", byte),
+                };
+                return Some(format!(
+                    "filename contains '{}', which is invalid on Windows",
+                    escaped
+                ));
+            }
+        }
+        let base = component.split(|&b| b == b'.').next().unwrap_or_default();
+        if !base.is_empty()
+            && WINDOWS_RESERVED_NAMES.contains(&&*base.to_ascii_lowercase())
+        {
+            return Some(format!(
+                "filename contains '{}', which is reserved on Windows",
+                String::from_utf8_lossy(base)
+            ));
+        }
+        let last = component[component.len() - 1];
+        if (last == b'.' || last == b' ')
+            && component != b"."
+            && component != b".."
+        {
+            return Some(format!(
+                "filename ends with '{}', which is not allowed on Windows",
+                last as char
+            ));
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
@@ -481,6 +594,19 @@ mod tests {
             canonical_path(root, cwd, name),
             Ok(PathBuf::from("subdir/filename"))
         );
+
+        let root = Path::new("/repo");
+        let cwd = Path::new("/repo/subdir");
+        let name = Path::new("../other/./filename");
+        assert_eq!(
+            canonical_path(root, cwd, name),
+            Ok(PathBuf::from("other/filename"))
+        );
+
+        let root = Path::new("/repo");
+        let cwd = Path::new("/repo/subdir");
+        let name = Path::new("..");
+        assert_eq!(canonical_path(root, cwd, name), Ok(PathBuf::from("")));
     }
 
     #[test]
@@ -519,4 +645,55 @@ mod tests {
             Ok(PathBuf::from("d"))
         );
     }
+
+    #[test]
+    fn test_relative_path() {
+        let relative = |path: &str, base: &str| {
+            relative_path(Path::new(path), Path::new(base))
+        };
+        assert_eq!(relative("/a/b/c", "/a"), Path::new("b/c"));
+        assert_eq!(relative("/a/b", "/a/c/d"), Path::new("../../b"));
+        assert_eq!(relative("/a/b", "/a/b"), Path::new("."));
+        assert_eq!(relative("/a", "/a/b/../c"), Path::new(".."));
+        assert_eq!(relative("/x/y", "/a/b"), Path::new("../../x/y"));
+    }
+
+    #[test]
+    fn test_check_windows_filename() {
+        assert_eq!(check_windows_filename(b"just/a/normal/path"), None);
+        assert_eq!(
+            check_windows_filename(b"foo/bar/con.xml").as_deref(),
+            Some("filename contains 'con', which is reserved on Windows")
+        );
+        assert_eq!(
+            check_windows_filename(b"foo/con.xml/bar").as_deref(),
+            Some("filename contains 'con', which is reserved on Windows")
+        );
+        assert_eq!(check_windows_filename(b"foo/bar/xml.con"), None);
+        assert_eq!(
+            check_windows_filename(b"foo/bar/AUX/bla.txt").as_deref(),
+            Some("filename contains 'AUX', which is reserved on Windows")
+        );
+        assert_eq!(
+            check_windows_filename(b"foo/bar/bla:.txt").as_deref(),
+            Some("filename contains ':', which is reserved on Windows")
+        );
+        assert_eq!(
+            check_windows_filename(b"foo/bar/b\x07la.txt").as_deref(),
+            Some("filename contains '\\x07', which is invalid on Windows")
+        );
+        assert_eq!(
+            check_windows_filename(b"foo/bar/bla ").as_deref(),
+            Some("filename ends with ' ', which is not allowed on Windows")
+        );
+        assert_eq!(check_windows_filename(b"../bar"), None);
+        assert_eq!(
+            check_windows_filename(b"foo\\").as_deref(),
+            Some("filename ends with '\\', which is invalid on Windows")
+        );
+        assert_eq!(
+            check_windows_filename(b"foo\\/bar").as_deref(),
+            Some("directory name ends with '\\', which is invalid on Windows")
+        );
+    }
 }
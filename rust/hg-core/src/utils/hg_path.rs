@@ -301,13 +301,11 @@ impl HgPath {
                         null_byte_index: index,
                     });
                 }
-                b'/' => {
-                    if previous_byte.is_some() && previous_byte == Some(b'/') {
-                        return Err(HgPathErrorKind::ConsecutiveSlashes {
-                            bytes: bytes.to_vec(),
-                            second_slash_index: index,
-                        });
-                    }
+                b'/' if previous_byte == Some(b'/') => {
+                    return Err(HgPathErrorKind::ConsecutiveSlashes {
+                        bytes: bytes.to_vec(),
+                        second_slash_index: index,
+                    });
                 }
                 _ => (),
             };
@@ -253,34 +253,88 @@ impl Escaped for &HgPath {
 
 #[cfg(unix)]
 pub fn shell_quote(value: &[u8]) -> Vec<u8> {
-    if value.iter().all(|&byte| {
-        matches!(
-            byte,
-            b'a'..=b'z'
-            | b'A'..=b'Z'
-            | b'0'..=b'9'
-            | b'.'
-            | b'_'
-            | b'/'
-            | b'+'
-            | b'-'
-        )
-    }) {
+    if !value.is_empty()
+        && value.iter().all(|&byte| {
+            matches!(
+                byte,
+                b'a'..=b'z'
+                | b'A'..=b'Z'
+                | b'0'..=b'9'
+                | b'.'
+                | b'_'
+                | b'/'
+                | b'+'
+                | b'-'
+            )
+        })
+    {
         value.to_owned()
     } else {
         let mut quoted = Vec::with_capacity(value.len() + 2);
         quoted.push(b'\'');
         for &byte in value {
             if byte == b'\'' {
-                quoted.push(b'\\');
+                quoted.extend_from_slice(b"'\\''");
+            } else {
+                quoted.push(byte);
             }
-            quoted.push(byte);
         }
         quoted.push(b'\'');
         quoted
     }
 }
 
+/// Percent-encode `value` like Python's `urllib.parse.quote` with its default
+/// `safe='/'`
+///
+/// # Examples
+///
+/// ```
+/// use hg::utils::strings::url_quote;
+///
+/// assert_eq!(url_quote(b"a,b c/d"), b"a%2Cb%20c/d");
+/// ```
+pub fn url_quote(value: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(value.len());
+    for &byte in value {
+        if byte.is_ascii_alphanumeric() || b"_.-~/".contains(&byte) {
+            quoted.push(byte);
+        } else {
+            write!(quoted, "%{:02X}", byte).unwrap();
+        }
+    }
+    quoted
+}
+
+/// Decode `%XX` escapes like Python's `urllib.parse.unquote_to_bytes`.
+/// Invalid escapes are kept as-is.
+///
+/// # Examples
+///
+/// ```
+/// use hg::utils::strings::url_unquote;
+///
+/// assert_eq!(url_unquote(b"a%2Cb%20c%zz"), b"a,b c%zz");
+/// ```
+pub fn url_unquote(value: &[u8]) -> Vec<u8> {
+    let hex_digit = |byte: u8| (byte as char).to_digit(16).map(|d| d as u8);
+    let mut unquoted = Vec::with_capacity(value.len());
+    let mut index = 0;
+    while index < value.len() {
+        if value[index] == b'%'
+            && let Some(high) = value.get(index + 1).and_then(|&b| hex_digit(b))
+            && let Some(low) = value.get(index + 2).and_then(|&b| hex_digit(b))
+        {
+            unquoted.push(high << 4 | low);
+            index += 3;
+        } else {
+            unquoted.push(value[index]);
+            index += 1;
+        }
+    }
+    unquoted
+}
+
 /// Expand `$FOO` and `${FOO}` environment variables in the given byte string
 pub fn expand_vars(s: &[u8]) -> std::borrow::Cow<'_, [u8]> {
     lazy_static::lazy_static! {
@@ -372,6 +426,27 @@ pub fn short_user(user: &[u8]) -> &[u8] {
     str
 }
 
+/// Renders a readable count of bytes, like `util.bytecount` in Python.
+pub fn byte_count(count: f64) -> String {
+    const UNITS: &[(f64, f64, usize, &str)] = &[
+        (100.0, (1 << 30) as f64, 0, "GB"),
+        (10.0, (1 << 30) as f64, 1, "GB"),
+        (1.0, (1 << 30) as f64, 2, "GB"),
+        (100.0, (1 << 20) as f64, 0, "MB"),
+        (10.0, (1 << 20) as f64, 1, "MB"),
+        (1.0, (1 << 20) as f64, 2, "MB"),
+        (100.0, (1 << 10) as f64, 0, "KB"),
+        (10.0, (1 << 10) as f64, 1, "KB"),
+        (1.0, (1 << 10) as f64, 2, "KB"),
+    ];
+    for &(multiplier, divisor, precision, unit) in UNITS {
+        if count.abs() >= divisor * multiplier {
+            return format!("{:.*} {}", precision, count / divisor, unit);
+        }
+    }
+    format!("{:.0} bytes", count)
+}
+
 /// Options for [`clean_whitespace`].
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum CleanWhitespace {
@@ -463,6 +538,16 @@ mod tests {
         assert_eq!(expand_vars(s), &s[..]);
     }
 
+    #[test]
+    fn test_byte_count() {
+        assert_eq!(byte_count(0.0), "0 bytes");
+        assert_eq!(byte_count(1023.0), "1023 bytes");
+        assert_eq!(byte_count(2124.0), "2.07 KB");
+        assert_eq!(byte_count(218112.5), "213 KB");
+        assert_eq!(byte_count(15.5 * (1 << 20) as f64), "15.5 MB");
+        assert_eq!(byte_count(3.0 * (1 << 30) as f64), "3.00 GB");
+    }
+
     #[test]
     fn test_short_user() {
         assert_eq!(short_user(b""), b"");
@@ -972,20 +972,30 @@ pub(crate) fn is_file(path: impl AsRef<Path>) -> Result<bool, HgIoError> {
 /// Taken from `cargo`'s codebase.
 #[cfg(target_os = "linux")]
 pub(crate) fn is_on_nfs_mount(path: impl AsRef<Path>) -> bool {
+    filesystem_type(path.as_ref()) == Some(libc::NFS_SUPER_MAGIC as u32)
+}
+
+/// Returns whether the given `path` is served by a FUSE, like the working
+/// copies of virtual shares
+#[cfg(target_os = "linux")]
+pub fn is_on_fuse_mount(path: impl AsRef<Path>) -> bool {
+    filesystem_type(path.as_ref()) == Some(libc::FUSE_SUPER_MAGIC as u32)
+}
+
+/// Returns the magic number of the file system of `path`, if it exists
+#[cfg(target_os = "linux")]
+fn filesystem_type(path: &Path) -> Option<u32> {
     use std::ffi::CString;
     use std::mem;
     use std::os::unix::prelude::*;
 
-    let path = match CString::new(path.as_ref().as_os_str().as_bytes()) {
-        Ok(path) => path,
-        Err(_) => return false,
-    };
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
 
     unsafe {
         let mut buf: libc::statfs = mem::zeroed();
         let r = libc::statfs(path.as_ptr(), &mut buf);
 
-        r == 0 && buf.f_type as u32 == libc::NFS_SUPER_MAGIC as u32
+        (r == 0).then_some(buf.f_type as u32)
     }
 }
 
@@ -999,6 +1009,12 @@ pub(crate) fn is_on_nfs_mount(_path: impl AsRef<Path>) -> bool {
     false
 }
 
+/// Virtual shares only exist on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn is_on_fuse_mount(_path: impl AsRef<Path>) -> bool {
+    false
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -5,6 +5,7 @@ use crossbeam_channel::Receiver;
 use crossbeam_channel::Sender;
 
 use crate::file_patterns::PatternFileWarning;
+use crate::operations::TrackingWarning;
 use crate::sparse::SparseNarrowWarning;
 use crate::update::UpdateWarning;
 
@@ -14,6 +15,7 @@ pub enum HgWarning {
     SparseNarrow(SparseNarrowWarning),
     PatternFile(PatternFileWarning),
     Update(UpdateWarning),
+    Tracking(TrackingWarning),
 }
 
 /// A simple convenience wrapper around a [`Sender<HgWarning>`].
@@ -96,7 +98,10 @@ pub mod format {
     use format_bytes::write_bytes;
 
     use super::*;
+    use crate::operations::NotRemovingReason;
     use crate::utils::files::get_bytes_from_path;
+    use crate::utils::hg_path::HgPath;
+    use crate::utils::strings::shell_quote;
 
     /// See this module's doc for why this function is in `hg-core`.
     #[inline(always)]
@@ -115,6 +120,11 @@ pub mod format {
             HgWarning::Update(w) => {
                 write_update_warning(w, output, working_directory)
             }
+            HgWarning::Tracking(w) => {
+                write_tracking_warning(w, output, &|path| {
+                    path.as_bytes().into()
+                })
+            }
         }
     }
 
@@ -237,4 +247,67 @@ pub mod format {
             }
         }
     }
+
+    /// Write a warning about tracked files, with `ui_path` formatting paths
+    /// for the user, typically relative to the current directory
+    pub fn write_tracking_warning(
+        warning: &TrackingWarning,
+        output: &mut dyn std::io::Write,
+        ui_path: &dyn Fn(&HgPath) -> Vec<u8>,
+    ) -> std::io::Result<()> {
+        match warning {
+            TrackingWarning::NotPortable(path, reason) => write_bytes!(
+                output,
+                b"warning: {}: {}\n",
+                reason.as_bytes(),
+                shell_quote(path.as_bytes()),
+            ),
+            TrackingWarning::DoesNotExist(path) => {
+                write_bytes!(output, b"{} does not exist!\n", ui_path(path))
+            }
+            TrackingWarning::NotAFile(path) => write_bytes!(
+                output,
+                b"{} not added: only files and symlinks supported \
+                currently\n",
+                ui_path(path),
+            ),
+            TrackingWarning::AlreadyTracked(path) => {
+                write_bytes!(output, b"{} already tracked!\n", ui_path(path))
+            }
+            TrackingWarning::LargeFile(path, size) => write_bytes!(
+                output,
+                b"{}: up to {} MB of RAM may be required to manage this \
+                file\n(use 'hg revert {}' to cancel the pending addition)\n",
+                path.as_bytes(),
+                (3 * size / 1_000_000).to_string().as_bytes(),
+                ui_path(path),
+            ),
+            TrackingWarning::NotTracked(path) => {
+                write_bytes!(output, b"{} not tracked!\n", ui_path(path))
+            }
+            TrackingWarning::NotRemoving(path, reason) => {
+                let reason: &[u8] = match reason {
+                    NotRemovingReason::AlreadyUntracked => {
+                        b"file is already untracked"
+                    }
+                    NotRemovingReason::Untracked => b"file is untracked",
+                    NotRemovingReason::NoTrackedFiles => b"no tracked files",
+                    NotRemovingReason::StillExists => b"file still exists",
+                    NotRemovingReason::Modified => {
+                        b"file is modified (use -f to force removal)"
+                    }
+                    NotRemovingReason::MarkedForAdd => {
+                        b"file has been marked for add \
+                        (use 'hg forget' to undo add)"
+                    }
+                };
+                write_bytes!(
+                    output,
+                    b"not removing {}: {}\n",
+                    ui_path(path),
+                    reason
+                )
+            }
+        }
+    }
 }
@@ -0,0 +1,856 @@
+//! The `getbundle` command, like `getbundle` in `wireprotov1server` and
+//! `getbundlechunks` in `exchange` in Python
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::FastHashSet;
+use crate::Graph;
+use crate::NULL_NODE;
+use crate::Node;
+use crate::Revision;
+use crate::ancestors::MissingAncestors;
+use crate::bookmarks::is_divergent;
+use crate::bookmarks::read_bookmarks;
+use crate::bundle::BundleCompression;
+use crate::bundle::add_stream_part;
+use crate::bundle::bundle2::Bundle2Writer;
+use crate::bundle::bundle2::PartHeader;
+use crate::bundle::caps::Capabilities;
+use crate::bundle::caps::Role;
+use crate::bundle::caps::caps_from_bundle_caps;
+use crate::bundle::caps::repo_caps;
+use crate::bundle::parts;
+use crate::bundle::rev_branch_cache_payload;
+use crate::bundle::tags_fnodes_payload;
+use crate::changegroup::ChangegroupVersion;
+use crate::changegroup::write_changegroup;
+use crate::discovery::Outgoing;
+use crate::errors::HgError;
+use crate::exit_codes;
+use crate::lock::LockError;
+use crate::repo::Repo;
+use crate::requirements::GENERALDELTA_REQUIREMENT;
+use crate::revlog::RevlogError;
+use crate::revlog::index::Phase;
+use crate::stream_clone::StreamGenerator;
+use crate::stream_clone::StreamVersion;
+use crate::stream_clone::allows_generation;
+use crate::wireprotocol::Args;
+use crate::wireprotocol::Keys;
+use crate::wireprotocol::Response;
+use crate::wireprotocol::Server;
+use crate::wireprotocol::Transport;
+use crate::wireprotocol::decode_list;
+use crate::wireprotocol::encode_keys;
+use crate::wireprotocol::head_revs;
+use crate::wireprotocol::is_known;
+
+/// The changegroup versions that rhg can send
+const OUTGOING_CHANGEGROUP_VERSIONS: &[&str] = &["01", "02", "03"];
+
+/// The options of `getbundle`, like `GETBUNDLE_ARGUMENTS` in Python
+#[derive(Default)]
+struct Options {
+    heads: Option<Vec<Node>>,
+    common: Option<Vec<Node>>,
+    bundle_caps: Option<Vec<Vec<u8>>>,
+    changegroup: Option<bool>,
+    bookmarks: bool,
+    phases: bool,
+    stream: bool,
+    listkeys: Vec<Vec<u8>>,
+    include_pats: Vec<Vec<u8>>,
+    exclude_pats: Vec<Vec<u8>>,
+    /// The names of the options other than `heads`, `common` and
+    /// `bundlecaps`, which bundle1 clients do not send
+    others: Vec<&'static str>,
+}
+
+impl Options {
+    /// Parses the known options of `args`, returning the names of the others
+    fn parse(args: Args) -> Result<(Self, Vec<Vec<u8>>), HgError> {
+        let mut options = Self::default();
+        let mut ignored = vec![];
+        let boolean = |value: &[u8]| value != b"0" && !value.is_empty();
+        let csv = |value: &[u8]| -> Vec<Vec<u8>> {
+            value.split(|&byte| byte == b',').map(<[u8]>::to_vec).collect()
+        };
+        for (name, value) in args.others {
+            let name = match &name[..] {
+                b"heads" => {
+                    options.heads = Some(decode_list(&value, b' ')?);
+                    continue;
+                }
+                b"common" => {
+                    options.common = Some(decode_list(&value, b' ')?);
+                    continue;
+                }
+                b"bundlecaps" => {
+                    let mut caps = csv(&value);
+                    caps.sort_unstable();
+                    caps.dedup();
+                    options.bundle_caps = Some(caps);
+                    continue;
+                }
+                b"cg" => {
+                    options.changegroup = Some(boolean(&value));
+                    "cg"
+                }
+                b"bookmarks" => {
+                    options.bookmarks = boolean(&value);
+                    "bookmarks"
+                }
+                b"phases" => {
+                    options.phases = boolean(&value);
+                    "phases"
+                }
+                b"stream" => {
+                    options.stream = boolean(&value);
+                    "stream"
+                }
+                b"listkeys" => {
+                    options.listkeys = csv(&value);
+                    "listkeys"
+                }
+                b"includepats" => {
+                    options.include_pats = csv(&value);
+                    "includepats"
+                }
+                b"excludepats" => {
+                    options.exclude_pats = csv(&value);
+                    "excludepats"
+                }
+                // Obsolescence markers are never sent, see `check_supported`
+                b"obsmarkers" => "obsmarkers",
+                b"cbattempted" => "cbattempted",
+                _ => {
+                    ignored.push(name);
+                    continue;
+                }
+            };
+            options.others.push(name);
+        }
+        Ok((options, ignored))
+    }
+
+    /// Whether the client asked for a bundle2, like `bundle2requested` in
+    /// Python
+    fn wants_bundle2(&self) -> bool {
+        self.bundle_caps.iter().flatten().any(|cap| cap.starts_with(b"HG2"))
+    }
+}
+
+/// A bundle to stream to a client, generated as it is written
+pub struct Bundle(Kind);
+
+enum Kind {
+    /// A version 01 changegroup, for clients not supporting bundle2
+    Changegroup {
+        outgoing: Outgoing,
+        fast_path: bool,
+    },
+    Bundle2(Bundle2Parts),
+    /// A bundle2 telling the client that the request failed
+    Error {
+        message: String,
+        hint: Option<String>,
+    },
+}
+
+/// The parts of a bundle2 answering a `getbundle` request, in order
+struct Bundle2Parts {
+    stream: Option<StreamVersion>,
+    changegroup: Option<ChangegroupPart>,
+    bookmarks: Vec<u8>,
+    listkeys: Keys,
+    phase_heads: Option<Vec<u8>>,
+    tags_fnodes: Vec<u8>,
+    rev_branch_cache: Option<Vec<u8>>,
+}
+
+struct ChangegroupPart {
+    /// `None` for the clients that do not list the versions they support,
+    /// which get version 01 without saying
+    version: Option<ChangegroupVersion>,
+    outgoing: Outgoing,
+    fast_path: bool,
+}
+
+pub(super) fn getbundle(
+    server: &mut Server,
+    args: Args,
+) -> Result<Response, HgError> {
+    let options = parse_request(server, args)?;
+    let http = server.transport == Transport::Http;
+    if refuses_bundle1(server, &options)? {
+        if http {
+            let message =
+                format!("{}\n({})\n", BUNDLE2_REQUIRED, BUNDLE2_REQUIRED_HINT);
+            return Ok(Response::Error(message.into_bytes()));
+        }
+        return Err(bundle2_required());
+    }
+    match prepare(server, &options) {
+        Ok(kind) => Ok(Response::Bundle(Box::new(Bundle(kind)))),
+        // Bundle2 clients expect the error in a bundle
+        Err(HgError::Abort { message, hint, .. })
+            if options.wants_bundle2() =>
+        {
+            Ok(Response::Bundle(Box::new(Bundle(Kind::Error {
+                message,
+                hint,
+            }))))
+        }
+        Err(HgError::Abort { message, .. }) if http => {
+            Ok(Response::Error(format!("{}\n", message).into_bytes()))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+const BUNDLE2_REQUIRED: &str =
+    "incompatible Mercurial client; bundle2 required";
+const BUNDLE2_REQUIRED_HINT: &str =
+    "see https://www.mercurial-scm.org/wiki/IncompatibleClient";
+
+/// Parses the arguments of a `getbundle` request, warning about those that
+/// are ignored
+fn parse_request(server: &mut Server, args: Args) -> Result<Options, HgError> {
+    let (options, ignored) = Options::parse(args)?;
+    if !ignored.is_empty() {
+        let mut message =
+            b"warning: getbundle ignored unexpected arguments ".to_vec();
+        message.extend(ignored.join(&b','));
+        message.push(b'\n');
+        server.messages.push(message);
+    }
+    Ok(options)
+}
+
+/// Whether a client that does not support bundle2 may get `options`
+fn refuses_bundle1(
+    server: &Server,
+    options: &Options,
+) -> Result<bool, HgError> {
+    Ok(!options.wants_bundle2() && !bundle1_allowed(server.repo)?)
+}
+
+fn bundle2_required() -> HgError {
+    HgError::abort(
+        BUNDLE2_REQUIRED,
+        exit_codes::ABORT,
+        Some(BUNDLE2_REQUIRED_HINT.to_owned()),
+    )
+}
+
+/// Whether clients not supporting bundle2 may pull, like
+/// `bundle1allowed(repo, b'pull')` in Python
+fn bundle1_allowed(repo: &Repo) -> Result<bool, HgError> {
+    let config = repo.config();
+    let generaldelta = repo.requirements().contains(GENERALDELTA_REQUIREMENT);
+    let mut items: Vec<&[u8]> = vec![b"bundle1.pull"];
+    if generaldelta {
+        items = vec![b"bundle1gd.pull", b"bundle1.pull", b"bundle1gd"];
+    }
+    for item in items {
+        if let Some(allowed) = config.get_option_no_default(b"server", item)? {
+            return Ok(allowed);
+        }
+    }
+    config.get_bool(b"server", b"bundle1")
+}
+
+/// Decides what to send, checking the request
+fn prepare(server: &mut Server, options: &Options) -> Result<Kind, HgError> {
+    let repo = server.repo;
+    let config = repo.config();
+    let wants_changegroup = options.changegroup.unwrap_or(true);
+    let common: Vec<Node> = options
+        .common
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|node| *node != NULL_NODE)
+        .collect();
+    if config.get_bool(b"server", b"disablefullbundle")? {
+        let changelog = repo.changelog()?;
+        let all_heads: FastHashSet<Node> = head_revs(&changelog)?
+            .into_iter()
+            .map(|rev| *changelog.node_from_rev(rev))
+            .collect();
+        let heads: FastHashSet<Node> =
+            options.heads.iter().flatten().copied().collect();
+        if wants_changegroup && common.is_empty() && heads == all_heads {
+            return Err(HgError::abort(
+                "server has pull-based clones disabled",
+                exit_codes::ABORT,
+                Some("remove --pull if specified or upgrade Mercurial".into()),
+            ));
+        }
+    }
+
+    if !options.wants_bundle2() {
+        let has_bundle_caps = options.bundle_caps.is_some();
+        if has_bundle_caps && !wants_changegroup {
+            return Err(HgError::abort_simple(
+                "request for bundle10 must include changegroup",
+            ));
+        }
+        if !options.others.is_empty() {
+            let mut others = options.others.clone();
+            others.sort_unstable();
+            return Err(HgError::abort_simple(format!(
+                "unsupported getbundle arguments: {}",
+                others.join(", ")
+            )));
+        }
+        let (outgoing, fast_path) =
+            compute_outgoing(repo, options.heads.as_deref(), &common)?;
+        server.changesets_found(&outgoing)?;
+        return Ok(Kind::Changegroup { outgoing, fast_path });
+    }
+
+    let caps = caps_from_bundle_caps(
+        options.bundle_caps.iter().flatten().map(Vec::as_slice),
+    );
+    let stream = if options.stream {
+        Some(stream_version(repo, &caps, options)?)
+    } else {
+        None
+    };
+
+    let mut changegroup = None;
+    if wants_changegroup && !caps.is_empty() {
+        let versions = caps.get(&b"changegroup"[..]).filter(|v| !v.is_empty());
+        let version = match versions {
+            Some(versions) => {
+                let version = versions
+                    .iter()
+                    .filter_map(|v| std::str::from_utf8(v).ok())
+                    .filter(|v| OUTGOING_CHANGEGROUP_VERSIONS.contains(v))
+                    .max()
+                    .and_then(|v| ChangegroupVersion::from_bytes(v.as_bytes()))
+                    .ok_or_else(|| {
+                        HgError::abort_simple("no common changegroup version")
+                    })?;
+                Some(version)
+            }
+            None => None,
+        };
+        let (outgoing, fast_path) =
+            compute_outgoing(repo, options.heads.as_deref(), &common)?;
+        if !outgoing.missing.is_empty() {
+            server.changesets_found(&outgoing)?;
+            changegroup =
+                Some(ChangegroupPart { version, outgoing, fast_path });
+        }
+    }
+
+    let mut bookmarks = vec![];
+    if options.bookmarks {
+        if !caps.contains_key(&b"bookmarks"[..]) {
+            return Err(HgError::abort_simple(
+                "no common bookmarks exchange method",
+            ));
+        }
+        let marks = read_bookmarks(repo)?;
+        let marks: Vec<(&[u8], Option<Node>)> = marks
+            .iter()
+            .filter(|(name, _)| !is_divergent(name))
+            .map(|(name, node)| (name.as_slice(), Some(*node)))
+            .collect();
+        bookmarks = parts::encode_bookmarks(&marks);
+    }
+
+    let mut listkeys = vec![];
+    for namespace in &options.listkeys {
+        let keys = server.list_keys(namespace)?;
+        listkeys.push((namespace.clone(), encode_keys(&keys)));
+    }
+
+    let mut phase_heads = None;
+    if options.phases {
+        let exchanges_heads = caps
+            .get(&b"phases"[..])
+            .is_some_and(|methods| methods.iter().any(|m| m == b"heads"));
+        if !exchanges_heads {
+            return Err(HgError::abort_simple(
+                "no common phases exchange method",
+            ));
+        }
+        let heads = heads_by_phase(repo, options.heads.as_deref())?;
+        phase_heads = Some(parts::encode_phase_heads(&heads));
+    }
+
+    let mut tags_fnodes = vec![];
+    let mut rev_branch_cache = None;
+    if wants_changegroup && caps.contains_key(&b"hgtagsfnodes"[..]) {
+        let (mut outgoing, _) =
+            compute_outgoing(repo, options.heads.as_deref(), &common)?;
+        if options.heads.as_ref().is_some_and(|h| !h.is_empty())
+            && changegroup.is_some()
+        {
+            // Python sorts the heads of the request in place when
+            // generating the changegroup
+            let changelog = repo.changelog()?;
+            outgoing.ancestors_of.sort_unstable_by(|a, b| {
+                let node = |rev| changelog.node_from_rev(rev).as_bytes();
+                node(*a).cmp(node(*b))
+            });
+        }
+        tags_fnodes = tags_fnodes_payload(repo, &outgoing.ancestors_of)?;
+    }
+    if wants_changegroup && caps.contains_key(&b"rev-branch-cache"[..]) {
+        let (outgoing, _) =
+            compute_outgoing(repo, options.heads.as_deref(), &common)?;
+        rev_branch_cache =
+            Some(rev_branch_cache_payload(repo, &outgoing.missing)?);
+    }
+
+    Ok(Kind::Bundle2(Bundle2Parts {
+        stream,
+        changegroup,
+        bookmarks,
+        listkeys,
+        phase_heads,
+        tags_fnodes,
+        rev_branch_cache,
+    }))
+}
+
+/// The version of the stream clone to send, with the checks of
+/// `addpartbundlestream2` in Python
+fn stream_version(
+    repo: &Repo,
+    caps: &Capabilities,
+    options: &Options,
+) -> Result<StreamVersion, HgError> {
+    let buggy_client = || Some("the client seems buggy".to_owned());
+    if !allows_generation(repo)? {
+        return Err(HgError::abort(
+            "stream data requested but server does not allow this feature",
+            exit_codes::ABORT,
+            buggy_client(),
+        ));
+    }
+    let Some(client_versions) = caps.get(&b"stream"[..]) else {
+        return Err(HgError::abort(
+            "stream data requested but supported streaming clone versions \
+             were not specified",
+            exit_codes::ABORT,
+            buggy_client(),
+        ));
+    };
+    let server_versions = repo_caps(repo, Role::Client)?
+        .remove(&b"stream"[..])
+        .unwrap_or_default();
+    let version = client_versions
+        .iter()
+        .filter(|version| server_versions.contains(version))
+        .filter_map(|version| std::str::from_utf8(version).ok())
+        .filter_map(StreamVersion::from_name)
+        .max();
+    let Some(version) = version else {
+        let sorted = |versions: &[Vec<u8>]| {
+            let versions: BTreeSet<_> =
+                versions.iter().map(|v| String::from_utf8_lossy(v)).collect();
+            versions.into_iter().collect::<Vec<_>>().join(",")
+        };
+        return Err(HgError::abort_simple(format!(
+            "no common supported version with the client: {}; {}",
+            sorted(&server_versions),
+            sorted(client_versions)
+        )));
+    };
+    let narrow = options.include_pats.iter().any(|p| !p.is_empty())
+        || options.exclude_pats.iter().any(|p| !p.is_empty());
+    if narrow {
+        return Err(HgError::abort_simple(
+            "server does not support narrow stream clones",
+        ));
+    }
+    Ok(version)
+}
+
+/// The changesets to send for the request of `heads`, all of them if
+/// `None` or empty, when the client has `common`. Also returns whether the
+/// changegroup can take the fast path, when all the heads are requested.
+///
+/// Like `_computeoutgoing` in Python.
+fn compute_outgoing(
+    repo: &Repo,
+    heads: Option<&[Node]>,
+    common: &[Node],
+) -> Result<(Outgoing, bool), HgError> {
+    let changelog = repo.changelog()?;
+    let mut common_revs = vec![];
+    for &node in common {
+        if is_known(&changelog, node)? {
+            common_revs.push(changelog.rev_from_node(node.into())?);
+        }
+    }
+    let all_heads = head_revs(&changelog)?;
+    let heads = match heads {
+        Some(heads) if !heads.is_empty() => heads
+            .iter()
+            .map(|&node| changelog.rev_from_node(node.into()))
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => all_heads.clone(),
+    };
+    let mut sorted_heads = heads.clone();
+    sorted_heads.sort_unstable();
+    let fast_path = sorted_heads == all_heads;
+    let outgoing = Outgoing::new(changelog.get_index(), common_revs, heads)
+        .map_err(RevlogError::from)?;
+    Ok((outgoing, fast_path))
+}
+
+/// The heads to send in a `phase-heads` part, like `_getbundlephasespart`
+/// in Python
+fn heads_by_phase(
+    repo: &Repo,
+    heads: Option<&[Node]>,
+) -> Result<Vec<(Phase, Node)>, HgError> {
+    let changelog = repo.changelog()?;
+    let index = changelog.get_index();
+    let heads: Vec<Revision> = match heads {
+        Some(heads) => heads
+            .iter()
+            .map(|&node| changelog.rev_from_node(node.into()))
+            .collect::<Result<_, _>>()?,
+        None => head_revs(&changelog)?,
+    };
+    let mut by_phase: BTreeMap<Phase, BTreeSet<Revision>> = BTreeMap::new();
+    if repo.config().get_bool(b"phases", b"publish")? {
+        by_phase.insert(Phase::Public, heads.into_iter().collect());
+    } else {
+        let phases = repo
+            .store()
+            .phase_cache()?
+            .phases(index)
+            .map_err(RevlogError::from)?;
+        for head in heads {
+            by_phase.entry(phases[head.0 as usize]).or_default().insert(head);
+        }
+        // The public heads among the ancestors of the draft heads that the
+        // public heads do not cover:
+        // heads(only(draft heads, public heads) and public())
+        if let Some(draft_heads) = by_phase.get(&Phase::Draft) {
+            let public_heads =
+                by_phase.get(&Phase::Public).cloned().unwrap_or_default();
+            let only: BTreeSet<Revision> =
+                MissingAncestors::new(index, public_heads)
+                    .missing_ancestors(draft_heads.iter().copied())
+                    .map_err(RevlogError::from)?
+                    .into_iter()
+                    .filter(|rev| phases[rev.0 as usize] == Phase::Public)
+                    .collect();
+            let mut parents = BTreeSet::new();
+            for &rev in &only {
+                parents.extend(index.parents(rev).map_err(RevlogError::from)?);
+            }
+            by_phase
+                .entry(Phase::Public)
+                .or_default()
+                .extend(only.difference(&parents));
+        }
+    }
+    let mut encoded = vec![];
+    for (phase, revs) in by_phase {
+        let mut nodes: Vec<Node> =
+            revs.iter().map(|&rev| *changelog.node_from_rev(rev)).collect();
+        nodes.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        encoded.extend(nodes.into_iter().map(|node| (phase, node)));
+    }
+    Ok(encoded)
+}
+
+impl Server<'_> {
+    /// The bundle answering a `getbundle` request with `args`, returning
+    /// errors rather than sending them in a bundle, like `getbundlechunks`
+    /// in Python
+    pub(crate) fn get_bundle(&mut self, args: Args) -> Result<Bundle, HgError> {
+        let options = parse_request(self, args)?;
+        if refuses_bundle1(self, &options)? {
+            return Err(bundle2_required());
+        }
+        Ok(Bundle(prepare(self, &options)?))
+    }
+
+    /// Tells how many changesets are about to be sent, like
+    /// `_changegroupinfo` in Python
+    fn changesets_found(&mut self, outgoing: &Outgoing) -> Result<(), HgError> {
+        let config = self.repo.config();
+        if config.get_bool(b"ui", b"verbose")? {
+            let message =
+                format!("{} changesets found\n", outgoing.missing.len());
+            self.messages.push(message.into_bytes());
+        }
+        if config.get_bool(b"ui", b"debug")? {
+            let changelog = self.repo.changelog()?;
+            let mut message = b"list of changesets:\n".to_vec();
+            for &rev in &outgoing.missing {
+                let node = changelog.node_from_rev(rev);
+                message.extend(format!("{:x}\n", node).into_bytes());
+            }
+            self.messages.push(message);
+        }
+        Ok(())
+    }
+}
+
+impl Bundle {
+    /// Whether compressing the bundle for the transport is worth it, which
+    /// it is not for errors and for stream clones, which are large and
+    /// compress poorly, like `prefercompressed` in Python
+    pub fn prefers_compression(&self) -> bool {
+        match &self.0 {
+            Kind::Changegroup { .. } => true,
+            Kind::Bundle2(parts) => parts.stream.is_none(),
+            Kind::Error { .. } => false,
+        }
+    }
+
+    /// Generates the bundle of `repo` into `out`
+    pub fn write(
+        &self,
+        repo: &Repo,
+        out: &mut impl Write,
+    ) -> Result<(), HgError> {
+        let parts = match &self.0 {
+            Kind::Changegroup { outgoing, fast_path } => {
+                let version = ChangegroupVersion::V1;
+                write_changegroup(repo, outgoing, version, *fast_path, out)?;
+                return Ok(());
+            }
+            Kind::Error { message, hint } => {
+                let mut bundle =
+                    Bundle2Writer::new(out, &[], BundleCompression::None)?;
+                let mut header = PartHeader::new("error:abort", true);
+                header.add_param("message", message.as_bytes(), true);
+                if let Some(hint) = hint {
+                    header.add_param("hint", hint.as_bytes(), false);
+                }
+                bundle.add_part(header, &[])?;
+                bundle.finish()?;
+                return Ok(());
+            }
+            Kind::Bundle2(parts) => parts,
+        };
+        let mut bundle = Bundle2Writer::new(out, &[], BundleCompression::None)?;
+        if let Some(version) = parts.stream {
+            let timeout =
+                repo.config().get_u64(b"ui", b"timeout")?.unwrap_or(600);
+            let generator = match repo
+                .try_with_lock(Duration::from_secs(timeout), || {
+                    StreamGenerator::new(repo, false)
+                }) {
+                Ok(generator) => generator?,
+                Err(LockError::AlreadyHeld) => {
+                    return Err(HgError::abort_simple(
+                        "timed out waiting for the store lock",
+                    ));
+                }
+                Err(LockError::IO(error)) => return Err(error.into()),
+            };
+            add_stream_part(&mut bundle, repo, &generator, version)?;
+        }
+        if let Some(part) = &parts.changegroup {
+            let changesets = part.outgoing.missing.len();
+            let header = match part.version {
+                Some(version) => parts::changegroup_header(version, changesets),
+                None => {
+                    let mut header = PartHeader::new("changegroup", true);
+                    header.add_param(
+                        "nbchanges",
+                        changesets.to_string(),
+                        false,
+                    );
+                    header
+                }
+            };
+            let version = part.version.unwrap_or(ChangegroupVersion::V1);
+            let mut writer = bundle.start_part(header)?;
+            write_changegroup(
+                repo,
+                &part.outgoing,
+                version,
+                part.fast_path,
+                &mut writer,
+            )?;
+            writer.finish()?;
+        }
+        if !parts.bookmarks.is_empty() {
+            bundle.add_part(
+                PartHeader::new("bookmarks", true),
+                &parts.bookmarks,
+            )?;
+        }
+        for (namespace, keys) in &parts.listkeys {
+            let mut header = PartHeader::new("listkeys", true);
+            header.add_param("namespace", namespace.as_slice(), true);
+            bundle.add_part(header, keys)?;
+        }
+        if let Some(phase_heads) = &parts.phase_heads {
+            bundle
+                .add_part(PartHeader::new("phase-heads", true), phase_heads)?;
+        }
+        if !parts.tags_fnodes.is_empty() {
+            bundle.add_part(
+                PartHeader::new("hgtagsfnodes", false),
+                &parts.tags_fnodes,
+            )?;
+        }
+        if let Some(cache) = &parts.rev_branch_cache {
+            bundle.add_part(
+                PartHeader::new("cache:rev-branch-cache", false),
+                cache,
+            )?;
+        }
+        bundle.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::Bundle as ReadBundle;
+    use crate::bundle::caps::encode_caps;
+    use crate::bundle::read_bundle;
+    use crate::changegroup::ChangegroupUnpacker;
+    use crate::changegroup::RevlogTarget;
+    use crate::testing::TestRepo;
+    use crate::utils::hg_path::HgPathBuf;
+    use crate::utils::strings::url_quote;
+    use crate::wireprotocol::encode_list;
+
+    /// What a bundle2 answering `getbundle` decodes to
+    #[derive(Debug, Default, PartialEq)]
+    struct Decoded {
+        changesets: Vec<Node>,
+        /// The filelog of each file revision
+        files: Vec<RevlogTarget>,
+        phase_heads: Vec<(Phase, Node)>,
+        bookmarks: Vec<parts::BookmarkChange>,
+    }
+
+    fn get_bundle(repo: &Repo, common: &[Node], heads: &[Node]) -> Decoded {
+        let caps = encode_caps(&repo_caps(repo, Role::Client).unwrap());
+        let bundle_caps = [&b"HG20,bundle2="[..], &url_quote(&caps)].concat();
+        let mut args = Args::default();
+        args.insert_other(b"bundlecaps".to_vec(), bundle_caps);
+        args.insert_other(b"common".to_vec(), encode_list(common));
+        args.insert_other(b"heads".to_vec(), encode_list(heads));
+        args.insert_other(b"cg".to_vec(), b"1".to_vec());
+        args.insert_other(b"phases".to_vec(), b"1".to_vec());
+        args.insert_other(b"bookmarks".to_vec(), b"1".to_vec());
+        let mut server = Server::new(repo, Transport::Ssh);
+        let mut out = vec![];
+        server.get_bundle(args).unwrap().write(repo, &mut out).unwrap();
+
+        let mut decoded = Decoded::default();
+        let ReadBundle::Bundle2(mut reader) =
+            read_bundle(&out[..], "test").unwrap()
+        else {
+            panic!("not a bundle2");
+        };
+        while let Some(mut part) = reader.next_part().unwrap() {
+            match part.header.part_type.as_str() {
+                "changegroup" => {
+                    let version =
+                        parts::changegroup_version(&part.header).unwrap();
+                    let payload = part.read_payload().unwrap();
+                    let mut unpacker =
+                        ChangegroupUnpacker::new(&payload[..], version);
+                    unpacker
+                        .unpack(|target, delta| -> Result<(), HgError> {
+                            match target {
+                                RevlogTarget::Changelog => {
+                                    decoded.changesets.push(delta.node)
+                                }
+                                RevlogTarget::Manifest(_) => {}
+                                target => decoded.files.push(target.clone()),
+                            }
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+                "phase-heads" => {
+                    let payload = part.read_payload().unwrap();
+                    decoded.phase_heads =
+                        parts::decode_phase_heads(&payload).unwrap();
+                }
+                "bookmarks" => {
+                    let payload = part.read_payload().unwrap();
+                    decoded.bookmarks =
+                        parts::decode_bookmarks(&payload).unwrap();
+                }
+                _ => {}
+            }
+        }
+        decoded
+    }
+
+    #[test]
+    fn test_get_bundle() {
+        let mut test_repo = TestRepo::new(&["phases.publish=no"]);
+        let nodes = {
+            let first = test_repo.commit(NULL_NODE, &[("a", Some("0"))]);
+            let second = test_repo.commit(first, &[("b", Some("1"))]);
+            let third = test_repo.commit(second, &[("a", Some("2"))]);
+            let fourth = test_repo.commit(first, &[("c", Some("3"))]);
+            [first, second, third, fourth]
+        };
+        let repo = &test_repo.repo;
+        // The third changeset is a draft, the others are public
+        let phaseroots = format!("1 {:x}\n", nodes[2]);
+        std::fs::write(repo.store_path().join("phaseroots"), phaseroots)
+            .unwrap();
+        let bookmarks = format!("{:x} feature\n", nodes[3]);
+        std::fs::write(repo.hg_vfs().join("bookmarks"), bookmarks).unwrap();
+        let bookmarks = vec![(b"feature".to_vec(), Some(nodes[3]))];
+        let file = |path: &str| {
+            RevlogTarget::Filelog(HgPathBuf::from_bytes(path.as_bytes()))
+        };
+        let sorted = |mut nodes: Vec<Node>| {
+            nodes.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+            nodes
+        };
+
+        let decoded = get_bundle(repo, &[], &[nodes[2], nodes[3]]);
+        let public_heads = sorted(vec![nodes[1], nodes[3]]);
+        assert_eq!(
+            decoded,
+            Decoded {
+                changesets: nodes.to_vec(),
+                files: vec![file("a"), file("a"), file("b"), file("c")],
+                phase_heads: vec![
+                    (Phase::Public, public_heads[0]),
+                    (Phase::Public, public_heads[1]),
+                    (Phase::Draft, nodes[2]),
+                ],
+                bookmarks: bookmarks.clone(),
+            }
+        );
+
+        // Only what is missing from common, and the phases of the heads
+        // asked for
+        let decoded = get_bundle(repo, &[nodes[1]], &[nodes[2]]);
+        assert_eq!(
+            decoded,
+            Decoded {
+                changesets: vec![nodes[2]],
+                files: vec![file("a")],
+                phase_heads: vec![
+                    (Phase::Public, nodes[1]),
+                    (Phase::Draft, nodes[2])
+                ],
+                bookmarks,
+            }
+        );
+    }
+}
@@ -0,0 +1,697 @@
+//! The HTTP transport of version 1 of the wire protocol, like
+//! `httpv1protocolhandler` in Python
+//!
+//! Commands are requests to the root of the repository with a `cmd` query
+//! parameter, their arguments being in the query, in `X-HgArg-<n>` headers
+//! or at the start of the body. This also has the small part of HTTP/1.1
+//! that Mercurial clients need, so that serving them does not take a web
+//! server.
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+
+use crate::bundle::BundleCompression;
+use crate::bundle::CompressedWriter;
+use crate::config::Config;
+use crate::errors::HgError;
+use crate::errors::HgIoError;
+use crate::errors::IoErrorContext;
+use crate::utils::strings::url_quote;
+use crate::utils::strings::url_unquote;
+use crate::wireprotocol::Args;
+use crate::wireprotocol::Command;
+
+/// The media type of responses, uncompressed except for bundles which are
+/// compressed with zlib
+pub const MEDIA_TYPE: &str = "application/mercurial-0.1";
+/// The media type of responses starting with the name of their compression
+pub const MEDIA_TYPE_2: &str = "application/mercurial-0.2";
+/// The media type of errors reported out of band
+pub const ERROR_MEDIA_TYPE: &str = "application/hg-error";
+
+/// The longest request line or header line accepted
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+/// The most header lines accepted in a request, which is plenty for the
+/// arguments of a large `getbundle` split into `X-HgArg-<n>` headers
+const MAX_HEADERS: usize = 16 * 1024;
+
+/// The capabilities that the HTTP transport adds to those of the repository
+pub(super) fn capabilities(config: &Config) -> Result<Vec<Vec<u8>>, HgError> {
+    let mut caps = vec![b"batch".to_vec()];
+    let header_length =
+        config.get_u64(b"server", b"maxhttpheaderlen")?.unwrap_or(1024);
+    caps.push(format!("httpheader={}", header_length).into_bytes());
+    if config.get_bool(b"experimental", b"httppostargs")? {
+        caps.push(b"httppostargs".to_vec());
+    }
+    caps.push(b"httpmediatype=0.1rx,0.1tx,0.2tx".to_vec());
+    let compressions = server_compressions(config)?;
+    if !compressions.is_empty() {
+        let names: Vec<Vec<u8>> = compressions
+            .iter()
+            .map(|&compression| url_quote(wire_name(compression).as_bytes()))
+            .collect();
+        let mut cap = b"compression=".to_vec();
+        cap.extend(names.join(&b','));
+        caps.push(cap);
+    }
+    Ok(caps)
+}
+
+/// The name of a compression engine in the configuration
+fn engine_name(compression: BundleCompression) -> &'static str {
+    match compression {
+        BundleCompression::None => "none",
+        BundleCompression::Bzip2 => "bz2",
+        BundleCompression::Gzip => "zlib",
+        BundleCompression::Zstd => "zstd",
+    }
+}
+
+/// The name of a compression engine in the protocol
+fn wire_name(compression: BundleCompression) -> &'static str {
+    match compression {
+        BundleCompression::None => "none",
+        BundleCompression::Bzip2 => "bzip2",
+        BundleCompression::Gzip => "zlib",
+        BundleCompression::Zstd => "zstd",
+    }
+}
+
+/// The compression engines that the server may use, by decreasing
+/// preference, like `supportedcompengines` in Python
+fn server_compressions(
+    config: &Config,
+) -> Result<Vec<BundleCompression>, HgError> {
+    let configured =
+        config.get_list(b"server", b"compressionengines").unwrap_or_default();
+    if configured.is_empty() {
+        return Ok(vec![BundleCompression::Zstd, BundleCompression::Gzip]);
+    }
+    let all = [
+        BundleCompression::Zstd,
+        BundleCompression::Gzip,
+        BundleCompression::Bzip2,
+        BundleCompression::None,
+    ];
+    let find = |name: &[u8]| {
+        all.into_iter().find(|&c| engine_name(c).as_bytes() == name)
+    };
+    let mut invalid: Vec<String> = configured
+        .iter()
+        .filter(|name| find(name).is_none())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect();
+    if !invalid.is_empty() {
+        invalid.sort_unstable();
+        invalid.dedup();
+        return Err(HgError::abort_simple(format!(
+            "invalid compression engine defined in \
+             server.compressionengines: {}",
+            invalid.join(", ")
+        )));
+    }
+    let mut compressions = vec![];
+    for compression in configured.iter().filter_map(|name| find(name)) {
+        if !compressions.contains(&compression) {
+            compressions.push(compression);
+        }
+    }
+    Ok(compressions)
+}
+
+/// How to send a response that may be compressed, like
+/// `_httpresponsetype` in Python
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    pub media_type: &'static str,
+    pub compression: BundleCompression,
+    /// The configured compression level, if any
+    pub level: Option<i32>,
+}
+
+impl Encoding {
+    /// Picks the media type and compression for a client that sent the
+    /// `X-HgProto-<n>` parameters `protocaps`, compressing with zlib for
+    /// the clients that do not say what they support
+    pub fn negotiate(
+        config: &Config,
+        protocaps: &[Vec<u8>],
+        prefer_uncompressed: bool,
+    ) -> Result<Self, HgError> {
+        if protocaps.iter().any(|cap| cap == b"0.2") {
+            if prefer_uncompressed {
+                return Ok(Self {
+                    media_type: MEDIA_TYPE_2,
+                    compression: BundleCompression::None,
+                    level: None,
+                });
+            }
+            let client: Vec<&[u8]> = protocaps
+                .iter()
+                .find_map(|cap| cap.strip_prefix(b"comp="))
+                .map(|names| names.split(|&byte| byte == b',').collect())
+                .unwrap_or_else(|| vec![&b"zlib"[..], &b"none"[..]]);
+            for compression in server_compressions(config)? {
+                if client.contains(&wire_name(compression).as_bytes()) {
+                    let item = format!("{}level", engine_name(compression));
+                    return Ok(Self {
+                        media_type: MEDIA_TYPE_2,
+                        compression,
+                        level: compression_level(config, item.as_bytes())?,
+                    });
+                }
+            }
+            // Without a compression that both support, use the media type
+            // that all clients support
+        }
+        Ok(Self {
+            media_type: MEDIA_TYPE,
+            compression: BundleCompression::Gzip,
+            level: compression_level(config, b"zliblevel")?,
+        })
+    }
+
+    /// Wraps `out` to write a response body with this encoding, starting
+    /// with the name of the compression for `application/mercurial-0.2`
+    pub fn writer<W: Write>(
+        &self,
+        mut out: W,
+    ) -> Result<CompressedWriter<W>, HgError> {
+        if self.media_type == MEDIA_TYPE_2 {
+            let name = wire_name(self.compression);
+            out.write_all(&[name.len() as u8])
+                .and_then(|()| out.write_all(name.as_bytes()))
+                .map_err(writing_error)?;
+        }
+        CompressedWriter::with_level(out, self.compression, self.level)
+    }
+}
+
+fn compression_level(
+    config: &Config,
+    item: &[u8],
+) -> Result<Option<i32>, HgError> {
+    let Some(level) = config.get_i64(b"server", item)? else {
+        return Ok(None);
+    };
+    i32::try_from(level).map(Some).map_err(|_| {
+        HgError::abort_simple(format!(
+            "invalid compression level for server.{}: {}",
+            String::from_utf8_lossy(item),
+            level
+        ))
+    })
+}
+
+/// Parses a query string into its parameters, keeping those without values
+/// like `parseqs(.., keep_blank_values=True)` in Python
+pub fn parse_query(query: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let decode = |value: &[u8]| {
+        let spaced: Vec<u8> = value
+            .iter()
+            .map(|&byte| if byte == b'+' { b' ' } else { byte })
+            .collect();
+        url_unquote(&spaced)
+    };
+    query
+        .split(|&byte| byte == b'&')
+        .filter(|parameter| !parameter.is_empty())
+        .map(|parameter| {
+            match parameter.iter().position(|&byte| byte == b'=') {
+                Some(equal) => (
+                    decode(&parameter[..equal]),
+                    decode(&parameter[equal + 1..]),
+                ),
+                None => (decode(parameter), vec![]),
+            }
+        })
+        .collect()
+}
+
+/// The arguments of `command` from the query string of the request and from
+/// `encoded_args`, which come from its headers or its body and take
+/// precedence, like `getargs` in Python
+pub fn request_args(
+    command: &Command,
+    query: &[u8],
+    encoded_args: &[u8],
+) -> Args {
+    let mut values: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+    for source in [query, encoded_args] {
+        let mut seen = BTreeMap::new();
+        for (name, value) in parse_query(source) {
+            // The first value of each parameter counts
+            seen.entry(name).or_insert(value);
+        }
+        values.extend(seen);
+    }
+    let mut args = Args::default();
+    for name in command.arg_names() {
+        if name == "*" {
+            for (other, value) in &values {
+                if other != b"cmd"
+                    && !command.arg_names().any(|n| n.as_bytes() == other)
+                {
+                    args.insert_other(other.clone(), value.clone());
+                }
+            }
+        } else if let Some(value) = values.get(name.as_bytes()) {
+            args.insert(name.as_bytes().to_vec(), value.clone());
+        }
+    }
+    args
+}
+
+/// The headers and request line of an HTTP request, its body left to read
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path and the query string, as sent
+    pub target: Vec<u8>,
+    pub version: String,
+    /// The names of the headers, as sent, with their values
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Request {
+    /// Reads the request line and the headers of the next request, `None`
+    /// at the end of the stream. A malformed request is an abort error.
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Self>, HgError> {
+        let mut line = read_line(reader)?;
+        // Clients may send an empty line before a request
+        while line.as_deref() == Some(b"") {
+            line = read_line(reader)?;
+        }
+        let Some(line) = line else {
+            return Ok(None);
+        };
+        let bad_request = || {
+            HgError::abort_simple(format!(
+                "bad request line: {}",
+                String::from_utf8_lossy(&line)
+            ))
+        };
+        let mut fields = line.split(|&byte| byte == b' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(bad_request());
+        };
+        if !version.starts_with(b"HTTP/1.") || method.is_empty() {
+            return Err(bad_request());
+        }
+        let mut request = Self {
+            method: String::from_utf8_lossy(method).into_owned(),
+            target: target.to_vec(),
+            version: String::from_utf8_lossy(version).into_owned(),
+            headers: vec![],
+        };
+        loop {
+            let line = read_line(reader)?.ok_or_else(|| {
+                HgError::abort_simple("incomplete request headers")
+            })?;
+            if line.is_empty() {
+                return Ok(Some(request));
+            }
+            if line[0] == b' ' || line[0] == b'\t' {
+                // The continuation of the previous header
+                let Some((_, value)) = request.headers.last_mut() else {
+                    return Err(HgError::abort_simple(
+                        "header continuation without a header",
+                    ));
+                };
+                value.push(b' ');
+                value.extend_from_slice(line.trim_ascii());
+                continue;
+            }
+            let colon = line.iter().position(|&byte| byte == b':').ok_or_else(
+                || {
+                    HgError::abort_simple(format!(
+                        "bad header line: {}",
+                        String::from_utf8_lossy(&line)
+                    ))
+                },
+            )?;
+            if request.headers.len() >= MAX_HEADERS {
+                return Err(HgError::abort_simple("too many headers"));
+            }
+            let name = String::from_utf8_lossy(&line[..colon]).into_owned();
+            let value = line[colon + 1..].trim_ascii().to_vec();
+            request.headers.push((name, value));
+        }
+    }
+
+    /// The request line, like in access logs
+    pub fn line(&self) -> String {
+        format!(
+            "{} {} {}",
+            self.method,
+            String::from_utf8_lossy(&self.target),
+            self.version
+        )
+    }
+
+    /// The value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The headers whose name starts with `x-` as sent, which Python logs
+    pub fn extension_headers(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.headers
+            .iter()
+            .filter(|(name, _)| name.starts_with("x-"))
+            .map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+
+    /// Joins the values of the headers `<prefix>-1`, `<prefix>-2` and so on
+    /// up to the first missing one, like `decodevaluefromheaders` in Python
+    pub fn joined_header(&self, prefix: &str) -> Vec<u8> {
+        let mut value = vec![];
+        for number in 1.. {
+            match self.header(&format!("{}-{}", prefix, number)) {
+                Some(part) => value.extend_from_slice(part),
+                None => break,
+            }
+        }
+        value
+    }
+
+    /// The protocol parameters sent in the `X-HgProto-<n>` headers
+    pub fn protocaps(&self) -> Vec<Vec<u8>> {
+        self.joined_header("X-HgProto")
+            .split(|&byte| byte == b' ')
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    /// The path of the request, decoded
+    pub fn path(&self) -> Vec<u8> {
+        let path = match self.target.iter().position(|&byte| byte == b'?') {
+            Some(question) => &self.target[..question],
+            None => &self.target,
+        };
+        url_unquote(path)
+    }
+
+    /// The query string of the request, still encoded
+    pub fn query(&self) -> &[u8] {
+        match self.target.iter().position(|&byte| byte == b'?') {
+            Some(question) => &self.target[question + 1..],
+            None => b"",
+        }
+    }
+
+    /// The length of the body of the request, which clients always give
+    /// for the bodies that they send
+    pub fn content_length(&self) -> Result<u64, HgError> {
+        if self.header("Transfer-Encoding").is_some() {
+            return Err(HgError::abort_simple(
+                "request bodies without a length are not supported",
+            ));
+        }
+        self.number_header("Content-Length")
+    }
+
+    /// The value of the numeric header `name`, zero if not given
+    pub fn number_header(&self, name: &str) -> Result<u64, HgError> {
+        let Some(value) = self.header(name) else {
+            return Ok(0);
+        };
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| {
+                HgError::abort_simple(format!(
+                    "invalid {} header: {}",
+                    name,
+                    String::from_utf8_lossy(value)
+                ))
+            })
+    }
+
+    /// Whether the connection stays open for another request after this
+    /// one, which is the default since HTTP/1.1
+    pub fn keeps_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or(b"");
+        if self.version == "HTTP/1.0" {
+            connection.eq_ignore_ascii_case(b"keep-alive")
+        } else {
+            !connection.eq_ignore_ascii_case(b"close")
+        }
+    }
+}
+
+/// Reads a line without its line ending, `None` at the end of the stream
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, HgError> {
+    let mut line = vec![];
+    let mut limited = Read::take(reader, MAX_LINE_LENGTH as u64 + 1);
+    limited.read_until(b'\n', &mut line).map_err(|error| {
+        HgIoError::from_os_error(error, IoErrorContext::ReadingStream)
+    })?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(HgError::abort_simple("request line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Writes a body of unknown length with the chunked transfer coding of
+/// HTTP/1.1, each write making a chunk
+pub struct ChunkedWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Writes the last chunk, returning the underlying writer
+    pub fn finish(mut self) -> Result<W, HgError> {
+        self.out
+            .write_all(b"0\r\n\r\n")
+            .and_then(|()| self.out.flush())
+            .map_err(writing_error)?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            // An empty chunk would end the body
+            return Ok(0);
+        }
+        write!(self.out, "{:x}\r\n", buf.len())?;
+        self.out.write_all(buf)?;
+        self.out.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn writing_error(error: std::io::Error) -> HgError {
+    HgIoError::from_os_error(error, IoErrorContext::WritingStream).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireprotocol::command;
+
+    fn request(text: &[u8]) -> Request {
+        Request::read(&mut &text[..]).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            parse_query(b"cmd=getbundle&a=1+2%2C3&&empty=&flag"),
+            vec![
+                (b"cmd".to_vec(), b"getbundle".to_vec()),
+                (b"a".to_vec(), b"1 2,3".to_vec()),
+                (b"empty".to_vec(), vec![]),
+                (b"flag".to_vec(), vec![]),
+            ]
+        );
+        assert!(parse_query(b"").is_empty());
+    }
+
+    #[test]
+    fn test_request_args() {
+        let getbundle = command(b"getbundle").unwrap();
+        let args = request_args(
+            getbundle,
+            b"cmd=getbundle&heads=old&cg=1",
+            b"heads=new&common=0000&heads=ignored",
+        );
+        let others: Vec<_> = args.others().collect();
+        assert_eq!(
+            others,
+            vec![
+                (&b"cg"[..], &b"1"[..]),
+                (&b"common"[..], &b"0000"[..]),
+                (&b"heads"[..], &b"new"[..]),
+            ]
+        );
+        let lookup = command(b"lookup").unwrap();
+        let args = request_args(lookup, b"cmd=lookup", b"key=tip&other=1");
+        assert_eq!(args.get("key"), Some(&b"tip"[..]));
+        assert_eq!(args.others().count(), 0);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let mut stream = &b"\r\nGET /repo?cmd=capabilities HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            X-HgArg-1: a=1&b\r\n\
+            x-hgarg-2: =2\r\n\
+            Accept: a,\r\n b\r\n\
+            \r\n\
+            POST / HTTP/1.0\r\n\r\n"[..];
+        let first = Request::read(&mut stream).unwrap().unwrap();
+        assert_eq!(first.method, "GET");
+        assert_eq!(first.path(), b"/repo");
+        assert_eq!(first.query(), b"cmd=capabilities");
+        assert_eq!(first.line(), "GET /repo?cmd=capabilities HTTP/1.1");
+        assert_eq!(first.header("host"), Some(&b"localhost"[..]));
+        assert_eq!(first.header("Accept"), Some(&b"a, b"[..]));
+        assert_eq!(first.joined_header("X-HgArg"), b"a=1&b=2");
+        assert_eq!(first.extension_headers().count(), 1);
+        assert!(first.keeps_alive());
+        assert_eq!(first.content_length().unwrap(), 0);
+        let second = Request::read(&mut stream).unwrap().unwrap();
+        assert_eq!(second.method, "POST");
+        assert!(!second.keeps_alive());
+        assert!(Request::read(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_bad_request() {
+        for text in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / SPDY/3\r\n\r\n",
+            b"GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n",
+        ] {
+            assert!(matches!(
+                Request::read(&mut &text[..]),
+                Err(HgError::Abort { .. })
+            ));
+        }
+        let chunked =
+            request(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert!(chunked.content_length().is_err());
+    }
+
+    #[test]
+    fn test_protocaps() {
+        let request = request(
+            b"GET / HTTP/1.1\r\n\
+            X-HgProto-1: 0.1 0.2 comp=zstd,zlib,none,bzip2 partial-pull\r\n\r\n",
+        );
+        assert_eq!(
+            request.protocaps(),
+            vec![
+                b"0.1".to_vec(),
+                b"0.2".to_vec(),
+                b"comp=zstd,zlib,none,bzip2".to_vec(),
+                b"partial-pull".to_vec(),
+            ]
+        );
+    }
+
+    fn config(args: &[&str]) -> Config {
+        let mut config = Config::empty();
+        config.load_cli_args(args, None).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!(
+            capabilities(&config(&[])).unwrap(),
+            vec![
+                b"batch".to_vec(),
+                b"httpheader=1024".to_vec(),
+                b"httpmediatype=0.1rx,0.1tx,0.2tx".to_vec(),
+                b"compression=zstd,zlib".to_vec(),
+            ]
+        );
+        let configured = config(&[
+            "server.compressionengines=none,bz2",
+            "experimental.httppostargs=yes",
+        ]);
+        let caps = capabilities(&configured).unwrap();
+        assert!(caps.contains(&b"httppostargs".to_vec()));
+        assert!(caps.contains(&b"compression=none,bzip2".to_vec()));
+        let invalid = config(&["server.compressionengines=zstd,lzma"]);
+        assert!(capabilities(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let caps = |caps: &[&str]| -> Vec<Vec<u8>> {
+            caps.iter().map(|cap| cap.as_bytes().to_vec()).collect()
+        };
+        let default = config(&[]);
+        let legacy = Encoding::negotiate(&default, &[], false).unwrap();
+        assert_eq!(legacy.media_type, MEDIA_TYPE);
+        assert_eq!(legacy.compression, BundleCompression::Gzip);
+        assert_eq!(legacy.level, Some(-1));
+        let modern = caps(&["0.1", "0.2", "comp=zstd,zlib,none,bzip2"]);
+        let zstd = Encoding::negotiate(&default, &modern, false).unwrap();
+        assert_eq!(zstd.media_type, MEDIA_TYPE_2);
+        assert_eq!(zstd.compression, BundleCompression::Zstd);
+        assert_eq!(zstd.level, Some(3));
+        let none = Encoding::negotiate(&default, &modern, true).unwrap();
+        assert_eq!(none.compression, BundleCompression::None);
+        // Without `comp=`, clients support zlib
+        let zlib =
+            Encoding::negotiate(&default, &caps(&["0.2"]), false).unwrap();
+        assert_eq!(zlib.media_type, MEDIA_TYPE_2);
+        assert_eq!(zlib.compression, BundleCompression::Gzip);
+        let bzip2 = caps(&["0.2", "comp=bzip2"]);
+        let fallback = Encoding::negotiate(&default, &bzip2, false).unwrap();
+        assert_eq!(fallback, legacy);
+    }
+
+    #[test]
+    fn test_encoding_writer() {
+        let encoding = Encoding {
+            media_type: MEDIA_TYPE_2,
+            compression: BundleCompression::None,
+            level: None,
+        };
+        let mut writer = encoding.writer(vec![]).unwrap();
+        writer.write_all(b"data").unwrap();
+        assert_eq!(writer.finish().unwrap(), b"\x04nonedata");
+    }
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut writer = ChunkedWriter::new(vec![]);
+        writer.write_all(b"hello, world").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"!").unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            b"c\r\nhello, world\r\n1\r\n!\r\n0\r\n\r\n"
+        );
+    }
+}
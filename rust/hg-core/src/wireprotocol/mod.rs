@@ -0,0 +1,822 @@
+//! The server side of version 1 of the wire protocol, independent of the
+//! transport of requests and responses
+//!
+//! This is a Rust counterpart to `mercurial.wireprotov1server`, covering
+//! the commands needed to serve pulls. Commands pushing to the repository
+//! are not supported.
+
+mod getbundle;
+pub mod http;
+
+use std::collections::BTreeMap;
+
+pub use getbundle::Bundle;
+
+use crate::FastHashMap;
+use crate::Graph;
+use crate::NULL_NODE;
+use crate::Node;
+use crate::Revision;
+use crate::bookmarks::is_divergent;
+use crate::bookmarks::read_bookmarks;
+use crate::branchmap::BranchMap;
+use crate::bundle::caps::Role;
+use crate::bundle::caps::encode_caps;
+use crate::bundle::caps::incoming_changegroup_versions;
+use crate::bundle::caps::obsolescence_configured;
+use crate::bundle::caps::repo_caps;
+use crate::errors::HgError;
+use crate::repo::Repo;
+use crate::requirements::REVLOGV1_REQUIREMENT;
+use crate::revlog::NodePrefix;
+use crate::revlog::RevlogError;
+use crate::revlog::changelog::Changelog;
+use crate::revlog::index::Phase;
+use crate::stream_clone::allows_generation;
+use crate::stream_clone::streamed_requirements;
+use crate::tags::global_tags;
+use crate::utils::strings::replace_slice;
+use crate::utils::strings::url_quote;
+
+/// Runs a command with its arguments
+type Handler = fn(&mut Server, Args) -> Result<Response, HgError>;
+
+/// Pushkey keys and their values
+pub type Keys = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A command of the protocol
+pub struct Command {
+    pub name: &'static str,
+    /// The names of the arguments separated by spaces, `*` standing for any
+    /// number of other arguments
+    pub args: &'static str,
+    /// `None` for the commands that are not supported
+    run: Option<Handler>,
+}
+
+impl Command {
+    /// The names of the arguments, including `*`
+    pub fn arg_names(&self) -> impl Iterator<Item = &'static str> {
+        self.args.split_ascii_whitespace()
+    }
+
+    /// Whether the command needs the permission to push, like
+    /// `permission=b'push'` in Python
+    pub fn pushes(&self) -> bool {
+        matches!(self.name, "pushkey" | "unbundle")
+    }
+
+    /// Whether running the command with `args` needs the permission to
+    /// push, which a `batch` does if any of its commands does
+    pub fn pushes_with(&self, args: &Args) -> bool {
+        if self.name != "batch" {
+            return self.pushes();
+        }
+        let cmds = args.get("cmds").unwrap_or_default();
+        cmds.split(|&byte| byte == b';').any(|request| {
+            let name = request.split(|&byte| byte == b' ').next();
+            name.and_then(command).is_some_and(Command::pushes)
+        })
+    }
+}
+
+/// The commands of version 1 of the protocol, like the `commands` table of
+/// Python
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "batch",
+        args: "cmds *",
+        run: Some(|server, args| server.batch(args)),
+    },
+    Command {
+        name: "between",
+        args: "pairs",
+        run: Some(|server, args| server.between(args)),
+    },
+    Command {
+        name: "branches",
+        args: "nodes",
+        run: Some(|server, args| server.branches(args)),
+    },
+    Command {
+        name: "branchmap",
+        args: "",
+        run: Some(|server, args| server.branchmap(args)),
+    },
+    Command {
+        name: "capabilities",
+        args: "",
+        run: Some(|server, args| server.capabilities(args)),
+    },
+    Command { name: "changegroup", args: "roots", run: None },
+    Command { name: "changegroupsubset", args: "bases heads", run: None },
+    Command { name: "clonebundles", args: "", run: None },
+    Command { name: "clonebundles_manifest", args: "*", run: None },
+    Command { name: "debugwireargs", args: "one two *", run: None },
+    Command { name: "get_cached_bundle_inline", args: "path", run: None },
+    Command { name: "getbundle", args: "*", run: Some(getbundle::getbundle) },
+    Command {
+        name: "heads",
+        args: "",
+        run: Some(|server, args| server.heads(args)),
+    },
+    Command {
+        name: "hello",
+        args: "",
+        run: Some(|server, args| server.hello(args)),
+    },
+    Command {
+        name: "known",
+        args: "nodes *",
+        run: Some(|server, args| server.known(args)),
+    },
+    Command {
+        name: "listkeys",
+        args: "namespace",
+        run: Some(|server, args| server.listkeys(args)),
+    },
+    Command {
+        name: "lookup",
+        args: "key",
+        run: Some(|server, args| server.lookup(args)),
+    },
+    Command {
+        name: "protocaps",
+        args: "caps",
+        run: Some(|server, args| server.protocaps(args)),
+    },
+    Command { name: "pushkey", args: "namespace key old new", run: None },
+    Command { name: "store_shape", args: "name *", run: None },
+    Command { name: "stream_out", args: "", run: None },
+    Command { name: "unbundle", args: "heads", run: None },
+];
+
+/// The command named `name`, if any
+pub fn command(name: &[u8]) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name.as_bytes() == name)
+}
+
+/// The arguments of a command, by name
+#[derive(Debug, Default)]
+pub struct Args {
+    values: FastHashMap<Vec<u8>, Vec<u8>>,
+    /// The arguments matched by `*`
+    others: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Args {
+    /// Sets the value of the argument `name`
+    pub fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        self.values.insert(name, value);
+    }
+
+    /// Sets the value of the argument `name` matched by `*`
+    pub fn insert_other(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        self.others.insert(name, value);
+    }
+
+    /// The value of the argument `name`, if set
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.values.get(name.as_bytes()).map(Vec::as_slice)
+    }
+
+    /// The arguments matched by `*`, sorted by name
+    pub fn others(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.others.iter().map(|(name, value)| (&name[..], &value[..]))
+    }
+
+    /// Removes and returns the value of the argument `name`, which is
+    /// required
+    fn take(&mut self, name: &str) -> Result<Vec<u8>, HgError> {
+        self.values.remove(name.as_bytes()).ok_or_else(|| {
+            HgError::abort_simple(format!("missing argument '{}'", name))
+        })
+    }
+}
+
+/// The response to a command
+pub enum Response {
+    /// Bytes sent as they are, framed by the transport
+    Bytes(Vec<u8>),
+    /// A bundle streamed to the client, generated while it is sent
+    Bundle(Box<Bundle>),
+    /// An error reported outside of the regular responses, like `ooberror`
+    /// in Python, which only the HTTP transport uses
+    Error(Vec<u8>),
+}
+
+/// How clients reach the server, which changes the capabilities that it
+/// advertises and how some errors are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// A peer in the same process, without any transport
+    Local,
+    /// Over stdin and stdout, like `sshv1protocolhandler` in Python
+    Ssh,
+    /// Over HTTP, like `httpv1protocolhandler` in Python
+    Http,
+}
+
+/// Answers the commands of a client of `repo`
+pub struct Server<'a> {
+    repo: &'a Repo,
+    transport: Transport,
+    /// The capabilities that the client sent with `protocaps`
+    protocaps: Vec<Vec<u8>>,
+    /// For the user running the server, like what Python writes to its
+    /// error stream
+    messages: Vec<Vec<u8>>,
+}
+
+/// Falls back to Python for the repositories that rhg cannot serve as
+/// Python would, such as those with changesets hidden from clients
+pub fn check_supported(repo: &Repo) -> Result<(), HgError> {
+    if repo.has_narrow() {
+        return Err(HgError::unsupported("serving a narrow repository"));
+    }
+    let obsstore = repo.store_vfs().join("obsstore");
+    if std::fs::metadata(&obsstore).is_ok_and(|meta| meta.len() > 0)
+        || obsolescence_configured(repo)
+    {
+        return Err(HgError::unsupported("obsolescence markers"));
+    }
+    if repo.store().phase_cache()?.has_secret() {
+        return Err(HgError::unsupported("serving secret changesets"));
+    }
+    if incoming_changegroup_versions(repo)?.iter().any(|v| *v > "03") {
+        return Err(HgError::unsupported("changegroups newer than 03"));
+    }
+    let config = repo.config();
+    if config.get_bool(b"experimental", b"narrow")? {
+        return Err(HgError::unsupported("narrow capabilities"));
+    }
+    if config.get_bool(b"server", b"pullbundle")?
+        && repo.hg_vfs().join("pullbundles.manifest").exists()
+    {
+        return Err(HgError::unsupported("pre-generated pull bundles"));
+    }
+    Ok(())
+}
+
+impl<'a> Server<'a> {
+    pub fn new(repo: &'a Repo, transport: Transport) -> Self {
+        Self { repo, transport, protocaps: vec![], messages: vec![] }
+    }
+
+    /// Takes the messages for the user running the server that commands
+    /// produced
+    pub fn take_messages(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// Runs `command` with `args`
+    pub fn dispatch(
+        &mut self,
+        command: &Command,
+        args: Args,
+    ) -> Result<Response, HgError> {
+        match command.run {
+            Some(run) => run(self, args),
+            None => Err(HgError::unsupported(format!(
+                "the '{}' wire protocol command",
+                command.name
+            ))),
+        }
+    }
+
+    fn batch(&mut self, mut args: Args) -> Result<Response, HgError> {
+        let cmds = args.take("cmds")?;
+        let mut results = vec![];
+        for request in cmds.split(|&byte| byte == b';') {
+            let invalid = || {
+                HgError::abort_simple(format!(
+                    "invalid batched command: {}",
+                    String::from_utf8_lossy(request)
+                ))
+            };
+            let space =
+                request.iter().position(|&b| b == b' ').ok_or_else(invalid)?;
+            let (name, encoded_args) =
+                (&request[..space], &request[space + 1..]);
+            let command = command(name).ok_or_else(|| {
+                HgError::abort_simple(format!(
+                    "unknown batched command: {}",
+                    String::from_utf8_lossy(name)
+                ))
+            })?;
+            let mut values = BTreeMap::new();
+            for arg in encoded_args.split(|&byte| byte == b',') {
+                if arg.is_empty() {
+                    continue;
+                }
+                let mut parts = arg.split(|&byte| byte == b'=');
+                let (Some(name), Some(value), None) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(invalid());
+                };
+                values.insert(
+                    unescape_batch_arg(name),
+                    unescape_batch_arg(value),
+                );
+            }
+            let mut args = Args::default();
+            for name in command.arg_names() {
+                if name == "*" {
+                    for (other, value) in &values {
+                        if !command.arg_names().any(|n| n.as_bytes() == other) {
+                            args.insert_other(other.clone(), value.clone());
+                        }
+                    }
+                } else if let Some(value) = values.get(name.as_bytes()) {
+                    args.insert(name.as_bytes().to_vec(), value.clone());
+                }
+            }
+            match self.dispatch(command, args)? {
+                Response::Bytes(result) => {
+                    results.push(escape_batch_arg(&result))
+                }
+                Response::Bundle(_) => {
+                    return Err(HgError::abort_simple(format!(
+                        "command {} cannot be batched",
+                        command.name
+                    )));
+                }
+                error @ Response::Error(_) => return Ok(error),
+            }
+        }
+        Ok(Response::Bytes(results.join(&b';')))
+    }
+
+    fn between(&mut self, mut args: Args) -> Result<Response, HgError> {
+        let pairs = args.take("pairs")?;
+        let changelog = self.repo.changelog()?;
+        let mut response = vec![];
+        for pair in pairs.split(|&byte| byte == b' ') {
+            let [top, bottom] = decode_list(pair, b'-')?[..] else {
+                return Err(HgError::abort_simple(format!(
+                    "invalid pair: {}",
+                    String::from_utf8_lossy(pair)
+                )));
+            };
+            // Every first parent at a distance 1, 2, 4, 8... from `top`
+            let mut nodes = vec![];
+            let (mut node, mut distance, mut next) = (top, 0, 1);
+            while node != bottom && node != NULL_NODE {
+                let parent = parents(&changelog, node)?[0];
+                if distance == next {
+                    nodes.push(node);
+                    next *= 2;
+                }
+                node = parent;
+                distance += 1;
+            }
+            response.extend(encode_list(&nodes));
+            response.push(b'\n');
+        }
+        Ok(Response::Bytes(response))
+    }
+
+    fn branches(&mut self, mut args: Args) -> Result<Response, HgError> {
+        let mut nodes = decode_list(&args.take("nodes")?, b' ')?;
+        let changelog = self.repo.changelog()?;
+        if nodes.is_empty() {
+            nodes.push(tip(&changelog));
+        }
+        let mut response = vec![];
+        for top in nodes {
+            // The linear segment of first parents ending with `top`
+            let mut node = top;
+            let segment = loop {
+                let [p1, p2] = parents(&changelog, node)?;
+                if p2 != NULL_NODE || p1 == NULL_NODE {
+                    break [top, node, p1, p2];
+                }
+                node = p1;
+            };
+            response.extend(encode_list(&segment));
+            response.push(b'\n');
+        }
+        Ok(Response::Bytes(response))
+    }
+
+    fn branchmap(&mut self, _args: Args) -> Result<Response, HgError> {
+        let changelog = self.repo.changelog()?;
+        let branchmap = BranchMap::new(&changelog)?;
+        let lines: Vec<Vec<u8>> = branchmap
+            .iter()
+            .map(|(branch, heads)| {
+                let heads: Vec<Node> = heads
+                    .iter()
+                    .map(|head| *changelog.node_from_rev(head.rev))
+                    .collect();
+                let mut line = url_quote(branch);
+                line.push(b' ');
+                line.extend(encode_list(&heads));
+                line
+            })
+            .collect();
+        Ok(Response::Bytes(lines.join(&b'\n')))
+    }
+
+    fn capabilities(&mut self, _args: Args) -> Result<Response, HgError> {
+        Ok(Response::Bytes(self.capability_list()?.join(&b' ')))
+    }
+
+    /// The sorted capabilities of the server, like `_capabilities` in Python
+    pub(crate) fn capability_list(&self) -> Result<Vec<Vec<u8>>, HgError> {
+        let config = self.repo.config();
+        let mut caps: Vec<Vec<u8>> = [
+            "lookup",
+            "branchmap",
+            "pushkey",
+            "known",
+            "getbundle",
+            "unbundlehash",
+            "changegroupsubset",
+        ]
+        .iter()
+        .map(|cap| cap.as_bytes().to_vec())
+        .collect();
+        if allows_generation(self.repo)? {
+            if config.get_bool(b"server", b"preferuncompressed")? {
+                caps.push(b"stream-preferred".to_vec());
+            }
+            let formats = streamed_requirements(self.repo.requirements());
+            if formats.iter().all(|format| format == REVLOGV1_REQUIREMENT) {
+                caps.push(b"stream".to_vec());
+            } else {
+                caps.push(format!("streamreqs={}", formats.join(",")).into());
+            }
+        }
+        if config.get_bool(b"experimental", b"bundle2-advertise")? {
+            let blob = encode_caps(&repo_caps(self.repo, Role::Server)?);
+            let mut cap = b"bundle2=".to_vec();
+            cap.extend(url_quote(&blob));
+            caps.push(cap);
+        }
+        caps.push(b"unbundle=HG10GZ,HG10BZ,HG10UN".to_vec());
+        match self.transport {
+            Transport::Local => {}
+            Transport::Ssh => {
+                caps.extend([b"protocaps".to_vec(), b"batch".to_vec()])
+            }
+            Transport::Http => caps.extend(http::capabilities(config)?),
+        }
+        caps.sort_unstable();
+        Ok(caps)
+    }
+
+    fn heads(&mut self, _args: Args) -> Result<Response, HgError> {
+        let mut response = encode_list(&self.head_nodes()?);
+        response.push(b'\n');
+        Ok(Response::Bytes(response))
+    }
+
+    /// The heads of the repository, by decreasing revision number, or null
+    /// if it is empty
+    pub(crate) fn head_nodes(&self) -> Result<Vec<Node>, HgError> {
+        let changelog = self.repo.changelog()?;
+        let mut heads = head_revs(&changelog)?;
+        heads.reverse();
+        let mut nodes: Vec<Node> =
+            heads.iter().map(|&rev| *changelog.node_from_rev(rev)).collect();
+        if nodes.is_empty() {
+            nodes.push(NULL_NODE);
+        }
+        Ok(nodes)
+    }
+
+    fn hello(&mut self, _args: Args) -> Result<Response, HgError> {
+        let mut response = b"capabilities: ".to_vec();
+        response.extend(self.capability_list()?.join(&b' '));
+        response.push(b'\n');
+        let publishing = self.repo.config().get_bool(b"phases", b"publish")?;
+        let all_public = !self.repo.store().phase_cache()?.has_non_public();
+        if publishing || all_public {
+            response.extend_from_slice(b"phase-summary-v01:");
+            if publishing {
+                response.extend_from_slice(b" publish=all");
+            }
+            if all_public {
+                response.extend_from_slice(b" public-revs=all");
+            }
+            response.push(b'\n');
+        }
+        Ok(Response::Bytes(response))
+    }
+
+    fn known(&mut self, mut args: Args) -> Result<Response, HgError> {
+        let nodes = decode_list(&args.take("nodes")?, b' ')?;
+        let response = self
+            .known_nodes(&nodes)?
+            .into_iter()
+            .map(|known| if known { b'1' } else { b'0' })
+            .collect();
+        Ok(Response::Bytes(response))
+    }
+
+    /// Whether each of `nodes` is in the repository
+    pub(crate) fn known_nodes(
+        &self,
+        nodes: &[Node],
+    ) -> Result<Vec<bool>, HgError> {
+        let changelog = self.repo.changelog()?;
+        nodes.iter().map(|&node| is_known(&changelog, node)).collect()
+    }
+
+    fn listkeys(&mut self, mut args: Args) -> Result<Response, HgError> {
+        let mut keys = self.list_keys(&args.take("namespace")?)?;
+        keys.sort_unstable();
+        Ok(Response::Bytes(encode_keys(&keys)))
+    }
+
+    /// The keys of the pushkey `namespace`, in the order of Python, like
+    /// `repo.listkeys`
+    pub(crate) fn list_keys(&self, namespace: &[u8]) -> Result<Keys, HgError> {
+        let pair = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+        let keys = match namespace {
+            b"namespaces" => vec![
+                pair(b"namespaces", b""),
+                pair(b"bookmarks", b""),
+                pair(b"phases", b""),
+            ],
+            b"bookmarks" => read_bookmarks(self.repo)?
+                .into_iter()
+                .filter(|(name, _)| !is_divergent(name))
+                .map(|(name, node)| (name, format!("{:x}", node).into()))
+                .collect(),
+            b"phases" => {
+                let changelog = self.repo.changelog()?;
+                let phase_cache = self.repo.store().phase_cache()?;
+                let mut keys: Vec<_> = phase_cache
+                    .roots(Phase::Draft)
+                    .map(|root| {
+                        let node = changelog.node_from_rev(root);
+                        (format!("{:x}", node).into(), b"1".to_vec())
+                    })
+                    .collect();
+                if self.repo.config().get_bool(b"phases", b"publish")? {
+                    keys.push(pair(b"publishing", b"True"));
+                }
+                keys
+            }
+            _ => vec![],
+        };
+        Ok(keys)
+    }
+
+    fn lookup(&mut self, mut args: Args) -> Result<Response, HgError> {
+        let key = args.take("key")?;
+        let mut response = match self.lookup_symbol(&key)? {
+            Ok(node) => format!("1 {:x}", node).into_bytes(),
+            Err(message) => {
+                let mut response = b"0 ".to_vec();
+                response.extend(message);
+                response
+            }
+        };
+        response.push(b'\n');
+        Ok(Response::Bytes(response))
+    }
+
+    /// Resolves `symbol` to a changeset like `scmutil.revsymbol` in Python,
+    /// or returns why it cannot be.
+    ///
+    /// Local tags and the prefixes of the working directory node are not
+    /// supported.
+    pub(crate) fn lookup_symbol(
+        &self,
+        symbol: &[u8],
+    ) -> Result<Result<Node, Vec<u8>>, HgError> {
+        let changelog = self.repo.changelog()?;
+        let len = changelog.get_index().len() as i64;
+        match symbol {
+            b"." => return Ok(Ok(self.repo.dirstate_parents()?.p1)),
+            b"tip" => return Ok(Ok(tip(&changelog))),
+            b"null" => return Ok(Ok(NULL_NODE)),
+            _ => {}
+        }
+        let number = std::str::from_utf8(symbol)
+            .ok()
+            .and_then(|symbol| Some((symbol, symbol.parse::<i64>().ok()?)))
+            .filter(|(symbol, number)| number.to_string() == *symbol);
+        if let Some((_, mut number)) = number {
+            if number < 0 {
+                number += len;
+            }
+            if (0..len).contains(&number) {
+                let rev = Revision(number as i32);
+                return Ok(Ok(*changelog.node_from_rev(rev)));
+            }
+        }
+        if symbol.len() == 40
+            && let Ok(node) = Node::from_hex(symbol)
+            && is_known(&changelog, node)?
+        {
+            return Ok(Ok(node));
+        }
+        drop(changelog);
+        if let Some(node) = read_bookmarks(self.repo)?.get(symbol) {
+            return Ok(Ok(*node));
+        }
+        if let Some(node) = global_tags(self.repo)?.get(symbol) {
+            return Ok(Ok(*node));
+        }
+        let changelog = self.repo.changelog()?;
+        if let Some(rev) = BranchMap::new(&changelog)?.tip(symbol) {
+            return Ok(Ok(*changelog.node_from_rev(rev)));
+        }
+        if let Ok(prefix) = NodePrefix::from_hex(symbol) {
+            match changelog.rev_from_node(prefix) {
+                Ok(rev) => return Ok(Ok(*changelog.node_from_rev(rev))),
+                Err(RevlogError::AmbiguousPrefix { .. }) => {
+                    let mut message = b"00changelog@".to_vec();
+                    message.extend_from_slice(symbol);
+                    message.extend_from_slice(b": ambiguous identifier");
+                    return Ok(Err(message));
+                }
+                Err(RevlogError::InvalidRevision { .. }) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        let mut message = b"unknown revision '".to_vec();
+        message.extend_from_slice(symbol);
+        message.push(b'\'');
+        Ok(Err(message))
+    }
+
+    fn protocaps(&mut self, mut args: Args) -> Result<Response, HgError> {
+        let caps = args.take("caps")?;
+        self.protocaps =
+            caps.split(|&byte| byte == b' ').map(<[u8]>::to_vec).collect();
+        Ok(Response::Bytes(b"OK".to_vec()))
+    }
+}
+
+/// The heads of the changelog, by increasing revision number
+fn head_revs(changelog: &Changelog) -> Result<Vec<Revision>, HgError> {
+    let mut heads =
+        changelog.get_index().head_revs().map_err(RevlogError::from)?;
+    heads.retain(|head| head.0 >= 0);
+    heads.sort_unstable();
+    Ok(heads)
+}
+
+/// The node of the last changeset, null if there is none
+fn tip(changelog: &Changelog) -> Node {
+    match changelog.get_index().len() {
+        0 => NULL_NODE,
+        len => *changelog.node_from_rev(Revision(len as i32 - 1)),
+    }
+}
+
+/// Whether the changeset `node` is in `changelog`
+fn is_known(changelog: &Changelog, node: Node) -> Result<bool, HgError> {
+    match changelog.rev_from_node(node.into()) {
+        Ok(_) => Ok(true),
+        Err(RevlogError::InvalidRevision { .. }) => Ok(false),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// The parents of the changeset `node`
+fn parents(changelog: &Changelog, node: Node) -> Result<[Node; 2], HgError> {
+    let rev = changelog.rev_from_node(node.into())?;
+    let parents = changelog.parents(rev).map_err(RevlogError::from)?;
+    Ok(parents.map(|parent| match parent.0 {
+        -1 => NULL_NODE,
+        _ => *changelog.node_from_rev(parent),
+    }))
+}
+
+/// Encodes `nodes` in hexadecimal separated by spaces, like `encodelist`
+/// in Python
+pub fn encode_list(nodes: &[Node]) -> Vec<u8> {
+    let hex: Vec<String> =
+        nodes.iter().map(|node| format!("{:x}", node)).collect();
+    hex.join(" ").into_bytes()
+}
+
+/// Decodes the hexadecimal nodes of `list` separated by `separator`, like
+/// `decodelist` in Python
+pub fn decode_list(list: &[u8], separator: u8) -> Result<Vec<Node>, HgError> {
+    if list.is_empty() {
+        return Ok(vec![]);
+    }
+    list.split(|&byte| byte == separator)
+        .map(|hex| {
+            Node::from_hex(hex).map_err(|_| {
+                HgError::abort_simple(format!(
+                    "invalid node: {}",
+                    String::from_utf8_lossy(hex)
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Encodes pushkey keys and values as lines, like `pushkey.encodekeys` in
+/// Python
+fn encode_keys(keys: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let lines: Vec<Vec<u8>> = keys
+        .iter()
+        .map(|(key, value)| [&key[..], b"\t", value].concat())
+        .collect();
+    lines.join(&b'\n')
+}
+
+/// Decodes pushkey keys and values encoded by [`encode_keys`], like
+/// `pushkey.decodekeys` in Python
+pub fn decode_keys(data: &[u8]) -> Result<Keys, HgError> {
+    data.split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.split(|&byte| byte == b'\t');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(key), Some(value), None) => {
+                    Ok((key.to_vec(), value.to_vec()))
+                }
+                _ => Err(HgError::abort_simple(format!(
+                    "invalid pushkey line: {}",
+                    String::from_utf8_lossy(line)
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// Escapes the separators of batched commands and their arguments, like
+/// `escapebatcharg` in Python
+pub fn escape_batch_arg(plain: &[u8]) -> Vec<u8> {
+    let escaped = replace_slice(plain, b":", b":c");
+    let escaped = replace_slice(&escaped, b",", b":o");
+    let escaped = replace_slice(&escaped, b";", b":s");
+    replace_slice(&escaped, b"=", b":e")
+}
+
+/// Reverts [`escape_batch_arg`], like `unescapebatcharg` in Python
+pub fn unescape_batch_arg(escaped: &[u8]) -> Vec<u8> {
+    let plain = replace_slice(escaped, b":e", b"=");
+    let plain = replace_slice(&plain, b":s", b";");
+    let plain = replace_slice(&plain, b":o", b",");
+    replace_slice(&plain, b":c", b":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pushes_with() {
+        let batch = command(b"batch").unwrap();
+        let mut args = Args::default();
+        args.insert(b"cmds".to_vec(), b"heads ;known nodes=".to_vec());
+        assert!(!batch.pushes_with(&args));
+        args.insert(b"cmds".to_vec(), b"heads ;pushkey namespace=a".to_vec());
+        assert!(batch.pushes_with(&args));
+        assert!(command(b"unbundle").unwrap().pushes_with(&Args::default()));
+        assert!(!command(b"heads").unwrap().pushes_with(&Args::default()));
+    }
+
+    #[test]
+    fn test_batch_arg_escaping() {
+        let plain = b"a:b,c;d=e:o";
+        let escaped = escape_batch_arg(plain);
+        assert_eq!(escaped, b"a:cb:oc:sd:ee:co".to_vec());
+        assert_eq!(unescape_batch_arg(&escaped), plain.to_vec());
+    }
+
+    #[test]
+    fn test_node_lists() {
+        let nodes = [Node::from([0x12; 20]), NULL_NODE];
+        let encoded = encode_list(&nodes);
+        assert_eq!(
+            encoded,
+            format!("{} {}", "12".repeat(20), "00".repeat(20)).into_bytes()
+        );
+        assert_eq!(decode_list(&encoded, b' ').unwrap(), nodes.to_vec());
+        assert_eq!(decode_list(b"", b' ').unwrap(), vec![]);
+        assert!(decode_list(b"12", b' ').is_err());
+    }
+
+    #[test]
+    fn test_keys() {
+        let keys = vec![
+            (b"book".to_vec(), b"12".repeat(20)),
+            (b"publishing".to_vec(), b"True".to_vec()),
+        ];
+        let encoded = encode_keys(&keys);
+        assert_eq!(decode_keys(&encoded).unwrap(), keys);
+        assert_eq!(decode_keys(b"").unwrap(), vec![]);
+        assert!(decode_keys(b"no tab").is_err());
+    }
+
+    #[test]
+    fn test_command_args() {
+        let names: Vec<_> = command(b"known").unwrap().arg_names().collect();
+        assert_eq!(names, vec!["nodes", "*"]);
+        assert_eq!(command(b"heads").unwrap().arg_names().count(), 0);
+        assert!(command(b"unknown").is_none());
+    }
+}
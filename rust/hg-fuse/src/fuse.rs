@@ -1,10 +1,12 @@
 use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::fs::Metadata;
 use std::ops::Range;
 use std::path::Path;
 use std::path::PathBuf;
 use std::thread::available_parallelism;
 use std::time::Duration;
+use std::time::SystemTime;
 
 use fuser::BackgroundSession;
 use fuser::Config;
@@ -17,6 +19,7 @@ use fuser::INodeNo;
 use fuser::InitFlags;
 use fuser::MountOption;
 use fuser::SessionACL;
+use fuser::TimeOrNow;
 use hg::Node;
 use hg::errors::HgError;
 use hg::errors::IoResultExt;
@@ -30,10 +33,18 @@ use crate::server::ATTRIBUTES_FOR_NEGATIVE_LOOKUP;
 use crate::server::Server;
 use crate::server::local::LocalBackend;
 use crate::server::local::LocalToken;
+#[cfg(feature = "remote")]
+use crate::server::remote::RemoteBackend;
+#[cfg(feature = "remote")]
+use crate::server::remote::RemoteToken;
+#[cfg(feature = "remote")]
+use crate::server::remote::StoreAddress;
+use crate::server::stats::ServerStatsHandle;
 use crate::server::store::BackendMode;
 use crate::server::store::FileToken;
 use crate::server::store::RevisionIdx;
 use crate::server::store::StoreBackend;
+use crate::server::store::SymbolKind;
 
 /// Return the path to the working directory root, relative to the FUSE root
 pub fn path_to_revision_working_copy(changeset: Node) -> PathBuf {
@@ -64,7 +75,11 @@ impl<S: StoreBackend<T>, T: FileToken> HgFuse<S, T> {
         let mut config = Config::default();
         config.mount_options.extend([
             MountOption::FSName("hgvfs".to_string()),
-            MountOption::RO,
+            if server.is_writable() {
+                MountOption::RW
+            } else {
+                MountOption::RO
+            },
             MountOption::NoAtime,
             // Don't use `MountOption::AutoUnmount`: it's prone to race
             // conditions (unmounting a new mount at the same place), and it's
@@ -84,8 +99,12 @@ impl<S: StoreBackend<T>, T: FileToken> HgFuse<S, T> {
 }
 
 impl HgFuse<LocalBackend, LocalToken> {
-    /// Mount `repo`'s read-only virtual filesystem at `destination`,
-    /// exposing every revision at once (under `commits/<rev>/`).
+    /// Mount `repo`'s virtual filesystem at `destination`, exposing every
+    /// revision at once (under `commits/<rev>/`).
+    ///
+    /// The filesystem is read-only unless an `overlay_directory` is given,
+    /// in which case all changes are stored there.
+    #[allow(clippy::too_many_arguments)]
     pub fn mount_all_revs(
         repo: Repo,
         destination: impl AsRef<Path>,
@@ -94,34 +113,128 @@ impl HgFuse<LocalBackend, LocalToken> {
         group_id: Option<u32>,
         max_revisions_loaded: Option<usize>,
         session_acl: SessionACL,
+        overlay_directory: Option<PathBuf>,
     ) -> Result<BackgroundSession, HgError> {
+        let (session, _) = Self::mount_local(
+            repo,
+            destination,
+            None,
+            backend_mode,
+            user_id,
+            group_id,
+            max_revisions_loaded,
+            session_acl,
+            overlay_directory,
+        )?;
+        Ok(session)
+    }
+
+    /// Mount `repo`'s virtual filesystem at `destination`, like
+    /// [`HgFuse::mount_all_revs`] unless a `root_revision` is given, in which
+    /// case only the working copy of that changeset is exposed, at the root.
+    ///
+    /// Also returns a handle on the counters of the server.
+    #[allow(clippy::too_many_arguments)]
+    pub fn mount_local(
+        repo: Repo,
+        destination: impl AsRef<Path>,
+        root_revision: Option<Node>,
+        backend_mode: BackendMode,
+        user_id: Option<u32>,
+        group_id: Option<u32>,
+        max_revisions_loaded: Option<usize>,
+        session_acl: SessionACL,
+        overlay_directory: Option<PathBuf>,
+    ) -> Result<(BackgroundSession, ServerStatsHandle), HgError> {
         let mountpoint = destination.as_ref();
-        let thread_count = repo
-            .config()
-            .get_u32(b"fuse", b"event-loop-threads")?
-            .map(u32_u)
-            .unwrap_or_else(|| {
-                available_parallelism().map(usize::from).unwrap_or(1)
-            });
+        let thread_count = event_loop_threads(repo.config())?;
         let store = LocalBackend::new(repo, backend_mode)?;
+        let mut server = Server::new(
+            store,
+            user_id,
+            group_id,
+            mountpoint,
+            max_revisions_loaded,
+            overlay_directory,
+        )?;
+        if let Some(changeset) = root_revision {
+            server.set_root_revision(changeset).map_err(|e| {
+                HgError::abort_simple(format!(
+                    "cannot load revision {changeset:x}: {:?}",
+                    e.kind
+                ))
+            })?;
+        }
+        let stats = server.stats_handle();
+        let session =
+            Self::mount(server, mountpoint, session_acl, thread_count)?;
+        Ok((session, stats))
+    }
+}
+
+#[cfg(feature = "remote")]
+impl HgFuse<RemoteBackend, RemoteToken> {
+    /// Mount the virtual filesystem of the store served at `address` to
+    /// `destination`, exposing every revision at once (under
+    /// `commits/<rev>/`).
+    ///
+    /// See [`HgFuse::mount_all_revs`] for the other arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn mount_remote(
+        config: &hg::config::Config,
+        address: StoreAddress,
+        destination: impl AsRef<Path>,
+        backend_mode: BackendMode,
+        user_id: Option<u32>,
+        group_id: Option<u32>,
+        max_revisions_loaded: Option<usize>,
+        session_acl: SessionACL,
+        overlay_directory: Option<PathBuf>,
+    ) -> Result<BackgroundSession, HgError> {
+        let mountpoint = destination.as_ref();
+        let thread_count = event_loop_threads(config)?;
+        let store = RemoteBackend::connect(address, backend_mode, config)?;
         let server = Server::new(
             store,
             user_id,
             group_id,
             mountpoint,
             max_revisions_loaded,
+            overlay_directory,
         )?;
         Self::mount(server, mountpoint, session_acl, thread_count)
     }
 }
 
-/// Everything we expose is read-only (for now), the kernel can cache it all
+/// Returns how many threads should answer FUSE requests
+fn event_loop_threads(config: &hg::config::Config) -> Result<usize, HgError> {
+    Ok(config
+        .get_u32(b"fuse", b"event-loop-threads")?
+        .map(u32_u)
+        .unwrap_or_else(|| {
+            available_parallelism().map(usize::from).unwrap_or(1)
+        }))
+}
+
+/// Everything we expose is either read-only or only changed through the
+/// kernel (which invalidates what it affects), so the kernel can cache it all
 const TTL: Duration = Duration::MAX;
-// Using `0` here means we're doing stateless work, which is true
-// only while we're read-only, but write support at this layer may be a
-// long way off.
+/// The symbol directories follow the repository, so the kernel must always
+/// ask us about them
+const VOLATILE_TTL: Duration = Duration::ZERO;
+// Using `0` here means we're doing stateless work, which is true since even
+// writes to the overlay find the file they target from its inode alone.
 const STATELESS_FILE_HANDLE: FileHandle = FileHandle(0);
 
+/// Returns how long the kernel may cache what it learns about this inode
+fn ttl_for(ino: INodeNo) -> &'static Duration {
+    if RootInodeEncoder::is_volatile(ino) {
+        &VOLATILE_TTL
+    } else {
+        &TTL
+    }
+}
+
 impl<S: StoreBackend<T>, T: FileToken> Filesystem for HgFuse<S, T> {
     fn init(
         &mut self,
@@ -154,7 +267,7 @@ impl<S: StoreBackend<T>, T: FileToken> Filesystem for HgFuse<S, T> {
     ) {
         match self.server.attributes(ino) {
             Some(attributes) => {
-                reply.attr(&TTL, &attributes);
+                reply.attr(ttl_for(ino), &attributes);
             }
             None => reply.error(fuser::Errno::ENOENT),
         }
@@ -201,10 +314,15 @@ impl<S: StoreBackend<T>, T: FileToken> Filesystem for HgFuse<S, T> {
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        // Names in the symbol directories come and go with the repository
+        let ttl = ttl_for(parent);
         match self.server.lookup(parent, name) {
             Ok(Some(entry)) => {
+                // The symbol directories' attributes must not be cached
+                // either, lest the kernel keep their listing
+                let ttl = ttl.min(ttl_for(entry.ino()));
                 reply.entry(
-                    &TTL,
+                    ttl,
                     &self.server.attributes_for_entry(entry),
                     Generation(0),
                 );
@@ -213,11 +331,7 @@ impl<S: StoreBackend<T>, T: FileToken> Filesystem for HgFuse<S, T> {
                 // The fuser library doesn't have a good way of adding a TTL to
                 // a negative entry. We work around this by sending an entry
                 // with inode 0.
-                reply.entry(
-                    &TTL,
-                    &ATTRIBUTES_FOR_NEGATIVE_LOOKUP,
-                    Generation(0),
-                )
+                reply.entry(ttl, &ATTRIBUTES_FOR_NEGATIVE_LOOKUP, Generation(0))
             }
             // TODO better error codes
             Err(e) => {
@@ -255,10 +369,21 @@ impl<S: StoreBackend<T>, T: FileToken> Filesystem for HgFuse<S, T> {
         _lock_owner: Option<fuser::LockOwner>,
         reply: fuser::ReplyData,
     ) {
+        if let Some(result) = self.server.read_overlay(ino, offset, size) {
+            match result {
+                Ok(data) => {
+                    self.server.record_served(data.len());
+                    reply.data(&data)
+                }
+                Err(e) => reply.error(e.into()),
+            }
+            return;
+        }
         match self.server.read(ino) {
             Ok(Some(data)) => {
                 let offset = u64_u(offset).min(data.len());
                 let end = offset.saturating_add(u32_u(size)).min(data.len());
+                self.server.record_served(end - offset);
                 reply.data(&data[offset..end]);
             }
             // TODO answer the correct error for folders
@@ -294,11 +419,14 @@ impl<S: StoreBackend<T>, T: FileToken> Filesystem for HgFuse<S, T> {
     fn opendir(
         &self,
         _req: &fuser::Request,
-        _ino: INodeNo,
+        ino: INodeNo,
         _flags: fuser::OpenFlags,
         reply: fuser::ReplyOpen,
     ) {
-        if self.fuse_no_opendir_support {
+        if RootInodeEncoder::is_volatile(ino) {
+            // Zero-message opendir would have the kernel cache the listing
+            reply.opened(STATELESS_FILE_HANDLE, FopenFlags::FOPEN_NOFLUSH);
+        } else if self.fuse_no_opendir_support {
             // Tell the kernel to use zero-message opendir
             reply.error(fuser::Errno::ENOSYS);
         } else {
@@ -308,6 +436,146 @@ impl<S: StoreBackend<T>, T: FileToken> Filesystem for HgFuse<S, T> {
             reply.opened(STATELESS_FILE_HANDLE, flags);
         }
     }
+
+    fn setattr(
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<FileHandle>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<fuser::BsdFileFlags>,
+        reply: fuser::ReplyAttr,
+    ) {
+        match self.server.setattr(ino, mode, size, atime, mtime) {
+            Ok(attributes) => reply.attr(&TTL, &attributes),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn create(
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        match self.server.create(parent, name, mode & !umask) {
+            Ok(attributes) => reply.created(
+                &TTL,
+                &attributes,
+                Generation(0),
+                STATELESS_FILE_HANDLE,
+                FopenFlags::FOPEN_NOFLUSH,
+            ),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn mkdir(
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        match self.server.mkdir(parent, name, mode & !umask) {
+            Ok(attributes) => reply.entry(&TTL, &attributes, Generation(0)),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn symlink(
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
+        link_name: &OsStr,
+        target: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        match self.server.symlink(parent, link_name, target) {
+            Ok(attributes) => reply.entry(&TTL, &attributes, Generation(0)),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn write(
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        data: &[u8],
+        _write_flags: fuser::WriteFlags,
+        _flags: fuser::OpenFlags,
+        _lock_owner: Option<fuser::LockOwner>,
+        reply: fuser::ReplyWrite,
+    ) {
+        match self.server.write(ino, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn unlink(
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.server.unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn rmdir(
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.server.rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn rename(
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
+        name: &OsStr,
+        newparent: INodeNo,
+        newname: &OsStr,
+        flags: fuser::RenameFlags,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if flags.contains(fuser::RenameFlags::RENAME_EXCHANGE) {
+            reply.error(fuser::Errno::EINVAL);
+            return;
+        }
+        let no_replace = flags.contains(fuser::RenameFlags::RENAME_NOREPLACE);
+        match self.server.rename(parent, name, newparent, newname, no_replace) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
 }
 
 /// Contains information about an entry of the [`HgFuse`]
@@ -317,6 +585,8 @@ pub enum Entry {
     Dir { name: OsString, ino: INodeNo },
     /// A normal file
     File { name: OsString, ino: INodeNo, size: u64, flags: ManifestFlags },
+    /// An entry of any type that was written to the overlay
+    Overlay { name: OsString, ino: INodeNo, metadata: Metadata },
 }
 
 impl Entry {
@@ -340,6 +610,7 @@ impl Entry {
         match self {
             Entry::Dir { ino, .. } => *ino,
             Entry::File { ino, .. } => *ino,
+            Entry::Overlay { ino, .. } => *ino,
         }
     }
 
@@ -354,6 +625,16 @@ impl Entry {
                     FileType::RegularFile
                 }
             }
+            Entry::Overlay { metadata, .. } => {
+                let file_type = metadata.file_type();
+                if file_type.is_dir() {
+                    FileType::Directory
+                } else if file_type.is_symlink() {
+                    FileType::Symlink
+                } else {
+                    FileType::RegularFile
+                }
+            }
         }
     }
 
@@ -362,6 +643,7 @@ impl Entry {
         match self {
             Entry::Dir { name, .. } => name.as_os_str(),
             Entry::File { name, .. } => name.as_os_str(),
+            Entry::Overlay { name, .. } => name.as_os_str(),
         }
     }
 }
@@ -377,14 +659,29 @@ pub const META_INODE: INodeNo = INodeNo(2);
 pub const COMMITS_INODE: INodeNo = INodeNo(3);
 /// Inode number for the null revision "working copy", which is always the same
 pub const NULL_REV_INODE: INodeNo = INodeNo(4);
+/// Inode number for the directory of symlinks to the named branches' heads
+pub const BRANCHES_INODE: INodeNo = INodeNo(5);
+/// Inode number for the directory of symlinks to the bookmarks
+pub const BOOKMARKS_INODE: INodeNo = INodeNo(6);
+/// Inode number for the directory of symlinks to the tags
+pub const TAGS_INODE: INodeNo = INodeNo(7);
+/// First inode number given out to the symlinks of the symbol directories,
+/// all following ones up to [`RESERVED_INODES_COUNT`] are for them as well
+pub const FIRST_SYMBOL_INODE: INodeNo = INodeNo(1000);
 
 /// Name of the top-level directory containing all revision directories
 pub const COMMITS_INODE_NAME: &str = "commits";
 /// Name of the directory containing a given revision's working copy
 pub const FILES_INODE_NAME: &str = "files";
+/// Name of the top-level directory of symlinks to the named branches' heads
+pub const BRANCHES_INODE_NAME: &str = "branches";
+/// Name of the top-level directory of symlinks to the bookmarks
+pub const BOOKMARKS_INODE_NAME: &str = "bookmarks";
+/// Name of the top-level directory of symlinks to the tags
+pub const TAGS_INODE_NAME: &str = "tags";
 
 /// Number of reserved inodes for the FUSE itself
-const RESERVED_INODES_COUNT: u64 = 1_000_000;
+pub const RESERVED_INODES_COUNT: u64 = 1_000_000;
 /// Maximum number of inodes per changelog revision
 const MAX_INODES_PER_REVISION: u64 = 4_294_967_295;
 
@@ -434,6 +731,9 @@ impl RootInodeEncoder {
                 let entries = vec![
                     Entry::dir(COMMITS_INODE_NAME.into(), COMMITS_INODE),
                     Entry::dir(META_INODE_NAME.into(), META_INODE),
+                    Entry::dir(BRANCHES_INODE_NAME.into(), BRANCHES_INODE),
+                    Entry::dir(BOOKMARKS_INODE_NAME.into(), BOOKMARKS_INODE),
+                    Entry::dir(TAGS_INODE_NAME.into(), TAGS_INODE),
                 ];
                 Some(entries)
             }
@@ -457,6 +757,16 @@ impl RootInodeEncoder {
                 META_INODE_NAME => {
                     Some(Entry::dir(META_INODE_NAME.into(), META_INODE))
                 }
+                BRANCHES_INODE_NAME => {
+                    Some(Entry::dir(BRANCHES_INODE_NAME.into(), BRANCHES_INODE))
+                }
+                BOOKMARKS_INODE_NAME => Some(Entry::dir(
+                    BOOKMARKS_INODE_NAME.into(),
+                    BOOKMARKS_INODE,
+                )),
+                TAGS_INODE_NAME => {
+                    Some(Entry::dir(TAGS_INODE_NAME.into(), TAGS_INODE))
+                }
                 _ => None,
             },
             _ => None,
@@ -471,10 +781,39 @@ impl RootInodeEncoder {
                 Some(Entry::dir(COMMITS_INODE_NAME.into(), COMMITS_INODE))
             }
             META_INODE => Some(Entry::dir(META_INODE_NAME.into(), META_INODE)),
+            BRANCHES_INODE => {
+                Some(Entry::dir(BRANCHES_INODE_NAME.into(), BRANCHES_INODE))
+            }
+            BOOKMARKS_INODE => {
+                Some(Entry::dir(BOOKMARKS_INODE_NAME.into(), BOOKMARKS_INODE))
+            }
+            TAGS_INODE => Some(Entry::dir(TAGS_INODE_NAME.into(), TAGS_INODE)),
+            _ => None,
+        }
+    }
+
+    /// Returns the kind of symbols listed in this reserved directory, if it
+    /// is one of the symbol directories
+    pub fn symbol_kind(ino: INodeNo) -> Option<SymbolKind> {
+        match ino {
+            BRANCHES_INODE => Some(SymbolKind::Branch),
+            BOOKMARKS_INODE => Some(SymbolKind::Bookmark),
+            TAGS_INODE => Some(SymbolKind::Tag),
             _ => None,
         }
     }
 
+    /// Returns `true` if this inode is a symlink of a symbol directory
+    pub fn is_symbol(ino: INodeNo) -> bool {
+        (FIRST_SYMBOL_INODE.0..RESERVED_INODES_COUNT).contains(&ino.0)
+    }
+
+    /// Returns `true` if this inode may change with the repository, and
+    /// thus shouldn't be cached by the kernel
+    pub fn is_volatile(ino: INodeNo) -> bool {
+        Self::symbol_kind(ino).is_some() || Self::is_symbol(ino)
+    }
+
     /// Returns the data for a reserved root inode, if it exists
     pub fn data_for_reserved(_ino: INodeNo) -> Option<RawData> {
         None
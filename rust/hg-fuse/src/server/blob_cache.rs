@@ -0,0 +1,538 @@
+//! An on-disk cache of file contents, by file node.
+//!
+//! Only a bounded number of revisions stay loaded, and reading a file means
+//! fetching it from the store again, which for a local store means
+//! decompressing it from its filelog. Contents never change for a given
+//! file node, so we keep the ones we've read in a directory where all
+//! revisions, and all servers using the same directory, can find them.
+//!
+//! Each file revision is stored in `<directory>/<hex[..2]>/<hex[2..]>`.
+//! Files are written to a temporary name first and renamed into place, so
+//! they are always complete. When the cache grows past its size limit, the
+//! least recently used files are removed. Servers sharing a directory only
+//! account for the files they have seen, so the limit is approximate in that
+//! case.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use hg::FastHashMap;
+use hg::Node;
+use hg::config::Config as HgConfig;
+use hg::errors::HgError;
+use hg::errors::IoResultExt;
+use hg::matchers::Matcher;
+use hg::repo::Repo;
+use hg::utils::RawData;
+use hg::utils::files::get_path_from_bytes;
+use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::ParallelIterator;
+
+use crate::server::store::ChangesetFiles;
+use crate::server::store::Error as StoreError;
+use crate::server::store::FileToken;
+use crate::server::store::StoreBackend;
+
+/// The prefix of the files being written to the cache
+const TEMPORARY_PREFIX: &str = "tmp-";
+/// How old a file being written must be to be considered abandoned
+const STALE_TEMPORARY_AGE: Duration = Duration::from_secs(3600);
+
+/// Where to keep the cache, and how big it may grow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobCacheConfig {
+    /// The directory holding the cached contents
+    pub directory: PathBuf,
+    /// The total size of the cached contents past which the least recently
+    /// used ones are removed
+    pub max_size: u64,
+}
+
+impl BlobCacheConfig {
+    /// Returns the configuration from `fuse.blob-cache-size` and
+    /// `fuse.blob-cache-path`, or `None` if the cache is disabled.
+    ///
+    /// `default_directory` is used if no path is configured: without either,
+    /// the cache is disabled.
+    pub fn from_config(
+        config: &HgConfig,
+        default_directory: Option<PathBuf>,
+    ) -> Result<Option<Self>, HgError> {
+        let max_size =
+            match config.get_byte_size(b"fuse", b"blob-cache-size")? {
+                None | Some(0) => return Ok(None),
+                Some(size) => size,
+            };
+        let directory = config
+            .get(b"fuse", b"blob-cache-path")
+            .map(|path| get_path_from_bytes(path).to_owned())
+            .or(default_directory);
+        let Some(directory) = directory else {
+            tracing::warn!(
+                "fuse.blob-cache-size is set without fuse.blob-cache-path, \
+                 not caching file contents"
+            );
+            return Ok(None);
+        };
+        Ok(Some(Self { directory, max_size }))
+    }
+
+    /// Returns the configuration for mounts of `repo`, which are cached in
+    /// its shared `.hg/cache/fuse-blobs` by default
+    pub fn for_repo(repo: &Repo) -> Result<Option<Self>, HgError> {
+        let default_directory = repo.shared_path().join("cache/fuse-blobs");
+        Self::from_config(repo.config(), Some(default_directory))
+    }
+}
+
+/// Counters of what happened to a [`BlobCache`] since it was opened
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BlobCacheStats {
+    /// How many reads were served from the cache
+    pub hits: u64,
+    /// How many reads had to go to the store
+    pub misses: u64,
+    /// How many contents were added to the cache
+    pub insertions: u64,
+    /// How many contents were removed to stay under the size limit
+    pub evictions: u64,
+    /// The total size of the removed contents
+    pub evicted_bytes: u64,
+    /// How many contents the cache currently knows of
+    pub entries: u64,
+    /// The total size of the contents the cache currently knows of
+    pub size: u64,
+}
+
+/// What [`BlobCache::prefetch`] did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// How many files matched
+    pub matched: u64,
+    /// How many of them were already cached
+    pub already_cached: u64,
+    /// How many of them were fetched from the store
+    pub fetched: u64,
+    /// The total size of the fetched contents
+    pub fetched_bytes: u64,
+}
+
+/// A cached file, as far as the eviction order is concerned
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    /// The size of the contents
+    size: u64,
+    /// When it was last used, in ticks of [`LruIndex::clock`]
+    last_use: u64,
+}
+
+/// The files known to be in the cache, in order of use
+#[derive(Debug, Default)]
+struct LruIndex {
+    entries: FastHashMap<Node, IndexEntry>,
+    by_last_use: BTreeMap<u64, Node>,
+    total_size: u64,
+    clock: u64,
+}
+
+impl LruIndex {
+    /// Marks `node` as the most recently used, adding it if needed
+    fn touch(&mut self, node: Node, size: u64) {
+        self.clock += 1;
+        let last_use = self.clock;
+        let previous = self.entries.insert(node, IndexEntry { size, last_use });
+        if let Some(previous) = previous {
+            self.by_last_use.remove(&previous.last_use);
+            self.total_size -= previous.size;
+        }
+        self.by_last_use.insert(last_use, node);
+        self.total_size += size;
+    }
+
+    /// Forgets about `node`, returning its size if it was known
+    fn remove(&mut self, node: &Node) -> Option<u64> {
+        let entry = self.entries.remove(node)?;
+        self.by_last_use.remove(&entry.last_use);
+        self.total_size -= entry.size;
+        Some(entry.size)
+    }
+
+    /// Forgets about the least recently used node, returning it
+    fn pop_oldest(&mut self) -> Option<(Node, u64)> {
+        let (_, node) = self.by_last_use.pop_first()?;
+        let entry = self.entries.remove(&node).expect("indexes are in sync");
+        self.total_size -= entry.size;
+        Some((node, entry.size))
+    }
+}
+
+/// An on-disk cache of file contents, by file node
+pub struct BlobCache {
+    directory: PathBuf,
+    max_size: u64,
+    index: Mutex<LruIndex>,
+    /// Used to give unique names to the files being written
+    temporary_counter: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    evictions: AtomicU64,
+    evicted_bytes: AtomicU64,
+}
+
+impl BlobCache {
+    /// Opens the cache described by `config`, creating its directory if
+    /// needed and taking the contents already there into account.
+    pub fn open(config: &BlobCacheConfig) -> Result<Self, HgError> {
+        let directory = &config.directory;
+        std::fs::create_dir_all(directory).when_writing_file(directory)?;
+        let mut existing = vec![];
+        for subdirectory in
+            std::fs::read_dir(directory).when_reading_file(directory)?
+        {
+            let subdirectory =
+                subdirectory.when_reading_file(directory)?.path();
+            let Some(prefix) = subdirectory.file_name() else {
+                continue;
+            };
+            let prefix = prefix.to_string_lossy().into_owned();
+            if prefix.len() != 2 || !subdirectory.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(&subdirectory)
+                .when_reading_file(&subdirectory)?
+            {
+                let file = file.when_reading_file(&subdirectory)?;
+                let name = file.file_name().to_string_lossy().into_owned();
+                if name.starts_with(TEMPORARY_PREFIX) {
+                    // Unless another server is still writing it, it was left
+                    // behind by an interrupted write
+                    let age = file
+                        .metadata()
+                        .and_then(|metadata| metadata.modified())
+                        .map(|modified| modified.elapsed().unwrap_or_default());
+                    if age.is_ok_and(|age| age > STALE_TEMPORARY_AGE) {
+                        let _ = std::fs::remove_file(file.path());
+                    }
+                    continue;
+                }
+                let Ok(node) = Node::from_hex(format!("{prefix}{name}")) else {
+                    continue;
+                };
+                let Ok(metadata) = file.metadata() else {
+                    continue;
+                };
+                let last_use =
+                    metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                existing.push((last_use, node, metadata.len()));
+            }
+        }
+        existing.sort_unstable_by_key(|(last_use, _, _)| *last_use);
+        let mut index = LruIndex::default();
+        for (_, node, size) in existing {
+            index.touch(node, size);
+        }
+        let cache = Self {
+            directory: directory.to_owned(),
+            max_size: config.max_size,
+            index: Mutex::new(index),
+            temporary_counter: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            insertions: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            evicted_bytes: AtomicU64::new(0),
+        };
+        cache.evict(None);
+        Ok(cache)
+    }
+
+    /// Returns the path of the cached contents of `node`
+    fn path(&self, node: &Node) -> PathBuf {
+        let hex = format!("{node:x}");
+        let (prefix, name) = hex.split_at(2);
+        self.directory.join(prefix).join(name)
+    }
+
+    /// Returns the cached contents of `node`, if any
+    pub fn get(&self, node: &Node) -> Option<RawData> {
+        let path = self.path(node);
+        match read_and_touch(&path) {
+            Ok(data) => {
+                let size = data.len() as u64;
+                self.lock_index().touch(*node, size);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(data.into())
+            }
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::debug!(
+                        "failed to read cached {}: {e}",
+                        path.display()
+                    );
+                }
+                // Possibly evicted by another server sharing the directory
+                self.lock_index().remove(node);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Returns whether the contents of `node` are cached, without counting
+    /// it as a use
+    pub fn contains(&self, node: &Node) -> bool {
+        self.lock_index().entries.contains_key(node) || self.path(node).exists()
+    }
+
+    /// Adds the contents of `node` to the cache, evicting the least recently
+    /// used contents if needed. Failures are only logged since the cache is
+    /// only an optimization.
+    pub fn insert(&self, node: Node, data: &[u8]) {
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return;
+        }
+        let path = self.path(&node);
+        if let Err(e) = self.write(&path, data) {
+            tracing::debug!("failed to cache {}: {e}", path.display());
+            return;
+        }
+        self.lock_index().touch(node, size);
+        self.insertions.fetch_add(1, Ordering::Relaxed);
+        self.evict(Some(&node));
+    }
+
+    /// Returns the contents of `node`, from the cache if possible or from
+    /// `fetch` otherwise, in which case they are added to the cache
+    pub fn get_or_fetch<E>(
+        &self,
+        node: Node,
+        fetch: impl FnOnce() -> Result<RawData, E>,
+    ) -> Result<RawData, E> {
+        if let Some(data) = self.get(&node) {
+            return Ok(data);
+        }
+        let data = fetch()?;
+        self.insert(node, &data);
+        Ok(data)
+    }
+
+    /// Fetches the contents of every file of `changeset` that `matcher`
+    /// matches and that isn't cached yet, in parallel
+    pub fn prefetch<S: StoreBackend<T>, T: FileToken>(
+        &self,
+        store: &S,
+        changeset: Node,
+        matcher: &dyn Matcher,
+    ) -> Result<PrefetchStats, StoreError<T>> {
+        let files = store.changeset_files(changeset)?;
+        let matched: Vec<_> =
+            files.iter().filter(|info| matcher.matches(info.path)).collect();
+        let fetched = matched
+            .par_iter()
+            .filter_map(|info| {
+                let node = info.token.file_node()?;
+                if self.contains(&node) {
+                    return None;
+                }
+                let fetched = store
+                    .file_data(changeset, info.path, info.token)
+                    .map(|data| {
+                        self.insert(node, &data);
+                        data.len() as u64
+                    });
+                Some(fetched)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let matched_count = matched.len() as u64;
+        let fetched_count = fetched.len() as u64;
+        Ok(PrefetchStats {
+            matched: matched_count,
+            already_cached: matched_count - fetched_count,
+            fetched: fetched_count,
+            fetched_bytes: fetched.iter().sum(),
+        })
+    }
+
+    /// Returns the counters of this cache
+    pub fn stats(&self) -> BlobCacheStats {
+        let (entries, size) = {
+            let index = self.lock_index();
+            (index.entries.len() as u64, index.total_size)
+        };
+        BlobCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            evicted_bytes: self.evicted_bytes.load(Ordering::Relaxed),
+            entries,
+            size,
+        }
+    }
+
+    /// Writes `data` to `path` through a temporary file
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let parent = path.parent().expect("cache files are in a subdirectory");
+        std::fs::create_dir_all(parent)?;
+        let counter = self.temporary_counter.fetch_add(1, Ordering::Relaxed);
+        let temporary = parent.join(format!(
+            "{TEMPORARY_PREFIX}{}-{counter}",
+            std::process::id()
+        ));
+        let result = File::create(&temporary)
+            .and_then(|mut file| file.write_all(data))
+            .and_then(|()| std::fs::rename(&temporary, path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temporary);
+        }
+        result
+    }
+
+    /// Removes the least recently used contents until the cache fits in its
+    /// size limit, sparing `keep`
+    fn evict(&self, keep: Option<&Node>) {
+        loop {
+            let evicted = {
+                let mut index = self.lock_index();
+                if index.total_size <= self.max_size {
+                    return;
+                }
+                match index.pop_oldest() {
+                    Some((node, size)) if Some(&node) == keep => {
+                        // Only the new contents are left, put them back
+                        index.touch(node, size);
+                        return;
+                    }
+                    Some(evicted) => evicted,
+                    None => return,
+                }
+            };
+            let (node, size) = evicted;
+            let path = self.path(&node);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    tracing::debug!("failed to evict {}: {e}", path.display())
+                }
+            }
+            tracing::trace!(node = %format!("{node:x}"), size, "evicted");
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            self.evicted_bytes.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    fn lock_index(&self) -> std::sync::MutexGuard<'_, LruIndex> {
+        self.index.lock().expect("propagate the panic")
+    }
+}
+
+/// Reads the file at `path`, marking it as recently used for other servers
+/// opening the cache
+fn read_and_touch(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    // Only used to order the files when opening the cache
+    let _ = file.set_modified(SystemTime::now());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(byte: u8) -> Node {
+        Node::from(&[byte; 20])
+    }
+
+    fn open(directory: &Path, max_size: u64) -> BlobCache {
+        let config =
+            BlobCacheConfig { directory: directory.to_owned(), max_size };
+        BlobCache::open(&config).unwrap()
+    }
+
+    #[test]
+    fn test_get_or_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), 1024);
+        assert_eq!(cache.get(&node(1)), None);
+        let fetch = || Ok::<_, ()>(RawData::from(b"contents".to_vec()));
+        let data = cache.get_or_fetch(node(1), fetch).unwrap();
+        assert_eq!(&data[..], b"contents");
+        let data = cache
+            .get_or_fetch(node(1), || -> Result<RawData, ()> {
+                panic!("should be cached")
+            })
+            .unwrap();
+        assert_eq!(&data[..], b"contents");
+        assert_eq!(
+            cache.stats(),
+            BlobCacheStats {
+                hits: 1,
+                misses: 2,
+                insertions: 1,
+                entries: 1,
+                size: 8,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_eviction_is_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), 20);
+        cache.insert(node(1), &[1; 8]);
+        cache.insert(node(2), &[2; 8]);
+        // Make the first one the most recently used
+        assert!(cache.get(&node(1)).is_some());
+        cache.insert(node(3), &[3; 8]);
+        assert!(cache.contains(&node(1)));
+        assert!(!cache.contains(&node(2)));
+        assert!(cache.contains(&node(3)));
+        // Too big to ever be cached
+        cache.insert(node(4), &[4; 21]);
+        assert!(!cache.contains(&node(4)));
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.evicted_bytes), (1, 8));
+        assert_eq!((stats.entries, stats.size), (2, 16));
+    }
+
+    #[test]
+    fn test_reopen_and_share() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = open(dir.path(), 1024);
+        first.insert(node(1), b"one");
+        // Left behind by a crash a while ago
+        let abandoned = dir.path().join("01").join("tmp-1-0");
+        File::create(&abandoned)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        let second = open(dir.path(), 1024);
+        assert_eq!(second.stats().entries, 1);
+        assert!(!abandoned.exists());
+        second.insert(node(2), b"two");
+        // Seen by the first one even though it was added after it opened
+        assert_eq!(first.get(&node(2)), Some(b"two".to_vec().into()));
+
+        // Opening with a smaller limit evicts the oldest
+        let third = open(dir.path(), 3);
+        assert_eq!(third.stats().evictions, 1);
+        assert_eq!(third.get(&node(1)), None);
+        assert!(third.get(&node(2)).is_some());
+    }
+}
@@ -0,0 +1,403 @@
+//! A persistent cache of the sizes of file revisions, by file node.
+//!
+//! Computing the size of a file revision means decompressing it from its
+//! filelog, which is by far the most expensive part of loading a revision.
+//! Sizes never change for a given file node, so we remember them in the
+//! `.hg/cache` shared with the store, where all servers of the repository
+//! can find them across restarts.
+//!
+//! The cache is made of three files, all of which are only ever appended to:
+//!
+//! - `fuse-file-sizes.entries`: fixed-size records of a file node and its
+//!   size. The position of a record plays the role of a revision number.
+//! - `fuse-file-sizes.tree`: a [`NodeTree`] from the nodes of the records to
+//!   their positions, just like a persistent nodemap.
+//! - `fuse-file-sizes.docket`: how much of the two other files is valid,
+//!   replaced atomically after they have been appended to.
+//!
+//! Writers take the `fuse-file-sizes.lock` lock. Readers don't need it since
+//! they never look past what the docket they read says.
+//!
+//! New sizes are buffered in memory until they are persisted, so that the
+//! threads computing them don't contend on the lock of the loaded state.
+
+use std::fs::OpenOptions;
+use std::ops::Deref;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use bytes_cast::BytesCast;
+use bytes_cast::unaligned;
+use dashmap::DashMap;
+use hg::Node;
+use hg::Revision;
+use hg::errors::HgError;
+use hg::errors::IoResultExt;
+use hg::lock::LockError;
+use hg::lock::try_with_lock;
+use hg::repo::Repo;
+use hg::revlog::NULL_REVISION;
+use hg::revlog::RevlogIndex;
+use hg::revlog::nodemap::NodeMap;
+use hg::revlog::nodemap::NodeTree;
+use hg::vfs::VfsImpl;
+
+const DOCKET_FILENAME: &str = "fuse-file-sizes.docket";
+const ENTRIES_FILENAME: &str = "fuse-file-sizes.entries";
+const TREE_FILENAME: &str = "fuse-file-sizes.tree";
+const LOCK_FILENAME: &str = "fuse-file-sizes.lock";
+const ONDISK_VERSION: u8 = 1;
+/// How long to wait for another server to finish writing to the cache
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Buffer = Box<dyn Deref<Target = [u8]> + Send + Sync>;
+
+/// The size of a file revision, as stored on disk
+#[derive(BytesCast, Clone, Copy)]
+#[repr(C)]
+struct SizeEntry {
+    node: Node,
+    size: unaligned::U64Be,
+}
+
+/// How much of the entries and tree files is valid
+#[derive(BytesCast, Clone, Copy)]
+#[repr(C)]
+struct DocketData {
+    version: u8,
+    entries_count: unaligned::U64Be,
+    tree_length: unaligned::U64Be,
+}
+
+/// The part of the cache we've read from disk
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Docket {
+    entries_count: usize,
+    tree_length: usize,
+}
+
+impl Docket {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let (data, rest) = DocketData::from_bytes(bytes).ok()?;
+        if data.version != ONDISK_VERSION || !rest.is_empty() {
+            return None;
+        }
+        Some(Self {
+            entries_count: data.entries_count.get().try_into().ok()?,
+            tree_length: data.tree_length.get().try_into().ok()?,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let data = DocketData {
+            version: ONDISK_VERSION,
+            entries_count: (self.entries_count as u64).into(),
+            tree_length: (self.tree_length as u64).into(),
+        };
+        data.as_bytes().to_vec()
+    }
+}
+
+/// The size entries, which serve as the index of the [`NodeTree`]
+struct Entries {
+    /// The entries read from disk
+    on_disk: Buffer,
+    /// The number of valid entries in `on_disk`
+    on_disk_count: usize,
+    /// The entries added since
+    added: Vec<SizeEntry>,
+}
+
+impl Entries {
+    fn get(&self, rev: Revision) -> Option<&SizeEntry> {
+        let index = usize::try_from(rev.0).ok()?;
+        if index < self.on_disk_count {
+            let start = index * size_of::<SizeEntry>();
+            let (entry, _) =
+                SizeEntry::from_bytes(&self.on_disk[start..]).ok()?;
+            Some(entry)
+        } else {
+            self.added.get(index - self.on_disk_count)
+        }
+    }
+}
+
+impl RevlogIndex for Entries {
+    fn len(&self) -> usize {
+        self.on_disk_count + self.added.len()
+    }
+
+    fn node(&self, rev: Revision) -> &Node {
+        &self.get(rev).expect("the node tree only has valid entries").node
+    }
+}
+
+/// Everything we know about the cache
+struct State {
+    /// The part of the files we've read
+    docket: Docket,
+    entries: Entries,
+    tree: NodeTree,
+}
+
+impl State {
+    fn empty() -> Self {
+        Self {
+            docket: Docket::default(),
+            entries: Entries {
+                on_disk: Box::<Vec<u8>>::default(),
+                on_disk_count: 0,
+                added: vec![],
+            },
+            tree: NodeTree::default(),
+        }
+    }
+
+    /// Reads the cache as described by the current docket, if it's valid
+    fn read(vfs: &VfsImpl) -> Result<Self, HgError> {
+        let Some(docket) = vfs.try_read(DOCKET_FILENAME)? else {
+            return Ok(Self::empty());
+        };
+        let Some(docket) = Docket::parse(&docket) else {
+            tracing::warn!("ignoring invalid file size cache docket");
+            return Ok(Self::empty());
+        };
+        let entries = vfs.mmap_open_opt(ENTRIES_FILENAME)?;
+        let tree = vfs.mmap_open_opt(TREE_FILENAME)?;
+        let entries_length = docket.entries_count * size_of::<SizeEntry>();
+        match (entries, tree) {
+            (Some(entries), Some(tree))
+                if entries.len() >= entries_length
+                    && tree.len() >= docket.tree_length =>
+            {
+                Ok(Self {
+                    docket,
+                    entries: Entries {
+                        on_disk: Box::new(entries),
+                        on_disk_count: docket.entries_count,
+                        added: vec![],
+                    },
+                    tree: NodeTree::load_bytes(
+                        Box::new(tree),
+                        docket.tree_length,
+                    ),
+                })
+            }
+            _ => {
+                tracing::warn!("ignoring truncated file size cache");
+                Ok(Self::empty())
+            }
+        }
+    }
+
+    fn get(&self, node: &Node) -> Option<u64> {
+        match self.tree.find_node(&self.entries, node) {
+            Ok(Some(rev)) if rev != NULL_REVISION => {
+                Some(self.entries.get(rev)?.size.get())
+            }
+            Ok(_) => None,
+            Err(e) => {
+                tracing::debug!(error = ?e, "corrupted file size cache");
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, node: Node, size: u64) {
+        if self.get(&node).is_some() {
+            return;
+        }
+        let rev = Revision(self.entries.len() as i32);
+        self.entries.added.push(SizeEntry { node, size: size.into() });
+        if let Err(e) = self.tree.insert(&self.entries, &node, rev) {
+            tracing::debug!(error = ?e, "failed to cache file size");
+            self.entries.added.pop();
+        }
+    }
+}
+
+/// A cache of the sizes of file revisions, persisted in the repository
+pub(super) struct FileSizeCache {
+    /// The cache directory of the repository
+    directory: PathBuf,
+    vfs: VfsImpl,
+    /// The cache as last read from disk, only replaced by [`Self::persist`]
+    state: RwLock<State>,
+    /// The sizes learned since, not persisted yet
+    pending: DashMap<Node, u64>,
+}
+
+impl FileSizeCache {
+    /// Opens the cache of `repo`, starting from scratch if it is unreadable
+    pub(super) fn open(repo: &Repo) -> Self {
+        let directory = repo.shared_path().join("cache");
+        let vfs = repo.cache_vfs();
+        let state = State::read(&vfs).unwrap_or_else(|e| {
+            tracing::warn!(error = ?e, "failed to read the file size cache");
+            State::empty()
+        });
+        Self {
+            directory,
+            vfs,
+            state: RwLock::new(state),
+            pending: DashMap::new(),
+        }
+    }
+
+    /// Returns the size of the file revision with this node, if known
+    pub(super) fn get(&self, node: &Node) -> Option<u64> {
+        let known = self.state.read().expect("propagate the panic").get(node);
+        known.or_else(|| self.pending.get(node).map(|size| *size))
+    }
+
+    /// Remembers the size of the file revision with this node
+    pub(super) fn insert(&self, node: Node, size: u64) {
+        if self.state.read().expect("propagate the panic").get(&node).is_none()
+        {
+            self.pending.insert(node, size);
+        }
+    }
+
+    /// Returns the number of sizes known
+    pub(super) fn len(&self) -> usize {
+        let state = self.state.read().expect("propagate the panic");
+        state.entries.len() + self.pending.len()
+    }
+
+    /// Appends the sizes learned since the last call to the files on disk,
+    /// along with any written by other servers in the meantime.
+    pub(super) fn persist(&self) -> Result<(), HgError> {
+        let added: Vec<(Node, u64)> = self
+            .pending
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        if added.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.directory)
+            .when_writing_file(&self.directory)?;
+        // Other servers may hold the lock for a while, keep serving sizes
+        // from the current state in the meantime
+        let result =
+            try_with_lock(&self.vfs, LOCK_FILENAME, LOCK_TIMEOUT, || {
+                self.persist_locked(&added)
+            });
+        let new_state = match result {
+            Ok(result) => result?,
+            Err(LockError::AlreadyHeld) => {
+                return Err(HgError::abort_simple(
+                    "timed out waiting for the file size cache lock",
+                ));
+            }
+            Err(LockError::IO(error)) => return Err(error.into()),
+        };
+        *self.state.write().expect("propagate the panic") = new_state;
+        // Sizes learned while we were writing stay pending
+        for (node, _) in &added {
+            self.pending.remove(node);
+        }
+        Ok(())
+    }
+
+    /// Writes these sizes and returns the cache as it now is on disk
+    fn persist_locked(&self, sizes: &[(Node, u64)]) -> Result<State, HgError> {
+        // Start from what is on disk now, since another server may have
+        // written to the cache since we last read it
+        let mut on_disk = State::read(&self.vfs)?;
+        for &(node, size) in sizes {
+            on_disk.insert(node, size);
+        }
+        let docket = on_disk.docket;
+        let added = std::mem::take(&mut on_disk.entries.added);
+        let (_, tree_bytes) = on_disk.tree.into_readonly_and_added_bytes();
+
+        let entries_offset = docket.entries_count * size_of::<SizeEntry>();
+        self.write_at(ENTRIES_FILENAME, entries_offset, added.as_bytes())?;
+        self.write_at(TREE_FILENAME, docket.tree_length, &tree_bytes)?;
+        let new_docket = Docket {
+            entries_count: docket.entries_count + added.len(),
+            tree_length: docket.tree_length + tree_bytes.len(),
+        };
+        self.vfs.atomic_write(DOCKET_FILENAME, &new_docket.to_bytes())?;
+        State::read(&self.vfs)
+    }
+
+    /// Writes `data` at `offset` in this file, overwriting whatever an
+    /// interrupted write may have left after the valid part
+    fn write_at(
+        &self,
+        filename: &str,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), HgError> {
+        let path = self.vfs.join(filename);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .when_writing_file(&path)?;
+        file.write_all_at(data, offset as u64).when_writing_file(&path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(byte: u8, last: u8) -> Node {
+        let mut bytes = [byte; 20];
+        bytes[19] = last;
+        Node::from(bytes)
+    }
+
+    #[test]
+    fn test_state_insert_and_get() {
+        let mut state = State::empty();
+        // Nodes sharing long prefixes force the tree to split blocks
+        state.insert(node(0xab, 1), 10);
+        state.insert(node(0xab, 2), 20);
+        state.insert(node(0x12, 1), 30);
+        state.insert(node(0xab, 1), 40);
+        assert_eq!(state.get(&node(0xab, 1)), Some(10));
+        assert_eq!(state.get(&node(0xab, 2)), Some(20));
+        assert_eq!(state.get(&node(0x12, 1)), Some(30));
+        assert_eq!(state.get(&node(0xab, 3)), None);
+        assert_eq!(state.get(&node(0x00, 0)), None);
+        assert_eq!(state.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_state_reload() {
+        let mut state = State::empty();
+        state.insert(node(0xab, 1), 10);
+        state.insert(node(0xab, 2), 20);
+        let added = std::mem::take(&mut state.entries.added);
+        let (_, tree) = state.tree.into_readonly_and_added_bytes();
+        let tree_length = tree.len();
+        let mut reloaded = State {
+            docket: Docket { entries_count: added.len(), tree_length },
+            entries: Entries {
+                on_disk: Box::new(added.as_bytes().to_vec()),
+                on_disk_count: added.len(),
+                added: vec![],
+            },
+            tree: NodeTree::load_bytes(Box::new(tree), tree_length),
+        };
+        reloaded.insert(node(0xab, 3), 30);
+        assert_eq!(reloaded.get(&node(0xab, 1)), Some(10));
+        assert_eq!(reloaded.get(&node(0xab, 2)), Some(20));
+        assert_eq!(reloaded.get(&node(0xab, 3)), Some(30));
+    }
+
+    #[test]
+    fn test_docket_roundtrip() {
+        let docket = Docket { entries_count: 3, tree_length: 192 };
+        assert_eq!(Docket::parse(&docket.to_bytes()), Some(docket));
+        assert_eq!(Docket::parse(b"\x02"), None);
+    }
+}
@@ -1,11 +1,21 @@
 //! Implementation of a [`StoreBackend`] based off a local repository.
 
+use std::collections::BTreeMap;
 use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::atomic::AtomicU32;
+use std::time::SystemTime;
 
 use dashmap::DashMap;
+use hg::FastHashMap;
 use hg::Node;
+use hg::Revision;
+use hg::bookmarks;
+use hg::branchmap::BranchMap;
 use hg::errors::HgError;
+use hg::errors::IoResultExt;
 use hg::matchers::Matcher;
 use hg::narrow;
 use hg::operations::FilesForDirstateBorrowed;
@@ -13,6 +23,7 @@ use hg::repo::Repo;
 use hg::revlog::manifest::DecodedManifestEntry;
 use hg::revlog::manifest::Manifest;
 use hg::sparse;
+use hg::tags;
 use hg::utils::RawData;
 use hg::utils::hg_path::HgPath;
 use hg::utils::u_u64;
@@ -22,6 +33,8 @@ use rayon::iter::ParallelIterator;
 use self_cell::self_cell;
 
 use crate::server::Config;
+use crate::server::blob_cache::BlobCacheConfig;
+use crate::server::file_sizes::FileSizeCache;
 use crate::server::store::BackendMode;
 use crate::server::store::ChangesetFiles;
 use crate::server::store::ChangesetFilesDiff;
@@ -33,6 +46,7 @@ use crate::server::store::FileToken;
 use crate::server::store::RevisionIdx;
 use crate::server::store::StoreBackend;
 use crate::server::store::StoreInfo;
+use crate::server::store::SymbolKind;
 
 /// A [`StoreBackend`] implementation that uses a normal, local repository.
 pub struct LocalBackend {
@@ -40,8 +54,8 @@ pub struct LocalBackend {
     repo: Repo,
     /// The configuration applicable to this backend
     server_config: Config,
-    /// A cache of file nodeids to their size
-    file_nodeid_to_size: DashMap<Node, u64>,
+    /// A cache of file nodeids to their size, shared with other servers
+    file_nodeid_to_size: FileSizeCache,
     /// Mapping from revision to revision index
     node_to_idx: DashMap<Node, u32>,
     /// Mapping from revision index to revision
@@ -50,6 +64,21 @@ pub struct LocalBackend {
     current_idx: AtomicU32,
     /// The narrow matcher for this repository, computed at the start
     narrow_matcher: Box<dyn Matcher + Send + 'static>,
+    /// The symbolic names computed since the repository last changed
+    symbols: Mutex<SymbolCache>,
+}
+
+/// The size and modification time of the files symbolic names depend on,
+/// which change whenever the names might have
+type Fingerprint = Vec<Option<(u64, SystemTime)>>;
+
+/// A cache of the symbolic names of a repository
+#[derive(Default)]
+struct SymbolCache {
+    /// The state of the repository these names were computed for
+    fingerprint: Fingerprint,
+    /// The names computed so far, by kind
+    by_kind: FastHashMap<SymbolKind, Arc<BTreeMap<Vec<u8>, Node>>>,
 }
 
 impl LocalBackend {
@@ -59,8 +88,9 @@ impl LocalBackend {
             preload_structure: repo_config
                 .get_bool(b"fuse", b"preload-working-copy-structure")?,
             backend_mode,
+            blob_cache: BlobCacheConfig::for_repo(&repo)?,
         };
-        let file_nodeid_to_size = DashMap::new();
+        let file_nodeid_to_size = FileSizeCache::open(&repo);
 
         let warnings = HgWarningContext::new();
         let narrow_matcher = narrow::matcher(&repo, warnings.sender())?;
@@ -78,9 +108,81 @@ impl LocalBackend {
             idx_to_node: DashMap::new(),
             current_idx: AtomicU32::new(1),
             narrow_matcher,
+            symbols: Mutex::default(),
         })
     }
 
+    /// Write the file sizes we've just computed to disk, if any
+    fn persist_file_sizes(&self, cache_misses: usize) {
+        if cache_misses == 0 {
+            return;
+        }
+        if let Err(e) = self.file_nodeid_to_size.persist() {
+            // Not a big deal, we'll compute them again next time
+            tracing::warn!(error = ?e, "failed to persist file sizes");
+        }
+    }
+
+    /// Returns the files that change whenever the symbolic names might have
+    fn symbol_sources(&self) -> Result<[PathBuf; 3], HgError> {
+        let store = self.repo.store_path();
+        Ok([
+            store.join("00changelog.i"),
+            store.join("00changelog.d"),
+            bookmarks::bookmarks_path(&self.repo)?,
+        ])
+    }
+
+    /// Returns the current state of the files symbolic names depend on
+    fn symbol_fingerprint(&self) -> Result<Fingerprint, HgError> {
+        let mut fingerprint = vec![];
+        for path in self.symbol_sources()? {
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    fingerprint.push(None);
+                    continue;
+                }
+                Err(e) => return Err(e).when_reading_file(&path)?,
+            };
+            let modified = metadata.modified().when_reading_file(&path)?;
+            fingerprint.push(Some((metadata.len(), modified)));
+        }
+        Ok(fingerprint)
+    }
+
+    /// Computes the symbolic names of this kind from the repository
+    fn compute_symbols(
+        &self,
+        kind: SymbolKind,
+    ) -> Result<BTreeMap<Vec<u8>, Node>, HgError> {
+        match kind {
+            SymbolKind::Branch => {
+                let changelog = self.repo.changelog()?;
+                let branchmap = BranchMap::new(&changelog)?;
+                let branches = branchmap
+                    .iter()
+                    .filter_map(|(name, _)| {
+                        let tip = branchmap.tip(name)?;
+                        Some((name.to_vec(), *changelog.node_from_rev(tip)))
+                    })
+                    .collect();
+                Ok(branches)
+            }
+            SymbolKind::Bookmark => bookmarks::read_bookmarks(&self.repo),
+            SymbolKind::Tag => {
+                let mut tags = tags::global_tags(&self.repo)?;
+                let changelog = self.repo.changelog()?;
+                let len = changelog.get_index().len();
+                if len > 0 {
+                    let tip = Revision(len as i32 - 1);
+                    tags.insert(b"tip".to_vec(), *changelog.node_from_rev(tip));
+                }
+                Ok(tags)
+            }
+        }
+    }
+
     /// Returns an iterator over this manifest given this sparse matcher
     fn changeset_files_iterator<'manifest>(
         &self,
@@ -105,14 +207,12 @@ impl LocalBackend {
                     // We already know this size
                     return Ok(FileInfo {
                         path,
-                        size: *size,
+                        size,
                         flags,
                         token: LocalToken(file_node),
                     });
                 }
                 let filelog = self.repo.filelog(path)?;
-                // TODO keep a persistent NodeTree of filenode_id -> size until
-                // we have it in revlogv2?
                 let size = u_u64(filelog.contents_size_for_node(file_node)?);
                 self.file_nodeid_to_size.insert(file_node, size);
                 Ok(FileInfo { path, size, flags, token: LocalToken(file_node) })
@@ -120,8 +220,10 @@ impl LocalBackend {
             .collect::<Result<Vec<_>, hg::revlog::RevlogError>>()?;
         drop(size_span);
 
-        let cache_misses = self.file_nodeid_to_size.len() - cached_file_sizes;
+        let cache_misses =
+            self.file_nodeid_to_size.len().saturating_sub(cached_file_sizes);
         tracing::debug!("cached {} new filelog node sizes", cache_misses);
+        self.persist_file_sizes(cache_misses);
 
         Ok(ChangesetFilesIterator { inner: vec })
     }
@@ -161,7 +263,7 @@ impl LocalBackend {
                             // We already know this size
                             let file_info = FileInfo {
                                 path,
-                                size: *size,
+                                size,
                                 flags,
                                 token: LocalToken(file_node),
                             };
@@ -172,9 +274,6 @@ impl LocalBackend {
                             }
                         }
                         let filelog = self.repo.filelog(path)?;
-                        // TODO keep a persistent NodeTree of filenode_id ->
-                        // size until we have it in
-                        // revlogv2?
                         let size =
                             u_u64(filelog.contents_size_for_node(file_node)?);
                         self.file_nodeid_to_size.insert(file_node, size);
@@ -197,8 +296,10 @@ impl LocalBackend {
             .collect::<Result<Vec<_>, hg::revlog::RevlogError>>()?;
         drop(size_span);
 
-        let cache_misses = self.file_nodeid_to_size.len() - cached_file_sizes;
+        let cache_misses =
+            self.file_nodeid_to_size.len().saturating_sub(cached_file_sizes);
         tracing::debug!("cached {} new filelog node sizes", cache_misses);
+        self.persist_file_sizes(cache_misses);
 
         Ok(ChangesetFilesDiffIterator { inner: vec })
     }
@@ -382,6 +483,26 @@ impl StoreBackend<LocalToken> for LocalBackend {
             branch,
         }))
     }
+
+    fn symbols(
+        &self,
+        kind: SymbolKind,
+    ) -> Result<Arc<BTreeMap<Vec<u8>, Node>>, Error<LocalToken>> {
+        let mut cache = self.symbols.lock().expect("propagate the panic");
+        let fingerprint = self.symbol_fingerprint()?;
+        if cache.fingerprint != fingerprint {
+            // The repository changed, pick up its new revisions
+            self.repo.reload_revlogs()?;
+            *cache =
+                SymbolCache { fingerprint, by_kind: FastHashMap::default() };
+        }
+        if let Some(symbols) = cache.by_kind.get(&kind) {
+            return Ok(Arc::clone(symbols));
+        }
+        let symbols = Arc::new(self.compute_symbols(kind)?);
+        cache.by_kind.insert(kind, Arc::clone(&symbols));
+        Ok(symbols)
+    }
 }
 
 self_cell!(
@@ -440,4 +561,8 @@ impl ChangesetFilesDiff<LocalToken> for ManifestRefDiffIterator {
 #[derive(Debug, Clone, Copy)]
 pub struct LocalToken(pub Node);
 
-impl FileToken for LocalToken {}
+impl FileToken for LocalToken {
+    fn file_node(&self) -> Option<Node> {
+        Some(self.0)
+    }
+}
@@ -1,4 +1,7 @@
 use std::convert::Infallible;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
@@ -7,12 +10,15 @@ use std::sync::Mutex;
 use std::time::Duration;
 use std::time::SystemTime;
 
+use fuser::Errno;
 use fuser::FileAttr;
 use fuser::FileType;
 use fuser::INodeNo;
+use fuser::TimeOrNow;
 use hg::Node;
 use hg::errors::HgError;
 use hg::errors::IoResultExt;
+use hg::matchers::Matcher;
 use hg::revlog::manifest::ManifestFlags;
 use hg::utils::RawData;
 use hg::warnings::HgWarningContext;
@@ -21,18 +27,41 @@ use quick_cache::sync::GuardResult;
 
 use crate::fuse::COMMITS_INODE;
 use crate::fuse::Entry;
+use crate::fuse::FILES_INODE_NAME;
+use crate::fuse::ROOT_INODE;
 use crate::fuse::RootInodeEncoder;
 use crate::fuse::path_to_revision_working_copy;
+use crate::server::blob_cache::BlobCache;
+use crate::server::blob_cache::BlobCacheConfig;
+use crate::server::blob_cache::BlobCacheStats;
+use crate::server::blob_cache::PrefetchStats;
+use crate::server::overlay::Overlay;
+use crate::server::overlay::RevisionOverlay;
 use crate::server::revision::OwnedRevision;
+use crate::server::stats::Counters;
+use crate::server::stats::ServerStatsHandle;
 use crate::server::store::BackendMode;
 use crate::server::store::DirstateBaseInfo;
 use crate::server::store::Error as StoreError;
+use crate::server::store::ErrorKind;
 use crate::server::store::FileToken;
 use crate::server::store::StoreBackend;
+use crate::server::store::SymbolKind;
+use crate::server::symbols::SymbolInodes;
+use crate::server::symbols::escape_name;
+use crate::server::symbols::symlink_target;
+use crate::server::symbols::unescape_name;
 
+pub mod blob_cache;
+mod file_sizes;
 pub mod local;
+pub mod overlay;
+#[cfg(feature = "remote")]
+pub mod remote;
 pub mod revision;
+pub mod stats;
 pub mod store;
+mod symbols;
 
 const DEFAULT_MAX_REVISIONS_LOADED: usize = 64;
 const BLOCK_SIZE: u32 = 4096;
@@ -51,6 +80,8 @@ pub struct Config {
     preload_structure: bool,
     /// What sort of working copy the VFS should present
     backend_mode: BackendMode,
+    /// Where to cache file contents on disk, if anywhere
+    blob_cache: Option<BlobCacheConfig>,
 }
 
 /// Responsible for serving contents from the store to the FUSE layer
@@ -71,6 +102,18 @@ pub struct Server<S, T> {
     /// Information about a previously loaded dirstate to compute later ones
     /// incrementally.
     dirstate_base_info: Mutex<Option<DirstateBaseInfo<T>>>,
+    /// The copy-on-write layer receiving all changes, if this FUSE is
+    /// writable
+    overlay: Option<Overlay>,
+    /// The inodes of the symlinks of the symbol directories
+    symbol_inodes: SymbolInodes,
+    /// The on-disk cache of file contents, if enabled
+    blob_cache: Option<Arc<BlobCache>>,
+    /// The inode that the root of the FUSE stands for, if it only exposes
+    /// the working copy of a single revision
+    root_redirect: Option<INodeNo>,
+    /// What this server did since it was started
+    counters: Arc<Counters>,
 }
 
 impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
@@ -80,6 +123,7 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
         group_id: Option<u32>,
         mount_point: impl AsRef<Path>,
         max_revisions_loaded: Option<usize>,
+        overlay_directory: Option<PathBuf>,
     ) -> Result<Self, HgError> {
         let process_metadata =
             std::fs::metadata("/proc/self").when_reading_file("/proc/self")?;
@@ -93,32 +137,188 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
             Ok(())
         });
 
+        // Working copies point `status` to their overlay, which must not
+        // depend on the current directory
+        let overlay_directory = overlay_directory
+            .map(|directory| {
+                std::path::absolute(&directory).when_reading_file(&directory)
+            })
+            .transpose()?;
+
+        let blob_cache = match &store.server_config().blob_cache {
+            Some(config) => Some(Arc::new(BlobCache::open(config)?)),
+            None => None,
+        };
+
+        // Use a constant time, so that restarts don't affect the dirstate.
+        let start_time =
+            SystemTime::UNIX_EPOCH + MERCURIAL_FIRST_COMMIT_TIMESTAMP;
         Ok(Self {
             store,
             revisions: Cache::new(
                 max_revisions_loaded.unwrap_or(DEFAULT_MAX_REVISIONS_LOADED),
             ),
-            // Use a constant time, so that restarts don't affect the dirstate.
-            start_time: SystemTime::UNIX_EPOCH
-                + MERCURIAL_FIRST_COMMIT_TIMESTAMP,
+            start_time,
             uid,
             gid,
             mount_point: mount_point.as_ref().to_path_buf(),
             dirstate_base_info: Mutex::new(None),
+            overlay: overlay_directory
+                .map(|directory| Overlay::new(directory, start_time)),
+            symbol_inodes: SymbolInodes::new(),
+            blob_cache,
+            root_redirect: None,
+            counters: Arc::new(Counters::default()),
         })
     }
 
+    /// Only expose the working copy of `changeset`, at the root of the FUSE,
+    /// instead of every revision.
+    ///
+    /// Must be called before mounting.
+    pub fn set_root_revision(
+        &mut self,
+        changeset: Node,
+    ) -> Result<(), StoreError<T>> {
+        // Don't go through `get_revision`, which would preload the structure
+        // before anything is mounted
+        let (revision, dirstate_base) = OwnedRevision::from_revision(
+            &self.store,
+            changeset,
+            self.start_time,
+            None,
+            self.blob_cache.clone(),
+            self.overlay_directory(changeset).as_deref(),
+        )?;
+        let revision_root = RootInodeEncoder::revision_inode(
+            self.store.idx_for_node(changeset)?,
+        );
+        let files_root = revision
+            .lookup(revision_root, OsStr::new(FILES_INODE_NAME))
+            .expect("every revision has a working copy");
+        self.root_redirect = Some(files_root.ino());
+        self.revisions.insert(changeset, Arc::new(revision));
+        self.counters.revision_loaded(self.revisions.len());
+        *self.dirstate_base_info.get_mut().expect("propagate the panic") =
+            Some(dirstate_base);
+        Ok(())
+    }
+
+    /// Returns a handle on the counters of this server, which outlives its
+    /// mounting
+    pub fn stats_handle(&self) -> ServerStatsHandle {
+        ServerStatsHandle::new(
+            Arc::clone(&self.counters),
+            self.blob_cache.clone(),
+        )
+    }
+
+    /// Record that `bytes` of file contents were sent to the kernel
+    pub fn record_served(&self, bytes: usize) {
+        self.counters.served(bytes);
+    }
+
+    /// Returns the inode that this one stands for, which is only different
+    /// for the root of a FUSE exposing a single revision
+    fn resolve(&self, ino: INodeNo) -> INodeNo {
+        match self.root_redirect {
+            Some(root) if ino == ROOT_INODE => root,
+            _ => ino,
+        }
+    }
+
+    /// Returns the counters of the on-disk cache of file contents, if enabled
+    pub fn blob_cache_stats(&self) -> Option<BlobCacheStats> {
+        Some(self.blob_cache.as_ref()?.stats())
+    }
+
+    /// Fetches the contents of every file of `changeset` that `matcher`
+    /// matches into the on-disk cache, in parallel. Does nothing if the
+    /// cache is disabled.
+    pub fn prefetch(
+        &self,
+        changeset: Node,
+        matcher: &dyn Matcher,
+    ) -> Result<PrefetchStats, StoreError<T>> {
+        match &self.blob_cache {
+            Some(blob_cache) => {
+                blob_cache.prefetch(&self.store, changeset, matcher)
+            }
+            None => Ok(PrefetchStats::default()),
+        }
+    }
+
+    /// Whether changes can be made to the revisions' working copies
+    pub fn is_writable(&self) -> bool {
+        self.overlay.is_some()
+    }
+
+    /// Where the changes to the working copy of `changeset` go, if this FUSE
+    /// is writable
+    fn overlay_directory(&self, changeset: Node) -> Option<PathBuf> {
+        Some(self.overlay.as_ref()?.revision_directory(changeset))
+    }
+
     pub fn attributes(&self, ino: INodeNo) -> Option<fuser::FileAttr> {
         let entry = self.get_entry(ino)?;
         Some(self.attributes_for_entry(entry))
     }
 
     pub fn attributes_for_entry(&self, entry: Entry) -> fuser::FileAttr {
+        let mut attributes = self.attributes_for_resolved_entry(entry);
+        if Some(attributes.ino) == self.root_redirect {
+            // Keep the kernel's view of the root consistent
+            attributes.ino = ROOT_INODE;
+        }
+        attributes
+    }
+
+    fn attributes_for_resolved_entry(&self, entry: Entry) -> fuser::FileAttr {
         match entry {
             Entry::Dir { ino, name: _ } => self.attributes_for_directory(ino),
             Entry::File { name: _, ino, size, flags } => {
                 self.attributes_for_file(ino, size, flags)
             }
+            Entry::Overlay { name: _, ino, metadata } => {
+                self.attributes_for_overlay(ino, &metadata)
+            }
+        }
+    }
+
+    fn attributes_for_overlay(
+        &self,
+        ino: INodeNo,
+        metadata: &std::fs::Metadata,
+    ) -> FileAttr {
+        let file_type = metadata.file_type();
+        let time =
+            |time: std::io::Result<SystemTime>| time.unwrap_or(self.start_time);
+        FileAttr {
+            ino,
+            size: metadata.size(),
+            blocks: metadata.blocks(),
+            atime: time(metadata.accessed()),
+            mtime: time(metadata.modified()),
+            ctime: SystemTime::UNIX_EPOCH
+                + Duration::new(
+                    metadata.ctime().try_into().unwrap_or(0),
+                    metadata.ctime_nsec().try_into().unwrap_or(0),
+                ),
+            crtime: time(metadata.created()),
+            kind: if file_type.is_dir() {
+                FileType::Directory
+            } else if file_type.is_symlink() {
+                FileType::Symlink
+            } else {
+                FileType::RegularFile
+            },
+            perm: (metadata.mode() & 0o7777) as u16,
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: BLOCK_SIZE,
         }
     }
 
@@ -152,12 +352,19 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
     }
 
     fn attributes_for_directory(&self, ino: INodeNo) -> FileAttr {
+        // The kernel drops its cached listing of a directory when its mtime
+        // changes, which the symbol directories' must do all the time.
+        let mtime = if RootInodeEncoder::symbol_kind(ino).is_some() {
+            SystemTime::now()
+        } else {
+            self.start_time
+        };
         FileAttr {
             ino,
             size: FAKE_DIR_SIZE,
             blocks: 0,
             atime: self.start_time,
-            mtime: self.start_time,
+            mtime,
             ctime: self.start_time,
             crtime: self.start_time,
             kind: FileType::Directory,
@@ -173,9 +380,20 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
 
     /// Return the [`Entry`] that corresponds to `ino`
     pub fn get_entry(&self, ino: fuser::INodeNo) -> Option<Entry> {
+        let ino = self.resolve(ino);
+        if RootInodeEncoder::is_symbol(ino) {
+            let (kind, name) = self.symbol_inodes.symbol(ino)?;
+            let changeset = self.resolve_symbol(kind, &name).ok()??;
+            return Some(self.symbol_entry(ino, &name, changeset));
+        }
         if RootInodeEncoder::is_reserved(ino) {
             return RootInodeEncoder::entry_for_reserved(ino);
         }
+        if let Some(entry) = self.with_overlay(ino, |overlay, revision| {
+            overlay.get_entry(revision, ino)
+        }) {
+            return entry;
+        }
         self.with_revision(ino, |revision| revision.get_entry(ino))?
     }
 
@@ -185,6 +403,7 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
         parent: INodeNo,
         name: &std::ffi::OsStr,
     ) -> Result<Option<Entry>, StoreError<T>> {
+        let parent = self.resolve(parent);
         if RootInodeEncoder::is_reserved(parent) {
             if parent == COMMITS_INODE {
                 if let Ok(node) = Node::from_hex(name.as_encoded_bytes()) {
@@ -198,10 +417,26 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
                 } else {
                     return Ok(None);
                 };
+            } else if let Some(kind) = RootInodeEncoder::symbol_kind(parent) {
+                let Some(name) = unescape_name(name.as_encoded_bytes()) else {
+                    return Ok(None);
+                };
+                let Some(changeset) = self.resolve_symbol(kind, &name)? else {
+                    return Ok(None);
+                };
+                let Some(ino) = self.symbol_inodes.inode(kind, &name) else {
+                    return Ok(None);
+                };
+                return Ok(Some(self.symbol_entry(ino, &name, changeset)));
             } else {
                 return Ok(RootInodeEncoder::lookup_reserved(parent, name));
             }
         }
+        if let Some(entry) = self.with_overlay(parent, |overlay, revision| {
+            overlay.lookup(revision, parent, name)
+        }) {
+            return Ok(entry);
+        }
         let maybe_entry_result = self
             .with_revision(parent, |revision| revision.lookup(parent, name));
         match maybe_entry_result {
@@ -228,6 +463,8 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
             changeset,
             self.start_time,
             base_dirstate,
+            self.blob_cache.clone(),
+            self.overlay_directory(changeset).as_deref(),
         )?;
         let revision_arc = Arc::new(revision_data);
         let preload = self.store.server_config().preload_structure;
@@ -254,6 +491,7 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
                 let (revision, new_dirstate_base) =
                     self.load_revision(changeset, base_dirstate_guard.take())?;
                 let _ = g.insert(Arc::clone(&revision));
+                self.counters.revision_loaded(self.revisions.len());
                 // Remember the latest dirstate update for later incremental
                 // loads
                 *base_dirstate_guard = Some(new_dirstate_base);
@@ -275,16 +513,42 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
         changeset: Node,
         revision: Arc<OwnedRevision<T>>,
     ) {
-        let root =
-            self.mount_point.join(path_to_revision_working_copy(changeset));
+        let root = if self.root_redirect.is_some() {
+            self.mount_point.clone()
+        } else {
+            self.mount_point.join(path_to_revision_working_copy(changeset))
+        };
         rayon::spawn(move || revision.preload(&root));
     }
 
     /// Return entries for all direct children of this inode
     pub fn entries(&self, ino: INodeNo) -> Option<Vec<Entry>> {
+        let ino = self.resolve(ino);
+        if let Some(kind) = RootInodeEncoder::symbol_kind(ino) {
+            let symbols = self
+                .store
+                .symbols(kind)
+                .inspect_err(
+                    |e| tracing::warn!(error = ?e, "failed to list {kind:?}"),
+                )
+                .ok()?;
+            let entries = symbols
+                .iter()
+                .filter_map(|(name, changeset)| {
+                    let ino = self.symbol_inodes.inode(kind, name)?;
+                    Some(self.symbol_entry(ino, name, *changeset))
+                })
+                .collect();
+            return Some(entries);
+        }
         if RootInodeEncoder::is_reserved(ino) {
             return RootInodeEncoder::entries_for_reserved(ino);
         }
+        if let Some(entries) = self.with_overlay(ino, |overlay, revision| {
+            overlay.entries(revision, ino)
+        }) {
+            return entries;
+        }
         self.with_revision(ino, |revision| -> Option<Vec<Entry>> {
             revision.entries(ino)
         })?
@@ -292,9 +556,26 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
 
     /// Return the contents of the file at this inoode, if it exists.
     pub fn read(&self, ino: INodeNo) -> Result<Option<RawData>, StoreError<T>> {
+        let ino = self.resolve(ino);
+        if RootInodeEncoder::is_symbol(ino) {
+            let Some((kind, name)) = self.symbol_inodes.symbol(ino) else {
+                return Ok(None);
+            };
+            let changeset = self.resolve_symbol(kind, &name)?;
+            return Ok(changeset.map(|node| symlink_target(node).into()));
+        }
         if RootInodeEncoder::is_reserved(ino) {
             return Ok(RootInodeEncoder::data_for_reserved(ino));
         }
+        let overlay_data = self
+            .with_overlay(ino, |overlay, revision| overlay.read(revision, ino))
+            .flatten();
+        if let Some(result) = overlay_data {
+            let data = result.map_err(|e| {
+                ErrorKind::Other(format!("overlay read failed: {e}").into())
+            })?;
+            return Ok(Some(data));
+        }
         match self
             .with_revision(ino, |revision| revision.read(ino, &self.store))
         {
@@ -304,6 +585,28 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
         }
     }
 
+    /// Returns the changeset this symbol currently resolves to, if any
+    fn resolve_symbol(
+        &self,
+        kind: SymbolKind,
+        name: &[u8],
+    ) -> Result<Option<Node>, StoreError<T>> {
+        Ok(self.store.symbols(kind)?.get(name).copied())
+    }
+
+    /// Returns the entry for the symlink to `changeset` named after this
+    /// symbol
+    fn symbol_entry(
+        &self,
+        ino: INodeNo,
+        name: &[u8],
+        changeset: Node,
+    ) -> Entry {
+        let file_name = OsString::from_vec(escape_name(name));
+        let size = symlink_target(changeset).len() as u64;
+        Entry::file(file_name, ino, size, ManifestFlags::LINK)
+    }
+
     /// Enables callback-based access to the revision tree for this inode.
     fn with_revision<R>(
         &self,
@@ -317,6 +620,177 @@ impl<S: StoreBackend<T>, T: FileToken> Server<S, T> {
         let revision = self.get_revision(node).ok()?;
         Some(func(&revision))
     }
+
+    /// Enables callback-based access to the overlay and revision tree for
+    /// this inode, if this server is writable.
+    fn with_overlay<R>(
+        &self,
+        ino: INodeNo,
+        func: impl FnOnce(&RevisionOverlay, &OwnedRevision<T>) -> R,
+    ) -> Option<R> {
+        let overlay = self.overlay.as_ref()?;
+        let idx = RootInodeEncoder::ino_to_idx(ino)?;
+        let node = self.store.node_for_idx(idx).ok()?;
+        let revision = self.get_revision(node).ok()?;
+        let inode_range = RootInodeEncoder::revision_inode_range(idx);
+        let revision_overlay = overlay
+            .revision(node, inode_range)
+            .inspect_err(|e| tracing::warn!("failed to load overlay: {e}"))
+            .ok()?;
+        Some(func(&revision_overlay, &revision))
+    }
+
+    /// Apply a change to the overlay of the revision containing `ino`
+    fn modify<R>(
+        &self,
+        ino: INodeNo,
+        func: impl FnOnce(&RevisionOverlay, &OwnedRevision<T>) -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        self.with_overlay(ino, func).unwrap_or_else(|| {
+            Err(std::io::Error::from_raw_os_error(Errno::EROFS.code()))
+        })
+    }
+
+    /// Return at most `size` bytes at `offset` of the file at this inode, if
+    /// it was written to the overlay.
+    pub fn read_overlay(
+        &self,
+        ino: INodeNo,
+        offset: u64,
+        size: u32,
+    ) -> Option<std::io::Result<Vec<u8>>> {
+        let ino = self.resolve(ino);
+        self.with_overlay(ino, |overlay, revision| {
+            overlay.read_at(revision, ino, offset, size)
+        })
+        .flatten()
+    }
+
+    /// Create a regular file named `name` in `parent`
+    pub fn create(
+        &self,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+    ) -> std::io::Result<FileAttr> {
+        let parent = self.resolve(parent);
+        let entry = self.modify(parent, |overlay, revision| {
+            overlay.create(revision, parent, name, mode)
+        })?;
+        Ok(self.attributes_for_entry(entry))
+    }
+
+    /// Create a directory named `name` in `parent`
+    pub fn mkdir(
+        &self,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+    ) -> std::io::Result<FileAttr> {
+        let parent = self.resolve(parent);
+        let entry = self.modify(parent, |overlay, revision| {
+            overlay.mkdir(revision, parent, name, mode)
+        })?;
+        Ok(self.attributes_for_entry(entry))
+    }
+
+    /// Create a symlink to `target` named `name` in `parent`
+    pub fn symlink(
+        &self,
+        parent: INodeNo,
+        name: &OsStr,
+        target: &Path,
+    ) -> std::io::Result<FileAttr> {
+        let parent = self.resolve(parent);
+        let entry = self.modify(parent, |overlay, revision| {
+            overlay.symlink(revision, parent, name, target)
+        })?;
+        Ok(self.attributes_for_entry(entry))
+    }
+
+    /// Write `data` at `offset` in the file at this inode
+    pub fn write(
+        &self,
+        ino: INodeNo,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<u32> {
+        let ino = self.resolve(ino);
+        self.modify(ino, |overlay, revision| {
+            overlay.write(revision, &self.store, ino, offset, data)
+        })
+    }
+
+    /// Change the mode, size or times of the entry at this inode
+    pub fn setattr(
+        &self,
+        ino: INodeNo,
+        mode: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> std::io::Result<FileAttr> {
+        let ino = self.resolve(ino);
+        let entry = self.modify(ino, |overlay, revision| {
+            overlay.setattr(
+                revision,
+                &self.store,
+                ino,
+                mode,
+                size,
+                atime,
+                mtime,
+            )
+        })?;
+        Ok(self.attributes_for_entry(entry))
+    }
+
+    /// Remove the non-directory `name` from `parent`
+    pub fn unlink(&self, parent: INodeNo, name: &OsStr) -> std::io::Result<()> {
+        let parent = self.resolve(parent);
+        self.modify(parent, |overlay, revision| {
+            overlay.unlink(revision, parent, name)
+        })
+    }
+
+    /// Remove the empty directory `name` from `parent`
+    pub fn rmdir(&self, parent: INodeNo, name: &OsStr) -> std::io::Result<()> {
+        let parent = self.resolve(parent);
+        self.modify(parent, |overlay, revision| {
+            overlay.rmdir(revision, parent, name)
+        })
+    }
+
+    /// Move `name` from `parent` to `new_name` in `new_parent`, replacing any
+    /// existing entry there unless `no_replace` is set
+    pub fn rename(
+        &self,
+        parent: INodeNo,
+        name: &OsStr,
+        new_parent: INodeNo,
+        new_name: &OsStr,
+        no_replace: bool,
+    ) -> std::io::Result<()> {
+        let parent = self.resolve(parent);
+        let new_parent = self.resolve(new_parent);
+        if RootInodeEncoder::ino_to_idx(parent)
+            != RootInodeEncoder::ino_to_idx(new_parent)
+        {
+            // Each revision has its own overlay
+            return Err(std::io::Error::from_raw_os_error(Errno::EXDEV.code()));
+        }
+        self.modify(parent, |overlay, revision| {
+            overlay.rename(
+                revision,
+                &self.store,
+                parent,
+                name,
+                new_parent,
+                new_name,
+                no_replace,
+            )
+        })
+    }
 }
 
 fn permissions_for_file(flags: ManifestFlags) -> u16 {
@@ -0,0 +1,1430 @@
+//! A copy-on-write layer that makes revision trees writable.
+//!
+//! Every change made through the FUSE lands in a per-mount scratch directory
+//! instead of the store: each modified revision gets an upper tree at
+//! `<scratch>/<node>/upper`, which mirrors the root of the revision and whose
+//! entries take precedence over the [`OwnedRevision`]'s. Removing an entry
+//! that exists in the revision records a whiteout (persisted in
+//! `<scratch>/<node>/whiteouts`) that hides it, and everything below it for
+//! directories. Like `overlayfs` without `redirect_dir`, directories coming
+//! from the revision cannot be renamed: callers get `EXDEV` and fall back to
+//! copying.
+//!
+//! The synthesized dirstate records the server's start time as the mtime of
+//! every file and directory. Entries copied up from the revision keep that
+//! time, but any change gives the touched file and its directory a new one,
+//! so that `hg status` considers them possibly dirty and looks at them again.
+
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::fs::FileTimes;
+use std::fs::Metadata;
+use std::fs::OpenOptions;
+use std::fs::Permissions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::SystemTime;
+
+use dashmap::DashMap;
+use fuser::Errno;
+use fuser::FileType;
+use fuser::INodeNo;
+use fuser::TimeOrNow;
+use hg::FastHashMap;
+use hg::Node;
+use hg::utils::RawData;
+use hg::utils::hg_path::HgPathBuf;
+use hg::vfs::is_on_fuse_mount;
+
+use crate::fuse::Entry;
+use crate::fuse::FILES_INODE_NAME;
+use crate::server::revision::OwnedRevision;
+use crate::server::store::FileToken;
+use crate::server::store::StoreBackend;
+
+/// Name of the directory mirroring the root of a revision
+const UPPER_DIRECTORY: &str = "upper";
+/// Name of the file listing the paths removed from a revision
+const WHITEOUTS_FILE: &str = "whiteouts";
+/// Name of the reserved file of every working copy's `.hg` holding the path
+/// of its revision's overlay directory, empty if the FUSE is read-only
+pub const DOT_HG_OVERLAY_FILE: &str = "fuse-overlay";
+
+/// The writable layer of a FUSE, shared by all of its revisions
+pub(super) struct Overlay {
+    /// The per-mount scratch directory
+    root: PathBuf,
+    /// The mtime of every entry of the revisions
+    start_time: SystemTime,
+    /// Overlays for the revisions accessed since the server started
+    revisions: DashMap<Node, Arc<RevisionOverlay>>,
+}
+
+impl Overlay {
+    pub fn new(root: PathBuf, start_time: SystemTime) -> Self {
+        Self { root, start_time, revisions: DashMap::new() }
+    }
+
+    /// Return the directory holding everything about `changeset`'s overlay
+    pub fn revision_directory(&self, changeset: Node) -> PathBuf {
+        self.root.join(format!("{:x}", changeset))
+    }
+
+    /// Return the overlay for `changeset`, whose inodes are in `inode_range`
+    pub fn revision(
+        &self,
+        changeset: Node,
+        inode_range: Range<INodeNo>,
+    ) -> std::io::Result<Arc<RevisionOverlay>> {
+        if let Some(overlay) = self.revisions.get(&changeset) {
+            return Ok(Arc::clone(&overlay));
+        }
+        let overlay =
+            self.revisions.entry(changeset).or_try_insert_with(|| {
+                let directory = self.revision_directory(changeset);
+                RevisionOverlay::load(directory, inode_range, self.start_time)
+                    .map(Arc::new)
+            })?;
+        Ok(Arc::clone(&overlay))
+    }
+}
+
+/// The writable layer of a single revision.
+///
+/// Paths are relative to the root of the revision (the parent of its
+/// `files` directory), so that its `.hg` can be written to as well.
+pub(super) struct RevisionOverlay {
+    /// The directory holding everything about this revision's overlay
+    directory: PathBuf,
+    /// The root of the upper tree
+    upper: PathBuf,
+    /// The mtime of every entry of the revision
+    start_time: SystemTime,
+    state: Mutex<State>,
+}
+
+/// Bookkeeping of a [`RevisionOverlay`], protected by a single lock so that
+/// changes to the upper tree and to the mappings stay consistent
+struct State {
+    /// Paths of the revision that were removed
+    whiteouts: BTreeSet<PathBuf>,
+    /// Inodes of the entries of the upper tree that were looked at
+    inodes: FastHashMap<PathBuf, INodeNo>,
+    /// Reverse mapping of [`Self::inodes`]
+    paths: FastHashMap<INodeNo, PathBuf>,
+    /// The next inode for an entry that does not come from the revision.
+    /// These are handed out from the end of the revision's inode range, far
+    /// from the ones of its dirstate.
+    next_inode: u64,
+}
+
+impl State {
+    fn is_whited_out(&self, path: &Path) -> bool {
+        path.ancestors().any(|ancestor| self.whiteouts.contains(ancestor))
+    }
+
+    /// Whether `ino` was handed out by the overlay (even if it is now gone)
+    fn is_overlay_inode(&self, ino: INodeNo) -> bool {
+        ino.0 > self.next_inode
+    }
+
+    fn new_inode(&mut self) -> INodeNo {
+        let ino = INodeNo(self.next_inode);
+        self.next_inode -= 1;
+        ino
+    }
+
+    fn record(&mut self, path: &Path, ino: INodeNo) {
+        if let Some(previous) = self.inodes.insert(path.to_owned(), ino)
+            && previous != ino
+        {
+            self.paths.remove(&previous);
+        }
+        if let Some(previous) = self.paths.insert(ino, path.to_owned())
+            && previous != path
+        {
+            self.inodes.remove(&previous);
+        }
+    }
+
+    fn forget(&mut self, path: &Path) {
+        if let Some(ino) = self.inodes.remove(path) {
+            self.paths.remove(&ino);
+        }
+    }
+
+    /// Forget `path` and everything below it
+    fn forget_tree(&mut self, path: &Path) {
+        self.inodes.retain(|entry_path, _| !entry_path.starts_with(path));
+        self.paths.retain(|_, entry_path| !entry_path.starts_with(path));
+    }
+
+    /// Move the inodes of `from` and everything below it to `to`
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<_> = self
+            .inodes
+            .iter()
+            .filter_map(|(path, ino)| {
+                let suffix = path.strip_prefix(from).ok()?;
+                let new_path = if suffix.as_os_str().is_empty() {
+                    to.to_owned()
+                } else {
+                    to.join(suffix)
+                };
+                Some((path.to_owned(), new_path, *ino))
+            })
+            .collect();
+        for (path, _, _) in &moved {
+            self.inodes.remove(path);
+        }
+        for (_, new_path, ino) in moved {
+            self.record(&new_path, ino);
+        }
+    }
+}
+
+impl RevisionOverlay {
+    fn load(
+        directory: PathBuf,
+        inode_range: Range<INodeNo>,
+        start_time: SystemTime,
+    ) -> std::io::Result<Self> {
+        let whiteouts = read_whiteouts(&directory)?;
+        let state = State {
+            whiteouts,
+            inodes: FastHashMap::default(),
+            paths: FastHashMap::default(),
+            next_inode: inode_range.end.0 - 1,
+        };
+        Ok(Self {
+            upper: directory.join(UPPER_DIRECTORY),
+            directory,
+            start_time,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("propagate the panic")
+    }
+
+    /// Return the [`Entry`] that corresponds to `ino`
+    pub fn get_entry<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        ino: INodeNo,
+    ) -> Option<Entry> {
+        let mut state = self.lock();
+        self.entry_for_inode(lower, &mut state, ino)
+    }
+
+    /// Return the child of `parent` matching this `name`, if any
+    pub fn lookup<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        parent: INodeNo,
+        name: &OsStr,
+    ) -> Option<Entry> {
+        let mut state = self.lock();
+        let parent_path = self.path_for_inode(lower, &state, parent)?;
+        self.child_entry(lower, &mut state, parent, &parent_path, name)
+    }
+
+    /// Return entries for all direct children of this inode
+    pub fn entries<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        ino: INodeNo,
+    ) -> Option<Vec<Entry>> {
+        let mut state = self.lock();
+        self.entries_locked(lower, &mut state, ino)
+    }
+
+    /// Return the contents of the file (or the target of the symlink) at this
+    /// inode if it comes from the overlay
+    pub fn read<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        ino: INodeNo,
+    ) -> Option<std::io::Result<RawData>> {
+        let (path, metadata) = self.upper_entry(lower, ino)?;
+        let path = self.upper.join(path);
+        let data = if metadata.file_type().is_symlink() {
+            std::fs::read_link(path)
+                .map(|target| target.into_os_string().into_vec())
+        } else {
+            std::fs::read(path)
+        };
+        Some(data.map(RawData::from))
+    }
+
+    /// Return at most `size` bytes at `offset` of the file at this inode if
+    /// it comes from the overlay
+    pub fn read_at<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        ino: INodeNo,
+        offset: u64,
+        size: u32,
+    ) -> Option<std::io::Result<Vec<u8>>> {
+        let (path, _) = self.upper_entry(lower, ino)?;
+        let read = || {
+            let mut file = File::open(self.upper.join(path))?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut data = vec![];
+            file.take(size.into()).read_to_end(&mut data)?;
+            Ok(data)
+        };
+        Some(read())
+    }
+
+    /// Create a regular file named `name` in `parent`
+    pub fn create<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+    ) -> std::io::Result<Entry> {
+        self.add_child(lower, parent, name, |path| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(mode & 0o7777)
+                .open(path)
+                .map(drop)
+        })
+    }
+
+    /// Create a directory named `name` in `parent`
+    pub fn mkdir<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+    ) -> std::io::Result<Entry> {
+        self.add_child(lower, parent, name, |path| {
+            DirBuilder::new().mode(mode & 0o7777).create(path)
+        })
+    }
+
+    /// Create a symlink to `target` named `name` in `parent`
+    pub fn symlink<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        parent: INodeNo,
+        name: &OsStr,
+        target: &Path,
+    ) -> std::io::Result<Entry> {
+        self.add_child(lower, parent, name, |path| {
+            std::os::unix::fs::symlink(target, path)
+        })
+    }
+
+    /// Write `data` at `offset` in the file at `ino`, returning the number of
+    /// bytes written
+    pub fn write<S: StoreBackend<T>, T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        store: &S,
+        ino: INodeNo,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<u32> {
+        let path = {
+            let mut state = self.lock();
+            self.copy_up(lower, store, &mut state, ino)?
+        };
+        let file =
+            OpenOptions::new().write(true).open(self.upper.join(path))?;
+        file.write_all_at(data, offset)?;
+        Ok(data.len().try_into().expect("FUSE writes fit in a u32"))
+    }
+
+    /// Change the mode, size or times of the entry at `ino`
+    #[allow(clippy::too_many_arguments)]
+    pub fn setattr<S: StoreBackend<T>, T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        store: &S,
+        ino: INodeNo,
+        mode: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> std::io::Result<Entry> {
+        let mut state = self.lock();
+        let path =
+            self.upper.join(self.copy_up(lower, store, &mut state, ino)?);
+        // Symlinks have no mode of their own, and nothing but their own
+        // times is worth changing
+        if !path.symlink_metadata()?.file_type().is_symlink() {
+            if let Some(mode) = mode {
+                std::fs::set_permissions(
+                    &path,
+                    Permissions::from_mode(mode & 0o7777),
+                )?;
+            }
+            if let Some(size) = size {
+                OpenOptions::new().write(true).open(&path)?.set_len(size)?;
+            }
+            if atime.is_some() || mtime.is_some() {
+                let time = |time| match time {
+                    TimeOrNow::SpecificTime(time) => time,
+                    TimeOrNow::Now => SystemTime::now(),
+                };
+                let mut times = FileTimes::new();
+                if let Some(atime) = atime {
+                    times = times.set_accessed(time(atime));
+                }
+                if let Some(mtime) = mtime {
+                    times = times.set_modified(time(mtime));
+                }
+                File::open(&path)?.set_times(times)?;
+            }
+        }
+        self.entry_for_inode(lower, &mut state, ino).ok_or_else(not_found)
+    }
+
+    /// Remove the non-directory `name` from `parent`
+    pub fn unlink<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        parent: INodeNo,
+        name: &OsStr,
+    ) -> std::io::Result<()> {
+        let mut state = self.lock();
+        let parent_path = self.directory_path(lower, &mut state, parent)?;
+        let entry = self
+            .child_entry(lower, &mut state, parent, &parent_path, name)
+            .ok_or_else(not_found)?;
+        if entry.file_type() == FileType::Directory {
+            return Err(errno(Errno::EISDIR));
+        }
+        let path = parent_path.join(name);
+        if let Entry::Overlay { .. } = entry {
+            std::fs::remove_file(self.upper.join(&path))?;
+        }
+        state.forget(&path);
+        self.remove_from_lower(lower, &mut state, parent, &parent_path, name)
+    }
+
+    /// Remove the empty directory `name` from `parent`
+    pub fn rmdir<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        parent: INodeNo,
+        name: &OsStr,
+    ) -> std::io::Result<()> {
+        let mut state = self.lock();
+        let parent_path = self.directory_path(lower, &mut state, parent)?;
+        let entry = self
+            .child_entry(lower, &mut state, parent, &parent_path, name)
+            .ok_or_else(not_found)?;
+        if entry.file_type() != FileType::Directory {
+            return Err(errno(Errno::ENOTDIR));
+        }
+        let children = self.entries_locked(lower, &mut state, entry.ino());
+        if children.is_some_and(|children| !children.is_empty()) {
+            return Err(errno(Errno::ENOTEMPTY));
+        }
+        let path = parent_path.join(name);
+        if let Entry::Overlay { .. } = entry {
+            std::fs::remove_dir(self.upper.join(&path))?;
+        }
+        state.forget_tree(&path);
+        // The whiteout of the directory itself covers everything below it
+        state.whiteouts.retain(|whiteout| !whiteout.starts_with(&path));
+        self.remove_from_lower(lower, &mut state, parent, &parent_path, name)
+    }
+
+    /// Move `name` from `parent` to `new_name` in `new_parent`, replacing
+    /// any existing entry there unless `no_replace` is set
+    #[allow(clippy::too_many_arguments)]
+    pub fn rename<S: StoreBackend<T>, T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        store: &S,
+        parent: INodeNo,
+        name: &OsStr,
+        new_parent: INodeNo,
+        new_name: &OsStr,
+        no_replace: bool,
+    ) -> std::io::Result<()> {
+        let mut state = self.lock();
+        let parent_path = self.directory_path(lower, &mut state, parent)?;
+        let new_parent_path =
+            self.directory_path(lower, &mut state, new_parent)?;
+        let source = parent_path.join(name);
+        let target = new_parent_path.join(new_name);
+        let entry = self
+            .child_entry(lower, &mut state, parent, &parent_path, name)
+            .ok_or_else(not_found)?;
+        let is_dir = entry.file_type() == FileType::Directory;
+        if source == target {
+            return Ok(());
+        }
+        if is_dir && target.starts_with(&source) {
+            return Err(errno(Errno::EINVAL));
+        }
+        let source_in_lower =
+            self.lower_child(lower, &state, parent, &parent_path, name);
+        if is_dir && source_in_lower.is_some() {
+            return Err(errno(Errno::EXDEV));
+        }
+        let existing = self.child_entry(
+            lower,
+            &mut state,
+            new_parent,
+            &new_parent_path,
+            new_name,
+        );
+        if let Some(existing) = existing {
+            if no_replace {
+                return Err(errno(Errno::EEXIST));
+            }
+            match (is_dir, existing.file_type() == FileType::Directory) {
+                (false, true) => return Err(errno(Errno::EISDIR)),
+                (true, false) => return Err(errno(Errno::ENOTDIR)),
+                (true, true) => {
+                    let children =
+                        self.entries_locked(lower, &mut state, existing.ino());
+                    if children.is_some_and(|children| !children.is_empty()) {
+                        return Err(errno(Errno::ENOTEMPTY));
+                    }
+                }
+                (false, false) => (),
+            }
+            if let Entry::Overlay { metadata, .. } = existing {
+                if metadata.is_dir() {
+                    std::fs::remove_dir(self.upper.join(&target))?;
+                } else {
+                    std::fs::remove_file(self.upper.join(&target))?;
+                }
+            }
+            state.forget_tree(&target);
+        }
+        let target_in_lower = self.lower_child(
+            lower,
+            &state,
+            new_parent,
+            &new_parent_path,
+            new_name,
+        );
+        if !is_dir {
+            self.copy_up(lower, store, &mut state, entry.ino())?;
+        }
+        self.create_parents(&target)?;
+        std::fs::rename(self.upper.join(&source), self.upper.join(&target))?;
+        state.rename(&source, &target);
+
+        let mut new_whiteouts = vec![];
+        if source_in_lower.is_some() {
+            new_whiteouts.push(source);
+        }
+        if is_dir && target_in_lower.is_some() {
+            // Hide the revision's children of the directory we replaced
+            new_whiteouts.push(target);
+        }
+        if !new_whiteouts.is_empty() {
+            state.whiteouts.extend(new_whiteouts);
+            self.save_whiteouts(&state)?;
+        }
+        Ok(())
+    }
+
+    fn entry_for_inode<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &mut State,
+        ino: INodeNo,
+    ) -> Option<Entry> {
+        if let Some(path) = state.paths.get(&ino).cloned() {
+            let Some(metadata) = self.upper_metadata(&path) else {
+                state.forget(&path);
+                return None;
+            };
+            return Some(overlay_entry(&path, ino, metadata));
+        }
+        if state.is_overlay_inode(ino) {
+            return None;
+        }
+        let path = lower.path(ino)?;
+        if state.inodes.contains_key(&path) || state.is_whited_out(&path) {
+            // Replaced or removed
+            return None;
+        }
+        match self.upper_metadata(&path) {
+            Some(metadata) => {
+                state.record(&path, ino);
+                Some(overlay_entry(&path, ino, metadata))
+            }
+            None => lower.get_entry(ino),
+        }
+    }
+
+    /// Return the path of `ino` relative to the root of the revision
+    fn path_for_inode<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &State,
+        ino: INodeNo,
+    ) -> Option<PathBuf> {
+        if let Some(path) = state.paths.get(&ino) {
+            return Some(path.to_owned());
+        }
+        if state.is_overlay_inode(ino) {
+            return None;
+        }
+        lower.path(ino)
+    }
+
+    /// Return the path of `ino`, making sure it is a directory
+    fn directory_path<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &mut State,
+        ino: INodeNo,
+    ) -> std::io::Result<PathBuf> {
+        let entry =
+            self.entry_for_inode(lower, state, ino).ok_or_else(not_found)?;
+        if entry.file_type() != FileType::Directory {
+            return Err(errno(Errno::ENOTDIR));
+        }
+        self.path_for_inode(lower, state, ino).ok_or_else(not_found)
+    }
+
+    /// Return the child `name` of `parent` (at `parent_path`), from the upper
+    /// tree if it is there
+    fn child_entry<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &mut State,
+        parent: INodeNo,
+        parent_path: &Path,
+        name: &OsStr,
+    ) -> Option<Entry> {
+        let path = parent_path.join(name);
+        let Some(metadata) = self.upper_metadata(&path) else {
+            state.forget(&path);
+            return self.lower_child(lower, state, parent, parent_path, name);
+        };
+        let ino = self.child_inode(lower, state, parent, parent_path, name);
+        Some(overlay_entry(&path, ino, metadata))
+    }
+
+    /// Return the inode of the child `name` of `parent` from the upper tree,
+    /// which is that of the revision's entry it replaces if any
+    fn child_inode<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &mut State,
+        parent: INodeNo,
+        parent_path: &Path,
+        name: &OsStr,
+    ) -> INodeNo {
+        let path = parent_path.join(name);
+        if let Some(ino) = state.inodes.get(&path) {
+            return *ino;
+        }
+        let ino =
+            match self.lower_child(lower, state, parent, parent_path, name) {
+                Some(entry) => entry.ino(),
+                None => state.new_inode(),
+            };
+        state.record(&path, ino);
+        ino
+    }
+
+    /// Return the revision's child `name` of `parent`, unless it was removed
+    fn lower_child<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &State,
+        parent: INodeNo,
+        parent_path: &Path,
+        name: &OsStr,
+    ) -> Option<Entry> {
+        // Directories created in the overlay hide the revision's, if any
+        if state.is_overlay_inode(parent)
+            || state.is_whited_out(&parent_path.join(name))
+        {
+            return None;
+        }
+        lower.lookup(parent, name)
+    }
+
+    fn entries_locked<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &mut State,
+        ino: INodeNo,
+    ) -> Option<Vec<Entry>> {
+        let path = self.path_for_inode(lower, state, ino)?;
+        let mut found = false;
+        let mut entries = vec![];
+        let mut upper_names = HashSet::new();
+        match std::fs::read_dir(self.upper.join(&path)) {
+            Ok(read_dir) => {
+                found = true;
+                for dir_entry in read_dir {
+                    let Ok(dir_entry) = dir_entry else { continue };
+                    let Ok(metadata) = dir_entry.metadata() else {
+                        continue;
+                    };
+                    let name = dir_entry.file_name();
+                    let ino = self.child_inode(lower, state, ino, &path, &name);
+                    entries.push(Entry::Overlay {
+                        name: name.clone(),
+                        ino,
+                        metadata,
+                    });
+                    upper_names.insert(name);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotADirectory => {
+                return None;
+            }
+            Err(_) => (),
+        }
+        if !state.is_overlay_inode(ino)
+            && !state.is_whited_out(&path)
+            && let Some(children) = lower.entries(ino)
+        {
+            found = true;
+            entries.extend(children.into_iter().filter(|child| {
+                !upper_names.contains(child.name())
+                    && !state.is_whited_out(&path.join(child.name()))
+            }));
+        }
+        found.then_some(entries)
+    }
+
+    /// Return the path and metadata of `ino` if it is in the upper tree
+    fn upper_entry<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        ino: INodeNo,
+    ) -> Option<(PathBuf, Metadata)> {
+        let mut state = self.lock();
+        match self.entry_for_inode(lower, &mut state, ino)? {
+            Entry::Overlay { metadata, .. } => {
+                Some((state.paths.get(&ino)?.to_owned(), metadata))
+            }
+            _ => None,
+        }
+    }
+
+    fn upper_metadata(&self, path: &Path) -> Option<Metadata> {
+        self.upper.join(path).symlink_metadata().ok()
+    }
+
+    /// Create the child `name` of `parent` in the upper tree with `create`
+    fn add_child<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        parent: INodeNo,
+        name: &OsStr,
+        create: impl FnOnce(&Path) -> std::io::Result<()>,
+    ) -> std::io::Result<Entry> {
+        let mut state = self.lock();
+        let parent_path = self.directory_path(lower, &mut state, parent)?;
+        if self
+            .child_entry(lower, &mut state, parent, &parent_path, name)
+            .is_some()
+        {
+            return Err(errno(Errno::EEXIST));
+        }
+        let path = parent_path.join(name);
+        self.create_parents(&path)?;
+        create(&self.upper.join(&path))?;
+        self.child_entry(lower, &mut state, parent, &parent_path, name)
+            .ok_or_else(not_found)
+    }
+
+    /// Make sure the entry at `ino` is in the upper tree, copying it from the
+    /// revision if needed, and return its path
+    fn copy_up<S: StoreBackend<T>, T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        store: &S,
+        state: &mut State,
+        ino: INodeNo,
+    ) -> std::io::Result<PathBuf> {
+        let entry =
+            self.entry_for_inode(lower, state, ino).ok_or_else(not_found)?;
+        let path =
+            self.path_for_inode(lower, state, ino).ok_or_else(not_found)?;
+        let flags = match entry {
+            Entry::Overlay { .. } => return Ok(path),
+            Entry::Dir { .. } => None,
+            Entry::File { flags, .. } => Some(flags),
+        };
+        self.create_parents(&path)?;
+        let upper = self.upper.join(&path);
+        match flags {
+            // The root of the revision is created along with the upper tree
+            None => match self.create_directory(&upper) {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+                result => result?,
+            },
+            Some(flags) => {
+                let data = lower
+                    .read(ino, store)
+                    .map_err(|e| std::io::Error::other(format!("{:?}", e)))?
+                    .ok_or_else(not_found)?;
+                if flags.is_link() {
+                    let target = OsStr::from_bytes(&data);
+                    std::os::unix::fs::symlink(target, &upper)?;
+                } else {
+                    let mode = if flags.is_exec() { 0o700 } else { 0o600 };
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .mode(mode)
+                        .open(&upper)?;
+                    file.write_all(&data)?;
+                    file.set_modified(self.start_time)?;
+                }
+            }
+        }
+        state.record(&path, ino);
+        Ok(path)
+    }
+
+    /// Create the missing ancestors of `path` in the upper tree
+    fn create_parents(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.upper)?;
+        let mut directory = self.upper.clone();
+        for component in path.parent().into_iter().flatten() {
+            directory.push(component);
+            match self.create_directory(&directory) {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a directory in the upper tree that looks like the revision's
+    fn create_directory(&self, path: &Path) -> std::io::Result<()> {
+        DirBuilder::new().mode(0o700).create(path)?;
+        File::open(path)?.set_modified(self.start_time)
+    }
+
+    /// Hide the revision's child `name` of `parent` if there is one, now that
+    /// the overlay's is gone
+    fn remove_from_lower<T: FileToken>(
+        &self,
+        lower: &OwnedRevision<T>,
+        state: &mut State,
+        parent: INodeNo,
+        parent_path: &Path,
+        name: &OsStr,
+    ) -> std::io::Result<()> {
+        if self.lower_child(lower, state, parent, parent_path, name).is_none() {
+            return Ok(());
+        }
+        let path = parent_path.join(name);
+        state.whiteouts.insert(path.clone());
+        self.save_whiteouts(state)?;
+        // Nothing changed in the upper directory, update it by hand so the
+        // removal is noticed
+        self.create_parents(&path)?;
+        File::open(self.upper.join(parent_path))?
+            .set_modified(SystemTime::now())
+    }
+
+    fn save_whiteouts(&self, state: &State) -> std::io::Result<()> {
+        let mut data = vec![];
+        for path in &state.whiteouts {
+            data.extend_from_slice(path.as_os_str().as_bytes());
+            data.push(b'\0');
+        }
+        std::fs::create_dir_all(&self.directory)?;
+        let temporary = self.directory.join(format!("{WHITEOUTS_FILE}.tmp"));
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(temporary, self.directory.join(WHITEOUTS_FILE))
+    }
+}
+
+/// Return the paths recorded as removed in the overlay at `directory`
+fn read_whiteouts(directory: &Path) -> std::io::Result<BTreeSet<PathBuf>> {
+    match std::fs::read(directory.join(WHITEOUTS_FILE)) {
+        Ok(data) => Ok(data
+            .split(|byte| *byte == b'\0')
+            .filter(|path| !path.is_empty())
+            .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(BTreeSet::new())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// What may differ between a working copy of the FUSE and the revision it
+/// was synthesized from
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WorkingCopyChanges {
+    /// Files created or changed in the working copy
+    pub changed: Vec<HgPathBuf>,
+    /// Paths of the revision that were removed, along with everything below
+    /// them
+    pub removed: Vec<HgPathBuf>,
+    /// Whether the dirstate is not the synthesized one anymore, whose
+    /// entries are all clean
+    pub dirstate_changed: bool,
+}
+
+impl WorkingCopyChanges {
+    /// Record a change to `path`, relative to the root of the revision
+    fn record(&mut self, path: &Path, removed: bool) {
+        let Ok(path) = path.strip_prefix(FILES_INODE_NAME) else {
+            return;
+        };
+        if let Ok(path_in_dot_hg) = path.strip_prefix(".hg") {
+            // `status` never reports anything in `.hg`, only the dirstate
+            // (whose docket is rewritten on every change) matters
+            if path_in_dot_hg.as_os_str().is_empty()
+                || path_in_dot_hg == Path::new("dirstate")
+            {
+                self.dirstate_changed = true;
+            }
+            return;
+        }
+        if path.as_os_str().is_empty() {
+            return;
+        }
+        let path = HgPathBuf::from_bytes(path.as_os_str().as_bytes());
+        if removed {
+            self.removed.push(path);
+        } else {
+            self.changed.push(path);
+        }
+    }
+}
+
+/// Return what may have changed in the working copy whose `.hg` directory
+/// is `dot_hg`, or `None` if it is not served by a FUSE.
+///
+/// Everything else is exactly as in the revision, so `status` does not need
+/// to look at it. Besides checking that `dot_hg` is still mounted, this only
+/// reads the overlay on disk.
+pub fn working_copy_changes(
+    dot_hg: &Path,
+) -> std::io::Result<Option<WorkingCopyChanges>> {
+    let directory = match std::fs::read(dot_hg.join(DOT_HG_OVERLAY_FILE)) {
+        Ok(data) => PathBuf::from(OsString::from_vec(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // The file outlives the FUSE in copies of the working copy, or in
+    // whatever was mounted over it: the overlay is only kept in sync with
+    // the files while served
+    if !is_on_fuse_mount(dot_hg) {
+        return Ok(None);
+    }
+    overlay_changes(&directory).map(Some)
+}
+
+/// Return what changed in the overlay at `directory`, which is empty if the
+/// FUSE is read-only
+fn overlay_changes(directory: &Path) -> std::io::Result<WorkingCopyChanges> {
+    let mut changes = WorkingCopyChanges::default();
+    if directory.as_os_str().is_empty() {
+        return Ok(changes);
+    }
+    let upper = directory.join(UPPER_DIRECTORY);
+    let mut directories = vec![PathBuf::from(FILES_INODE_NAME)];
+    while let Some(directory) = directories.pop() {
+        let read_dir = match std::fs::read_dir(upper.join(&directory)) {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in read_dir {
+            let entry = entry?;
+            let path = directory.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                directories.push(path);
+            } else {
+                changes.record(&path, false);
+            }
+        }
+    }
+    for path in read_whiteouts(directory)? {
+        changes.record(&path, true);
+    }
+    Ok(changes)
+}
+
+fn overlay_entry(path: &Path, ino: INodeNo, metadata: Metadata) -> Entry {
+    let name = path.file_name().map(OsString::from).unwrap_or_default();
+    Entry::Overlay { name, ino, metadata }
+}
+
+fn errno(errno: Errno) -> std::io::Error {
+    std::io::Error::from_raw_os_error(errno.code())
+}
+
+fn not_found() -> std::io::Error {
+    errno(Errno::ENOENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use hg::NULL_NODE;
+    use hg::revlog::manifest::ManifestFlags;
+    use hg::utils::hg_path::HgPath;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::fuse::RootInodeEncoder;
+    use crate::server::Config;
+    use crate::server::local::LocalToken;
+    use crate::server::store::BackendMode;
+    use crate::server::store::ChangesetFiles;
+    use crate::server::store::ChangesetFilesDiff;
+    use crate::server::store::Error;
+    use crate::server::store::FileChangeInfo;
+    use crate::server::store::FileInfo;
+    use crate::server::store::RevisionIdx;
+
+    /// A store whose only changeset has `a`, `dir/b` and `dir/c`, each
+    /// containing its own path
+    struct FakeStore {
+        config: Config,
+    }
+
+    struct FakeFiles(Vec<HgPathBuf>);
+
+    impl ChangesetFiles<LocalToken> for FakeFiles {
+        fn iter(&self) -> impl Iterator<Item = FileInfo<'_, LocalToken>> {
+            self.0.iter().map(|path| FileInfo {
+                path,
+                size: path.len() as u64,
+                flags: ManifestFlags::EMPTY,
+                token: LocalToken(NULL_NODE),
+            })
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    impl ChangesetFilesDiff<LocalToken> for FakeFiles {
+        fn iter_diff(
+            &self,
+        ) -> impl Iterator<Item = FileChangeInfo<'_, LocalToken>> {
+            std::iter::empty()
+        }
+    }
+
+    fn changeset() -> Node {
+        Node::from(&[1; 20])
+    }
+
+    impl StoreBackend<LocalToken> for FakeStore {
+        fn server_config(&self) -> &Config {
+            &self.config
+        }
+
+        fn branch(
+            &self,
+            _changeset: Node,
+        ) -> Result<String, Error<LocalToken>> {
+            Ok("default".to_string())
+        }
+
+        fn idx_for_node(
+            &self,
+            _changeset: Node,
+        ) -> Result<RevisionIdx, Error<LocalToken>> {
+            Ok(RevisionIdx(0))
+        }
+
+        fn node_for_idx(
+            &self,
+            _idx: RevisionIdx,
+        ) -> Result<Node, Error<LocalToken>> {
+            Ok(changeset())
+        }
+
+        fn changeset_files(
+            &self,
+            _changeset: Node,
+        ) -> Result<impl ChangesetFiles<LocalToken>, Error<LocalToken>>
+        {
+            let paths = [&b"a"[..], b"dir/b", b"dir/c"];
+            Ok(FakeFiles(paths.map(HgPathBuf::from_bytes).to_vec()))
+        }
+
+        fn changeset_files_diff(
+            &self,
+            _from: Node,
+            _to: Node,
+        ) -> Result<impl ChangesetFilesDiff<LocalToken>, Error<LocalToken>>
+        {
+            Ok(FakeFiles(vec![]))
+        }
+
+        fn file_data(
+            &self,
+            _changeset: Node,
+            path: &HgPath,
+            _token: LocalToken,
+        ) -> Result<RawData, Error<LocalToken>> {
+            Ok(path.as_bytes().to_vec().into())
+        }
+    }
+
+    /// The revision of [`FakeStore`] with an empty overlay
+    struct Fixture {
+        store: FakeStore,
+        lower: OwnedRevision<LocalToken>,
+        overlay: Arc<RevisionOverlay>,
+        /// The inode of the `files` directory
+        files: INodeNo,
+        /// The inode of `files/dir`
+        dir: INodeNo,
+        scratch: TempDir,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let scratch = tempfile::tempdir().unwrap();
+            let store = FakeStore {
+                config: Config {
+                    preload_structure: false,
+                    backend_mode: BackendMode::Archive,
+                    blob_cache: None,
+                },
+            };
+            let start_time = SystemTime::now();
+            let (lower, _) = OwnedRevision::from_revision(
+                &store,
+                changeset(),
+                start_time,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let overlay = Overlay::new(scratch.path().to_owned(), start_time);
+            let overlay = Self::load(&overlay);
+            let root = RootInodeEncoder::revision_inode(RevisionIdx(0));
+            let files = lower.lookup(root, FILES_INODE_NAME.as_ref()).unwrap();
+            let dir = lower.lookup(files.ino(), "dir".as_ref()).unwrap();
+            let (files, dir) = (files.ino(), dir.ino());
+            Self { store, lower, overlay, files, dir, scratch }
+        }
+
+        fn load(overlay: &Overlay) -> Arc<RevisionOverlay> {
+            let range = RootInodeEncoder::revision_inode_range(RevisionIdx(0));
+            overlay.revision(changeset(), range).unwrap()
+        }
+
+        /// The overlay as loaded again from disk by a new server
+        fn reload(&self) -> Arc<RevisionOverlay> {
+            let overlay =
+                Overlay::new(self.scratch.path().to_owned(), SystemTime::now());
+            Self::load(&overlay)
+        }
+
+        fn lookup(&self, parent: INodeNo, name: &str) -> Option<Entry> {
+            self.overlay.lookup(&self.lower, parent, name.as_ref())
+        }
+
+        /// The sorted names of the children of `ino`, with whether they come
+        /// from the overlay
+        fn entries(&self, ino: INodeNo) -> Vec<(OsString, bool)> {
+            let entries = self.overlay.entries(&self.lower, ino).unwrap();
+            let mut entries: Vec<_> = entries
+                .into_iter()
+                .map(|entry| {
+                    let overlay = matches!(entry, Entry::Overlay { .. });
+                    (entry.name().to_owned(), overlay)
+                })
+                .collect();
+            entries.sort();
+            entries
+        }
+
+        fn read(&self, ino: INodeNo) -> Vec<u8> {
+            let data = self.overlay.read(&self.lower, ino).unwrap().unwrap();
+            data.to_vec()
+        }
+
+        fn write(&self, ino: INodeNo, data: &[u8]) {
+            let overlay = &self.overlay;
+            overlay.write(&self.lower, &self.store, ino, 0, data).unwrap();
+        }
+    }
+
+    fn names(names: &[(&str, bool)]) -> Vec<(OsString, bool)> {
+        names.iter().map(|(name, overlay)| (name.into(), *overlay)).collect()
+    }
+
+    #[test]
+    fn test_whited_out_file() {
+        let fixture = Fixture::new();
+        let a = fixture.lookup(fixture.files, "a").unwrap().ino();
+        let overlay = &fixture.overlay;
+        let lower = &fixture.lower;
+        overlay.unlink(lower, fixture.files, "a".as_ref()).unwrap();
+
+        for overlay in [overlay, &fixture.reload()] {
+            assert!(overlay.get_entry(lower, a).is_none());
+            assert!(
+                overlay.lookup(lower, fixture.files, "a".as_ref()).is_none()
+            );
+            assert!(overlay.read(lower, a).is_none());
+        }
+        assert_eq!(fixture.entries(fixture.files), names(&[("dir", false)]));
+        // The revision itself is untouched
+        assert!(lower.get_entry(a).is_some());
+
+        // Creating it again does not bring back the revision's
+        let entry = overlay.create(lower, fixture.files, "a".as_ref(), 0o644);
+        let entry = entry.unwrap();
+        assert!(matches!(entry, Entry::Overlay { .. }));
+        assert_eq!(fixture.read(entry.ino()), b"");
+    }
+
+    #[test]
+    fn test_rename_over_revision_file() {
+        let fixture = Fixture::new();
+        let overlay = &fixture.overlay;
+        let lower = &fixture.lower;
+        let (files, dir) = (fixture.files, fixture.dir);
+        let new = overlay.create(lower, files, "new".as_ref(), 0o644).unwrap();
+        fixture.write(new.ino(), b"new data");
+        let b = fixture.lookup(dir, "b").unwrap().ino();
+
+        overlay
+            .rename(
+                lower,
+                &fixture.store,
+                files,
+                "new".as_ref(),
+                dir,
+                "b".as_ref(),
+                false,
+            )
+            .unwrap();
+        assert!(fixture.lookup(files, "new").is_none());
+        let entry = fixture.lookup(dir, "b").unwrap();
+        assert!(matches!(entry, Entry::Overlay { .. }));
+        assert_eq!(entry.ino(), new.ino());
+        assert_eq!(fixture.read(entry.ino()), b"new data");
+        // The inode of the replaced file is gone
+        assert!(overlay.get_entry(lower, b).is_none());
+        assert_eq!(fixture.entries(dir), names(&[("b", true), ("c", false)]));
+
+        // Moving a file of the revision copies it up and hides the original
+        overlay
+            .rename(
+                lower,
+                &fixture.store,
+                files,
+                "a".as_ref(),
+                dir,
+                "c".as_ref(),
+                false,
+            )
+            .unwrap();
+        let entry = fixture.lookup(dir, "c").unwrap();
+        assert_eq!(fixture.read(entry.ino()), b"a");
+        // `dir` now exists in the upper tree as well
+        assert_eq!(fixture.entries(files), names(&[("dir", true)]));
+        assert!(fixture.reload().lookup(lower, files, "a".as_ref()).is_none());
+        assert_eq!(
+            overlay
+                .rename(
+                    lower,
+                    &fixture.store,
+                    dir,
+                    "b".as_ref(),
+                    dir,
+                    "c".as_ref(),
+                    true,
+                )
+                .unwrap_err()
+                .raw_os_error(),
+            Some(Errno::EEXIST.code())
+        );
+    }
+
+    #[test]
+    fn test_readdir_merges_upper_and_lower() {
+        let fixture = Fixture::new();
+        let overlay = &fixture.overlay;
+        let lower = &fixture.lower;
+        let dir = fixture.dir;
+        let b = fixture.lookup(dir, "b").unwrap().ino();
+        fixture.write(b, b"changed");
+        overlay.create(lower, dir, "d".as_ref(), 0o644).unwrap();
+        overlay.mkdir(lower, dir, "e".as_ref(), 0o755).unwrap();
+
+        assert_eq!(
+            fixture.entries(dir),
+            names(&[("b", true), ("c", false), ("d", true), ("e", true)])
+        );
+        // Copied up entries keep the inode of the revision's
+        let entry = fixture.lookup(dir, "b").unwrap();
+        assert!(matches!(entry, Entry::Overlay { .. }));
+        assert_eq!(entry.ino(), b);
+        assert_eq!(fixture.read(b), b"changed");
+        assert!(overlay.entries(lower, b).is_none());
+        let e = fixture.lookup(dir, "e").unwrap().ino();
+        assert_eq!(fixture.entries(e), vec![]);
+    }
+
+    fn state() -> State {
+        State {
+            whiteouts: BTreeSet::new(),
+            inodes: FastHashMap::default(),
+            paths: FastHashMap::default(),
+            next_inode: 100,
+        }
+    }
+
+    #[test]
+    fn test_whiteouts_hide_descendants() {
+        let mut state = state();
+        state.whiteouts.insert("files/dir".into());
+        assert!(state.is_whited_out(Path::new("files/dir")));
+        assert!(state.is_whited_out(Path::new("files/dir/sub/file")));
+        assert!(!state.is_whited_out(Path::new("files/dir2")));
+        assert!(!state.is_whited_out(Path::new("files")));
+    }
+
+    #[test]
+    fn test_new_inodes_count_down() {
+        let mut state = state();
+        assert!(!state.is_overlay_inode(INodeNo(100)));
+        assert_eq!(state.new_inode(), INodeNo(100));
+        assert_eq!(state.new_inode(), INodeNo(99));
+        assert!(state.is_overlay_inode(INodeNo(100)));
+        assert!(!state.is_overlay_inode(INodeNo(98)));
+    }
+
+    #[test]
+    fn test_rename_moves_descendants() {
+        let mut state = state();
+        state.record(Path::new("files/dir"), INodeNo(1));
+        state.record(Path::new("files/dir/file"), INodeNo(2));
+        state.record(Path::new("files/dir2"), INodeNo(3));
+        state.rename(Path::new("files/dir"), Path::new("files/new"));
+        assert_eq!(state.inodes.get(Path::new("files/new")), Some(&INodeNo(1)));
+        assert_eq!(
+            state.inodes.get(Path::new("files/new/file")),
+            Some(&INodeNo(2))
+        );
+        assert_eq!(state.paths[&INodeNo(2)], Path::new("files/new/file"));
+        assert_eq!(state.paths[&INodeNo(3)], Path::new("files/dir2"));
+        assert!(!state.inodes.contains_key(Path::new("files/dir")));
+
+        state.forget_tree(Path::new("files/new"));
+        assert_eq!(state.inodes.len(), 1);
+        assert_eq!(state.paths.len(), 1);
+    }
+
+    #[test]
+    fn test_record_replaces_previous_inode() {
+        let mut state = state();
+        state.record(Path::new("files/a"), INodeNo(1));
+        state.record(Path::new("files/a"), INodeNo(2));
+        assert!(!state.paths.contains_key(&INodeNo(1)));
+        state.record(Path::new("files/b"), INodeNo(2));
+        assert!(!state.inodes.contains_key(Path::new("files/a")));
+        assert_eq!(state.paths[&INodeNo(2)], Path::new("files/b"));
+    }
+
+    #[test]
+    fn test_overlay_changes() {
+        let temp = tempfile::tempdir().unwrap();
+        // Read-only
+        assert_eq!(
+            overlay_changes(Path::new("")).unwrap(),
+            WorkingCopyChanges::default()
+        );
+
+        let directory = temp.path().join("overlay");
+        let upper = directory.join(UPPER_DIRECTORY);
+        for path in ["files/a", "files/dir/b", "files/.hg/cache/tags"] {
+            let path = upper.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        std::fs::create_dir_all(upper.join("files/empty")).unwrap();
+        std::fs::write(directory.join(WHITEOUTS_FILE), b"files/c\0files/d\0")
+            .unwrap();
+        let mut changes = overlay_changes(&directory).unwrap();
+        changes.changed.sort();
+        assert_eq!(
+            changes,
+            WorkingCopyChanges {
+                changed: vec![
+                    HgPathBuf::from_bytes(b"a"),
+                    HgPathBuf::from_bytes(b"dir/b"),
+                ],
+                removed: vec![
+                    HgPathBuf::from_bytes(b"c"),
+                    HgPathBuf::from_bytes(b"d"),
+                ],
+                dirstate_changed: false,
+            }
+        );
+
+        std::fs::write(upper.join("files/.hg/dirstate"), b"").unwrap();
+        let changes = overlay_changes(&directory).unwrap();
+        assert!(changes.dirstate_changed);
+    }
+
+    #[test]
+    fn test_working_copy_changes() {
+        let temp = tempfile::tempdir().unwrap();
+        let dot_hg = temp.path().join("dot_hg");
+        std::fs::create_dir(&dot_hg).unwrap();
+        assert_eq!(working_copy_changes(&dot_hg).unwrap(), None);
+
+        // Left behind by a FUSE that is not serving `dot_hg` anymore, so
+        // whatever its overlay says cannot be trusted
+        let directory = temp.path().join("overlay");
+        std::fs::create_dir_all(directory.join(UPPER_DIRECTORY)).unwrap();
+        std::fs::write(
+            dot_hg.join(DOT_HG_OVERLAY_FILE),
+            directory.as_os_str().as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(working_copy_changes(&dot_hg).unwrap(), None);
+        std::fs::write(dot_hg.join(DOT_HG_OVERLAY_FILE), b"").unwrap();
+        assert_eq!(working_copy_changes(&dot_hg).unwrap(), None);
+    }
+}
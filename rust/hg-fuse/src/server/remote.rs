@@ -0,0 +1,999 @@
+//! A [`StoreBackend`] for stores served over gRPC, along with the service
+//! serving them.
+//!
+//! This allows machines without a copy of the repository to mount virtual
+//! shares: a central process serves its store with a [`StoreService`], and
+//! each virtual filesystem talks to it through a [`RemoteBackend`].
+//!
+//! File contents never change for a given token, so the client keeps the
+//! ones it fetched in memory, up to a size limit.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+use dashmap::DashMap;
+use hg::Node;
+use hg::config::Config as HgConfig;
+use hg::errors::HgError;
+use hg::revlog::manifest::ManifestFlags;
+use hg::utils::RawData;
+use hg::utils::hg_path::HgPath;
+use hg::utils::hg_path::HgPathBuf;
+use hyper_util::rt::TokioIo;
+use quick_cache::Weighter;
+use quick_cache::sync::Cache;
+use tokio::net::UnixStream;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Code;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
+use tonic::transport::Channel;
+use tonic::transport::Endpoint;
+use tonic::transport::Uri;
+use tower::service_fn;
+use vfs_api::store;
+use vfs_api::store::BranchRequest;
+use vfs_api::store::BranchResponse;
+use vfs_api::store::ChangesetFilesDiffRequest;
+use vfs_api::store::ChangesetFilesRequest;
+use vfs_api::store::FileChange;
+use vfs_api::store::FileChangeBatch;
+use vfs_api::store::FileDataRequest;
+use vfs_api::store::FileDataResponse;
+use vfs_api::store::FileInfoBatch;
+use vfs_api::store::Symbol;
+use vfs_api::store::SymbolsRequest;
+use vfs_api::store::SymbolsResponse;
+use vfs_api::store::file_change::Kind;
+use vfs_api::store::store_client::StoreClient;
+use vfs_api::store::store_server::Store;
+use vfs_api::store::store_server::StoreServer;
+use vfs_api::store::symbols_request;
+
+use crate::server::Config;
+use crate::server::blob_cache::BlobCacheConfig;
+use crate::server::local::LocalToken;
+use crate::server::store::BackendMode;
+use crate::server::store::ChangesetFiles;
+use crate::server::store::ChangesetFilesDiff;
+use crate::server::store::Error;
+use crate::server::store::ErrorKind;
+use crate::server::store::FileChangeInfo;
+use crate::server::store::FileInfo;
+use crate::server::store::FileToken;
+use crate::server::store::RevisionIdx;
+use crate::server::store::StoreBackend;
+use crate::server::store::SymbolKind;
+
+/// How many files are sent per message when listing a changeset
+const BATCH_SIZE: usize = 10_000;
+/// How many batches may wait for the client before the server stops listing
+const STREAM_BUFFER: usize = 4;
+/// How much file content the client keeps in memory by default
+const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Serves a local store to [`RemoteBackend`]s
+pub struct StoreService<S> {
+    store: Arc<S>,
+}
+
+impl<S: StoreBackend<LocalToken>> StoreService<S> {
+    pub fn new(store: S) -> Self {
+        Self { store: Arc::new(store) }
+    }
+
+    /// Returns the gRPC service to add to a server
+    pub fn into_server(self) -> StoreServer<Self> {
+        StoreServer::new(self)
+    }
+
+    /// Runs `func` on the store, off the async runtime since the store
+    /// blocks
+    async fn with_store<R: Send + 'static>(
+        &self,
+        func: impl FnOnce(&S) -> Result<R, Error<LocalToken>> + Send + 'static,
+    ) -> Result<R, Status> {
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || func(&store))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(error_to_status)
+    }
+
+    /// Streams the batches of items computed by `func` on the store, off the
+    /// async runtime since the store blocks
+    fn stream_from_store<B: Send + 'static>(
+        &self,
+        func: impl FnOnce(
+            &S,
+            &mpsc::Sender<Result<B, Status>>,
+        ) -> Result<(), Error<LocalToken>>
+        + Send
+        + 'static,
+    ) -> ReceiverStream<Result<B, Status>> {
+        let store = Arc::clone(&self.store);
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = func(&store, &sender) {
+                // The client may be gone already, nothing to do then
+                let _ = sender.blocking_send(Err(error_to_status(e)));
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+}
+
+#[tonic::async_trait]
+impl<S: StoreBackend<LocalToken>> Store for StoreService<S> {
+    async fn branch(
+        &self,
+        request: Request<BranchRequest>,
+    ) -> Result<Response<BranchResponse>, Status> {
+        let changeset =
+            Node::try_from(request.into_inner().changeset.as_slice())
+                .map_err(|_| invalid_node())?;
+        let branch =
+            self.with_store(move |store| store.branch(changeset)).await?;
+        Ok(Response::new(BranchResponse { branch }))
+    }
+
+    type ChangesetFilesStream = ReceiverStream<Result<FileInfoBatch, Status>>;
+
+    async fn changeset_files(
+        &self,
+        request: Request<ChangesetFilesRequest>,
+    ) -> Result<Response<Self::ChangesetFilesStream>, Status> {
+        let changeset =
+            Node::try_from(request.into_inner().changeset.as_slice())
+                .map_err(|_| invalid_node())?;
+        let stream = self.stream_from_store(move |store, sender| {
+            let files = store.changeset_files(changeset)?;
+            let files = files.iter().map(file_info_to_proto);
+            send_in_batches(sender, files, |files| FileInfoBatch { files });
+            Ok(())
+        });
+        Ok(Response::new(stream))
+    }
+
+    type ChangesetFilesDiffStream =
+        ReceiverStream<Result<FileChangeBatch, Status>>;
+
+    async fn changeset_files_diff(
+        &self,
+        request: Request<ChangesetFilesDiffRequest>,
+    ) -> Result<Response<Self::ChangesetFilesDiffStream>, Status> {
+        let request = request.into_inner();
+        let from = Node::try_from(request.from.as_slice())
+            .map_err(|_| invalid_node())?;
+        let to = Node::try_from(request.to.as_slice())
+            .map_err(|_| invalid_node())?;
+        let stream = self.stream_from_store(move |store, sender| {
+            let diff = store.changeset_files_diff(from, to)?;
+            let changes = diff.iter_diff().map(file_change_to_proto);
+            send_in_batches(sender, changes, |changes| FileChangeBatch {
+                changes,
+            });
+            Ok(())
+        });
+        Ok(Response::new(stream))
+    }
+
+    async fn file_data(
+        &self,
+        request: Request<FileDataRequest>,
+    ) -> Result<Response<FileDataResponse>, Status> {
+        let request = request.into_inner();
+        let changeset = Node::try_from(request.changeset.as_slice())
+            .map_err(|_| invalid_node())?;
+        let path = HgPathBuf::from_bytes(&request.path);
+        let token = LocalToken(
+            Node::try_from(request.token.as_slice())
+                .map_err(|_| invalid_node())?,
+        );
+        let data = self
+            .with_store(move |store| store.file_data(changeset, &path, token))
+            .await?;
+        Ok(Response::new(FileDataResponse { data: data.into() }))
+    }
+
+    async fn symbols(
+        &self,
+        request: Request<SymbolsRequest>,
+    ) -> Result<Response<SymbolsResponse>, Status> {
+        let kind = match request.into_inner().kind() {
+            symbols_request::Kind::Branch => SymbolKind::Branch,
+            symbols_request::Kind::Bookmark => SymbolKind::Bookmark,
+            symbols_request::Kind::Tag => SymbolKind::Tag,
+        };
+        let symbols = self.with_store(move |store| store.symbols(kind)).await?;
+        let symbols = symbols
+            .iter()
+            .map(|(name, changeset)| Symbol {
+                name: name.clone(),
+                changeset: changeset.as_bytes().to_vec(),
+            })
+            .collect();
+        Ok(Response::new(SymbolsResponse { symbols }))
+    }
+}
+
+/// Sends `items` in batches of [`BATCH_SIZE`], stopping early if the client
+/// went away
+fn send_in_batches<I, B>(
+    sender: &mpsc::Sender<Result<B, Status>>,
+    items: impl Iterator<Item = I>,
+    batch: impl Fn(Vec<I>) -> B,
+) {
+    let mut items = items.peekable();
+    while items.peek().is_some() {
+        let chunk = items.by_ref().take(BATCH_SIZE).collect();
+        if sender.blocking_send(Ok(batch(chunk))).is_err() {
+            return;
+        }
+    }
+}
+
+fn file_info_to_proto(info: FileInfo<'_, LocalToken>) -> store::FileInfo {
+    store::FileInfo {
+        path: info.path.as_bytes().to_vec(),
+        size: info.size,
+        flags: info.flags.as_byte().into_iter().collect(),
+        token: info.token.0.as_bytes().to_vec(),
+    }
+}
+
+fn file_change_to_proto(change: FileChangeInfo<'_, LocalToken>) -> FileChange {
+    let (kind, file) = match change {
+        FileChangeInfo::New(info) => (Kind::New, file_info_to_proto(info)),
+        FileChangeInfo::Changed(info) => {
+            (Kind::Changed, file_info_to_proto(info))
+        }
+        FileChangeInfo::Removed(path) => (
+            Kind::Removed,
+            store::FileInfo {
+                path: path.as_bytes().to_vec(),
+                ..Default::default()
+            },
+        ),
+    };
+    FileChange { kind: kind.into(), file: Some(file) }
+}
+
+/// The status for requests with nodes of the wrong length
+fn invalid_node() -> Status {
+    Status::invalid_argument("nodes must be 20 bytes long")
+}
+
+fn error_to_status<T>(error: Error<T>) -> Status {
+    match error.kind {
+        ErrorKind::NoSuchChangeset(changeset) => {
+            Status::not_found(format!("unknown changeset {changeset:x}"))
+        }
+        ErrorKind::NoSuchFile { changeset, path, .. } => {
+            Status::not_found(format!(
+                "no file {} in changeset {changeset:x}",
+                path_display(&path)
+            ))
+        }
+        ErrorKind::InvalidToken { changeset, path, .. } => {
+            Status::invalid_argument(format!(
+                "invalid token for {} in changeset {changeset:x}",
+                path_display(&path)
+            ))
+        }
+        ErrorKind::ReadFailed { changeset, path, .. } => {
+            Status::data_loss(format!(
+                "failed to read {} in changeset {changeset:x}",
+                path_display(&path)
+            ))
+        }
+        ErrorKind::InvalidRevisionIdx(idx) => {
+            Status::internal(format!("invalid revision index {}", idx.0))
+        }
+        ErrorKind::InvalidShareSource { share_source } => Status::internal(
+            format!("invalid share source {}", share_source.display()),
+        ),
+        ErrorKind::Other(message) => {
+            Status::internal(String::from_utf8_lossy(&message))
+        }
+    }
+}
+
+fn path_display(path: &HgPath) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(path.as_bytes())
+}
+
+/// Where a [`StoreService`] is listening
+#[derive(Debug, Clone)]
+pub enum StoreAddress {
+    /// A Unix domain socket
+    Unix(PathBuf),
+    /// An `http://` URL
+    Http(String),
+}
+
+impl StoreAddress {
+    /// Parses an `http://` URL, or takes anything else as a socket path
+    pub fn parse(address: &OsStr) -> Self {
+        match address.to_str() {
+            Some(url) if url.starts_with("http://") => {
+                Self::Http(url.to_owned())
+            }
+            _ => Self::Unix(address.into()),
+        }
+    }
+
+    async fn connect(self) -> Result<Channel, tonic::transport::Error> {
+        match self {
+            Self::Http(url) => Endpoint::from_shared(url)?.connect().await,
+            Self::Unix(socket) => {
+                // We use a custom connector rather than a `unix://<path>`
+                // URI because tonic requires a connection URI to be UTF-8.
+                // `http://store` is a placeholder that gets ignored.
+                Endpoint::from_static("http://store")
+                    .connect_with_connector(service_fn(move |_: Uri| {
+                        let socket = socket.clone();
+                        async move {
+                            Ok::<_, std::io::Error>(TokioIo::new(
+                                UnixStream::connect(socket).await?,
+                            ))
+                        }
+                    }))
+                    .await
+            }
+        }
+    }
+}
+
+/// An implementation of [`FileToken`] for a remote store
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteToken(pub Node);
+
+impl FileToken for RemoteToken {
+    fn file_node(&self) -> Option<Node> {
+        Some(self.0)
+    }
+}
+
+/// Weighs cached file contents by their size
+#[derive(Clone)]
+struct DataWeighter;
+
+impl Weighter<Node, RawData> for DataWeighter {
+    fn weight(&self, _node: &Node, data: &RawData) -> u64 {
+        // Count the key too so that empty files aren't free
+        (size_of::<Node>() + data.len()) as u64
+    }
+}
+
+/// A [`StoreBackend`] implementation that fetches everything from a
+/// [`StoreService`].
+pub struct RemoteBackend {
+    /// Runs the gRPC calls, which the FUSE threads wait for
+    runtime: Runtime,
+    /// The client for the remote store
+    client: StoreClient<Channel>,
+    /// The configuration applicable to this backend
+    server_config: Config,
+    /// A cache of file contents by file node
+    file_data: Cache<Node, RawData, DataWeighter>,
+    /// Mapping from revision to revision index
+    node_to_idx: DashMap<Node, u32>,
+    /// Mapping from revision index to revision
+    idx_to_node: DashMap<u32, Node>,
+    /// Counter for generating indices for node_to_idx and idx_to_node
+    current_idx: AtomicU32,
+}
+
+impl RemoteBackend {
+    /// Connects to the store served at `address`, keeping up to
+    /// `fuse.remote-cache-size` bytes of file contents in memory (256MiB by
+    /// default), and possibly more on disk if `fuse.blob-cache-size` and
+    /// `fuse.blob-cache-path` are set.
+    pub fn connect(
+        address: StoreAddress,
+        backend_mode: BackendMode,
+        config: &HgConfig,
+    ) -> Result<Self, HgError> {
+        let cache_size = config
+            .get_byte_size(b"fuse", b"remote-cache-size")?
+            .unwrap_or(DEFAULT_CACHE_SIZE);
+        let server_config = Config {
+            preload_structure: false,
+            backend_mode,
+            blob_cache: BlobCacheConfig::from_config(config, None)?,
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("hg-fuse-remote")
+            .enable_all()
+            .build()
+            .map_err(|e| {
+                HgError::abort_simple(format!("cannot start runtime: {e}"))
+            })?;
+        let display = format!("{address:?}");
+        let channel = block_on(&runtime, address.connect()).map_err(|e| {
+            HgError::abort_simple(format!(
+                "cannot reach the store at {display}: {e}"
+            ))
+        })?;
+        let client =
+            StoreClient::new(channel).max_decoding_message_size(usize::MAX);
+        Ok(Self {
+            runtime,
+            client,
+            server_config,
+            // Assume an average of 16KiB per file
+            file_data: Cache::with_weighter(
+                (cache_size / 16384).max(1) as usize,
+                cache_size,
+                DataWeighter,
+            ),
+            node_to_idx: DashMap::new(),
+            idx_to_node: DashMap::new(),
+            current_idx: AtomicU32::new(1),
+        })
+    }
+
+    /// Waits for the result of `call` made with our client
+    fn call<F>(&self, call: impl FnOnce(StoreClient<Channel>) -> F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        block_on(&self.runtime, call(self.client.clone()))
+    }
+}
+
+/// Waits for `future` to complete on `runtime`.
+///
+/// Unlike [`Runtime::block_on`], this works from within another runtime,
+/// which is where the control server mounts from.
+fn block_on<F>(runtime: &Runtime, future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+    runtime.spawn(async move {
+        let _ = sender.send(future.await);
+    });
+    receiver.recv().expect("the runtime outlives the backend")
+}
+
+/// Converts the status of a request about `changeset` to our error type
+fn changeset_error(status: Status, changeset: Node) -> Error<RemoteToken> {
+    match status.code() {
+        Code::NotFound => ErrorKind::NoSuchChangeset(changeset).into(),
+        _ => other_error(status),
+    }
+}
+
+fn other_error(status: Status) -> Error<RemoteToken> {
+    let message = format!("remote store error: {}", status.message());
+    ErrorKind::Other(message.into_bytes()).into()
+}
+
+fn invalid_response(what: &str) -> Error<RemoteToken> {
+    let message = format!("remote store sent an invalid {what}");
+    ErrorKind::Other(message.into_bytes()).into()
+}
+
+fn file_info_from_proto(
+    info: store::FileInfo,
+) -> Result<OwnedFileInfo<RemoteToken>, Error<RemoteToken>> {
+    let flags = match info.flags.as_slice() {
+        [] => ManifestFlags::EMPTY,
+        [byte] => ManifestFlags::from_byte(*byte)
+            .ok_or_else(|| invalid_response("file flag"))?,
+        _ => return Err(invalid_response("file flag")),
+    };
+    let token = Node::try_from(info.token.as_slice())
+        .map_err(|_| invalid_response("file token"))?;
+    Ok(OwnedFileInfo {
+        path: HgPathBuf::from_bytes(&info.path),
+        size: info.size,
+        flags,
+        token: RemoteToken(token),
+    })
+}
+
+impl StoreBackend<RemoteToken> for RemoteBackend {
+    fn server_config(&self) -> &Config {
+        &self.server_config
+    }
+
+    fn branch(&self, changeset: Node) -> Result<String, Error<RemoteToken>> {
+        let request =
+            BranchRequest { changeset: changeset.as_bytes().to_vec() };
+        let response = self
+            .call(|mut client| async move { client.branch(request).await })
+            .map_err(|status| changeset_error(status, changeset))?;
+        Ok(response.into_inner().branch)
+    }
+
+    fn idx_for_node(
+        &self,
+        changeset: Node,
+    ) -> Result<RevisionIdx, Error<RemoteToken>> {
+        let entry = self.node_to_idx.entry(changeset);
+        let idx = match entry {
+            dashmap::Entry::Occupied(entry) => *entry.get(),
+            dashmap::Entry::Vacant(vacant) => {
+                let idx = self.current_idx.fetch_add(1, Ordering::Relaxed);
+                assert!(idx < u32::MAX, "inode overflow");
+                self.idx_to_node.insert(idx, changeset);
+                vacant.insert(idx);
+                idx
+            }
+        };
+        Ok(RevisionIdx(idx))
+    }
+
+    fn node_for_idx(
+        &self,
+        idx: RevisionIdx,
+    ) -> Result<Node, Error<RemoteToken>> {
+        Ok(*self
+            .idx_to_node
+            .get(&idx.0)
+            .ok_or(ErrorKind::InvalidRevisionIdx(idx))?)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn changeset_files(
+        &self,
+        changeset: Node,
+    ) -> Result<impl ChangesetFiles<RemoteToken>, Error<RemoteToken>> {
+        let request =
+            ChangesetFilesRequest { changeset: changeset.as_bytes().to_vec() };
+        let batches = self
+            .call(|mut client| async move {
+                let mut stream =
+                    client.changeset_files(request).await?.into_inner();
+                let mut batches = vec![];
+                while let Some(batch) = stream.next().await {
+                    batches.push(batch?);
+                }
+                Ok(batches)
+            })
+            .map_err(|status| changeset_error(status, changeset))?;
+        let files = batches
+            .into_iter()
+            .flat_map(|batch| batch.files)
+            .map(file_info_from_proto)
+            .collect::<Result<_, _>>()?;
+        Ok(OwnedChangesetFiles { files })
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    fn changeset_files_diff(
+        &self,
+        from: Node,
+        to: Node,
+    ) -> Result<impl ChangesetFilesDiff<RemoteToken>, Error<RemoteToken>> {
+        let request = ChangesetFilesDiffRequest {
+            from: from.as_bytes().to_vec(),
+            to: to.as_bytes().to_vec(),
+        };
+        let batches = self
+            .call(|mut client| async move {
+                let mut stream =
+                    client.changeset_files_diff(request).await?.into_inner();
+                let mut batches = vec![];
+                while let Some(batch) = stream.next().await {
+                    batches.push(batch?);
+                }
+                Ok(batches)
+            })
+            .map_err(|status: Status| match status.code() {
+                // We can't tell which one, but they're checked in order
+                Code::NotFound => {
+                    let message = status.message();
+                    let missing = if message.contains(&format!("{from:x}")) {
+                        from
+                    } else {
+                        to
+                    };
+                    ErrorKind::NoSuchChangeset(missing).into()
+                }
+                _ => other_error(status),
+            })?;
+        let changes = batches
+            .into_iter()
+            .flat_map(|batch| batch.changes)
+            .map(|change| {
+                let file =
+                    change.file.ok_or_else(|| invalid_response("change"))?;
+                let kind = Kind::try_from(change.kind)
+                    .map_err(|_| invalid_response("change kind"))?;
+                Ok(match kind {
+                    Kind::New => {
+                        OwnedFileChange::New(file_info_from_proto(file)?)
+                    }
+                    Kind::Changed => {
+                        OwnedFileChange::Changed(file_info_from_proto(file)?)
+                    }
+                    Kind::Removed => OwnedFileChange::Removed(
+                        HgPathBuf::from_bytes(&file.path),
+                    ),
+                })
+            })
+            .collect::<Result<_, Error<RemoteToken>>>()?;
+        Ok(OwnedChangesetFilesDiff { changes })
+    }
+
+    fn file_data(
+        &self,
+        changeset: Node,
+        path: &HgPath,
+        token: RemoteToken,
+    ) -> Result<RawData, Error<RemoteToken>> {
+        if let Some(data) = self.file_data.get(&token.0) {
+            return Ok(data);
+        }
+        let request = FileDataRequest {
+            changeset: changeset.as_bytes().to_vec(),
+            path: path.as_bytes().to_vec(),
+            token: token.0.as_bytes().to_vec(),
+        };
+        let response = self
+            .call(|mut client| async move { client.file_data(request).await })
+            .map_err(|status| {
+                let path = path.to_owned();
+                let kind = match status.code() {
+                    Code::NotFound => {
+                        ErrorKind::NoSuchFile { changeset, path, token }
+                    }
+                    Code::InvalidArgument => {
+                        ErrorKind::InvalidToken { changeset, path, token }
+                    }
+                    Code::DataLoss => {
+                        ErrorKind::ReadFailed { changeset, path, token }
+                    }
+                    _ => return other_error(status),
+                };
+                kind.into()
+            })?;
+        let data = RawData::from(response.into_inner().data);
+        self.file_data.insert(token.0, data.clone());
+        Ok(data)
+    }
+
+    fn symbols(
+        &self,
+        kind: SymbolKind,
+    ) -> Result<Arc<BTreeMap<Vec<u8>, Node>>, Error<RemoteToken>> {
+        let kind = match kind {
+            SymbolKind::Branch => symbols_request::Kind::Branch,
+            SymbolKind::Bookmark => symbols_request::Kind::Bookmark,
+            SymbolKind::Tag => symbols_request::Kind::Tag,
+        };
+        let request = SymbolsRequest { kind: kind.into() };
+        let response = self
+            .call(|mut client| async move { client.symbols(request).await })
+            .map_err(other_error)?;
+        let symbols = response
+            .into_inner()
+            .symbols
+            .into_iter()
+            .map(|symbol| {
+                let changeset = Node::try_from(symbol.changeset.as_slice())
+                    .map_err(|_| invalid_response("symbol"))?;
+                Ok((symbol.name, changeset))
+            })
+            .collect::<Result<_, Error<RemoteToken>>>()?;
+        Ok(Arc::new(symbols))
+    }
+}
+
+/// Information about a file, owning its path
+#[derive(Debug, Clone)]
+pub struct OwnedFileInfo<T> {
+    pub path: HgPathBuf,
+    pub size: u64,
+    pub flags: ManifestFlags,
+    pub token: T,
+}
+
+impl<T: Copy> OwnedFileInfo<T> {
+    fn borrow(&self) -> FileInfo<'_, T> {
+        FileInfo {
+            path: &self.path,
+            size: self.size,
+            flags: self.flags,
+            token: self.token,
+        }
+    }
+}
+
+/// An implementation of [`ChangesetFiles`] that owns its files
+pub struct OwnedChangesetFiles<T> {
+    files: Vec<OwnedFileInfo<T>>,
+}
+
+impl<T: FileToken> ChangesetFiles<T> for OwnedChangesetFiles<T> {
+    fn iter(&self) -> impl Iterator<Item = FileInfo<'_, T>> {
+        self.files.iter().map(OwnedFileInfo::borrow)
+    }
+
+    fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// How a file changed between two changesets, owning its path
+#[derive(Debug, Clone)]
+pub enum OwnedFileChange<T> {
+    New(OwnedFileInfo<T>),
+    Changed(OwnedFileInfo<T>),
+    Removed(HgPathBuf),
+}
+
+/// An implementation of [`ChangesetFilesDiff`] that owns its changes
+pub struct OwnedChangesetFilesDiff<T> {
+    changes: Vec<OwnedFileChange<T>>,
+}
+
+impl<T: FileToken> ChangesetFilesDiff<T> for OwnedChangesetFilesDiff<T> {
+    fn iter_diff(&self) -> impl Iterator<Item = FileChangeInfo<'_, T>> {
+        self.changes.iter().map(|change| match change {
+            OwnedFileChange::New(info) => FileChangeInfo::New(info.borrow()),
+            OwnedFileChange::Changed(info) => {
+                FileChangeInfo::Changed(info.borrow())
+            }
+            OwnedFileChange::Removed(path) => FileChangeInfo::Removed(path),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use std::path::Path;
+
+    use tokio::net::UnixListener;
+    use tokio_stream::wrappers::UnixListenerStream;
+
+    use super::*;
+
+    /// A store with two changesets, counting how often file data is read
+    struct FakeStore {
+        config: Config,
+        reads: Arc<AtomicUsize>,
+    }
+
+    fn node(byte: u8) -> Node {
+        Node::from(&[byte; 20])
+    }
+
+    /// The changesets are `node(1)` and `node(2)`, and all files have the
+    /// same contents, with token `node(3)`
+    fn is_known(changeset: Node) -> bool {
+        changeset == node(1) || changeset == node(2)
+    }
+
+    fn fake_file(
+        path: &str,
+        flags: ManifestFlags,
+    ) -> OwnedFileInfo<LocalToken> {
+        OwnedFileInfo {
+            path: HgPathBuf::from_bytes(path.as_bytes()),
+            size: 5,
+            flags,
+            token: LocalToken(node(3)),
+        }
+    }
+
+    impl StoreBackend<LocalToken> for FakeStore {
+        fn server_config(&self) -> &Config {
+            &self.config
+        }
+
+        fn branch(&self, changeset: Node) -> Result<String, Error<LocalToken>> {
+            if !is_known(changeset) {
+                return Err(ErrorKind::NoSuchChangeset(changeset).into());
+            }
+            Ok("default".to_string())
+        }
+
+        fn idx_for_node(
+            &self,
+            _changeset: Node,
+        ) -> Result<RevisionIdx, Error<LocalToken>> {
+            unreachable!("not served")
+        }
+
+        fn node_for_idx(
+            &self,
+            _idx: RevisionIdx,
+        ) -> Result<Node, Error<LocalToken>> {
+            unreachable!("not served")
+        }
+
+        fn changeset_files(
+            &self,
+            changeset: Node,
+        ) -> Result<impl ChangesetFiles<LocalToken>, Error<LocalToken>>
+        {
+            self.branch(changeset)?;
+            Ok(OwnedChangesetFiles {
+                files: vec![
+                    fake_file("a", ManifestFlags::EMPTY),
+                    fake_file("dir/b", ManifestFlags::EXEC),
+                ],
+            })
+        }
+
+        fn changeset_files_diff(
+            &self,
+            from: Node,
+            to: Node,
+        ) -> Result<impl ChangesetFilesDiff<LocalToken>, Error<LocalToken>>
+        {
+            self.branch(from)?;
+            self.branch(to)?;
+            Ok(OwnedChangesetFilesDiff {
+                changes: vec![
+                    OwnedFileChange::Changed(fake_file(
+                        "a",
+                        ManifestFlags::LINK,
+                    )),
+                    OwnedFileChange::Removed(HgPathBuf::from_bytes(b"dir/b")),
+                ],
+            })
+        }
+
+        fn file_data(
+            &self,
+            changeset: Node,
+            path: &HgPath,
+            token: LocalToken,
+        ) -> Result<RawData, Error<LocalToken>> {
+            self.branch(changeset)?;
+            if token.0 != node(3) {
+                let path = path.to_owned();
+                return Err(
+                    ErrorKind::InvalidToken { changeset, path, token }.into()
+                );
+            }
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(b"hello".to_vec().into())
+        }
+
+        fn symbols(
+            &self,
+            kind: SymbolKind,
+        ) -> Result<Arc<BTreeMap<Vec<u8>, Node>>, Error<LocalToken>> {
+            let symbols = match kind {
+                SymbolKind::Branch => vec![(b"default".to_vec(), node(2))],
+                SymbolKind::Bookmark => vec![],
+                SymbolKind::Tag => vec![(b"v1".to_vec(), node(1))],
+            };
+            Ok(Arc::new(symbols.into_iter().collect()))
+        }
+    }
+
+    /// Serves a [`FakeStore`] on a Unix socket, returning the backend
+    /// connected to it
+    fn serve_fake_store(
+        socket: PathBuf,
+    ) -> (Runtime, RemoteBackend, Arc<AtomicUsize>) {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let store = FakeStore {
+            config: Config {
+                preload_structure: false,
+                backend_mode: BackendMode::Archive,
+                blob_cache: None,
+            },
+            reads: Arc::clone(&reads),
+        };
+        let runtime = Runtime::new().unwrap();
+        let listener = {
+            let _guard = runtime.enter();
+            UnixListener::bind(&socket).unwrap()
+        };
+        runtime.spawn(
+            tonic::transport::Server::builder()
+                .add_service(StoreService::new(store).into_server())
+                .serve_with_incoming(UnixListenerStream::new(listener)),
+        );
+        let address = StoreAddress::parse(socket.as_os_str());
+        let backend = RemoteBackend::connect(
+            address,
+            BackendMode::Archive,
+            &HgConfig::empty(),
+        )
+        .unwrap();
+        (runtime, backend, reads)
+    }
+
+    #[test]
+    fn test_store_address() {
+        assert!(matches!(
+            StoreAddress::parse(OsStr::new("http://[::1]:8080")),
+            StoreAddress::Http(url) if url == "http://[::1]:8080"
+        ));
+        assert!(matches!(
+            StoreAddress::parse(OsStr::new("/run/store.sock")),
+            StoreAddress::Unix(path) if path == Path::new("/run/store.sock")
+        ));
+    }
+
+    #[test]
+    fn test_remote_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_runtime, backend, reads) =
+            serve_fake_store(dir.path().join("store.sock"));
+
+        assert_eq!(backend.branch(node(1)).unwrap(), "default");
+        let unknown = node(9);
+        assert!(matches!(
+            backend.branch(unknown).map_err(|e| e.kind),
+            Err(ErrorKind::NoSuchChangeset(node)) if node == unknown
+        ));
+
+        let files = backend.changeset_files(node(1)).unwrap();
+        let files: Vec<_> =
+            files.iter().map(|f| (f.path.to_owned(), f.flags)).collect();
+        assert_eq!(
+            files,
+            [
+                (HgPathBuf::from_bytes(b"a"), ManifestFlags::EMPTY),
+                (HgPathBuf::from_bytes(b"dir/b"), ManifestFlags::EXEC),
+            ]
+        );
+
+        let diff = backend.changeset_files_diff(node(1), node(2)).unwrap();
+        let diff: Vec<_> = diff.iter_diff().collect();
+        assert!(matches!(
+            diff.as_slice(),
+            [
+                FileChangeInfo::Changed(FileInfo {
+                    flags: ManifestFlags::LINK,
+                    size: 5,
+                    ..
+                }),
+                FileChangeInfo::Removed(path),
+            ] if path.as_bytes() == b"dir/b"
+        ));
+        assert!(matches!(
+            backend.changeset_files_diff(node(1), unknown).map(|_| ()).map_err(|e| e.kind),
+            Err(ErrorKind::NoSuchChangeset(node)) if node == unknown
+        ));
+
+        let path = HgPath::new(b"a");
+        let token = RemoteToken(node(3));
+        for _ in 0..2 {
+            let data = backend.file_data(node(1), path, token).unwrap();
+            assert_eq!(&data[..], b"hello");
+        }
+        // The second read came from the cache
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+        let bad_token = RemoteToken(node(1));
+        assert!(matches!(
+            backend.file_data(node(1), path, bad_token).map_err(|e| e.kind),
+            Err(ErrorKind::InvalidToken { .. })
+        ));
+
+        let branches = backend.symbols(SymbolKind::Branch).unwrap();
+        assert_eq!(branches.get(b"default".as_slice()), Some(&node(2)));
+        assert!(backend.symbols(SymbolKind::Bookmark).unwrap().is_empty());
+        let tags = backend.symbols(SymbolKind::Tag).unwrap();
+        assert_eq!(tags.keys().collect::<Vec<_>>(), [b"v1"]);
+    }
+}
@@ -4,6 +4,7 @@ use std::ops::Range;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
@@ -47,6 +48,8 @@ use rayon::iter::ParallelIterator;
 use crate::fuse::Entry;
 use crate::fuse::FILES_INODE_NAME;
 use crate::fuse::RootInodeEncoder;
+use crate::server::blob_cache::BlobCache;
+use crate::server::overlay::DOT_HG_OVERLAY_FILE;
 use crate::server::permissions_for_file;
 use crate::server::store::BackendMode;
 use crate::server::store::ChangesetFiles;
@@ -62,6 +65,8 @@ use crate::server::store::StoreInfo;
 /// Represents a manifest revision
 pub(super) struct OwnedRevision<T> {
     revision: RevisionTree<T>,
+    /// Where to look for file contents before asking the store, if anywhere
+    blob_cache: Option<Arc<BlobCache>>,
 }
 
 impl<T: FileToken> OwnedRevision<T> {
@@ -73,14 +78,17 @@ impl<T: FileToken> OwnedRevision<T> {
         changeset: Node,
         start_time: SystemTime,
         dirstate_base: Option<DirstateBaseInfo<T>>,
+        blob_cache: Option<Arc<BlobCache>>,
+        overlay_directory: Option<&Path>,
     ) -> Result<(Self, DirstateBaseInfo<T>), StoreError<T>> {
         let (revision, new_dirstate_base) = RevisionTree::from_revision(
             store,
             changeset,
             start_time,
             dirstate_base,
+            overlay_directory,
         )?;
-        Ok((Self { revision }, new_dirstate_base))
+        Ok((Self { revision, blob_cache }, new_dirstate_base))
     }
 
     /// Preload this revision's filesystem structure into the kernel's caches.
@@ -181,7 +189,8 @@ impl<T: FileToken> OwnedRevision<T> {
                         .collect();
                     Some(children)
                 }
-                Entry::File { .. } => None,
+                // Reserved entries never come from the overlay
+                Entry::File { .. } | Entry::Overlay { .. } => None,
             };
         }
 
@@ -207,7 +216,7 @@ impl<T: FileToken> OwnedRevision<T> {
         let revision = &self.revision;
         if let Some(reserved) = revision.reserved.get(&ino) {
             return match &reserved.entry {
-                Entry::Dir { .. } => Ok(None),
+                Entry::Dir { .. } | Entry::Overlay { .. } => Ok(None),
                 Entry::File { ino, .. } => {
                     Ok(Some(revision.reserved_contents[ino].clone()))
                 }
@@ -231,10 +240,49 @@ impl<T: FileToken> OwnedRevision<T> {
             .offset_to_token
             .get(&u_u64(offset))
             .expect("node should exist");
-        let data = store.file_data(changeset, info.path, *token)?;
+        let fetch = || store.file_data(changeset, info.path, *token);
+        let data = match (&self.blob_cache, token.file_node()) {
+            (Some(blob_cache), Some(node)) => {
+                blob_cache.get_or_fetch(node, fetch)?
+            }
+            _ => fetch()?,
+        };
         Ok(Some(data))
     }
 
+    /// Return the path of `ino` relative to the root of this revision, if it
+    /// exists
+    pub fn path(&self, ino: INodeNo) -> Option<PathBuf> {
+        let revision = &self.revision;
+        if revision.reserved.contains_key(&ino) {
+            return self.reserved_path(revision.root_ino, PathBuf::new(), ino);
+        }
+        let dirstate_map = revision.dirstate.get_map();
+        let info = dirstate_map.fuse_node_info(
+            RevisionInodeEncoder::ino_to_offset(revision.files_root_ino, ino),
+        )?;
+        let path = hg_path_to_path_buf(info.path).ok()?;
+        Some(Path::new(FILES_INODE_NAME).join(path))
+    }
+
+    /// Look for `ino` among the reserved descendants of `parent`, which is at
+    /// `parent_path`
+    fn reserved_path(
+        &self,
+        parent: INodeNo,
+        parent_path: PathBuf,
+        ino: INodeNo,
+    ) -> Option<PathBuf> {
+        if parent == ino {
+            return Some(parent_path);
+        }
+        let reserved = self.revision.reserved.get(&parent)?;
+        reserved.children.iter().find_map(|child| {
+            let entry = &self.revision.reserved.get(child)?.entry;
+            self.reserved_path(*child, parent_path.join(entry.name()), ino)
+        })
+    }
+
     fn entry_for_dirstate_node(
         &self,
         FuseNodeInfo { path, size, flags, offset }: FuseNodeInfo,
@@ -261,6 +309,8 @@ struct RevisionTree<T> {
     offset_to_token: FastHashMap<u64, T>,
     /// Inode for the "files" folder for this revision
     files_root_ino: INodeNo,
+    /// Inode for the root of the revision hierarchy
+    root_ino: INodeNo,
     /// Mapping of all reserved inodes to their FUSE entries
     pub reserved: FastHashMap<INodeNo, ReservedRevisionEntry>,
     /// Mapping of all reserved inodes that have file contents
@@ -274,6 +324,7 @@ impl<T: FileToken> RevisionTree<T> {
         changeset: Node,
         start_time: SystemTime,
         dirstate_base: Option<DirstateBaseInfo<T>>,
+        overlay_directory: Option<&Path>,
     ) -> Result<(RevisionTree<T>, DirstateBaseInfo<T>), StoreError<T>> {
         let (dirstate, inode_encoder, new_dirstate_base) =
             Self::process_manifest_files(
@@ -281,6 +332,7 @@ impl<T: FileToken> RevisionTree<T> {
                 changeset,
                 start_time,
                 dirstate_base,
+                overlay_directory,
             )?;
 
         // Remember the inodes for reserved entries
@@ -290,6 +342,7 @@ impl<T: FileToken> RevisionTree<T> {
             dirstate,
             offset_to_token: new_dirstate_base.offset_to_token.clone(),
             files_root_ino: inode_encoder.files_root_inode,
+            root_ino: inode_encoder.root_ino,
             reserved,
             reserved_contents: inode_encoder.reserved_contents,
         };
@@ -305,6 +358,7 @@ impl<T: FileToken> RevisionTree<T> {
         changeset: Node,
         start_time: SystemTime,
         dirstate_base: Option<DirstateBaseInfo<T>>,
+        overlay_directory: Option<&Path>,
     ) -> Result<RevisionInfo<T>, StoreError<T>> {
         let revision_idx = store.idx_for_node(changeset)?;
         let available_inode_range =
@@ -313,6 +367,7 @@ impl<T: FileToken> RevisionTree<T> {
             available_inode_range,
             store.changeset_store_info(changeset)?,
             store.server_config().backend_mode,
+            overlay_directory,
         )?;
 
         let start_time: TruncatedTimestamp = start_time.into();
@@ -483,6 +538,7 @@ impl RevisionInodeEncoder {
         available_range: Range<INodeNo>,
         store_info: Option<StoreInfo>,
         backend_mode: BackendMode,
+        overlay_directory: Option<&Path>,
     ) -> Result<Self, StoreError<T>> {
         let mut encoder = Self {
             current_ino: AtomicU64::new(available_range.start.0),
@@ -506,8 +562,11 @@ impl RevisionInodeEncoder {
                 encoder.add_reserved_directory(FILES_INODE_NAME, &[])
             }
             BackendMode::Full | BackendMode::Thin => {
-                let dot_hg_ino =
-                    encoder.add_dot_hg(store_info, backend_mode)?;
+                let dot_hg_ino = encoder.add_dot_hg(
+                    store_info,
+                    backend_mode,
+                    overlay_directory,
+                )?;
                 encoder.dot_hg_ino = Some(dot_hg_ino);
                 encoder.add_reserved_directory(FILES_INODE_NAME, &[dot_hg_ino])
             }
@@ -530,6 +589,7 @@ impl RevisionInodeEncoder {
         &mut self,
         store_info: Option<StoreInfo>,
         backend_mode: BackendMode,
+        overlay_directory: Option<&Path>,
     ) -> Result<INodeNo, StoreError<T>> {
         let mut requirements = vec![
             SHARESAFE_REQUIREMENT,
@@ -611,8 +671,19 @@ impl RevisionInodeEncoder {
         let tracked_key_ino =
             self.add_reserved_file("dirstate-tracked-hint", tracked_key.into());
 
+        // Lets `status` only look at what the overlay changed, see
+        // `overlay::working_copy_changes`
+        let overlay_ino = self.add_reserved_file(
+            DOT_HG_OVERLAY_FILE,
+            overlay_directory
+                .map(get_bytes_from_path)
+                .unwrap_or_default()
+                .into(),
+        );
+
         dot_hg_files.push(requires_ino);
         dot_hg_files.push(tracked_key_ino);
+        dot_hg_files.push(overlay_ino);
         Ok(self.add_reserved_directory(".hg", &dot_hg_files))
     }
 
@@ -0,0 +1,111 @@
+//! Counters of what a [`Server`](super::Server) did since it was started,
+//! readable from outside of the FUSE session it is moved into.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::server::blob_cache::BlobCache;
+use crate::server::blob_cache::BlobCacheStats;
+
+/// The counters a server updates as it answers requests
+#[derive(Debug, Default)]
+pub(super) struct Counters {
+    /// How many revisions are currently loaded
+    revisions_loaded: AtomicU64,
+    /// How many revisions were loaded since the start
+    revisions_loads: AtomicU64,
+    /// How many reads of file contents were answered
+    reads: AtomicU64,
+    /// The total size of the file contents sent to the kernel
+    bytes_served: AtomicU64,
+}
+
+impl Counters {
+    /// Record that a revision was loaded, leaving `loaded` in memory
+    pub(super) fn revision_loaded(&self, loaded: usize) {
+        self.revisions_loads.fetch_add(1, Ordering::Relaxed);
+        self.revisions_loaded.store(loaded as u64, Ordering::Relaxed);
+    }
+
+    /// Record that `bytes` of file contents were sent to the kernel
+    pub(super) fn served(&self, bytes: usize) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_served.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the counters of a server
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    /// How many revisions are currently loaded
+    pub revisions_loaded: u64,
+    /// How many revisions were loaded since the start, including those
+    /// that were evicted since
+    pub revisions_loads: u64,
+    /// How many reads of file contents were answered
+    pub reads: u64,
+    /// The total size of the file contents sent to the kernel
+    pub bytes_served: u64,
+    /// The counters of the on-disk cache of file contents, if enabled
+    pub blob_cache: Option<BlobCacheStats>,
+}
+
+/// A cheap handle on the counters of a server, which stays valid after the
+/// server was moved into its FUSE session
+#[derive(Clone)]
+pub struct ServerStatsHandle {
+    counters: Arc<Counters>,
+    blob_cache: Option<Arc<BlobCache>>,
+}
+
+impl ServerStatsHandle {
+    pub(super) fn new(
+        counters: Arc<Counters>,
+        blob_cache: Option<Arc<BlobCache>>,
+    ) -> Self {
+        Self { counters, blob_cache }
+    }
+
+    /// Returns the current value of every counter
+    pub fn snapshot(&self) -> ServerStats {
+        let counters = &self.counters;
+        ServerStats {
+            revisions_loaded: counters.revisions_loaded.load(Ordering::Relaxed),
+            revisions_loads: counters.revisions_loads.load(Ordering::Relaxed),
+            reads: counters.reads.load(Ordering::Relaxed),
+            bytes_served: counters.bytes_served.load(Ordering::Relaxed),
+            blob_cache: self.blob_cache.as_ref().map(|cache| cache.stats()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let counters = Arc::new(Counters::default());
+        let handle = ServerStatsHandle::new(Arc::clone(&counters), None);
+        assert_eq!(handle.snapshot(), ServerStats::default());
+
+        counters.revision_loaded(1);
+        counters.revision_loaded(2);
+        // Evicting the oldest revision to load another one
+        counters.revision_loaded(2);
+        counters.served(10);
+        counters.served(0);
+        counters.served(32);
+        assert_eq!(
+            handle.snapshot(),
+            ServerStats {
+                revisions_loaded: 2,
+                revisions_loads: 3,
+                reads: 3,
+                bytes_served: 42,
+                blob_cache: None,
+            }
+        );
+    }
+}
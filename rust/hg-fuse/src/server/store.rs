@@ -1,7 +1,9 @@
 //! Defines interfaces that the FUSE calls into to interact with the Mercurial
 //! store.
 
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use clap::ValueEnum;
 use hg::FastHashMap;
@@ -157,6 +159,19 @@ pub trait StoreBackend<T: FileToken>: Send + Sync + 'static {
     ) -> Result<Option<StoreInfo>, Error<T>> {
         Ok(None)
     }
+
+    /// Returns every name of this kind with the changeset it currently
+    /// resolves to, as of the latest state of the store.
+    ///
+    /// Named branches resolve to their tip-most open head, like `hg update`
+    /// would. Stores without symbolic names can keep the default, which has
+    /// none.
+    fn symbols(
+        &self,
+        _kind: SymbolKind,
+    ) -> Result<Arc<BTreeMap<Vec<u8>, Node>>, Error<T>> {
+        Ok(Arc::default())
+    }
 }
 
 /// A trait that enables iteration over a given changeset's files. Allows for a
@@ -193,6 +208,11 @@ pub trait ChangesetFilesDiff<T: FileToken> {
 pub trait FileToken:
     Send + Sync + Copy + Clone + std::fmt::Debug + 'static
 {
+    /// Returns the node of the file revision this token stands for, if it
+    /// identifies one, which allows caching its contents across revisions.
+    fn file_node(&self) -> Option<Node> {
+        None
+    }
 }
 
 /// An opaque index, unique for every changeset, due to a current implementation
@@ -229,6 +249,17 @@ pub struct FileInfo<'store, T> {
     pub token: T,
 }
 
+/// The kinds of symbolic names that resolve to a changeset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// Named branches
+    Branch,
+    /// Bookmarks
+    Bookmark,
+    /// Global tags, along with `tip`
+    Tag,
+}
+
 #[derive(Clone, Copy, Default, Debug, ValueEnum)]
 pub enum BackendMode {
     /// Present working copies that look like fully-functional hg repos
@@ -0,0 +1,143 @@
+//! The `branches/`, `bookmarks/` and `tags/` directories at the root of the
+//! FUSE, which hold one symlink per name into `commits/`.
+//!
+//! Names are resolved against the store every time they are looked up, so
+//! these directories always reflect the latest state of the repository.
+//! Their inodes are reserved, and given out the first time a name is seen:
+//! a name keeps its inode even if it moves to another changeset or
+//! disappears for a while.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use dashmap::DashMap;
+use fuser::INodeNo;
+use hg::Node;
+
+use crate::fuse::COMMITS_INODE_NAME;
+use crate::fuse::FIRST_SYMBOL_INODE;
+use crate::fuse::RESERVED_INODES_COUNT;
+use crate::server::store::SymbolKind;
+
+/// Gives out the inodes of the symlinks of the symbol directories
+pub(super) struct SymbolInodes {
+    /// The inode of each symbol we've been asked about
+    by_symbol: DashMap<(SymbolKind, Vec<u8>), INodeNo>,
+    /// The symbol for each inode we've given out
+    by_inode: DashMap<INodeNo, (SymbolKind, Vec<u8>)>,
+    /// The next inode to give out
+    next_inode: AtomicU64,
+}
+
+impl SymbolInodes {
+    pub(super) fn new() -> Self {
+        Self {
+            by_symbol: DashMap::new(),
+            by_inode: DashMap::new(),
+            next_inode: AtomicU64::new(FIRST_SYMBOL_INODE.0),
+        }
+    }
+
+    /// Returns the inode for this symbol, or `None` if we've run out of them
+    pub(super) fn inode(
+        &self,
+        kind: SymbolKind,
+        name: &[u8],
+    ) -> Option<INodeNo> {
+        let entry = self.by_symbol.entry((kind, name.to_vec()));
+        match entry {
+            dashmap::Entry::Occupied(entry) => Some(*entry.get()),
+            dashmap::Entry::Vacant(vacant) => {
+                let ino = self.next_inode.fetch_add(1, Ordering::Relaxed);
+                if ino >= RESERVED_INODES_COUNT {
+                    tracing::warn!("out of inodes for symbolic names");
+                    return None;
+                }
+                let ino = INodeNo(ino);
+                self.by_inode.insert(ino, (kind, name.to_vec()));
+                vacant.insert(ino);
+                Some(ino)
+            }
+        }
+    }
+
+    /// Returns the symbol this inode was given out for, if any
+    pub(super) fn symbol(&self, ino: INodeNo) -> Option<(SymbolKind, Vec<u8>)> {
+        self.by_inode.get(&ino).map(|symbol| symbol.clone())
+    }
+}
+
+/// Returns the file name for this symbol name, which may contain slashes
+pub(super) fn escape_name(name: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(name.len());
+    for &byte in name {
+        match byte {
+            b'%' => escaped.extend_from_slice(b"%25"),
+            b'/' => escaped.extend_from_slice(b"%2F"),
+            _ => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+/// Returns the symbol name for this file name, if it was escaped by
+/// [`escape_name`]
+pub(super) fn unescape_name(file_name: &[u8]) -> Option<Vec<u8>> {
+    let mut name = Vec::with_capacity(file_name.len());
+    let mut bytes = file_name.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'%' {
+            name.push(byte);
+            continue;
+        }
+        match (bytes.next(), bytes.next()) {
+            (Some(b'2'), Some(b'5')) => name.push(b'%'),
+            (Some(b'2'), Some(b'F')) => name.push(b'/'),
+            _ => return None,
+        }
+    }
+    Some(name)
+}
+
+/// Returns the target of the symlink to this changeset, relative to the
+/// symbol directories
+pub(super) fn symlink_target(changeset: Node) -> Vec<u8> {
+    format!("../{COMMITS_INODE_NAME}/{changeset:x}").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_name() {
+        let pairs: [(&[u8], &[u8]); 4] = [
+            (b"default", b"default"),
+            (b"feature/fuse", b"feature%2Ffuse"),
+            (b"100%", b"100%25"),
+            (b"%2F/", b"%252F%2F"),
+        ];
+        for (name, escaped) in pairs {
+            assert_eq!(escape_name(name), escaped);
+            assert_eq!(unescape_name(escaped).as_deref(), Some(name));
+        }
+        // Not something we would have given out
+        assert_eq!(unescape_name(b"100%"), None);
+        assert_eq!(unescape_name(b"%2f"), None);
+    }
+
+    #[test]
+    fn test_symbol_inodes_are_stable() {
+        let inodes = SymbolInodes::new();
+        let default = inodes.inode(SymbolKind::Branch, b"default").unwrap();
+        let bookmark = inodes.inode(SymbolKind::Bookmark, b"default").unwrap();
+        assert_eq!(default, FIRST_SYMBOL_INODE);
+        assert_ne!(default, bookmark);
+        assert_eq!(inodes.inode(SymbolKind::Branch, b"default"), Some(default));
+        assert_eq!(
+            inodes.symbol(bookmark),
+            Some((SymbolKind::Bookmark, b"default".to_vec()))
+        );
+        assert_eq!(inodes.symbol(INodeNo(bookmark.0 + 1)), None);
+    }
+}
@@ -33,4 +33,13 @@ impl Transaction for PyTransaction {
                 .expect("transaction add failed");
         })
     }
+
+    fn add_backup(&mut self, file: impl AsRef<std::path::Path>) {
+        Python::attach(|py| {
+            let file = PyBytes::new(py, &get_bytes_from_path(file.as_ref()));
+            self.inner
+                .call_method(py, intern!(py, "addbackup"), (file,), None)
+                .expect("transaction addbackup failed");
+        })
+    }
 }
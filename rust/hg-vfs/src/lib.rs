@@ -1,4 +1,4 @@
-//! Manager for multiple read-only FUSE mounts.
+//! Manager for multiple FUSE mounts.
 
 use std::collections::HashMap;
 use std::path::Path;
@@ -8,14 +8,26 @@ use std::time::UNIX_EPOCH;
 
 use fuser::BackgroundSession;
 pub use fuser::SessionACL;
+use hg::Node;
+use hg::config::Config;
 use hg::errors::HgBacktrace;
 use hg::errors::HgError;
 use hg::repo::Repo;
 use hg_fuse::fuse::HgFuse;
+pub use hg_fuse::server::stats::ServerStats;
+use hg_fuse::server::stats::ServerStatsHandle;
 pub use hg_fuse::server::store::BackendMode;
 use parking_lot::Mutex;
 
+use crate::registry::MountRecord;
+use crate::registry::MountRegistry;
+use crate::registry::lazy_unmount;
+use crate::registry::mounted_virtual_filesystems;
+
+pub mod registry;
+
 /// Per-mount options.
+#[derive(Debug, Clone)]
 pub struct MountOptions {
     /// What kind of working copy to present.
     pub backend_mode: BackendMode,
@@ -27,9 +39,13 @@ pub struct MountOptions {
     pub group_id: Option<u32>,
     /// Maximum number of revisions to keep loaded (`None` for no limit).
     pub max_revisions_loaded: Option<usize>,
+    /// Scratch directory receiving all changes, making the mount writable
+    /// (`None` for a read-only mount).
+    pub overlay_directory: Option<PathBuf>,
 }
 
-/// Info about a live mount, returned by `mount_all_revs` and `list_mounts`.
+/// Info about a live mount, returned by `mount_all_revs`, `mount_revision`
+/// and `list_mounts`.
 #[derive(Debug, Clone)]
 pub struct MountInfo {
     /// Path of the clone being served.
@@ -38,8 +54,55 @@ pub struct MountInfo {
     pub mount_point: PathBuf,
     /// Seconds since the Unix epoch when the mount was established.
     pub created_at: u64,
+    /// The only revision exposed, at the root (`None` for every revision).
+    pub revision: Option<Node>,
+    /// The options the mount was established with.
+    pub options: MountOptions,
+}
+
+/// What happened to a mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountEventKind {
+    /// The mount was established.
+    Mounted,
+    /// The mount was removed from the manager and is being torn down.
+    Unmounted,
+}
+
+/// A change in the lifecycle of a mount, as sent to watchers.
+#[derive(Debug, Clone)]
+pub struct MountEvent {
+    pub kind: MountEventKind,
+    /// Seconds since the Unix epoch when the event happened.
+    pub timestamp: u64,
+    /// The mount the event is about.
+    pub info: MountInfo,
 }
 
+/// What `MountManager::recover` did about a mount of a previous server.
+#[derive(Debug)]
+pub struct Recovery {
+    /// The mount, as recorded by the previous server.
+    pub record: MountRecord,
+    /// Whether its mount point was left behind and had to be detached.
+    pub detached: bool,
+    pub outcome: RecoveryOutcome,
+}
+
+/// Whether a mount of a previous server was re-established.
+#[derive(Debug)]
+pub enum RecoveryOutcome {
+    /// It was forgotten, as asked.
+    Dropped,
+    /// It is live again.
+    Remounted(MountInfo),
+    /// It could not be detached or re-established, and was forgotten.
+    Failed(MountError),
+}
+
+/// Callback receiving mount events, until it returns `false`.
+type Watcher = Box<dyn FnMut(&MountEvent) -> bool + Send>;
+
 /// Why a mount or unmount failed.
 #[derive(Debug, derive_more::From)]
 pub enum MountError {
@@ -60,12 +123,19 @@ struct MountHandle {
     session: BackgroundSession,
     /// Metadata reported by `list_mounts`.
     info: MountInfo,
+    /// The counters of the mount's server.
+    stats: ServerStatsHandle,
 }
 
-/// Registry of live read-only FUSE mounts, keyed by mount point.
+/// Registry of live FUSE mounts, keyed by mount point.
 #[derive(Default)]
 pub struct MountManager {
     mounts: Mutex<HashMap<PathBuf, MountHandle>>,
+    /// Callbacks interested in mount events. Always locked after `mounts`,
+    /// so that they see events in the order they happen.
+    watchers: Mutex<Vec<Watcher>>,
+    /// Where to record the mounts, if anywhere.
+    registry: Option<MountRegistry>,
 }
 
 impl MountManager {
@@ -73,6 +143,67 @@ impl MountManager {
         Self::default()
     }
 
+    /// A manager that records its mounts in `registry`, so that a later
+    /// manager can `recover` them.
+    pub fn with_registry(registry: MountRegistry) -> Self {
+        Self { registry: Some(registry), ..Self::default() }
+    }
+
+    /// Deal with the mounts recorded in the registry by a previous manager:
+    /// detach those left behind, then re-establish them all if `remount` is
+    /// set, using `config` to open their clones. Every mount that isn't
+    /// re-established is forgotten.
+    ///
+    /// Must be called before any other mount is made.
+    pub fn recover(
+        &self,
+        config: &Config,
+        remount: bool,
+    ) -> Result<Vec<Recovery>, HgError> {
+        let Some(registry) = &self.registry else {
+            return Ok(vec![]);
+        };
+        let records = registry.load()?;
+        let mounted = mounted_virtual_filesystems()?;
+        let recoveries = records
+            .into_iter()
+            .map(|record| {
+                let detached = mounted.contains(&record.mount_point);
+                let outcome = if detached
+                    && let Err(e) = lazy_unmount(&record.mount_point)
+                {
+                    RecoveryOutcome::Failed(MountError::Unmount(
+                        e,
+                        HgBacktrace::capture(),
+                    ))
+                } else if remount {
+                    match self.remount(config, &record) {
+                        Ok(info) => RecoveryOutcome::Remounted(info),
+                        Err(e) => RecoveryOutcome::Failed(e),
+                    }
+                } else {
+                    RecoveryOutcome::Dropped
+                };
+                Recovery { record, detached, outcome }
+            })
+            .collect();
+        // Forget everything that wasn't re-established
+        self.persist(&self.mounts.lock());
+        Ok(recoveries)
+    }
+
+    /// Re-establish a mount of a previous manager
+    fn remount(
+        &self,
+        config: &Config,
+        record: &MountRecord,
+    ) -> Result<MountInfo, MountError> {
+        let options = record.options()?;
+        let revision = record.revision()?;
+        let repo = Repo::find(config, Some(record.clone_path.clone()))?;
+        self.mount(repo, revision, record.mount_point.clone(), options)
+    }
+
     /// Mount all of `repo`'s revisions at `mount_point`, returning the info for
     /// the new mount.
     ///
@@ -84,6 +215,30 @@ impl MountManager {
         repo: Repo,
         mount_point: PathBuf,
         options: MountOptions,
+    ) -> Result<MountInfo, MountError> {
+        self.mount(repo, None, mount_point, options)
+    }
+
+    /// Mount the working copy of `repo`'s `changeset` at `mount_point`,
+    /// returning the info for the new mount.
+    ///
+    /// Behaves like `mount_all_revs` otherwise.
+    pub fn mount_revision(
+        &self,
+        repo: Repo,
+        changeset: Node,
+        mount_point: PathBuf,
+        options: MountOptions,
+    ) -> Result<MountInfo, MountError> {
+        self.mount(repo, Some(changeset), mount_point, options)
+    }
+
+    fn mount(
+        &self,
+        repo: Repo,
+        revision: Option<Node>,
+        mount_point: PathBuf,
+        options: MountOptions,
     ) -> Result<MountInfo, MountError> {
         let clone_path = repo.working_directory_path().to_path_buf();
         let mount_point = canonical_mount_point(&mount_point);
@@ -95,26 +250,31 @@ impl MountManager {
             ));
         }
 
-        let session = HgFuse::mount_all_revs(
+        let (session, stats) = HgFuse::mount_local(
             repo,
             &mount_point,
+            revision,
             options.backend_mode,
             options.user_id,
             options.group_id,
             options.max_revisions_loaded,
             options.session_acl,
+            options.overlay_directory.clone(),
         )?;
 
-        let created_at = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_secs())
-            .unwrap_or(0);
         let info = MountInfo {
             clone_path,
             mount_point: mount_point.clone(),
-            created_at,
+            created_at: now(),
+            revision,
+            options,
         };
-        mounts.insert(mount_point, MountHandle { session, info: info.clone() });
+        self.notify(MountEventKind::Mounted, &info);
+        mounts.insert(
+            mount_point,
+            MountHandle { session, info: info.clone(), stats },
+        );
+        self.persist(&mounts);
         Ok(info)
     }
 
@@ -124,10 +284,15 @@ impl MountManager {
     /// the umount syscall or the session thread-join fails.
     pub fn unmount(&self, mount_point: &Path) -> Result<(), MountError> {
         let mount_point = canonical_mount_point(mount_point);
-        let handle =
-            self.mounts.lock().remove(&mount_point).ok_or_else(|| {
+        let handle = {
+            let mut mounts = self.mounts.lock();
+            let handle = mounts.remove(&mount_point).ok_or_else(|| {
                 MountError::NotMounted(mount_point, HgBacktrace::capture())
             })?;
+            self.notify(MountEventKind::Unmounted, &handle.info);
+            self.persist(&mounts);
+            handle
+        };
         handle
             .session
             .umount_and_join()
@@ -139,6 +304,80 @@ impl MountManager {
     pub fn list_mounts(&self) -> Vec<MountInfo> {
         self.mounts.lock().values().map(|h| h.info.clone()).collect()
     }
+
+    /// Return the counters of the mount at `mount_point`.
+    ///
+    /// Errors with `NotMounted` if nothing is mounted there.
+    pub fn mount_stats(
+        &self,
+        mount_point: &Path,
+    ) -> Result<ServerStats, MountError> {
+        let mount_point = canonical_mount_point(mount_point);
+        match self.mounts.lock().get(&mount_point) {
+            Some(handle) => Ok(handle.stats.snapshot()),
+            None => {
+                Err(MountError::NotMounted(mount_point, HgBacktrace::capture()))
+            }
+        }
+    }
+
+    /// Call `watcher` with every mount event from now on, until it returns
+    /// `false`. If `include_existing` is set, it is first called with a
+    /// `Mounted` event for every live mount.
+    pub fn watch(
+        &self,
+        include_existing: bool,
+        mut watcher: impl FnMut(&MountEvent) -> bool + Send + 'static,
+    ) {
+        // Hold the mounts while subscribing so that no event gets lost or
+        // duplicated in between
+        let mounts = self.mounts.lock();
+        if include_existing {
+            for handle in mounts.values() {
+                let event = MountEvent {
+                    kind: MountEventKind::Mounted,
+                    timestamp: handle.info.created_at,
+                    info: handle.info.clone(),
+                };
+                if !watcher(&event) {
+                    return;
+                }
+            }
+        }
+        self.watchers.lock().push(Box::new(watcher));
+    }
+
+    /// Record the live mounts in the registry, if any. Failing to do so only
+    /// affects recovery, so it is not fatal.
+    fn persist(&self, mounts: &HashMap<PathBuf, MountHandle>) {
+        let Some(registry) = &self.registry else {
+            return;
+        };
+        let mut records: Vec<MountRecord> =
+            mounts.values().map(|h| MountRecord::new(&h.info)).collect();
+        records.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+        if let Err(e) = registry.save(records) {
+            tracing::warn!(
+                "failed to record mounts in {}: {e}",
+                registry.path().display()
+            );
+        }
+    }
+
+    /// Send an event about this mount to every watcher, forgetting those
+    /// that are no longer interested.
+    fn notify(&self, kind: MountEventKind, info: &MountInfo) {
+        let event = MountEvent { kind, timestamp: now(), info: info.clone() };
+        self.watchers.lock().retain_mut(|watcher| watcher(&event));
+    }
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
 /// Canonicalize a mount point so that the keys of the `mounts` map are stable.
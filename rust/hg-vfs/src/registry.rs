@@ -0,0 +1,342 @@
+//! On-disk record of the mounts of a [`MountManager`](crate::MountManager).
+//!
+//! FUSE mounts outlive the process serving them: if the server dies, its
+//! mount points are left as dead endpoints (`ENOTCONN`). Recording every
+//! mount lets the next server find and unmount them, and optionally
+//! re-establish them.
+
+use std::ffi::CString;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use hg::Node;
+use hg::errors::HgError;
+use hg::errors::IoResultExt;
+use hg::revlog::path_encode::PathEncoding;
+use hg::vfs::VfsImpl;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::BackendMode;
+use crate::MountInfo;
+use crate::MountOptions;
+use crate::SessionACL;
+
+/// Version of the format of the state file, bumped on incompatible changes
+const STATE_FORMAT_VERSION: u32 = 1;
+/// Name of the filesystem of our mounts, as it appears in the mount table
+const FS_NAME: &[u8] = b"hgvfs";
+
+/// What is recorded about a mount, enough to re-establish it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MountRecord {
+    /// Path of the clone being served
+    pub clone_path: PathBuf,
+    /// Canonical path the filesystem is mounted at
+    pub mount_point: PathBuf,
+    /// Seconds since the Unix epoch when the mount was first established
+    pub created_at: u64,
+    /// Hex node of the only revision exposed, if any
+    pub revision: Option<String>,
+    /// One of `full`, `archive` or `thin`
+    pub backend_mode: String,
+    /// One of `owner`, `root-and-owner` or `all`
+    pub session_acl: String,
+    pub user_id: Option<u32>,
+    pub group_id: Option<u32>,
+    pub max_revisions_loaded: Option<usize>,
+    pub overlay_directory: Option<PathBuf>,
+}
+
+impl MountRecord {
+    pub fn new(info: &MountInfo) -> Self {
+        let options = &info.options;
+        Self {
+            clone_path: info.clone_path.clone(),
+            mount_point: info.mount_point.clone(),
+            created_at: info.created_at,
+            revision: info.revision.map(|node| format!("{node:x}")),
+            backend_mode: match options.backend_mode {
+                BackendMode::Full => "full",
+                BackendMode::Archive => "archive",
+                BackendMode::Thin => "thin",
+            }
+            .to_string(),
+            session_acl: match options.session_acl {
+                SessionACL::Owner => "owner",
+                SessionACL::RootAndOwner => "root-and-owner",
+                SessionACL::All => "all",
+            }
+            .to_string(),
+            user_id: options.user_id,
+            group_id: options.group_id,
+            max_revisions_loaded: options.max_revisions_loaded,
+            overlay_directory: options.overlay_directory.clone(),
+        }
+    }
+
+    /// The only revision exposed by this mount, if any
+    pub fn revision(&self) -> Result<Option<Node>, HgError> {
+        self.revision
+            .as_ref()
+            .map(|hex| {
+                Node::from_hex(hex).map_err(|_| {
+                    HgError::corrupted(format!(
+                        "invalid revision in the mount registry: {hex}"
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// The options this mount was established with
+    pub fn options(&self) -> Result<MountOptions, HgError> {
+        let invalid = |what: &str, value: &str| {
+            HgError::corrupted(format!(
+                "invalid {what} in the mount registry: {value}"
+            ))
+        };
+        let backend_mode = match self.backend_mode.as_str() {
+            "full" => BackendMode::Full,
+            "archive" => BackendMode::Archive,
+            "thin" => BackendMode::Thin,
+            other => return Err(invalid("backend mode", other)),
+        };
+        let session_acl = match self.session_acl.as_str() {
+            "owner" => SessionACL::Owner,
+            "root-and-owner" => SessionACL::RootAndOwner,
+            "all" => SessionACL::All,
+            other => return Err(invalid("session ACL", other)),
+        };
+        Ok(MountOptions {
+            backend_mode,
+            session_acl,
+            user_id: self.user_id,
+            group_id: self.group_id,
+            max_revisions_loaded: self.max_revisions_loaded,
+            overlay_directory: self.overlay_directory.clone(),
+        })
+    }
+}
+
+/// The contents of the state file
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    version: u32,
+    mounts: Vec<MountRecord>,
+}
+
+/// The state file recording the mounts of a server
+#[derive(Debug, Clone)]
+pub struct MountRegistry {
+    path: PathBuf,
+}
+
+impl MountRegistry {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// The registry of the server listening on `socket_path`, which lives
+    /// next to it
+    pub fn for_socket(socket_path: &Path) -> Self {
+        Self::new(socket_path.with_extension("state.json"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the recorded mounts, if any
+    pub fn load(&self) -> Result<Vec<MountRecord>, HgError> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(vec![]);
+            }
+            Err(e) => return Err(e).when_reading_file(&self.path)?,
+        };
+        let state: State = serde_json::from_slice(&data).map_err(|e| {
+            HgError::corrupted(format!(
+                "invalid mount registry {}: {e}",
+                self.path.display()
+            ))
+        })?;
+        if state.version != STATE_FORMAT_VERSION {
+            return Err(HgError::unsupported(format!(
+                "mount registry {} has unknown version {}",
+                self.path.display(),
+                state.version
+            )));
+        }
+        Ok(state.mounts)
+    }
+
+    /// Replace the recorded mounts with `mounts`, atomically
+    pub fn save(&self, mounts: Vec<MountRecord>) -> Result<(), HgError> {
+        let state = State { version: STATE_FORMAT_VERSION, mounts };
+        let data = serde_json::to_vec_pretty(&state).map_err(|e| {
+            HgError::abort_simple(format!("cannot serialize mounts: {e}"))
+        })?;
+        let directory = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let vfs =
+            VfsImpl::new(directory.to_path_buf(), false, PathEncoding::None);
+        let file_name = self.path.file_name().unwrap_or(self.path.as_os_str());
+        vfs.atomic_write(file_name, &data)?;
+        Ok(())
+    }
+}
+
+/// Returns the mount points of every virtual filesystem in a
+/// `/proc/<pid>/mountinfo` table, whether or not they're served anymore
+fn parse_mount_table(mount_table: &[u8]) -> Vec<PathBuf> {
+    mount_table
+        .split(|b| *b == b'\n')
+        .filter_map(|line| {
+            let fields: Vec<&[u8]> = line.split(|b| *b == b' ').collect();
+            // The optional fields end with a single hyphen, followed by the
+            // filesystem type and its source
+            let separator = fields.iter().position(|f| *f == b"-")?;
+            let fs_type = fields.get(separator + 1)?;
+            let source = fields.get(separator + 2)?;
+            let is_ours = (*fs_type == b"fuse"
+                || fs_type.starts_with(b"fuse."))
+                && *source == FS_NAME;
+            if !is_ours {
+                return None;
+            }
+            let mount_point = fields.get(4)?;
+            Some(PathBuf::from(OsString::from_vec(unescape_octal(mount_point))))
+        })
+        .collect()
+}
+
+/// Undo the `\ooo` escaping of spaces, tabs, newlines and backslashes of
+/// the mount table
+fn unescape_octal(field: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut rest = field;
+    while let Some((&byte, tail)) = rest.split_first() {
+        let digits = tail.get(..3).filter(|d| d.iter().all(u8::is_ascii_digit));
+        match digits {
+            Some(digits) if byte == b'\\' => {
+                let value = digits.iter().fold(0u32, |value, digit| {
+                    value * 8 + u32::from(digit - b'0')
+                });
+                unescaped.push(value as u8);
+                rest = &tail[3..];
+            }
+            _ => {
+                unescaped.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    unescaped
+}
+
+/// Returns the mount points of every virtual filesystem of this system,
+/// whether or not they're served anymore
+pub fn mounted_virtual_filesystems() -> Result<Vec<PathBuf>, HgError> {
+    let path = Path::new("/proc/self/mountinfo");
+    let mount_table = std::fs::read(path).when_reading_file(path)?;
+    Ok(parse_mount_table(&mount_table))
+}
+
+/// Detach the filesystem mounted at `mount_point`, even if it's busy or its
+/// server is gone.
+///
+/// Falls back to `fusermount` if we're not allowed to unmount it ourselves.
+pub fn lazy_unmount(mount_point: &Path) -> std::io::Result<()> {
+    let c_path = CString::new(mount_point.as_os_str().as_bytes())
+        .map_err(std::io::Error::other)?;
+    // SAFETY: `c_path` is a valid NUL-terminated string
+    if unsafe { libc::umount2(c_path.as_ptr(), libc::MNT_DETACH) } == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    if error.raw_os_error() != Some(libc::EPERM) {
+        return Err(error);
+    }
+    for fusermount in ["fusermount3", "fusermount"] {
+        match Command::new(fusermount).arg("-uz").arg(mount_point).status() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => {
+                return Err(std::io::Error::other(format!(
+                    "{fusermount} failed ({status})"
+                )));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount_table() {
+        let mount_table = b"\
+22 1 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:5 - proc proc rw
+43 28 0:39 / /tmp/vs\\040x ro,nosuid,nodev,noatime - fuse hgvfs ro,user_id=0
+44 28 0:40 / /mnt/other rw,relatime shared:7 - fuse.sshfs host:/ rw
+45 28 0:41 / /mnt/share rw,noatime shared:8 master:2 - fuse.hgvfs hgvfs rw
+";
+        assert_eq!(
+            parse_mount_table(mount_table),
+            vec![PathBuf::from("/tmp/vs x"), PathBuf::from("/mnt/share")]
+        );
+    }
+
+    #[test]
+    fn test_unescape_octal() {
+        assert_eq!(unescape_octal(b"/a\\040b\\011c\\134"), b"/a b\tc\\");
+        // Not an escape
+        assert_eq!(unescape_octal(b"/a\\04"), b"/a\\04");
+        assert_eq!(unescape_octal(b"/a\\x40"), b"/a\\x40");
+    }
+
+    #[test]
+    fn test_registry_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry =
+            MountRegistry::for_socket(&dir.path().join("hgfs-control.sock"));
+        assert_eq!(registry.path(), dir.path().join("hgfs-control.state.json"));
+        assert_eq!(registry.load().unwrap(), vec![]);
+
+        let info = MountInfo {
+            clone_path: "/repos/clone".into(),
+            mount_point: "/mnt/clone".into(),
+            created_at: 1234,
+            revision: Some(Node::from_hex("ab".repeat(20)).unwrap()),
+            options: MountOptions {
+                backend_mode: BackendMode::Thin,
+                session_acl: SessionACL::RootAndOwner,
+                user_id: Some(1000),
+                group_id: None,
+                max_revisions_loaded: Some(4),
+                overlay_directory: Some("/scratch".into()),
+            },
+        };
+        let record = MountRecord::new(&info);
+        registry.save(vec![record.clone()]).unwrap();
+        let loaded = registry.load().unwrap();
+        assert_eq!(loaded, vec![record]);
+        assert_eq!(loaded[0].revision().unwrap(), info.revision);
+        let options = loaded[0].options().unwrap();
+        assert!(matches!(options.backend_mode, BackendMode::Thin));
+        assert_eq!(options.session_acl, SessionACL::RootAndOwner);
+        assert_eq!(options.overlay_directory, info.options.overlay_directory);
+    }
+}
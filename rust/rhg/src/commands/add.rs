@@ -0,0 +1,119 @@
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::errors::HgError;
+use hg::exit_codes;
+use hg::operations::PortabilityCheck;
+use hg::operations::add_apply;
+use hg::operations::add_plan;
+use hg::warnings::HgWarningContext;
+
+use crate::error::CommandError;
+use crate::utils::tracking_utils::check_supported;
+use crate::utils::tracking_utils::file_args;
+use crate::utils::tracking_utils::file_matcher;
+use crate::utils::tracking_utils::print_bad;
+use crate::utils::tracking_utils::print_tracking_warnings;
+use crate::utils::tracking_utils::ui_path_fn;
+use crate::utils::tracking_utils::with_wlock;
+
+pub const HELP_TEXT: &str = "
+add the specified files on the next commit
+
+Schedule files to be version controlled and added to the repository.
+
+The files will be added to the repository at the next commit. To undo an add
+before that, see 'hg forget'.
+
+If no names are given, add all files to the repository (except files matching
+\".hgignore\").
+
+Returns 0 if all files are successfully added.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("add")
+        .arg(
+            Arg::new("dry-run")
+                .help("do not perform actions, just print output")
+                .short('n')
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("enable additional output")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file")
+                .value_parser(clap::value_parser!(std::ffi::OsString))
+                .help("files to add")
+                .action(clap::ArgAction::Append),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg add")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    let dry_run = args.get_flag("dry-run");
+    let verbose =
+        args.get_flag("verbose") || config.get_bool(b"ui", b"verbose")?;
+    let patterns = file_args(args);
+
+    let repo = invocation.repo?;
+    check_supported(repo, &patterns)?;
+    let matcher = file_matcher(repo, patterns)?;
+    let ui_path = ui_path_fn(repo, true)?;
+    let portability = PortabilityCheck::from_config(config)?;
+
+    let failed = with_wlock(repo, || {
+        let mut dmap = repo.dirstate_map_mut()?;
+        let plan = add_plan(repo, &mut dmap, &matcher)?;
+        print_bad(ui, &plan.bad, &ui_path)?;
+        for entry in &plan.entries {
+            if entry.case_collision {
+                let message = format_bytes!(
+                    b"possible case-folding collision for {}",
+                    entry.path.as_bytes()
+                );
+                if portability == PortabilityCheck::Abort {
+                    return Err(HgError::abort(
+                        String::from_utf8_lossy(&message),
+                        exit_codes::STATE_ERROR,
+                        None,
+                    )
+                    .into());
+                }
+                ui.write_stderr(&format_bytes!(b"warning: {}\n", message))?;
+            }
+            if verbose || !entry.exact {
+                ui.write_stdout(&format_bytes!(
+                    b"adding {}\n",
+                    ui_path(&entry.path)
+                ))?;
+            }
+        }
+        let mut failed = !plan.bad.is_empty();
+        if dry_run {
+            return Ok(failed);
+        }
+        let warnings = HgWarningContext::new();
+        let rejected = add_apply(repo, &mut dmap, &plan, warnings.sender());
+        print_tracking_warnings(ui, warnings, repo, &ui_path);
+        failed |= rejected?.iter().any(|path| matcher.exact_match(path));
+        drop(dmap); // Avoid "already mutably borrowed" RefCell panics
+        repo.write_dirstate()?;
+        Ok(failed)
+    })?;
+    if failed {
+        Err(CommandError::Unsuccessful)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::exit_codes;
+use hg::operations::AddRemoveAction;
+use hg::operations::addremove_apply;
+use hg::operations::addremove_plan;
+use hg::warnings::HgWarningContext;
+
+use crate::error::CommandError;
+use crate::utils::tracking_utils::check_supported;
+use crate::utils::tracking_utils::file_args;
+use crate::utils::tracking_utils::file_matcher;
+use crate::utils::tracking_utils::print_bad;
+use crate::utils::tracking_utils::print_tracking_warnings;
+use crate::utils::tracking_utils::ui_path_fn;
+use crate::utils::tracking_utils::with_wlock;
+
+pub const HELP_TEXT: &str = "
+add all new files, delete all missing files
+
+Add all new files and remove all missing files from the repository.
+
+Unless names are given, new files are ignored if they match any of the
+patterns in \".hgignore\". As with add, these changes take effect at the
+next commit.
+
+Use the -s/--similarity option to detect renamed files. This option takes a
+percentage between 0 (disabled) and 100 (files must be identical) as its
+parameter. With a parameter greater than 0, this compares every removed file
+with every added file and records those similar enough as renames.
+
+Returns 0 if all files are successfully added.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("addremove")
+        .arg(
+            Arg::new("similarity")
+                .help("guess renamed files by similarity (0<=s<=100)")
+                .short('s')
+                .long("similarity")
+                .value_name("SIMILARITY"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .help("do not perform actions, just print output")
+                .short('n')
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("enable additional output")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file")
+                .value_parser(clap::value_parser!(std::ffi::OsString))
+                .help("files to add or remove")
+                .action(clap::ArgAction::Append),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg addremove")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    let similarity = match args.get_one::<String>("similarity") {
+        Some(s) if !s.is_empty() => s.trim().parse::<f64>().map_err(|_| {
+            CommandError::abort_with_exit_code(
+                "abort: similarity must be a number",
+                exit_codes::INPUT_ERROR,
+            )
+        })?,
+        _ => 100.0,
+    };
+    if !(0.0..=100.0).contains(&similarity) {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: similarity must be between 0 and 100",
+            exit_codes::INPUT_ERROR,
+        ));
+    }
+    let dry_run = args.get_flag("dry-run");
+    let verbose =
+        args.get_flag("verbose") || config.get_bool(b"ui", b"verbose")?;
+    let patterns = file_args(args);
+
+    let repo = invocation.repo?;
+    check_supported(repo, &patterns)?;
+    // Paths are only relative by default when files were given
+    let ui_path = ui_path_fn(repo, !patterns.is_empty())?;
+    let matcher = file_matcher(repo, patterns)?;
+
+    let failed = with_wlock(repo, || {
+        let mut dmap = repo.dirstate_map_mut()?;
+        let plan =
+            addremove_plan(repo, &mut dmap, &matcher, similarity / 100.0)?;
+        print_bad(ui, &plan.bad, &ui_path)?;
+        for entry in &plan.entries {
+            if verbose || !entry.exact {
+                let action: &[u8] = match entry.action {
+                    AddRemoveAction::Add => b"adding",
+                    AddRemoveAction::Remove => b"removing",
+                };
+                ui.write_stdout(&format_bytes!(
+                    b"{} {}\n",
+                    action,
+                    ui_path(&entry.path)
+                ))?;
+            }
+        }
+        for rename in &plan.renames {
+            if verbose
+                || !matcher.exact_match(&rename.source)
+                || !matcher.exact_match(&rename.dest)
+            {
+                ui.write_stdout(&format_bytes!(
+                    b"recording removal of {} as rename to {} \
+                      ({}% similar)\n",
+                    ui_path(&rename.source),
+                    ui_path(&rename.dest),
+                    ((rename.score * 100.0) as u32).to_string().as_bytes()
+                ))?;
+            }
+        }
+        let failed = plan.bad.iter().any(|(path, _)| matcher.exact_match(path));
+        if dry_run || (plan.entries.is_empty() && plan.renames.is_empty()) {
+            return Ok(failed);
+        }
+        let warnings = HgWarningContext::new();
+        let result = addremove_apply(repo, &mut dmap, &plan, warnings.sender());
+        print_tracking_warnings(ui, warnings, repo, &ui_path);
+        result?;
+        drop(dmap); // Avoid "already mutably borrowed" RefCell panics
+        repo.write_dirstate()?;
+        Ok(failed)
+    })?;
+    if failed {
+        Err(CommandError::Unsuccessful)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::ffi::OsString;
+
+use clap::Arg;
+use hg::NULL_REVISION;
+use hg::errors::HgError;
+use hg::exit_codes;
+use hg::narrow;
+use hg::operations::ArchiveKind;
+use hg::operations::ArchiveOptions;
+use hg::operations::archive;
+use hg::operations::archive_prefix;
+use hg::utils::files::get_bytes_from_os_str;
+use hg::utils::files::get_path_from_bytes;
+use hg::warnings::HgWarningContext;
+
+use crate::error::CommandError;
+use crate::ui::print_warnings;
+use crate::utils::path_utils::include_exclude_args;
+use crate::utils::path_utils::include_exclude_matcher;
+
+pub const HELP_TEXT: &str = "
+create an unversioned archive of a repository revision
+
+By default, the revision used is the parent of the working directory; use
+-r/--rev to specify a different revision.
+
+The archive type is automatically detected based on file extension (to
+override, use -t/--type).
+
+Valid types are:
+
+files   a directory full of files (default)
+tar     tar archive, uncompressed
+tbz2    tar archive, compressed using bzip2
+tgz     tar archive, compressed using gzip
+tzst    tar archive, compressed using zstd (never detected from the extension)
+uzip    zip archive, uncompressed
+zip     zip archive, compressed using deflate
+
+Each member added to an archive file has a directory prefix prepended. Use
+-p/--prefix to specify a prefix. The default is the basename of the archive,
+with suffixes removed.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    include_exclude_args(
+        clap::command!("archive")
+            .args_override_self(true)
+            .arg(
+                Arg::new("no-decode")
+                    .help("do not pass files through decoders")
+                    .long("no-decode")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("prefix")
+                    .help("directory prefix for files in archive")
+                    .short('p')
+                    .long("prefix")
+                    .value_name("PREFIX")
+                    .value_parser(clap::value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("rev")
+                    .help("revision to distribute")
+                    .short('r')
+                    .long("rev")
+                    .value_name("REV"),
+            )
+            .arg(
+                Arg::new("type")
+                    .help("type of distribution to create")
+                    .short('t')
+                    .long("type")
+                    .value_name("TYPE")
+                    .value_parser(clap::value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("subrepos")
+                    .help("recurse into subrepositories")
+                    .short('S')
+                    .long("subrepos")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("dest")
+                    .help("destination of the archive")
+                    .value_name("DEST")
+                    .required(true)
+                    .value_parser(clap::value_parser!(OsString)),
+            ),
+    )
+    .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg archive")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    let dest = get_bytes_from_os_str(
+        args.get_one::<OsString>("dest").expect("dest is required"),
+    );
+    let prefix = args.get_one::<OsString>("prefix").map(get_bytes_from_os_str);
+    if dest.contains(&b'%')
+        || prefix.as_ref().is_some_and(|p| p.contains(&b'%'))
+    {
+        return Err(CommandError::unsupported("archive format strings"));
+    }
+    if dest == b"-" {
+        return Err(CommandError::unsupported("archiving to stdout"));
+    }
+    if args.get_flag("subrepos") {
+        return Err(CommandError::unsupported("archiving sub-repositories"));
+    }
+    if config.get(b"experimental", b"archivemetatemplate").is_some() {
+        return Err(CommandError::unsupported(
+            "experimental.archivemetatemplate",
+        ));
+    }
+
+    let repo = invocation.repo?;
+    if repo.hg_vfs().join("localtags").exists() {
+        return Err(CommandError::unsupported("local tags"));
+    }
+    let rev = args.get_one::<String>("rev").map_or(".", |rev| rev);
+    let Some(rev) = hg::revset::resolve_single(rev, repo)?.exclude_wdir()
+    else {
+        return Err(CommandError::unsupported("archiving the working copy"));
+    };
+    if rev == NULL_REVISION {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: no working directory: please specify a revision",
+            exit_codes::INPUT_ERROR,
+        ));
+    }
+    let cwd = hg::utils::current_dir()?;
+    let dest_path = cwd.join(get_path_from_bytes(&dest));
+    if let (Ok(dest), Ok(root)) =
+        (dest_path.canonicalize(), repo.working_directory_path().canonicalize())
+        && dest == root
+    {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: repository root cannot be destination",
+            exit_codes::INPUT_ERROR,
+        ));
+    }
+
+    let kind = match args.get_one::<OsString>("type") {
+        Some(kind) => {
+            let kind = get_bytes_from_os_str(kind);
+            ArchiveKind::from_name(&kind).ok_or_else(|| {
+                HgError::abort(
+                    format!(
+                        "unknown archive type '{}'",
+                        String::from_utf8_lossy(&kind)
+                    ),
+                    exit_codes::ABORT,
+                    None,
+                )
+            })?
+        }
+        None => ArchiveKind::guess(&dest).unwrap_or(ArchiveKind::Files),
+    };
+    let prefix = archive_prefix(&dest, kind, prefix.as_deref())?;
+
+    let warnings = HgWarningContext::new();
+    let narrow_matcher = narrow::matcher(repo, warnings.sender())?;
+    print_warnings(invocation.ui, warnings, repo.working_directory_path());
+    let matcher = include_exclude_matcher(repo, args, narrow_matcher)?;
+    let options = ArchiveOptions {
+        kind,
+        prefix,
+        metadata: config.get_bool(b"ui", b"archivemeta")?,
+    };
+    archive(repo, rev, &matcher, &dest_path, &options)?;
+    Ok(())
+}
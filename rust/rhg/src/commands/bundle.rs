@@ -0,0 +1,323 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::Graph;
+use hg::NULL_REVISION;
+use hg::Revision;
+use hg::bundle::BundleSpec;
+use hg::bundle::write_bundle;
+use hg::bundle::write_stream_bundle;
+use hg::dagops;
+use hg::discovery::Outgoing;
+use hg::errors::HgError;
+use hg::errors::IoResultExt;
+use hg::exit_codes;
+use hg::lock::LockError;
+use hg::repo::Repo;
+use hg::revlog::RevlogError;
+use hg::stream_clone::StreamGenerator;
+use hg::stream_clone::StreamVersion;
+use hg::stream_clone::check_generation;
+
+use crate::error::CommandError;
+
+pub const HELP_TEXT: &str = "
+create a bundle file
+
+Generate a bundle file containing data to be transferred to another
+repository.
+
+To create a bundle containing all changesets, use -a/--all (or --base
+null). Otherwise, hg assumes the destination will have all the nodes you
+specify with --base parameters.
+
+You can change bundle format with the -t/--type option. See 'hg help
+bundlespec' for documentation on this format. By default, the most
+appropriate format is used and compression defaults to bzip2.
+
+Returns 0 on success, 1 if no changes found.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("bundle")
+        .args_override_self(true)
+        .arg(
+            Arg::new("file")
+                .help("the bundle file to write")
+                .required(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::new("dest")
+                .help("the repositories assumed to be the destination")
+                .num_args(0..)
+                .value_name("DEST"),
+        )
+        .arg(
+            Arg::new("rev")
+                .help("a changeset intended to be added to the destination")
+                .short('r')
+                .long("rev")
+                .value_name("REV")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("base")
+                .help("a base changeset assumed to be available at the destination")
+                .long("base")
+                .value_name("REV")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("all")
+                .help("bundle all changesets in the repository")
+                .short('a')
+                .long("all")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("type")
+                .help("bundle compression type to use")
+                .short('t')
+                .long("type")
+                .value_name("TYPE")
+                .default_value("bzip2"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("suppress output")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg bundle")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let repo = invocation.repo?;
+    let file = args
+        .get_one::<String>("file")
+        .expect("file should be a required argument");
+    let revs: Vec<&String> =
+        args.get_many("rev").map(Iterator::collect).unwrap_or_default();
+    let bases: Vec<&String> =
+        args.get_many("base").map(Iterator::collect).unwrap_or_default();
+    let all = args.get_flag("all");
+    let quiet = args.get_flag("quiet") || config.get_bool(b"ui", b"quiet")?;
+
+    if args.get_many::<String>("dest").is_some() {
+        if all {
+            return Err(CommandError::abort_with_exit_code(
+                "abort: --all is incompatible with specifying destinations",
+                exit_codes::INPUT_ERROR,
+            ));
+        }
+        if !bases.is_empty() {
+            return Err(CommandError::abort_with_exit_code(
+                "abort: --base is incompatible with specifying destinations",
+                exit_codes::INPUT_ERROR,
+            ));
+        }
+    }
+    if !all && bases.is_empty() {
+        return Err(CommandError::unsupported(
+            "bundle against a destination repository",
+        ));
+    }
+    check_supported(repo)?;
+
+    let bundle_type = args
+        .get_one::<String>("type")
+        .expect("type has a default value")
+        .to_lowercase();
+    let mut spec = BundleSpec::parse(&bundle_type, repo.requirements())
+        .map_err(|error| match error {
+            HgError::Abort {
+                message,
+                detailed_exit_code: exit_codes::INPUT_ERROR,
+                backtrace,
+                ..
+            } => HgError::Abort {
+                message,
+                detailed_exit_code: exit_codes::INPUT_ERROR,
+                hint: Some(
+                    "see 'hg help bundlespec' for supported values for --type"
+                        .to_owned(),
+                ),
+                backtrace,
+            },
+            error => error,
+        })?;
+    if spec.changegroup_version().is_none() {
+        return Err(HgError::abort(
+            "packed bundles cannot be produced by \"hg bundle\"",
+            exit_codes::INPUT_ERROR,
+            Some("use 'hg debugcreatestreamclonebundle'".to_owned()),
+        )
+        .into());
+    }
+
+    let base = if all {
+        if !bases.is_empty() {
+            ui.write_stderr(b"ignoring --base because --all was specified\n")?;
+        }
+        vec![]
+    } else {
+        resolve_revs(repo, &bases)?
+    };
+    let heads = resolve_revs(repo, &revs)?;
+
+    let changelog = repo.changelog()?;
+    let index = changelog.get_index();
+    let heads = if revs.is_empty() {
+        index.head_revs().map_err(RevlogError::from)?
+    } else {
+        heads
+    };
+    let missing =
+        Outgoing::new(index, base, heads).map_err(RevlogError::from)?.missing;
+    if missing.is_empty() {
+        if !quiet {
+            ui.write_stdout(b"no changes found\n")?;
+        }
+        return Err(CommandError::Unsuccessful);
+    }
+
+    // What is sent is described by the changesets themselves, like with
+    // `outgoing(missingroots=...)` in Python
+    let mut common_heads = vec![];
+    for &rev in &missing {
+        for parent in index.parents(rev).map_err(RevlogError::from)? {
+            if parent != NULL_REVISION
+                && missing.binary_search(&parent).is_err()
+            {
+                common_heads.push(parent);
+            }
+        }
+    }
+    let mut heads: Vec<Revision> = dagops::heads(index, missing.iter())
+        .map_err(RevlogError::from)?
+        .into_iter()
+        .collect();
+    heads.sort_unstable();
+    let outgoing =
+        Outgoing::new(index, common_heads, heads).map_err(RevlogError::from)?;
+
+    let compression = spec.compression.spec_name();
+    let level_key = format!("bundlecomplevel.{}", compression);
+    let level = match config.get_i64(b"experimental", level_key.as_bytes())? {
+        Some(level) => Some(level),
+        None => config.get_i64(b"experimental", b"bundlecomplevel")?,
+    };
+    let level = level
+        .map(|level| {
+            i32::try_from(level).map_err(|_| {
+                CommandError::abort(format!(
+                    "abort: invalid {} compression level: {}",
+                    compression, level
+                ))
+            })
+        })
+        .transpose()?;
+    spec.set_default_flag(
+        "obsolescence",
+        config.get_bool(b"experimental", b"evolution.bundle-obsmarker")?,
+    );
+    if !spec.flag("phases") {
+        spec.set_default_flag(
+            "phases",
+            config.get_bool(b"experimental", b"bundle-phases")?,
+        );
+    }
+
+    let path = Path::new(file);
+    if let Some(version) = spec.params.get("stream") {
+        // Stream clones copy the whole store, whatever the changesets
+        let Some(version) = StreamVersion::from_name(version) else {
+            return Err(CommandError::unsupported(format!(
+                "stream clone version {}",
+                version
+            )));
+        };
+        check_generation(repo, version)?;
+        let generator = match repo
+            .try_with_lock_no_wait(|| StreamGenerator::new(repo, false))
+        {
+            Ok(generator) => generator?,
+            Err(LockError::AlreadyHeld) => {
+                return Err(CommandError::unsupported(
+                    "waiting for the store lock",
+                ));
+            }
+            Err(LockError::IO(error)) => return Err(error.into()),
+        };
+        let out = File::create(path).when_writing_file(path)?;
+        let result =
+            write_stream_bundle(repo, &generator, version, BufWriter::new(out))
+                .and_then(|mut out| Ok(out.flush().when_writing_file(path)?));
+        if let Err(error) = result {
+            let _ = std::fs::remove_file(path);
+            return Err(error.into());
+        }
+        return Ok(());
+    }
+
+    if !quiet {
+        ui.write_stdout(&format_bytes!(
+            b"{} changesets found\n",
+            outgoing.missing.len()
+        ))?;
+    }
+    let out = File::create(path).when_writing_file(path)?;
+    let result =
+        write_bundle(repo, &outgoing, &spec, level, BufWriter::new(out))
+            .and_then(|mut out| Ok(out.flush().when_writing_file(path)?));
+    if let Err(error) = result {
+        // Like in Python, do not leave a truncated bundle behind
+        let _ = std::fs::remove_file(path);
+        return Err(error.into());
+    }
+    Ok(())
+}
+
+/// Falls back to Python for the repositories rhg cannot bundle from
+fn check_supported(repo: &Repo) -> Result<(), CommandError> {
+    // Changegroups of narrow repositories need revlog flags
+    if repo.has_narrow() {
+        return Err(CommandError::unsupported("bundle in a narrow repository"));
+    }
+    // Obsolete changesets are hidden, which rhg does not know about
+    let obsstore = repo.store_vfs().join("obsstore");
+    if std::fs::metadata(&obsstore).is_ok_and(|meta| meta.len() > 0) {
+        return Err(CommandError::unsupported("obsolescence markers"));
+    }
+    Ok(())
+}
+
+/// Resolves each of `revs` to a changeset
+fn resolve_revs(
+    repo: &Repo,
+    revs: &[&String],
+) -> Result<Vec<Revision>, CommandError> {
+    let mut resolved = vec![];
+    for rev in revs {
+        match hg::revset::resolve_single(rev, repo)?.exclude_wdir() {
+            Some(NULL_REVISION) => {}
+            Some(rev) => resolved.push(rev),
+            None => {
+                return Err(CommandError::unsupported(
+                    "bundling the working directory",
+                ));
+            }
+        }
+    }
+    Ok(resolved)
+}
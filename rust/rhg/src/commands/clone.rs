@@ -0,0 +1,714 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::NULL_NODE;
+use hg::Node;
+use hg::Revision;
+use hg::bookmarks::bookmarks_path;
+use hg::bookmarks::read_bookmarks;
+use hg::branchmap::BranchMap;
+use hg::changegroup::ChangegroupUnpacker;
+use hg::changegroup::ChangegroupVersion;
+use hg::changegroup::write_changegroup;
+use hg::config::Config;
+use hg::dirstate::on_disk::write_tracked_key;
+use hg::discovery::Outgoing;
+use hg::errors::HgError;
+use hg::errors::HgIoError;
+use hg::errors::IoErrorContext;
+use hg::errors::IoResultExt;
+use hg::exit_codes;
+use hg::lock::LockError;
+use hg::progress::HgProgressBar;
+use hg::progress::Progress;
+use hg::repo::Repo;
+use hg::requirements;
+use hg::revlog::RevlogError;
+use hg::revlog::index::Phase;
+use hg::store::Store;
+use hg::stream_clone::StreamGenerator;
+use hg::transaction::StoreTransaction;
+use hg::transaction::cleanup_undo_files;
+use hg::update::update_from_null;
+use hg::utils::files::get_bytes_from_path;
+use hg::utils::files::normalize_path;
+use hg::warnings::HgWarningContext;
+
+use crate::commands::unbundle;
+use crate::commands::unbundle::Transaction;
+use crate::commands::unbundle::Unbundling;
+use crate::error::CommandError;
+use crate::ui::Ui;
+use crate::ui::print_warnings;
+use crate::utils::hooks::Hooks;
+use crate::utils::update_utils::WorkingCopyWrite;
+use crate::utils::update_utils::update_config;
+
+pub const HELP_TEXT: &str = "
+make a copy of an existing repository
+
+Create a copy of an existing repository in a new directory.
+
+If no destination directory name is specified, it defaults to the
+basename of the source.
+
+The location of the source is added to the new repository's
+.hg/hgrc file, as the default to be used for future pulls.
+
+The store of the source is hardlinked when possible, and copied otherwise.
+With --pull or -r/--rev, the changesets are added one by one instead.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("clone")
+        .args_override_self(true)
+        .arg(
+            Arg::new("source")
+                .help("the repository to copy")
+                .required(true)
+                .value_name("SOURCE"),
+        )
+        .arg(
+            Arg::new("dest")
+                .help("the directory of the copy")
+                .value_name("DEST"),
+        )
+        .arg(
+            Arg::new("noupdate")
+                .help(
+                    "the clone will include an empty working directory \
+                     (only a repository)",
+                )
+                .short('U')
+                .long("noupdate")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("updaterev")
+                .help("revision, tag, or branch to check out")
+                .short('u')
+                .long("updaterev")
+                .value_name("REV"),
+        )
+        .arg(
+            Arg::new("rev")
+                .help(
+                    "do not clone everything, but include this changeset \
+                     and its ancestors",
+                )
+                .short('r')
+                .long("rev")
+                .value_name("REV")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("branch")
+                .help(
+                    "do not clone everything, but include this branch's \
+                     changesets and their ancestors",
+                )
+                .short('b')
+                .long("branch")
+                .value_name("BRANCH")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("pull")
+                .help("use pull protocol to copy metadata")
+                .long("pull")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stream")
+                .help("clone with minimal data processing")
+                .long("stream")
+                .visible_alias("uncompressed")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT)
+}
+
+/// The hooks run in the new repository by the transaction of a local copy
+const COPY_HOOK_TYPES: &[&[u8]] = &[b"pretxnopen", b"pretxnclose", b"txnclose"];
+
+/// The hooks run when checking out the new working directory, which rhg
+/// does not run
+const UPDATE_HOOK_TYPES: &[&[u8]] = &[b"preupdate", b"update"];
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg clone")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let source: &String =
+        args.get_one("source").expect("source should be a required argument");
+    let no_update = args.get_flag("noupdate");
+    let update_rev = args.get_one::<String>("updaterev");
+    if no_update && update_rev.is_some() {
+        return Err(HgError::abort(
+            "cannot specify both --noupdate and --updaterev",
+            exit_codes::INPUT_ERROR,
+            None,
+        )
+        .into());
+    }
+    let revs: Vec<&String> =
+        args.get_many("rev").map(Iterator::collect).unwrap_or_default();
+    if args.get_many::<String>("branch").is_some() {
+        return Err(CommandError::unsupported("clone --branch"));
+    }
+    let pull = args.get_flag("pull") || !revs.is_empty();
+    if args.get_flag("stream") && pull {
+        return Err(CommandError::unsupported("stream clone with pull"));
+    }
+    let quiet = config.get_bool(b"ui", b"quiet")?;
+
+    let source_path = local_path(config, source)?;
+    let source_repo = Repo::find(config, Some(source_path.clone()))?;
+    check_source(&source_repo)?;
+    let dest = match args.get_one::<String>("dest") {
+        Some(dest) => local_path(config, dest)?,
+        None => {
+            let dest = default_dest(&source_path);
+            if !dest.as_os_str().is_empty() && !quiet {
+                ui.write_stdout(&format_bytes!(
+                    b"destination directory: {}\n",
+                    get_bytes_from_path(&dest)
+                ))?;
+            }
+            dest
+        }
+    };
+    if dest.as_os_str().is_empty() {
+        return Err(HgError::abort(
+            "empty destination path is not valid",
+            exit_codes::INPUT_ERROR,
+            None,
+        )
+        .into());
+    }
+    check_dest(&dest)?;
+    if !pull {
+        check_no_hooks(config, COPY_HOOK_TYPES)?;
+    }
+    if !no_update {
+        check_update_hooks(config)?;
+    }
+
+    // Only remove what was created, should anything fail
+    let cleanup = if dest.exists() {
+        dest.join(".hg")
+    } else {
+        dest.to_owned()
+    };
+    let result = (|| {
+        let dest_repo = if pull {
+            let heads = revs
+                .iter()
+                .map(|rev| resolve(&source_repo, rev))
+                .collect::<Result<Vec<_>, _>>()?;
+            pull_clone(ui, config, &source_repo, &dest, &heads, quiet)?
+        } else {
+            local_copy(ui, config, &source_repo, &dest)?
+        };
+        write_hgrc(&dest_repo, &source_path)?;
+        // Written by transactions in Python, which also hold the working
+        // copy lock of a clone
+        let hg_vfs = dest_repo.hg_vfs();
+        if hg_vfs.try_read("branch")?.is_none() {
+            hg_vfs.atomic_write("branch", b"default\n")?;
+        }
+        if no_update {
+            return Ok(());
+        }
+        let checkout = match update_rev {
+            Some(rev) => Some(resolve(&source_repo, rev)?),
+            None => revs
+                .first()
+                .map(|rev| resolve(&source_repo, rev))
+                .transpose()?,
+        };
+        let target = update_target(&dest_repo, checkout)?;
+        checkout_new(ui, &dest_repo, target, quiet, |branch| {
+            format_bytes!(b"updating to branch {}\n", branch)
+        })
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&cleanup);
+    }
+    result
+}
+
+/// The path of the local repository at `location`, falling back for the
+/// other kinds of locations
+pub(crate) fn local_path(
+    config: &Config,
+    location: &str,
+) -> Result<PathBuf, CommandError> {
+    let scheme =
+        location.split_once(':').map(|(scheme, _)| scheme).filter(|scheme| {
+            !scheme.is_empty()
+                && scheme.bytes().all(|byte| {
+                    byte.is_ascii_alphanumeric() || b"+.-".contains(&byte)
+                })
+        });
+    if scheme.is_some() || location.contains('#') {
+        return Err(CommandError::unsupported(format!(
+            "non-local location {}",
+            location
+        )));
+    }
+    if config.get(b"paths", location.as_bytes()).is_some() {
+        return Err(CommandError::unsupported("path aliases"));
+    }
+    Ok(PathBuf::from(location))
+}
+
+/// The directory of a clone of `source` with no explicit destination, like
+/// `default_dest` in Python
+pub(crate) fn default_dest(source: &Path) -> PathBuf {
+    normalize_path(source).file_name().map(PathBuf::from).unwrap_or_default()
+}
+
+/// Falls back to Python when checking out a new working directory would run
+/// hooks
+pub(crate) fn check_update_hooks(config: &Config) -> Result<(), CommandError> {
+    check_no_hooks(config, UPDATE_HOOK_TYPES)
+}
+
+/// Falls back to Python if any hook of `hook_types` is configured
+pub(crate) fn check_no_hooks(
+    config: &Config,
+    hook_types: &[&[u8]],
+) -> Result<(), CommandError> {
+    let hooks = Hooks::new(config, hook_types)?;
+    for hook_type in hook_types {
+        if hooks.has(hook_type) {
+            return Err(CommandError::unsupported(format!(
+                "{} hook",
+                String::from_utf8_lossy(hook_type)
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Falls back to Python for what rhg cannot clone
+fn check_source(source: &Repo) -> Result<(), CommandError> {
+    if source.has_narrow() {
+        return Err(CommandError::unsupported("narrow clones"));
+    }
+    if source.has_fileindex() {
+        return Err(CommandError::unsupported("clone with a file index"));
+    }
+    let obsstore = source.store_vfs().join("obsstore");
+    if std::fs::metadata(&obsstore).is_ok_and(|meta| meta.len() > 0) {
+        return Err(CommandError::unsupported("obsolescence markers"));
+    }
+    // Python leaves them out, only sending what is served
+    if source.store().phase_cache()?.has_secret() {
+        return Err(CommandError::unsupported("clone with secret changesets"));
+    }
+    Ok(())
+}
+
+/// Checks that the repository can be created in `dest`
+fn check_dest(dest: &Path) -> Result<(), CommandError> {
+    if std::fs::symlink_metadata(dest).is_err() {
+        return Ok(());
+    }
+    let message = if !dest.is_dir() {
+        "already exists"
+    } else if std::fs::read_dir(dest).when_reading_file(dest)?.next().is_some()
+    {
+        "is not empty"
+    } else {
+        return Ok(());
+    };
+    Err(HgError::abort(
+        format!("destination '{}' {}", dest.display(), message),
+        exit_codes::INPUT_ERROR,
+        None,
+    )
+    .into())
+}
+
+/// Resolves `rev` in the source of a clone
+fn resolve(source: &Repo, rev: &str) -> Result<Node, CommandError> {
+    let rev = hg::revset::resolve_single(rev, source)?;
+    match rev.exclude_wdir() {
+        Some(rev) if rev != hg::NULL_REVISION => {
+            Ok(*source.changelog()?.node_from_rev(rev))
+        }
+        _ => Err(CommandError::unsupported("cloning to the null revision")),
+    }
+}
+
+/// Creates `dest` with a copy of the store of `source`, like `local_copy`
+/// in Python
+fn local_copy(
+    ui: &Ui,
+    config: &Config,
+    source: &Repo,
+    dest: &Path,
+) -> Result<Repo, CommandError> {
+    let hooks = Hooks::new(source.config(), &[b"preoutgoing", b"outgoing"])?;
+    let source_root = source.working_directory_path();
+    let result = source.try_with_lock_no_wait(|| -> Result<_, CommandError> {
+        hooks.run(
+            ui,
+            source_root,
+            b"preoutgoing",
+            &[("source", b"clone".to_vec())],
+            true,
+            || Ok(false),
+        )?;
+        let requirements =
+            requirements::clone_requirements(config, source.requirements())?;
+        let dest_repo = Repo::create(config, dest, &requirements, None)?;
+        let copied =
+            dest_repo.try_with_lock_no_wait(|| copy_store(source, &dest_repo));
+        match copied {
+            Ok(result) => result?,
+            Err(LockError::AlreadyHeld) => {
+                return Err(CommandError::unsupported(
+                    "waiting for the store lock",
+                ));
+            }
+            Err(LockError::IO(error)) => return Err(error.into()),
+        }
+        Ok(dest_repo)
+    });
+    let dest_repo = match result {
+        Ok(dest_repo) => dest_repo?,
+        Err(LockError::AlreadyHeld) => {
+            return Err(CommandError::unsupported(
+                "waiting for the store lock",
+            ));
+        }
+        Err(LockError::IO(error)) => return Err(error.into()),
+    };
+    hooks.run(
+        ui,
+        source_root,
+        b"outgoing",
+        &[
+            ("source", b"clone".to_vec()),
+            ("node", format!("{:x}", NULL_NODE).into_bytes()),
+        ],
+        false,
+        || Ok(false),
+    )?;
+    Ok(dest_repo)
+}
+
+/// Copies the store and the bookmarks of `source` into the new `dest`, with
+/// both store locks held
+fn copy_store(source: &Repo, dest: &Repo) -> Result<(), CommandError> {
+    let generator = StreamGenerator::new(source, true)?;
+    let mut fncache =
+        if dest.requirements().contains(requirements::FNCACHE_REQUIREMENT) {
+            Some(Store::new(dest).fncache()?)
+        } else {
+            None
+        };
+    generator.copy_to(dest, fncache.as_mut())?;
+    // Not part of the store, they are not streamed
+    let (from, to) = (bookmarks_path(source)?, bookmarks_path(dest)?);
+    if from.exists() {
+        std::fs::copy(&from, &to).when_writing_file(&to)?;
+    }
+    if let Some(fncache) = &mut fncache {
+        let mut tr = StoreTransaction::open(dest, "localclone")?;
+        fncache.write(&mut tr)?;
+        tr.close()?;
+    }
+    // Undoing the copy makes no sense
+    cleanup_undo_files(dest)?;
+    Ok(())
+}
+
+/// Creates `dest` with the changesets of `source` that are ancestors of
+/// `heads`, or all of them, like `exchange.pull` for a clone in Python
+fn pull_clone(
+    ui: &Ui,
+    config: &Config,
+    source: &Repo,
+    dest: &Path,
+    heads: &[Node],
+    quiet: bool,
+) -> Result<Repo, CommandError> {
+    if std::fs::metadata(bookmarks_path(source)?).is_ok_and(|m| m.len() > 0) {
+        return Err(CommandError::unsupported("pulling bookmarks"));
+    }
+    let requirements = requirements::new_repository_requirements(config)?;
+    let dest_repo = Repo::create(config, dest, &requirements, None)?;
+    unbundle::check_supported(&dest_repo)?;
+    let hooks = unbundle::hooks(dest_repo.config())?;
+
+    let changelog = source.changelog()?;
+    let index = changelog.get_index();
+    let head_revs = if heads.is_empty() {
+        if !quiet {
+            ui.write_stdout(b"requesting all changes\n")?;
+        }
+        index.head_revs().map_err(RevlogError::from)?
+    } else {
+        heads
+            .iter()
+            .map(|node| changelog.rev_from_node((*node).into()))
+            .collect::<Result<_, _>>()?
+    };
+    let outgoing =
+        Outgoing::new(index, vec![], head_revs).map_err(RevlogError::from)?;
+    let phase_heads = pulled_phase_heads(source, &outgoing)?;
+    drop(changelog);
+    let version = ChangegroupVersion::V2;
+    let url = format!(
+        "file:{}",
+        std::fs::canonicalize(source.working_directory_path())
+            .when_reading_file(source.working_directory_path())?
+            .display()
+    );
+
+    let mut after_lock = vec![];
+    let result =
+        dest_repo.try_with_lock_no_wait(|| -> Result<_, CommandError> {
+            let mut unbundling = Unbundling {
+                ui,
+                repo: &dest_repo,
+                hooks: &hooks,
+                quiet,
+                after_lock: &mut after_lock,
+            };
+            let mut tr =
+                Transaction::open(&mut unbundling, &format!("pull\n{}", url))?;
+            tr.set_hook_arg("source", b"pull");
+            tr.set_hook_arg("url", url.as_bytes());
+            let applied = std::thread::scope(|scope| {
+                let (reader, mut writer) =
+                    std::io::pipe().map_err(|error| {
+                        HgError::from(HgIoError::from_os_error(
+                            error,
+                            IoErrorContext::WritingStream,
+                        ))
+                    })?;
+                let writing = scope.spawn(move || {
+                    write_changegroup(
+                        source,
+                        &outgoing,
+                        version,
+                        false,
+                        &mut writer,
+                    )
+                });
+                let mut unpacker = ChangegroupUnpacker::new(reader, version);
+                let applied = tr.apply_changegroup(&mut unpacker, Phase::Draft);
+                // Unblocks the writer if applying stopped early
+                drop(unpacker);
+                let written =
+                    writing.join().expect("changegroup writer panicked");
+                applied?;
+                written?;
+                tr.update_phases(&phase_heads)
+            });
+            match applied {
+                Ok(()) => tr.close(),
+                Err(error) => Err(tr.abort(error)),
+            }
+        });
+    let result = match result {
+        Ok(result) => result,
+        Err(LockError::AlreadyHeld) => {
+            Err(CommandError::unsupported("waiting for the store lock"))
+        }
+        Err(LockError::IO(error)) => Err(error.into()),
+    };
+    let root = dest_repo.working_directory_path();
+    for (hook_type, args) in after_lock {
+        hooks.run(ui, root, hook_type, &args, false, || Ok(false))?;
+    }
+    result?;
+    Ok(dest_repo)
+}
+
+/// The phases of the changesets pulled from `source`, like the
+/// `phase-heads` part a server sends: everything is public if it is
+/// publishing
+fn pulled_phase_heads(
+    source: &Repo,
+    outgoing: &Outgoing,
+) -> Result<Vec<(Phase, Node)>, CommandError> {
+    let changelog = source.changelog()?;
+    let index = changelog.get_index();
+    let heads: Vec<(Phase, Revision)> = if source
+        .config()
+        .get_bool(b"phases", b"publish")?
+    {
+        outgoing.ancestors_of.iter().map(|&rev| (Phase::Public, rev)).collect()
+    } else {
+        source
+            .store()
+            .phase_cache()?
+            .subset_phase_heads(index, &outgoing.missing)
+            .map_err(RevlogError::from)?
+            .into_iter()
+            .filter(|(phase, _)| *phase == Phase::Public)
+            .flat_map(|(phase, revs)| {
+                revs.into_iter().map(move |rev| (phase, rev))
+            })
+            .collect()
+    };
+    Ok(heads
+        .into_iter()
+        .map(|(phase, rev)| (phase, *changelog.node_from_rev(rev)))
+        .collect())
+}
+
+/// Writes the configuration of a new clone of the repository at `source`,
+/// like the `cloned` sample `hgrc` in Python
+fn write_hgrc(repo: &Repo, source: &Path) -> Result<(), CommandError> {
+    let current_dir = hg::utils::current_dir()?;
+    let default_path = normalize_path(&current_dir.join(source));
+    let path = repo.hg_vfs().join("hgrc");
+    let existing = std::fs::read(&path).unwrap_or_default();
+    let mut content = format_bytes!(
+        b"# example repository config (see 'hg help config' for more info)\n\
+          [paths]\n\
+          default = {}\n\
+          \n\
+          # path aliases to other clones of this repo in URLs or filesystem \
+          paths\n\
+          # (see 'hg help config.paths' for more info)\n\
+          #\n\
+          # default:pushurl = ssh://jdoe@example.net/hg/jdoes-fork\n\
+          # my-fork         = ssh://jdoe@example.net/hg/jdoes-fork\n\
+          # my-clone        = /home/jdoe/jdoes-clone\n\
+          \n\
+          [ui]\n\
+          # name and email (local to this repository, optional), e.g.\n\
+          # username = Jane Doe <jdoe@example.com>\n",
+        get_bytes_from_path(default_path)
+    );
+    content.extend(existing);
+    std::fs::write(&path, content).when_writing_file(&path)?;
+    Ok(())
+}
+
+/// The revision to check out in a new clone `repo`: `checkout` if given,
+/// else the tip of the default branch, else the tip
+fn update_target(
+    repo: &Repo,
+    checkout: Option<Node>,
+) -> Result<Revision, CommandError> {
+    let changelog = repo.changelog()?;
+    if let Some(checkout) = checkout {
+        return match changelog.rev_from_node(checkout.into()) {
+            Ok(rev) => Ok(rev),
+            Err(RevlogError::InvalidRevision { .. }) => Err(
+                CommandError::unsupported("checking out a revision not pulled"),
+            ),
+            Err(error) => Err(error.into()),
+        };
+    }
+    if read_bookmarks(repo)?.contains_key(&b"@"[..]) {
+        return Err(CommandError::unsupported("updating to bookmark @"));
+    }
+    drop(changelog);
+    match branch_tip(repo, b"default")? {
+        Some(rev) => Ok(rev),
+        None => default_checkout(repo),
+    }
+}
+
+/// The tip-most head of `branch` that does not close it, or the tip-most
+/// head if they all do, like `branchtip` in Python
+pub(crate) fn branch_tip(
+    repo: &Repo,
+    branch: &[u8],
+) -> Result<Option<Revision>, CommandError> {
+    let changelog = repo.changelog()?;
+    Ok(BranchMap::new(&changelog)?.tip(branch))
+}
+
+/// The tip of `repo`, checked out when nothing else is
+pub(crate) fn default_checkout(repo: &Repo) -> Result<Revision, CommandError> {
+    let len = repo.changelog()?.get_index().len();
+    if len == 0 {
+        return Err(CommandError::unsupported(
+            "checking out the null revision",
+        ));
+    }
+    Ok(Revision(len as i32 - 1))
+}
+
+/// Checks out `target` in the empty working directory of the new `repo`,
+/// announced by `message` given the branch of `target`, like `merge.update`
+/// in Python when updating from the null revision
+pub(crate) fn checkout_new(
+    ui: &Ui,
+    repo: &Repo,
+    target: Revision,
+    quiet: bool,
+    message: impl FnOnce(&[u8]) -> Vec<u8>,
+) -> Result<(), CommandError> {
+    let branch = {
+        let changelog = repo.changelog()?;
+        changelog.branch(*changelog.node_from_rev(target))?
+    };
+    if !quiet {
+        ui.write_stdout(&message(branch.as_bytes()))?;
+    }
+    let update_config = update_config(repo, WorkingCopyWrite::FromNull)?;
+    let result = repo.try_with_wlock_no_wait(|| -> Result<_, CommandError> {
+        let hg_vfs = repo.hg_vfs();
+        let node = format!("{:x}", repo.changelog()?.node_from_rev(target));
+        // Notes that an update is in progress
+        hg_vfs.atomic_write("updatestate", node.as_bytes())?;
+        hg_vfs.atomic_write("branch", format!("{}\n", branch).as_bytes())?;
+        let mut dirstate = repo.dirstate_map_mut()?;
+        let progress = HgProgressBar::new("updating");
+        let warnings = HgWarningContext::new();
+        let stats = update_from_null(
+            repo,
+            target.into(),
+            &mut dirstate,
+            &progress,
+            &update_config,
+            warnings.sender(),
+        );
+        progress.complete();
+        print_warnings(ui, warnings, repo.working_directory_path());
+        let stats = stats?;
+        drop(dirstate); // Avoid "already mutably borrowed" RefCell panics
+        repo.write_dirstate()?;
+        write_tracked_key(repo)?;
+        let path = hg_vfs.join("updatestate");
+        std::fs::remove_file(&path).when_writing_file(&path)?;
+        Ok(stats)
+    });
+    let stats = match result {
+        Ok(stats) => stats?,
+        Err(LockError::AlreadyHeld) => {
+            return Err(CommandError::unsupported(
+                "waiting for the working copy lock",
+            ));
+        }
+        Err(LockError::IO(error)) => return Err(error.into()),
+    };
+    if !quiet {
+        ui.write_stdout(
+            format!(
+                "{} files updated, 0 files merged, 0 files removed, 0 files \
+                 unresolved\n",
+                stats.updated
+            )
+            .as_bytes(),
+        )?;
+    }
+    Ok(())
+}
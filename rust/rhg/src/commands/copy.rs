@@ -0,0 +1,359 @@
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::errors::HgError;
+use hg::exit_codes;
+use hg::file_patterns::parse_pattern_syntax_kind;
+use hg::operations::Copier;
+use hg::operations::CopyEvent;
+use hg::operations::CopyOptions;
+use hg::operations::CopyWarning;
+use hg::utils::files::get_path_from_bytes;
+use hg::utils::hg_path::HgPath;
+use hg::warnings::format::write_tracking_warning;
+
+use crate::error::CommandError;
+use crate::ui::Ui;
+use crate::utils::path_utils::RelativizePaths;
+use crate::utils::tracking_utils::check_supported;
+use crate::utils::tracking_utils::file_args;
+use crate::utils::tracking_utils::file_matcher;
+use crate::utils::tracking_utils::print_bad;
+use crate::utils::tracking_utils::strerror;
+use crate::utils::tracking_utils::ui_path_fn;
+use crate::utils::tracking_utils::with_wlock;
+
+pub const HELP_TEXT: &str = "
+mark files as copied for the next commit
+
+Mark dest as having copies of source files. If dest is a directory, copies
+are put in that directory. If dest is a file, the source must be a single
+file.
+
+By default, this command copies the contents of files as they exist in the
+working directory. If invoked with -A/--after, the operation is recorded, but
+no copying is performed.
+
+This command takes effect with the next commit by default.
+
+Returns 0 on success, 1 if errors are encountered.
+";
+
+pub fn args() -> clap::Command {
+    copy_args(clap::command!("copy").alias("cp"), "copy").about(HELP_TEXT)
+}
+
+/// Add the arguments shared by `copy` and `rename`, where `noun` is what is
+/// recorded
+pub fn copy_args(command: clap::Command, noun: &str) -> clap::Command {
+    command
+        .arg(
+            Arg::new("forget")
+                .help(format!("unmark a destination file as {noun}ed"))
+                .long("forget")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("after")
+                .help(format!("record a {noun} that has already occurred"))
+                .short('A')
+                .long("after")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("at-rev")
+                .help(format!("(un)mark {noun}s in the given revision"))
+                .long("at-rev")
+                .value_name("REV"),
+        )
+        .arg(
+            Arg::new("force")
+                .help("forcibly copy over an existing managed file")
+                .short('f')
+                .long("force")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .help("do not perform actions, just print output")
+                .short('n')
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("enable additional output")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file")
+                .value_parser(clap::value_parser!(std::ffi::OsString))
+                .help("sources, then destination")
+                .action(clap::ArgAction::Append),
+        )
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg copy")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    copy_or_rename(invocation, false)
+}
+
+/// Run `copy`, or `rename` if `rename` is set
+pub fn copy_or_rename(
+    invocation: &crate::CliInvocation,
+    rename: bool,
+) -> Result<(), CommandError> {
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    if args.get_flag("forget") {
+        return Err(CommandError::unsupported("unmarking copies"));
+    }
+    if args.contains_id("at-rev") {
+        return Err(CommandError::unsupported("--at-rev"));
+    }
+    let options = CopyOptions {
+        rename,
+        after: args.get_flag("after"),
+        force: args.get_flag("force"),
+        dry_run: args.get_flag("dry-run"),
+    };
+    let verbose =
+        args.get_flag("verbose") || config.get_bool(b"ui", b"verbose")?;
+    let quiet = config.get_bool(b"ui", b"quiet")?;
+
+    let mut patterns = file_args(args);
+    let Some(dest) = patterns.pop() else {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: no source or destination specified",
+            exit_codes::INPUT_ERROR,
+        ));
+    };
+    if patterns.is_empty() {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: no destination specified",
+            exit_codes::INPUT_ERROR,
+        ));
+    }
+    let has_kind =
+        |pattern: &[u8]| match pattern.iter().position(|&b| b == b':') {
+            Some(index) => {
+                let kind = &pattern[..index];
+                kind == b"set" || parse_pattern_syntax_kind(kind).is_ok()
+            }
+            None => false,
+        };
+    if patterns.iter().any(|p| has_kind(p)) {
+        return Err(CommandError::unsupported("copying with patterns"));
+    }
+
+    let repo = invocation.repo?;
+    check_supported(repo, &patterns)?;
+    let cwd = hg::utils::current_dir()?;
+    let dest_path = get_path_from_bytes(&dest);
+    let dest_dir_exists =
+        cwd.join(dest_path).is_dir() && !cwd.join(dest_path).is_symlink();
+    if !dest_dir_exists {
+        if patterns.len() > 1 {
+            return Err(CommandError::abort_with_exit_code(
+                "abort: with multiple sources, destination must be an \
+                 existing directory",
+                exit_codes::INPUT_ERROR,
+            ));
+        }
+        if dest.ends_with(b"/") {
+            return Err(CommandError::abort_with_exit_code_bytes(
+                format_bytes!(
+                    b"abort: destination {} is not a directory",
+                    dest
+                ),
+                exit_codes::INPUT_ERROR,
+            ));
+        }
+    }
+    let ui_path = ui_path_fn(repo, true)?;
+    let relativize = RelativizePaths::new(repo)?;
+    let rel_path = |path: &HgPath| relativize.relativize(path).into_owned();
+
+    let failed = with_wlock(repo, || {
+        let mut dmap = repo.dirstate_map_mut()?;
+        let mut copier = Copier::new(repo, options)?;
+        let mut copies = vec![];
+        for pattern in patterns {
+            let pattern_path = get_path_from_bytes(&pattern).to_owned();
+            let matcher = file_matcher(repo, vec![pattern])?;
+            let sources = copier.sources(&mut dmap, &matcher)?;
+            print_bad(ui, &sources.bad, &ui_path)?;
+            for warning in &sources.skipped {
+                print_copy_warning(
+                    ui, warning, rename, options, &ui_path, &rel_path,
+                )?;
+            }
+            if sources.sources.is_empty() {
+                continue;
+            }
+            let targets = copier.targets(
+                &cwd,
+                &pattern_path,
+                dest_path,
+                dest_dir_exists,
+                &sources.sources,
+            )?;
+            copies.extend(sources.sources.into_iter().zip(targets));
+        }
+        if copies.is_empty() {
+            let hint = rename.then(|| {
+                "maybe you meant to use --after --at-rev=.".to_owned()
+            });
+            return Err(HgError::abort(
+                "no files to copy",
+                exit_codes::INPUT_ERROR,
+                hint,
+            )
+            .into());
+        }
+
+        let mut failed = false;
+        for (source, target) in copies {
+            let events = copier.copy(&mut dmap, &source.path, &target)?;
+            let mut copied = false;
+            for event in events {
+                match event {
+                    CopyEvent::Copied => {
+                        copied = true;
+                        if verbose || !source.exact {
+                            let verb: &[u8] =
+                                if rename { b"moving" } else { b"copying" };
+                            ui.write_stdout(&format_bytes!(
+                                b"{} {} to {}\n",
+                                verb,
+                                ui_path(&source.path),
+                                rel_path(&target)
+                            ))?;
+                        }
+                    }
+                    CopyEvent::Warning(CopyWarning::NotCommitted {
+                        ..
+                    }) if quiet => {}
+                    CopyEvent::Warning(warning) => print_copy_warning(
+                        ui, &warning, rename, options, &ui_path, &rel_path,
+                    )?,
+                }
+            }
+            failed |= !copied;
+        }
+        if !options.dry_run {
+            drop(dmap); // Avoid "already mutably borrowed" RefCell panics
+            repo.write_dirstate()?;
+        }
+        Ok(failed)
+    })?;
+    if failed {
+        Err(CommandError::Unsuccessful)
+    } else {
+        Ok(())
+    }
+}
+
+/// Print a warning with Python's wording. Sources are formatted with
+/// `ui_path`, and other paths with `rel_path`.
+fn print_copy_warning(
+    ui: &Ui,
+    warning: &CopyWarning,
+    rename: bool,
+    options: CopyOptions,
+    ui_path: &impl Fn(&HgPath) -> Vec<u8>,
+    rel_path: &impl Fn(&HgPath) -> Vec<u8>,
+) -> Result<(), CommandError> {
+    let (command, noun): (&[u8], &[u8]) = if rename {
+        (b"rename", b"rename")
+    } else {
+        (b"copy", b"copy")
+    };
+    let message = match warning {
+        CopyWarning::MarkedForRemove(path) => format_bytes!(
+            b"{}: not copying - file has been marked for remove\n",
+            ui_path(path)
+        ),
+        CopyWarning::NotManaged(path) => format_bytes!(
+            b"{}: not copying - file is not managed\n",
+            ui_path(path)
+        ),
+        CopyWarning::Collides { target, source, previous } => format_bytes!(
+            b"{}: not overwriting - {} collides with {}\n",
+            rel_path(target),
+            rel_path(source),
+            rel_path(previous)
+        ),
+        CopyWarning::TargetExists(target) => format_bytes!(
+            b"{}: not overwriting - file exists\n\
+              ('hg {} --after' to record the {})\n",
+            rel_path(target),
+            command,
+            noun
+        ),
+        CopyWarning::TargetCommitted { target, copied_in_parent: true } => {
+            format_bytes!(
+                b"{}: not overwriting - file already committed\n\
+                  ('hg {} --at-rev .' to record the {} in the parent of the \
+                  working copy)\n",
+                rel_path(target),
+                command,
+                noun
+            )
+        }
+        CopyWarning::TargetCommitted { target, copied_in_parent: false } => {
+            let flags: &[u8] = if options.after {
+                b"--after --force"
+            } else {
+                b"--force"
+            };
+            format_bytes!(
+                b"{}: not overwriting - file already committed\n\
+                  ('hg {} {}' to replace the file by recording a {})\n",
+                rel_path(target),
+                command,
+                flags,
+                noun
+            )
+        }
+        CopyWarning::TargetMissing { source, target } => {
+            let verb: &[u8] = if rename { b"move" } else { b"copy" };
+            format_bytes!(
+                b"{}: not recording {} - {} does not exist\n",
+                ui_path(source),
+                verb,
+                rel_path(target)
+            )
+        }
+        CopyWarning::SourceDeleted(source) => format_bytes!(
+            b"{}: deleted in working directory\n",
+            ui_path(source)
+        ),
+        CopyWarning::CannotCopy(source, error) => format_bytes!(
+            b"{}: cannot copy - {}\n",
+            ui_path(source),
+            strerror(error).as_bytes()
+        ),
+        CopyWarning::NotCommitted { source, target } => format_bytes!(
+            b"{} has not been committed yet, so no copy data will be stored \
+              for {}.\n",
+            rel_path(source),
+            rel_path(target)
+        ),
+        CopyWarning::NotAFile(target) => format_bytes!(
+            b"copy failed: {} is not a file or a symbolic link\n",
+            rel_path(target)
+        ),
+        CopyWarning::Tracking(warning) => {
+            let mut message = vec![];
+            write_tracking_warning(warning, &mut message, rel_path)
+                .expect("writing to a Vec cannot fail");
+            message
+        }
+    };
+    ui.write_stderr(&message)?;
+    Ok(())
+}
@@ -0,0 +1,317 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+
+use chrono::DateTime;
+use chrono::FixedOffset;
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::bundle::Bundle;
+use hg::bundle::bundle2::Bundle2Reader;
+use hg::bundle::parts::OBSMARKERS_VERSION;
+use hg::bundle::parts::ObsMarker;
+use hg::bundle::parts::changegroup_version;
+use hg::bundle::parts::decode_obsmarkers;
+use hg::bundle::parts::decode_phase_heads;
+use hg::changegroup::ChangegroupUnpacker;
+use hg::errors::HgError;
+use hg::errors::IoResultExt;
+
+use crate::error::CommandError;
+use crate::ui::Ui;
+
+pub const HELP_TEXT: &str = "
+lists the contents of a bundle
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("debugbundle")
+        .args_override_self(true)
+        .arg(
+            Arg::new("file")
+                .help("the bundle to inspect")
+                .required(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::new("all")
+                .help("show all details")
+                .short('a')
+                .long("all")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("part-type")
+                .help("show only the named part type")
+                .long("part-type")
+                .value_name("TYPE")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("spec")
+                .help("print the bundlespec of the bundle")
+                .long("spec")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("do not list the contents of parts")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg debugbundle")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let path = args
+        .get_one::<String>("file")
+        .expect("file should be a required argument");
+    let all = args.get_flag("all");
+    let quiet = args.get_flag("quiet")
+        || invocation.config.get_bool(b"ui", b"quiet")?;
+    let part_types: Vec<&String> =
+        args.get_many("part-type").map(Iterator::collect).unwrap_or_default();
+
+    let file = File::open(path).when_reading_file(Path::new(path))?;
+    let bundle = hg::bundle::read_bundle(BufReader::new(file), path)?;
+    if args.get_flag("spec") {
+        let spec = hg::bundle::bundle_spec(bundle)?;
+        ui.write_stdout(format!("{}\n", spec).as_bytes())?;
+        return Ok(());
+    }
+    match bundle {
+        Bundle::Changegroup { mut unpacker, .. } => {
+            show_changegroup(ui, &mut unpacker, all, "")
+        }
+        Bundle::Bundle2(mut reader) => {
+            show_bundle2(ui, &mut reader, all, &part_types, quiet)
+        }
+        Bundle::StreamClone { .. } => Err(CommandError::unsupported(
+            "debugbundle of a stream clone bundle",
+        )),
+    }
+}
+
+fn show_changegroup<R: Read>(
+    ui: &Ui,
+    unpacker: &mut ChangegroupUnpacker<R>,
+    all: bool,
+    indent: &str,
+) -> Result<(), CommandError> {
+    if !all {
+        while let Some(delta) = unpacker.delta_chunk()? {
+            ui.write_stdout(
+                format!("{}{:x}\n", indent, delta.node).as_bytes(),
+            )?;
+        }
+        return Ok(());
+    }
+    ui.write_stdout(
+        format!("{}format: id, p1, p2, cset, delta base, len(delta)\n", indent)
+            .as_bytes(),
+    )?;
+    show_group(ui, unpacker, indent, b"changelog")?;
+    show_group(ui, unpacker, indent, b"manifest")?;
+    while let Some(path) = unpacker.group_header()? {
+        show_group(ui, unpacker, indent, path.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Lists every revision of the current group of `unpacker`
+fn show_group<R: Read>(
+    ui: &Ui,
+    unpacker: &mut ChangegroupUnpacker<R>,
+    indent: &str,
+    name: &[u8],
+) -> Result<(), CommandError> {
+    let indent = indent.as_bytes();
+    let mut output = format_bytes!(b"\n{}{}\n", indent, name);
+    while let Some(delta) = unpacker.delta_chunk()? {
+        output.extend_from_slice(indent);
+        output.extend_from_slice(
+            format!(
+                "{:x} {:x} {:x} {:x} {:x} {}\n",
+                delta.node,
+                delta.p1,
+                delta.p2,
+                delta.link_node,
+                delta.delta_base,
+                delta.delta.len()
+            )
+            .as_bytes(),
+        );
+    }
+    ui.write_stdout(&output)?;
+    Ok(())
+}
+
+fn show_bundle2(
+    ui: &Ui,
+    reader: &mut Bundle2Reader,
+    all: bool,
+    part_types: &[&String],
+    quiet: bool,
+) -> Result<(), CommandError> {
+    let params = reader.params().iter().map(|(name, value)| {
+        let value = match value {
+            Some(value) => String::from_utf8_lossy(value).into_owned(),
+            None => "None".to_owned(),
+        };
+        (name.clone(), value)
+    });
+    ui.write_stdout(
+        format!("Stream params: {}\n", quasi_repr(params.collect())).as_bytes(),
+    )?;
+    while let Some(mut part) = reader.next_part()? {
+        let part_type = part.header.part_type.clone();
+        if !part_types.is_empty() && !part_types.contains(&&part_type) {
+            continue;
+        }
+        let params = part.header.params().map(|(name, value)| {
+            (name.to_owned(), String::from_utf8_lossy(value).into_owned())
+        });
+        ui.write_stdout(
+            format!(
+                "{} -- {} (mandatory: {})\n",
+                part_type,
+                quasi_repr(params.collect()),
+                if part.header.mandatory {
+                    "True"
+                } else {
+                    "False"
+                }
+            )
+            .as_bytes(),
+        )?;
+        if quiet {
+            continue;
+        }
+        match part_type.as_str() {
+            "changegroup" => {
+                let version = changegroup_version(&part.header)?;
+                let mut unpacker = ChangegroupUnpacker::new(&mut part, version);
+                show_changegroup(ui, &mut unpacker, all, "    ")?;
+            }
+            "obsmarkers" => {
+                let data = part.read_payload()?;
+                show_obsmarkers(ui, &data)?;
+            }
+            "phase-heads" => {
+                let mut heads = decode_phase_heads(&part.read_payload()?)?;
+                heads.sort_by_key(|(phase, _)| phase.number());
+                for (phase, node) in heads {
+                    ui.write_stdout(
+                        format!("    {:x} {}\n", node, phase.name()).as_bytes(),
+                    )?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Formats `params` sorted by name, like `_quasirepr` in Python
+fn quasi_repr(mut params: Vec<(String, String)>) -> String {
+    params.sort();
+    let params: Vec<String> = params
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect();
+    format!("{{{}}}", params.join(", "))
+}
+
+fn show_obsmarkers(ui: &Ui, data: &[u8]) -> Result<(), CommandError> {
+    let version = data.first().copied().ok_or_else(|| {
+        HgError::abort_simple("parsing obsolete marker: truncated marker data")
+    })?;
+    if version != OBSMARKERS_VERSION && version != 0 {
+        ui.write_stdout(
+            format!(
+                "    unsupported version: {} ({} bytes)\n",
+                version,
+                data.len()
+            )
+            .as_bytes(),
+        )?;
+        return Ok(());
+    }
+    let mut markers = decode_obsmarkers(data)?;
+    markers.sort_by(ObsMarker::python_cmp);
+    let mut output =
+        format!("    version: {} ({} bytes)\n", version, data.len())
+            .into_bytes();
+    for marker in &markers {
+        output.extend_from_slice(b"    ");
+        output.extend(format_marker(marker));
+    }
+    ui.write_stdout(&output)?;
+    Ok(())
+}
+
+/// Formats an obsolescence marker like `cmdutil.showmarker` in Python
+fn format_marker(marker: &ObsMarker) -> Vec<u8> {
+    let mut line = format!("{:x} ", marker.predecessor);
+    for successor in &marker.successors {
+        line.push_str(&format!("{:x} ", successor));
+    }
+    line.push_str(&format!("{:X} ", marker.flags));
+    if let Some(parents) = &marker.parents {
+        let parents: Vec<String> =
+            parents.iter().map(|node| format!("{:x}", node)).collect();
+        line.push_str(&format!("{{{}}} ", parents.join(", ")));
+    }
+    let (seconds, offset) = marker.date;
+    let date = FixedOffset::west_opt(offset).and_then(|offset| {
+        DateTime::from_timestamp(seconds as i64, 0)
+            .map(|date| date.with_timezone(&offset))
+    });
+    if let Some(date) = date {
+        line.push_str(&format!(
+            "({}) ",
+            date.format("%a %b %d %H:%M:%S %Y %z")
+        ));
+    }
+    let mut output = line.into_bytes();
+    let metadata: Vec<Vec<u8>> = marker
+        .metadata
+        .iter()
+        .filter(|(key, _)| key != b"date")
+        .map(|(key, value)| {
+            format_bytes!(b"{}: {}", python_repr(key), python_repr(value))
+        })
+        .collect();
+    output.push(b'{');
+    output.extend(metadata.join(&b", "[..]));
+    output.extend_from_slice(b"}\n");
+    output
+}
+
+/// Quotes `bytes` like the `repr` of a Python string
+fn python_repr(bytes: &[u8]) -> Vec<u8> {
+    let quote = if bytes.contains(&b'\'') && !bytes.contains(&b'"') {
+        b'"'
+    } else {
+        b'\''
+    };
+    let mut output = vec![quote];
+    for &byte in bytes {
+        match byte {
+            b'\\' => output.extend_from_slice(b"\\\\"),
+            b'\t' => output.extend_from_slice(b"\\t"),
+            b'\n' => output.extend_from_slice(b"\\n"),
+            b'\r' => output.extend_from_slice(b"\\r"),
+            _ if byte == quote => output.extend_from_slice(&[b'\\', byte]),
+            0x20..=0x7e => output.push(byte),
+            _ => output.extend(format!("\\x{:02x}", byte).into_bytes()),
+        }
+    }
+    output.push(quote);
+    output
+}
@@ -1,11 +1,17 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
 use clap::Arg;
 use hg::dirstate::DirstateError;
 use hg::file_patterns::parse_pattern_args;
 use hg::matchers::IntersectionMatcher;
+use hg::matchers::PatternMatcher;
 use hg::narrow;
 use hg::operations::list_revset_tracked_files;
 use hg::repo::Repo;
+use hg::revlog::RevisionOrWdir;
 use hg::utils::files::get_bytes_from_os_str;
+use hg::utils::files::get_path_from_bytes;
 use hg::utils::filter_map_results;
 use hg::utils::hg_path::HgPath;
 use hg::warnings::HgWarningContext;
@@ -17,6 +23,7 @@ use crate::ui::RelativePaths;
 use crate::ui::Ui;
 use crate::ui::relative_paths;
 use crate::utils::path_utils::RelativizePaths;
+use crate::utils::path_utils::file_args_matcher;
 
 pub const HELP_TEXT: &str = "
 List tracked files.
@@ -48,6 +55,19 @@ pub fn args() -> clap::Command {
                 .action(clap::ArgAction::SetTrue)
                 .help("end filenames with NUL, for use with xargs"),
         )
+        .arg(
+            Arg::new("verbose")
+                .help("also show the size and flags of each file")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("debug")
+                .help("same as --verbose")
+                .long("debug")
+                .action(clap::ArgAction::SetTrue),
+        )
         .about(HELP_TEXT)
 }
 
@@ -59,7 +79,12 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
     };
 
     let args = invocation.subcommand_args;
+    let config = invocation.config;
     let rev = args.get_one::<String>("rev");
+    let verbose = args.get_flag("verbose")
+        || args.get_flag("debug")
+        || config.get_bool(b"ui", b"verbose")?
+        || config.get_bool(b"ui", b"debug")?;
     let delimiter = if args.get_flag("print0") {
         b"\0"
     } else {
@@ -83,12 +108,6 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
     let warning_context = HgWarningContext::new();
     let matcher = narrow::matcher(repo, warning_context.sender())?;
 
-    let mut stderr = invocation.ui.stderr_locked();
-    // Can't really do anything if writing to stderr failed
-    let _ = warning_context.finish(|warning| {
-        write_warning(&warning, &mut stderr, repo.working_directory_path())
-    });
-
     let matcher = match args.get_many::<std::ffi::OsString>("file") {
         None => matcher,
         Some(files) => {
@@ -96,20 +115,33 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
                 .filter(|s| !s.is_empty())
                 .map(get_bytes_from_os_str)
                 .collect();
-            for file in &patterns {
-                if file.starts_with(b"set:") {
-                    return Err(CommandError::unsupported("fileset"));
-                }
-            }
-            let cwd = hg::utils::current_dir()?;
-            let root = repo.working_directory_path();
-            let file_patterns = parse_pattern_args(patterns, &cwd, root)?;
-            let files_matcher =
-                hg::matchers::PatternMatcher::new(file_patterns)?;
-            Box::new(IntersectionMatcher::new(Box::new(files_matcher), matcher))
+            let fileset_rev = match rev {
+                Some(rev) => hg::revset::resolve_single(rev, repo)?,
+                None => RevisionOrWdir::wdir(),
+            };
+            let files_matcher = file_args_matcher(
+                repo,
+                fileset_rev,
+                patterns,
+                warning_context.sender(),
+                |patterns| {
+                    let cwd = hg::utils::current_dir()?;
+                    let root = repo.working_directory_path();
+                    let file_patterns =
+                        parse_pattern_args(patterns, &cwd, root)?;
+                    Ok(Box::new(PatternMatcher::new(file_patterns)?))
+                },
+            )?;
+            Box::new(IntersectionMatcher::new(files_matcher, matcher))
         }
     };
 
+    let mut stderr = invocation.ui.stderr_locked();
+    // Can't really do anything if writing to stderr failed
+    let _ = warning_context.finish(|warning| {
+        write_warning(&warning, &mut stderr, repo.working_directory_path())
+    });
+
     if let Some(rev) = rev {
         let files = list_revset_tracked_files(repo, rev, matcher)?;
         display_files(
@@ -117,9 +149,19 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
             repo,
             relative_paths,
             delimiter,
-            files
-                .iter()
-                .map::<Result<_, CommandError>, _>(|result| Ok(result?.path)),
+            files.iter().map(|result| {
+                let entry = result?;
+                let details = if verbose {
+                    let size = repo
+                        .filelog(entry.path)?
+                        .contents_size_for_node(entry.node)?;
+                    let flag = entry.flags.as_byte().unwrap_or(b' ');
+                    Some((size as u64, flag))
+                } else {
+                    None
+                };
+                Ok::<_, CommandError>((entry.path, details))
+            }),
         )
     } else {
         // The dirstate always reflects the sparse narrowspec.
@@ -137,22 +179,57 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
         let mut files = files_res.map_err(DirstateError::from)?;
         files.par_sort_unstable();
 
+        let root = repo.working_directory_path();
         display_files(
             invocation.ui,
             repo,
             relative_paths,
             delimiter,
-            files.into_iter().map::<Result<_, CommandError>, _>(Ok),
+            files.into_iter().map(|path| {
+                let details = if verbose {
+                    Some(working_copy_details(root, path)?)
+                } else {
+                    None
+                };
+                Ok::<_, CommandError>((path, details))
+            }),
         )
     }
 }
 
+/// Returns the size and flag of a file in the working copy, like Python's
+/// `workingfilectx.size()` and `flags()`.
+fn working_copy_details(
+    root: &Path,
+    path: &HgPath,
+) -> Result<(u64, u8), CommandError> {
+    let fs_path = root.join(get_path_from_bytes(path.as_bytes()));
+    let metadata = match fs_path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        // Python crashes on missing files, leave it to the fallback
+        Err(_) => {
+            return Err(CommandError::unsupported(
+                "missing file in verbose listing",
+            ));
+        }
+    };
+    let flag = if metadata.is_symlink() {
+        b'l'
+    } else if metadata.permissions().mode() & 0o111 != 0 {
+        b'x'
+    } else {
+        b' '
+    };
+    Ok((metadata.len(), flag))
+}
+
+/// Display the listed files, each preceded by its size and flag if given.
 fn display_files<'a, E>(
     ui: &Ui,
     repo: &Repo,
     relative_paths: bool,
     delimiter: &[u8],
-    files: impl IntoIterator<Item = Result<&'a HgPath, E>>,
+    files: impl IntoIterator<Item = Result<(&'a HgPath, Option<(u64, u8)>), E>>,
 ) -> Result<(), CommandError>
 where
     CommandError: From<E>,
@@ -162,7 +239,11 @@ where
 
     let relativize = RelativizePaths::new(repo)?;
     for result in files {
-        let path = result?;
+        let (path, details) = result?;
+        if let Some((size, flag)) = details {
+            stdout.write_all(format!("{:>10} ", size).as_bytes())?;
+            stdout.write_all(&[flag, b' '])?;
+        }
         if relative_paths {
             stdout.write_all(&relativize.relativize(path))?;
         } else {
@@ -0,0 +1,121 @@
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::exit_codes;
+use hg::operations::forget_apply;
+use hg::operations::forget_plan;
+use hg::warnings::HgWarningContext;
+
+use crate::error::CommandError;
+use crate::utils::tracking_utils::check_supported;
+use crate::utils::tracking_utils::file_args;
+use crate::utils::tracking_utils::file_matcher;
+use crate::utils::tracking_utils::print_bad;
+use crate::utils::tracking_utils::print_tracking_warnings;
+use crate::utils::tracking_utils::ui_path_fn;
+use crate::utils::tracking_utils::with_wlock;
+
+pub const HELP_TEXT: &str = "
+forget the specified files on the next commit
+
+Mark the specified files so they will no longer be tracked after the next
+commit.
+
+This only removes files from the current branch, not from the entire project
+history, and it does not delete them from the working directory.
+
+To delete the file from the working directory, see 'hg remove'.
+
+To undo a forget before the next commit, see 'hg add'.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("forget")
+        .arg(
+            Arg::new("interactive")
+                .help("use interactive mode")
+                .short('i')
+                .long("interactive")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .help("do not perform actions, just print output")
+                .short('n')
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("enable additional output")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file")
+                .value_parser(clap::value_parser!(std::ffi::OsString))
+                .help("files to forget")
+                .action(clap::ArgAction::Append),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg forget")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    if args.get_flag("interactive") {
+        return Err(CommandError::unsupported("interactive forget"));
+    }
+    let dry_run = args.get_flag("dry-run");
+    let verbose =
+        args.get_flag("verbose") || config.get_bool(b"ui", b"verbose")?;
+    let patterns = file_args(args);
+    if patterns.is_empty() {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: no files specified",
+            exit_codes::INPUT_ERROR,
+        ));
+    }
+
+    let repo = invocation.repo?;
+    check_supported(repo, &patterns)?;
+    let matcher = file_matcher(repo, patterns)?;
+    let ui_path = ui_path_fn(repo, true)?;
+
+    let failed = with_wlock(repo, || {
+        let mut dmap = repo.dirstate_map_mut()?;
+        let warnings = HgWarningContext::new();
+        let plan = forget_plan(repo, &mut dmap, &matcher, warnings.sender())?;
+        print_bad(ui, &plan.bad, &ui_path)?;
+        print_tracking_warnings(ui, warnings, repo, &ui_path);
+        for entry in &plan.entries {
+            if verbose || !entry.exact {
+                ui.write_stdout(&format_bytes!(
+                    b"removing {}\n",
+                    ui_path(&entry.path)
+                ))?;
+            }
+        }
+        let mut failed = plan.rejected || !plan.bad.is_empty();
+        if dry_run {
+            return Ok(failed);
+        }
+        let warnings = HgWarningContext::new();
+        let rejected = forget_apply(repo, &mut dmap, &plan, warnings.sender());
+        print_tracking_warnings(ui, warnings, repo, &ui_path);
+        failed |= rejected?.iter().any(|path| matcher.exact_match(path));
+        drop(dmap); // Avoid "already mutably borrowed" RefCell panics
+        repo.write_dirstate()?;
+        Ok(failed)
+    })?;
+    if failed {
+        Err(CommandError::Unsuccessful)
+    } else {
+        Ok(())
+    }
+}
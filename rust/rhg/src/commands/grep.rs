@@ -0,0 +1,437 @@
+use std::ffi::OsString;
+use std::io::Write;
+
+use clap::Arg;
+use hg::NULL_REVISION;
+use hg::Revision;
+use hg::exit_codes;
+use hg::operations::FileMatches;
+use hg::operations::GrepPattern;
+use hg::operations::LineChange;
+use hg::operations::MatchedLine;
+use hg::operations::compile_pattern;
+use hg::operations::grep_changes;
+use hg::operations::grep_revision;
+use hg::operations::grep_working_copy;
+use hg::repo::Repo;
+use hg::revlog::RevisionOrWdir;
+use hg::revlog::WORKING_DIRECTORY_REVISION;
+use hg::utils::files::get_bytes_from_os_str;
+use hg::utils::hg_path::HgPath;
+
+use crate::error::CommandError;
+use crate::ui::StdoutBuffer;
+use crate::utils::path_utils::include_exclude_args;
+use crate::utils::path_utils::include_exclude_matcher;
+use crate::utils::tracking_utils::check_supported;
+use crate::utils::tracking_utils::file_args;
+use crate::utils::tracking_utils::file_matcher;
+use crate::utils::tracking_utils::ui_path_fn;
+
+pub const HELP_TEXT: &str = "
+search for a pattern in specified files
+
+Search the working directory or revision history for a regular expression in
+the specified files for the entire repository.
+
+By default, grep searches the repository files in the working directory and
+prints the files where it finds a match. To specify historical revisions
+instead of the working directory, use the --rev flag.
+
+To search instead historical revision differences that contains a change in
+match status (\"-\" for a match that becomes a non-match, or \"+\" for a
+non-match that becomes a match), use the --diff flag.
+
+PATTERN is a regular expression, matched like Python's (roughly
+Perl-compatible) regular expressions. Patterns using features that Python
+has but rhg does not, like back-references, fall back to Python.
+
+Returns 0 if a match is found, 1 otherwise.
+";
+
+pub fn args() -> clap::Command {
+    include_exclude_args(
+        clap::command!("grep")
+            .args_override_self(true)
+            .arg(
+                Arg::new("print0")
+                    .help("end fields with NUL")
+                    .short('0')
+                    .long("print0")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("all")
+                    .help("an alias to --diff (DEPRECATED)")
+                    .long("all")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("diff")
+                    .help(
+                        "search revision differences for when the pattern \
+                         was added or removed",
+                    )
+                    .long("diff")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("text")
+                    .help("treat all files as text")
+                    .short('a')
+                    .long("text")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("follow")
+                    .help(
+                        "follow changeset history, or file history across \
+                         copies and renames",
+                    )
+                    .short('f')
+                    .long("follow")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("ignore-case")
+                    .help("ignore case when matching")
+                    .short('i')
+                    .long("ignore-case")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("word-regexp")
+                    .help("only match whole words")
+                    .short('w')
+                    .long("word-regexp")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("files-with-matches")
+                    .help("print only filenames and revisions that match")
+                    .short('l')
+                    .long("files-with-matches")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("line-number")
+                    .help("print matching line numbers")
+                    .short('n')
+                    .long("line-number")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("rev")
+                    .help("search files changed within revision range")
+                    .short('r')
+                    .long("rev")
+                    .value_name("REV")
+                    .action(clap::ArgAction::Append),
+            )
+            .arg(
+                Arg::new("all-files")
+                    .help(
+                        "include all files in the changeset while grepping \
+                         (DEPRECATED)",
+                    )
+                    .long("all-files")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("user")
+                    .help("list the author (long with -v)")
+                    .short('u')
+                    .long("user")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("date")
+                    .help("list the date (short with -q)")
+                    .short('d')
+                    .long("date")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("template")
+                    .help("display with template")
+                    .short('T')
+                    .long("template")
+                    .value_name("TEMPLATE"),
+            )
+            .arg(
+                Arg::new("quiet")
+                    .help("show short date for -d")
+                    .short('q')
+                    .long("quiet")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("verbose")
+                    .help("show full username for -u")
+                    .short('v')
+                    .long("verbose")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("pattern")
+                    .help("the regular expression to search for")
+                    .value_name("PATTERN")
+                    .required(true)
+                    .value_parser(clap::value_parser!(OsString)),
+            )
+            .arg(
+                Arg::new("file")
+                    .help("files to search")
+                    .value_name("FILE")
+                    .value_parser(clap::value_parser!(OsString))
+                    .action(clap::ArgAction::Append),
+            ),
+    )
+    .about(HELP_TEXT)
+}
+
+/// What to show for each matching line, besides the file name
+struct DisplayOptions<'a> {
+    regex: &'a GrepPattern,
+    rev: bool,
+    line_number: bool,
+    user: bool,
+    date: bool,
+    files_with_matches: bool,
+    text: bool,
+    verbose: bool,
+    quiet: bool,
+    /// Separates fields, then ends lines
+    separators: (&'static [u8], &'static [u8]),
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg grep")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    if args.get_flag("all-files") {
+        for other in ["all", "diff"] {
+            if args.get_flag(other) {
+                return Err(CommandError::abort_with_exit_code(
+                    format!(
+                        "abort: cannot specify both --all-files and --{other}"
+                    ),
+                    exit_codes::INPUT_ERROR,
+                ));
+            }
+        }
+    }
+    if args.get_flag("follow") {
+        return Err(CommandError::unsupported("grep --follow"));
+    }
+    if args.contains_id("template") {
+        return Err(CommandError::unsupported("grep --template"));
+    }
+    let diff = args.get_flag("all") || args.get_flag("diff");
+    let revs: Vec<&String> =
+        args.get_many::<String>("rev").into_iter().flatten().collect();
+    let plain = !diff && revs.is_empty();
+
+    let pattern = get_bytes_from_os_str(
+        args.get_one::<OsString>("pattern").expect("pattern is required"),
+    );
+    let regex = compile_pattern(
+        &pattern,
+        args.get_flag("ignore-case"),
+        args.get_flag("word-regexp"),
+    )?;
+
+    let repo = invocation.repo?;
+    let patterns = file_args(args);
+    check_supported(repo, &patterns)?;
+    let matcher = file_matcher(repo, patterns)?;
+    let matcher = include_exclude_matcher(repo, args, matcher)?;
+
+    let revs = if plain {
+        vec![RevisionOrWdir::wdir()]
+    } else if !revs.is_empty() {
+        let mut resolved = vec![];
+        for rev in revs {
+            let rev = hg::revset::resolve_single(rev, repo)?;
+            if !resolved.contains(&rev) {
+                resolved.push(rev);
+            }
+        }
+        resolved
+    } else {
+        let obsstore = repo.store_vfs().join("obsstore");
+        if obsstore.metadata().is_ok_and(|metadata| metadata.len() > 0) {
+            return Err(CommandError::unsupported("hidden revisions"));
+        }
+        let len = repo.changelog()?.get_index().len() as i32;
+        (0..len).rev().map(|rev| Revision(rev).into()).collect()
+    };
+
+    let options = DisplayOptions {
+        regex: &regex,
+        rev: !plain,
+        line_number: args.get_flag("line-number"),
+        user: args.get_flag("user"),
+        date: args.get_flag("date"),
+        files_with_matches: args.get_flag("files-with-matches"),
+        text: args.get_flag("text"),
+        verbose: args.get_flag("verbose")
+            || config.get_bool(b"ui", b"verbose")?,
+        quiet: args.get_flag("quiet") || config.get_bool(b"ui", b"quiet")?,
+        separators: if args.get_flag("print0") {
+            (b"\0", b"\0")
+        } else {
+            (b":", b"\n")
+        },
+    };
+    let ui_path = ui_path_fn(repo, false)?;
+    let mut stdout = invocation.ui.stdout_buffer();
+    let mut found = false;
+    let mut display = |rev: RevisionOrWdir, files: Vec<FileMatches>| {
+        for file in files {
+            display_file(&mut stdout, repo, rev, &file, &options, &ui_path)?;
+            found = true;
+        }
+        Ok::<(), CommandError>(())
+    };
+
+    if diff {
+        let mut changed = vec![];
+        for rev in revs {
+            match rev.exclude_wdir() {
+                Some(NULL_REVISION) => {}
+                Some(rev) => changed.push(rev),
+                None => {
+                    return Err(CommandError::unsupported(
+                        "grep --diff in the working copy",
+                    ));
+                }
+            }
+        }
+        grep_changes(repo, &changed, &matcher, &regex, |rev, files| {
+            display(rev.into(), files)
+        })?;
+    } else {
+        for rev in revs {
+            let files = match rev.exclude_wdir() {
+                Some(NULL_REVISION) => vec![],
+                Some(rev) => grep_revision(repo, rev, &matcher, &regex)?,
+                None => {
+                    if options.user || options.date {
+                        return Err(CommandError::unsupported(
+                            "grep --user or --date in the working copy",
+                        ));
+                    }
+                    grep_working_copy(repo, &matcher, &regex)?
+                }
+            };
+            display(rev, files)?;
+        }
+    }
+    stdout.flush()?;
+    if found {
+        Ok(())
+    } else {
+        Err(CommandError::Unsuccessful)
+    }
+}
+
+/// Print the matching lines of a file in a revision, like Python's
+/// `display` in `commands.grep`
+fn display_file(
+    stdout: &mut StdoutBuffer<impl Write>,
+    repo: &Repo,
+    rev: RevisionOrWdir,
+    file: &FileMatches,
+    options: &DisplayOptions,
+    ui_path: &impl Fn(&HgPath) -> Vec<u8>,
+) -> Result<(), CommandError> {
+    let (sep, eol) = options.separators;
+    let mut user = None;
+    let mut date = None;
+    if let Some(rev) = rev.exclude_wdir()
+        && (options.user || options.date)
+    {
+        let changelog = repo.changelog()?;
+        let entry = changelog.entry(rev)?;
+        let data = entry.data()?;
+        if options.user {
+            user = Some(if options.verbose {
+                data.user().to_vec()
+            } else {
+                hg::utils::strings::short_user(data.user()).to_vec()
+            });
+        }
+        if options.date {
+            let format = if options.quiet {
+                "%Y-%m-%d"
+            } else {
+                "%a %b %d %H:%M:%S %Y %z"
+            };
+            date = Some(data.timestamp()?.format(format).to_string());
+        }
+    }
+    let rev_number = match rev.exclude_wdir() {
+        Some(rev) => rev.0,
+        None => WORKING_DIRECTORY_REVISION.0,
+    };
+
+    for (change, line) in &file.lines {
+        stdout.write_stdout_labelled(&ui_path(&file.path), "grep.filename")?;
+        let mut field = |value: &[u8], label: &str| {
+            stdout.write_stdout_labelled(sep, "grep.sep")?;
+            stdout.write_stdout_labelled(value, label)
+        };
+        if options.rev {
+            field(rev_number.to_string().as_bytes(), "grep.rev")?;
+        }
+        if options.line_number {
+            field(line.number.to_string().as_bytes(), "grep.linenumber")?;
+        }
+        match change {
+            Some(LineChange::Added) => field(b"+", "grep.inserted")?,
+            Some(LineChange::Removed) => field(b"-", "grep.deleted")?,
+            None => {}
+        }
+        if let Some(user) = &user {
+            field(user, "grep.user")?;
+        }
+        if let Some(date) = &date {
+            field(date.as_bytes(), "grep.date")?;
+        }
+        if !options.files_with_matches {
+            stdout.write_stdout_labelled(sep, "grep.sep")?;
+            if !options.text && file.binary {
+                stdout.write_all(b" Binary file matches")?;
+            } else {
+                write_line(stdout, line, options.regex)?;
+            }
+        }
+        stdout.write_all(eol)?;
+        if options.files_with_matches {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Write a matching line, highlighting the matches
+fn write_line(
+    stdout: &mut StdoutBuffer<impl Write>,
+    line: &MatchedLine,
+    regex: &GrepPattern,
+) -> Result<(), CommandError> {
+    let mut position = 0;
+    for (start, end) in line.match_positions(regex) {
+        if position < start {
+            stdout.write_all(&line.line[position..start])?;
+        }
+        stdout.write_stdout_labelled(&line.line[start..end], "grep.match")?;
+        position = end;
+    }
+    if position < line.line.len() {
+        stdout.write_all(&line.line[position..])?;
+    }
+    Ok(())
+}
@@ -9,7 +9,15 @@ use std::path::PathBuf;
 use clap::Arg;
 use clap::ArgMatches;
 use clap::Command;
+use clap::builder::EnumValueParser;
+use hg::Node;
 use hg::utils::files::get_path_from_bytes;
+use hg_vfs::BackendMode;
+use hg_vfs::SessionACL;
+use hg_vfs::registry::MountRecord;
+use hg_vfs::registry::MountRegistry;
+use hg_vfs::registry::lazy_unmount;
+use hg_vfs::registry::mounted_virtual_filesystems;
 use hyper_util::rt::TokioIo;
 use tokio::net::UnixStream;
 use tonic::transport::Channel;
@@ -18,11 +26,18 @@ use tonic::transport::Uri;
 use tower::service_fn;
 use vfs_api::vfs::HealthRequest;
 use vfs_api::vfs::ListMountsRequest;
+use vfs_api::vfs::MountInfo;
 use vfs_api::vfs::MountRequest;
+use vfs_api::vfs::MountStatsRequest;
 use vfs_api::vfs::UnmountRequest;
 use vfs_api::vfs::UnmountResponse;
+use vfs_api::vfs::WatchMountsRequest;
+use vfs_api::vfs::mount_event;
 use vfs_api::vfs::vfs_control_client::VfsControlClient;
 
+use crate::commands::hgfs_server::from_proto_mount_options;
+use crate::commands::hgfs_server::is_server_running;
+use crate::commands::hgfs_server::to_proto_mount_options;
 use crate::error::CommandError;
 
 pub const HELP_TEXT: &str =
@@ -56,6 +71,64 @@ pub fn args() -> clap::Command {
                         .required(true)
                         .value_parser(clap::value_parser!(OsString))
                         .help("path to mount the virtual filesystem at"),
+                )
+                .arg(
+                    Arg::new("rev")
+                        .long("rev")
+                        .short('r')
+                        .value_name("REV")
+                        .help(
+                            "only mount the working copy of this revision, \
+                             at the root",
+                        ),
+                )
+                .arg(
+                    Arg::new("backend-mode")
+                        .long("backend-mode")
+                        .value_parser(EnumValueParser::<BackendMode>::new())
+                        .help("what type of working copy to present"),
+                )
+                .arg(
+                    Arg::new("user-id")
+                        .long("user-id")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("override the uid"),
+                )
+                .arg(
+                    Arg::new("group-id")
+                        .long("group-id")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("override the gid"),
+                )
+                .arg(
+                    Arg::new("max-revisions-loaded")
+                        .long("max-revisions-loaded")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("maximum number of revisions to keep loaded"),
+                )
+                .arg(
+                    Arg::new("open-to-all")
+                        .long("open-to-all")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("allow requests from any user"),
+                )
+                .arg(
+                    Arg::new("open-to-root")
+                        .long("open-to-root")
+                        .action(clap::ArgAction::SetTrue)
+                        .help(
+                            "allow requests from the filesystem owner and root",
+                        )
+                        .conflicts_with("open-to-all"),
+                )
+                .arg(
+                    Arg::new("overlay")
+                        .long("overlay")
+                        .value_parser(clap::value_parser!(OsString))
+                        .help(
+                            "directory to store changes in, making the mount \
+                             writable",
+                        ),
                 ),
         )
         .subcommand(
@@ -68,6 +141,33 @@ pub fn args() -> clap::Command {
             ),
         )
         .subcommand(Command::new("list").about("list current mounts"))
+        .subcommand(
+            Command::new("stats").about("show what a mount did so far").arg(
+                Arg::new("mount")
+                    .long("mount")
+                    .required(true)
+                    .value_parser(clap::value_parser!(OsString))
+                    .help("mount point to show the counters of"),
+            ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("print mount events as they happen")
+                .arg(
+                    Arg::new("existing")
+                        .long("existing")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("start with the current mounts"),
+                ),
+        )
+        .subcommand(
+            Command::new("state").about(
+                "show the mounts recorded by the server, even if stopped",
+            ),
+        )
+        .subcommand(Command::new("cleanup").about(
+            "detach the mounts left behind by a stopped server and forget them",
+        ))
 }
 
 pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
@@ -87,8 +187,13 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
         .subcommand()
         .expect("subcommand is required");
 
-    dispatch(socket, name, sub_args)
-        .map_err(|e| CommandError::abort(format!("abort: {e}")))
+    // These only look at the state the server leaves on disk
+    let result = match name {
+        "state" => show_state(&socket),
+        "cleanup" => cleanup(&socket),
+        _ => dispatch(socket, name, sub_args),
+    };
+    result.map_err(|e| CommandError::abort(format!("abort: {e}")))
 }
 
 #[tokio::main]
@@ -114,12 +219,22 @@ async fn dispatch(
             let request = MountRequest {
                 clone_path: clone.as_bytes().to_vec(),
                 mount_point: mount.as_bytes().to_vec(),
+                options: Some(to_proto_mount_options(mount_options(sub_args))),
+                revision: sub_args
+                    .get_one::<String>("rev")
+                    .cloned()
+                    .unwrap_or_default(),
             };
             let resp =
                 client.mount(request).await.map_err(map_status)?.into_inner();
+            let revision = match node_from_bytes(&resp.revision) {
+                Some(node) => format!(" revision {node:x}"),
+                None => String::new(),
+            };
             println!(
-                "mounted {} at {} (created {})",
+                "mounted {}{} at {} (created {})",
                 clone.display(),
+                revision,
                 mount.display(),
                 format_time(resp.created_at)
             );
@@ -142,20 +257,127 @@ async fn dispatch(
                 println!("no mounts");
             } else {
                 for m in resp.mounts {
-                    println!(
-                        "{} clone={} created={}",
-                        get_path_from_bytes(&m.mount_point).display(),
-                        get_path_from_bytes(&m.clone_path).display(),
-                        format_time(m.created_at)
-                    );
+                    println!("{}", describe_mount(&m));
                 }
             }
         }
+        "stats" => {
+            let mount_point = get_arg(sub_args, "mount");
+            let request = MountStatsRequest {
+                mount_point: mount_point.as_bytes().to_vec(),
+            };
+            let stats = client
+                .get_mount_stats(request)
+                .await
+                .map_err(map_status)?
+                .into_inner();
+            println!("revisions loaded: {}", stats.revisions_loaded);
+            println!("revision loads: {}", stats.revisions_loads);
+            println!("reads: {}", stats.reads);
+            println!("bytes served: {}", stats.bytes_served);
+            if stats.blob_cache_enabled {
+                println!("cache hits: {}", stats.blob_cache_hits);
+                println!("cache misses: {}", stats.blob_cache_misses);
+                println!("cache size: {}", stats.blob_cache_size);
+            } else {
+                println!("cache: disabled");
+            }
+        }
+        "watch" => {
+            let request = WatchMountsRequest {
+                include_existing: sub_args.get_flag("existing"),
+            };
+            let mut events = client
+                .watch_mounts(request)
+                .await
+                .map_err(map_status)?
+                .into_inner();
+            while let Some(event) =
+                events.message().await.map_err(map_status)?
+            {
+                let kind = match event.kind() {
+                    mount_event::Kind::Mounted => "mounted",
+                    mount_event::Kind::Unmounted => "unmounted",
+                };
+                let mount = event.mount.unwrap_or_default();
+                println!(
+                    "{} {} {}",
+                    format_time(event.timestamp),
+                    kind,
+                    describe_mount(&mount)
+                );
+            }
+        }
         other => return Err(format!("unknown subcommand: {other}").into()),
     }
     Ok(())
 }
 
+/// Print the mounts recorded by the server of `socket`, and whether they're
+/// still there
+fn show_state(socket: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let registry = MountRegistry::for_socket(socket);
+    let records = registry.load()?;
+    let running = is_server_running(socket)?;
+    let mounted = mounted_virtual_filesystems()?;
+    println!(
+        "server {} ({})",
+        if running { "running" } else { "stopped" },
+        registry.path().display()
+    );
+    if records.is_empty() {
+        println!("no recorded mounts");
+    }
+    for record in records {
+        let state = match (running, mounted.contains(&record.mount_point)) {
+            (true, true) => "live",
+            // Unmounted behind the server's back
+            (true, false) => "missing",
+            (false, true) => "stale",
+            (false, false) => "gone",
+        };
+        println!("{} {}", describe_record(&record), state);
+    }
+    Ok(())
+}
+
+/// Detach the mounts left behind by the stopped server of `socket`, and
+/// forget about them
+fn cleanup(socket: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if is_server_running(socket)? {
+        return Err("hgfs-server is running, unmount through it instead".into());
+    }
+    let registry = MountRegistry::for_socket(socket);
+    let records = registry.load()?;
+    let mounted = mounted_virtual_filesystems()?;
+    for record in &records {
+        let mount_point = &record.mount_point;
+        if mounted.contains(mount_point) {
+            lazy_unmount(mount_point).map_err(|e| {
+                format!("cannot detach {}: {e}", mount_point.display())
+            })?;
+            println!("detached {}", mount_point.display());
+        }
+    }
+    registry.save(vec![])?;
+    println!("forgot {} recorded mounts", records.len());
+    Ok(())
+}
+
+/// One-line summary of a recorded mount for display
+fn describe_record(record: &MountRecord) -> String {
+    let mut description = format!(
+        "{} clone={} created={}",
+        record.mount_point.display(),
+        record.clone_path.display(),
+        format_time(record.created_at)
+    );
+    if let Some(revision) = &record.revision {
+        description.push_str(&format!(" revision={revision}"));
+    }
+    description
+}
+
 /// Connect to the hgfs-server listening on `socket`.
 ///
 /// We use a custom connector rather than a `unix://<path>` URI because tonic
@@ -184,6 +406,55 @@ async fn connect_channel(
         })
 }
 
+/// Build the mount options from the arguments of the `mount` subcommand
+fn mount_options(args: &ArgMatches) -> hg_vfs::MountOptions {
+    let session_acl = if args.get_flag("open-to-all") {
+        SessionACL::All
+    } else if args.get_flag("open-to-root") {
+        SessionACL::RootAndOwner
+    } else {
+        SessionACL::Owner
+    };
+    hg_vfs::MountOptions {
+        backend_mode: args
+            .get_one::<BackendMode>("backend-mode")
+            .copied()
+            .unwrap_or_default(),
+        session_acl,
+        user_id: args.get_one("user-id").copied(),
+        group_id: args.get_one("group-id").copied(),
+        max_revisions_loaded: args.get_one("max-revisions-loaded").copied(),
+        overlay_directory: args
+            .get_one::<OsString>("overlay")
+            .map(PathBuf::from),
+    }
+}
+
+/// One-line summary of a mount for display
+fn describe_mount(mount: &MountInfo) -> String {
+    let mut description = format!(
+        "{} clone={} created={}",
+        get_path_from_bytes(&mount.mount_point).display(),
+        get_path_from_bytes(&mount.clone_path).display(),
+        format_time(mount.created_at)
+    );
+    if let Some(node) = node_from_bytes(&mount.revision) {
+        description.push_str(&format!(" revision={node:x}"));
+    }
+    let options =
+        from_proto_mount_options(mount.options.clone().unwrap_or_default());
+    description.push_str(&format!(" mode={:?}", options.backend_mode));
+    if let Some(overlay) = options.overlay_directory {
+        description.push_str(&format!(" overlay={}", overlay.display()));
+    }
+    description
+}
+
+/// Returns the node in these bytes, if they're not empty
+fn node_from_bytes(bytes: &[u8]) -> Option<Node> {
+    Node::try_from(bytes).ok()
+}
+
 /// Map a gRPC failure to the bare message without tonic's verbose wrapper.
 fn map_status(status: tonic::Status) -> Box<dyn std::error::Error> {
     status.message().to_string().into()
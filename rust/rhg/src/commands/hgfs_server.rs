@@ -8,6 +8,7 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use clap::Arg;
+use hg::Node;
 use hg::config::Config;
 use hg::errors::HgError;
 use hg::repo::Repo;
@@ -15,13 +16,16 @@ use hg::utils::files::get_bytes_from_path;
 use hg::utils::files::get_path_from_bytes;
 use hg_vfs::BackendMode;
 use hg_vfs::MountError;
+use hg_vfs::MountEventKind;
 use hg_vfs::MountManager;
-use hg_vfs::MountOptions;
+use hg_vfs::RecoveryOutcome;
 use hg_vfs::SessionACL;
+use hg_vfs::registry::MountRegistry;
 use tokio::net::UnixListener;
 use tokio::signal::unix::Signal;
 use tokio::signal::unix::SignalKind;
 use tokio::signal::unix::signal;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::wrappers::UnixListenerStream;
 use tonic::Request;
 use tonic::Response;
@@ -31,10 +35,16 @@ use vfs_api::vfs::HealthRequest;
 use vfs_api::vfs::HealthResponse;
 use vfs_api::vfs::ListMountsRequest;
 use vfs_api::vfs::ListMountsResponse;
+use vfs_api::vfs::MountEvent;
 use vfs_api::vfs::MountInfo;
+use vfs_api::vfs::MountOptions;
 use vfs_api::vfs::MountRequest;
+use vfs_api::vfs::MountStats;
+use vfs_api::vfs::MountStatsRequest;
 use vfs_api::vfs::UnmountRequest;
 use vfs_api::vfs::UnmountResponse;
+use vfs_api::vfs::WatchMountsRequest;
+use vfs_api::vfs::mount_event;
 use vfs_api::vfs::vfs_control_server::VfsControl;
 use vfs_api::vfs::vfs_control_server::VfsControlServer;
 
@@ -44,12 +54,20 @@ pub const HELP_TEXT: &str =
     "Run the hg virtual filesystem control server (EXPERIMENTAL)";
 
 pub fn args() -> clap::Command {
-    clap::command!("debug::hgfs-server").about(HELP_TEXT).arg(
-        Arg::new("socket")
-            .long("socket")
-            .value_parser(clap::value_parser!(OsString))
-            .help("path to the control socket to bind"),
-    )
+    clap::command!("debug::hgfs-server")
+        .about(HELP_TEXT)
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .value_parser(clap::value_parser!(OsString))
+                .help("path to the control socket to bind"),
+        )
+        .arg(
+            Arg::new("remount")
+                .long("remount")
+                .action(clap::ArgAction::SetTrue)
+                .help("re-establish the mounts of the previous server"),
+        )
 }
 
 /// gRPC service implementing the `VfsControl` interface.
@@ -81,9 +99,13 @@ impl VfsControl for VfsControlService {
         let clone_path = get_path_from_bytes(&request.clone_path).to_path_buf();
         let mount_point =
             get_path_from_bytes(&request.mount_point).to_path_buf();
+        let options =
+            from_proto_mount_options(request.options.unwrap_or_default());
+        let revision = request.revision;
         tracing::info!(
             clone_path = %clone_path.display(),
             mount_point = %mount_point.display(),
+            revision,
             "Mount requested"
         );
 
@@ -99,9 +121,19 @@ impl VfsControl for VfsControlService {
                         _ => Status::internal(format!("opening clone: {e}")),
                     },
                 )?;
-                manager
-                    .mount_all_revs(repo, mount_point, default_mount_options())
-                    .map_err(mount_error_to_status)
+                if revision.is_empty() {
+                    manager.mount_all_revs(repo, mount_point, options)
+                } else {
+                    let changeset = resolve_revision(&repo, &revision)
+                        .map_err(Status::invalid_argument)?;
+                    manager.mount_revision(
+                        repo,
+                        changeset,
+                        mount_point,
+                        options,
+                    )
+                }
+                .map_err(mount_error_to_status)
             },
         )
         .await
@@ -141,6 +173,58 @@ impl VfsControl for VfsControlService {
         .map_err(|e| Status::internal(format!("list mounts: {e}")))?;
         Ok(Response::new(ListMountsResponse { mounts }))
     }
+
+    async fn get_mount_stats(
+        &self,
+        req: Request<MountStatsRequest>,
+    ) -> Result<Response<MountStats>, Status> {
+        let mount_point =
+            get_path_from_bytes(&req.into_inner().mount_point).to_path_buf();
+        let stats = self
+            .manager
+            .mount_stats(&mount_point)
+            .map_err(mount_error_to_status)?;
+        let blob_cache = stats.blob_cache.unwrap_or_default();
+        Ok(Response::new(MountStats {
+            revisions_loaded: stats.revisions_loaded,
+            revisions_loads: stats.revisions_loads,
+            reads: stats.reads,
+            bytes_served: stats.bytes_served,
+            blob_cache_enabled: stats.blob_cache.is_some(),
+            blob_cache_hits: blob_cache.hits,
+            blob_cache_misses: blob_cache.misses,
+            blob_cache_size: blob_cache.size,
+        }))
+    }
+
+    type WatchMountsStream =
+        UnboundedReceiverStream<Result<MountEvent, Status>>;
+
+    async fn watch_mounts(
+        &self,
+        req: Request<WatchMountsRequest>,
+    ) -> Result<Response<Self::WatchMountsStream>, Status> {
+        tracing::info!("Mount events watched");
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        // The watcher is dropped at the first event after the client went away
+        self.manager.watch(req.into_inner().include_existing, move |event| {
+            sender.send(Ok(to_proto_mount_event(event))).is_ok()
+        });
+        Ok(Response::new(UnboundedReceiverStream::new(receiver)))
+    }
+}
+
+/// Resolve `revision` to a changeset of `repo`, for single-revision mounts
+fn resolve_revision(repo: &Repo, revision: &str) -> Result<Node, String> {
+    let invalid = |e: HgError| format!("cannot resolve {revision}: {e}");
+    let Some(rev) = hg::revset::resolve_single(revision, repo)
+        .map_err(invalid)?
+        .exclude_wdir()
+    else {
+        return Err("cannot mount the working directory revision".into());
+    };
+    let changelog = repo.changelog().map_err(invalid)?;
+    Ok(*changelog.node_from_rev(rev))
 }
 
 fn to_proto_mount_info(info: hg_vfs::MountInfo) -> MountInfo {
@@ -148,6 +232,73 @@ fn to_proto_mount_info(info: hg_vfs::MountInfo) -> MountInfo {
         created_at: info.created_at,
         clone_path: get_bytes_from_path(&info.clone_path),
         mount_point: get_bytes_from_path(&info.mount_point),
+        options: Some(to_proto_mount_options(info.options)),
+        revision: info
+            .revision
+            .map(|node| node.as_bytes().to_vec())
+            .unwrap_or_default(),
+    }
+}
+
+fn to_proto_mount_event(event: &hg_vfs::MountEvent) -> MountEvent {
+    let kind = match event.kind {
+        MountEventKind::Mounted => mount_event::Kind::Mounted,
+        MountEventKind::Unmounted => mount_event::Kind::Unmounted,
+    };
+    MountEvent {
+        kind: kind.into(),
+        timestamp: event.timestamp,
+        mount: Some(to_proto_mount_info(event.info.clone())),
+    }
+}
+
+pub fn to_proto_mount_options(options: hg_vfs::MountOptions) -> MountOptions {
+    let backend_mode = match options.backend_mode {
+        BackendMode::Full => vfs_api::vfs::BackendMode::Full,
+        BackendMode::Archive => vfs_api::vfs::BackendMode::Archive,
+        BackendMode::Thin => vfs_api::vfs::BackendMode::Thin,
+    };
+    let session_acl = match options.session_acl {
+        SessionACL::Owner => vfs_api::vfs::SessionAcl::Owner,
+        SessionACL::RootAndOwner => vfs_api::vfs::SessionAcl::RootAndOwner,
+        SessionACL::All => vfs_api::vfs::SessionAcl::All,
+    };
+    MountOptions {
+        backend_mode: backend_mode.into(),
+        session_acl: session_acl.into(),
+        user_id: options.user_id,
+        group_id: options.group_id,
+        max_revisions_loaded: options.max_revisions_loaded.map(|n| n as u64),
+        overlay_directory: options
+            .overlay_directory
+            .map(|path| get_bytes_from_path(&path))
+            .unwrap_or_default(),
+    }
+}
+
+pub fn from_proto_mount_options(options: MountOptions) -> hg_vfs::MountOptions {
+    let backend_mode = match options.backend_mode() {
+        vfs_api::vfs::BackendMode::Full => BackendMode::Full,
+        vfs_api::vfs::BackendMode::Archive => BackendMode::Archive,
+        vfs_api::vfs::BackendMode::Thin => BackendMode::Thin,
+    };
+    let session_acl = match options.session_acl() {
+        vfs_api::vfs::SessionAcl::Owner => SessionACL::Owner,
+        vfs_api::vfs::SessionAcl::RootAndOwner => SessionACL::RootAndOwner,
+        vfs_api::vfs::SessionAcl::All => SessionACL::All,
+    };
+    let max_revisions_loaded = options
+        .max_revisions_loaded
+        .map(|n| usize::try_from(n).unwrap_or(usize::MAX));
+    let overlay_directory = (!options.overlay_directory.is_empty())
+        .then(|| get_path_from_bytes(&options.overlay_directory).to_path_buf());
+    hg_vfs::MountOptions {
+        backend_mode,
+        session_acl,
+        user_id: options.user_id,
+        group_id: options.group_id,
+        max_revisions_loaded,
+        overlay_directory,
     }
 }
 
@@ -167,16 +318,6 @@ fn mount_error_to_status(e: MountError) -> Status {
     }
 }
 
-fn default_mount_options() -> MountOptions {
-    MountOptions {
-        backend_mode: BackendMode::default(),
-        session_acl: SessionACL::Owner,
-        user_id: None,
-        group_id: None,
-        max_revisions_loaded: None,
-    }
-}
-
 pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
     let socket_path =
         match invocation.subcommand_args.get_one::<OsString>("socket") {
@@ -189,13 +330,17 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
             })?,
         };
 
-    serve(socket_path).map_err(|e| {
+    let remount = invocation.subcommand_args.get_flag("remount");
+    serve(socket_path, remount).map_err(|e| {
         CommandError::abort(format!("abort: hgfs-server error: {e}"))
     })
 }
 
 #[tokio::main]
-async fn serve(socket_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+async fn serve(
+    socket_path: PathBuf,
+    remount: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     let lock = take_socket_lock(&socket_path)?;
     match std::fs::remove_file(&socket_path) {
         Ok(()) => {
@@ -220,7 +365,10 @@ async fn serve(socket_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
         Config::load_non_repo()
             .map_err(|e| format!("loading config: {e:?}"))?,
     );
-    let manager = Arc::new(MountManager::new());
+    let manager = Arc::new(MountManager::with_registry(
+        MountRegistry::for_socket(&socket_path),
+    ));
+    recover_mounts(Arc::clone(&manager), Arc::clone(&config), remount).await?;
     let service = VfsControlService { manager, config };
 
     let serve_result = Server::builder()
@@ -237,6 +385,58 @@ async fn serve(socket_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Clean up after the previous server of this socket, re-establishing its
+/// mounts if asked to
+async fn recover_mounts(
+    manager: Arc<MountManager>,
+    config: Arc<Config>,
+    remount: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let recoveries =
+        tokio::task::spawn_blocking(move || manager.recover(&config, remount))
+            .await?
+            .map_err(|e| format!("recovering mounts: {e}"))?;
+    for recovery in recoveries {
+        let mount_point = recovery.record.mount_point.display();
+        if recovery.detached {
+            println!("detached stale mount {mount_point}");
+        }
+        match recovery.outcome {
+            RecoveryOutcome::Dropped => {}
+            RecoveryOutcome::Remounted(_) => {
+                println!("remounted {mount_point}")
+            }
+            RecoveryOutcome::Failed(e) => {
+                let status = mount_error_to_status(e);
+                println!(
+                    "failed to recover {mount_point}: {}",
+                    status.message()
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether a server is running for this socket, i.e. whether its lock
+/// file is held
+pub fn is_server_running(socket_path: &Path) -> std::io::Result<bool> {
+    let lock_path = socket_lock_file(socket_path);
+    let file = match File::open(&lock_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    match file.try_lock() {
+        Ok(()) => {
+            file.unlock()?;
+            Ok(false)
+        }
+        Err(std::fs::TryLockError::WouldBlock) => Ok(true),
+        Err(std::fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
 /// Take an exclusive advisory lock on the socket's lock file, proving we're the
 /// only server for this socket. It is released automatically if the server
 /// dies, so a lock file that exists but isn't held means the previous owner is
@@ -0,0 +1,121 @@
+//! `hgfs_store_server` serves a repository's store to remote virtual shares.
+
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::Arg;
+use hg::repo::Repo;
+use hg_fuse::server::local::LocalBackend;
+use hg_fuse::server::remote::StoreService;
+use hg_fuse::server::store::BackendMode;
+use tokio::net::UnixListener;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::Server;
+
+use crate::error::CommandError;
+
+pub const HELP_TEXT: &str =
+    "Serve the repository's store to remote virtual shares (EXPERIMENTAL)";
+
+pub fn args() -> clap::Command {
+    clap::command!("debug::hgfs-store-server")
+        .about(HELP_TEXT)
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .value_parser(clap::value_parser!(OsString))
+                .help("path to the Unix socket to bind"),
+        )
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_parser(clap::value_parser!(SocketAddr))
+                .help("TCP address to listen on")
+                .conflicts_with("socket"),
+        )
+}
+
+/// Where to listen for clients
+enum Listen {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let repo = invocation.repo?;
+    let listen = if let Some(address) =
+        invocation.subcommand_args.get_one::<SocketAddr>("listen")
+    {
+        Listen::Tcp(*address)
+    } else if let Some(socket) =
+        invocation.subcommand_args.get_one::<OsString>("socket")
+    {
+        Listen::Unix(PathBuf::from(socket))
+    } else {
+        return Err(CommandError::abort(
+            "abort: one of --socket or --listen is required",
+        ));
+    };
+    // Recreate an owned repo for the backend
+    let backend_repo = Repo::find(
+        repo.config(),
+        Some(repo.working_directory_path().to_path_buf()),
+    )?;
+    // The backend mode only matters to the client, which picks its own
+    let store = LocalBackend::new(backend_repo, BackendMode::default())?;
+
+    serve(StoreService::new(store), listen).map_err(|e| {
+        CommandError::abort(format!("abort: hgfs-store-server error: {e}"))
+    })
+}
+
+#[tokio::main]
+async fn serve(
+    service: StoreService<LocalBackend>,
+    listen: Listen,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Server::builder().add_service(service.into_server());
+    match listen {
+        Listen::Tcp(address) => {
+            println!(
+                "hgfs-store-server listening on {} (pid {})",
+                address,
+                std::process::id()
+            );
+            server.serve_with_shutdown(address, shutdown_signal()).await?;
+        }
+        Listen::Unix(socket_path) => {
+            let listener = UnixListener::bind(&socket_path)?;
+            println!(
+                "hgfs-store-server listening on {} (pid {})",
+                socket_path.display(),
+                std::process::id()
+            );
+            let serve_result = server
+                .serve_with_incoming_shutdown(
+                    UnixListenerStream::new(listener),
+                    shutdown_signal(),
+                )
+                .await;
+            let () = std::fs::remove_file(&socket_path)?;
+            serve_result?;
+        }
+    }
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    let (Ok(mut sigterm), Ok(mut sigint)) =
+        (signal(SignalKind::terminate()), signal(SignalKind::interrupt()))
+    else {
+        tracing::error!("failed to listen for termination signals");
+        return;
+    };
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+}
@@ -0,0 +1,646 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::NULL_NODE;
+use hg::NULL_REVISION;
+use hg::Node;
+use hg::Revision;
+use hg::bookmarks::read_bookmarks;
+use hg::bundle::Bundle;
+use hg::bundle::caps::Role;
+use hg::bundle::caps::encode_caps;
+use hg::bundle::caps::obsolescence_configured;
+use hg::bundle::caps::repo_caps;
+use hg::bundle::parts::changegroup_version;
+use hg::bundle::read_bundle;
+use hg::changegroup::ChangegroupUnpacker;
+use hg::config::Config;
+use hg::discovery::Outgoing;
+use hg::discovery::find_common_incoming;
+use hg::errors::HgError;
+use hg::peer::GetBundleArgs;
+use hg::peer::Peer;
+use hg::repo::Repo;
+use hg::revlog::RevlogError;
+use hg::revlog::changelog::Changelog;
+use hg::revlog::changelog::ChangelogRevisionData;
+use hg::revlog::index::Phase;
+use hg::tags::global_tags;
+use hg::utils::hg_path::HgPath;
+use hg::utils::strings::SliceExt;
+use hg::utils::strings::url_quote;
+
+use crate::commands::pull;
+use crate::commands::pull::RemoteOptions;
+use crate::commands::pull::Source;
+use crate::error::CommandError;
+use crate::ui::StdoutBuffer;
+use crate::ui::Ui;
+
+pub const HELP_TEXT: &str = "
+show new changesets found in source
+
+Show new changesets found in the specified path/URL or the default
+pull location. These are the changesets that would have been pulled
+by 'hg pull' at the time you issued this command.
+
+Only local repositories and ssh:// URLs are supported.
+
+Returns 0 if there are incoming changes, 1 otherwise.
+";
+
+pub fn args() -> clap::Command {
+    let command = clap::command!("incoming")
+        .args_override_self(true)
+        .arg(
+            Arg::new("source")
+                .help("the repository to compare with")
+                .value_name("SOURCE"),
+        )
+        .arg(
+            Arg::new("force")
+                .help("run even if remote repository is unrelated")
+                .short('f')
+                .long("force")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("rev")
+                .help("a remote changeset intended to be added")
+                .short('r')
+                .long("rev")
+                .value_name("REV")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("suppress output")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT);
+    pull::remote_args(display_args(command))
+}
+
+/// Adds the options of `hg log` that `incoming` and `outgoing` support
+pub fn display_args(command: clap::Command) -> clap::Command {
+    command
+        .arg(
+            Arg::new("newest-first")
+                .help("show newest record first")
+                .short('n')
+                .long("newest-first")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("limit")
+                .help("limit number of changes displayed")
+                .short('l')
+                .long("limit")
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::new("no-merges")
+                .help("do not show merges")
+                .short('M')
+                .long("no-merges")
+                .action(clap::ArgAction::SetTrue),
+        )
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg incoming")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let repo = invocation.repo?;
+    let quiet = args.get_flag("quiet") || config.get_bool(b"ui", b"quiet")?;
+    let display = DisplayOptions::from_args(args, config, quiet)?;
+    let source = Source::resolve(
+        repo,
+        args.get_one::<String>("source").map(String::as_str),
+    )?;
+    let remote = RemoteOptions::from_args(args);
+    let revs: Vec<&String> =
+        args.get_many("rev").map(Iterator::collect).unwrap_or_default();
+    let force = args.get_flag("force");
+    if obsolescence_configured(repo) {
+        return Err(CommandError::unsupported("exchanging obsolescence"));
+    }
+    let status = |message: &[u8]| -> Result<(), CommandError> {
+        if !quiet {
+            ui.write_stdout(message)?;
+        }
+        Ok(())
+    };
+
+    // Unlike `pull`, Python connects before telling where it compares with
+    let local_source = source.open_local(config)?;
+    let mut peer =
+        source
+            .connect(ui, config, local_source.as_ref(), &remote, &|| Ok(()))?;
+    let result = (|| {
+        if let Some(remote) = peer.local()
+            && obsolescence_configured(remote)
+        {
+            return Err(CommandError::unsupported("exchanging obsolescence"));
+        }
+        status(&format_bytes!(
+            b"comparing with {}\n",
+            source.location.as_bytes()
+        ))?;
+        pull::print_messages(ui, &mut *peer, true, quiet)?;
+        let heads = revs
+            .iter()
+            .map(|rev| peer.lookup(rev.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let incoming =
+            find_common_incoming(repo, &mut *peer, &heads, force, || {
+                status(b"searching for changes\n")
+            })?;
+        if incoming.unrelated {
+            ui.write_stderr(b"warning: repository is unrelated\n")?;
+        }
+        if !incoming.any_incoming {
+            status(b"no changes found\n")?;
+            return Ok(false);
+        }
+        let common = incoming.common;
+        let remote_heads: Vec<Node> = incoming
+            .remote_heads
+            .into_iter()
+            .filter(|node| !common.contains(node))
+            .collect();
+        let shown = match peer.local() {
+            Some(remote) => {
+                show_remote(ui, remote, &display, &common, &remote_heads)?
+            }
+            None => show_bundle(
+                ui,
+                repo,
+                &mut *peer,
+                &display,
+                common,
+                remote_heads,
+            )?,
+        };
+        if !shown {
+            status(b"no changes found\n")?;
+        }
+        Ok(shown)
+    })();
+    let closed = peer.close();
+    pull::print_messages(ui, &mut *peer, false, quiet)?;
+    let shown = result?;
+    closed?;
+    if shown {
+        Ok(())
+    } else {
+        Err(CommandError::Unsuccessful)
+    }
+}
+
+/// Shows the changesets of the local repository `remote` that are missing
+/// locally, returning whether there were any
+fn show_remote(
+    ui: &Ui,
+    remote: &Repo,
+    display: &DisplayOptions,
+    common: &[Node],
+    heads: &[Node],
+) -> Result<bool, CommandError> {
+    let changelog = remote.changelog()?;
+    let to_revs = |nodes: &[Node]| -> Result<Vec<Revision>, RevlogError> {
+        nodes
+            .iter()
+            .filter(|&&node| node != NULL_NODE)
+            .map(|&node| changelog.rev_from_node(node.into()))
+            .collect()
+    };
+    let missing =
+        Outgoing::new(changelog.get_index(), to_revs(common)?, to_revs(heads)?)
+            .map_err(RevlogError::from)?
+            .missing;
+    if missing.is_empty() {
+        return Ok(false);
+    }
+    let phases = remote
+        .store()
+        .phase_cache()?
+        .phases(changelog.get_index())
+        .map_err(RevlogError::from)?;
+    let tip = changelog.get_index().len() as i32 - 1;
+    let names = Names::read(remote, *changelog.node_from_rev(Revision(tip)))?;
+    display.show(ui, missing, |rev| {
+        repo_changeset(&changelog, &phases, &names, rev)
+    })?;
+    Ok(true)
+}
+
+/// Shows the changesets that `peer` sends in a bundle, numbered after the
+/// local ones like in the `bundlerepo` of Python, returning whether there
+/// were any
+fn show_bundle(
+    ui: &Ui,
+    repo: &Repo,
+    peer: &mut dyn Peer,
+    display: &DisplayOptions,
+    common: Vec<Node>,
+    heads: Vec<Node>,
+) -> Result<bool, CommandError> {
+    let caps = encode_caps(&repo_caps(repo, Role::Client)?);
+    let args = GetBundleArgs {
+        heads,
+        common,
+        bundle_caps: vec![
+            b"HG20".to_vec(),
+            [&b"bundle2="[..], &url_quote(&caps)].concat(),
+        ],
+        changegroup: true,
+        phases: false,
+    };
+    let changelog = repo.changelog()?;
+    let url = peer.url().to_owned();
+    let mut result = Ok(vec![]);
+    let fetched = peer.get_bundle(&args, &mut |reader| {
+        result = read_changesets(&changelog, reader, &url);
+        match &result {
+            Ok(_) => Ok(()),
+            // Stops reading, the actual error is in `result`
+            Err(_) => Err(HgError::abort_simple("incoming interrupted")),
+        }
+    });
+    let texts = result?;
+    fetched?;
+    if texts.is_empty() {
+        return Ok(false);
+    }
+
+    let local_phases = repo
+        .store()
+        .phase_cache()?
+        .phases(changelog.get_index())
+        .map_err(RevlogError::from)?;
+    let remote_phases = peer.list_keys(b"phases")?;
+    let publishing = remote_phases
+        .iter()
+        .any(|(key, value)| key == b"publishing" && value == b"True");
+    let draft_roots: Vec<Node> = remote_phases
+        .iter()
+        .filter(|(key, value)| key != b"publishing" && value == b"1")
+        .filter_map(|(key, _)| Node::from_hex(key).ok())
+        .collect();
+    let local_len = changelog.get_index().len();
+    let mut bundle_revs: HashMap<Node, (Revision, Phase)> = HashMap::new();
+    let mut changesets = vec![];
+    for (index, delta) in texts.iter().enumerate() {
+        let rev = Revision((local_len + index) as i32);
+        let data = ChangelogRevisionData::new(rev, delta.1.clone().into())?;
+        if data.files().any(|file| file == HgPath::new(b".hgtags")) {
+            return Err(CommandError::unsupported("incoming tags"));
+        }
+        let mut parents = vec![];
+        for (position, parent) in
+            [delta.0.p1, delta.0.p2].into_iter().enumerate()
+        {
+            if parent == NULL_NODE && position == 1 {
+                continue;
+            }
+            let (parent_rev, phase) = if parent == NULL_NODE {
+                (NULL_REVISION, Phase::Public)
+            } else if let Some(&(parent_rev, phase)) = bundle_revs.get(&parent)
+            {
+                (parent_rev, phase)
+            } else {
+                let parent_rev = changelog.rev_from_node(parent.into())?;
+                let phase = if publishing {
+                    Phase::Public
+                } else {
+                    local_phases[parent_rev.0 as usize]
+                };
+                (parent_rev, phase)
+            };
+            parents.push((parent_rev, parent, phase));
+        }
+        // Like `_pullapplyphases` in Python, as the phases of the remote
+        // apply to the bundle
+        let phase = if publishing {
+            Phase::Public
+        } else if draft_roots.contains(&delta.0.node)
+            || parents.iter().any(|parent| parent.2 != Phase::Public)
+        {
+            Phase::Draft
+        } else {
+            Phase::Public
+        };
+        bundle_revs.insert(delta.0.node, (rev, phase));
+        changesets.push(Some(Changeset {
+            rev,
+            node: delta.0.node,
+            phase,
+            data,
+            parents,
+            bookmarks: vec![],
+            tags: vec![],
+        }));
+    }
+    // Tags come from the local repository, the bundle leaving `.hgtags`
+    // untouched, except for `tip` which is the last changeset of the bundle
+    let names = Names::read(repo, NULL_NODE)?;
+    if let Some(Some(tip)) = changesets.last_mut() {
+        tip.tags.push(b"tip".to_vec());
+    }
+    let revs: Vec<usize> = (0..changesets.len()).collect();
+    display.show(ui, revs, |index| {
+        let mut changeset =
+            changesets[index].take().expect("changeset shown once");
+        let mut tags = names.tags(changeset.node);
+        tags.append(&mut changeset.tags);
+        tags.sort();
+        changeset.tags = tags;
+        Ok(changeset)
+    })?;
+    Ok(true)
+}
+
+/// A changeset of a bundle, with its data
+type BundleChangeset = (hg::changegroup::RevisionDelta, Vec<u8>);
+
+/// Reads the changesets of the changegroup in the bundle of `reader`,
+/// resolving their deltas against `changelog` or earlier ones
+fn read_changesets(
+    changelog: &Changelog,
+    reader: &mut dyn std::io::Read,
+    name: &str,
+) -> Result<Vec<BundleChangeset>, CommandError> {
+    let Bundle::Bundle2(mut bundle) = read_bundle(reader, name)? else {
+        return Err(CommandError::unsupported("incoming bundle1"));
+    };
+    let mut changesets: Vec<BundleChangeset> = vec![];
+    let mut by_node: HashMap<Node, usize> = HashMap::new();
+    while let Some(mut part) = bundle.next_part()? {
+        match part.header.part_type.as_str() {
+            "changegroup" => {}
+            "hgtagsfnodes" | "cache:rev-branch-cache" | "output" => continue,
+            part_type => {
+                return Err(CommandError::unsupported(format!(
+                    "bundle2 part {}",
+                    part_type
+                )));
+            }
+        }
+        if !changesets.is_empty() {
+            return Err(CommandError::unsupported("several changegroups"));
+        }
+        let version = changegroup_version(&part.header)?;
+        let mut unpacker = ChangegroupUnpacker::new(&mut part, version);
+        while let Some(delta) = unpacker.delta_chunk()? {
+            let base = if delta.delta_base == NULL_NODE {
+                vec![]
+            } else if let Some(&index) = by_node.get(&delta.delta_base) {
+                changesets[index].1.clone()
+            } else {
+                let rev = changelog.rev_from_node(delta.delta_base.into())?;
+                changelog.entry(rev)?.as_revlog_entry().data()?.to_vec()
+            };
+            let data = delta.apply(&base)?;
+            by_node.insert(delta.node, changesets.len());
+            changesets.push((delta, data));
+        }
+    }
+    Ok(changesets)
+}
+
+/// How `incoming` and `outgoing` show changesets, like the default
+/// `changesetprinter` of Python
+pub(crate) struct DisplayOptions {
+    limit: Option<usize>,
+    no_merges: bool,
+    newest_first: bool,
+    quiet: bool,
+}
+
+impl DisplayOptions {
+    pub(crate) fn from_args(
+        args: &clap::ArgMatches,
+        config: &Config,
+        quiet: bool,
+    ) -> Result<Self, CommandError> {
+        pull::check_verbosity(config)?;
+        for (section, item) in [
+            (&b"ui"[..], &b"logtemplate"[..]),
+            (b"ui", b"style"),
+            (b"command-templates", b"log"),
+        ] {
+            if config.get(section, item).is_some() {
+                return Err(CommandError::unsupported("log templates"));
+            }
+        }
+        let limit = match args.get_one::<String>("limit") {
+            None => None,
+            Some(limit) => match limit.parse::<usize>() {
+                Ok(limit) if limit > 0 => Some(limit),
+                _ => {
+                    return Err(CommandError::unsupported(
+                        "invalid changeset limit",
+                    ));
+                }
+            },
+        };
+        Ok(Self {
+            limit,
+            no_merges: args.get_flag("no-merges"),
+            newest_first: args.get_flag("newest-first"),
+            quiet,
+        })
+    }
+
+    /// Shows the changesets of `revs`, in this order unless showing the
+    /// newest first
+    pub(crate) fn show<R>(
+        &self,
+        ui: &Ui,
+        mut revs: Vec<R>,
+        mut changeset: impl FnMut(R) -> Result<Changeset, CommandError>,
+    ) -> Result<(), CommandError> {
+        if self.newest_first {
+            revs.reverse();
+        }
+        let mut stdout = ui.stdout_buffer();
+        let mut count = 0;
+        for rev in revs {
+            if self.limit.is_some_and(|limit| count >= limit) {
+                break;
+            }
+            let changeset = changeset(rev)?;
+            if self.no_merges && changeset.parents.len() == 2 {
+                continue;
+            }
+            count += 1;
+            show_changeset(&mut stdout, &changeset, self.quiet)?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// What the default output of `hg log` shows about a changeset
+pub(crate) struct Changeset {
+    rev: Revision,
+    node: Node,
+    phase: Phase,
+    data: ChangelogRevisionData,
+    /// The first parent, and the second one unless null, with their phase
+    parents: Vec<(Revision, Node, Phase)>,
+    bookmarks: Vec<Vec<u8>>,
+    tags: Vec<Vec<u8>>,
+}
+
+/// The changeset `rev` of a repository, given its `phases` and `names`
+pub(crate) fn repo_changeset(
+    changelog: &Changelog,
+    phases: &[Phase],
+    names: &Names,
+    rev: Revision,
+) -> Result<Changeset, CommandError> {
+    let entry = changelog.entry(rev)?;
+    let revlog_entry = entry.as_revlog_entry();
+    let parent = |rev: Option<Revision>| match rev {
+        Some(rev) => {
+            (rev, *changelog.node_from_rev(rev), phases[rev.0 as usize])
+        }
+        None => (NULL_REVISION, NULL_NODE, Phase::Public),
+    };
+    let mut parents = vec![parent(revlog_entry.p1())];
+    if let Some(p2) = revlog_entry.p2() {
+        parents.push(parent(Some(p2)));
+    }
+    let node = *changelog.node_from_rev(rev);
+    Ok(Changeset {
+        rev,
+        node,
+        phase: phases[rev.0 as usize],
+        data: entry.data()?,
+        parents,
+        bookmarks: names.bookmarks(node),
+        tags: names.tags(node),
+    })
+}
+
+/// The bookmarks and tags of a repository by changeset
+pub(crate) struct Names {
+    bookmarks: HashMap<Node, Vec<Vec<u8>>>,
+    tags: HashMap<Node, Vec<Vec<u8>>>,
+}
+
+impl Names {
+    /// Reads the names of `repo`, whose tip is `tip`
+    pub(crate) fn read(repo: &Repo, tip: Node) -> Result<Self, CommandError> {
+        if repo.hg_vfs().join("localtags").exists() {
+            return Err(CommandError::unsupported("local tags"));
+        }
+        let mut bookmarks: HashMap<Node, Vec<Vec<u8>>> = HashMap::new();
+        for (name, node) in read_bookmarks(repo)? {
+            bookmarks.entry(node).or_default().push(name);
+        }
+        let mut tags: HashMap<Node, Vec<Vec<u8>>> = HashMap::new();
+        for (name, node) in global_tags(repo)? {
+            tags.entry(node).or_default().push(name);
+        }
+        if tip != NULL_NODE {
+            tags.entry(tip).or_default().push(b"tip".to_vec());
+        }
+        for names in bookmarks.values_mut().chain(tags.values_mut()) {
+            names.sort();
+        }
+        Ok(Self { bookmarks, tags })
+    }
+
+    fn bookmarks(&self, node: Node) -> Vec<Vec<u8>> {
+        self.bookmarks.get(&node).cloned().unwrap_or_default()
+    }
+
+    fn tags(&self, node: Node) -> Vec<Vec<u8>> {
+        self.tags.get(&node).cloned().unwrap_or_default()
+    }
+}
+
+/// Shows `changeset` like `changesetprinter._show` in Python
+fn show_changeset(
+    stdout: &mut StdoutBuffer<impl Write>,
+    changeset: &Changeset,
+    quiet: bool,
+) -> Result<(), CommandError> {
+    let id = |rev: Revision, node: &Node| {
+        format!("{}:{:x}", rev.0, node.short()).into_bytes()
+    };
+    let phase_label = |phase: Phase| format!("changeset.{}", phase.name());
+    if quiet {
+        let line = format_bytes!(b"{}\n", id(changeset.rev, &changeset.node));
+        stdout.write_stdout_labelled(&line, "log.node")?;
+        return Ok(());
+    }
+    stdout.write_stdout_labelled(
+        &format_bytes!(
+            b"changeset:   {}\n",
+            id(changeset.rev, &changeset.node)
+        ),
+        &format!("log.changeset {}", phase_label(changeset.phase)),
+    )?;
+    let extra = changeset.data.extra()?;
+    if let Some(branch) = extra.get("branch")
+        && branch != b"default"
+    {
+        stdout.write_stdout_labelled(
+            &format_bytes!(b"branch:      {}\n", branch),
+            "log.branch",
+        )?;
+    }
+    for bookmark in &changeset.bookmarks {
+        stdout.write_stdout_labelled(
+            &format_bytes!(b"bookmark:    {}\n", bookmark),
+            "log.bookmark",
+        )?;
+    }
+    for tag in &changeset.tags {
+        stdout.write_stdout_labelled(
+            &format_bytes!(b"tag:         {}\n", tag),
+            "log.tag",
+        )?;
+    }
+    // Only the parents that are not implied, like `meaningfulparents`
+    let parents = match &changeset.parents[..] {
+        [p1] if p1.0.0 >= changeset.rev.0 - 1 => &[][..],
+        parents => parents,
+    };
+    for (rev, node, phase) in parents {
+        stdout.write_stdout_labelled(
+            &format_bytes!(b"parent:      {}\n", id(*rev, node)),
+            &format!("log.parent {}", phase_label(*phase)),
+        )?;
+    }
+    stdout.write_stdout_labelled(
+        &format_bytes!(b"user:        {}\n", changeset.data.user()),
+        "log.user",
+    )?;
+    let date = changeset.data.timestamp()?.format("%a %b %d %H:%M:%S %Y %z");
+    stdout.write_stdout_labelled(
+        format!("date:        {}\n", date).as_bytes(),
+        "log.date",
+    )?;
+    let description = changeset.data.description().trim();
+    if let Some(summary) =
+        description.split(|&b| b == b'\n' || b == b'\r').next()
+        && !description.is_empty()
+    {
+        stdout.write_stdout_labelled(
+            &format_bytes!(b"summary:     {}\n", summary),
+            "log.summary",
+        )?;
+    }
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use clap::Arg;
+use hg::exit_codes;
+use hg::narrow;
+use hg::operations::list_all_tracked_files;
+use hg::operations::list_revset_tracked_files;
+use hg::revlog::manifest::ManifestFlags;
+use hg::warnings::HgWarningContext;
+
+use crate::error::CommandError;
+use crate::ui::print_warnings;
+
+pub const HELP_TEXT: &str = "
+output the current or given revision of the project manifest
+
+Print a list of version controlled files for the given revision.
+If no revision is given, the first parent of the working directory
+is used, or the null revision if no revision is checked out.
+
+With -v, print file permissions, symlink and executable bits.
+With --debug, print file revision hashes.
+
+If option --all is specified, the list of all files from all revisions
+is printed. This includes deleted and renamed files.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("manifest")
+        .args_override_self(true)
+        .arg(
+            Arg::new("rev")
+                .help("revision to display")
+                .short('r')
+                .long("rev")
+                .value_name("REV"),
+        )
+        .arg(Arg::new("node").help("revision to display").value_name("REV"))
+        .arg(
+            Arg::new("all")
+                .help("list files from all revisions")
+                .long("all")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("template")
+                .help("display with template")
+                .short('T')
+                .long("template")
+                .value_name("TEMPLATE"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("print file permissions and symlink markers")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("debug")
+                .help("also print file revision hashes")
+                .long("debug")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg manifest")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    if args.contains_id("template") {
+        return Err(CommandError::unsupported("manifest -T"));
+    }
+    let debug = args.get_flag("debug") || config.get_bool(b"ui", b"debug")?;
+    let verbose = debug
+        || args.get_flag("verbose")
+        || config.get_bool(b"ui", b"verbose")?;
+
+    let rev =
+        match (args.get_one::<String>("rev"), args.get_one::<String>("node")) {
+            (Some(_), Some(_)) => {
+                return Err(CommandError::abort_with_exit_code(
+                    "abort: please specify just one revision",
+                    exit_codes::INPUT_ERROR,
+                ));
+            }
+            (rev, node) => rev.or(node),
+        };
+
+    let repo = invocation.repo?;
+    let mut stdout = invocation.ui.stdout_buffer();
+
+    if args.get_flag("all") {
+        if rev.is_some() {
+            return Err(CommandError::abort_with_exit_code(
+                "abort: can't specify a revision with --all",
+                exit_codes::INPUT_ERROR,
+            ));
+        }
+        if repo.has_narrow() {
+            // The store only has the files of the narrowspec
+            return Err(CommandError::unsupported("manifest --all in narrow"));
+        }
+        if repo
+            .store_vfs()
+            .join("obsstore")
+            .metadata()
+            .is_ok_and(|m| m.len() > 0)
+        {
+            return Err(CommandError::unsupported("hidden revisions"));
+        }
+        for path in list_all_tracked_files(repo)? {
+            stdout.write_all(path.as_bytes())?;
+            stdout.write_all(b"\n")?;
+        }
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    let warnings = HgWarningContext::new();
+    let narrow_matcher = narrow::matcher(repo, warnings.sender())?;
+    print_warnings(invocation.ui, warnings, repo.working_directory_path());
+    let files = list_revset_tracked_files(
+        repo,
+        rev.map_or(".", |rev| rev),
+        narrow_matcher,
+    )?;
+    for entry in files.iter() {
+        let entry = entry?;
+        if debug {
+            stdout.write_all(format!("{:x} ", entry.node).as_bytes())?;
+        }
+        if verbose {
+            stdout.write_all(mode_and_type(entry.flags))?;
+        }
+        stdout.write_all(entry.path.as_bytes())?;
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// The permissions and type marker printed by `manifest -v`
+fn mode_and_type(flags: ManifestFlags) -> &'static [u8] {
+    if flags.is_link() {
+        b"644 @ "
+    } else if flags.is_exec() {
+        b"755 * "
+    } else {
+        b"644   "
+    }
+}
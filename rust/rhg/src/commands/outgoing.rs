@@ -0,0 +1,198 @@
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::NULL_NODE;
+use hg::Node;
+use hg::Revision;
+use hg::bundle::caps::obsolescence_configured;
+use hg::discovery::Outgoing;
+use hg::discovery::find_common_heads;
+use hg::repo::Repo;
+use hg::revlog::RevlogError;
+use hg::revlog::index::Phase;
+
+use crate::commands::incoming;
+use crate::commands::incoming::DisplayOptions;
+use crate::commands::incoming::Names;
+use crate::commands::pull;
+use crate::commands::pull::RemoteOptions;
+use crate::commands::pull::Source;
+use crate::error::CommandError;
+
+pub const HELP_TEXT: &str = "
+show changesets not found in the destination
+
+Show changesets not found in the specified destination repository
+or the default push location. These are the changesets that would
+be pushed if a push was requested.
+
+Only local repositories and ssh:// URLs are supported.
+
+Returns 0 if there are outgoing changes, 1 otherwise.
+";
+
+pub fn args() -> clap::Command {
+    let command = clap::command!("outgoing")
+        .args_override_self(true)
+        .arg(
+            Arg::new("dest")
+                .help("the repository to compare with")
+                .num_args(0..)
+                .value_name("DEST"),
+        )
+        .arg(
+            Arg::new("force")
+                .help("run even when the destination is unrelated")
+                .short('f')
+                .long("force")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("rev")
+                .help("a changeset intended to be included in the destination")
+                .short('r')
+                .long("rev")
+                .value_name("REV")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("suppress output")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT);
+    pull::remote_args(incoming::display_args(command))
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg outgoing")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let repo = invocation.repo?;
+    let quiet = args.get_flag("quiet") || config.get_bool(b"ui", b"quiet")?;
+    let display = DisplayOptions::from_args(args, config, quiet)?;
+    let dests: Vec<&String> =
+        args.get_many("dest").map(Iterator::collect).unwrap_or_default();
+    if dests.len() > 1 {
+        return Err(CommandError::unsupported("several destinations"));
+    }
+    let dest = Source::resolve_with_default(
+        repo,
+        dests.first().map(|dest| dest.as_str()),
+        &["default-push", "default"],
+    )?;
+    dest.check_push_location()?;
+    let remote = RemoteOptions::from_args(args);
+    let revs: Vec<&String> =
+        args.get_many("rev").map(Iterator::collect).unwrap_or_default();
+    let force = args.get_flag("force");
+    if obsolescence_configured(repo) {
+        return Err(CommandError::unsupported("exchanging obsolescence"));
+    }
+    let status = |message: &[u8]| -> Result<(), CommandError> {
+        if !quiet {
+            ui.write_stdout(message)?;
+        }
+        Ok(())
+    };
+
+    let announce = || {
+        status(&format_bytes!(b"comparing with {}\n", dest.location.as_bytes()))
+    };
+    // Resolved first so that falling back to Python prints nothing twice
+    let heads: Vec<Node> = pull::announce_error(
+        revs.iter().map(|rev| resolve(repo, rev)).collect(),
+        &announce,
+    )?;
+    announce()?;
+    let local_dest = dest.open_local(config)?;
+    let mut peer =
+        dest.connect(ui, config, local_dest.as_ref(), &remote, &|| Ok(()))?;
+    let result = (|| -> Result<bool, CommandError> {
+        pull::print_messages(ui, &mut *peer, true, quiet)?;
+        let ancestors_of = (!heads.is_empty()).then_some(&heads[..]);
+        let common =
+            find_common_heads(repo, &mut *peer, ancestors_of, force, || {
+                status(b"searching for changes\n")
+            })?;
+        if common.unrelated {
+            ui.write_stderr(b"warning: repository is unrelated\n")?;
+        }
+        let changelog = repo.changelog()?;
+        let index = changelog.get_index();
+        let phases = repo
+            .store()
+            .phase_cache()?
+            .phases(index)
+            .map_err(RevlogError::from)?;
+        let to_revs = |nodes: &[Node]| -> Result<Vec<Revision>, RevlogError> {
+            nodes
+                .iter()
+                .filter(|&&node| node != NULL_NODE)
+                .map(|&node| changelog.rev_from_node(node.into()))
+                .collect()
+        };
+        let ancestors_of = if heads.is_empty() {
+            index.head_revs().map_err(RevlogError::from)?
+        } else {
+            to_revs(&heads)?
+        };
+        let all_missing =
+            Outgoing::new(index, to_revs(&common.common)?, ancestors_of)
+                .map_err(RevlogError::from)?
+                .missing;
+        // Secret changesets are never pushed, like in `findcommonoutgoing`
+        let is_secret =
+            |rev: &Revision| phases[rev.0 as usize] >= Phase::Secret;
+        let missing: Vec<Revision> =
+            all_missing.iter().copied().filter(|rev| !is_secret(rev)).collect();
+        if missing.is_empty() {
+            // Python counts all the secret changesets when not given heads
+            let secret = if heads.is_empty() {
+                phases.iter().filter(|&&phase| phase >= Phase::Secret).count()
+            } else {
+                all_missing.iter().filter(|rev| is_secret(rev)).count()
+            };
+            if secret > 0 {
+                status(
+                    format!(
+                        "no changes found (ignored {} secret changesets)\n",
+                        secret
+                    )
+                    .as_bytes(),
+                )?;
+            } else {
+                status(b"no changes found\n")?;
+            }
+            return Ok(false);
+        }
+        let tip = Revision(index.len() as i32 - 1);
+        let names = Names::read(repo, *changelog.node_from_rev(tip))?;
+        display.show(ui, missing, |rev| {
+            incoming::repo_changeset(&changelog, &phases, &names, rev)
+        })?;
+        Ok(true)
+    })();
+    let closed = peer.close();
+    pull::print_messages(ui, &mut *peer, false, quiet)?;
+    let shown = result?;
+    closed?;
+    if shown {
+        Ok(())
+    } else {
+        Err(CommandError::Unsuccessful)
+    }
+}
+
+/// Resolves `rev` in the local repository
+fn resolve(repo: &Repo, rev: &str) -> Result<Node, CommandError> {
+    let rev = hg::revset::resolve_single(rev, repo)?;
+    match rev.exclude_wdir() {
+        Some(rev) if rev != hg::NULL_REVISION => {
+            Ok(*repo.changelog()?.node_from_rev(rev))
+        }
+        _ => Err(CommandError::unsupported("outgoing of the null revision")),
+    }
+}
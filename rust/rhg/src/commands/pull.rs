@@ -0,0 +1,788 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::NULL_NODE;
+use hg::Node;
+use hg::Revision;
+use hg::ancestors::AncestorsIterator;
+use hg::bookmarks::read_bookmarks;
+use hg::bundle::Bundle;
+use hg::bundle::caps::Role;
+use hg::bundle::caps::encode_caps;
+use hg::bundle::caps::obsolescence_configured;
+use hg::bundle::caps::repo_caps;
+use hg::bundle::parts::decode_phase_heads;
+use hg::bundle::read_bundle;
+use hg::config::Config;
+use hg::config::ConfigOrigin;
+use hg::discovery::find_common_incoming;
+use hg::errors::HgError;
+use hg::exit_codes;
+use hg::lock::LockError;
+use hg::peer::GetBundleArgs;
+use hg::peer::LocalPeer;
+use hg::peer::Peer;
+use hg::peer::SshPeer;
+use hg::repo::Repo;
+use hg::requirements::BOOKMARKS_IN_STORE_REQUIREMENT;
+use hg::revlog::RevlogError;
+use hg::revlog::changelog::Changelog;
+use hg::revlog::index::Phase;
+use hg::utils::files::get_path_from_bytes;
+use hg::utils::files::normalize_path;
+use hg::utils::strings::url_quote;
+
+use crate::commands::serve::PULL_HOOK_TYPES;
+use crate::commands::unbundle;
+use crate::commands::unbundle::Transaction;
+use crate::commands::unbundle::Unbundling;
+use crate::error::CommandError;
+use crate::ui::Ui;
+use crate::utils::tracking_utils::with_wlock;
+
+pub const HELP_TEXT: &str = "
+pull changes from the specified source
+
+Pull changes from a remote repository to a local one.
+
+This finds all changes from the repository at the specified path
+or URL and adds them to a local repository (the current one unless
+-R is specified). By default, this does not update the copy of the
+project in the working directory.
+
+Only local repositories and ssh:// URLs are supported.
+
+If SOURCE is omitted, the 'default' path will be used.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    let command = clap::command!("pull")
+        .args_override_self(true)
+        .arg(
+            Arg::new("source")
+                .help("the repository to pull from")
+                .num_args(0..)
+                .value_name("SOURCE"),
+        )
+        .arg(
+            Arg::new("update")
+                .help(
+                    "update to new branch head if new descendants were pulled",
+                )
+                .short('u')
+                .long("update")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force")
+                .help("run even when remote repository is unrelated")
+                .short('f')
+                .long("force")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("confirm")
+                .help("confirm pull before applying changes")
+                .long("confirm")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("rev")
+                .help("a remote changeset intended to be added")
+                .short('r')
+                .long("rev")
+                .value_name("REV")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("bookmark")
+                .help("bookmark to pull")
+                .short('B')
+                .long("bookmark")
+                .value_name("BOOKMARK")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("branch")
+                .help("a specific branch you would like to pull")
+                .short('b')
+                .long("branch")
+                .value_name("BRANCH")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("suppress output")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("enable additional output")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT);
+    remote_args(command)
+}
+
+/// Adds the options of the commands talking to another repository
+pub fn remote_args(command: clap::Command) -> clap::Command {
+    command
+        .arg(
+            Arg::new("ssh")
+                .help("specify ssh command to use")
+                .short('e')
+                .long("ssh")
+                .value_name("CMD"),
+        )
+        .arg(
+            Arg::new("remotecmd")
+                .help("specify hg command to run on the remote side")
+                .long("remotecmd")
+                .value_name("CMD"),
+        )
+        .arg(
+            Arg::new("insecure")
+                .help("do not verify server certificate")
+                .long("insecure")
+                .action(clap::ArgAction::SetTrue),
+        )
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg pull")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let repo = invocation.repo?;
+    for (option, feature) in [
+        ("update", "pull --update"),
+        ("confirm", "pull --confirm"),
+        ("verbose", "pull --verbose"),
+    ] {
+        if args.get_flag(option) {
+            return Err(CommandError::unsupported(feature));
+        }
+    }
+    for (option, feature) in
+        [("bookmark", "pull --bookmark"), ("branch", "pull --branch")]
+    {
+        if args.get_many::<String>(option).is_some() {
+            return Err(CommandError::unsupported(feature));
+        }
+    }
+    check_verbosity(config)?;
+    let sources: Vec<&String> =
+        args.get_many("source").map(Iterator::collect).unwrap_or_default();
+    if sources.len() > 1 {
+        return Err(CommandError::unsupported("pulling from several sources"));
+    }
+    let source = Source::resolve(repo, sources.first().map(|s| s.as_str()))?;
+    let remote = RemoteOptions::from_args(args);
+    let revs: Vec<&String> =
+        args.get_many("rev").map(Iterator::collect).unwrap_or_default();
+    let force = args.get_flag("force");
+    let quiet = args.get_flag("quiet") || config.get_bool(b"ui", b"quiet")?;
+    unbundle::check_supported(repo)?;
+    let hooks = unbundle::hooks(config)?;
+    if config.get_bool(b"experimental", b"remotenames")? {
+        return Err(CommandError::unsupported("remote names"));
+    }
+    if !config
+        .get_list(b"devel", b"legacy.exchange")
+        .unwrap_or_default()
+        .is_empty()
+    {
+        return Err(CommandError::unsupported("legacy exchange"));
+    }
+
+    let announce = || -> Result<(), CommandError> {
+        if !quiet {
+            ui.write_stdout(&format_bytes!(
+                b"pulling from {}\n",
+                source.location.as_bytes()
+            ))?;
+        }
+        Ok(())
+    };
+    let mut after_lock = vec![];
+    // Taken before anything is printed, so that falling back to Python
+    // when they are held does not print things twice
+    let pulled = with_repo_locks(repo, || {
+        let local_source =
+            announce_error(source.open_local(config), &announce)?;
+        let mut peer = source.connect(
+            ui,
+            config,
+            local_source.as_ref(),
+            &remote,
+            &announce,
+        )?;
+        let result = (|| {
+            check_peer(repo, &mut *peer)?;
+            announce()?;
+            print_messages(ui, &mut *peer, true, quiet)?;
+            let heads = revs
+                .iter()
+                .map(|rev| peer.lookup(rev.as_bytes()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut unbundling = Unbundling {
+                ui,
+                repo,
+                hooks: &hooks,
+                quiet,
+                after_lock: &mut after_lock,
+            };
+            pull(&mut unbundling, &mut *peer, &heads, force)
+        })();
+        let closed = peer.close();
+        print_messages(ui, &mut *peer, false, quiet)?;
+        let modheads = result?;
+        closed?;
+        Ok(modheads)
+    });
+    // Like in Python, these run once the lock is released
+    let root = repo.working_directory_path();
+    for (hook_type, args) in after_lock {
+        hooks.run(ui, root, hook_type, &args, false, || Ok(false))?;
+    }
+    unbundle::post_incoming(ui, repo, pulled?, quiet)
+}
+
+/// Falls back to Python for the messages that rhg does not print
+pub(crate) fn check_verbosity(config: &Config) -> Result<(), CommandError> {
+    if config.get_bool(b"ui", b"verbose")?
+        || config.get_bool(b"ui", b"debug")?
+    {
+        return Err(CommandError::unsupported("verbose exchange"));
+    }
+    Ok(())
+}
+
+/// Runs `f` with the locks that Python takes to pull
+fn with_repo_locks<R>(
+    repo: &Repo,
+    f: impl FnOnce() -> Result<R, CommandError>,
+) -> Result<R, CommandError> {
+    let with_lock = || match repo.try_with_lock_no_wait(f) {
+        Ok(result) => result,
+        Err(LockError::AlreadyHeld) => {
+            Err(CommandError::unsupported("waiting for the store lock"))
+        }
+        Err(LockError::IO(error)) => Err(error.into()),
+    };
+    if repo.requirements().contains(BOOKMARKS_IN_STORE_REQUIREMENT) {
+        with_lock()
+    } else {
+        with_wlock(repo, with_lock)
+    }
+}
+
+/// Pulls the changesets of `peer` that are ancestors of `heads`, or all of
+/// them, returning the change in the number of heads like `exchange.pull`
+/// in Python
+fn pull(
+    unbundling: &mut Unbundling,
+    peer: &mut dyn Peer,
+    heads: &[Node],
+    force: bool,
+) -> Result<isize, CommandError> {
+    let ui = unbundling.ui;
+    let repo = unbundling.repo;
+    let quiet = unbundling.quiet;
+    let status = |message: &[u8]| -> Result<(), CommandError> {
+        if !quiet {
+            ui.write_stdout(message)?;
+        }
+        Ok(())
+    };
+    let incoming = find_common_incoming(repo, peer, heads, force, || {
+        status(b"searching for changes\n")
+    })?;
+    if incoming.unrelated {
+        ui.write_stderr(b"warning: repository is unrelated\n")?;
+    }
+    let mut common = incoming.common;
+    let remote_heads = incoming.remote_heads;
+    let mut fetch = incoming.any_incoming;
+    if fetch && !remote_heads.is_empty() {
+        // Like Python, in case discovery missed some
+        let changelog = repo.changelog()?;
+        for node in &remote_heads {
+            if has_node(&changelog, *node)? && !common.contains(node) {
+                common.push(*node);
+            }
+        }
+        if remote_heads.iter().all(|node| common.contains(node)) {
+            fetch = false;
+        }
+    }
+    if !fetch {
+        status(b"no changes found\n")?;
+    } else if heads.is_empty() && common == [NULL_NODE] {
+        status(b"requesting all changes\n")?;
+    }
+    let caps = encode_caps(&repo_caps(repo, Role::Client)?);
+    let args = GetBundleArgs {
+        heads: remote_heads,
+        common,
+        bundle_caps: vec![
+            b"HG20".to_vec(),
+            [&b"bundle2="[..], &url_quote(&caps)].concat(),
+        ],
+        changegroup: fetch,
+        phases: true,
+    };
+
+    let url = peer.url().to_owned();
+    let mut unbundling = Some(unbundling);
+    let mut tr: Option<Transaction> = None;
+    let mut result = Ok(());
+    let fetched = peer.get_bundle(&args, &mut |reader| {
+        result = (|| {
+            let Bundle::Bundle2(mut bundle) = read_bundle(reader, &url)? else {
+                return Err(CommandError::unsupported("pulling a bundle1"));
+            };
+            while let Some(mut part) = bundle.next_part()? {
+                match part.header.part_type.as_str() {
+                    "changegroup" => {
+                        transaction(&mut tr, &mut unbundling, &url)?
+                            .apply_changegroup_part(&mut part)?;
+                    }
+                    "phase-heads" => {
+                        let heads = decode_phase_heads(&part.read_payload()?)?;
+                        if tr.is_some() || phases_differ(repo, &heads)? {
+                            transaction(&mut tr, &mut unbundling, &url)?
+                                .update_phases(&heads)?;
+                        }
+                    }
+                    "output" => {
+                        let output = part.read_payload()?;
+                        for line in output.split_inclusive(|&b| b == b'\n') {
+                            let line = line.strip_suffix(b"\n").unwrap_or(line);
+                            status(&format_bytes!(b"remote: {}\n", line))?;
+                        }
+                    }
+                    "error:abort" => {
+                        let header = &part.header;
+                        let message = header.param("message").unwrap_or(b"");
+                        ui.write_stderr(&format_bytes!(
+                            b"remote: abort: {}\n",
+                            message
+                        ))?;
+                        let hint = header.param("hint").map(|hint| {
+                            String::from_utf8_lossy(hint).into_owned()
+                        });
+                        return Err(HgError::abort(
+                            "pull failed on remote",
+                            exit_codes::REMOTE_ERROR,
+                            hint,
+                        )
+                        .into());
+                    }
+                    // Caches that Mercurial rebuilds when needed
+                    "hgtagsfnodes" | "cache:rev-branch-cache" => {}
+                    part_type => {
+                        return Err(CommandError::unsupported(format!(
+                            "bundle2 part {}",
+                            part_type
+                        )));
+                    }
+                }
+            }
+            Ok(())
+        })();
+        match &result {
+            Ok(()) => Ok(()),
+            // Stops reading, the actual error is in `result`
+            Err(_) => Err(HgError::abort_simple("pull interrupted")),
+        }
+    });
+    let result = result.and_then(|()| Ok(fetched?));
+    match (tr, result) {
+        (Some(tr), Ok(())) => {
+            let modheads = tr.close()?;
+            Ok(if fetch { modheads } else { 0 })
+        }
+        (Some(tr), Err(error)) => Err(tr.abort(error)),
+        (None, result) => result.map(|()| 0),
+    }
+}
+
+/// The transaction of the pull, opened when first needed like
+/// `trmanager.transaction` in Python
+fn transaction<'t, 'u, 'a>(
+    tr: &'t mut Option<Transaction<'u, 'a>>,
+    unbundling: &mut Option<&'u mut Unbundling<'a>>,
+    url: &str,
+) -> Result<&'t mut Transaction<'u, 'a>, CommandError> {
+    if let Some(tr) = tr {
+        return Ok(tr);
+    }
+    let unbundling = unbundling.take().expect("transaction opened twice");
+    let mut opened = Transaction::open(unbundling, &format!("pull\n{}", url))?;
+    opened.set_hook_arg("source", b"pull");
+    opened.set_hook_arg("url", url.as_bytes());
+    Ok(tr.insert(opened))
+}
+
+/// Whether applying the `phase-heads` part `heads` changes any phase, since
+/// Python only opens a transaction then
+fn phases_differ(
+    repo: &Repo,
+    heads: &[(Phase, Node)],
+) -> Result<bool, CommandError> {
+    let changelog = repo.changelog()?;
+    let phases = repo
+        .store()
+        .phase_cache()?
+        .phases(changelog.get_index())
+        .map_err(RevlogError::from)?;
+    for (phase, node) in heads {
+        let rev = changelog.rev_from_node((*node).into())?;
+        if phases[rev.0 as usize] != *phase {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Falls back to Python for what rhg cannot pull from `peer`
+fn check_peer(repo: &Repo, peer: &mut dyn Peer) -> Result<(), CommandError> {
+    if peer.capable("bundle2").is_none() || peer.capable("getbundle").is_none()
+    {
+        return Err(CommandError::unsupported("pulling without bundle2"));
+    }
+    let caps = peer.bundle2_caps();
+    let binary_phases = caps
+        .get(&b"phases"[..])
+        .is_some_and(|values| values.iter().any(|value| value == b"heads"));
+    if !binary_phases {
+        return Err(CommandError::unsupported("pulling phases with listkeys"));
+    }
+    if obsolescence_configured(repo) {
+        return Err(CommandError::unsupported("exchanging obsolescence"));
+    }
+    if repo.changelog()?.get_index().is_empty()
+        && (peer.capable("stream-preferred").is_some()
+            || peer.capable("clonebundles").is_some())
+    {
+        return Err(CommandError::unsupported("pulling into an empty repo"));
+    }
+    check_bookmarks(repo, peer)
+}
+
+/// Falls back to Python unless pulling leaves the bookmarks as they are,
+/// which is when each remote bookmark has the same local node or one of
+/// its descendants
+fn check_bookmarks(
+    repo: &Repo,
+    peer: &mut dyn Peer,
+) -> Result<(), CommandError> {
+    let remote = peer.list_keys(b"bookmarks")?;
+    if remote.is_empty() {
+        return Ok(());
+    }
+    let local = read_bookmarks(repo)?;
+    let changelog = repo.changelog()?;
+    let unsupported = || CommandError::unsupported("pulling bookmarks");
+    for (name, hex) in remote {
+        let node = Node::from_hex(&hex).map_err(|_| unsupported())?;
+        let local_node = *local.get(&name).ok_or_else(unsupported)?;
+        if local_node == node {
+            continue;
+        }
+        let (Some(remote_rev), Some(local_rev)) =
+            (local_rev(&changelog, node)?, local_rev(&changelog, local_node)?)
+        else {
+            return Err(unsupported());
+        };
+        let mut ancestors = AncestorsIterator::new(
+            changelog.get_index(),
+            [local_rev],
+            remote_rev,
+            true,
+        )
+        .map_err(RevlogError::from)?;
+        if remote_rev >= local_rev
+            || !ancestors.contains(remote_rev).map_err(RevlogError::from)?
+        {
+            return Err(unsupported());
+        }
+    }
+    Ok(())
+}
+
+fn local_rev(
+    changelog: &Changelog,
+    node: Node,
+) -> Result<Option<Revision>, CommandError> {
+    match changelog.rev_from_node(node.into()) {
+        Ok(rev) => Ok(Some(rev)),
+        Err(RevlogError::InvalidRevision { .. }) => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn has_node(changelog: &Changelog, node: Node) -> Result<bool, CommandError> {
+    Ok(local_rev(changelog, node)?.is_some())
+}
+
+/// Prints what the remote wrote for the user, on stderr during the
+/// handshake like Python
+pub(crate) fn print_messages(
+    ui: &Ui,
+    peer: &mut dyn Peer,
+    handshake: bool,
+    quiet: bool,
+) -> Result<(), CommandError> {
+    let local = peer.local().is_some();
+    for line in peer.take_messages() {
+        let message = if local {
+            format_bytes!(b"{}\n", line)
+        } else {
+            format_bytes!(b"remote: {}\n", line)
+        };
+        if handshake {
+            ui.write_stderr(&message)?;
+        } else if !quiet {
+            ui.write_stdout(&message)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints `announce` before the errors that are not about falling back to
+/// Python, since Python tells where it pulls from before connecting
+pub(crate) fn announce_error<T>(
+    result: Result<T, CommandError>,
+    announce: &dyn Fn() -> Result<(), CommandError>,
+) -> Result<T, CommandError> {
+    match result {
+        Err(error @ CommandError::UnsupportedFeature { .. }) => Err(error),
+        Err(error) => {
+            announce()?;
+            Err(error)
+        }
+        Ok(value) => Ok(value),
+    }
+}
+
+/// The options overriding how to reach a remote repository
+pub(crate) struct RemoteOptions {
+    ssh: Option<Vec<u8>>,
+    remote_cmd: Option<Vec<u8>>,
+}
+
+impl RemoteOptions {
+    pub(crate) fn from_args(args: &clap::ArgMatches) -> Self {
+        let bytes = |name| {
+            args.get_one::<String>(name).map(|value| value.as_bytes().to_vec())
+        };
+        Self { ssh: bytes("ssh"), remote_cmd: bytes("remotecmd") }
+    }
+}
+
+/// A repository to exchange changesets with
+pub(crate) struct Source {
+    /// As told to the user
+    pub(crate) location: String,
+    /// `None` for an `ssh://` URL
+    local_path: Option<PathBuf>,
+    /// Whether the location comes from the `[paths]` configuration
+    named: bool,
+}
+
+impl Source {
+    /// The repository named `name` in the `[paths]` configuration of `repo`,
+    /// or at the location `name` otherwise, like `get_pull_paths` in Python
+    pub(crate) fn resolve(
+        repo: &Repo,
+        name: Option<&str>,
+    ) -> Result<Self, CommandError> {
+        Self::resolve_with_default(repo, name, &["default"])
+    }
+
+    /// Like [`Source::resolve`], with the names of the paths used by
+    /// default tried in turn
+    pub(crate) fn resolve_with_default(
+        repo: &Repo,
+        name: Option<&str>,
+        defaults: &[&str],
+    ) -> Result<Self, CommandError> {
+        let config = repo.config();
+        let configured = |name: &str| {
+            config
+                .get_with_origin(b"paths", name.as_bytes())
+                .filter(|(value, _)| !value.is_empty())
+        };
+        let (name, path) = match name {
+            Some(name) => (name, configured(name)),
+            None => {
+                let found = defaults
+                    .iter()
+                    .find_map(|name| Some((*name, configured(name)?)));
+                match found {
+                    Some((name, path)) => (name, Some(path)),
+                    None => {
+                        return Err(CommandError::unsupported(
+                            "no default path",
+                        ));
+                    }
+                }
+            }
+        };
+        let named = path.is_some();
+        let location = match path {
+            Some((value, origin)) => {
+                let prefix = format!("{}:", name);
+                if config
+                    .get_section_keys(b"paths")
+                    .iter()
+                    .any(|key| key.starts_with(prefix.as_bytes()))
+                {
+                    return Err(CommandError::unsupported("path sub-options"));
+                }
+                let value = String::from_utf8(value.to_vec())
+                    .map_err(|_| CommandError::unsupported("non-UTF-8 path"))?;
+                if value.contains('$') || value.starts_with('~') {
+                    return Err(CommandError::unsupported(
+                        "path with variables",
+                    ));
+                }
+                if has_scheme(&value) || Path::new(&value).is_absolute() {
+                    value
+                } else {
+                    let root = path_root(repo, origin)?;
+                    normalize_path(&root.join(&value))
+                        .to_string_lossy()
+                        .into_owned()
+                }
+            }
+            None => name.to_owned(),
+        };
+        if location.contains('#') {
+            return Err(CommandError::unsupported("pulling a branch"));
+        }
+        let local_path = if let Some(rest) = location.strip_prefix("ssh:") {
+            if !rest.starts_with("//") {
+                return Err(CommandError::unsupported(format!(
+                    "location {}",
+                    location
+                )));
+            }
+            None
+        } else if has_scheme(&location) {
+            return Err(CommandError::unsupported(format!(
+                "non-local location {}",
+                location
+            )));
+        } else {
+            let path = get_path_from_bytes(location.as_bytes()).to_owned();
+            if path.is_file() {
+                return Err(CommandError::unsupported("bundle repositories"));
+            }
+            Some(path)
+        };
+        Ok(Self { location, local_path, named })
+    }
+
+    /// Checks that a location not taken from the configuration is a
+    /// repository, like `get_push_paths` in Python
+    pub(crate) fn check_push_location(&self) -> Result<(), CommandError> {
+        if !self.named
+            && let Some(path) = &self.local_path
+            && !path.join(".hg").is_dir()
+        {
+            return Err(CommandError::abort(format!(
+                "abort: repository {} does not exist",
+                self.location
+            )));
+        }
+        Ok(())
+    }
+
+    /// Opens the repository if it is local
+    pub(crate) fn open_local(
+        &self,
+        config: &Config,
+    ) -> Result<Option<Repo>, CommandError> {
+        match &self.local_path {
+            Some(path) => Ok(Some(Repo::find(config, Some(path.clone()))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Connects to the repository, `local` if opened with
+    /// [`Source::open_local`], calling `announce` before returning errors
+    /// other than falling back
+    pub(crate) fn connect<'a>(
+        &self,
+        ui: &Ui,
+        config: &Config,
+        local: Option<&'a Repo>,
+        options: &RemoteOptions,
+        announce: &dyn Fn() -> Result<(), CommandError>,
+    ) -> Result<Box<dyn Peer + 'a>, CommandError> {
+        if let Some(local) = local {
+            crate::commands::clone::check_no_hooks(
+                local.config(),
+                PULL_HOOK_TYPES,
+            )?;
+            let peer = announce_error(
+                LocalPeer::new(local).map_err(CommandError::from),
+                announce,
+            )?;
+            return Ok(Box::new(peer));
+        }
+        let mut peer = announce_error(
+            SshPeer::spawn(
+                config,
+                &self.location,
+                options.ssh.as_deref(),
+                options.remote_cmd.as_deref(),
+            )
+            .map_err(CommandError::from),
+            announce,
+        )?;
+        if let Err(error) = peer.handshake() {
+            announce()?;
+            print_messages(ui, &mut peer, true, false)?;
+            return Err(error.into());
+        }
+        Ok(Box::new(peer))
+    }
+}
+
+/// Whether `location` starts with a URL scheme, like `hasscheme` in Python
+fn has_scheme(location: &str) -> bool {
+    location.split_once(':').is_some_and(|(scheme, _)| {
+        !scheme.is_empty()
+            && scheme.bytes().all(|byte| {
+                byte.is_ascii_alphanumeric() || b"+.-".contains(&byte)
+            })
+    })
+}
+
+/// The directory that the relative locations of `[paths]` set in `origin`
+/// are relative to: the root of the repository for its own configuration,
+/// and the home directory otherwise
+fn path_root(
+    repo: &Repo,
+    origin: &ConfigOrigin,
+) -> Result<PathBuf, CommandError> {
+    if let ConfigOrigin::File(file) = origin
+        && file.parent() == Some(&repo.hg_vfs().join(""))
+    {
+        return Ok(repo.working_directory_path().to_owned());
+    }
+    home::home_dir()
+        .ok_or_else(|| CommandError::unsupported("relative path without home"))
+}
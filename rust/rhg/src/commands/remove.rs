@@ -0,0 +1,133 @@
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::exit_codes;
+use hg::operations::remove_apply;
+use hg::operations::remove_plan;
+use hg::warnings::HgWarningContext;
+
+use crate::error::CommandError;
+use crate::utils::tracking_utils::check_supported;
+use crate::utils::tracking_utils::file_args;
+use crate::utils::tracking_utils::file_matcher;
+use crate::utils::tracking_utils::print_bad;
+use crate::utils::tracking_utils::print_tracking_warnings;
+use crate::utils::tracking_utils::ui_path_fn;
+use crate::utils::tracking_utils::with_wlock;
+
+pub const HELP_TEXT: &str = "
+remove the specified files on the next commit
+
+Schedule the indicated files for removal from the current branch.
+
+This command schedules the files to be removed at the next commit. To undo a
+remove before that, see 'hg revert'. To undo added files, see 'hg forget'.
+
+-A/--after can be used to remove only files that have already been deleted,
+-f/--force can be used to force deletion, and -Af can be used to remove files
+from the next revision without deleting them from the working directory.
+
+Returns 0 on success, 1 if any warnings encountered.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("remove")
+        .alias("rm")
+        .arg(
+            Arg::new("after")
+                .help("record delete for missing files")
+                .short('A')
+                .long("after")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force")
+                .help("forget added files, delete modified files")
+                .short('f')
+                .long("force")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .help("do not perform actions, just print output")
+                .short('n')
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("enable additional output")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file")
+                .value_parser(clap::value_parser!(std::ffi::OsString))
+                .help("files to remove")
+                .action(clap::ArgAction::Append),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg remove")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    let after = args.get_flag("after");
+    let force = args.get_flag("force");
+    let dry_run = args.get_flag("dry-run");
+    let verbose =
+        args.get_flag("verbose") || config.get_bool(b"ui", b"verbose")?;
+    let patterns = file_args(args);
+    if patterns.is_empty() && !after {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: no files specified",
+            exit_codes::INPUT_ERROR,
+        ));
+    }
+
+    let repo = invocation.repo?;
+    check_supported(repo, &patterns)?;
+    let matcher = file_matcher(repo, patterns)?;
+    let ui_path = ui_path_fn(repo, true)?;
+    let remove_empty_dirs =
+        config.get_bool(b"experimental", b"removeemptydirs")?;
+
+    let failed = with_wlock(repo, || {
+        let mut dmap = repo.dirstate_map_mut()?;
+        // Like Python, print warnings once everything is done
+        let warnings = HgWarningContext::new();
+        let sender = warnings.sender();
+        let plan = remove_plan(
+            repo, &mut dmap, &matcher, after, force, verbose, sender,
+        )?;
+        print_bad(ui, &plan.bad, &ui_path)?;
+        for entry in &plan.entries {
+            if verbose || !entry.exact {
+                ui.write_stdout(&format_bytes!(
+                    b"removing {}\n",
+                    ui_path(&entry.path)
+                ))?;
+            }
+        }
+        let result = if dry_run {
+            Ok(())
+        } else {
+            remove_apply(repo, &mut dmap, &plan, remove_empty_dirs, sender)
+        };
+        print_tracking_warnings(ui, warnings, repo, &ui_path);
+        result?;
+        if !dry_run {
+            drop(dmap); // Avoid "already mutably borrowed" RefCell panics
+            repo.write_dirstate()?;
+        }
+        Ok(plan.rejected || !plan.bad.is_empty())
+    })?;
+    if failed {
+        Err(CommandError::Unsuccessful)
+    } else {
+        Ok(())
+    }
+}
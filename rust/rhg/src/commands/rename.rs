@@ -0,0 +1,29 @@
+use crate::commands::copy::copy_args;
+use crate::commands::copy::copy_or_rename;
+use crate::error::CommandError;
+
+pub const HELP_TEXT: &str = "
+rename files; equivalent of copy + remove
+
+Mark dest as copies of sources; mark sources for deletion. If dest is a
+directory, copies are put in that directory. If dest is a file, there can only
+be one source.
+
+By default, this command copies the contents of files as they exist in the
+working directory. If invoked with -A/--after, the operation is recorded, but
+no copying is performed.
+
+This command takes effect with the next commit by default.
+
+Returns 0 on success, 1 if errors are encountered.
+";
+
+pub fn args() -> clap::Command {
+    copy_args(clap::command!("rename").alias("move").alias("mv"), "rename")
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg rename")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    copy_or_rename(invocation, true)
+}
@@ -0,0 +1,287 @@
+use std::ffi::OsString;
+
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::file_patterns::parse_pattern_args;
+use hg::lock::LockError;
+use hg::matchers::IntersectionMatcher;
+use hg::narrow;
+use hg::operations::RevertAction;
+use hg::operations::RevertPlan;
+use hg::operations::revert_apply;
+use hg::operations::revert_plan;
+use hg::progress::HgProgressBar;
+use hg::progress::Progress;
+use hg::utils::files::get_bytes_from_os_str;
+use hg::utils::files::get_bytes_from_path;
+use hg::utils::hg_path::HgPath;
+use hg::warnings::HgWarningContext;
+
+use crate::error::CommandError;
+use crate::ui::RelativePaths;
+use crate::ui::Ui;
+use crate::ui::print_warnings;
+use crate::ui::relative_paths;
+use crate::utils::path_utils::RelativizePaths;
+use crate::utils::update_utils::WorkingCopyWrite;
+use crate::utils::update_utils::update_config;
+
+pub const HELP_TEXT: &str = "
+restore files to their checkout state
+
+With no revision specified, revert the specified files or directories to the
+contents they had in the parent of the working directory. This restores the
+contents of files to an unmodified state and unschedules adds, removes,
+copies, and renames.
+
+Using the -r/--rev option, revert the given files or directories to their
+states as of a specific revision.
+
+Modified files are saved with a .orig suffix before reverting. To disable
+these backups, use --no-backup. It is possible to store the backup files in a
+custom directory relative to the root of the repository by setting the
+``ui.origbackuppath`` configuration option.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("revert")
+        .args_override_self(true)
+        .arg(
+            Arg::new("all")
+                .help("revert all changes when no arguments given")
+                .short('a')
+                .long("all")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("rev")
+                .help("revert to the specified revision")
+                .short('r')
+                .long("rev")
+                .value_name("REV"),
+        )
+        .arg(
+            Arg::new("no-backup")
+                .help("do not save backup copies of files")
+                .short('C')
+                .long("no-backup")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .help("do not perform actions, just print output")
+                .short('n')
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("enable additional output")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file")
+                .value_parser(clap::value_parser!(std::ffi::OsString))
+                .help("files or directories to revert")
+                .action(clap::ArgAction::Append),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg revert")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+
+    let all = args.get_flag("all");
+    let no_backup = args.get_flag("no-backup");
+    let dry_run = args.get_flag("dry-run");
+    let verbose =
+        args.get_flag("verbose") || config.get_bool(b"ui", b"verbose")?;
+    let patterns: Vec<Vec<u8>> = args
+        .get_many::<OsString>("file")
+        .map(|files| {
+            files.filter(|s| !s.is_empty()).map(get_bytes_from_os_str).collect()
+        })
+        .unwrap_or_default();
+
+    if patterns.is_empty() && !all {
+        // Let Python explain what the user may have meant
+        return Err(CommandError::unsupported("revert without files or --all"));
+    }
+    if patterns.iter().any(|p| p.starts_with(b"set:")) {
+        return Err(CommandError::unsupported("fileset"));
+    }
+
+    let repo = invocation.repo?;
+    if repo.has_sparse() {
+        return Err(CommandError::unsupported("revert in a sparse repo"));
+    }
+    if repo.has_subrepos()? {
+        return Err(CommandError::unsupported("sub-repositories"));
+    }
+    let target = match args.get_one::<String>("rev") {
+        Some(rev) => {
+            hg::revset::resolve_single(rev, repo)?.exclude_wdir().ok_or_else(
+                || CommandError::unsupported("revert to the working directory"),
+            )?
+        }
+        None => {
+            let p1 = repo.dirstate_parents()?.p1;
+            repo.changelog()?.rev_from_node(p1.into())?
+        }
+    };
+
+    let warning_context = HgWarningContext::new();
+    let matcher = narrow::matcher(repo, warning_context.sender())?;
+    print_warnings(ui, warning_context, repo.working_directory_path());
+    let matcher = if patterns.is_empty() {
+        matcher
+    } else {
+        let cwd = hg::utils::current_dir()?;
+        let root = repo.working_directory_path();
+        let file_patterns = parse_pattern_args(patterns, &cwd, root)?;
+        let files_matcher = hg::matchers::PatternMatcher::new(file_patterns)?;
+        Box::new(IntersectionMatcher::new(Box::new(files_matcher), matcher))
+    };
+
+    let update_config = update_config(repo, WorkingCopyWrite::Revert)?;
+    let relativize = match relative_paths(config)? {
+        RelativePaths::Legacy => true,
+        RelativePaths::Bool(v) => v,
+    };
+    let relativize = if relativize {
+        Some(RelativizePaths::new(repo)?)
+    } else {
+        None
+    };
+    let ui_path = |path: &HgPath| -> Vec<u8> {
+        match &relativize {
+            Some(relativize) => relativize.relativize(path).into_owned(),
+            None => path.as_bytes().to_owned(),
+        }
+    };
+
+    let with_lock_result =
+        repo.try_with_wlock_no_wait(|| -> Result<(), CommandError> {
+            let mut dmap = repo.dirstate_map_mut()?;
+            let plan = revert_plan(
+                repo,
+                &mut dmap,
+                target,
+                &matcher,
+                no_backup,
+                update_config.orig_backup_path.as_deref(),
+            )?;
+            let short = format!(
+                "{:x}",
+                repo.changelog()?.node_from_rev(target).short()
+            );
+            for path in &plan.bad {
+                ui.write_stderr(&format_bytes!(
+                    b"{}: no such file in rev {}\n",
+                    ui_path(path),
+                    short.as_bytes()
+                ))?;
+            }
+            print_plan(ui, &plan, dry_run, verbose, &ui_path)?;
+            if dry_run {
+                return Ok(());
+            }
+            print_actions(ui, &plan, verbose, &ui_path)?;
+            let progress = HgProgressBar::new("reverting");
+            revert_apply(repo, &mut dmap, &plan, &progress, &update_config)?;
+            progress.complete();
+            drop(dmap); // Avoid "already mutably borrowed" RefCell panics
+            repo.write_dirstate()?;
+            Ok(())
+        });
+    match with_lock_result {
+        Ok(result) => result,
+        Err(LockError::AlreadyHeld) => {
+            Err(CommandError::unsupported("waiting for the working copy lock"))
+        }
+        Err(LockError::IO(error)) => Err(error.into()),
+    }
+}
+
+/// Report the backups and, for dry runs, what would be done. Also warn about
+/// explicitly given files that need nothing.
+fn print_plan(
+    ui: &Ui,
+    plan: &RevertPlan,
+    dry_run: bool,
+    verbose: bool,
+    ui_path: &impl Fn(&HgPath) -> Vec<u8>,
+) -> Result<(), CommandError> {
+    for entry in &plan.entries {
+        if !entry.action.is_change() {
+            if entry.exact {
+                ui.write_stderr(&format_bytes!(
+                    b"{}",
+                    action_message(entry.action, &ui_path(&entry.path))
+                ))?;
+            }
+            continue;
+        }
+        if let Some(backup) = &entry.backup
+            && verbose
+        {
+            let backup = hg::utils::hg_path::HgPathBuf::from_bytes(
+                &get_bytes_from_path(backup),
+            );
+            ui.write_stdout(&format_bytes!(
+                b"saving current version of {} as {}\n",
+                ui_path(&entry.path),
+                ui_path(&backup)
+            ))?;
+        }
+        if dry_run && (verbose || !entry.exact) {
+            ui.write_stdout(&action_message(
+                entry.action,
+                &ui_path(&entry.path),
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Report the actions about to be performed, grouped by action
+fn print_actions(
+    ui: &Ui,
+    plan: &RevertPlan,
+    verbose: bool,
+    ui_path: &impl Fn(&HgPath) -> Vec<u8>,
+) -> Result<(), CommandError> {
+    let mut entries: Vec<_> = plan
+        .entries
+        .iter()
+        .filter(|e| e.action.is_change() && (verbose || !e.exact))
+        .collect();
+    entries.sort_by_key(|e| e.action);
+    let mut stdout = ui.stdout_buffer();
+    for entry in entries {
+        stdout
+            .write_all(&action_message(entry.action, &ui_path(&entry.path)))?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn action_message(action: RevertAction, path: &[u8]) -> Vec<u8> {
+    let template: &[u8] = match action {
+        RevertAction::Revert => b"reverting",
+        RevertAction::Add => b"adding",
+        RevertAction::Remove | RevertAction::Drop => b"removing",
+        RevertAction::Forget => b"forgetting",
+        RevertAction::Undelete => b"undeleting",
+        RevertAction::Noop => b"no changes needed to",
+        RevertAction::Unknown => b"file not managed:",
+    };
+    format_bytes!(b"{} {}\n", template, path)
+}
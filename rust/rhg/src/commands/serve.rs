@@ -0,0 +1,1122 @@
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::ToSocketAddrs;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::config::Config;
+use hg::errors::HgError;
+use hg::errors::HgIoError;
+use hg::errors::IoErrorContext;
+use hg::errors::IoResultExt;
+use hg::exit_codes;
+use hg::repo::Repo;
+use hg::utils::files::get_bytes_from_os_string;
+use hg::utils::files::get_bytes_from_path;
+use hg::utils::files::get_path_from_bytes;
+use hg::wireprotocol;
+use hg::wireprotocol::Args;
+use hg::wireprotocol::Command;
+use hg::wireprotocol::Response;
+use hg::wireprotocol::Server;
+use hg::wireprotocol::Transport;
+use hg::wireprotocol::http;
+use hg::wireprotocol::http::ChunkedWriter;
+use hg::wireprotocol::http::Encoding;
+use hg::wireprotocol::http::Request;
+
+use crate::OnUnsupported;
+use crate::commands::clone::check_no_hooks;
+use crate::error::CommandError;
+
+pub const HELP_TEXT: &str = "
+start stand-alone webserver
+
+Serve the repository to Mercurial clients pulling from it over HTTP, on
+a TCP port or on a Unix socket, or over stdin and stdout with --stdio,
+which is how SSH clients reach it.
+
+Only the wire protocol is served over HTTP, without the pages browsing the
+repository. Pushing to the repository is not supported: over HTTP, servers
+allowing it are left to Python, and over stdio, Python takes over the session
+when the client starts pushing.
+
+By default, the server logs accesses to stdout and errors to stderr. Use
+the -A/--accesslog and -E/--errorlog options to log to files.
+
+To have the server choose a free port number to listen on, specify a port
+number of 0; in this case, the server will print the port number it uses.
+
+Returns 0 on success.
+";
+
+/// The hooks that serving pulls can run
+pub(crate) const PULL_HOOK_TYPES: &[&[u8]] =
+    &[b"preoutgoing", b"outgoing", b"prelistkeys", b"listkeys"];
+
+pub fn args() -> clap::Command {
+    clap::command!("serve")
+        .args_override_self(true)
+        .arg(
+            Arg::new("stdio")
+                .help("for remote clients (ADVANCED)")
+                .long("stdio")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("port")
+                .help("port to listen on (default: 8000)")
+                .short('p')
+                .long("port")
+                .value_name("PORT"),
+        )
+        .arg(
+            Arg::new("address")
+                .help("address to listen on (default: all interfaces)")
+                .short('a')
+                .long("address")
+                .value_name("ADDR"),
+        )
+        .arg(
+            Arg::new("unix-socket")
+                .help("Unix socket to listen on instead of a port")
+                .long("unix-socket")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("prefix")
+                .help("prefix path to serve from (default: server root)")
+                .long("prefix")
+                .value_name("PREFIX"),
+        )
+        .arg(
+            Arg::new("accesslog")
+                .help("name of access log file to write to")
+                .short('A')
+                .long("accesslog")
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::new("errorlog")
+                .help("name of error log file to write to")
+                .short('E')
+                .long("errorlog")
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::new("pid-file")
+                .help("name of file to write process ID to")
+                .long("pid-file")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("print-url")
+                .help("start and print only the URL")
+                .long("print-url")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg serve")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    if invocation.subcommand_args.get_flag("stdio") {
+        serve_stdio(invocation)
+    } else {
+        serve_http(invocation)
+    }
+}
+
+fn serve_stdio(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    check_invocation()?;
+    let ui = invocation.ui;
+    let repo = invocation.repo?;
+    check_no_hooks(invocation.config, PULL_HOOK_TYPES)?;
+    let push_server = push_server(invocation.config)?;
+    // Past this point, the client talks to rhg and falling back to Python
+    // is no longer possible
+    wireprotocol::check_supported(repo)?;
+
+    let mut stdin = BufReader::new(std::io::stdin().lock());
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    let mut server = Server::new(repo, Transport::Ssh);
+    loop {
+        let mut request = vec![];
+        stdin.read_until(b'\n', &mut request).map_err(reading_error)?;
+        // An empty line ends the session, like the end of the input
+        request.pop();
+        if request.is_empty() {
+            return Ok(());
+        }
+        let Some(command) = wireprotocol::command(&request) else {
+            respond(&mut stdout, b"")?;
+            continue;
+        };
+        let args = read_args(&mut stdin, command)?;
+        if command.pushes_with(&args) {
+            stdout.flush().map_err(writing_error)?;
+            return hand_over(&push_server, &request, command, &args, stdin);
+        }
+        let response = match server.dispatch(command, args) {
+            Ok(response) => response,
+            Err(HgError::UnsupportedFeature(message, _)) => {
+                return Err(HgError::abort_simple(format!(
+                    "rhg serve does not support {}",
+                    message
+                ))
+                .into());
+            }
+            Err(error) => return Err(error.into()),
+        };
+        for message in server.take_messages() {
+            ui.write_stderr(&message)?;
+        }
+        match response {
+            Response::Bytes(bytes) => respond(&mut stdout, &bytes)?,
+            Response::Bundle(bundle) => {
+                bundle.write(repo, &mut stdout)?;
+                stdout.flush().map_err(writing_error)?;
+            }
+            Response::Error(message) => {
+                // Like `_sshv1respondooberror` in Python
+                let mut error = message;
+                error.extend_from_slice(b"\n-\n");
+                ui.write_stderr(&error)?;
+                stdout
+                    .write_all(b"\n")
+                    .and_then(|()| stdout.flush())
+                    .map_err(writing_error)?;
+            }
+        }
+    }
+}
+
+/// The Python executable taking over the session when the client pushes,
+/// since SSH clients may always push.
+///
+/// It must be known before answering anything, the client being unable to
+/// retry with Python afterwards.
+fn push_server(config: &Config) -> Result<PathBuf, CommandError> {
+    let unsupported = || CommandError::unsupported("pushing over SSH");
+    let OnUnsupported::Fallback { executable: Some(executable) } =
+        OnUnsupported::from_config(config)
+    else {
+        return Err(unsupported());
+    };
+    let executable = get_path_from_bytes(&executable).to_owned();
+    // Like the fallback, never run rhg again
+    if std::env::args_os().next().is_some_and(|this| executable == this) {
+        return Err(unsupported());
+    }
+    Ok(executable)
+}
+
+/// Runs `executable` with the arguments of this process to serve the rest
+/// of the session, starting with `request` and its `args` already read.
+///
+/// The protocol keeps no state between commands, so the new server needs
+/// nothing else.
+fn hand_over(
+    executable: &Path,
+    request: &[u8],
+    command: &Command,
+    args: &Args,
+    mut stdin: impl Read,
+) -> Result<(), CommandError> {
+    let mut child = std::process::Command::new(executable)
+        .args(std::env::args_os().skip(1))
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|error| {
+            HgError::abort_simple(format!(
+                "failed to run {}: {}",
+                executable.display(),
+                error
+            ))
+        })?;
+    let mut child_stdin = child.stdin.take().expect("stdin is piped");
+    let mut replayed = request.to_vec();
+    replayed.push(b'\n');
+    replayed.extend(encode_args(command, args));
+    let forwarded = child_stdin
+        .write_all(&replayed)
+        .and_then(|()| std::io::copy(&mut stdin, &mut child_stdin));
+    drop(child_stdin);
+    let status = child.wait().map_err(reading_error)?;
+    match forwarded {
+        // The server stopped reading when it exited
+        Err(error) if error.kind() == std::io::ErrorKind::BrokenPipe => {}
+        forwarded => {
+            forwarded.map_err(reading_error)?;
+        }
+    }
+    if !status.success() {
+        return Err(CommandError::Unsuccessful);
+    }
+    Ok(())
+}
+
+/// Encodes `args` as the client sent them, see [`read_args`]
+fn encode_args(command: &Command, args: &Args) -> Vec<u8> {
+    let mut encoded = vec![];
+    let encode = |encoded: &mut Vec<u8>, name: &[u8], value: &[u8]| {
+        encoded.extend(format_bytes!(b"{} {}\n", name, value.len()));
+        encoded.extend_from_slice(value);
+    };
+    for name in command.arg_names() {
+        if name == "*" {
+            let others: Vec<_> = args.others().collect();
+            encoded.extend(format_bytes!(b"* {}\n", others.len()));
+            for (name, value) in others {
+                encode(&mut encoded, name, value);
+            }
+        } else {
+            let value = args.get(name).unwrap_or_default();
+            encode(&mut encoded, name.as_bytes(), value);
+        }
+    }
+    encoded
+}
+
+/// Restricts `serve --stdio` to exactly `hg -R <repo> serve --stdio`, like
+/// Python, since tools granting SSH access may only allow that command
+fn check_invocation() -> Result<(), CommandError> {
+    let args: Vec<Vec<u8>> =
+        std::env::args_os().skip(1).map(get_bytes_from_os_string).collect();
+    let safe = match &args[..] {
+        [option, repo, serve, stdio] => {
+            option == b"-R"
+                && !repo.starts_with(b"--")
+                && serve == b"serve"
+                && stdio == b"--stdio"
+        }
+        _ => false,
+    };
+    if safe {
+        return Ok(());
+    }
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| format!("'{}'", String::from_utf8_lossy(arg)))
+        .collect();
+    Err(HgError::abort_simple(format!(
+        "potentially unsafe serve --stdio invocation: [{}]",
+        args.join(", ")
+    ))
+    .into())
+}
+
+/// Reads the arguments of `command`, each a line with its name and the
+/// length of its value followed by the value, like `sshv1protocolhandler`
+/// in Python. `*` stands for a number of other arguments.
+fn read_args(
+    stdin: &mut impl BufRead,
+    command: &Command,
+) -> Result<Args, HgError> {
+    let mut args = Args::default();
+    for _ in command.arg_names() {
+        let (name, length) = read_arg_line(stdin)?;
+        if !command.arg_names().any(|known| known.as_bytes() == name) {
+            return Err(HgError::abort_simple(format!(
+                "unexpected parameter b'{}'",
+                String::from_utf8_lossy(&name)
+            )));
+        }
+        if name == b"*" {
+            for _ in 0..length {
+                let (name, length) = read_arg_line(stdin)?;
+                args.insert_other(name, read_value(stdin, length)?);
+            }
+        } else {
+            args.insert(name, read_value(stdin, length)?);
+        }
+    }
+    Ok(args)
+}
+
+/// Reads a `name length` line
+fn read_arg_line(
+    stdin: &mut impl BufRead,
+) -> Result<(Vec<u8>, usize), HgError> {
+    let mut line = vec![];
+    stdin.read_until(b'\n', &mut line).map_err(reading_error)?;
+    let line = line.strip_suffix(b"\n").unwrap_or(&line);
+    let mut fields = line.split(|&byte| byte == b' ');
+    let parsed = match (fields.next(), fields.next(), fields.next()) {
+        (Some(name), Some(length), None) => std::str::from_utf8(length)
+            .ok()
+            .and_then(|length| length.parse().ok())
+            .map(|length| (name.to_vec(), length)),
+        _ => None,
+    };
+    parsed.ok_or_else(|| {
+        HgError::abort_simple(format!(
+            "invalid argument line: {}",
+            String::from_utf8_lossy(line)
+        ))
+    })
+}
+
+fn read_value(
+    stdin: &mut impl Read,
+    length: usize,
+) -> Result<Vec<u8>, HgError> {
+    // The length comes from the client, only allocate what it sends
+    let mut value = vec![];
+    stdin.take(length as u64).read_to_end(&mut value).map_err(reading_error)?;
+    if value.len() < length {
+        return Err(reading_error(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(value)
+}
+
+/// Sends `data` preceded by its length on its own line
+fn respond(stdout: &mut impl Write, data: &[u8]) -> Result<(), HgError> {
+    let length = format!("{}\n", data.len());
+    stdout
+        .write_all(length.as_bytes())
+        .and_then(|()| stdout.write_all(data))
+        .and_then(|()| stdout.flush())
+        .map_err(writing_error)
+}
+
+fn reading_error(error: std::io::Error) -> HgError {
+    HgIoError::from_os_error(error, IoErrorContext::ReadingStream).into()
+}
+
+fn writing_error(error: std::io::Error) -> HgError {
+    HgIoError::from_os_error(error, IoErrorContext::WritingStream).into()
+}
+
+/// Serves the repository over HTTP until the process is killed, like
+/// `hgweb` restricted to the wire protocol
+fn serve_http(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let repo = invocation.repo?;
+    let print_url = args.get_flag("print-url");
+    let verbose = config.get_bool(b"ui", b"verbose")?;
+    if print_url && verbose {
+        return Err(CommandError::abort_with_exit_code(
+            "abort: cannot use --print-url with --verbose",
+            exit_codes::INPUT_ERROR,
+        ));
+    }
+    check_no_hooks(config, PULL_HOOK_TYPES)?;
+    check_http_supported(config)?;
+    wireprotocol::check_supported(repo)?;
+    // Options override the configuration, like in Python
+    let option = |name: &str, item: &[u8]| -> Option<Vec<u8>> {
+        match args.get_one::<String>(name) {
+            Some(value) => Some(value.as_bytes().to_vec()),
+            None => config.get(b"web", item).map(<[u8]>::to_vec),
+        }
+    };
+    let prefix = match option("prefix", b"prefix") {
+        Some(prefix) if !prefix.trim_ascii().is_empty() => {
+            let mut path = b"/".to_vec();
+            path.extend_from_slice(trim_slashes(&prefix));
+            path
+        }
+        _ => vec![],
+    };
+    let logs = Logs {
+        access: open_log(option("accesslog", b"accesslog"), || {
+            Box::new(std::io::stdout())
+        })?,
+        errors: open_log(option("errorlog", b"errorlog"), || {
+            Box::new(std::io::stderr())
+        })?,
+    };
+
+    let listener = match args.get_one::<PathBuf>("unix-socket") {
+        Some(path) => {
+            let listener = UnixListener::bind(path).map_err(|error| {
+                HgError::abort_simple(format!(
+                    "cannot start server at '{}': {}",
+                    path.display(),
+                    strerror(&error)
+                ))
+            })?;
+            if print_url {
+                ui.write_stdout(&format_bytes!(
+                    b"{}\n",
+                    get_bytes_from_path(path)
+                ))?;
+            } else if !config.get_bool(b"ui", b"quiet")? {
+                ui.write_stdout(&format_bytes!(
+                    b"listening at {}\n",
+                    get_bytes_from_path(path)
+                ))?;
+            }
+            Listener::Unix(listener)
+        }
+        None => {
+            let address = option("address", b"address").unwrap_or_default();
+            let address = String::from_utf8_lossy(&address).into_owned();
+            let port = option("port", b"port").unwrap_or_default();
+            let port = parse_port(&port)?;
+            let bind_address = if address.is_empty() {
+                "0.0.0.0"
+            } else {
+                address.as_str()
+            };
+            let listener =
+                TcpListener::bind((bind_address, port)).map_err(|error| {
+                    HgError::abort_simple(format!(
+                        "cannot start server at '{}:{}': {}",
+                        address,
+                        port,
+                        strerror(&error)
+                    ))
+                })?;
+            let bound = listener.local_addr().map_err(reading_error)?;
+            let announce = !args.contains_id("port") || verbose || print_url;
+            if announce {
+                let url = server_url(&bound, &prefix);
+                if print_url {
+                    ui.write_stdout(&format_bytes!(b"{}\n", url.as_bytes()))?;
+                } else if !args.contains_id("port")
+                    || !config.get_bool(b"ui", b"quiet")?
+                {
+                    let ip = match bound.ip() {
+                        ip if ip.is_unspecified() && ip.is_ipv4() => {
+                            "*".to_owned()
+                        }
+                        IpAddr::V6(ip) => format!("[{}]", ip),
+                        ip => ip.to_string(),
+                    };
+                    let message = format!(
+                        "listening at {} (bound to {}:{})\n",
+                        url,
+                        ip,
+                        bound.port()
+                    );
+                    ui.write_stdout(message.as_bytes())?;
+                }
+            }
+            Listener::Tcp(listener)
+        }
+    };
+    if let Some(path) = args.get_one::<PathBuf>("pid-file") {
+        let pid = format!("{}\n", std::process::id());
+        std::fs::write(path, pid).when_writing_file(path)?;
+    }
+
+    let service = Arc::new(Service {
+        config: config.clone(),
+        root: repo.working_directory_path().to_owned(),
+        prefix,
+        logs,
+    });
+    loop {
+        let accepted = match &listener {
+            Listener::Tcp(listener) => listener.accept().and_then(|(s, a)| {
+                let reader: Box<dyn Read + Send> = Box::new(s.try_clone()?);
+                Ok((reader, Box::new(s) as _, a.ip().to_string()))
+            }),
+            Listener::Unix(listener) => listener.accept().and_then(|(s, _)| {
+                let reader: Box<dyn Read + Send> = Box::new(s.try_clone()?);
+                Ok((reader, Box::new(s) as _, "-".to_owned()))
+            }),
+        };
+        let (reader, writer, client): (_, Box<dyn Write + Send>, _) =
+            match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    let message =
+                        format!("error accepting a connection: {}", error);
+                    service.logs.error("-", &message);
+                    continue;
+                }
+            };
+        let service = Arc::clone(&service);
+        std::thread::spawn(move || {
+            service.serve_connection(reader, writer, &client)
+        });
+    }
+}
+
+/// Falls back to Python for what the HTTP server does not do as Python
+/// would, like restricting access or pushing
+fn check_http_supported(config: &Config) -> Result<(), CommandError> {
+    if config.get(b"web", b"certificate").is_some() {
+        return Err(CommandError::unsupported("serving over HTTPS"));
+    }
+    if config.get_bool(b"web", b"ipv6")? {
+        return Err(CommandError::unsupported("serving over IPv6"));
+    }
+    for item in [&b"allow_read"[..], b"deny_read"] {
+        if config.get_list(b"web", item).is_some_and(|list| !list.is_empty()) {
+            return Err(CommandError::unsupported("restricting reads"));
+        }
+    }
+    if !config.get_bool(b"web", b"allow-pull")? {
+        return Err(CommandError::unsupported("denying pulls"));
+    }
+    for item in [&b"allow-push"[..], b"allow_push", b"deny_push"] {
+        if config.get_list(b"web", item).is_some_and(|list| !list.is_empty()) {
+            return Err(CommandError::unsupported("allowing pushes"));
+        }
+    }
+    if config
+        .get_list(b"experimental", b"server.allow-hidden-access")
+        .is_some_and(|list| !list.is_empty())
+    {
+        return Err(CommandError::unsupported("hidden changesets access"));
+    }
+    for item in [&b"web-conf"[..], b"webdir-conf"] {
+        if config.get(b"web", item).is_some() {
+            return Err(CommandError::unsupported(
+                "serving several repositories",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parses a port number, which Python also accepts as a service name
+fn parse_port(port: &[u8]) -> Result<u16, CommandError> {
+    if port.is_empty() {
+        return Ok(8000);
+    }
+    std::str::from_utf8(port)
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .ok_or_else(|| CommandError::unsupported("service names as ports"))
+}
+
+/// The URL that clients can pull from, like Python prints it
+fn server_url(bound: &SocketAddr, prefix: &[u8]) -> String {
+    let mut host = fqdn(bound.ip());
+    if host.contains(':') {
+        host = format!("[{}]", host);
+    }
+    let port = match bound.port() {
+        80 => String::new(),
+        port => format!(":{}", port),
+    };
+    let mut path = String::from_utf8_lossy(trim_slashes(prefix)).into_owned();
+    if !path.is_empty() {
+        path.push('/');
+    }
+    format!("http://{}{}/{}", host, port, path)
+}
+
+/// The name of the host at `ip`, this one if unspecified, like
+/// `socket.getfqdn` in Python but without considering the aliases
+fn fqdn(ip: IpAddr) -> String {
+    let (name, address) = if ip.is_unspecified() {
+        let Ok(hostname) = whoami::hostname() else {
+            return ip.to_string();
+        };
+        let address = (hostname.as_str(), 0)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next());
+        (hostname, address)
+    } else {
+        (ip.to_string(), Some(SocketAddr::new(ip, 0)))
+    };
+    address.and_then(reverse_lookup).unwrap_or(name)
+}
+
+/// The name that the resolver gives to `address`, if any
+fn reverse_lookup(address: SocketAddr) -> Option<String> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let length = match address {
+        SocketAddr::V4(address) => {
+            let raw = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: 0,
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(address.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: `sockaddr_storage` is large enough for any address
+            unsafe {
+                std::ptr::write(&mut storage as *mut _ as *mut _, raw);
+            }
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let mut raw: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_addr.s6_addr = address.ip().octets();
+            // SAFETY: `sockaddr_storage` is large enough for any address
+            unsafe {
+                std::ptr::write(&mut storage as *mut _ as *mut _, raw);
+            }
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    // SAFETY: the address and the buffer are valid for their lengths
+    let result = unsafe {
+        libc::getnameinfo(
+            &storage as *const _ as *const libc::sockaddr,
+            length as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    // SAFETY: `getnameinfo` wrote a nul-terminated string
+    let name = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    name.to_str().ok().map(str::to_owned)
+}
+
+fn trim_slashes(path: &[u8]) -> &[u8] {
+    let start = path.iter().position(|&b| b != b'/').unwrap_or(path.len());
+    let end = path.iter().rposition(|&b| b != b'/').map_or(start, |i| i + 1);
+    &path[start..end]
+}
+
+/// The description of an I/O error, without the error number that Python
+/// does not print
+fn strerror(error: &std::io::Error) -> String {
+    let message = error.to_string();
+    match message.rfind(" (os error ") {
+        Some(suffix) => message[..suffix].to_owned(),
+        None => message,
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+type Log = Mutex<Box<dyn Write + Send>>;
+
+/// Where the server logs the requests it answered and its errors
+struct Logs {
+    access: Log,
+    errors: Log,
+}
+
+/// Opens the log file `path`, `-` or nothing standing for `default`
+fn open_log(
+    path: Option<Vec<u8>>,
+    default: impl FnOnce() -> Box<dyn Write + Send>,
+) -> Result<Log, CommandError> {
+    let file: Box<dyn Write + Send> = match path {
+        Some(path) if !path.is_empty() && path != b"-" => {
+            let path = get_path_from_bytes(&path);
+            Box::new(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .when_writing_file(path)?,
+            )
+        }
+        _ => default(),
+    };
+    Ok(Mutex::new(file))
+}
+
+impl Logs {
+    fn access(&self, client: &str, message: &str) {
+        Self::log(&self.access, client, message)
+    }
+
+    fn error(&self, client: &str, message: &str) {
+        Self::log(&self.errors, client, message)
+    }
+
+    /// Writes a line like `_log_any` in Python, ignoring failures since
+    /// there would be nowhere to report them
+    fn log(log: &Log, client: &str, message: &str) {
+        let date = chrono::Local::now().format("%d/%b/%Y %H:%M:%S");
+        let mut log = log.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = writeln!(log, "{} - - [{}] {}", client, date, message)
+            .and_then(|()| log.flush());
+    }
+}
+
+/// What every connection needs to answer requests
+struct Service {
+    /// The configuration to open the repository with for each request, so
+    /// that responses follow its changes
+    config: Config,
+    root: PathBuf,
+    /// Where the repository is, like `/repo`, or empty for the root
+    prefix: Vec<u8>,
+    logs: Logs,
+}
+
+/// A response whose body is known in advance
+struct Reply<'a> {
+    status: &'a str,
+    media_type: Option<&'a str>,
+    body: &'a [u8],
+}
+
+impl Service {
+    /// Answers the requests of a client until it closes the connection or
+    /// a response cannot be sent in full
+    fn serve_connection(
+        &self,
+        reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+        client: &str,
+    ) {
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        loop {
+            let request = match Request::read(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(HgError::Abort { message, .. }) => {
+                    self.logs.error(
+                        client,
+                        &format!("code 400, message {}", message),
+                    );
+                    let reply = Reply {
+                        status: "400 Bad Request",
+                        media_type: None,
+                        body: b"Bad Request",
+                    };
+                    let _ = write_reply(&mut writer, &reply, false, false);
+                    return;
+                }
+                Err(_) => return,
+            };
+            match self.answer(&request, &mut reader, &mut writer, client) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(error) => {
+                    let message = format!(
+                        "error answering '{}': {}",
+                        String::from_utf8_lossy(&request.target),
+                        error
+                    );
+                    self.logs.error(client, &message);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Answers `request`, whose body is in `body`, telling whether the
+    /// connection may stay open for the next request
+    fn answer(
+        &self,
+        request: &Request,
+        body: &mut impl BufRead,
+        out: &mut impl Write,
+        client: &str,
+    ) -> Result<bool, HgError> {
+        let mut keep_alive = request.keeps_alive();
+        let head = request.method == "HEAD";
+        let not_found = Reply {
+            status: "404 Not Found",
+            media_type: None,
+            body: b"Not Found",
+        };
+
+        let path = request.path();
+        let Some(dispatch_path) = path
+            .strip_prefix(&self.prefix[..])
+            .filter(|rest| rest.is_empty() || rest.starts_with(b"/"))
+        else {
+            // The body of a POST request is not read, so that the client
+            // cannot send another request on the connection
+            return self.reply(
+                request,
+                out,
+                client,
+                not_found,
+                keep_alive && request.method != "POST",
+            );
+        };
+        let query = request.query();
+        let command = http::parse_query(query)
+            .into_iter()
+            .find(|(name, _)| name == b"cmd")
+            .and_then(|(_, value)| wireprotocol::command(&value));
+        let Some(command) = command else {
+            // Browsing the repository is not supported
+            return self.reply(
+                request,
+                out,
+                client,
+                not_found,
+                keep_alive && request.method != "POST",
+            );
+        };
+
+        let length = match request.content_length() {
+            Ok(length) => length,
+            Err(_) => {
+                let bad_request = Reply {
+                    status: "400 Bad Request",
+                    media_type: None,
+                    body: b"Bad Request",
+                };
+                return self.reply(request, out, client, bad_request, false);
+            }
+        };
+        if length > 0
+            && request.header("Expect").is_some_and(|value| {
+                value.eq_ignore_ascii_case(b"100-continue")
+            })
+        {
+            out.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .and_then(|()| out.flush())
+                .map_err(writing_error)?;
+        }
+        let mut body = Read::take(body, length);
+        let post_length = request.number_header("X-HgArgs-Post")?;
+        let encoded_args = if post_length > 0 {
+            let mut args = vec![];
+            (&mut body)
+                .take(post_length)
+                .read_to_end(&mut args)
+                .map_err(reading_error)?;
+            args
+        } else {
+            request.joined_header("X-HgArg")
+        };
+        // What is left is the payload of commands pushing, which are not
+        // supported
+        std::io::copy(&mut body, &mut std::io::sink())
+            .map_err(reading_error)?;
+        if body.limit() > 0 {
+            // The client closed the connection before sending its body
+            return Ok(false);
+        }
+
+        if dispatch_path.len() > 1 {
+            let reply_not_found = Reply {
+                status: "404 Not Found",
+                media_type: Some(http::MEDIA_TYPE),
+                body: b"0\nNot Found\n",
+            };
+            return self.reply(
+                request,
+                out,
+                client,
+                reply_not_found,
+                keep_alive,
+            );
+        }
+        if command.pushes() {
+            // Pushes are only allowed by configurations that fall back to
+            // Python, so this is what `checkauthz` denies them with
+            let (status, message) = if request.method != "POST" {
+                ("405 push requires POST request", "push requires POST request")
+            } else if self.config.get_bool(b"web", b"push_ssl")? {
+                ("403 ssl required", "ssl required")
+            } else {
+                ("401 push not authorized", "push not authorized")
+            };
+            let body = format!("0\n{}\n", message);
+            let denied =
+                Reply { status, media_type: None, body: body.as_bytes() };
+            return self.reply(request, out, client, denied, keep_alive);
+        }
+
+        let args = http::request_args(command, query, &encoded_args);
+        let repo = Repo::find(&self.config, Some(self.root.clone()))?;
+        let mut server = Server::new(&repo, Transport::Http);
+        let response = wireprotocol::check_supported(&repo)
+            .and_then(|()| server.dispatch(command, args));
+        let messages = server.take_messages().concat();
+        if !messages.is_empty() {
+            let mut stderr = std::io::stderr().lock();
+            let _ = stderr.write_all(&messages).and_then(|()| stderr.flush());
+        }
+        let ok = "200 Script output follows";
+        match response {
+            Ok(Response::Bytes(bytes)) => self.reply(
+                request,
+                out,
+                client,
+                Reply {
+                    status: ok,
+                    media_type: Some(http::MEDIA_TYPE),
+                    body: &bytes,
+                },
+                keep_alive,
+            ),
+            Ok(Response::Error(message)) => self.reply(
+                request,
+                out,
+                client,
+                Reply {
+                    status: ok,
+                    media_type: Some(http::ERROR_MEDIA_TYPE),
+                    body: &message,
+                },
+                keep_alive,
+            ),
+            Ok(Response::Bundle(bundle)) => {
+                let encoding = Encoding::negotiate(
+                    repo.config(),
+                    &request.protocaps(),
+                    !bundle.prefers_compression(),
+                )?;
+                // Without a length, the end of the body is either the last
+                // chunk or the end of the connection
+                keep_alive &= request.version == "HTTP/1.1";
+                self.log_request(request, ok, client);
+                let framing = if keep_alive {
+                    "Transfer-Encoding: chunked"
+                } else {
+                    "Connection: close"
+                };
+                write_head(out, ok, Some(encoding.media_type), framing)?;
+                if head {
+                    out.flush().map_err(writing_error)?;
+                    return Ok(keep_alive);
+                }
+                if keep_alive {
+                    let chunks = BufWriter::with_capacity(
+                        CHUNK_SIZE,
+                        ChunkedWriter::new(&mut *out),
+                    );
+                    let mut writer = encoding.writer(chunks)?;
+                    bundle.write(&repo, &mut writer)?;
+                    let chunks = writer.finish()?;
+                    let chunks = chunks
+                        .into_inner()
+                        .map_err(|error| writing_error(error.into_error()))?;
+                    chunks.finish()?;
+                } else {
+                    let mut writer = encoding.writer(&mut *out)?;
+                    bundle.write(&repo, &mut writer)?;
+                    writer.finish()?.flush().map_err(writing_error)?;
+                }
+                Ok(keep_alive)
+            }
+            Err(HgError::UnsupportedFeature(message, _)) => {
+                let message =
+                    format!("rhg serve does not support {}\n", message);
+                self.logs.error(client, message.trim_end());
+                self.reply(
+                    request,
+                    out,
+                    client,
+                    Reply {
+                        status: ok,
+                        media_type: Some(http::ERROR_MEDIA_TYPE),
+                        body: message.as_bytes(),
+                    },
+                    keep_alive,
+                )
+            }
+            Err(error) => {
+                let message = format!(
+                    "Exception happened during processing request '{}':\n{}",
+                    String::from_utf8_lossy(&request.target),
+                    error
+                );
+                self.logs.error(client, &message);
+                self.reply(
+                    request,
+                    out,
+                    client,
+                    Reply {
+                        status: "500 Internal Server Error",
+                        media_type: None,
+                        body: b"Internal Server Error",
+                    },
+                    keep_alive,
+                )
+            }
+        }
+    }
+
+    /// Sends `reply` to `request`, returning `keep_alive`
+    fn reply(
+        &self,
+        request: &Request,
+        out: &mut impl Write,
+        client: &str,
+        reply: Reply,
+        keep_alive: bool,
+    ) -> Result<bool, HgError> {
+        self.log_request(request, reply.status, client);
+        write_reply(out, &reply, keep_alive, request.method == "HEAD")?;
+        Ok(keep_alive)
+    }
+
+    /// Logs a response to `request` like `log_request` in Python
+    fn log_request(&self, request: &Request, status: &str, client: &str) {
+        let code = status.split(' ').next().unwrap_or(status);
+        let mut message = format!("\"{}\" {} -", request.line(), code);
+        let mut headers: Vec<_> = request.extension_headers().collect();
+        headers.sort_unstable();
+        for (name, value) in headers {
+            message.push_str(&format!(
+                " {}:{}",
+                name,
+                String::from_utf8_lossy(value)
+            ));
+        }
+        self.logs.access(client, &message);
+    }
+}
+
+/// The size of the chunks of streamed responses
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes the status line and the headers of a response, `framing` telling
+/// where its body ends
+fn write_head(
+    out: &mut impl Write,
+    status: &str,
+    media_type: Option<&str>,
+    framing: &str,
+) -> Result<(), HgError> {
+    let date = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT");
+    let mut head = format!("HTTP/1.1 {}\r\nDate: {}\r\n", status, date);
+    if let Some(media_type) = media_type {
+        head.push_str(&format!("Content-Type: {}\r\n", media_type));
+    }
+    head.push_str(framing);
+    head.push_str("\r\n\r\n");
+    out.write_all(head.as_bytes()).map_err(writing_error)
+}
+
+fn write_reply(
+    out: &mut impl Write,
+    reply: &Reply,
+    keep_alive: bool,
+    head: bool,
+) -> Result<(), HgError> {
+    let mut framing = format!("Content-Length: {}", reply.body.len());
+    if !keep_alive {
+        framing.push_str("\r\nConnection: close");
+    }
+    write_head(out, reply.status, reply.media_type, &framing)?;
+    if !head {
+        out.write_all(reply.body).map_err(writing_error)?;
+    }
+    out.flush().map_err(writing_error)
+}
@@ -0,0 +1,121 @@
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::errors::IoResultExt;
+use hg::repo::Repo;
+use hg::repo::ShareOptions;
+use hg::requirements;
+
+use crate::commands::clone::branch_tip;
+use crate::commands::clone::check_update_hooks;
+use crate::commands::clone::checkout_new;
+use crate::commands::clone::default_checkout;
+use crate::commands::clone::default_dest;
+use crate::commands::clone::local_path;
+use crate::error::CommandError;
+
+pub const HELP_TEXT: &str = "
+create a new shared repository
+
+Initialize a new repository and working directory that shares its
+history (and optionally bookmarks) with another repository.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("share")
+        .args_override_self(true)
+        .arg(
+            Arg::new("source")
+                .help("the repository to share")
+                .required(true)
+                .value_name("SOURCE"),
+        )
+        .arg(
+            Arg::new("dest")
+                .help("the directory of the new repository")
+                .value_name("DEST"),
+        )
+        .arg(
+            Arg::new("noupdate")
+                .help("do not create a working directory")
+                .short('U')
+                .long("noupdate")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("bookmarks")
+                .help("also share bookmarks")
+                .short('B')
+                .long("bookmarks")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("relative")
+                .help("point to source using a relative path")
+                .long("relative")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg share")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    if !config.is_extension_enabled(b"share") {
+        return Err(CommandError::unsupported(
+            "share is provided by an extension",
+        ));
+    }
+    let source: &String =
+        args.get_one("source").expect("source should be a required argument");
+    let no_update = args.get_flag("noupdate");
+    let relative = args.get_flag("relative");
+    let quiet = config.get_bool(b"ui", b"quiet")?;
+
+    let source_path = local_path(config, source)?;
+    let source_repo = Repo::find(config, Some(source_path.clone()))?;
+    if source_repo.has_narrow() {
+        return Err(CommandError::unsupported("sharing a narrow repository"));
+    }
+    let dest = match args.get_one::<String>("dest") {
+        Some(dest) => local_path(config, dest)?,
+        None => default_dest(&source_path),
+    };
+    if !no_update {
+        check_update_hooks(config)?;
+    }
+
+    let requirements = requirements::share_requirements(
+        config,
+        source_repo.requirements(),
+        relative,
+    )?;
+    let share = ShareOptions {
+        source: source_repo.shared_path().to_owned(),
+        relative,
+        items: if args.get_flag("bookmarks") {
+            vec!["bookmarks"]
+        } else {
+            vec![]
+        },
+    };
+    let repo = Repo::create(config, &dest, &requirements, Some(&share))?;
+    if let Some(default) = source_repo.config().get(b"paths", b"default") {
+        let path = repo.hg_vfs().join("hgrc");
+        let content = format_bytes!(b"[paths]\ndefault = {}\n", default);
+        std::fs::write(&path, content).when_writing_file(&path)?;
+    }
+    if no_update {
+        return Ok(());
+    }
+    let target = match branch_tip(&repo, b"default")? {
+        Some(rev) => rev,
+        None => default_checkout(&repo)?,
+    };
+    checkout_new(ui, &repo, target, quiet, |_| {
+        b"updating working directory\n".to_vec()
+    })
+}
@@ -7,11 +7,15 @@
 
 use std::io;
 use std::mem::take;
+use std::path::Path;
 
 use clap::Arg;
 use format_bytes::format_bytes;
+use hg::FastHashSet;
 use hg::Revision;
+use hg::dirstate::DirstateError;
 use hg::dirstate::entry::TruncatedTimestamp;
+use hg::dirstate::owning::OwningDirstateMap;
 use hg::dirstate::status::BadMatch;
 use hg::dirstate::status::DirstateStatus;
 use hg::dirstate::status::StatusError;
@@ -20,10 +24,17 @@ use hg::dirstate::status::StatusPath;
 use hg::errors::HgBacktrace;
 use hg::errors::HgError;
 use hg::errors::IoResultExt;
+use hg::file_patterns::FilePattern;
+use hg::file_patterns::PatternSyntax;
 use hg::file_patterns::parse_pattern_args;
 use hg::lock::LockError;
 use hg::matchers::AlwaysMatcher;
+use hg::matchers::FileMatcher;
 use hg::matchers::IntersectionMatcher;
+use hg::matchers::Matcher;
+use hg::matchers::PatternMatcher;
+use hg::matchers::UnionMatcher;
+use hg::matchers::VisitChildrenSet;
 use hg::matchers::get_ignore_files;
 use hg::narrow;
 use hg::repo::Repo;
@@ -36,9 +47,13 @@ use hg::revlog::options::default_revlog_options;
 use hg::sparse;
 use hg::utils::debug::debug_wait_for_file;
 use hg::utils::files::get_bytes_from_os_str;
+use hg::utils::hg_path::HgPath;
+use hg::utils::hg_path::HgPathBuf;
 use hg::utils::hg_path::hg_path_to_path_buf;
+use hg::vfs::Vfs;
 use hg::warnings::HgWarningContext;
 use hg::{self};
+use hg_fuse::server::overlay::working_copy_changes;
 use rayon::prelude::*;
 use tracing::info;
 
@@ -48,6 +63,7 @@ use crate::ui::Ui;
 use crate::ui::print_warnings;
 use crate::ui::relative_paths;
 use crate::utils::path_utils::RelativizePaths;
+use crate::utils::path_utils::file_args_matcher;
 
 pub const HELP_TEXT: &str = "
 Show changed files in the working directory
@@ -367,8 +383,6 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
         ));
     }
 
-    let mut dmap = repo.dirstate_map_mut()?;
-
     let check_exec = hg::checkexec::check_exec(repo.working_directory_path());
 
     let options = StatusOptions {
@@ -533,23 +547,32 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
                 .filter(|s| !s.is_empty())
                 .map(get_bytes_from_os_str)
                 .collect();
-            for file in &patterns {
-                if file.starts_with(b"set:") {
-                    return Err(CommandError::unsupported("fileset"));
-                }
-            }
-            let cwd = hg::utils::current_dir()?;
-            let root = repo.working_directory_path();
-            let file_patterns = parse_pattern_args(patterns, &cwd, root)
-                .map_err(|e| {
-                    // relative paths are not handled correctly here and
-                    // make rhg falsely claim that we're traversing .hg
-                    // TODO improve this situation
-                    CommandError::unsupported(HgError::from(e).to_string())
-                })?;
-            let files_matcher =
-                hg::matchers::PatternMatcher::new(file_patterns)?;
-            Box::new(IntersectionMatcher::new(Box::new(files_matcher), matcher))
+            // Filesets are evaluated in the revision whose files are listed
+            let fileset_rev = match (change, revpair) {
+                (Some(rev), _) | (None, Some((_, rev))) => rev.into(),
+                (None, None) => RevisionOrWdir::wdir(),
+            };
+            let files_matcher = file_args_matcher(
+                repo,
+                fileset_rev,
+                patterns,
+                warnings_sender,
+                |patterns| {
+                    let cwd = hg::utils::current_dir()?;
+                    let root = repo.working_directory_path();
+                    let file_patterns = parse_pattern_args(
+                        patterns, &cwd, root,
+                    )
+                    .map_err(|e| {
+                        // relative paths are not handled correctly here and
+                        // make rhg falsely claim that we're traversing .hg
+                        // TODO improve this situation
+                        CommandError::unsupported(HgError::from(e).to_string())
+                    })?;
+                    Ok(Box::new(PatternMatcher::new(file_patterns)?))
+                },
+            )?;
+            Box::new(IntersectionMatcher::new(files_matcher, matcher))
         }
     };
 
@@ -610,6 +633,13 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
         return Ok(());
     }
 
+    let mut dmap = repo.dirstate_map_mut()?;
+    let matcher = match overlay_changes_matcher(repo, &dmap, display_states)? {
+        Some(changes_matcher) => {
+            Box::new(IntersectionMatcher::new(changes_matcher, matcher))
+        }
+        None => matcher,
+    };
     let (fixup, mut dirstate_write_needed, filesystem_time_at_status_start) =
         dmap.with_status(
             &matcher,
@@ -698,6 +728,92 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
     Ok(())
 }
 
+/// Returns a matcher for the only paths that may have changed in this
+/// working copy, if it is served by a FUSE.
+///
+/// The FUSE synthesizes a clean dirstate entry for every file of its
+/// revision, and keeps every change in its overlay: nothing else can have
+/// changed, so the status doesn't need to look at every file.
+fn overlay_changes_matcher(
+    repo: &Repo,
+    dmap: &OwningDirstateMap,
+    display_states: DisplayStates,
+) -> Result<Option<OverlayChangesMatcher>, CommandError> {
+    // Listing clean files needs to look at all of them anyway
+    if display_states.clean
+        || !repo.config().get_bool(b"fuse", b"overlay-status")?
+    {
+        return Ok(None);
+    }
+    let hg_vfs = repo.hg_vfs();
+    let changes = match working_copy_changes(hg_vfs.base()) {
+        Ok(Some(changes)) => changes,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            // Look at everything instead
+            tracing::warn!("failed to read the changes of the overlay: {e}");
+            return Ok(None);
+        }
+    };
+    let mut files = changes.changed;
+    if changes.dirstate_changed {
+        // Files that were added, removed or merged may not have changed
+        for entry in dmap.iter() {
+            let (path, entry) = entry.map_err(DirstateError::from)?;
+            if !entry.maybe_clean() {
+                files.push(path.to_owned());
+            }
+        }
+    }
+    let removed = changes
+        .removed
+        .iter()
+        .map(|path| {
+            FilePattern::new(
+                PatternSyntax::Path,
+                path.as_bytes(),
+                Path::new(""),
+            )
+        })
+        .collect();
+    let matchers: Vec<Box<dyn Matcher + Send>> = vec![
+        Box::new(FileMatcher::new(files)?),
+        Box::new(PatternMatcher::new(removed)?),
+    ];
+    Ok(Some(OverlayChangesMatcher(UnionMatcher::new(matchers))))
+}
+
+/// Restricts the status to the paths of [`overlay_changes_matcher`], without
+/// them being explicitly listed: those that are gone are not an error.
+#[derive(Debug)]
+struct OverlayChangesMatcher(UnionMatcher<Box<dyn Matcher + Send>>);
+
+impl Matcher for OverlayChangesMatcher {
+    fn file_set(&self) -> Option<&FastHashSet<HgPathBuf>> {
+        None
+    }
+
+    fn exact_match(&self, _filename: &HgPath) -> bool {
+        false
+    }
+
+    fn matches(&self, filename: &HgPath) -> bool {
+        self.0.matches(filename)
+    }
+
+    fn visit_children_set(&self, directory: &HgPath) -> VisitChildrenSet {
+        self.0.visit_children_set(directory)
+    }
+
+    fn matches_everything(&self) -> bool {
+        false
+    }
+
+    fn is_exact(&self) -> bool {
+        false
+    }
+}
+
 struct DisplayStatusPaths<'a> {
     ui: &'a Ui,
     no_status: bool,
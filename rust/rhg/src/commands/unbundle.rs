@@ -0,0 +1,898 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+use std::time::Instant;
+
+use clap::Arg;
+use format_bytes::format_bytes;
+use hg::Graph;
+use hg::Node;
+use hg::Revision;
+use hg::bundle::Bundle;
+use hg::bundle::bundle2::Bundle2Reader;
+use hg::bundle::bundle2::Part;
+use hg::bundle::parts::Stream2Params;
+use hg::bundle::parts::Stream3Params;
+use hg::bundle::parts::changegroup_version;
+use hg::bundle::parts::decode_phase_heads;
+use hg::changegroup::ApplyStage;
+use hg::changegroup::ChangegroupUnpacker;
+use hg::changegroup::apply_changegroup;
+use hg::config::Config;
+use hg::errors::HgError;
+use hg::errors::IoResultExt;
+use hg::exit_codes;
+use hg::lock::LockError;
+use hg::phases::PhaseCache;
+use hg::repo::Repo;
+use hg::requirements;
+use hg::revlog::RevlogError;
+use hg::revlog::index::Phase;
+use hg::store::Store;
+use hg::store::fncache::Fncache;
+use hg::stream_clone::StreamApplier;
+use hg::stream_clone::new_requirements;
+use hg::stream_clone::post_stream_cleanup;
+use hg::stream_clone::read_entry_count;
+use hg::transaction::StoreTransaction;
+use hg::utils::strings::SliceExt;
+use hg::utils::strings::byte_count;
+
+use crate::error::CommandError;
+use crate::ui::Ui;
+use crate::utils::hooks::HookArgs;
+use crate::utils::hooks::Hooks;
+
+pub const HELP_TEXT: &str = "
+apply one or more bundle files
+
+Apply one or more bundle files generated by 'hg bundle'.
+
+Returns 0 on success.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("unbundle")
+        .args_override_self(true)
+        .arg(
+            Arg::new("file")
+                .help("the bundles to apply")
+                .required(true)
+                .num_args(1..)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::new("update")
+                .help("update to new branch head if changesets were unbundled")
+                .short('u')
+                .long("update")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("suppress output")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .about(HELP_TEXT)
+}
+
+/// The hooks run while applying a bundle
+const HOOK_TYPES: &[&[u8]] = &[
+    b"pretxnopen",
+    b"prechangegroup",
+    b"pretxnchangegroup",
+    b"pretxnclose",
+    b"changegroup",
+    b"incoming",
+    b"txnclose",
+];
+
+/// The hooks about phase and bookmark changes, which are not run by rhg
+const UNSUPPORTED_HOOK_TYPES: &[&[u8]] = &[
+    b"pretxnclose-phase",
+    b"pretxnclose-bookmark",
+    b"txnclose-phase",
+    b"txnclose-bookmark",
+];
+
+/// The requirements of the repositories rhg can add changesets to
+const SUPPORTED_REQUIREMENTS: &[&str] = &[
+    requirements::REVLOGV1_REQUIREMENT,
+    requirements::STORE_REQUIREMENT,
+    requirements::FNCACHE_REQUIREMENT,
+    requirements::DOTENCODE_REQUIREMENT,
+    requirements::GENERALDELTA_REQUIREMENT,
+    requirements::SPARSEREVLOG_REQUIREMENT,
+    requirements::REVLOG_COMPRESSION_ZSTD,
+    // The persistent nodemap is not updated, but Python catches up with
+    // the revisions it does not cover, and updates it on its next write.
+    requirements::NODEMAP_REQUIREMENT,
+    requirements::SHARED_REQUIREMENT,
+    requirements::RELATIVE_SHARED_REQUIREMENT,
+    requirements::SHARESAFE_REQUIREMENT,
+    requirements::DIRSTATE_V2_REQUIREMENT,
+    requirements::DIRSTATE_TRACKED_HINT_V1,
+    requirements::SPARSE_REQUIREMENT,
+    requirements::BOOKMARKS_IN_STORE_REQUIREMENT,
+];
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg unbundle")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let config = invocation.config;
+    let repo = invocation.repo?;
+    let files: Vec<&String> = args
+        .get_many("file")
+        .expect("file should be a required argument")
+        .collect();
+    if args.get_flag("update") {
+        return Err(CommandError::unsupported("unbundle --update"));
+    }
+    check_supported(repo)?;
+    let hooks = hooks(config)?;
+    let quiet = args.get_flag("quiet") || config.get_bool(b"ui", b"quiet")?;
+
+    let mut after_lock = vec![];
+    let result = match repo.try_with_lock_no_wait(|| {
+        let mut modheads = 0;
+        for file in files {
+            let mut unbundling = Unbundling {
+                ui,
+                repo,
+                hooks: &hooks,
+                quiet,
+                after_lock: &mut after_lock,
+            };
+            modheads = unbundling.apply_file(file)?;
+        }
+        Ok(modheads)
+    }) {
+        Ok(result) => result,
+        Err(LockError::AlreadyHeld) => {
+            Err(CommandError::unsupported("waiting for the store lock"))
+        }
+        Err(LockError::IO(error)) => Err(error.into()),
+    };
+    // Like in Python, these run once the lock is released, even if applying
+    // a later bundle failed
+    let root = repo.working_directory_path();
+    for (hook_type, args) in after_lock {
+        hooks.run(ui, root, hook_type, &args, false, || Ok(false))?;
+    }
+    post_incoming(ui, repo, result?, quiet)
+}
+
+/// The hooks run while adding changesets, falling back to Python for those
+/// rhg does not run
+pub(crate) fn hooks(config: &Config) -> Result<Hooks, CommandError> {
+    let hooks =
+        Hooks::new(config, &[HOOK_TYPES, UNSUPPORTED_HOOK_TYPES].concat())?;
+    for hook_type in UNSUPPORTED_HOOK_TYPES {
+        if hooks.has(hook_type) {
+            return Err(CommandError::unsupported(format!(
+                "{} hook",
+                String::from_utf8_lossy(hook_type)
+            )));
+        }
+    }
+    Ok(hooks)
+}
+
+/// Falls back to Python for what rhg cannot add changesets to
+pub(crate) fn check_supported(repo: &Repo) -> Result<(), CommandError> {
+    for requirement in repo.requirements() {
+        if !SUPPORTED_REQUIREMENTS.contains(&requirement.as_str()) {
+            return Err(CommandError::unsupported(format!(
+                "unbundle with requirement {}",
+                requirement
+            )));
+        }
+    }
+    let obsstore = repo.store_vfs().join("obsstore");
+    if std::fs::metadata(&obsstore).is_ok_and(|meta| meta.len() > 0) {
+        return Err(CommandError::unsupported("obsolescence markers"));
+    }
+    Ok(())
+}
+
+/// Applies bundles, running hooks the way `hg unbundle` does
+pub(crate) struct Unbundling<'a> {
+    pub(crate) ui: &'a Ui,
+    pub(crate) repo: &'a Repo,
+    pub(crate) hooks: &'a Hooks,
+    pub(crate) quiet: bool,
+    /// The hooks to run once the lock is released
+    pub(crate) after_lock: &'a mut Vec<(&'static [u8], HookArgs)>,
+}
+
+impl Unbundling<'_> {
+    /// Applies the bundle `file` in its own transaction, returning the
+    /// change in the number of heads like `combinechangegroupresults` in
+    /// Python
+    fn apply_file(&mut self, file: &str) -> Result<isize, CommandError> {
+        let path = Path::new(file);
+        let reader = BufReader::new(File::open(path).when_reading_file(path)?);
+        let bundle = hg::bundle::read_bundle(reader, file)
+            .map_err(|error| prefix_unknown_feature(error.into(), file))?;
+        let url = format!("bundle:{}", file);
+        let txn_name = match &bundle {
+            Bundle::Bundle2(_) => "unbundle".to_owned(),
+            Bundle::Changegroup { .. } => format!("unbundle\n{}", url),
+            Bundle::StreamClone { .. } => {
+                return Err(HgError::abort(
+                    "packed bundles cannot be applied with \"hg unbundle\"",
+                    exit_codes::INPUT_ERROR,
+                    Some("use \"hg debugapplystreamclonebundle\"".to_owned()),
+                )
+                .into());
+            }
+        };
+
+        let mut tr = Transaction::open(self, &txn_name)?;
+        let result = match bundle {
+            Bundle::Changegroup { mut unpacker, .. } => {
+                tr.set_hook_arg("source", b"unbundle");
+                tr.set_hook_arg("url", url.as_bytes());
+                tr.apply_changegroup(&mut unpacker, Phase::Draft)
+            }
+            Bundle::Bundle2(mut reader) => {
+                tr.set_hook_arg("bundle2", b"1");
+                tr.set_hook_arg("source", b"unbundle");
+                tr.set_hook_arg("url", url.as_bytes());
+                tr.apply_bundle2(&mut reader)
+                    .map_err(|error| prefix_unknown_feature(error, file))
+            }
+            Bundle::StreamClone { .. } => unreachable!("rejected above"),
+        };
+        match result {
+            Ok(()) => tr.close(),
+            Err(error) => Err(tr.abort(error)),
+        }
+    }
+}
+
+/// Mentions the bundle in the errors about what it needs, like Python
+fn prefix_unknown_feature(error: CommandError, file: &str) -> CommandError {
+    match error {
+        CommandError::Abort {
+            message,
+            detailed_exit_code,
+            hint,
+            backtrace,
+        } if message.starts_with(b"unknown bundle feature") => {
+            CommandError::Abort {
+                message: format_bytes!(b"{}: {}", file.as_bytes(), message),
+                detailed_exit_code,
+                hint,
+                backtrace,
+            }
+        }
+        error => error,
+    }
+}
+
+/// The transaction applying one bundle, like `repo.transaction` in Python
+/// with the callbacks registered for `unbundle`
+pub(crate) struct Transaction<'u, 'a> {
+    unbundling: &'u mut Unbundling<'a>,
+    tr: StoreTransaction,
+    phases: PhaseCache,
+    fncache: Option<Fncache>,
+    /// The arguments of the hooks about the whole transaction, like
+    /// `tr.hookargs`
+    hook_args: HookArgs,
+    /// Whether pending changes were written for hooks, which then keep
+    /// seeing them
+    any_pending: bool,
+    /// The number of changesets before the transaction
+    original_len: usize,
+    /// The phases of these changesets before phases were first updated
+    original_phases: Option<Vec<Phase>>,
+    /// What `added N changesets with N changes to N files` reports
+    changesets: usize,
+    revisions: usize,
+    files: usize,
+    heads: isize,
+    /// The change in the number of heads of each changegroup
+    results: Vec<isize>,
+    /// The hooks to run once the lock is released if the transaction
+    /// closes
+    post_close: Vec<(&'static [u8], HookArgs)>,
+}
+
+impl<'u, 'a> Transaction<'u, 'a> {
+    pub(crate) fn open(
+        unbundling: &'u mut Unbundling<'a>,
+        name: &str,
+    ) -> Result<Self, CommandError> {
+        let repo = unbundling.repo;
+        let tr = StoreTransaction::open(repo, name)?;
+        let store = Store::new(repo);
+        let fncache = if repo
+            .requirements()
+            .contains(requirements::FNCACHE_REQUIREMENT)
+        {
+            Some(store.fncache()?)
+        } else {
+            None
+        };
+        let hook_args: HookArgs = vec![
+            ("txnid", tr.id().as_bytes().to_vec()),
+            ("txnname", name.as_bytes().to_vec()),
+        ];
+        let mut this = Self {
+            phases: store.phase_cache()?,
+            fncache,
+            original_len: repo.changelog()?.get_index().len(),
+            original_phases: None,
+            unbundling,
+            tr,
+            hook_args,
+            any_pending: false,
+            changesets: 0,
+            revisions: 0,
+            files: 0,
+            heads: 0,
+            results: vec![],
+            post_close: vec![],
+        };
+        // Python runs it right before creating the transaction, whose
+        // journal is all there is to roll back if it fails
+        let args = this.hook_args.clone();
+        match this.run_hook(b"pretxnopen", &args) {
+            Ok(()) => Ok(this),
+            Err(error) => Err(this.abort(error)),
+        }
+    }
+
+    fn hook_arg(&self, name: &str) -> Option<&[u8]> {
+        self.hook_args
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Sets a hook argument unless it is already set
+    pub(crate) fn set_hook_arg(&mut self, name: &'static str, value: &[u8]) {
+        if self.hook_arg(name).is_none() {
+            self.hook_args.push((name, value.to_vec()));
+        }
+    }
+
+    /// Runs the hooks of `hook_type`, which abort the transaction if they
+    /// fail
+    fn run_hook(
+        &mut self,
+        hook_type: &[u8],
+        args: &[(&str, Vec<u8>)],
+    ) -> Result<(), CommandError> {
+        let hooks = self.unbundling.hooks;
+        if !hooks.has(hook_type) {
+            return Ok(());
+        }
+        let ui = self.unbundling.ui;
+        let root = self.unbundling.repo.working_directory_path();
+        hooks.run(ui, root, hook_type, args, true, || self.write_pending())?;
+        Ok(())
+    }
+
+    /// Makes the changes so far visible to hooks, like `writepending` in
+    /// Python
+    fn write_pending(&mut self) -> Result<bool, CommandError> {
+        let mut changelog = self.unbundling.repo.changelog_mut()?;
+        let changelog_pending = changelog.write_pending(&mut self.tr)?;
+        let phases_pending =
+            self.phases.write_pending(changelog.get_index(), &mut self.tr)?;
+        self.any_pending |= changelog_pending || phases_pending;
+        Ok(self.any_pending)
+    }
+
+    fn status(&self, message: &[u8]) -> Result<(), CommandError> {
+        if !self.unbundling.quiet {
+            self.unbundling.ui.write_stdout(message)?;
+        }
+        Ok(())
+    }
+
+    fn apply_bundle2(
+        &mut self,
+        reader: &mut Bundle2Reader,
+    ) -> Result<(), CommandError> {
+        while let Some(mut part) = reader.next_part()? {
+            match part.header.part_type.as_str() {
+                "changegroup" => self.apply_changegroup_part(&mut part)?,
+                "phase-heads" => {
+                    let heads = decode_phase_heads(&part.read_payload()?)?;
+                    self.update_phases(&heads)?;
+                }
+                "output" => {
+                    for line in
+                        part.read_payload()?.split_inclusive(|&b| b == b'\n')
+                    {
+                        let line = line.strip_suffix(b"\n").unwrap_or(line);
+                        self.status(&format_bytes!(b"remote: {}\n", line))?;
+                    }
+                }
+                "stream2" => {
+                    let params = Stream2Params::from_header(&part.header)?;
+                    self.check_stream_clone(&params.requirements)?;
+                    self.status(
+                        format!(
+                            "{} files to transfer, {} of data\n",
+                            params.file_count,
+                            byte_count(params.byte_count as f64)
+                        )
+                        .as_bytes(),
+                    )?;
+                    let start = Instant::now();
+                    let repo = self.unbundling.repo;
+                    let mut applier = StreamApplier::new(
+                        repo,
+                        &mut self.tr,
+                        self.fncache.as_mut(),
+                    )?;
+                    applier.apply_v2(&mut part, params.file_count)?;
+                    let counts = (applier.file_count(), applier.byte_count());
+                    self.finish_stream_clone(start, counts)?;
+                }
+                "stream3-exp" => {
+                    let params = Stream3Params::from_header(&part.header)?;
+                    self.check_stream_clone(&params.requirements)?;
+                    let entry_count = read_entry_count(&mut part)?;
+                    self.status(
+                        format!("{} entries to transfer\n", entry_count)
+                            .as_bytes(),
+                    )?;
+                    let start = Instant::now();
+                    let repo = self.unbundling.repo;
+                    let mut applier = StreamApplier::new(
+                        repo,
+                        &mut self.tr,
+                        self.fncache.as_mut(),
+                    )?;
+                    applier.apply_v3(&mut part, entry_count)?;
+                    let counts = (applier.file_count(), applier.byte_count());
+                    self.finish_stream_clone(start, counts)?;
+                }
+                // Caches that Mercurial rebuilds when needed
+                "hgtagsfnodes" | "cache:rev-branch-cache" => {}
+                part_type => {
+                    return Err(CommandError::unsupported(format!(
+                        "bundle2 part {}",
+                        part_type
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the changegroup of a `changegroup` part, like
+    /// `handlechangegroup` in Python
+    pub(crate) fn apply_changegroup_part(
+        &mut self,
+        part: &mut Part,
+    ) -> Result<(), CommandError> {
+        let header = &part.header;
+        for unsupported in ["treemanifest", "exp-sidedata"] {
+            if header.param(unsupported).is_some() {
+                return Err(CommandError::unsupported(format!(
+                    "changegroup part with {}",
+                    unsupported
+                )));
+            }
+        }
+        header.check_params(&[
+            "version",
+            "nbchanges",
+            "targetphase",
+            "exp-wanted-sidedata",
+        ])?;
+        let version = changegroup_version(header)?;
+        let target_phase = match header.param("targetphase") {
+            None => Phase::Draft,
+            Some(phase) => std::str::from_utf8(phase)
+                .ok()
+                .and_then(|phase| phase.parse::<usize>().ok())
+                .and_then(|phase| Phase::try_from(phase).ok())
+                .ok_or_else(|| {
+                    CommandError::abort(format!(
+                        "abort: invalid targetphase: {}",
+                        String::from_utf8_lossy(phase)
+                    ))
+                })?,
+        };
+        let mut unpacker = ChangegroupUnpacker::new(part, version);
+        self.apply_changegroup(&mut unpacker, target_phase)
+    }
+
+    /// Adds a changegroup to the repository, like `cg1unpacker.apply` in
+    /// Python
+    pub(crate) fn apply_changegroup<R: Read>(
+        &mut self,
+        unpacker: &mut ChangegroupUnpacker<R>,
+        target_phase: Phase,
+    ) -> Result<(), CommandError> {
+        let repo = self.unbundling.repo;
+        let args = self.hook_args.clone();
+        self.run_hook(b"prechangegroup", &args)?;
+
+        let mut ui_result = Ok(());
+        let applied = apply_changegroup(
+            repo,
+            unpacker,
+            &mut self.tr,
+            self.fncache.as_mut(),
+            |stage| {
+                let message: &[u8] = match stage {
+                    ApplyStage::Changesets => b"adding changesets\n",
+                    ApplyStage::Manifests => b"adding manifests\n",
+                    ApplyStage::Files => b"adding file changes\n",
+                };
+                if ui_result.is_ok() && !self.unbundling.quiet {
+                    ui_result = self.unbundling.ui.write_stdout(message);
+                }
+            },
+        )?;
+        ui_result?;
+        if !self.unbundling.quiet {
+            self.changesets += applied.changesets.len();
+            self.revisions += applied.file_revisions;
+            self.files += applied.files;
+            self.heads += applied.head_delta;
+        }
+
+        if let (Some(&first), Some(&last)) =
+            (applied.changesets.first(), applied.changesets.last())
+        {
+            let changelog = repo.changelog()?;
+            let hex = |rev| format!("{:x}", changelog.node_from_rev(rev));
+            let mut args = self.hook_args.clone();
+            if self.hook_arg("node").is_none() {
+                self.hook_args.push(("node", hex(first).into_bytes()));
+                self.hook_args.push(("node_last", hex(last).into_bytes()));
+                args = self.hook_args.clone();
+            } else {
+                for (key, value) in args.iter_mut() {
+                    match *key {
+                        "node" => *value = hex(first).into_bytes(),
+                        "node_last" => *value = hex(last).into_bytes(),
+                        _ => {}
+                    }
+                }
+            }
+            let incoming: Vec<HookArgs> = applied
+                .changesets
+                .iter()
+                .map(|&rev| {
+                    args.iter()
+                        .filter(|(key, _)| *key != "node_last")
+                        .map(|(key, value)| match *key {
+                            "node" => (*key, hex(rev).into_bytes()),
+                            _ => (*key, value.to_owned()),
+                        })
+                        .collect()
+                })
+                .collect();
+            drop(changelog);
+            self.run_hook(b"pretxnchangegroup", &args)?;
+
+            let changelog = repo.changelog()?;
+            let moved = self
+                .phases
+                .register_new(changelog.get_index(), target_phase, first)
+                .map_err(RevlogError::from)?;
+            drop(changelog);
+            if moved {
+                self.set_hook_arg("phases_moved", b"1");
+            }
+            self.post_close.push((b"changegroup", args));
+            for args in incoming {
+                self.post_close.push((b"incoming", args));
+            }
+        }
+        let delta = applied.head_delta;
+        self.results.push(if delta < 0 { delta - 1 } else { delta + 1 });
+        Ok(())
+    }
+
+    /// Checks that a stream clone with the `streamed` requirements can be
+    /// applied, like the handlers of the stream parts in Python
+    fn check_stream_clone(
+        &self,
+        streamed: &[String],
+    ) -> Result<(), CommandError> {
+        if self.original_len > 0 {
+            return Err(CommandError::abort(
+                "abort: cannot apply stream clone to non empty repository",
+            ));
+        }
+        // The revlogs of the repository cannot be reloaded with other
+        // requirements
+        let repo = self.unbundling.repo;
+        if new_requirements(repo.requirements(), streamed)
+            != *repo.requirements()
+        {
+            return Err(CommandError::unsupported(
+                "stream clone changing the requirements",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Picks up the store written by a stream clone of `files` files of
+    /// `bytes` bytes in total, and reports it like `_report_transferred` in
+    /// Python
+    fn finish_stream_clone(
+        &mut self,
+        start: Instant,
+        (files, bytes): (u64, u64),
+    ) -> Result<(), CommandError> {
+        let repo = self.unbundling.repo;
+        post_stream_cleanup(repo, repo.requirements())?;
+        repo.reload_revlogs()?;
+        // Or closing the transaction would overwrite the streamed phases
+        self.phases = Store::new(repo).phase_cache()?;
+        let elapsed = start.elapsed().as_secs_f64().max(0.001);
+        self.status(
+            format!(
+                "stream-cloned {} files / {} in {:.1} seconds ({}/sec)\n",
+                files,
+                byte_count(bytes as f64),
+                elapsed,
+                byte_count(bytes as f64 / elapsed)
+            )
+            .as_bytes(),
+        )
+    }
+
+    /// Applies the phases of a `phase-heads` part, like `updatephases` in
+    /// Python
+    pub(crate) fn update_phases(
+        &mut self,
+        heads: &[(Phase, Node)],
+    ) -> Result<(), CommandError> {
+        let changelog = self.unbundling.repo.changelog()?;
+        if self.original_phases.is_none() {
+            let mut phases = self
+                .phases
+                .phases(changelog.get_index())
+                .map_err(RevlogError::from)?;
+            phases.truncate(self.original_len);
+            self.original_phases = Some(phases);
+        }
+        let mut moved = false;
+        for &phase in Phase::all_phases() {
+            let revs = heads
+                .iter()
+                .filter(|(head_phase, _)| *head_phase == phase)
+                .map(|(_, node)| changelog.rev_from_node((*node).into()))
+                .collect::<Result<Vec<Revision>, _>>()?;
+            moved |= self
+                .phases
+                .advance_boundary(changelog.get_index(), phase, &revs)
+                .map_err(RevlogError::from)?;
+        }
+        drop(changelog);
+        if moved {
+            self.set_hook_arg("phases_moved", b"1");
+        }
+        Ok(())
+    }
+
+    /// Closes the transaction, reporting what was added, and returns the
+    /// change in the number of heads
+    pub(crate) fn close(mut self) -> Result<isize, CommandError> {
+        let args = self.hook_args.clone();
+        if let Err(error) = self.run_hook(b"pretxnclose", &args) {
+            return Err(self.abort(error));
+        }
+        let repo = self.unbundling.repo;
+        let finalized = (|| -> Result<(), CommandError> {
+            let mut changelog = repo.changelog_mut()?;
+            changelog.finalize_pending()?;
+            self.phases.write(changelog.get_index(), &mut self.tr)?;
+            if let Some(fncache) = &mut self.fncache {
+                fncache.write(&mut self.tr)?;
+            }
+            Ok(())
+        })();
+        if let Err(error) = finalized {
+            return Err(self.abort(error));
+        }
+        let mut messages = vec![];
+        if self.changesets > 0 || self.revisions > 0 || self.files > 0 {
+            let heads = match self.heads {
+                0 => String::new(),
+                heads => format!(" ({:+} heads)", heads),
+            };
+            messages.push(format!(
+                "added {} changesets with {} changes to {} files{}\n",
+                self.changesets, self.revisions, self.files, heads
+            ));
+        }
+        messages.extend(self.new_changesets_message()?);
+        messages.extend(self.published_message()?);
+        let Self { tr, unbundling, mut post_close, hook_args, results, .. } =
+            self;
+        tr.close()?;
+        if !unbundling.quiet {
+            for message in messages {
+                unbundling.ui.write_stdout(message.as_bytes())?;
+            }
+        }
+        // Like in Python, `txnclose` is queued when the transaction is
+        // finalized, before the hooks of its changegroups
+        unbundling.after_lock.push((b"txnclose", hook_args));
+        unbundling.after_lock.append(&mut post_close);
+        Ok(combine_results(&results))
+    }
+
+    /// Reports the range of the new changesets, like `reportnewcs` in Python
+    fn new_changesets_message(&self) -> Result<Option<String>, CommandError> {
+        let changelog = self.unbundling.repo.changelog()?;
+        let len = changelog.get_index().len();
+        if self.original_len >= len {
+            return Ok(None);
+        }
+        let short = |rev: usize| {
+            format!(
+                "{:x}",
+                changelog.node_from_rev(Revision(rev as i32)).short()
+            )
+        };
+        let range = if self.original_len == len - 1 {
+            short(len - 1)
+        } else {
+            format!("{}:{}", short(self.original_len), short(len - 1))
+        };
+        let phases = self
+            .phases
+            .phases(changelog.get_index())
+            .map_err(RevlogError::from)?;
+        let count = |phase| {
+            phases[self.original_len..].iter().filter(|&&p| p == phase).count()
+        };
+        let message = match (count(Phase::Draft), count(Phase::Secret)) {
+            (0, 0) => format!("new changesets {}\n", range),
+            (drafts, 0) => {
+                format!("new changesets {} ({} drafts)\n", range, drafts)
+            }
+            (0, secrets) => {
+                format!("new changesets {} ({} secrets)\n", range, secrets)
+            }
+            (drafts, secrets) => format!(
+                "new changesets {} ({} drafts, {} secrets)\n",
+                range, drafts, secrets
+            ),
+        };
+        Ok(Some(message))
+    }
+
+    /// Reports how many of the changesets that were already there became
+    /// public, like `reportphasechanges` in Python
+    fn published_message(&self) -> Result<Option<String>, CommandError> {
+        let Some(original_phases) = &self.original_phases else {
+            return Ok(None);
+        };
+        let changelog = self.unbundling.repo.changelog()?;
+        let phases = self
+            .phases
+            .phases(changelog.get_index())
+            .map_err(RevlogError::from)?;
+        let published = original_phases
+            .iter()
+            .zip(phases)
+            .filter(|&(&old, new)| old != Phase::Public && new == Phase::Public)
+            .count();
+        Ok((published > 0)
+            .then(|| format!("{} local changesets published\n", published)))
+    }
+
+    /// Rolls the transaction back after `error`, which is returned
+    pub(crate) fn abort(self, error: CommandError) -> CommandError {
+        let ui = self.unbundling.ui;
+        // Python is going to do it all again
+        let fallback = matches!(error, CommandError::UnsupportedFeature { .. });
+        let report = !fallback && self.tr.needs_rollback();
+        if report {
+            let _ = ui.write_stderr(b"transaction abort!\n");
+        }
+        match self.tr.abort() {
+            Ok(()) if report => {
+                let _ = ui.write_stderr(b"rollback completed\n");
+            }
+            Ok(()) => {}
+            Err(_) => {
+                let _ = ui
+                    .write_stderr(b"rollback failed - please run hg recover\n");
+            }
+        }
+        error
+    }
+}
+
+/// Combines the change in the number of heads of several changegroups, like
+/// `combinechangegroupresults` in Python
+fn combine_results(results: &[isize]) -> isize {
+    let mut changed_heads = 0;
+    for &result in results {
+        if result == 0 {
+            return 0;
+        } else if result < -1 {
+            changed_heads += result + 1;
+        } else if result > 1 {
+            changed_heads += result - 1;
+        }
+    }
+    match changed_heads {
+        0 => 1,
+        changed if changed > 0 => 1 + changed,
+        changed => -1 + changed,
+    }
+}
+
+/// Suggests what to do next, like `postincoming` in Python
+pub(crate) fn post_incoming(
+    ui: &Ui,
+    repo: &Repo,
+    modheads: isize,
+    quiet: bool,
+) -> Result<(), CommandError> {
+    if modheads == 0 || quiet {
+        return Ok(());
+    }
+    if modheads > 1 {
+        let branch = repo.hg_vfs().try_read("branch")?.unwrap_or_default();
+        let branch = match branch.trim() {
+            b"" => b"default".as_slice(),
+            branch => branch,
+        };
+        let branch_heads = open_branch_head_count(repo, branch)?;
+        let message: &[u8] = if branch_heads == modheads as usize {
+            b"(run 'hg heads' to see heads, 'hg merge' to merge)\n"
+        } else if branch_heads > 1 {
+            b"(run 'hg heads .' to see heads, 'hg merge' to merge)\n"
+        } else {
+            b"(run 'hg heads' to see heads)\n"
+        };
+        ui.write_stdout(message)?;
+    } else if !repo.config().get_bool(b"commands", b"update.requiredest")? {
+        ui.write_stdout(b"(run 'hg update' to get a working copy)\n")?;
+    }
+    Ok(())
+}
+
+/// The number of heads of `branch` that do not close it
+fn open_branch_head_count(
+    repo: &Repo,
+    branch: &[u8],
+) -> Result<usize, CommandError> {
+    let changelog = repo.changelog()?;
+    let len = changelog.get_index().len();
+    // Whether each revision is an open head of the branch so far, `None` if
+    // it is on another branch
+    let mut heads: Vec<Option<bool>> = vec![None; len];
+    for rev in 0..len {
+        let revision = Revision(rev as i32);
+        let extra = changelog.entry(revision)?.data()?.extra()?;
+        let rev_branch =
+            extra.get("branch").map(Vec::as_slice).unwrap_or(b"default");
+        if rev_branch != branch {
+            continue;
+        }
+        for parent in changelog.parents(revision).map_err(RevlogError::from)? {
+            if parent.0 >= 0
+                && let Some(head) = &mut heads[parent.0 as usize]
+            {
+                *head = false;
+            }
+        }
+        heads[rev] = Some(!extra.contains_key("close"));
+    }
+    Ok(heads.iter().filter(|&&head| head == Some(true)).count())
+}
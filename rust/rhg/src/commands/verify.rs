@@ -0,0 +1,188 @@
+use clap::Arg;
+use hg::lock::LockError;
+use hg::operations::VerifyMessage;
+use hg::operations::VerifyOptions;
+use hg::operations::verify;
+use hg::requirements::CHANGELOGV2_REQUIREMENT;
+use hg::requirements::REVLOGV1_REQUIREMENT;
+use hg::requirements::REVLOGV2_REQUIREMENT;
+use hg::utils::hg_path::HgPath;
+
+use crate::error::CommandError;
+
+pub const HELP_TEXT: &str = "
+verify the integrity of the repository
+
+Verify the integrity of the current repository.
+
+This will perform an extensive check of the repository's
+integrity, validating the hashes and checksums of each entry in
+the changelog, manifest, and tracked files, as well as the
+integrity of their crosslinks and indices.
+
+Please see https://mercurial-scm.org/wiki/RepositoryCorruption
+for more information about recovery from corruption of the
+repository.
+
+Returns 0 on success, 1 if errors are encountered.
+";
+
+pub fn args() -> clap::Command {
+    clap::command!("verify")
+        .arg(
+            Arg::new("full")
+                .help("perform more checks (EXPERIMENTAL)")
+                .long("full")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("only report problems")
+                .short('q')
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("also report suspicious copies")
+                .short('v')
+                .long("verbose")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("quiet"),
+        )
+        .about(HELP_TEXT)
+}
+
+#[tracing::instrument(level = "debug", skip_all, name = "rhg verify")]
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let config = invocation.config;
+    let args = invocation.subcommand_args;
+    let ui = invocation.ui;
+    let repo = invocation.repo?;
+
+    let quiet = args.get_flag("quiet") || config.get_bool(b"ui", b"quiet")?;
+    let verbose = !quiet
+        && (args.get_flag("verbose") || config.get_bool(b"ui", b"verbose")?);
+    let options = VerifyOptions {
+        full: args.get_flag("full"),
+        verbose,
+        error_on_censored: config.get_str(b"censor", b"policy")?
+            == Some("abort"),
+        skip_flags: config.get_u32(b"verify", b"skipflags")?.unwrap_or(0),
+    };
+
+    if repo.has_narrow() {
+        return Err(CommandError::unsupported("verify in narrow"));
+    }
+    if !repo.filelog(HgPath::new(b".hgsubstate"))?.is_empty() {
+        return Err(CommandError::unsupported("verify with subrepos"));
+    }
+    let requirements = repo.requirements();
+    if requirements.contains(REVLOGV2_REQUIREMENT)
+        || requirements.contains(CHANGELOGV2_REQUIREMENT)
+    {
+        return Err(CommandError::unsupported("verify with revlogv2"));
+    }
+    // Python only tells apart the original format 0 from later ones
+    let revlogv1 = requirements.contains(REVLOGV1_REQUIREMENT);
+
+    let verify_with_lock = || -> Result<bool, CommandError> {
+        if repo.store_vfs().join("journal").exists() {
+            ui.write_stderr(b"abandoned transaction found - run hg recover\n")?;
+        }
+        if verbose || !revlogv1 {
+            let format: &[u8] = if revlogv1 { b"1" } else { b"0" };
+            ui.write_stdout(
+                &[b"repository uses revlog format ", format, b"\n"].concat(),
+            )?;
+        }
+        let summary =
+            verify(repo, &options, |message| -> Result<(), CommandError> {
+                match message {
+                    VerifyMessage::Stage(stage) => {
+                        if !quiet {
+                            ui.write_stdout(
+                                &[stage.message(), b"\n"].concat(),
+                            )?;
+                        }
+                    }
+                    VerifyMessage::SkippingDirstate => ui.write_stderr(
+                        b"not checking dirstate because of previous errors\n",
+                    )?,
+                    VerifyMessage::Warning(message)
+                    | VerifyMessage::DirstateError(message) => {
+                        ui.write_stderr(&[&message[..], b"\n"].concat())?
+                    }
+                    VerifyMessage::Error(error) => ui.write_stderr(
+                        &[error.format(), b"\n".to_vec()].concat(),
+                    )?,
+                    VerifyMessage::Note(message) => {
+                        if verbose {
+                            ui.write_stdout(&[&message[..], b"\n"].concat())?;
+                        }
+                    }
+                }
+                Ok(())
+            })?;
+
+        if !quiet {
+            ui.write_stdout(
+                format!(
+                    "checked {} changesets with {} changes to {} files\n",
+                    summary.changesets, summary.file_revisions, summary.files
+                )
+                .as_bytes(),
+            )?;
+        }
+        if summary.warnings > 0 {
+            ui.write_stderr(
+                format!("{} warnings encountered!\n", summary.warnings)
+                    .as_bytes(),
+            )?;
+        }
+        if summary.fncache_warned {
+            ui.write_stderr(
+                b"hint: run \"hg debugrebuildfncache\" to recover from \
+                corrupt fncache\n",
+            )?;
+        }
+        if summary.errors == 0 {
+            return Ok(true);
+        }
+        ui.write_stderr(
+            format!("{} integrity errors encountered!\n", summary.errors)
+                .as_bytes(),
+        )?;
+        if let Some(rev) = summary.first_damaged_changeset {
+            ui.write_stderr(
+                format!("(first damaged changeset appears to be {})\n", rev)
+                    .as_bytes(),
+            )?;
+        }
+        if summary.dirstate_errors > 0 {
+            ui.write_stderr(
+                b"dirstate inconsistent with current parent's manifest\n",
+            )?;
+            ui.write_stderr(
+                format!("{} dirstate errors\n", summary.dirstate_errors)
+                    .as_bytes(),
+            )?;
+        }
+        Ok(false)
+    };
+
+    let success = match repo.try_with_lock_no_wait(verify_with_lock) {
+        Ok(result) => result?,
+        Err(LockError::AlreadyHeld) => {
+            return Err(CommandError::unsupported(
+                "waiting for the store lock",
+            ));
+        }
+        Err(LockError::IO(error)) => return Err(error.into()),
+    };
+    if success {
+        Ok(())
+    } else {
+        Err(CommandError::Unsuccessful)
+    }
+}
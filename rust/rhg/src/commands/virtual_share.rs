@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
@@ -9,6 +10,8 @@ use fuser::SessionACL;
 use hg::errors::IoResultExt;
 use hg::repo::Repo;
 use hg_fuse::fuse::HgFuse;
+#[cfg(feature = "hgfs")]
+use hg_fuse::server::remote::StoreAddress;
 use hg_fuse::server::store::BackendMode;
 use libc::SIGHUP;
 use libc::SIGINT;
@@ -19,7 +22,7 @@ use crate::error::CommandError;
 pub const HELP_TEXT: &str = "Mount a virtual hg filesystem (EXPERIMENTAL)";
 
 pub fn args() -> clap::Command {
-    clap::command!("debug::virtual-share")
+    let command = clap::command!("debug::virtual-share")
         .args_override_self(true)
         .arg(
             Arg::new("destination")
@@ -69,11 +72,28 @@ pub fn args() -> clap::Command {
                 .value_parser(EnumValueParser::<BackendMode>::new())
                 .help("what type of working copy to present"),
         )
-        .about(HELP_TEXT)
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .value_parser(clap::value_parser!(std::ffi::OsString))
+                .help(
+                    "directory to store changes in, making the share writable",
+                ),
+        );
+    #[cfg(feature = "hgfs")]
+    let command = command.arg(
+        Arg::new("remote-store")
+            .long("remote-store")
+            .value_parser(clap::value_parser!(std::ffi::OsString))
+            .help(
+                "mount the store served at this socket or http:// URL \
+                 instead of the local repository",
+            ),
+    );
+    command.about(HELP_TEXT)
 }
 
 pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
-    let repo = invocation.repo?;
     let Some(destination) =
         invocation.subcommand_args.get_one::<std::ffi::OsString>("destination")
     else {
@@ -93,11 +113,14 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
         invocation.subcommand_args.get_one("max-revisions-loaded").copied();
     let open_to_all = invocation.subcommand_args.get_flag("open-to-all");
     let open_to_root = invocation.subcommand_args.get_flag("open-to-root");
-    let backend_mode: BackendMode = invocation
+    let backend_mode = invocation
         .subcommand_args
         .get_one::<BackendMode>("backend-mode")
-        .copied()
-        .unwrap_or_default();
+        .copied();
+    let overlay_directory = invocation
+        .subcommand_args
+        .get_one::<std::ffi::OsString>("overlay")
+        .map(PathBuf::from);
     let session_acl = if open_to_all {
         SessionACL::All
     } else if open_to_root {
@@ -105,11 +128,6 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
     } else {
         SessionACL::Owner
     };
-    // Recreate an owned repo for the backend
-    let backend_repo = Repo::find(
-        repo.config(),
-        Some(repo.working_directory_path().to_path_buf()),
-    )?;
     // Set up non-fatal signals to break our loop
     let should_terminate = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(SIGINT, Arc::clone(&should_terminate))
@@ -119,16 +137,47 @@ pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
     signal_hook::flag::register(SIGHUP, Arc::clone(&should_terminate))
         .expect("signal should be valid to register");
 
+    #[cfg(feature = "hgfs")]
+    let remote_store = invocation
+        .subcommand_args
+        .get_one::<std::ffi::OsString>("remote-store");
+    #[cfg(not(feature = "hgfs"))]
+    let remote_store: Option<&std::ffi::OsString> = None;
+
     // Dropping this handle will unmount the filesystem
-    let session = HgFuse::mount_all_revs(
-        backend_repo,
-        destination,
-        backend_mode,
-        user_id,
-        group_id,
-        max_revisions_loaded,
-        session_acl,
-    )?;
+    let session = match remote_store {
+        #[cfg(feature = "hgfs")]
+        Some(address) => HgFuse::mount_remote(
+            invocation.config,
+            StoreAddress::parse(address),
+            destination,
+            // There's no local repository to point a `.hg` directory to
+            backend_mode.unwrap_or(BackendMode::Archive),
+            user_id,
+            group_id,
+            max_revisions_loaded,
+            session_acl,
+            overlay_directory,
+        )?,
+        _ => {
+            let repo = invocation.repo?;
+            // Recreate an owned repo for the backend
+            let backend_repo = Repo::find(
+                repo.config(),
+                Some(repo.working_directory_path().to_path_buf()),
+            )?;
+            HgFuse::mount_all_revs(
+                backend_repo,
+                destination,
+                backend_mode.unwrap_or_default(),
+                user_id,
+                group_id,
+                max_revisions_loaded,
+                session_acl,
+                overlay_directory,
+            )?
+        }
+    };
     loop {
         std::thread::sleep(Duration::from_millis(250));
         let was_unmounted = session.guard.is_finished();
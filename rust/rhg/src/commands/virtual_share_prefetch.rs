@@ -0,0 +1,68 @@
+use clap::Arg;
+use hg::matchers::AlwaysMatcher;
+use hg::repo::Repo;
+use hg_fuse::server::blob_cache::BlobCache;
+use hg_fuse::server::blob_cache::BlobCacheConfig;
+use hg_fuse::server::local::LocalBackend;
+use hg_fuse::server::store::BackendMode;
+
+use crate::error::CommandError;
+use crate::utils::path_utils::include_exclude_args;
+use crate::utils::path_utils::include_exclude_matcher;
+
+pub const HELP_TEXT: &str =
+    "Fill the virtual share file cache for a revision (EXPERIMENTAL)";
+
+pub fn args() -> clap::Command {
+    include_exclude_args(
+        clap::command!("debug::virtual-share-prefetch")
+            .args_override_self(true)
+            .arg(
+                Arg::new("rev")
+                    .help("revision to prefetch the files of")
+                    .short('r')
+                    .long("rev")
+                    .value_name("REV"),
+            ),
+    )
+    .about(HELP_TEXT)
+}
+
+pub fn run(invocation: &crate::CliInvocation) -> Result<(), CommandError> {
+    let repo = invocation.repo?;
+    let args = invocation.subcommand_args;
+    let Some(config) = BlobCacheConfig::for_repo(repo)? else {
+        return Err(CommandError::abort(
+            "abort: the file cache is disabled (set fuse.blob-cache-size)",
+        ));
+    };
+    let rev = args.get_one::<String>("rev").map_or(".", |rev| rev);
+    let Some(rev) = hg::revset::resolve_single(rev, repo)?.exclude_wdir()
+    else {
+        return Err(CommandError::unsupported("prefetching the working copy"));
+    };
+    let changeset = *repo.changelog()?.node_from_rev(rev);
+    let matcher = include_exclude_matcher(repo, args, AlwaysMatcher)?;
+
+    // Recreate an owned repo for the backend
+    let backend_repo = Repo::find(
+        repo.config(),
+        Some(repo.working_directory_path().to_path_buf()),
+    )?;
+    let store = LocalBackend::new(backend_repo, BackendMode::default())?;
+    let blob_cache = BlobCache::open(&config)?;
+    let stats = blob_cache
+        .prefetch(&store, changeset, &*matcher)
+        .map_err(|e| CommandError::abort(format!("abort: {:?}", e.kind)))?;
+    invocation.ui.write_stdout(
+        format!(
+            "{} files matched, {} already cached, {} fetched ({} bytes)\n",
+            stats.matched,
+            stats.already_cached,
+            stats.fetched,
+            stats.fetched_bytes
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
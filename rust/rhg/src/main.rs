@@ -43,7 +43,10 @@ mod color;
 mod error;
 mod ui;
 pub mod utils {
+    pub mod hooks;
     pub mod path_utils;
+    pub mod tracking_utils;
+    pub mod update_utils;
 }
 
 fn expand_aliases(
@@ -723,25 +726,48 @@ fn exit_no_fallback(
 }
 
 mod commands {
+    pub mod add;
+    pub mod addremove;
     pub mod admin_narrow_client;
     pub mod annotate;
+    pub mod archive;
+    pub mod bundle;
     pub mod cat;
+    pub mod clone;
     pub mod config;
+    pub mod copy;
     pub mod debug_narrow_fingerprint;
+    pub mod debugbundle;
     pub mod debugdata;
     pub mod debugignorerhg;
     pub mod debugrequirements;
     pub mod debugrhgsparse;
     pub mod files;
+    pub mod forget;
+    pub mod grep;
     #[cfg(feature = "hgfs")]
     pub mod hgfs_client;
     #[cfg(feature = "hgfs")]
     pub mod hgfs_server;
+    #[cfg(feature = "hgfs")]
+    pub mod hgfs_store_server;
+    pub mod incoming;
+    pub mod manifest;
+    pub mod outgoing;
+    pub mod pull;
     pub mod purge;
+    pub mod remove;
+    pub mod rename;
+    pub mod revert;
     pub mod root;
     pub mod script_hgignore;
+    pub mod serve;
+    pub mod share;
     pub mod status;
+    pub mod unbundle;
+    pub mod verify;
     pub mod virtual_share;
+    pub mod virtual_share_prefetch;
 }
 
 pub type RunFn = fn(&CliInvocation) -> Result<(), CommandError>;
@@ -812,25 +838,48 @@ impl Subcommands {
 
 fn subcommands() -> Subcommands {
     let subcommands = vec![
+        subcommand!(add),
+        subcommand!(addremove),
         subcommand!(admin_narrow_client),
         subcommand!(annotate),
+        subcommand!(archive),
+        subcommand!(bundle),
         subcommand!(cat),
+        subcommand!(clone),
+        subcommand!(debugbundle),
         subcommand!(debugdata),
         subcommand!(debug_narrow_fingerprint),
         subcommand!(debugrequirements),
         subcommand!(debugignorerhg),
         subcommand!(debugrhgsparse),
         subcommand!(files),
+        subcommand!(forget),
+        subcommand!(grep),
+        subcommand!(incoming),
+        subcommand!(manifest),
+        subcommand!(outgoing),
         subcommand!(root),
         subcommand!(purge),
+        subcommand!(pull),
+        subcommand!(remove),
+        subcommand!(rename),
+        subcommand!(revert),
         subcommand!(config),
+        subcommand!(copy),
         subcommand!(status),
+        subcommand!(unbundle),
         subcommand!(script_hgignore),
+        subcommand!(serve),
+        subcommand!(share),
+        subcommand!(verify),
         subcommand!(virtual_share),
+        subcommand!(virtual_share_prefetch),
         #[cfg(feature = "hgfs")]
         subcommand!(hgfs_server),
         #[cfg(feature = "hgfs")]
         subcommand!(hgfs_client),
+        #[cfg(feature = "hgfs")]
+        subcommand!(hgfs_store_server),
     ];
     let mut commands = Subcommands::new();
     for cmd in subcommands {
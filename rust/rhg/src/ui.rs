@@ -108,19 +108,25 @@ impl<W: Write> StdoutBuffer<'_, W> {
     /// Write bytes to stdout with the given label
     ///
     /// Like the optional `label` parameter in `mercurial/ui.py`,
-    /// this label influences the color used for this output.
+    /// this label influences the color used for this output. It may be
+    /// several labels separated by spaces, whose effects add up.
     pub fn write_stdout_labelled(
         &mut self,
         bytes: &[u8],
         label: &str,
     ) -> Result<(), UiError> {
-        if let Some(colors) = &self.colors
-            && let Some(effects) = colors.styles.get(label.as_bytes())
-            && !effects.is_empty()
-        {
-            return self
-                .write_stdout_with_effects(bytes, effects)
-                .or_else(handle_stdout_error);
+        if let Some(colors) = &self.colors {
+            let effects: Vec<Effect> = label
+                .split_ascii_whitespace()
+                .filter_map(|label| colors.styles.get(label.as_bytes()))
+                .flatten()
+                .copied()
+                .collect();
+            if !effects.is_empty() {
+                return self
+                    .write_stdout_with_effects(bytes, &effects)
+                    .or_else(handle_stdout_error);
+            }
         }
         self.write_all(bytes)
     }
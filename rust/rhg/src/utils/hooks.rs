@@ -0,0 +1,199 @@
+//! Running the shell commands configured in the `[hooks]` section, like
+//! `mercurial.hook` in Python.
+
+use std::ffi::OsString;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+
+use format_bytes::format_bytes;
+use hg::config::Config;
+use hg::exit_codes;
+use hg::utils::files::get_os_str_from_bytes;
+
+use crate::error::CommandError;
+use crate::ui::Ui;
+use crate::ui::plain;
+
+/// The arguments given to hooks, in order
+pub type HookArgs = Vec<(&'static str, Vec<u8>)>;
+
+/// A hook from the `[hooks]` section of the configuration
+struct Hook {
+    name: Vec<u8>,
+    command: Vec<u8>,
+    plain: bool,
+}
+
+/// The hooks of some types, sorted in the order they run
+pub struct Hooks {
+    hooks: Vec<Hook>,
+}
+
+impl Hooks {
+    /// Reads the hooks of the given types from `config`, like `_allhooks` in
+    /// Python.
+    ///
+    /// Python hooks are not supported: this falls back if any of them is
+    /// configured, so that a command never has to stop half way through.
+    pub fn new(
+        config: &Config,
+        hook_types: &[&[u8]],
+    ) -> Result<Self, CommandError> {
+        let mut hooks = vec![];
+        for (name, _) in config.iter_section(b"hooks") {
+            if name.starts_with(b"priority.")
+                || name.starts_with(b"tonative.")
+                || name.contains(&b':')
+            {
+                continue;
+            }
+            let hook_type = name.split(|&byte| byte == b'.').next();
+            if !hook_types.iter().any(|&t| Some(t) == hook_type) {
+                continue;
+            }
+            let command = config.get(b"hooks", name).unwrap_or_default();
+            if command.is_empty() {
+                continue;
+            }
+            if command.starts_with(b"python:") {
+                return Err(CommandError::unsupported(format!(
+                    "python hook {}",
+                    String::from_utf8_lossy(name)
+                )));
+            }
+            let priority_key = [b"priority.", name].concat();
+            let priority = match config
+                .get_str_no_default(b"hooks", &priority_key)?
+            {
+                None => 0,
+                Some(value) => value.trim().parse::<i64>().map_err(|_| {
+                    CommandError::abort_with_exit_code(
+                        format!(
+                            "abort: hooks.{} is not a valid integer ('{}')",
+                            String::from_utf8_lossy(&priority_key),
+                            value
+                        ),
+                        exit_codes::CONFIG_ERROR_ABORT,
+                    )
+                })?,
+            };
+            let plain_key = [name, b":run-with-plain"].concat();
+            let plain = if config.get(b"hooks", &plain_key) == Some(b"auto") {
+                plain(None)
+            } else {
+                config.get_bool_no_default(b"hooks", &plain_key)?
+            };
+            let hook = Hook {
+                name: name.to_owned(),
+                command: command.to_owned(),
+                plain,
+            };
+            hooks.push((-priority, hook));
+        }
+        // Stable, so hooks of the same priority keep the configuration order
+        hooks.sort_by_key(|(priority, _)| *priority);
+        Ok(Self { hooks: hooks.into_iter().map(|(_, hook)| hook).collect() })
+    }
+
+    fn of_type<'a>(
+        &'a self,
+        hook_type: &'a [u8],
+    ) -> impl Iterator<Item = &'a Hook> + 'a {
+        self.hooks.iter().filter(move |hook| {
+            hook.name.split(|&byte| byte == b'.').next() == Some(hook_type)
+        })
+    }
+
+    /// Whether any hook of `hook_type` is configured
+    pub fn has(&self, hook_type: &[u8]) -> bool {
+        self.of_type(hook_type).next().is_some()
+    }
+
+    /// Runs the hooks of `hook_type` in `repo_root`, giving them each of
+    /// `args` as an `HG_<NAME>` environment variable. `write_pending` is
+    /// called before each hook and returns whether the pending changes of
+    /// the current transaction are visible through `HG_PENDING`.
+    ///
+    /// A failing hook aborts if `throw` is set, and prints a warning
+    /// otherwise. Returns whether any hook failed.
+    pub fn run(
+        &self,
+        ui: &Ui,
+        repo_root: &Path,
+        hook_type: &[u8],
+        args: &[(&str, Vec<u8>)],
+        throw: bool,
+        mut write_pending: impl FnMut() -> Result<bool, CommandError>,
+    ) -> Result<bool, CommandError> {
+        let mut failed = false;
+        for hook in self.of_type(hook_type) {
+            let mut command = Command::new("/bin/sh");
+            command
+                .arg("-c")
+                .arg(get_os_str_from_bytes(&hook.command))
+                .current_dir(repo_root)
+                .env("HG", hg_executable())
+                .env("HG_HOOKTYPE", get_os_str_from_bytes(hook_type))
+                .env("HG_HOOKNAME", get_os_str_from_bytes(&hook.name))
+                .env("HGPLAIN", if hook.plain { "1" } else { "" })
+                .stdin(Stdio::inherit());
+            if write_pending()? {
+                command.env("HG_PENDING", repo_root);
+            }
+            for (name, value) in args {
+                command.env(
+                    format!("HG_{}", name.to_uppercase()),
+                    get_os_str_from_bytes(value),
+                );
+            }
+            let status = command.status().map_err(|error| {
+                CommandError::abort(format!(
+                    "abort: could not run {} hook: {}",
+                    String::from_utf8_lossy(&hook.name),
+                    error
+                ))
+            })?;
+            if status.success() {
+                continue;
+            }
+            failed = true;
+            let description = explain_exit(status);
+            if throw {
+                return Err(CommandError::abort_with_exit_code(
+                    format!(
+                        "abort: {} hook {}",
+                        String::from_utf8_lossy(&hook.name),
+                        description
+                    ),
+                    exit_codes::HOOK_FAILURE,
+                ));
+            }
+            ui.write_stderr(&format_bytes!(
+                b"warning: {} hook {}\n",
+                hook.name,
+                description.as_bytes()
+            ))?;
+        }
+        Ok(failed)
+    }
+}
+
+/// The command that hooks can use to run Mercurial, like `hgexecutable` in
+/// Python
+fn hg_executable() -> OsString {
+    std::env::var_os("HG")
+        .or_else(|| std::env::current_exe().ok().map(Into::into))
+        .unwrap_or_else(|| "hg".into())
+}
+
+/// Describes how a hook failed, like `explainexit` in Python
+fn explain_exit(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with status {}", code),
+        (None, Some(signal)) => format!("killed by signal {}", signal),
+        (None, None) => "failed".to_owned(),
+    }
+}
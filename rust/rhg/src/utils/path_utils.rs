@@ -7,12 +7,25 @@ use std::borrow::Cow;
 use std::ffi::OsString;
 
 use hg::errors::HgError;
+use hg::file_patterns::PatternSyntax;
+use hg::file_patterns::parse_pattern_args_with_default;
+use hg::fileset::fileset_matcher;
+use hg::matchers::DifferenceMatcher;
+use hg::matchers::IncludeMatcher;
+use hg::matchers::IntersectionMatcher;
+use hg::matchers::Matcher;
+use hg::matchers::UnionMatcher;
 use hg::repo::Repo;
+use hg::revlog::RevisionOrWdir;
 use hg::utils::current_dir;
+use hg::utils::files::get_bytes_from_os_str;
 use hg::utils::files::get_bytes_from_path;
 use hg::utils::files::relativize_path;
 use hg::utils::hg_path::HgPath;
 use hg::utils::hg_path::HgPathBuf;
+use hg::utils::hg_path::HgPathError;
+use hg::utils::hg_path::HgPathErrorKind;
+use hg::warnings::HgWarningSender;
 
 use crate::error::CommandError;
 
@@ -92,3 +105,118 @@ pub fn resolve_file_args<'a>(
     }
     Ok(result)
 }
+
+/// Add the `-I/--include` and `-X/--exclude` arguments to `command`
+pub fn include_exclude_args(command: clap::Command) -> clap::Command {
+    command
+        .arg(
+            clap::Arg::new("include")
+                .help("include names matching the given patterns")
+                .short('I')
+                .long("include")
+                .value_name("PATTERN")
+                .value_parser(clap::value_parser!(OsString))
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            clap::Arg::new("exclude")
+                .help("exclude names matching the given patterns")
+                .short('X')
+                .long("exclude")
+                .value_name("PATTERN")
+                .value_parser(clap::value_parser!(OsString))
+                .action(clap::ArgAction::Append),
+        )
+}
+
+/// Restrict `matcher` to the `-I/--include` patterns and remove the
+/// `-X/--exclude` ones, like Python's `scmutil.match`.
+///
+/// These patterns are globs relative to the current directory by default.
+pub fn include_exclude_matcher<'a>(
+    repo: &Repo,
+    args: &clap::ArgMatches,
+    matcher: impl Matcher + 'a,
+) -> Result<Box<dyn Matcher + 'a>, CommandError> {
+    let cwd = current_dir()?;
+    let root = repo.working_directory_path();
+    let parse = |id: &str| -> Result<_, CommandError> {
+        let Some(values) = args.get_many::<OsString>(id) else {
+            return Ok(None);
+        };
+        let patterns: Vec<Vec<u8>> =
+            values.map(get_bytes_from_os_str).collect();
+        if patterns.iter().any(|p| p.starts_with(b"set:")) {
+            return Err(CommandError::unsupported("fileset"));
+        }
+        let mut file_patterns = vec![];
+        for pattern in patterns {
+            let parsed = parse_pattern_args_with_default(
+                vec![pattern.clone()],
+                &cwd,
+                root,
+                PatternSyntax::Glob,
+            );
+            match parsed {
+                Ok(parsed) => file_patterns.extend(parsed),
+                Err(HgPathError {
+                    kind: HgPathErrorKind::NotUnderRoot { .. },
+                    ..
+                }) => {
+                    let relativize = RelativizePaths::new(repo)?;
+                    let root = relativize.relativize(HgPath::new(""));
+                    let root = root.strip_suffix(b"/").unwrap_or(&root);
+                    return Err(CommandError::abort(format!(
+                        "abort: {} not under root '{}'\n\
+                         (consider using '--cwd {}')",
+                        String::from_utf8_lossy(&pattern),
+                        repo.working_directory_path().display(),
+                        String::from_utf8_lossy(root),
+                    )));
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(Some(IncludeMatcher::new(file_patterns)?))
+    };
+    let matcher: Box<dyn Matcher + 'a> = match parse("include")? {
+        Some(include) => Box::new(IntersectionMatcher::new(matcher, include)),
+        None => Box::new(matcher),
+    };
+    Ok(match parse("exclude")? {
+        Some(exclude) => Box::new(DifferenceMatcher::new(matcher, exclude)),
+        None => matcher,
+    })
+}
+
+/// Returns a matcher for the `FILE ...` arguments of a command, where `set:`
+/// patterns are filesets evaluated in `rev`, like Python's
+/// `matchmod._expandsets`.
+///
+/// `pattern_matcher` builds the matcher of the other patterns.
+pub fn file_args_matcher(
+    repo: &Repo,
+    rev: RevisionOrWdir,
+    patterns: Vec<Vec<u8>>,
+    warnings: &HgWarningSender,
+    pattern_matcher: impl FnOnce(
+        Vec<Vec<u8>>,
+    )
+        -> Result<Box<dyn Matcher + Send>, CommandError>,
+) -> Result<Box<dyn Matcher + Send>, CommandError> {
+    let (filesets, patterns): (Vec<_>, Vec<_>) =
+        patterns.into_iter().partition(|p| p.starts_with(b"set:"));
+    if filesets.is_empty() {
+        return pattern_matcher(patterns);
+    }
+    let cwd = current_dir()?;
+    let mut matchers = vec![];
+    for fileset in filesets {
+        let expression = &fileset[b"set:".len()..];
+        matchers.push(fileset_matcher(repo, rev, &cwd, expression, warnings)?);
+    }
+    if !patterns.is_empty() {
+        matchers.push(pattern_matcher(patterns)?);
+    }
+    Ok(Box::new(UnionMatcher::new(matchers)))
+}
@@ -0,0 +1,160 @@
+//! Helpers shared by the commands changing which files are tracked:
+//! `add`, `forget`, `remove`, `addremove`, `copy` and `rename`.
+
+use std::ffi::OsString;
+
+use format_bytes::format_bytes;
+use hg::dirstate::status::BadMatch;
+use hg::file_patterns::parse_pattern_args;
+use hg::lock::LockError;
+use hg::matchers::AlwaysMatcher;
+use hg::matchers::Matcher;
+use hg::matchers::PatternMatcher;
+use hg::repo::Repo;
+use hg::utils::files::get_bytes_from_os_str;
+use hg::utils::hg_path::HgPath;
+use hg::utils::hg_path::HgPathBuf;
+use hg::warnings::HgWarning;
+use hg::warnings::HgWarningContext;
+use hg::warnings::format::write_tracking_warning;
+use hg::warnings::format::write_warning;
+
+use crate::error::CommandError;
+use crate::ui::RelativePaths;
+use crate::ui::Ui;
+use crate::ui::relative_paths;
+use crate::utils::path_utils::RelativizePaths;
+
+/// Returns the `FILE` arguments of the command, ignoring empty ones
+pub fn file_args(args: &clap::ArgMatches) -> Vec<Vec<u8>> {
+    args.get_many::<OsString>("file")
+        .map(|files| {
+            files.filter(|s| !s.is_empty()).map(get_bytes_from_os_str).collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Fall back to Python for what these commands do not support
+pub fn check_supported(
+    repo: &Repo,
+    patterns: &[Vec<u8>],
+) -> Result<(), CommandError> {
+    if patterns.iter().any(|p| p.starts_with(b"set:")) {
+        return Err(CommandError::unsupported("fileset"));
+    }
+    if repo.has_sparse() {
+        return Err(CommandError::unsupported("sparse"));
+    }
+    if repo.has_narrow() {
+        return Err(CommandError::unsupported("narrow"));
+    }
+    if repo.has_subrepos()? {
+        return Err(CommandError::unsupported("sub-repositories"));
+    }
+    Ok(())
+}
+
+/// Returns the matcher for the given `FILE` arguments, or one that matches
+/// everything if there are none.
+pub fn file_matcher(
+    repo: &Repo,
+    patterns: Vec<Vec<u8>>,
+) -> Result<Box<dyn Matcher + Sync>, CommandError> {
+    if patterns.is_empty() {
+        return Ok(Box::new(AlwaysMatcher));
+    }
+    let cwd = hg::utils::current_dir()?;
+    let root = repo.working_directory_path();
+    let file_patterns = parse_pattern_args(patterns, &cwd, root)?;
+    Ok(Box::new(PatternMatcher::new(file_patterns)?))
+}
+
+/// Returns a function formatting paths for the user, relative to the current
+/// directory depending on `ui.relative-paths`.
+pub fn ui_path_fn(
+    repo: &Repo,
+    legacy_relative: bool,
+) -> Result<impl Fn(&HgPath) -> Vec<u8> + use<>, CommandError> {
+    let relativize = match relative_paths(repo.config())? {
+        RelativePaths::Legacy => legacy_relative,
+        RelativePaths::Bool(v) => v,
+    };
+    let relativize = if relativize {
+        Some(RelativizePaths::new(repo)?)
+    } else {
+        None
+    };
+    Ok(move |path: &HgPath| match &relativize {
+        Some(relativize) => relativize.relativize(path).into_owned(),
+        None => path.as_bytes().to_owned(),
+    })
+}
+
+/// Print explicitly given paths that could not be looked at, like Python
+/// does while walking the working directory
+pub fn print_bad(
+    ui: &Ui,
+    bad: &[(HgPathBuf, BadMatch)],
+    ui_path: &impl Fn(&HgPath) -> Vec<u8>,
+) -> Result<(), CommandError> {
+    for (path, error) in bad {
+        let message = match error {
+            BadMatch::OsError(code) => {
+                strerror(&std::io::Error::from_raw_os_error(*code))
+            }
+            BadMatch::BadType(ty) => {
+                format!("unsupported file type (type is {})", ty)
+            }
+        };
+        ui.write_stderr(&format_bytes!(
+            b"{}: {}\n",
+            ui_path(path),
+            message.as_bytes()
+        ))?;
+    }
+    Ok(())
+}
+
+/// Returns the description of an OS error without its code, like Python's
+/// `strerror`
+pub fn strerror(error: &std::io::Error) -> String {
+    let message = error.to_string();
+    match message.rfind(" (os error ") {
+        Some(index) => message[..index].to_owned(),
+        None => message,
+    }
+}
+
+/// Print the warnings sent so far, with paths formatted by `ui_path`
+pub fn print_tracking_warnings(
+    ui: &Ui,
+    warnings: HgWarningContext,
+    repo: &Repo,
+    ui_path: &impl Fn(&HgPath) -> Vec<u8>,
+) {
+    let mut stderr = ui.stderr_locked();
+    // Can't really do anything if writing to stderr failed
+    let _ = warnings.finish(|warning| match &warning {
+        HgWarning::Tracking(warning) => {
+            write_tracking_warning(warning, &mut stderr, ui_path)
+        }
+        warning => {
+            write_warning(warning, &mut stderr, repo.working_directory_path())
+        }
+    });
+}
+
+/// Run `f` with the working copy lock, falling back to Python if it is
+/// already held
+pub fn with_wlock<R>(
+    repo: &Repo,
+    f: impl FnOnce() -> Result<R, CommandError>,
+) -> Result<R, CommandError> {
+    match repo.try_with_wlock_no_wait(f) {
+        Ok(result) => result,
+        Err(LockError::AlreadyHeld) => {
+            Err(CommandError::unsupported("waiting for the working copy lock"))
+        }
+        Err(LockError::IO(error)) => Err(error.into()),
+    }
+}
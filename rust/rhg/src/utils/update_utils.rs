@@ -0,0 +1,66 @@
+//! Helpers shared by the commands writing files to the working copy:
+//! `clone` and `revert`.
+
+use hg::errors::HgError;
+use hg::repo::Repo;
+use hg::update::FileConflictConfig;
+use hg::update::UpdateConfig;
+
+/// How a command writes files to the working copy, which decides what
+/// [`update_config`] reads from the configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkingCopyWrite {
+    /// A checkout into an empty working copy, like `update_from_null` in
+    /// Python: there is nothing to back up or remove, but unknown files can
+    /// conflict with the files written
+    FromNull,
+    /// A revert, which backs up and overwrites files without checking for
+    /// conflicts
+    Revert,
+}
+
+/// The configuration of writing files to the working copy of `repo`, like
+/// the arguments given to the Rust update code in Python
+pub fn update_config(
+    repo: &Repo,
+    write: WorkingCopyWrite,
+) -> Result<UpdateConfig, HgError> {
+    let config = repo.config();
+    let workers = if config.get_bool(b"worker", b"enabled")? {
+        config.get_u32(b"worker", b"numcpus")?.map(|n| n as usize)
+    } else {
+        Some(1)
+    };
+    let conflict = |item: &str| {
+        FileConflictConfig::new(
+            &format!("merge.{}", item),
+            config.get_str(b"merge", item.as_bytes())?,
+        )
+    };
+    Ok(match write {
+        WorkingCopyWrite::FromNull => UpdateConfig {
+            workers,
+            remove_empty_dirs: false,
+            devel_abort_dirstate: config
+                .get_bool(b"devel", b"update.abort-on-dirstate-change")?,
+            orig_backup_path: None,
+            atomic_file: false,
+            ignored_conflict: conflict("checkignored")?,
+            unknown_conflict: conflict("checkunknown")?,
+        },
+        WorkingCopyWrite::Revert => UpdateConfig {
+            workers,
+            remove_empty_dirs: config
+                .get_bool(b"experimental", b"removeemptydirs")?,
+            devel_abort_dirstate: false,
+            orig_backup_path: config
+                .get(b"ui", b"origbackuppath")
+                .map(|p| p.to_owned()),
+            atomic_file: config
+                .get_bool(b"experimental", b"update.atomic-file")?,
+            // Unused: revert overwrites files without checking for conflicts
+            ignored_conflict: FileConflictConfig::Abort,
+            unknown_conflict: FileConflictConfig::Abort,
+        },
+    })
+}
@@ -1,6 +1,9 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let fds = protox::compile(["proto/vfs.proto"], ["proto"])?;
+    let protos = ["proto/vfs.proto", "proto/store.proto"];
+    let fds = protox::compile(protos, ["proto"])?;
     tonic_build::compile_fds(fds)?;
-    println!("cargo::rerun-if-changed=proto/vfs.proto");
+    for proto in protos {
+        println!("cargo::rerun-if-changed={proto}");
+    }
     Ok(())
 }
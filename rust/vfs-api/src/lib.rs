@@ -9,3 +9,7 @@ pub fn default_socket_path() -> Option<PathBuf> {
 pub mod vfs {
     tonic::include_proto!("vfs");
 }
+
+pub mod store {
+    tonic::include_proto!("store");
+}
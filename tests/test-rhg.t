@@ -316,6 +316,422 @@ Purge files
   copy_of_original
   original
 
+Revert files
+  $ cd $TESTTMP
+  $ hg init working-copy
+  $ cd working-copy
+  $ echo a > a
+  $ echo b > b
+  $ mkdir dir
+  $ echo c > dir/c
+  $ hg commit -Aqm init
+  $ echo a2 > a
+  $ hg remove -q b
+  $ echo new > new
+  $ hg add -q new
+  $ echo c2 > dir/c
+  $ $NO_FALLBACK rhg revert --no-backup dir a
+  reverting dir/c
+  $ hg status
+  A new
+  R b
+  $ $NO_FALLBACK rhg revert --all
+  forgetting new
+  undeleting b
+  $ hg status
+  ? new
+  $ rm new
+  $ echo a3 > a
+  $ hg commit -qm a3
+  $ $NO_FALLBACK rhg revert -r 0 a
+  $ hg status
+  M a
+  $ cat a
+  a
+  $ $NO_FALLBACK rhg revert nonexistent
+  nonexistent: no such file in rev a95ac80925f6
+  $ $NO_FALLBACK rhg revert
+  unsupported feature: revert without files or --all
+  [252]
+  $ cd $TESTTMP/repository
+
+Add, remove and forget files
+  $ cd $TESTTMP
+  $ hg init tracking
+  $ cd tracking
+  $ echo clean > clean
+  $ echo modified > modified
+  $ echo deleted > deleted
+  $ mkdir dir
+  $ echo inner > dir/inner
+  $ hg commit -Aqm init
+  $ echo twice > modified
+  $ rm deleted
+  $ echo unknown > unknown
+  $ echo added > added
+  $ $NO_FALLBACK rhg add added
+  $ $NO_FALLBACK rhg add -v
+  adding unknown
+  $ hg forget -q unknown
+  $ $NO_FALLBACK rhg remove modified added
+  not removing modified: file is modified (use -f to force removal)
+  not removing added: file has been marked for add (use 'hg forget' to undo add)
+  [1]
+  $ $NO_FALLBACK rhg remove --after clean
+  not removing clean: file still exists
+  [1]
+  $ $NO_FALLBACK rhg remove -A
+  removing deleted
+  [1]
+  $ $NO_FALLBACK rhg remove dir
+  removing dir/inner
+  $ $NO_FALLBACK rhg remove -f added modified
+  $ hg status
+  R deleted
+  R dir/inner
+  R modified
+  ? added
+  ? unknown
+  $ $NO_FALLBACK rhg forget clean nonexistent unknown
+  nonexistent: $ENOENT$
+  not removing unknown: file is already untracked
+  [1]
+  $ hg status
+  R clean
+  R deleted
+  R dir/inner
+  R modified
+  ? added
+  ? unknown
+  $ hg revert -q --all --no-backup
+  $ rm added unknown
+  $ mv clean renamed
+  $ echo other > other
+  $ $NO_FALLBACK rhg addremove -n
+  removing clean
+  adding other
+  adding renamed
+  recording removal of clean as rename to renamed (100% similar)
+  $ $NO_FALLBACK rhg addremove -s 50
+  removing clean
+  adding other
+  adding renamed
+  recording removal of clean as rename to renamed (100% similar)
+  $ hg status -C
+  A other
+  A renamed
+    clean
+  R clean
+  $ cd $TESTTMP/repository
+
+Copy and rename files
+  $ cd $TESTTMP
+  $ hg init copies
+  $ cd copies
+  $ echo a > a
+  $ mkdir dir target
+  $ echo b > dir/b
+  $ hg commit -Aqm init
+  $ $NO_FALLBACK rhg copy a c
+  $ $NO_FALLBACK rhg copy -v dir/b a target
+  copying dir/b to target/b
+  copying a to target/a
+  $ $NO_FALLBACK rhg rename a moved
+  $ $NO_FALLBACK rhg copy a c
+  a: not copying - file has been marked for remove
+  abort: no files to copy
+  [10]
+  $ $NO_FALLBACK rhg copy moved c
+  c: not overwriting - file exists
+  ('hg copy --after' to record the copy)
+  [1]
+  $ $NO_FALLBACK rhg copy --after a d
+  a: not recording copy - d does not exist
+  [1]
+  $ cp dir/b copied
+  $ $NO_FALLBACK rhg copy --after dir/b copied
+  $ mv moved moved-twice
+  $ $NO_FALLBACK rhg rename --after moved moved-twice
+  $ hg status -C
+  A c
+    a
+  A copied
+    dir/b
+  A moved-twice
+    a
+  A target/a
+    a
+  A target/b
+    dir/b
+  R a
+  $ $NO_FALLBACK rhg copy dir/b .hg/b
+  abort: path contains illegal component: .hg/b
+  [10]
+#if symlink
+  $ ln -s target link
+  $ $NO_FALLBACK rhg copy dir/b link/b
+  abort: path 'link/b' traverses symbolic link 'link'
+  [255]
+#endif
+  $ cd $TESTTMP/repository
+
+Archive a revision
+  $ cd $TESTTMP
+  $ hg init archives
+  $ cd archives
+  $ echo a > a
+  $ mkdir dir
+  $ echo b > dir/b
+  $ chmod +x dir/b
+  $ hg commit -Aqm init
+  $ hg tag -q v1
+  $ $NO_FALLBACK rhg archive -r 0 ../files
+  $ find ../files | sort
+  ../files
+  ../files/.hg_archival.txt
+  ../files/a
+  ../files/dir
+  ../files/dir/b
+  $ cat ../files/.hg_archival.txt
+  repo: [0-9a-f]{40} (re)
+  node: [0-9a-f]{40} (re)
+  branch: default
+  tag: v1
+#if execbit
+  $ test -x ../files/dir/b && echo executable
+  executable
+#endif
+  $ $NO_FALLBACK rhg archive -t tgz ../out.tar.gz
+  $ tar tzf ../out.tar.gz
+  out/.hg_archival.txt
+  out/.hgtags
+  out/a
+  out/dir/b
+  $ tar xOzf ../out.tar.gz out/.hg_archival.txt | grep latesttag
+  latesttag: v1
+  latesttagdistance: 1
+  changessincelatesttag: 1
+  $ $NO_FALLBACK rhg archive -t tzst ../out.tar.zst
+  $ f --hexdump --bytes 4 ../out.tar.zst
+  ../out.tar.zst:
+  0000: 28 b5 2f fd                                     |(./.|
+  $ $NO_FALLBACK rhg archive -r 0 -I dir --prefix p ../dir.tar
+  $ tar tf ../dir.tar
+  p/dir/b
+  $ $NO_FALLBACK rhg archive -X '**' ../none.tar
+  abort: no files match the archive pattern
+  [255]
+  $ $NO_FALLBACK rhg archive -t foo ../bad
+  abort: unknown archive type 'foo'
+  [255]
+  $ $NO_FALLBACK rhg archive --prefix ../x ../bad.tar
+  abort: archive prefix contains illegal components
+  [255]
+  $ cd $TESTTMP/repository
+
+Search files
+  $ cd $TESTTMP
+  $ hg init searches
+  $ cd searches
+  $ printf 'foo\nbar foo\nbaz\n' > a
+  $ mkdir dir
+  $ printf 'Foo\nfoobar\n' > dir/b
+  $ hg commit -Aqm init -u alice -d '0 0'
+  $ printf 'foo\nbaz\n' > a
+  $ hg commit -qm two -u bob -d '86400 0'
+  $ echo foo > unknown
+  $ echo foo > ignored
+  $ echo ignored > .hgignore
+  $ $NO_FALLBACK rhg grep foo
+  a:foo
+  dir/b:foobar
+  $ $NO_FALLBACK rhg grep -n -i foo dir
+  dir/b:1:Foo
+  dir/b:2:foobar
+  $ $NO_FALLBACK rhg grep -l 'ba.'
+  a
+  dir/b
+  $ $NO_FALLBACK rhg grep -r 0 -u -d foo a
+  a:0:alice:Thu Jan 01 00:00:00 1970 +0000:foo
+  a:0:alice:Thu Jan 01 00:00:00 1970 +0000:bar foo
+  $ $NO_FALLBACK rhg grep --diff foo
+  a:1:-:bar foo
+  a:0:+:foo
+  a:0:+:bar foo
+  dir/b:0:+:foobar
+  $ $NO_FALLBACK rhg grep --all-files -r 0 'o\nb'
+  a:0:foo
+  bar foo
+  $ $NO_FALLBACK rhg grep nomatch
+  [1]
+  $ $NO_FALLBACK rhg grep --all --all-files foo
+  abort: cannot specify both --all-files and --all
+  [10]
+  $ $NO_FALLBACK rhg grep 'a\1'
+  unsupported feature: grep pattern: regex parse error:
+      a\1
+       ^^
+  error: backreferences are not supported
+  [252]
+  $ rhg grep '(a)\1'
+  [1]
+  $ cd $TESTTMP/repository
+
+List files with their details
+  $ cd $TESTTMP
+  $ hg init details
+  $ cd details
+  $ echo a > a
+  $ mkdir dir
+  $ echo bb > dir/b
+  $ chmod +x dir/b
+  $ hg commit -Aqm init
+  $ hg rm -q a
+  $ echo c > c
+  $ hg add -q c
+#if execbit
+  $ $NO_FALLBACK rhg files -v -r 0
+           2   a
+           3 x dir/b
+  $ $NO_FALLBACK rhg files --debug
+           2   c
+           3 x dir/b
+  $ $NO_FALLBACK rhg manifest -v
+  644   a
+  755 * dir/b
+  $ $NO_FALLBACK rhg manifest --debug -r 0
+  b789fdd96dc2f3bd229c1dd8eedf0fc60e2b68e3 644   a
+  04c6faf8a9fdd848a5304dfc1704749a374dff44 755 * dir/b
+#endif
+  $ $NO_FALLBACK rhg manifest --all
+  a
+  dir/b
+  $ $NO_FALLBACK rhg manifest --all -r 0
+  abort: can't specify a revision with --all
+  [10]
+  $ cd $TESTTMP/repository
+
+Verify the repository
+  $ cd $TESTTMP/details
+  $ $NO_FALLBACK rhg verify -v
+  repository uses revlog format 1
+  checking changesets
+  checking manifests
+  crosschecking files in changesets and manifests
+  checking files
+  checking dirstate
+  checked 1 changesets with 2 changes to 2 files
+  $ $NO_FALLBACK rhg verify -q
+  $ cd $TESTTMP/repository
+
+Run hooks while unbundling
+  $ cd $TESTTMP
+  $ hg init hooks-source
+  $ cd hooks-source
+  $ echo a > a
+  $ hg commit -Aqm first
+  $ echo b > b
+  $ hg commit -Aqm second
+  $ hg bundle -q --all ../hooks.hg
+  $ cd ..
+  $ hg init hooks
+  $ cd hooks
+  $ cat >> .hg/hgrc << EOF
+  > [hooks]
+  > pretxnchangegroup.low = echo low
+  > pretxnchangegroup.high = echo "high \$HG_PENDING"; RHG_ON_UNSUPPORTED=fallback hg log -T '{desc}\n'
+  > priority.pretxnchangegroup.high = 1
+  > pretxnchangegroup.same = echo same
+  > changegroup = echo changegroup \$HG_SOURCE
+  > EOF
+  $ $NO_FALLBACK rhg unbundle -q ../hooks.hg --config 'hooks.pretxnchangegroup.py=python:os.getcwd'
+  unsupported feature: python hook pretxnchangegroup.py
+  [252]
+  $ hg log -T '{desc}\n'
+  $ $NO_FALLBACK rhg unbundle -q ../hooks.hg
+  high $TESTTMP/hooks
+  second
+  first
+  low
+  same
+  changegroup unbundle
+  $ cd $TESTTMP/repository
+
+Clone and share
+  $ cd $TESTTMP
+  $ hg init --config format.use-share-safe=no unsafe
+  $ echo a > unsafe/a
+  $ hg -R unsafe commit -Aqm first
+  $ $NO_FALLBACK rhg clone unsafe cloned
+  updating to branch default
+  1 files updated, 0 files merged, 0 files removed, 0 files unresolved
+  $ cat cloned/.hg/requires
+  share-safe
+  $ cat cloned/.hg/store/requires
+  dotencode
+  fncache
+  generaldelta
+  persistent-nodemap (rust !)
+  revlog-compression-zstd (zstd !)
+  revlogv1
+  sparserevlog
+  store
+  $ $NO_FALLBACK rhg share --config extensions.share= cloned shared
+  updating working directory
+  1 files updated, 0 files merged, 0 files removed, 0 files unresolved
+  $ cat shared/.hg/requires
+  share-safe
+  shared
+  $ $NO_FALLBACK rhg share --config extensions.share= --relative cloned relshared
+  updating working directory
+  1 files updated, 0 files merged, 0 files removed, 0 files unresolved
+  $ cat relshared/.hg/requires
+  relshared
+  share-safe
+  $ cat relshared/.hg/sharedpath; echo
+  ../../cloned/.hg
+  $ $NO_FALLBACK rhg share --config extensions.share= unsafe unsafe-share --config format.use-dirstate-tracked-hint=yes
+  updating working directory
+  1 files updated, 0 files merged, 0 files removed, 0 files unresolved
+  $ cat unsafe-share/.hg/requires
+  dirstate-tracked-key-v1
+  dotencode
+  fncache
+  generaldelta
+  persistent-nodemap (rust !)
+  revlog-compression-zstd (zstd !)
+  revlogv1
+  shared
+  sparserevlog
+  store
+  $ test -f unsafe-share/.hg/dirstate-tracked-hint
+  $ hg -R relshared log -T '{desc}\n'
+  first
+  $ cd $TESTTMP/repository
+
+Serve over SSH, Python taking over the session when the client pushes
+  $ cd $TESTTMP
+  $ hg init ssh-server
+  $ echo a > ssh-server/a
+  $ hg -R ssh-server commit -Aqm first
+  $ hg clone -q -e "\"$PYTHON\" \"$TESTDIR/dummyssh\"" ssh://user@dummy/ssh-server ssh-client
+  $ echo b > ssh-client/b
+  $ hg -R ssh-client commit -Aqm second
+  $ hg -R ssh-client push -e "\"$PYTHON\" \"$TESTDIR/dummyssh\"" ssh://user@dummy/ssh-server
+  pushing to ssh://user@dummy/ssh-server
+  searching for changes
+  remote: adding changesets
+  remote: adding manifests
+  remote: adding file changes
+  remote: added 1 changesets with 1 changes to 1 files
+  $ hg -R ssh-server log -T '{desc} {phase}\n'
+  second public
+  first public
+  $ printf 'known\nnodes 99999999999\nabc' | rhg -R ssh-server serve --stdio
+  abort: when reading stream: unexpected end of file
+  [255]
+  $ cd $TESTTMP/repository
+
 Fallback to Python
   $ $NO_FALLBACK rhg cat original --exclude="*.rs"
   unsupported feature: error: unexpected argument '--exclude' found